@@ -1,104 +1,940 @@
 use eframe::{egui, CreationContext};
 use egui::{Color32, Ui};
-use std::sync::Arc;
-use std::time::Duration;
-use tracing::{error, info};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
+use open_reverb_common::models::{AudioQuality, ExportFormat, SchemaCompatibility, ScreenRegion, VideoQuality, validate_username, SCHEMA_VERSION};
 use crate::audio::AudioManager;
-use crate::config::{self, ClientConfig, Theme};
-use crate::connection::Connection;
+use crate::config::{self, ClientConfig};
+use crate::connection::{Connection, ConnectionQuality, CHANNEL_PAGE_SIZE};
+use crate::connection::reconnect::ReconnectState;
+use crate::crash_reporter::CrashReporter;
+use crate::privacy::PrivacyMode;
+use crate::sync::{self, MediaTiming, SYNC_HINT_INTERVAL};
+use crate::ui::login::ConnectionTestPanel;
+use crate::ui::main_view::{MainView, MainViewAction};
+use crate::ui::region_selector::{RegionSelection, RegionSelector};
+use crate::ui::search_panel::SEARCH_PAGE_SIZE;
+use crate::ui::settings::SettingsScreen;
+use crate::ui::totp::TOTPDialog;
+use crate::ui::video_pane::VideoPane;
+use crate::ui::wizard::FirstRunWizard;
 use crate::ui::style;
 use crate::video::{VideoManager, VideoPlayback, CaptureType};
 
+// The session token a login was answered with, and who it's for
+struct SavedSession {
+    server_url: String,
+    name: String,
+    token: String,
+}
+
 pub struct DemoApp {
     name: String,
     server_url: String,
     password: String,
     connection: Arc<Connection>,
+    config: ClientConfig,
     status_message: Option<String>,
-    show_settings: bool,
-    theme: Theme,
+    // Crash reports left over from earlier runs that the user hasn't decided on yet
+    pending_crash_reports: usize,
     
     // Media components
     audio_manager: Option<AudioManager>,
+    // What the current channel asks voice to be encoded with
+    audio_quality: AudioQuality,
     video_manager: Option<VideoManager>,
     screen_manager: Option<VideoManager>,
-    video_playback: VideoPlayback,
+    // Part of the screen to share, when not all of it, and the overlay for picking it while it's up
+    screen_region: Option<ScreenRegion>,
+    region_selector: Option<RegionSelector>,
+    // Shared with the main view and detached video panes
+    video_playback: Arc<Mutex<VideoPlayback>>,
+    // Participants' videos popped out of the grid
+    detached_panes: HashMap<Uuid, VideoPane>,
+    // Jitter and A/V sync of the media we receive
+    media_timing: MediaTiming,
+    last_sync_hint: Instant,
+    // Chat history exports still arriving, by transfer
+    pending_exports: HashMap<Uuid, Vec<u8>>,
+    
+    // Shown in place of the login screen once logged in
+    main_view: MainView,
+    settings_screen: Option<SettingsScreen>,
+    // Shown instead of the login screen until the user has been through setup once
+    wizard: Option<FirstRunWizard>,
+    // Open while the server waits for a TOTP code
+    totp_dialog: Option<TOTPDialog>,
+    connection_test: ConnectionTestPanel,
+    // Token from the last login, kept when the connection drops so reconnecting doesn't need
+    // the password again. Forgotten on disconnecting.
+    saved_session: Option<SavedSession>,
+    // A ResumeSession was sent; a 401 means the token is no good and the password is needed
+    resuming_session: bool,
+    // As last shown in the status message
+    reconnect_state: ReconnectState,
     
     // Media state
     audio_active: bool,
     video_active: bool,
     screen_active: bool,
-    
-    // Selected devices
-    selected_audio_input: Option<String>,
-    selected_audio_output: Option<String>,
-    selected_video_device: Option<String>,
+    // Camera blurred and paused while the window is unfocused
+    privacy_mode: PrivacyMode,
 }
 
 impl DemoApp {
     pub fn new(cc: &CreationContext) -> Self {
+        let connection = Arc::new(Connection::new());
+        
+        let config = config::load_config().unwrap_or_else(|e| {
+            error!("Failed to load config, using defaults: {}", e);
+            ClientConfig::default()
+        });
+        
         // Set up styles
-        style::setup_style(&cc.egui_ctx);
+        style::setup_style(&cc.egui_ctx, config.theme, &config.custom_theme);
         
-        let connection = Arc::new(Connection::new());
+        let wizard = (!config.first_run_complete).then(|| FirstRunWizard::new(config.clone()));
+        
+        let mut video_playback = VideoPlayback::new();
+        video_playback.set_decoder_backend(config.decoder_backend());
+        let video_playback = Arc::new(Mutex::new(video_playback));
+        let mut main_view = MainView::new();
+        main_view.set_video_playback(Arc::clone(&video_playback));
         
         Self {
             name: "".to_string(),
-            server_url: "127.0.0.1:8080".to_string(),
+            server_url: config.server_url.clone(),
             password: "".to_string(),
             connection,
+            config,
             status_message: None,
-            show_settings: false,
-            theme: Theme::Dark,
+            pending_crash_reports: CrashReporter::pending_reports().len(),
             
             audio_manager: None,
+            audio_quality: AudioQuality::default(),
             video_manager: None,
             screen_manager: None,
-            video_playback: VideoPlayback::new(),
+            screen_region: None,
+            region_selector: None,
+            video_playback,
+            detached_panes: HashMap::new(),
+            media_timing: MediaTiming::new(),
+            pending_exports: HashMap::new(),
+            last_sync_hint: Instant::now(),
+            
+            main_view,
+            settings_screen: None,
+            wizard,
+            totp_dialog: None,
+            connection_test: ConnectionTestPanel::new(),
+            saved_session: None,
+            resuming_session: false,
+            reconnect_state: ReconnectState::Idle,
             
             audio_active: false,
             video_active: false,
             screen_active: false,
-            
-            selected_audio_input: None,
-            selected_audio_output: None,
-            selected_video_device: None,
+            privacy_mode: PrivacyMode::new(),
         }
     }
     fn handle_message(&mut self, message: open_reverb_common::protocol::Message) {
         use open_reverb_common::protocol::Message;
         
         match message {
-            Message::LoginResponse { success, user_id, error } => {
+            Message::LoginResponse { success, user_id, token, error } => {
+                self.resuming_session = false;
+                
                 if success {
+                    self.totp_dialog = None;
+                    self.saved_session = token.map(|token| SavedSession {
+                        server_url: self.server_url.clone(),
+                        name: self.name.clone(),
+                        token,
+                    });
+                    
                     if let Some(id) = user_id {
                         info!("Login successful with user ID: {}", id);
                         self.status_message = Some(format!("Login successful with user ID: {}", id));
+                        self.main_view.set_current_user_id(id);
                     }
+                } else if let (Some(dialog), Some(err)) = (&mut self.totp_dialog, error.clone()) {
+                    // Most likely a mistyped code, which can be tried again
+                    dialog.show_error(err);
                 } else if let Some(err) = error {
                     error!("Login failed: {}", err);
                     self.status_message = Some(format!("Login failed: {}", err));
                 }
             }
-            Message::VoiceData { user_id, channel_id, data } => {
-                // Process received voice data
-                // In a real implementation, this would be sent to the audio playback system
-                info!("Received voice data from user {}", user_id);
+            Message::TOTPRequest { user_id } => {
+                self.totp_dialog.get_or_insert_with(TOTPDialog::new).request_code(user_id);
+            }
+            Message::SetupTOTP { secret, .. } => {
+                self.totp_dialog.get_or_insert_with(TOTPDialog::new).set_setup_secret(secret);
+            }
+            Message::SessionExpiring { remaining_secs, renew_token } => {
+                self.main_view.set_session_expiring(Duration::from_secs(remaining_secs as u64), renew_token);
+            }
+            Message::SessionExpired {} => {
+                // The server is closing the connection; log straight back in with the same credentials
+                info!("Session expired, logging in again");
+                self.disconnect();
+                self.connect_and_login();
+                self.status_message = Some("Your session expired, so you were logged in again".to_string());
+            }
+            Message::ServerInfo { server } => {
+                self.check_schema_version(server.schema_version);
+                self.main_view.set_server_info(server);
+            }
+            Message::ServerInfoSummary { server_id, name, channel_count, user_count, schema_version } => {
+                info!("{} has {} channels and {} users online; loading them as needed", name, channel_count, user_count);
+                self.check_schema_version(schema_version);
+                self.main_view.set_server_summary(server_id, name, channel_count, schema_version);
+                
+                let connection = self.connection_mut();
+                if let Err(e) = connection.get_channels(0, CHANNEL_PAGE_SIZE).and_then(|()| connection.get_online_users(None)) {
+                    error!("Failed to ask for the server's channels and users: {}", e);
+                }
+            }
+            Message::ChannelList { channels, total } => {
+                if let Some(offset) = self.main_view.add_channel_page(channels, total) {
+                    if let Err(e) = self.connection_mut().get_channels(offset, CHANNEL_PAGE_SIZE) {
+                        error!("Failed to ask for more channels: {}", e);
+                    }
+                }
+            }
+            Message::UserList { users } => {
+                self.main_view.set_online_users(users);
+            }
+            Message::HelloAck { .. } => {
+                // The connection has already taken note of them
+                self.main_view.set_features(self.connection.negotiated_features());
+            }
+            Message::ServerStats { stats } => {
+                self.main_view.set_server_stats(stats);
+            }
+            Message::ServerAnnouncement { text } => {
+                self.main_view.add_announcement(text);
+            }
+            Message::ChannelUpdate { channel } => {
+                // An admin changed the quality of the channel we're in
+                if self.connection.get_current_channel_id() == Some(channel.id) {
+                    self.set_audio_quality(channel.audio_quality);
+                }
+                self.main_view.update_channel(channel);
+            }
+            Message::ChannelDeleted { channel_id } => {
+                self.remove_channels(vec![channel_id]);
+            }
+            Message::ChannelsPurged { channel_ids } => {
+                self.remove_channels(channel_ids);
             }
-            Message::VideoData { user_id, channel_id, data } => {
-                // Process received video data
-                self.video_playback.process_video_data(user_id, data);
+            Message::JoinChannelAck { audio_quality, .. } => {
+                self.set_audio_quality(audio_quality);
             }
-            Message::ScreenShareData { user_id, channel_id, data } => {
+            Message::CreateChannelResponse { success: false, error, .. } => {
+                let error = error.unwrap_or_else(|| "Unknown error".to_string());
+                self.main_view.show_toast(format!("Couldn't create channel: {}", error));
+            }
+            Message::WaitingForApproval { channel_id } => {
+                self.main_view.waiting_for_approval(channel_id);
+            }
+            Message::JoinPending { user, channel_id } => {
+                self.main_view.add_pending_member(user, channel_id);
+            }
+            Message::GhostMembers { channel_id, users } => {
+                self.main_view.set_ghost_members(channel_id, users);
+            }
+            Message::JoinRejected { reason, .. } => {
+                self.main_view.join_failed(reason.as_deref().unwrap_or("Rejected by an admin"));
+            }
+            Message::UserJoined { user } => {
+                self.main_view.add_user(user);
+            }
+            Message::DirectMessage { from_user_id, content, timestamp, .. } => {
+                self.main_view.receive_direct_message(from_user_id, content, timestamp);
+            }
+            Message::FileTransferOffer { id, from, filename, size_bytes, mime_type, .. } => {
+                self.main_view.receive_file_offer(id, from, filename, size_bytes, mime_type);
+            }
+            Message::FileTransferAccept { .. } => {
+                self.main_view.show_toast("File accepted, sending it");
+            }
+            Message::FileTransferReject { id, reason } => {
+                self.main_view.withdraw_file_offer(id);
+                self.main_view.show_toast(format!("File transfer stopped: {}", reason));
+            }
+            Message::FileTransferComplete { id } => {
+                if let Some(path) = self.connection_mut().take_saved_file(id) {
+                    self.main_view.show_toast(format!("Saved {}", path.display()));
+                }
+            }
+            Message::UserLeft { user_id } => {
+                self.main_view.remove_user(user_id);
+                
+                // Their video has nothing left to show
+                self.detached_panes.remove(&user_id);
+                self.media_timing.remove_user(user_id);
+                self.video_playback.lock().unwrap().remove_user(user_id);
+                
+                // Nor is their delay worth holding everyone else back for
+                if let Some(audio_manager) = &self.audio_manager {
+                    audio_manager.remove_user(user_id);
+                }
+            }
+            Message::UserUpdated { user } => {
+                self.main_view.update_user(user);
+            }
+            Message::ChannelMediaState { channel_id, .. } => {
+                info!("Joined channel {}", channel_id);
+                self.connection_mut().set_current_channel_id(Some(channel_id));
+                self.main_view.channel_joined(channel_id);
+            }
+            Message::TextMessage { message_id, user_id, channel_id, content, timestamp } => {
+                self.main_view.add_text_message(message_id, user_id, channel_id, content, timestamp);
+            }
+            Message::MessagesDeleted { channel_id, oldest_remaining, .. } => {
+                self.main_view.messages_deleted(channel_id, oldest_remaining);
+            }
+            Message::ExportData { transfer_id, format, chunk_index, chunk_count, data } => {
+                self.receive_export_chunk(transfer_id, format, chunk_index, chunk_count, data);
+            }
+            Message::WelcomeMessage { content } => {
+                self.main_view.set_welcome_message(content);
+            }
+            Message::SpeakingStats { channel_id, stats } => {
+                self.main_view.set_speaking_stats(channel_id, stats);
+            }
+            Message::SearchResults { results } => {
+                self.main_view.add_search_results(results);
+            }
+            Message::SearchUsersResult { users } => {
+                self.main_view.set_user_search_results(users);
+            }
+            Message::TranscriptionResult { user_id, channel_id, text, timestamp } => {
+                self.main_view.add_transcript(user_id, channel_id, text, timestamp);
+            }
+            Message::VoiceData { user_id, channel_id, seq, captured_at_ms, data, .. } => {
+                // Our own voice only comes back during the loopback test
+                if Some(user_id) == self.connection.get_user_id() {
+                    if let Some(settings_screen) = &mut self.settings_screen {
+                        settings_screen.set_loopback_latency(sync::now_millis().saturating_sub(captured_at_ms));
+                    }
+                } else {
+                    self.media_timing.on_voice_frame(user_id, captured_at_ms);
+                }
+                
+                if let Some(audio_manager) = &self.audio_manager {
+                    if Some(channel_id) == self.connection.get_current_channel_id() {
+                        let level = audio_manager.play_voice(user_id, seq, &data);
+                        self.main_view.update_audio_level(user_id, level);
+                    }
+                }
+            }
+            Message::VideoData { user_id, captured_at_ms, video_codec, data, .. } => {
+                self.media_timing.on_video_frame(user_id, captured_at_ms);
+                
+                let mut video_playback = self.video_playback.lock().unwrap();
+                video_playback.set_codec(user_id, video_codec);
+                video_playback.process_video_data(user_id, data);
+            }
+            Message::VideoCodecInfo { user_id, codec } => {
+                self.video_playback.lock().unwrap().set_codec(user_id, codec);
+            }
+            Message::VideoQualityUpdate { user_id, quality, .. } => {
+                self.main_view.set_video_paused(user_id, quality == VideoQuality::Paused);
+            }
+            Message::ScreenShareRegionChanged { user_id, region } => {
+                self.video_playback.lock().unwrap().set_screen_region(user_id, region);
+            }
+            Message::ScreenShareData { user_id, data, .. } => {
                 // Process received screen share data
-                self.video_playback.process_video_data(user_id, data);
+                self.video_playback.lock().unwrap().process_video_data(user_id, data);
+            }
+            Message::SyncHint { user_id, audio_pts_ms, video_pts_ms } => {
+                self.media_timing.on_sync_hint(user_id, audio_pts_ms, video_pts_ms);
+            }
+            Message::RecordingConsent { recording_user_id, recording_started } => {
+                self.main_view.set_recording_state(recording_user_id, recording_started);
+                
+                if self.connection.get_user_id() == Some(recording_user_id) {
+                    // The server has notified the channel, so our recording can begin
+                    if let Some(audio_manager) = &mut self.audio_manager {
+                        audio_manager.set_recording(recording_started);
+                    }
+                    
+                    if recording_started {
+                        self.status_message = Some("Recording started".to_string());
+                    }
+                }
+            }
+            Message::Error { code: 401, message } if self.resuming_session => {
+                info!("Couldn't resume the session ({}), logging in with the password", message);
+                self.resuming_session = false;
+                self.saved_session = None;
+                self.login();
+            }
+            Message::Error { code: 451, message } => {
+                if let Some(audio_manager) = &mut self.audio_manager {
+                    audio_manager.set_recording(false);
+                }
+                
+                error!("Recording refused: {}", message);
+                self.status_message = Some(message);
+            }
+            Message::Error { code: 429, message } => {
+                warn!("{}", message);
+                
+                for manager in [&self.video_manager, &self.screen_manager].into_iter().flatten() {
+                    manager.signal_congestion();
+                }
+            }
+            Message::Error { message, .. } if self.main_view.is_joining() => {
+                error!("Failed to join channel: {}", message);
+                self.main_view.join_failed(&message);
             }
             _ => {}
         }
     }
     
+    fn handle_main_view_action(&mut self, action: MainViewAction) {
+        use open_reverb_common::protocol::Message;
+        
+        match action {
+            MainViewAction::RespondToRecording { accepted } => {
+                if let Some(user_id) = self.connection.get_user_id() {
+                    let response = if accepted {
+                        Message::ConsentAcknowledged { user_id }
+                    } else {
+                        Message::ConsentRejected { user_id }
+                    };
+                    
+                    if let Err(e) = self.connection.get_sender().send(response) {
+                        error!("Failed to send recording consent: {}", e);
+                    }
+                }
+            }
+            MainViewAction::JoinChannel { channel_id, ghost } => {
+                // Media managers are tied to the channel they were started in
+                self.stop_all_media();
+                
+                // The client is in one channel at a time
+                let connection = self.connection_mut();
+                if let Some(current_channel_id) = connection.get_current_channel_id() {
+                    let _ = connection.leave_channel(current_channel_id);
+                    connection.set_current_channel_id(None);
+                }
+                
+                if let Err(e) = connection.join_channel(channel_id, ghost) {
+                    error!("Failed to join channel: {}", e);
+                    self.main_view.join_failed(&e.to_string());
+                }
+            }
+            MainViewAction::LeaveChannel { channel_id } => {
+                self.stop_all_media();
+                
+                let connection = self.connection_mut();
+                connection.set_current_channel_id(None);
+                if let Err(e) = connection.leave_channel(channel_id) {
+                    error!("Failed to leave channel: {}", e);
+                    self.main_view.show_toast(format!("Couldn't leave channel: {}", e));
+                }
+            }
+            MainViewAction::SetChannelTopic { channel_id, topic } => {
+                if let Err(e) = self.connection_mut().set_channel_topic(channel_id, topic) {
+                    error!("Failed to set channel topic: {}", e);
+                    self.main_view.show_toast(format!("Couldn't set channel topic: {}", e));
+                }
+            }
+            MainViewAction::EnableE2E { channel_id } => {
+                if let Err(e) = self.connection_mut().enable_e2e(channel_id) {
+                    error!("Failed to enable voice encryption: {}", e);
+                    self.main_view.show_toast(format!("Couldn't encrypt voice: {}", e));
+                }
+            }
+            MainViewAction::CreateChannel { name, description, parent_id, channel_type, audio_quality, permanent } => {
+                if let Err(e) = self.connection_mut().create_channel(name, description, parent_id, channel_type, audio_quality, permanent) {
+                    error!("Failed to create channel: {}", e);
+                    self.main_view.show_toast(format!("Couldn't create channel: {}", e));
+                }
+            }
+            MainViewAction::SetUserVolume { user_id, volume } => {
+                if let Some(audio_manager) = &self.audio_manager {
+                    audio_manager.set_user_volume(user_id, volume);
+                }
+            }
+            MainViewAction::DeleteChannel { channel_id } => {
+                if let Err(e) = self.connection_mut().delete_channel(channel_id) {
+                    error!("Failed to delete channel: {}", e);
+                    self.main_view.show_toast(format!("Couldn't delete channel: {}", e));
+                }
+            }
+            MainViewAction::SetLobbyMode { channel_id, enabled } => {
+                if let Err(e) = self.connection_mut().set_lobby_mode(channel_id, enabled) {
+                    error!("Failed to set lobby mode: {}", e);
+                    self.main_view.show_toast(format!("Couldn't change the lobby: {}", e));
+                }
+            }
+            MainViewAction::ToggleBookmark { channel_id, display_name } => {
+                self.config.toggle_bookmark(&self.server_url, channel_id, display_name);
+                self.save_bookmarks();
+            }
+            MainViewAction::RemoveBookmark { channel_id } => {
+                self.config.remove_bookmark(&self.server_url, channel_id);
+                self.save_bookmarks();
+            }
+            MainViewAction::ApprovePending { user_id, channel_id } => {
+                if let Err(e) = self.connection_mut().approve_pending(user_id, channel_id) {
+                    error!("Failed to approve user: {}", e);
+                    self.main_view.show_toast(format!("Couldn't let the user in: {}", e));
+                }
+            }
+            MainViewAction::RejectPending { user_id, channel_id } => {
+                if let Err(e) = self.connection_mut().reject_pending(user_id, channel_id, None) {
+                    error!("Failed to reject user: {}", e);
+                    self.main_view.show_toast(format!("Couldn't reject the user: {}", e));
+                }
+            }
+            MainViewAction::SendTextMessage { channel_id, content } => {
+                if let Err(e) = self.connection_mut().send_text_message(channel_id, content) {
+                    error!("Failed to send message: {}", e);
+                    self.main_view.show_toast(format!("Couldn't send message: {}", e));
+                }
+            }
+            MainViewAction::SendDirectMessage { to_user_id, content } => {
+                if let Err(e) = self.connection_mut().send_direct_message(to_user_id, content) {
+                    error!("Failed to send direct message: {}", e);
+                    self.main_view.show_toast(format!("Couldn't send message: {}", e));
+                }
+            }
+            MainViewAction::SendFile { to_user_id } => {
+                // Cancelled
+                let Some(path) = rfd::FileDialog::new().pick_file() else {
+                    return;
+                };
+                
+                match self.connection_mut().send_file(to_user_id, path) {
+                    Ok(()) => self.main_view.show_toast("Waiting for them to accept the file"),
+                    Err(e) => {
+                        error!("Failed to offer file: {}", e);
+                        self.main_view.show_toast(format!("Couldn't send the file: {}", e));
+                    }
+                }
+            }
+            MainViewAction::AcceptFile { id } => {
+                if let Err(e) = self.connection_mut().accept_file(id) {
+                    error!("Failed to accept file: {}", e);
+                    self.main_view.show_toast(format!("Couldn't accept the file: {}", e));
+                }
+            }
+            MainViewAction::DeclineFile { id } => {
+                if let Err(e) = self.connection_mut().reject_file(id, "Declined".to_string()) {
+                    error!("Failed to decline file: {}", e);
+                }
+            }
+            MainViewAction::ExportMessages { channel_id, start_ts, end_ts, format } => {
+                if let Err(e) = self.connection_mut().export_messages(channel_id, start_ts, end_ts, format) {
+                    error!("Failed to export messages: {}", e);
+                    self.main_view.show_toast(format!("Couldn't export history: {}", e));
+                }
+            }
+            MainViewAction::GetSpeakingStats { channel_id } => {
+                if let Err(e) = self.connection_mut().get_speaking_stats(channel_id) {
+                    error!("Failed to request speaking stats: {}", e);
+                }
+            }
+            MainViewAction::GetServerStats => {
+                if let Err(e) = self.connection_mut().get_server_stats() {
+                    error!("Failed to request server stats: {}", e);
+                }
+            }
+            MainViewAction::SearchMessages { query, offset } => {
+                if let Err(e) = self.connection_mut().search_messages(query, Vec::new(), SEARCH_PAGE_SIZE, offset) {
+                    error!("Failed to search messages: {}", e);
+                    self.main_view.show_toast(format!("Couldn't search messages: {}", e));
+                }
+            }
+            MainViewAction::SearchUsers { query, include_offline } => {
+                if let Err(e) = self.connection_mut().search_users(query, include_offline) {
+                    error!("Failed to search users: {}", e);
+                }
+            }
+            MainViewAction::RenewSession { token } => {
+                if let Err(e) = self.connection_mut().renew_session(token) {
+                    error!("Failed to renew session: {}", e);
+                    self.main_view.show_toast(format!("Couldn't renew session: {}", e));
+                }
+            }
+            MainViewAction::DetachVideo { user_id } => {
+                if self.detached_panes.len() >= self.config.max_detached_panes as usize {
+                    self.main_view.show_toast(format!(
+                        "At most {} videos can be popped out at once",
+                        self.config.max_detached_panes
+                    ));
+                } else {
+                    self.detached_panes.entry(user_id).or_insert_with(|| VideoPane::detach(user_id));
+                }
+            }
+            MainViewAction::ToggleAudio => self.toggle_audio(),
+            MainViewAction::ToggleVideo => self.toggle_video(),
+            MainViewAction::ToggleScreenShare => self.toggle_screen_sharing(),
+            MainViewAction::SelectScreenRegion => self.region_selector = Some(RegionSelector::new()),
+            MainViewAction::ToggleRecording => self.toggle_recording(),
+            MainViewAction::Disconnect => self.disconnect(),
+        }
+    }
+    
+    // The media managers hold their own references to the connection,
+    // so it can't be borrowed mutably through the Arc once media has started
+    fn connection_mut(&mut self) -> &mut Connection {
+        unsafe { &mut *(Arc::as_ptr(&self.connection) as *mut Connection) }
+    }
+    
+    // In trust-on-first-use mode, the first certificate we see becomes the pinned one
+    fn pin_certificate_on_first_use(&mut self) {
+        if !self.config.trust_on_first_use || self.config.pinned_cert_fingerprint.is_some() {
+            return;
+        }
+        
+        if let Some(fingerprint) = self.connection.server_cert_fingerprint() {
+            info!("Pinning server certificate {}", fingerprint);
+            self.config.pinned_cert_fingerprint = Some(fingerprint.to_string());
+            
+            if let Err(e) = config::save_config(&self.config) {
+                error!("Failed to save pinned certificate: {}", e);
+            }
+        }
+    }
+    
+    fn save_bookmarks(&mut self) {
+        if let Err(e) = config::save_config(&self.config) {
+            error!("Failed to save bookmarks: {}", e);
+            self.main_view.show_toast(format!("Failed to save bookmarks: {}", e));
+        }
+    }
+    
+    // Chunks arrive in order; once the last is in, ask where to save the export
+    fn receive_export_chunk(&mut self, transfer_id: Uuid, format: ExportFormat, chunk_index: u32, chunk_count: u32, data: Vec<u8>) {
+        let buffer = self.pending_exports.entry(transfer_id).or_default();
+        buffer.extend_from_slice(&data);
+        if chunk_index + 1 < chunk_count {
+            return;
+        }
+        
+        let data = self.pending_exports.remove(&transfer_id).unwrap_or_default();
+        let extension = format.extension();
+        let path = rfd::FileDialog::new()
+            .set_file_name(&format!("chat-history.{}", extension))
+            .add_filter(&extension.to_uppercase(), &[extension])
+            .save_file();
+        
+        // Cancelled
+        let Some(path) = path else {
+            return;
+        };
+        
+        match std::fs::write(&path, data) {
+            Ok(()) => self.main_view.show_toast(format!("Exported history to {}", path.display())),
+            Err(e) => {
+                error!("Failed to save export: {}", e);
+                self.main_view.show_toast(format!("Couldn't save the export: {}", e));
+            }
+        }
+    }
+    
+    fn render_settings(&mut self, ctx: &egui::Context) {
+        let settings_screen = self.settings_screen.get_or_insert_with(|| {
+            let mut settings_screen = SettingsScreen::new(self.config.clone());
+            settings_screen.set_server_cert_fingerprint(self.connection.server_cert_fingerprint().map(str::to_string));
+            settings_screen
+        });
+        
+        let mut open = true;
+        let settings = settings_screen.show(ctx, &mut open);
+        
+        // The loopback test only runs while settings are open
+        let loopback_change = match settings_screen.take_loopback_change() {
+            Some(enabled) => Some(enabled),
+            None if !open && settings_screen.is_loopback_test_on() => Some(false),
+            None => None,
+        };
+        if let Some(enabled) = loopback_change {
+            if let Err(e) = self.connection_mut().set_broadcast_self(enabled) {
+                error!("Failed to switch the loopback test: {}", e);
+                self.main_view.show_toast(format!("Couldn't switch the echo test: {}", e));
+            }
+        }
+        
+        if let Some(mut config) = settings {
+            // Bookmarks aren't edited in settings, and may have changed since it was opened
+            config.bookmarked_channels = self.config.bookmarked_channels.clone();
+            
+            if let Err(e) = config::save_config(&config) {
+                error!("Failed to save settings: {}", e);
+                self.main_view.show_toast(format!("Failed to save settings: {}", e));
+            }
+            if let Some(audio_manager) = &mut self.audio_manager {
+                // Switching device means reopening the streams
+                let devices = (config.audio_input_device.as_deref(), config.audio_output_device.as_deref());
+                if audio_manager.devices() != devices {
+                    audio_manager.set_devices(config.audio_input_device.clone(), config.audio_output_device.clone());
+                    if self.audio_active {
+                        audio_manager.stop_audio();
+                        if let Err(e) = audio_manager.start_audio() {
+                            error!("Failed to restart audio on the new device: {}", e);
+                            self.main_view.show_toast(format!("Couldn't switch audio device: {}", e));
+                            self.audio_active = false;
+                        }
+                    }
+                }
+                audio_manager.set_ptt_mode(config.ptt_configured());
+                audio_manager.set_output_volume(config.audio_volume);
+                audio_manager.set_jitter_buffer_ms(config.jitter_buffer_ms);
+                audio_manager.set_delay_compensation(config.delay_compensation_enabled);
+                audio_manager.set_noise_reduction(config.noise_reduction_enabled, config.noise_reduction_strength);
+                audio_manager.set_echo_cancellation(config.echo_cancellation, config.aec_delay_ms);
+                audio_manager.set_agc(config.agc_enabled, config.agc_target_dbfs);
+                audio_manager.set_vad(config.vad_enabled, config.vad_threshold, config.vad_hangover_frames);
+                audio_manager.set_voice_bitrate_kbps(config.voice_bitrate_kbps);
+                audio_manager.set_max_bitrate_kbps(config.max_upload_kbps);
+            }
+            if let Some(video_manager) = &self.video_manager {
+                video_manager.set_background_blur(config.background_blur_enabled, config.blur_strength);
+                video_manager.set_encoding(config.video_encoding);
+            }
+            if let Some(screen_manager) = &self.screen_manager {
+                screen_manager.set_encoding(config.video_encoding);
+            }
+            self.video_playback.lock().unwrap().set_decoder_backend(config.decoder_backend());
+            self.config = config;
+        }
+        
+        if !open {
+            // Settings shows theme changes as they're made; undo them if they weren't saved
+            style::apply_theme(ctx, self.config.theme, &self.config.custom_theme);
+            self.settings_screen = None;
+            self.main_view.close_settings();
+        }
+    }
+    
+    fn render_wizard(&mut self, ctx: &egui::Context) {
+        // Keep the window background behind the wizard
+        egui::CentralPanel::default().show(ctx, |_| {});
+        
+        let finished = self.wizard.as_mut().and_then(|wizard| wizard.show(ctx));
+        
+        if let Some(config) = finished {
+            if let Err(e) = config::save_config(&config) {
+                error!("Failed to save settings: {}", e);
+                self.status_message = Some(format!("Failed to save settings: {}", e));
+            }
+            
+            self.server_url = config.server_url.clone();
+            self.config = config;
+            self.wizard = None;
+        }
+    }
+    
+    // Ask whether to send crash reports from earlier runs, when there is somewhere to send them
+    fn render_crash_report_prompt(&mut self, ctx: &egui::Context) {
+        if self.pending_crash_reports == 0 || self.config.crash_report_url.is_none() {
+            return;
+        }
+        
+        egui::Window::new("Send Crash Reports?")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+            .show(ctx, |ui| {
+                ui.label(style::body_text(&format!(
+                    "Open Reverb crashed {} time(s) since reports were last sent. Send the crash reports to help fix the problem?",
+                    self.pending_crash_reports
+                )));
+                ui.add_space(10.0);
+                
+                ui.horizontal(|ui| {
+                    if ui.button("Send").clicked() {
+                        if let Err(e) = CrashReporter::submit_pending(&self.config) {
+                            error!("Failed to submit crash reports: {}", e);
+                            self.status_message = Some(format!("Failed to send crash reports: {}", e));
+                        }
+                        self.pending_crash_reports = 0;
+                    }
+                    
+                    if ui.button("Not Now").clicked() {
+                        self.pending_crash_reports = 0;
+                    }
+                    
+                    if ui.button("Delete").clicked() {
+                        CrashReporter::discard_pending();
+                        self.pending_crash_reports = 0;
+                    }
+                });
+            });
+    }
+    
+    // Only needed while sending both voice and video
+    fn send_sync_hint_if_due(&mut self) {
+        if self.last_sync_hint.elapsed() < SYNC_HINT_INTERVAL {
+            return;
+        }
+        
+        let pts = match (&self.audio_manager, &self.video_manager) {
+            (Some(audio), Some(video)) if self.audio_active && self.video_active => (audio.last_pts_ms(), video.last_pts_ms()),
+            _ => return,
+        };
+        
+        self.last_sync_hint = Instant::now();
+        if let Err(e) = self.connection_mut().send_sync_hint(pts.0, pts.1) {
+            error!("Failed to send sync hint: {}", e);
+        }
+    }
+    
+    fn render_totp_dialog(&mut self, ctx: &egui::Context) {
+        let submitted = match &mut self.totp_dialog {
+            Some(dialog) => dialog.show(ctx),
+            None => return,
+        };
+        
+        if let Some((user_id, code)) = submitted {
+            if let Err(e) = self.connection_mut().send_totp_code(user_id, code) {
+                error!("Failed to send TOTP code: {}", e);
+                self.status_message = Some(format!("Login error: {}", e));
+            }
+        }
+    }
+    
+    fn connect_and_login(&mut self) {
+        // Connect to server
+        match Arc::get_mut(&mut self.connection).unwrap().connect_with_config(&self.server_url, &self.config) {
+            Ok(_) => {
+                info!("Connected to server at {}", self.server_url);
+                self.status_message = Some("Connected to server".to_string());
+                self.pin_certificate_on_first_use();
+                
+                // Pick up where a dropped connection left off, as long as it's the same user and server
+                let token = self
+                    .saved_session
+                    .as_ref()
+                    .filter(|saved| saved.server_url == self.server_url && saved.name == self.name)
+                    .map(|saved| saved.token.clone());
+                
+                match token {
+                    Some(token) => match Arc::get_mut(&mut self.connection).unwrap().resume_session(&token) {
+                        Ok(_) => {
+                            info!("Resuming session for user: {}", self.name);
+                            self.resuming_session = true;
+                        }
+                        Err(e) => {
+                            error!("Failed to resume session: {}", e);
+                            self.status_message = Some(format!("Login error: {}", e));
+                        }
+                    },
+                    None => self.login(),
+                }
+            }
+            Err(e) => {
+                error!("Failed to connect: {}", e);
+                self.status_message = Some(format!("Connection error: {}", e));
+            }
+        }
+    }
+    
+    fn login(&mut self) {
+        if self.name.is_empty() {
+            return;
+        }
+        
+        match Arc::get_mut(&mut self.connection).unwrap().login(&self.name, &self.password) {
+            Ok(_) => {
+                info!("Login request sent for user: {}", self.name);
+                self.status_message = Some(format!("Login request sent for user: {}", self.name));
+            }
+            Err(e) => {
+                error!("Failed to login: {}", e);
+                self.status_message = Some(format!("Login error: {}", e));
+            }
+        }
+    }
+    
+    // Say so while the connection is getting back to the server after it dropped
+    fn show_reconnect_state(&mut self) {
+        let state = self.connection.reconnect_state();
+        if state == self.reconnect_state {
+            return;
+        }
+        
+        let message = match state {
+            ReconnectState::WaitingRetry { attempt, wake_at } => {
+                let secs = wake_at.saturating_duration_since(Instant::now()).as_secs_f32().ceil();
+                Some(format!("Connection lost, reconnecting in {}s (attempt {})", secs, attempt + 1))
+            }
+            ReconnectState::Reconnecting { .. } => Some("Reconnecting...".to_string()),
+            ReconnectState::Idle => match self.reconnect_state {
+                ReconnectState::Reconnecting { .. } if self.connection.is_connected() => Some("Reconnected to server".to_string()),
+                ReconnectState::WaitingRetry { .. } if !self.connection.is_connected() => Some("Couldn't reconnect to the server".to_string()),
+                _ => None,
+            },
+        };
+        
+        if let Some(message) = message {
+            self.status_message = Some(message);
+        }
+        self.reconnect_state = state;
+    }
+    
+    fn disconnect(&mut self) {
+        // Stop any active media first
+        self.stop_all_media();
+        self.totp_dialog = None;
+        self.saved_session = None;
+        self.resuming_session = false;
+        
+        // Disconnect from server
+        self.connection_mut().disconnect();
+        self.main_view = MainView::new();
+        self.main_view.set_video_playback(Arc::clone(&self.video_playback));
+        self.status_message = Some("Disconnected from server".to_string());
+        info!("Disconnected from server");
+    }
+    
+    // Warn when the server's models may not match ours, and tell it so it can be logged
+    fn check_schema_version(&mut self, server_version: u32) {
+        let warning = match SchemaCompatibility::check(server_version, SCHEMA_VERSION).warning() {
+            Some(warning) => warning,
+            None => return,
+        };
+        
+        warn!("Server schema version is {}, ours is {}", server_version, SCHEMA_VERSION);
+        self.main_view.show_toast(warning.to_string());
+        
+        let mismatch = open_reverb_common::protocol::Message::SchemaVersionMismatch { server_version, client_version: SCHEMA_VERSION };
+        if let Err(e) = self.connection.get_sender().send(mismatch) {
+            error!("Failed to report schema version mismatch: {}", e);
+        }
+    }
+    
+    // Channels deleted on the server, leaving the one we're in if it's among them
+    fn remove_channels(&mut self, channel_ids: Vec<Uuid>) {
+        if self.connection.get_current_channel_id().is_some_and(|id| channel_ids.contains(&id)) {
+            self.stop_all_media();
+            self.connection_mut().set_current_channel_id(None);
+            self.main_view.show_toast("The channel you were in was deleted".to_string());
+        }
+        self.main_view.remove_channels(&channel_ids);
+    }
+    
+    // Takes effect straight away if we're already sending voice
+    fn set_audio_quality(&mut self, quality: AudioQuality) {
+        self.audio_quality = quality;
+        if let Some(audio_manager) = &self.audio_manager {
+            audio_manager.set_audio_quality(quality);
+        }
+    }
+    
     fn toggle_audio(&mut self) {
         if let Some(user_id) = self.connection.get_user_id() {
             if self.audio_active {
@@ -112,7 +948,27 @@ impl DemoApp {
                 // Start audio
                 if let Some(channel_id) = self.connection.get_current_channel_id() {
                     if self.audio_manager.is_none() {
-                        self.audio_manager = Some(AudioManager::new(user_id, channel_id, self.connection.clone()));
+                        let audio_manager = AudioManager::new(user_id, channel_id, self.connection.clone(), self.config.audio_send_buffer_frames);
+                        for (user_id, volume) in self.main_view.user_volumes() {
+                            audio_manager.set_user_volume(user_id, volume);
+                        }
+                        self.audio_manager = Some(audio_manager);
+                    }
+                    
+                    let ptt_mode = self.config.ptt_configured();
+                    if let Some(audio_manager) = &mut self.audio_manager {
+                        audio_manager.set_devices(self.config.audio_input_device.clone(), self.config.audio_output_device.clone());
+                        audio_manager.set_ptt_mode(ptt_mode);
+                        audio_manager.set_output_volume(self.config.audio_volume);
+                        audio_manager.set_jitter_buffer_ms(self.config.jitter_buffer_ms);
+                        audio_manager.set_delay_compensation(self.config.delay_compensation_enabled);
+                        audio_manager.set_noise_reduction(self.config.noise_reduction_enabled, self.config.noise_reduction_strength);
+                        audio_manager.set_echo_cancellation(self.config.echo_cancellation, self.config.aec_delay_ms);
+                        audio_manager.set_agc(self.config.agc_enabled, self.config.agc_target_dbfs);
+                        audio_manager.set_vad(self.config.vad_enabled, self.config.vad_threshold, self.config.vad_hangover_frames);
+                        audio_manager.set_voice_bitrate_kbps(self.config.voice_bitrate_kbps);
+                        audio_manager.set_max_bitrate_kbps(self.config.max_upload_kbps);
+                        audio_manager.set_audio_quality(self.audio_quality);
                     }
                     
                     if let Some(audio_manager) = &mut self.audio_manager {
@@ -136,6 +992,77 @@ impl DemoApp {
         }
     }
     
+    // Cut back outgoing video while the connection sends more than the upload limit. The video
+    // bitrate recovers by itself once it's under.
+    fn throttle_upload_if_needed(&mut self) {
+        if !self.connection.bandwidth().should_throttle(self.config.max_upload_kbps) {
+            return;
+        }
+        
+        info!("Over the {}kbps upload limit, lowering video bitrate", self.config.max_upload_kbps.unwrap_or_default());
+        for manager in [&self.video_manager, &self.screen_manager].into_iter().flatten() {
+            manager.signal_congestion();
+        }
+    }
+    
+    // Blur and pause the camera while the window is unfocused, if the user asked for that
+    // Send the microphone while the push-to-talk key is held. Focus moving away counts as letting
+    // go, since the release would never arrive.
+    fn update_push_to_talk(&mut self, ctx: &egui::Context, focused: bool) {
+        let (Some(audio_manager), Some(key)) = (&self.audio_manager, &self.config.ptt_key) else {
+            return;
+        };
+        if !audio_manager.is_ptt_mode() {
+            return;
+        }
+        
+        if !focused {
+            audio_manager.release_ptt();
+            return;
+        }
+        
+        // Typing the key into a text field doesn't count
+        let typing = ctx.wants_keyboard_input();
+        let presses: Vec<bool> = ctx.input(|i| {
+            i.events
+                .iter()
+                .filter_map(|event| match event {
+                    egui::Event::Key { key: pressed_key, pressed, repeat: false, .. } if pressed_key.name() == key => Some(*pressed),
+                    _ => None,
+                })
+                .collect()
+        });
+        
+        for pressed in presses {
+            if !pressed {
+                audio_manager.release_ptt();
+            } else if !typing {
+                audio_manager.press_ptt();
+            }
+        }
+    }
+    
+    fn update_privacy_mode(&mut self, focused: bool) {
+        let enabled = self.config.privacy_mode_on_focus_loss && self.video_active;
+        let Some(active) = self.privacy_mode.update(enabled, focused) else {
+            return;
+        };
+        
+        if let Some(video_manager) = &self.video_manager {
+            video_manager.set_privacy_mode(active);
+        }
+        if let Some(user_id) = self.connection.get_user_id() {
+            self.main_view.set_video_paused(user_id, active);
+        }
+        
+        if let Some(channel_id) = self.connection.get_current_channel_id() {
+            let quality = if active { VideoQuality::Paused } else { VideoQuality::Normal };
+            if let Err(e) = self.connection_mut().send_video_quality(channel_id, quality) {
+                error!("Failed to send video quality: {}", e);
+            }
+        }
+    }
+    
     fn toggle_video(&mut self) {
         if let Some(user_id) = self.connection.get_user_id() {
             if self.video_active {
@@ -149,16 +1076,20 @@ impl DemoApp {
                 // Start video
                 if let Some(channel_id) = self.connection.get_current_channel_id() {
                     if self.video_manager.is_none() {
-                        self.video_manager = Some(VideoManager::new(user_id, channel_id, self.connection.clone(), CaptureType::Camera));
+                        self.video_manager = Some(VideoManager::new(
+                            user_id,
+                            channel_id,
+                            self.connection.clone(),
+                            CaptureType::Camera,
+                            self.config.preferred_video_codec,
+                            self.config.video_send_buffer_frames,
+                        ));
                     }
                     
                     if let Some(video_manager) = &mut self.video_manager {
-                        // Initialize GStreamer if needed
-                        if let Err(e) = video_manager.initialize() {
-                            error!("Failed to initialize video: {}", e);
-                            self.status_message = Some(format!("Failed to initialize video: {}", e));
-                            return;
-                        }
+                        video_manager.set_background_blur(self.config.background_blur_enabled, self.config.blur_strength);
+                        video_manager.set_encoding(self.config.video_encoding);
+                        video_manager.set_format(self.config.selected_video_resolution, self.config.selected_video_framerate);
                         
                         match video_manager.start_camera() {
                             Ok(_) => {
@@ -180,6 +1111,38 @@ impl DemoApp {
         }
     }
     
+    // The window is fullscreen while the selector is up, so it covers the whole monitor
+    fn render_region_selector(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        let Some(selector) = &mut self.region_selector else {
+            return;
+        };
+        if !frame.info().window_info.fullscreen {
+            frame.set_fullscreen(true);
+        }
+        
+        let origin = frame.info().window_info.position.unwrap_or(egui::Pos2::ZERO);
+        let Some(selection) = selector.select_region(ctx, origin) else {
+            return;
+        };
+        self.region_selector = None;
+        frame.set_fullscreen(false);
+        
+        self.screen_region = match selection {
+            RegionSelection::Region(region) => Some(region),
+            RegionSelection::WholeScreen => None,
+            RegionSelection::Cancelled => return,
+        };
+        if let Some(screen_manager) = &mut self.screen_manager {
+            screen_manager.set_region(self.screen_region);
+        }
+        
+        // Capture starts over to pick up the new region
+        if self.screen_active {
+            self.toggle_screen_sharing();
+            self.toggle_screen_sharing();
+        }
+    }
+    
     fn toggle_screen_sharing(&mut self) {
         if let Some(user_id) = self.connection.get_user_id() {
             if self.screen_active {
@@ -193,16 +1156,21 @@ impl DemoApp {
                 // Start screen sharing
                 if let Some(channel_id) = self.connection.get_current_channel_id() {
                     if self.screen_manager.is_none() {
-                        self.screen_manager = Some(VideoManager::new(user_id, channel_id, self.connection.clone(), CaptureType::Screen));
+                        self.screen_manager = Some(VideoManager::new(
+                            user_id,
+                            channel_id,
+                            self.connection.clone(),
+                            CaptureType::Screen,
+                            self.config.preferred_video_codec,
+                            self.config.video_send_buffer_frames,
+                        ));
                     }
                     
                     if let Some(screen_manager) = &mut self.screen_manager {
-                        // Initialize GStreamer if needed
-                        if let Err(e) = screen_manager.initialize() {
-                            error!("Failed to initialize screen sharing: {}", e);
-                            self.status_message = Some(format!("Failed to initialize screen sharing: {}", e));
-                            return;
-                        }
+                        screen_manager.set_encoding(self.config.video_encoding);
+                        screen_manager.set_format(self.config.selected_video_resolution, self.config.selected_video_framerate);
+                        screen_manager.set_screen(self.config.screen_index);
+                        screen_manager.set_region(self.screen_region);
                         
                         match screen_manager.start_screen_sharing() {
                             Ok(_) => {
@@ -224,29 +1192,64 @@ impl DemoApp {
         }
     }
     
+    fn toggle_recording(&mut self) {
+        match &mut self.audio_manager {
+            Some(audio_manager) if self.audio_active => {
+                if audio_manager.is_recording() {
+                    audio_manager.stop_recording();
+                    self.status_message = Some("Recording stopped".to_string());
+                } else {
+                    audio_manager.start_recording();
+                    self.status_message = Some("Waiting for the channel to consent to recording".to_string());
+                }
+            }
+            _ => {
+                self.status_message = Some("Start audio before recording".to_string());
+            }
+        }
+    }
+    
+    // Closing a pane's window puts the video back in the grid
+    fn render_detached_panes(&mut self, ctx: &egui::Context) {
+        let main_view = &self.main_view;
+        let video_playback = &self.video_playback;
+        
+        self.detached_panes.retain(|user_id, pane| {
+            let title = main_view.username(*user_id).unwrap_or("Video").to_string();
+            pane.show(ctx, video_playback, &title)
+        });
+    }
+    
     fn stop_all_media(&mut self) {
         // Stop audio
-        if self.audio_active && self.audio_manager.is_some() {
-            self.audio_manager.as_mut().unwrap().stop_audio();
+        if let Some(audio_manager) = self.audio_manager.as_mut().filter(|_| self.audio_active) {
+            audio_manager.stop_audio();
             self.audio_active = false;
         }
         
         // Stop video
-        if self.video_active && self.video_manager.is_some() {
-            self.video_manager.as_mut().unwrap().stop();
+        if let Some(video_manager) = self.video_manager.as_mut().filter(|_| self.video_active) {
+            video_manager.stop();
             self.video_active = false;
         }
         
         // Stop screen sharing
-        if self.screen_active && self.screen_manager.is_some() {
-            self.screen_manager.as_mut().unwrap().stop();
+        if let Some(screen_manager) = self.screen_manager.as_mut().filter(|_| self.screen_active) {
+            screen_manager.stop();
             self.screen_active = false;
         }
+        
+        // Popped out videos belong to the channel being left
+        self.detached_panes.clear();
+        
+        self.audio_manager = None;
+        self.video_manager = None;
+        self.screen_manager = None;
     }
 }
 
 impl eframe::App for DemoApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         // Process messages from the server by cloning the Arc
         let messages = {
             let connection = Arc::clone(&self.connection);
@@ -258,9 +1261,89 @@ impl eframe::App for DemoApp {
             info!("Received message: {:?}", message);
             self.handle_message(message);
         }
+        self.show_reconnect_state();
+        
+        if self.connection.is_connected() {
+            self.connection_mut().ping_if_due();
+            self.send_sync_hint_if_due();
+        }
+        
+        let focused = ctx.input(|i| i.raw.focused);
+        self.update_privacy_mode(focused);
+        self.update_push_to_talk(ctx, focused);
+        self.throttle_upload_if_needed();
+        
+        if let Some(audio_manager) = &self.audio_manager {
+            for rms in audio_manager.take_waveform_samples() {
+                self.main_view.push_waveform_sample(rms);
+                if let Some(settings_screen) = &mut self.settings_screen {
+                    settings_screen.push_waveform_sample(rms);
+                }
+            }
+        }
         
         // Request continuous repaints for message processing
         ctx.request_repaint_after(Duration::from_millis(100));
+        
+        self.render_crash_report_prompt(ctx);
+        
+        // Once logged in and the server has described itself, show the full client
+        if self.connection.get_user_id().is_some() && self.main_view.has_server_info() {
+            if let Some(message) = self.status_message.take() {
+                self.main_view.show_toast(message);
+            }
+            
+            let recording = self.audio_manager.as_ref().is_some_and(|a| a.is_recording());
+            self.main_view.set_media_state(self.audio_active, self.video_active, self.screen_active, recording);
+            self.main_view.set_transcription_enabled(self.config.transcription_enabled);
+            self.main_view.set_waveform_color(self.config.waveform_color);
+            self.main_view.set_detached_users(self.detached_panes.keys().copied().collect());
+            self.main_view.set_bookmarks(self.config.bookmarks_for_server(&self.server_url));
+            self.main_view.set_latency(self.connection.get_ping_latency());
+            let bandwidth = self.connection.bandwidth();
+            self.main_view.set_bandwidth_kbps(bandwidth.upload_kbps(), bandwidth.download_kbps());
+            self.main_view.set_gain_reduction_db(
+                self.audio_manager.as_ref().filter(|_| self.audio_active && self.config.agc_enabled).map(AudioManager::gain_reduction_db),
+            );
+            self.main_view.set_jitter_ms(self.media_timing.max_jitter_ms());
+            self.main_view.set_self_speaking(self.audio_manager.as_ref().is_some_and(AudioManager::is_speaking));
+            self.main_view.decay_audio_levels();
+            self.main_view.set_buffer_stats(
+                self.audio_manager.as_ref().map(AudioManager::buffer_stats),
+                self.video_manager.as_ref().map(VideoManager::buffer_stats),
+            );
+            
+            let mut quality = ConnectionQuality::default();
+            if let Some(audio_manager) = &self.audio_manager {
+                audio_manager.update_quality(&mut quality);
+            }
+            self.video_playback.lock().unwrap().update_quality(&mut quality);
+            self.main_view.set_connection_quality(quality);
+            
+            let action = egui::CentralPanel::default()
+                .show(ctx, |ui| self.main_view.ui(ui))
+                .inner;
+            
+            self.render_detached_panes(ctx);
+            self.render_region_selector(ctx, frame);
+            
+            if let Some(action) = action {
+                self.handle_main_view_action(action);
+            }
+            
+            if self.main_view.is_showing_settings() {
+                self.render_settings(ctx);
+            }
+            return;
+        }
+        
+        if self.wizard.is_some() {
+            self.render_wizard(ctx);
+            return;
+        }
+        
+        self.render_totp_dialog(ctx);
+        
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.vertical_centered(|ui| {
                 ui.add_space(50.0);
@@ -273,50 +1356,31 @@ impl eframe::App for DemoApp {
                 
                 ui.label(style::body_text("Username:"));
                 ui.text_edit_singleline(&mut self.name);
+                // Checked here too so the user finds out before connecting
+                let username_error = (!self.name.is_empty()).then(|| validate_username(&self.name).err()).flatten();
+                if let Some(error) = &username_error {
+                    ui.label(style::error_text(error));
+                }
                 ui.add_space(10.0);
                 
                 ui.label(style::body_text("Password:"));
                 ui.add(egui::TextEdit::singleline(&mut self.password).password(true));
                 ui.add_space(20.0);
                 
-                if ui.button(if self.connection.is_connected() { "Disconnect" } else { "Connect" }).clicked() {
+                let can_connect = self.connection.is_connected() || username_error.is_none();
+                if ui.add_enabled(can_connect, egui::Button::new(if self.connection.is_connected() { "Disconnect" } else { "Connect" })).clicked() {
                     if self.connection.is_connected() {
-                        // Stop any active media first
-                        self.stop_all_media();
-                        
-                        // Disconnect from server
-                        Arc::get_mut(&mut self.connection).unwrap().disconnect();
-                        self.status_message = Some("Disconnected from server".to_string());
-                        info!("Disconnected from server");
+                        self.disconnect();
                     } else {
-                        // Connect to server
-                        match Arc::get_mut(&mut self.connection).unwrap().connect(&self.server_url) {
-                            Ok(_) => {
-                                info!("Connected to server at {}", self.server_url);
-                                self.status_message = Some("Connected to server".to_string());
-                                
-                                // Login
-                                if !self.name.is_empty() {
-                                    match Arc::get_mut(&mut self.connection).unwrap().login(&self.name, &self.password) {
-                                        Ok(_) => {
-                                            info!("Login request sent for user: {}", self.name);
-                                            self.status_message = Some(format!("Login request sent for user: {}", self.name));
-                                        }
-                                        Err(e) => {
-                                            error!("Failed to login: {}", e);
-                                            self.status_message = Some(format!("Login error: {}", e));
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error!("Failed to connect: {}", e);
-                                self.status_message = Some(format!("Connection error: {}", e));
-                            }
-                        }
+                        self.connect_and_login();
                     }
                 }
                 
+                if !self.connection.is_connected() {
+                    ui.add_space(10.0);
+                    self.connection_test.ui(ui, &self.server_url, &self.config);
+                }
+                
                 // Status message
                 if let Some(message) = &self.status_message {
                     ui.add_space(10.0);
@@ -363,6 +1427,11 @@ impl eframe::App for DemoApp {
                         if ui.button(if self.screen_active { "Stop Sharing" } else { "Share Screen" }).clicked() {
                             self.toggle_screen_sharing();
                         }
+                        
+                        let recording = self.audio_manager.as_ref().is_some_and(|a| a.is_recording());
+                        if ui.button(if recording { "Stop Recording" } else { "Record" }).clicked() {
+                            self.toggle_recording();
+                        }
                     });
                     
                     // Show active media status
@@ -1,38 +1,799 @@
+#[cfg(feature = "aec")]
+pub mod aec;
+pub mod agc;
+pub mod encoder;
+pub mod jitter;
+#[cfg(feature = "test-audio")]
+pub mod mock_host;
+pub mod noise_reduce;
+pub mod vad;
+
 use anyhow::Result;
-use crossbeam_channel::{Receiver, Sender};
-use std::sync::{mpsc, atomic::{AtomicBool, Ordering}, Arc};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}, Arc};
 use std::time::Duration;
 use uuid::Uuid;
 
-use crate::connection::Connection;
+use open_reverb_common::models::AudioQuality;
+#[cfg(feature = "aec")]
+use self::aec::EchoCanceller;
+use self::agc::AutoGainController;
+use self::encoder::VoiceEncoder;
+use self::jitter::JitterBuffer;
+use self::noise_reduce::NoiseReducer;
+use self::vad::VoiceActivityDetector;
+use crate::connection::{Connection, ConnectionQuality};
+use crate::sync;
+use crate::ui::widgets::WAVEFORM_CAPACITY;
 
 // Sample rate and buffer size for audio processing
 const SAMPLE_RATE: u32 = 48000;
+#[cfg(feature = "audio")]
 const CHANNELS: u16 = 1;
 const BUFFER_SIZE: usize = 960; // 20ms at 48kHz
 
-#[cfg(feature = "audio")]
-use cpal::{self, traits::{DeviceTrait, HostTrait, StreamTrait}};
-#[cfg(feature = "audio")]
-use cpal::{InputCallbackInfo, OutputCallbackInfo, SampleFormat, Stream};
+// Smoothing factor for the mix latency moving average
+const MIX_LATENCY_SMOOTHING: f32 = 0.1;
+
+// Most of a user's voice held in their jitter buffer. Past this the oldest is skipped, so a
+// sender running faster than playback can't use up memory or pile up latency.
+const MAX_QUEUED_VOICE_MS: u64 = 500;
+
+// Voice buffered before a user starts playing, unless the settings say otherwise; 3 frames
+pub const DEFAULT_JITTER_BUFFER_MS: u32 = 60;
+// Deepest jitter buffer the settings offer, leaving room under MAX_QUEUED_VOICE_MS
+pub const MAX_JITTER_BUFFER_MS: u32 = 300;
+
+// Frames averaged into each waveform sample; 100ms, so the waveform gets 10 samples a second
+const WAVEFORM_SAMPLE_FRAMES: usize = 5;
+
+// Loudest a user's voice can be turned up to, as a multiple of how it arrives
+pub const MAX_USER_VOLUME: f32 = 2.0;
+
+// Lowest bitrate the upload limit can push voice down to
+const MIN_BITRATE_KBPS: u32 = 6;
+
+// Bitrate voice is sent at unless the settings or the channel ask for less
+pub const DEFAULT_VOICE_BITRATE_KBPS: u32 = 64;
+
+// Delay between playing and capturing that echo cancellation assumes unless it's told otherwise
+pub const DEFAULT_AEC_DELAY_MS: u32 = 50;
+
+// Longest delay between playing and capturing that echo cancellation can line up
+#[cfg(feature = "aec")]
+pub const MAX_AEC_DELAY_MS: u32 = 500;
+
+// Played output kept for echo cancellation, in ms; enough for the longest delay and a few frames
+#[cfg(feature = "aec")]
+const MAX_ECHO_REFERENCE_MS: u64 = 1000;
+
+#[cfg(feature = "audio")]
+use cpal::{self, traits::{DeviceTrait, HostTrait, StreamTrait}};
+#[cfg(feature = "audio")]
+use cpal::{InputCallbackInfo, OutputCallbackInfo, SampleFormat, Stream};
+#[cfg(feature = "test-audio")]
+use self::mock_host::{MockAudioHost, MockStream};
+
+// Names of the system's microphones, for picking one in settings
+pub fn input_device_names() -> Vec<String> {
+    #[cfg(feature = "audio")]
+    {
+        device_names(cpal::default_host().input_devices(), "microphones")
+    }
+    
+    #[cfg(not(feature = "audio"))]
+    {
+        vec!["Default Microphone".to_string(), "Headset Microphone".to_string()]
+    }
+}
+
+// Names of the system's speakers and headphones, for picking one in settings
+pub fn output_device_names() -> Vec<String> {
+    #[cfg(feature = "audio")]
+    {
+        device_names(cpal::default_host().output_devices(), "speakers")
+    }
+    
+    #[cfg(not(feature = "audio"))]
+    {
+        vec!["Default Speakers".to_string(), "Headphones".to_string()]
+    }
+}
+
+// A backend that can't list its devices right now (ALSA returns EWOULDBLOCK while another
+// program holds the card) gives an empty list, and devices whose names can't be read are left out
+#[cfg(feature = "audio")]
+fn device_names<I: Iterator<Item = cpal::Device>>(devices: Result<I, cpal::DevicesError>, kind: &str) -> Vec<String> {
+    match devices {
+        Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+        Err(e) => {
+            tracing::warn!("Couldn't list {}: {}", kind, e);
+            Vec::new()
+        }
+    }
+}
+
+// The named microphone, or the default one if there's no name or no device with it
+#[cfg(feature = "audio")]
+fn find_input_device(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device> {
+    let named_device = name.and_then(|name| host.input_devices().ok()?.find(|d| d.name().ok().as_deref() == Some(name)));
+    if let (Some(name), None) = (name, &named_device) {
+        tracing::warn!("Microphone {} not found, using the default", name);
+    }
+    
+    named_device.or_else(|| host.default_input_device()).ok_or_else(|| anyhow::anyhow!("No input device found"))
+}
+
+// The named speakers, or the default ones if there's no name or no device with it
+#[cfg(all(feature = "audio", not(feature = "test-audio")))]
+fn find_output_device(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device> {
+    let named_device = name.and_then(|name| host.output_devices().ok()?.find(|d| d.name().ok().as_deref() == Some(name)));
+    if let (Some(name), None) = (name, &named_device) {
+        tracing::warn!("Speakers {} not found, using the default", name);
+    }
+    
+    named_device.or_else(|| host.default_output_device()).ok_or_else(|| anyhow::anyhow!("No output device found"))
+}
+
+// How full a send buffer is, and how many frames it has turned away
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufferStats {
+    pub capacity: usize,
+    pub current_depth: usize,
+    pub dropped_frames: u64,
+}
+
+impl BufferStats {
+    pub fn of<T>(tx: &Sender<T>, dropped_frames: &AtomicU64) -> Self {
+        Self {
+            capacity: tx.capacity().unwrap_or(0),
+            current_depth: tx.len(),
+            dropped_frames: dropped_frames.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Queue a captured frame for sending. When the buffer is full the frame is dropped and counted.
+pub fn queue_frame<T>(tx: &Sender<T>, frame: T, dropped_frames: &AtomicU64) {
+    if let Err(TrySendError::Full(_)) = tx.try_send(frame) {
+        dropped_frames.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Capture settings, changed from the UI thread and read by the capture pipeline every frame
+struct CaptureSettings {
+    agc_enabled: AtomicBool,
+    // Bits of the AGC target level in dBFS (f32)
+    agc_target_dbfs: AtomicU32,
+    // Bits of the gain AGC applied to the last frame, in dB (f32); reported back to the UI
+    gain_reduction_db: AtomicU32,
+    // Bits of the noise reduction strength (f32); 0.0 while it's off
+    noise_reduction: AtomicU32,
+    // Quality of the channel we're sending to
+    audio_quality: parking_lot::Mutex<AudioQuality>,
+    // Most voice is sent at, in kbps, to stay under the upload limit; 0 when there's no limit
+    max_bitrate_kbps: AtomicU32,
+    // Bitrate picked in settings; the channel's applies when it's lower
+    voice_bitrate_kbps: AtomicU32,
+    // Only send frames with voice in them
+    vad_enabled: AtomicBool,
+    // Bits of the RMS (0.0 - 1.0, f32) a frame needs to count as voice
+    vad_threshold: AtomicU32,
+    vad_hangover_frames: AtomicU32,
+    // Whether the last frame had voice in it, or came within the hangover after one
+    speaking: AtomicBool,
+    // Cancel the echo of what's being played
+    #[cfg(feature = "aec")]
+    echo_cancellation: AtomicBool,
+    // Time between a sample being played and the microphone hearing it, in ms
+    #[cfg(feature = "aec")]
+    aec_delay_ms: AtomicU32,
+    // Samples (-1.0 - 1.0) played while echo cancellation is on, newest last. The output callback
+    // adds to it and the capture pipeline takes what's had time to reach the microphone.
+    #[cfg(feature = "aec")]
+    echo_reference: parking_lot::Mutex<VecDeque<f32>>,
+}
+
+impl CaptureSettings {
+    fn new() -> Self {
+        Self {
+            agc_enabled: AtomicBool::new(false),
+            agc_target_dbfs: AtomicU32::new(agc::DEFAULT_TARGET_DBFS.to_bits()),
+            gain_reduction_db: AtomicU32::new(0.0f32.to_bits()),
+            noise_reduction: AtomicU32::new(0.0f32.to_bits()),
+            audio_quality: parking_lot::Mutex::new(AudioQuality::default()),
+            max_bitrate_kbps: AtomicU32::new(0),
+            voice_bitrate_kbps: AtomicU32::new(DEFAULT_VOICE_BITRATE_KBPS),
+            vad_enabled: AtomicBool::new(false),
+            vad_threshold: AtomicU32::new(vad::DEFAULT_THRESHOLD.to_bits()),
+            vad_hangover_frames: AtomicU32::new(vad::DEFAULT_HANGOVER_FRAMES),
+            speaking: AtomicBool::new(false),
+            #[cfg(feature = "aec")]
+            echo_cancellation: AtomicBool::new(false),
+            #[cfg(feature = "aec")]
+            aec_delay_ms: AtomicU32::new(DEFAULT_AEC_DELAY_MS),
+            #[cfg(feature = "aec")]
+            echo_reference: parking_lot::Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+// Keep what the output callback just played, for the echo canceller
+#[cfg(feature = "aec")]
+fn record_played(settings: &CaptureSettings, played: &[i16]) {
+    if !settings.echo_cancellation.load(Ordering::Relaxed) {
+        return;
+    }
+    
+    let mut reference = settings.echo_reference.lock();
+    reference.extend(played.iter().map(|s| *s as f32 / i16::MAX as f32));
+    let excess = reference.len().saturating_sub((SAMPLE_RATE as u64 * MAX_ECHO_REFERENCE_MS / 1000) as usize);
+    reference.drain(..excess);
+}
+
+// The `len` samples played `delay` samples before the newest, lined up with a frame just captured;
+// silence where nothing was playing. Anything older has been heard already, so it's dropped.
+#[cfg(feature = "aec")]
+fn take_echo_reference(played: &mut VecDeque<f32>, len: usize, delay: usize) -> Vec<f32> {
+    let end = played.len().saturating_sub(delay);
+    let start = end.saturating_sub(len);
+    
+    let mut reference = vec![0.0; len - (end - start)];
+    reference.extend(played.drain(..end).skip(start));
+    reference
+}
+
+// State of the capture pipeline, one per input stream
+struct CapturePipeline {
+    #[cfg(feature = "aec")]
+    echo_canceller: EchoCanceller,
+    agc: AutoGainController,
+    reducer: NoiseReducer,
+    vad: VoiceActivityDetector,
+    encoder: VoiceEncoder,
+}
+
+impl CapturePipeline {
+    fn new(settings: &CaptureSettings) -> Self {
+        Self {
+            #[cfg(feature = "aec")]
+            echo_canceller: EchoCanceller::new(aec::DEFAULT_FILTER_LEN),
+            agc: AutoGainController::new(f32::from_bits(settings.agc_target_dbfs.load(Ordering::Relaxed))),
+            reducer: NoiseReducer::new(0.0),
+            vad: VoiceActivityDetector::new(
+                f32::from_bits(settings.vad_threshold.load(Ordering::Relaxed)),
+                settings.vad_hangover_frames.load(Ordering::Relaxed),
+            ),
+            encoder: VoiceEncoder::new(*settings.audio_quality.lock()),
+        }
+    }
+    
+    // The encoded frame, or None when voice activity detection is on and there's no voice in it.
+    // The echo of the speakers is taken out first, then noise is suppressed, so neither can hold
+    // detection open or be turned up by AGC.
+    fn capture(&mut self, samples: &mut [i16], settings: &CaptureSettings) -> Option<Vec<u8>> {
+        #[cfg(feature = "aec")]
+        if settings.echo_cancellation.load(Ordering::Relaxed) {
+            let delay = (SAMPLE_RATE as u64 * settings.aec_delay_ms.load(Ordering::Relaxed) as u64 / 1000) as usize;
+            let reference = take_echo_reference(&mut settings.echo_reference.lock(), samples.len(), delay);
+            self.echo_canceller.process_pcm(samples, &reference);
+        }
+        
+        let strength = f32::from_bits(settings.noise_reduction.load(Ordering::Relaxed));
+        if strength > 0.0 {
+            self.reducer.set_strength(strength);
+            self.reducer.process_pcm(samples);
+        }
+        
+        self.vad.configure(
+            f32::from_bits(settings.vad_threshold.load(Ordering::Relaxed)),
+            settings.vad_hangover_frames.load(Ordering::Relaxed),
+        );
+        let speaking = self.vad.process_pcm(samples);
+        settings.speaking.store(speaking, Ordering::Relaxed);
+        
+        if !speaking && settings.vad_enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(self.process(samples, settings))
+    }
+    
+    // Level the captured frame with AGC, if it's on, then encode it with the channel's audio quality
+    fn process(&mut self, samples: &mut [i16], settings: &CaptureSettings) -> Vec<u8> {
+        let gain_reduction_db = if settings.agc_enabled.load(Ordering::Relaxed) {
+            self.agc.set_target_dbfs(f32::from_bits(settings.agc_target_dbfs.load(Ordering::Relaxed)));
+            self.agc.process_pcm(samples);
+            self.agc.gain_reduction_db()
+        } else {
+            0.0
+        };
+        settings.gain_reduction_db.store(gain_reduction_db.to_bits(), Ordering::Relaxed);
+        
+        let mut quality = *settings.audio_quality.lock();
+        quality.bitrate_kbps = quality.bitrate_kbps.min(settings.voice_bitrate_kbps.load(Ordering::Relaxed).max(MIN_BITRATE_KBPS));
+        let max_bitrate_kbps = settings.max_bitrate_kbps.load(Ordering::Relaxed);
+        if max_bitrate_kbps > 0 {
+            quality.bitrate_kbps = quality.bitrate_kbps.min(max_bitrate_kbps.max(MIN_BITRATE_KBPS));
+        }
+        
+        if self.encoder.configure(quality) {
+            let quality = self.encoder.quality();
+            tracing::info!("Encoding voice at {}Hz, {} channel(s), {}kbps", quality.sample_rate, quality.channels, quality.bitrate_kbps);
+        }
+        
+        self.encoder.encode(samples, SAMPLE_RATE)
+    }
+}
+
+// RMS of 16-bit samples, scaled to 0.0 - 1.0
+fn rms(samples: &[i16]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    
+    let sum: f32 = samples.iter().map(|s| (*s as f32 / i16::MAX as f32).powi(2)).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+// Mean of the squared samples of a frame of 16-bit little-endian PCM, scaled to 0.0 - 1.0
+fn mean_square(frame: &[u8]) -> f32 {
+    let samples = frame.len() / 2;
+    if samples == 0 {
+        return 0.0;
+    }
+    
+    let sum: f32 = frame
+        .chunks_exact(2)
+        .map(|b| {
+            let sample = i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32;
+            sample * sample
+        })
+        .sum();
+    
+    sum / samples as f32
+}
+
+// Holds back a user's decoded PCM by a fixed number of samples
+pub struct DelayBuffer {
+    samples: VecDeque<i16>,
+    delay_samples: usize,
+}
+
+impl DelayBuffer {
+    pub fn new(delay_ms: u32) -> Self {
+        let mut buffer = Self {
+            samples: VecDeque::new(),
+            delay_samples: 0,
+        };
+        buffer.set_delay_ms(delay_ms);
+        buffer
+    }
+    
+    pub fn set_delay_ms(&mut self, delay_ms: u32) {
+        let delay_samples = (delay_ms as u64 * SAMPLE_RATE as u64 / 1000) as usize;
+        
+        // Pad with silence when the delay grows, drop the oldest samples when it shrinks
+        if delay_samples > self.delay_samples {
+            for _ in 0..delay_samples - self.delay_samples {
+                self.samples.push_front(0);
+            }
+        } else {
+            let excess = (self.delay_samples - delay_samples).min(self.samples.len());
+            self.samples.drain(..excess);
+        }
+        
+        self.delay_samples = delay_samples;
+    }
+    
+    pub fn push(&mut self, samples: &[i16]) {
+        self.samples.extend(samples.iter().copied());
+    }
+    
+    // Samples left to play, counting the delay
+    pub fn queued_samples(&self) -> usize {
+        self.samples.len()
+    }
+    
+    // Take the next sample, or silence if nothing is buffered
+    pub fn pop(&mut self) -> i16 {
+        self.samples.pop_front().unwrap_or(0)
+    }
+}
+
+// Mixes the voice streams of all users in the channel into a single output
+pub struct AudioMixer {
+    buffers: HashMap<Uuid, DelayBuffer>,
+    
+    // Voice from each user put back in order, waiting to go into their delay buffer
+    jitter_buffers: HashMap<Uuid, JitterBuffer>,
+    jitter_buffer_ms: u32,
+    
+    // Network delay estimated for each user
+    user_delays: HashMap<Uuid, u32>,
+    delay_compensation_enabled: bool,
+    
+    // Exponential moving average of the delay added by compensation
+    mix_latency_ms: f32,
+    
+    // Gain for each user's voice (0.0 - 2.0), for those not at 1.0
+    volumes: HashMap<Uuid, f32>,
+    // Gain applied to the mix as a whole (0.0 - 1.0)
+    output_volume: f32,
+}
+
+impl AudioMixer {
+    pub fn new(delay_compensation_enabled: bool) -> Self {
+        Self {
+            buffers: HashMap::new(),
+            jitter_buffers: HashMap::new(),
+            jitter_buffer_ms: DEFAULT_JITTER_BUFFER_MS,
+            user_delays: HashMap::new(),
+            delay_compensation_enabled,
+            mix_latency_ms: 0.0,
+            volumes: HashMap::new(),
+            output_volume: 1.0,
+        }
+    }
+    
+    // Queue a frame by its sequence number. It's played in order once the user's jitter buffer
+    // has filled; if it arrives after its turn, it's dropped, and silence was played instead.
+    pub fn add_frame(&mut self, user_id: Uuid, seq: u32, samples: Vec<i16>) {
+        if !self.buffers.contains_key(&user_id) {
+            let compensation = self.compensation_ms(user_id);
+            self.buffers.insert(user_id, DelayBuffer::new(compensation));
+        }
+        
+        let depth_samples = (self.jitter_buffer_ms as u64 * SAMPLE_RATE as u64 / 1000) as usize;
+        self.jitter_buffers
+            .entry(user_id)
+            .or_insert_with(|| JitterBuffer::new((MAX_QUEUED_VOICE_MS * SAMPLE_RATE as u64 / 1000) as usize / BUFFER_SIZE, depth_samples))
+            .push(seq, samples);
+    }
+    
+    // How much voice to buffer before playing each user, to ride out network jitter
+    pub fn set_jitter_buffer_ms(&mut self, jitter_buffer_ms: u32) {
+        self.jitter_buffer_ms = jitter_buffer_ms;
+        
+        let depth_samples = (jitter_buffer_ms as u64 * SAMPLE_RATE as u64 / 1000) as usize;
+        for jitter_buffer in self.jitter_buffers.values_mut() {
+            jitter_buffer.set_depth_samples(depth_samples);
+        }
+    }
+    
+    // Record the estimated arrival delay for a user, as reported by their jitter buffer
+    fn set_user_delay(&mut self, user_id: Uuid, delay_ms: u32) {
+        self.user_delays.insert(user_id, delay_ms);
+        self.realign();
+    }
+    
+    pub fn remove_user(&mut self, user_id: Uuid) {
+        self.buffers.remove(&user_id);
+        self.jitter_buffers.remove(&user_id);
+        self.user_delays.remove(&user_id);
+        self.realign();
+    }
+    
+    // Kept when the user leaves, so they're just as loud if they come back
+    pub fn set_user_volume(&mut self, user_id: Uuid, volume: f32) {
+        let volume = volume.clamp(0.0, MAX_USER_VOLUME);
+        if volume == 1.0 {
+            self.volumes.remove(&user_id);
+        } else {
+            self.volumes.insert(user_id, volume);
+        }
+    }
+    
+    pub fn set_output_volume(&mut self, volume: f32) {
+        self.output_volume = volume.clamp(0.0, 1.0);
+    }
+    
+    pub fn set_delay_compensation_enabled(&mut self, enabled: bool) {
+        self.delay_compensation_enabled = enabled;
+        self.realign();
+    }
+    
+    pub fn mix(&mut self, output: &mut [i16]) {
+        // Enough of everyone's voice to fill the output, from those whose jitter buffers are ready
+        for (user_id, jitter_buffer) in self.jitter_buffers.iter_mut() {
+            let Some(buffer) = self.buffers.get_mut(user_id) else {
+                continue;
+            };
+            
+            while buffer.queued_samples() < output.len() {
+                match jitter_buffer.pop() {
+                    Some(frame) => buffer.push(&frame),
+                    None => break,
+                }
+            }
+        }
+        
+        // A jitter buffer's delay changes when it starts playing again
+        let changed: Vec<(Uuid, u32)> = self.jitter_buffers
+            .iter()
+            .map(|(user_id, jitter_buffer)| (*user_id, (jitter_buffer.delay_samples() as u64 * 1000 / SAMPLE_RATE as u64) as u32))
+            .filter(|(user_id, delay_ms)| self.user_delays.get(user_id) != Some(delay_ms))
+            .collect();
+        for (user_id, delay_ms) in changed {
+            self.set_user_delay(user_id, delay_ms);
+        }
+        
+        for sample in output.iter_mut() {
+            let mut sum: f32 = 0.0;
+            
+            for (user_id, buffer) in self.buffers.iter_mut() {
+                let volume = self.volumes.get(user_id).copied().unwrap_or(1.0);
+                sum += buffer.pop() as f32 * volume;
+            }
+            
+            *sample = (sum * self.output_volume).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+        
+        let added_latency = if self.delay_compensation_enabled { self.max_delay_ms() } else { 0 };
+        self.mix_latency_ms += (added_latency as f32 - self.mix_latency_ms) * MIX_LATENCY_SMOOTHING;
+    }
+    
+    pub fn mix_latency_ms(&self) -> f32 {
+        self.mix_latency_ms
+    }
+    
+    pub fn update_quality(&self, quality: &mut ConnectionQuality) {
+        quality.mix_latency_ms = self.mix_latency_ms();
+    }
+    
+    fn max_delay_ms(&self) -> u32 {
+        self.user_delays.values().copied().max().unwrap_or(0)
+    }
+    
+    // Earlier-arriving users are held back so every stream lines up with the slowest one
+    fn compensation_ms(&self, user_id: Uuid) -> u32 {
+        if !self.delay_compensation_enabled {
+            return 0;
+        }
+        
+        let user_delay = self.user_delays.get(&user_id).copied().unwrap_or(0);
+        self.max_delay_ms() - user_delay.min(self.max_delay_ms())
+    }
+    
+    fn realign(&mut self) {
+        let compensations: Vec<(Uuid, u32)> = self.buffers
+            .keys()
+            .map(|user_id| (*user_id, self.compensation_ms(*user_id)))
+            .collect();
+        
+        for (user_id, compensation) in compensations {
+            if let Some(buffer) = self.buffers.get_mut(&user_id) {
+                buffer.set_delay_ms(compensation);
+            }
+        }
+    }
+}
+
+// Plays the microphone straight back through the speakers, so users can check their devices
+pub struct AudioLoopback {
+    active: Arc<AtomicBool>,
+    // Peak level of the most recent input, as f32 bits in the range 0.0 - 1.0
+    level: Arc<AtomicU32>,
+    
+    #[cfg(feature = "audio")]
+    streams: Vec<Stream>,
+    #[cfg(not(feature = "audio"))]
+    mock_audio_thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl AudioLoopback {
+    pub fn new() -> Self {
+        Self {
+            active: Arc::new(AtomicBool::new(false)),
+            level: Arc::new(AtomicU32::new(0)),
+            #[cfg(feature = "audio")]
+            streams: Vec::new(),
+            #[cfg(not(feature = "audio"))]
+            mock_audio_thread: None,
+        }
+    }
+    
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+    
+    pub fn level(&self) -> f32 {
+        f32::from_bits(self.level.load(Ordering::Relaxed))
+    }
+    
+    // Start the loopback on the named input device, or the default one
+    pub fn start(&mut self, input_device: Option<&str>) -> Result<()> {
+        if self.is_active() {
+            return Ok(());
+        }
+        
+        #[cfg(feature = "audio")]
+        {
+            let host = cpal::default_host();
+            
+            let input_device = find_input_device(&host, input_device)?;
+            let output_device = host.default_output_device().ok_or_else(|| {
+                anyhow::anyhow!("No output device found")
+            })?;
+            
+            // Captured samples waiting to be played back
+            let buffer = Arc::new(parking_lot::Mutex::new(VecDeque::<i16>::new()));
+            
+            match input_device.default_input_config()?.sample_format() {
+                SampleFormat::F32 => self.setup_input_stream::<f32>(&input_device, buffer.clone())?,
+                SampleFormat::I16 => self.setup_input_stream::<i16>(&input_device, buffer.clone())?,
+                SampleFormat::U16 => self.setup_input_stream::<u16>(&input_device, buffer.clone())?,
+            }
+            
+            match output_device.default_output_config()?.sample_format() {
+                SampleFormat::F32 => self.setup_output_stream::<f32>(&output_device, buffer)?,
+                SampleFormat::I16 => self.setup_output_stream::<i16>(&output_device, buffer)?,
+                SampleFormat::U16 => self.setup_output_stream::<u16>(&output_device, buffer)?,
+            }
+        }
+        
+        #[cfg(not(feature = "audio"))]
+        {
+            tracing::info!("Audio support is disabled, simulating a loopback on {}", input_device.unwrap_or("the default device"));
+            
+            let active = self.active.clone();
+            let level = self.level.clone();
+            
+            // Report a pulsing tone so the level meter has something to show
+            self.mock_audio_thread = Some(std::thread::spawn(move || {
+                let mut t: f32 = 0.0;
+                
+                while active.load(Ordering::SeqCst) {
+                    let peak = (t * 2.0).sin().abs() * 0.5;
+                    level.store(peak.to_bits(), Ordering::Relaxed);
+                    
+                    t += 0.02;
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            }));
+        }
+        
+        self.active.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+    
+    pub fn stop(&mut self) {
+        self.active.store(false, Ordering::SeqCst);
+        self.level.store(0, Ordering::Relaxed);
+        
+        #[cfg(feature = "audio")]
+        {
+            self.streams.clear();
+        }
+        
+        #[cfg(not(feature = "audio"))]
+        {
+            if let Some(handle) = self.mock_audio_thread.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+    
+    #[cfg(feature = "audio")]
+    fn setup_input_stream<T>(&mut self, device: &cpal::Device, buffer: Arc<parking_lot::Mutex<VecDeque<i16>>>) -> Result<()>
+    where
+        T: cpal::Sample + Send + 'static,
+    {
+        let config = cpal::StreamConfig {
+            channels: CHANNELS,
+            sample_rate: cpal::SampleRate(SAMPLE_RATE),
+            buffer_size: cpal::BufferSize::Fixed(BUFFER_SIZE as u32),
+        };
+        
+        let level = self.level.clone();
+        
+        let input_stream = device.build_input_stream(
+            &config,
+            move |data: &[T], _: &InputCallbackInfo| {
+                let mut buffer = buffer.lock();
+                let mut peak: i16 = 0;
+                
+                for sample in data {
+                    let value = sample.to_i16();
+                    peak = peak.max(value.saturating_abs());
+                    buffer.push_back(value);
+                }
+                
+                // Don't let the loopback fall further and further behind
+                let excess = buffer.len().saturating_sub(SAMPLE_RATE as usize / 2);
+                buffer.drain(..excess);
+                
+                level.store((peak as f32 / i16::MAX as f32).to_bits(), Ordering::Relaxed);
+            },
+            move |err| {
+                tracing::error!("Error in loopback input stream: {}", err);
+            },
+        )?;
+        
+        input_stream.play()?;
+        self.streams.push(input_stream);
+        
+        Ok(())
+    }
+    
+    #[cfg(feature = "audio")]
+    fn setup_output_stream<T>(&mut self, device: &cpal::Device, buffer: Arc<parking_lot::Mutex<VecDeque<i16>>>) -> Result<()>
+    where
+        T: cpal::Sample + Send + 'static,
+    {
+        let config = cpal::StreamConfig {
+            channels: CHANNELS,
+            sample_rate: cpal::SampleRate(SAMPLE_RATE),
+            buffer_size: cpal::BufferSize::Fixed(BUFFER_SIZE as u32),
+        };
+        
+        let output_stream = device.build_output_stream(
+            &config,
+            move |data: &mut [T], _: &OutputCallbackInfo| {
+                let mut buffer = buffer.lock();
+                
+                for sample in data.iter_mut() {
+                    *sample = T::from(&buffer.pop_front().unwrap_or(0));
+                }
+            },
+            move |err| {
+                tracing::error!("Error in loopback output stream: {}", err);
+            },
+        )?;
+        
+        output_stream.play()?;
+        self.streams.push(output_stream);
+        
+        Ok(())
+    }
+}
+
+impl Drop for AudioLoopback {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
 
 pub struct AudioManager {
     // State
     active: Arc<AtomicBool>,
+    // Set once the server confirms everyone in the channel has been notified
+    recording: Arc<AtomicBool>,
+    // With push-to-talk on, the microphone is only sent while the key is held
+    ptt_mode: bool,
+    // Whether captured frames are sent; always set unless push-to-talk is on
+    transmitting: Arc<AtomicBool>,
     
-    // Audio device streams
-    #[cfg(feature = "audio")]
+    // Audio device streams. The test-audio feature swaps the real devices for in-memory ones.
+    #[cfg(all(feature = "audio", not(feature = "test-audio")))]
     input_stream: Option<Stream>,
-    #[cfg(feature = "audio")]
+    #[cfg(all(feature = "audio", not(feature = "test-audio")))]
     output_streams: Vec<Stream>,
-    #[cfg(not(feature = "audio"))]
+    #[cfg(feature = "test-audio")]
+    mock_streams: Vec<MockStream>,
+    #[cfg(not(any(feature = "audio", feature = "test-audio")))]
     mock_audio_thread: Option<std::thread::JoinHandle<()>>,
-    #[cfg(not(feature = "audio"))]
-    mock_audio_stop: Option<mpsc::Sender<()>>,
+    #[cfg(not(any(feature = "audio", feature = "test-audio")))]
+    mock_audio_stop: Option<std::sync::mpsc::Sender<()>>,
+    // Sends captured frames to the server; joined when audio stops
+    sender_thread: Option<std::thread::JoinHandle<()>>,
     
     // Channels for audio data
     tx: Sender<Vec<u8>>,
     rx: Receiver<Vec<u8>>,
+    // Frames captured while the send buffer was full
+    dropped_frames: Arc<AtomicU64>,
+    // Capture time of the last frame sent, for sync hints
+    last_pts_ms: Arc<AtomicU64>,
+    // RMS levels of the microphone not yet collected by the waveform display
+    waveform_samples: Arc<parking_lot::Mutex<Vec<f32>>>,
+    // AGC, noise reduction and audio quality, picked up by the capture pipeline from the next frame
+    capture_settings: Arc<CaptureSettings>,
+    // Voice from the rest of the channel, waiting to be played
+    mixer: Arc<parking_lot::Mutex<AudioMixer>>,
+    // Names of the devices to use, or None for the system's defaults
+    input_device: Option<String>,
+    output_device: Option<String>,
     
     // User and channel info
     user_id: Uuid,
@@ -43,45 +804,238 @@ pub struct AudioManager {
 }
 
 impl AudioManager {
-    pub fn new(user_id: Uuid, channel_id: Uuid, connection: Arc<Connection>) -> Self {
-        let (tx, rx) = crossbeam_channel::bounded(10);
+    // `buffer_frames` is how many captured frames can wait to be sent before new ones are dropped
+    pub fn new(user_id: Uuid, channel_id: Uuid, connection: Arc<Connection>, buffer_frames: usize) -> Self {
+        let (tx, rx) = crossbeam_channel::bounded(buffer_frames.max(1));
         
         Self {
             active: Arc::new(AtomicBool::new(false)),
-            #[cfg(feature = "audio")]
+            recording: Arc::new(AtomicBool::new(false)),
+            ptt_mode: false,
+            transmitting: Arc::new(AtomicBool::new(true)),
+            #[cfg(all(feature = "audio", not(feature = "test-audio")))]
             input_stream: None,
-            #[cfg(feature = "audio")]
+            #[cfg(all(feature = "audio", not(feature = "test-audio")))]
             output_streams: Vec::new(),
-            #[cfg(not(feature = "audio"))]
+            #[cfg(feature = "test-audio")]
+            mock_streams: Vec::new(),
+            #[cfg(not(any(feature = "audio", feature = "test-audio")))]
             mock_audio_thread: None,
-            #[cfg(not(feature = "audio"))]
+            #[cfg(not(any(feature = "audio", feature = "test-audio")))]
             mock_audio_stop: None,
+            sender_thread: None,
             tx,
             rx,
+            dropped_frames: Arc::new(AtomicU64::new(0)),
+            last_pts_ms: Arc::new(AtomicU64::new(0)),
+            waveform_samples: Arc::new(parking_lot::Mutex::new(Vec::new())),
+            capture_settings: Arc::new(CaptureSettings::new()),
+            mixer: Arc::new(parking_lot::Mutex::new(AudioMixer::new(true))),
+            input_device: None,
+            output_device: None,
             user_id,
             channel_id,
             connection,
         }
     }
     
+    // RMS levels (0.0 - 1.0) measured since the last call, oldest first
+    pub fn take_waveform_samples(&self) -> Vec<f32> {
+        std::mem::take(&mut *self.waveform_samples.lock())
+    }
+    
+    // Cancel the echo of the speakers from the microphone, assuming sound takes `delay_ms` to get
+    // from one to the other. Only builds with the aec feature can; others leave it off.
+    pub fn set_echo_cancellation(&self, enabled: bool, delay_ms: u32) {
+        #[cfg(feature = "aec")]
+        {
+            self.capture_settings.aec_delay_ms.store(delay_ms.min(MAX_AEC_DELAY_MS), Ordering::Relaxed);
+            self.capture_settings.echo_cancellation.store(enabled, Ordering::Relaxed);
+            if !enabled {
+                self.capture_settings.echo_reference.lock().clear();
+            }
+        }
+        
+        #[cfg(not(feature = "aec"))]
+        if enabled {
+            tracing::warn!("Echo cancellation ({}ms) needs a build with the aec feature", delay_ms);
+        }
+    }
+    
+    // Takes effect from the next captured frame
+    pub fn set_noise_reduction(&self, enabled: bool, strength: f32) {
+        let strength = if enabled { strength.clamp(0.0, 1.0) } else { 0.0 };
+        self.capture_settings.noise_reduction.store(strength.to_bits(), Ordering::Relaxed);
+    }
+    
+    // Set when joining a channel, and when an admin changes the channel's quality
+    pub fn set_audio_quality(&self, quality: AudioQuality) {
+        *self.capture_settings.audio_quality.lock() = quality;
+    }
+    
+    // Keep voice under the upload limit, when there is one. Takes effect from the next captured frame.
+    pub fn set_max_bitrate_kbps(&self, max_bitrate_kbps: Option<u32>) {
+        self.capture_settings.max_bitrate_kbps.store(max_bitrate_kbps.unwrap_or(0), Ordering::Relaxed);
+    }
+    
+    // Takes effect from the next captured frame
+    pub fn set_voice_bitrate_kbps(&self, bitrate_kbps: u32) {
+        self.capture_settings.voice_bitrate_kbps.store(bitrate_kbps, Ordering::Relaxed);
+    }
+    
+    // Takes effect from the next captured frame
+    pub fn set_agc(&self, enabled: bool, target_dbfs: f32) {
+        self.capture_settings.agc_target_dbfs.store(target_dbfs.to_bits(), Ordering::Relaxed);
+        self.capture_settings.agc_enabled.store(enabled, Ordering::Relaxed);
+    }
+    
+    // Takes effect from the next captured frame
+    pub fn set_vad(&self, enabled: bool, threshold: f32, hangover_frames: u32) {
+        self.capture_settings.vad_threshold.store(threshold.to_bits(), Ordering::Relaxed);
+        self.capture_settings.vad_hangover_frames.store(hangover_frames, Ordering::Relaxed);
+        self.capture_settings.vad_enabled.store(enabled, Ordering::Relaxed);
+    }
+    
+    // Whether the microphone is picking up voice that's being sent, for the speaking indicator
+    pub fn is_speaking(&self) -> bool {
+        self.is_active() && self.transmitting.load(Ordering::Relaxed) && self.capture_settings.speaking.load(Ordering::Relaxed)
+    }
+    
+    // Gain AGC is taking off the microphone, in dB; negative while it's boosting it
+    pub fn gain_reduction_db(&self) -> f32 {
+        f32::from_bits(self.capture_settings.gain_reduction_db.load(Ordering::Relaxed))
+    }
+    
+    // Used from the next start_audio
+    pub fn set_devices(&mut self, input_device: Option<String>, output_device: Option<String>) {
+        self.input_device = input_device;
+        self.output_device = output_device;
+    }
+    
+    pub fn devices(&self) -> (Option<&str>, Option<&str>) {
+        (self.input_device.as_deref(), self.output_device.as_deref())
+    }
+    
+    // Turning push-to-talk on stops the microphone being sent until the key is pressed
+    pub fn set_ptt_mode(&mut self, enabled: bool) {
+        self.ptt_mode = enabled;
+        self.transmitting.store(!enabled, Ordering::SeqCst);
+    }
+    
+    pub fn is_ptt_mode(&self) -> bool {
+        self.ptt_mode
+    }
+    
+    pub fn press_ptt(&self) {
+        if self.ptt_mode {
+            self.transmitting.store(true, Ordering::SeqCst);
+        }
+    }
+    
+    pub fn release_ptt(&self) {
+        if self.ptt_mode {
+            self.transmitting.store(false, Ordering::SeqCst);
+        }
+    }
+    
+    // 1.0 plays a user's voice as it arrives; up to MAX_USER_VOLUME turns them up
+    pub fn set_user_volume(&self, user_id: Uuid, volume: f32) {
+        self.mixer.lock().set_user_volume(user_id, volume);
+    }
+    
+    pub fn remove_user(&self, user_id: Uuid) {
+        self.mixer.lock().remove_user(user_id);
+    }
+    
+    // Applied to everyone's voice after mixing
+    pub fn set_output_volume(&self, volume: f32) {
+        self.mixer.lock().set_output_volume(volume);
+    }
+    
+    // Queue voice from another user in the channel to be played, in `seq` order. Returns the
+    // frame's RMS level (0.0 - 1.0).
+    pub fn play_voice(&self, user_id: Uuid, seq: u32, data: &[u8]) -> f32 {
+        let quality = *self.capture_settings.audio_quality.lock();
+        let samples = encoder::decode(data, quality, SAMPLE_RATE);
+        let level = rms(&samples);
+        self.mixer.lock().add_frame(user_id, seq, samples);
+        level
+    }
+    
+    // Voice to buffer from each user before playing it; more rides out worse jitter, with more delay
+    pub fn set_jitter_buffer_ms(&self, jitter_buffer_ms: u32) {
+        self.mixer.lock().set_jitter_buffer_ms(jitter_buffer_ms.min(MAX_JITTER_BUFFER_MS));
+    }
+    
+    // Hold back users whose voice arrives sooner, so everyone plays in step
+    pub fn set_delay_compensation(&self, enabled: bool) {
+        self.mixer.lock().set_delay_compensation_enabled(enabled);
+    }
+    
+    pub fn update_quality(&self, quality: &mut ConnectionQuality) {
+        self.mixer.lock().update_quality(quality);
+    }
+    
+    pub fn buffer_stats(&self) -> BufferStats {
+        BufferStats::of(&self.tx, &self.dropped_frames)
+    }
+    
+    // Capture time of the last voice frame sent, in milliseconds since the Unix epoch
+    pub fn last_pts_ms(&self) -> u64 {
+        self.last_pts_ms.load(Ordering::Relaxed)
+    }
+    
     pub fn is_active(&self) -> bool {
         self.active.load(Ordering::SeqCst)
     }
     
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::SeqCst)
+    }
+    
+    // Ask the server to announce a recording. It only begins once the server
+    // confirms with a RecordingConsent message of its own.
+    pub fn start_recording(&mut self) {
+        let request = open_reverb_common::protocol::Message::RecordingConsent {
+            recording_user_id: self.user_id,
+            recording_started: true,
+        };
+        
+        if let Err(e) = self.connection.get_sender().send(request) {
+            tracing::error!("Failed to send recording request: {}", e);
+        }
+    }
+    
+    pub fn stop_recording(&mut self) {
+        self.recording.store(false, Ordering::SeqCst);
+        
+        let notice = open_reverb_common::protocol::Message::RecordingConsent {
+            recording_user_id: self.user_id,
+            recording_started: false,
+        };
+        
+        if let Err(e) = self.connection.get_sender().send(notice) {
+            tracing::error!("Failed to send recording stopped message: {}", e);
+        }
+    }
+    
+    // Called when the server grants or withdraws permission to record
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording.store(recording, Ordering::SeqCst);
+    }
+    
     pub fn start_audio(&mut self) -> Result<()> {
         if self.is_active() {
             return Ok(());
         }
         
-        #[cfg(feature = "audio")]
+        #[cfg(all(feature = "audio", not(feature = "test-audio")))]
         {
             // Initialize audio with cpal
             let host = cpal::default_host();
             
             // Set up input device
-            let input_device = host.default_input_device().ok_or_else(|| {
-                anyhow::anyhow!("No input device found")
-            })?;
+            let input_device = find_input_device(&host, self.input_device.as_deref())?;
             
             let input_config = input_device.default_input_config()?;
             
@@ -90,13 +1044,10 @@ impl AudioManager {
                 SampleFormat::F32 => self.setup_input_stream::<f32>(&input_device)?,
                 SampleFormat::I16 => self.setup_input_stream::<i16>(&input_device)?,
                 SampleFormat::U16 => self.setup_input_stream::<u16>(&input_device)?,
-                format => return Err(anyhow::anyhow!("Unsupported sample format: {:?}", format)),
             }
             
             // Set up output device
-            let output_device = host.default_output_device().ok_or_else(|| {
-                anyhow::anyhow!("No output device found")
-            })?;
+            let output_device = find_output_device(&host, self.output_device.as_deref())?;
             
             let output_config = output_device.default_output_config()?;
             
@@ -105,34 +1056,86 @@ impl AudioManager {
                 SampleFormat::F32 => self.setup_output_stream::<f32>(&output_device)?,
                 SampleFormat::I16 => self.setup_output_stream::<i16>(&output_device)?,
                 SampleFormat::U16 => self.setup_output_stream::<u16>(&output_device)?,
-                format => return Err(anyhow::anyhow!("Unsupported sample format: {:?}", format)),
             }
         }
         
-        #[cfg(not(feature = "audio"))]
+        #[cfg(feature = "test-audio")]
+        {
+            let host = MockAudioHost;
+            let input_device = host.default_input_device().ok_or_else(|| anyhow::anyhow!("No input device found"))?;
+            let output_device = host.default_output_device().ok_or_else(|| anyhow::anyhow!("No output device found"))?;
+            
+            let tx = self.tx.clone();
+            let dropped_frames = Arc::clone(&self.dropped_frames);
+            let capture_settings = Arc::clone(&self.capture_settings);
+            let transmitting = Arc::clone(&self.transmitting);
+            let mut pipeline = CapturePipeline::new(&capture_settings);
+            let mut input_stream = input_device.build_input_stream(SAMPLE_RATE, BUFFER_SIZE, move |data: &[f32]| {
+                if !transmitting.load(Ordering::Relaxed) {
+                    return;
+                }
+                let mut samples: Vec<i16> = data.iter().map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).collect();
+                if let Some(frame) = pipeline.capture(&mut samples, &capture_settings) {
+                    queue_frame(&tx, frame, &dropped_frames);
+                }
+            })?;
+            
+            let mixer = Arc::clone(&self.mixer);
+            #[cfg(feature = "aec")]
+            let capture_settings = Arc::clone(&self.capture_settings);
+            let mut mixed = vec![0i16; BUFFER_SIZE];
+            let mut output_stream = output_device.build_output_stream(SAMPLE_RATE, BUFFER_SIZE, move |data: &mut [f32]| {
+                mixed.resize(data.len(), 0);
+                mixer.lock().mix(&mut mixed);
+                #[cfg(feature = "aec")]
+                record_played(&capture_settings, &mixed);
+                for (sample, mixed) in data.iter_mut().zip(mixed.iter()) {
+                    *sample = *mixed as f32 / i16::MAX as f32;
+                }
+            })?;
+            
+            input_stream.play()?;
+            output_stream.play()?;
+            self.mock_streams = vec![input_stream, output_stream];
+        }
+        
+        #[cfg(not(any(feature = "audio", feature = "test-audio")))]
         {
             // Mock audio implementation for builds without audio support
-            let (stop_tx, stop_rx) = mpsc::channel::<()>();
+            let (stop_tx, stop_rx) = std::sync::mpsc::channel::<()>();
             self.mock_audio_stop = Some(stop_tx);
             
             let tx = self.tx.clone();
+            let dropped_frames = Arc::clone(&self.dropped_frames);
+            let capture_settings = Arc::clone(&self.capture_settings);
+            let transmitting = Arc::clone(&self.transmitting);
+            let mixer = Arc::clone(&self.mixer);
             
             // Create a thread that generates mock audio data
             let handle = std::thread::spawn(move || {
                 let sample_interval = Duration::from_millis(20); // 20ms chunks
-                let mut sample_data = vec![0u8; BUFFER_SIZE * 2]; // 16-bit samples
+                let mut samples = vec![0i16; BUFFER_SIZE];
+                let mut pipeline = CapturePipeline::new(&capture_settings);
+                let mut output = vec![0i16; BUFFER_SIZE];
                 
                 loop {
                     // Generate a simple sine wave
-                    for i in 0..BUFFER_SIZE {
+                    for (i, sample) in samples.iter_mut().enumerate() {
                         let t = i as f32 / SAMPLE_RATE as f32;
                         let value = (t * 440.0 * 2.0 * std::f32::consts::PI).sin() * 0.1;
-                        let sample = (value * 32767.0) as i16;
-                        sample_data[i * 2] = (sample & 0xFF) as u8;
-                        sample_data[i * 2 + 1] = ((sample >> 8) & 0xFF) as u8;
+                        *sample = (value * 32767.0) as i16;
+                    }
+                    
+                    if transmitting.load(Ordering::Relaxed) {
+                        if let Some(frame) = pipeline.capture(&mut samples, &capture_settings) {
+                            queue_frame(&tx, frame, &dropped_frames);
+                        }
                     }
                     
-                    let _ = tx.try_send(sample_data.clone());
+                    // There's nowhere to play received voice, but it's taken as if it had been
+                    mixer.lock().mix(&mut output);
+                    #[cfg(feature = "aec")]
+                    record_played(&capture_settings, &output);
                     
                     // Check if we should stop
                     if stop_rx.try_recv().is_ok() {
@@ -153,20 +1156,54 @@ impl AudioManager {
         let channel_id = self.channel_id;
         let active = self.active.clone();
         
-        std::thread::spawn(move || {
-            active.store(true, Ordering::SeqCst);
-            
+        let last_pts_ms = Arc::clone(&self.last_pts_ms);
+        let waveform_samples = Arc::clone(&self.waveform_samples);
+        
+        // Set before the thread starts, so stopping straight away can't be missed
+        self.active.store(true, Ordering::SeqCst);
+        
+        self.sender_thread = Some(std::thread::spawn(move || {
             // Send "voice started" message
             let voice_started = open_reverb_common::protocol::Message::VoiceStarted { user_id };
             if let Err(e) = connection.get_sender().send(voice_started) {
                 tracing::error!("Failed to send voice started message: {}", e);
             }
             
+            let mut seq = Connection::initial_seq();
+            
+            // Mean square of the frames so far towards the next waveform sample
+            let mut waveform_frames = Vec::with_capacity(WAVEFORM_SAMPLE_FRAMES);
+            
+            // Wakes up regularly while no audio is flowing, to notice being stopped
             while active.load(Ordering::SeqCst) {
-                if let Ok(data) = rx.recv() {
-                    if let Err(e) = connection.get_sender().send(open_reverb_common::protocol::Message::VoiceData { user_id, channel_id, data }) {
+                if let Ok(data) = rx.recv_timeout(Duration::from_millis(100)) {
+                    waveform_frames.push(mean_square(&data));
+                    if waveform_frames.len() == WAVEFORM_SAMPLE_FRAMES {
+                        let rms = (waveform_frames.iter().sum::<f32>() / WAVEFORM_SAMPLE_FRAMES as f32).sqrt();
+                        waveform_frames.clear();
+                        
+                        // Capped in case nothing is collecting them
+                        let mut samples = waveform_samples.lock();
+                        if samples.len() < WAVEFORM_CAPACITY {
+                            samples.push(rms);
+                        }
+                    }
+                    
+                    let captured_at_ms = sync::now_millis();
+                    last_pts_ms.store(captured_at_ms, Ordering::Relaxed);
+                    
+                    if let Err(e) = connection.get_sender().send(open_reverb_common::protocol::Message::VoiceData {
+                        user_id,
+                        channel_id,
+                        seq,
+                        captured_at_ms,
+                        // Set when the frame is encrypted
+                        epoch: 0,
+                        data,
+                    }) {
                         tracing::error!("Failed to send voice data: {}", e);
                     }
+                    seq = seq.wrapping_add(1);
                 }
             }
             
@@ -175,7 +1212,7 @@ impl AudioManager {
             if let Err(e) = connection.get_sender().send(voice_stopped) {
                 tracing::error!("Failed to send voice stopped message: {}", e);
             }
-        });
+        }));
         
         Ok(())
     }
@@ -183,13 +1220,22 @@ impl AudioManager {
     pub fn stop_audio(&mut self) {
         self.active.store(false, Ordering::SeqCst);
         
-        #[cfg(feature = "audio")]
+        if self.is_recording() {
+            self.stop_recording();
+        }
+        
+        #[cfg(all(feature = "audio", not(feature = "test-audio")))]
         {
             self.input_stream = None;
             self.output_streams.clear();
         }
         
-        #[cfg(not(feature = "audio"))]
+        #[cfg(feature = "test-audio")]
+        {
+            self.mock_streams.clear();
+        }
+        
+        #[cfg(not(any(feature = "audio", feature = "test-audio")))]
         {
             if let Some(stop_tx) = &self.mock_audio_stop {
                 let _ = stop_tx.send(());
@@ -201,9 +1247,13 @@ impl AudioManager {
             
             self.mock_audio_stop = None;
         }
+        
+        if let Some(handle) = self.sender_thread.take() {
+            let _ = handle.join();
+        }
     }
     
-    #[cfg(feature = "audio")]
+    #[cfg(all(feature = "audio", not(feature = "test-audio")))]
     fn setup_input_stream<T>(&mut self, device: &cpal::Device) -> Result<()>
     where
         T: cpal::Sample + Send + 'static,
@@ -215,22 +1265,25 @@ impl AudioManager {
         };
         
         let tx = self.tx.clone();
+        let dropped_frames = Arc::clone(&self.dropped_frames);
+        let capture_settings = Arc::clone(&self.capture_settings);
+        let transmitting = Arc::clone(&self.transmitting);
+        let mut pipeline = CapturePipeline::new(&capture_settings);
         
         let input_stream = device.build_input_stream(
             &config,
             move |data: &[T], _: &InputCallbackInfo| {
-                // Convert samples to i16 bytes
-                let bytes: Vec<u8> = data
-                    .iter()
-                    .map(|sample| {
-                        let value = sample.to_i16();
-                        [value as u8, (value >> 8) as u8]
-                    })
-                    .flatten()
-                    .collect();
+                // Push-to-talk is on and the key isn't held
+                if !transmitting.load(Ordering::Relaxed) {
+                    return;
+                }
+                
+                let mut samples: Vec<i16> = data.iter().map(|sample| sample.to_i16()).collect();
                 
                 // Send bytes to sender task
-                let _ = tx.try_send(bytes);
+                if let Some(frame) = pipeline.capture(&mut samples, &capture_settings) {
+                    queue_frame(&tx, frame, &dropped_frames);
+                }
             },
             move |err| {
                 tracing::error!("Error in input stream: {}", err);
@@ -243,7 +1296,7 @@ impl AudioManager {
         Ok(())
     }
     
-    #[cfg(feature = "audio")]
+    #[cfg(all(feature = "audio", not(feature = "test-audio")))]
     fn setup_output_stream<T>(&mut self, device: &cpal::Device) -> Result<()>
     where
         T: cpal::Sample + Send + 'static,
@@ -254,15 +1307,20 @@ impl AudioManager {
             buffer_size: cpal::BufferSize::Fixed(BUFFER_SIZE as u32),
         };
         
-        // This is a placeholder for handling incoming audio data
-        // In a real implementation, we would have a buffer for each user
-        // and mix them together for output
+        let mixer = Arc::clone(&self.mixer);
+        #[cfg(feature = "aec")]
+        let capture_settings = Arc::clone(&self.capture_settings);
+        let mut mixed = vec![0i16; BUFFER_SIZE];
+        
         let output_stream = device.build_output_stream(
             &config,
             move |data: &mut [T], _: &OutputCallbackInfo| {
-                // Fill buffer with silence for now
-                for sample in data.iter_mut() {
-                    *sample = T::from(&0i16);
+                mixed.resize(data.len(), 0);
+                mixer.lock().mix(&mut mixed);
+                #[cfg(feature = "aec")]
+                record_played(&capture_settings, &mixed);
+                for (sample, mixed) in data.iter_mut().zip(mixed.iter()) {
+                    *sample = T::from(mixed);
                 }
             },
             move |err| {
@@ -275,4 +1333,372 @@ impl AudioManager {
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use open_reverb_common::models::AudioCodec;
+    
+    #[test]
+    fn join_ack_reconfigures_encoder_on_next_frame() {
+        let manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::new(Connection::new()), 4);
+        let mut pipeline = CapturePipeline::new(&manager.capture_settings);
+        let mut frame = vec![0i16; BUFFER_SIZE];
+        
+        // Compressed to 16kHz at 4 bits a sample, plus the header
+        let before = pipeline.process(&mut frame, &manager.capture_settings);
+        assert_eq!(before.len(), 9 + BUFFER_SIZE / 3 / 2);
+        
+        // As the app does on JoinChannelAck
+        let quality = AudioQuality { sample_rate: 16_000, channels: 1, bitrate_kbps: 24, codec: AudioCodec::Adpcm };
+        manager.set_audio_quality(quality);
+        
+        // 24kbps only fits 8kHz
+        let after = pipeline.process(&mut frame, &manager.capture_settings);
+        assert_eq!(pipeline.encoder.quality(), quality);
+        assert_eq!(after.len(), 9 + BUFFER_SIZE / 6 / 2);
+    }
+    
+    #[test]
+    fn voice_bitrate_setting_caps_the_channel_bitrate() {
+        let manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::new(Connection::new()), 4);
+        let mut pipeline = CapturePipeline::new(&manager.capture_settings);
+        let mut frame = vec![0i16; BUFFER_SIZE];
+        
+        manager.set_audio_quality(AudioQuality { bitrate_kbps: 128, ..AudioQuality::default() });
+        manager.set_voice_bitrate_kbps(32);
+        pipeline.process(&mut frame, &manager.capture_settings);
+        assert_eq!(pipeline.encoder.quality().bitrate_kbps, 32);
+        
+        manager.set_voice_bitrate_kbps(256);
+        pipeline.process(&mut frame, &manager.capture_settings);
+        assert_eq!(pipeline.encoder.quality().bitrate_kbps, 128);
+    }
+    
+    #[test]
+    fn voice_activity_detection_holds_back_quiet_frames() {
+        let manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::new(Connection::new()), 4);
+        let mut pipeline = CapturePipeline::new(&manager.capture_settings);
+        let mut quiet = vec![100i16; BUFFER_SIZE];
+        let mut loud = vec![10_000i16; BUFFER_SIZE];
+        
+        // Sent regardless while it's off, though the speaking indicator still follows the voice
+        assert!(pipeline.capture(&mut quiet, &manager.capture_settings).is_some());
+        assert!(!manager.capture_settings.speaking.load(Ordering::Relaxed));
+        
+        manager.set_vad(true, vad::DEFAULT_THRESHOLD, 1);
+        assert!(pipeline.capture(&mut quiet, &manager.capture_settings).is_none());
+        assert!(pipeline.capture(&mut loud, &manager.capture_settings).is_some());
+        assert!(manager.capture_settings.speaking.load(Ordering::Relaxed));
+        // One frame of hangover
+        assert!(pipeline.capture(&mut quiet, &manager.capture_settings).is_some());
+        assert!(pipeline.capture(&mut quiet, &manager.capture_settings).is_none());
+    }
+    
+    #[test]
+    fn steady_noise_doesnt_hold_voice_detection_open() {
+        let manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::new(Connection::new()), 4);
+        let mut pipeline = CapturePipeline::new(&manager.capture_settings);
+        manager.set_vad(true, vad::DEFAULT_THRESHOLD, vad::DEFAULT_HANGOVER_FRAMES);
+        manager.set_noise_reduction(true, 1.0);
+        
+        // A hum well over the detection threshold
+        let hum: Vec<i16> = (0..BUFFER_SIZE)
+            .map(|i| ((i as f32 / SAMPLE_RATE as f32 * 100.0 * std::f32::consts::TAU).sin() * 3_000.0) as i16)
+            .collect();
+        let sent: Vec<bool> = (0..20).map(|_| pipeline.capture(&mut hum.clone(), &manager.capture_settings).is_some()).collect();
+        
+        // Once the noise floor has been found and the hangover is over
+        assert!(sent[10..].iter().all(|sent| !sent), "{:?}", sent);
+    }
+    
+    #[cfg(feature = "aec")]
+    #[test]
+    fn echo_reference_lines_up_with_the_delay() {
+        let mut played: VecDeque<f32> = (0..10).map(|s| s as f32).collect();
+        
+        assert_eq!(take_echo_reference(&mut played, 3, 2), vec![5.0, 6.0, 7.0]);
+        assert_eq!(played, VecDeque::from(vec![8.0, 9.0]));
+        
+        // Nothing has been playing long enough to be heard yet
+        assert_eq!(take_echo_reference(&mut played, 3, 2), vec![0.0; 3]);
+        assert_eq!(played.len(), 2);
+    }
+    
+    #[cfg(feature = "aec")]
+    #[test]
+    fn played_voice_is_cancelled_from_the_microphone() {
+        let manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::new(Connection::new()), 4);
+        let mut pipeline = CapturePipeline::new(&manager.capture_settings);
+        // One frame between playing and capturing
+        manager.set_echo_cancellation(true, 20);
+        
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut previous = vec![0i16; BUFFER_SIZE];
+        let mut residual = Vec::new();
+        for _ in 0..100 {
+            let played: Vec<i16> = (0..BUFFER_SIZE)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    (seed >> 48) as i16 / 4
+                })
+                .collect();
+            record_played(&manager.capture_settings, &played);
+            
+            // The speakers, heard quieter through the room
+            let mut mic: Vec<i16> = previous.iter().map(|s| s / 3).collect();
+            pipeline.capture(&mut mic, &manager.capture_settings);
+            residual = mic;
+            previous = played;
+        }
+        
+        let rms = |samples: &[i16]| (samples.iter().map(|s| (*s as f32).powi(2)).sum::<f32>() / samples.len() as f32).sqrt();
+        let echo: Vec<i16> = previous.iter().map(|s| s / 3).collect();
+        assert!(rms(&residual) < rms(&echo) / 10.0, "{} left of {}", rms(&residual), rms(&echo));
+    }
+    
+    #[test]
+    fn mixer_sums_voices_and_clips() {
+        let mut mixer = AudioMixer::new(false);
+        mixer.set_jitter_buffer_ms(0);
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        mixer.add_frame(alice, 0, vec![1_000, 30_000, -30_000]);
+        mixer.add_frame(bob, 0, vec![2_000, 10_000, -10_000]);
+        
+        let mut output = [0i16; 4];
+        mixer.mix(&mut output);
+        assert_eq!(output, [3_000, i16::MAX, i16::MIN, 0]);
+    }
+    
+    #[test]
+    fn user_volumes_apply_before_the_output_volume() {
+        let mut mixer = AudioMixer::new(false);
+        mixer.set_jitter_buffer_ms(0);
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        mixer.set_user_volume(alice, 0.5);
+        mixer.set_user_volume(bob, 5.0);
+        mixer.add_frame(alice, 0, vec![1_000, 1_000]);
+        mixer.add_frame(bob, 0, vec![1_000, 20_000]);
+        
+        let mut output = [0i16; 2];
+        mixer.mix(&mut output);
+        // Bob is capped at double, and the sum clipped
+        assert_eq!(output, [2_500, i16::MAX]);
+        
+        mixer.set_output_volume(0.5);
+        mixer.add_frame(alice, 1, vec![1_000]);
+        mixer.add_frame(bob, 1, vec![1_000]);
+        let mut output = [0i16; 1];
+        mixer.mix(&mut output);
+        assert_eq!(output, [1_250]);
+    }
+    
+    #[test]
+    fn fast_senders_only_queue_so_much_voice() {
+        let mut mixer = AudioMixer::new(false);
+        let user_id = Uuid::new_v4();
+        
+        // Two seconds of voice, with nothing played
+        for seq in 0..100 {
+            mixer.add_frame(user_id, seq, vec![seq as i16; BUFFER_SIZE]);
+        }
+        
+        // The newest is kept, and nothing more
+        let queued = (MAX_QUEUED_VOICE_MS * SAMPLE_RATE as u64 / 1000) as usize;
+        let mut output = vec![0i16; queued + BUFFER_SIZE];
+        mixer.mix(&mut output);
+        assert_eq!((output[0], output[queued - 1], output[queued]), (75, 99, 0));
+    }
+    
+    #[test]
+    fn full_send_buffers_drop_and_count_new_frames() {
+        let manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::new(Connection::new()), 3);
+        
+        for frame in 0..5u8 {
+            queue_frame(&manager.tx, vec![frame], &manager.dropped_frames);
+        }
+        let stats = manager.buffer_stats();
+        assert_eq!((stats.capacity, stats.current_depth, stats.dropped_frames), (3, 3, 2));
+        
+        // The frames that made it in are the oldest, and sending one makes room for another
+        assert_eq!(manager.rx.try_recv().unwrap(), vec![0]);
+        queue_frame(&manager.tx, vec![5], &manager.dropped_frames);
+        queue_frame(&manager.tx, vec![6], &manager.dropped_frames);
+        let stats = manager.buffer_stats();
+        assert_eq!((stats.current_depth, stats.dropped_frames), (3, 3));
+        assert_eq!(manager.rx.try_iter().collect::<Vec<_>>(), [vec![1], vec![2], vec![5]]);
+        
+        // A buffer always holds at least one frame
+        let manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::new(Connection::new()), 0);
+        queue_frame(&manager.tx, vec![0], &manager.dropped_frames);
+        queue_frame(&manager.tx, vec![1], &manager.dropped_frames);
+        let stats = manager.buffer_stats();
+        assert_eq!((stats.capacity, stats.current_depth, stats.dropped_frames), (1, 1, 1));
+    }
+    
+    #[test]
+    fn voice_waits_for_the_jitter_buffer_and_plays_in_order() {
+        let mut mixer = AudioMixer::new(false);
+        let user_id = Uuid::new_v4();
+        let frame = |value: i16| vec![value; BUFFER_SIZE];
+        
+        // Two of the three frames the default buffer holds
+        mixer.add_frame(user_id, 1, frame(2));
+        mixer.add_frame(user_id, 0, frame(1));
+        let mut output = vec![0i16; BUFFER_SIZE];
+        mixer.mix(&mut output);
+        assert!(output.iter().all(|s| *s == 0));
+        
+        // Frame 3 never arrives
+        mixer.add_frame(user_id, 2, frame(3));
+        mixer.add_frame(user_id, 4, frame(5));
+        let mut output = vec![0i16; BUFFER_SIZE * 5];
+        mixer.mix(&mut output);
+        let played: Vec<i16> = output.chunks(BUFFER_SIZE).map(|frame| frame[0]).collect();
+        assert_eq!(played, vec![1, 2, 3, 0, 5]);
+    }
+    
+    #[test]
+    fn streams_arriving_sooner_are_held_back_to_line_up() {
+        let frame = |value: i16| vec![value; BUFFER_SIZE];
+        let mix = |compensate: bool| {
+            let mut mixer = AudioMixer::new(compensate);
+            mixer.set_jitter_buffer_ms(20);
+            let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+            
+            // Alice's jitter buffer has 60ms queued when she starts playing, Bob's 20ms
+            for seq in 0..3 {
+                mixer.add_frame(alice, seq, frame(1));
+            }
+            mixer.add_frame(bob, 0, frame(2));
+            
+            let mut output = vec![0i16; BUFFER_SIZE * 4];
+            mixer.mix(&mut output);
+            let played: Vec<i16> = output.chunks(BUFFER_SIZE).map(|frame| frame[0]).collect();
+            (played, mixer.mix_latency_ms())
+        };
+        
+        // Bob is held back by the 40ms his voice arrives ahead of Alice's
+        let (played, latency) = mix(true);
+        assert_eq!(played, vec![1, 1, 3, 0]);
+        assert!(latency > 0.0);
+        
+        let (played, latency) = mix(false);
+        assert_eq!(played, vec![3, 1, 1, 0]);
+        assert_eq!(latency, 0.0);
+    }
+    
+    // Goes through the PCM codec, not Opus, which the client can't encode yet
+    #[cfg(feature = "test-audio")]
+    #[test]
+    fn mock_devices_play_back_a_sine_wave_after_a_pcm_round_trip() {
+        use open_reverb_common::protocol::Message;
+        
+        const FRAMES: usize = 10;
+        
+        // Starts at its peak rather than zero, so where it begins in the output can be found
+        let input: Vec<f32> = (0..BUFFER_SIZE * FRAMES)
+            .map(|i| (i as f32 / SAMPLE_RATE as f32 * 440.0 * std::f32::consts::TAU).cos() * 0.5)
+            .collect();
+        MockAudioHost::set_input_data(input.clone());
+        
+        let connection = Arc::new(Connection::new());
+        let mut manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::clone(&connection), 64);
+        // Uncompressed, so what's played can be compared sample for sample
+        manager.set_audio_quality(AudioQuality { codec: AudioCodec::PCM, ..AudioQuality::default() });
+        manager.start_audio().unwrap();
+        
+        // Capture and encode, as sent to the server
+        let mut sent = Vec::new();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while sent.len() < FRAMES {
+            assert!(std::time::Instant::now() < deadline, "only {} frames were captured", sent.len());
+            sent.extend(connection.take_queued_messages().into_iter().filter_map(|m| match m {
+                Message::VoiceData { data, .. } => Some(data),
+                _ => None,
+            }));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        
+        // Decode and play, as if it came back from another user
+        let sender = Uuid::new_v4();
+        for (i, data) in sent.iter().take(FRAMES).enumerate() {
+            manager.play_voice(sender, i as u32, data);
+        }
+        
+        let output = MockAudioHost::get_output_data();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let played = loop {
+            {
+                let output = output.lock();
+                if let Some(start) = output.iter().position(|s| *s != 0.0) {
+                    if output.len() >= start + input.len() {
+                        break output[start..start + input.len()].to_vec();
+                    }
+                }
+            }
+            assert!(std::time::Instant::now() < deadline, "the voice wasn't played");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        manager.stop_audio();
+        
+        // Within the rounding of 16-bit samples
+        for (i, (played, input)) in played.iter().zip(input.iter()).enumerate() {
+            assert!((played - input).abs() < 0.001, "sample {} was {} instead of {}", i, played, input);
+        }
+    }
+    
+    #[cfg(not(all(feature = "audio", not(feature = "test-audio"))))]
+    #[test]
+    fn stop_audio_returns_promptly_when_no_audio_is_flowing() {
+        let mut manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::new(Connection::new()), 4);
+        manager.start_audio().unwrap();
+        
+        // Stop capturing, leaving the sender thread waiting for frames that never come
+        #[cfg(feature = "test-audio")]
+        manager.mock_streams.clear();
+        #[cfg(not(feature = "test-audio"))]
+        {
+            manager.mock_audio_stop.take().unwrap().send(()).unwrap();
+            manager.mock_audio_thread.take().unwrap().join().unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(150));
+        
+        let started = std::time::Instant::now();
+        manager.stop_audio();
+        
+        assert!(started.elapsed() < Duration::from_millis(200), "stop_audio took {:?}", started.elapsed());
+        assert!(manager.sender_thread.is_none());
+    }
+    
+    #[cfg(not(any(feature = "audio", feature = "test-audio")))]
+    #[test]
+    fn push_to_talk_only_sends_while_the_key_is_held() {
+        use open_reverb_common::protocol::Message;
+        
+        let connection = Arc::new(Connection::new());
+        let mut manager = AudioManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::clone(&connection), 64);
+        manager.set_ptt_mode(true);
+        manager.start_audio().unwrap();
+        let voice_frames = || connection.take_queued_messages().into_iter().filter(|m| matches!(m, Message::VoiceData { .. })).count();
+        
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(voice_frames(), 0);
+        
+        manager.press_ptt();
+        std::thread::sleep(Duration::from_millis(100));
+        manager.release_ptt();
+        // Let frames already captured reach the sender thread
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(voice_frames() > 0);
+        
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(voice_frames(), 0);
+        
+        manager.set_ptt_mode(false);
+        assert!(manager.transmitting.load(Ordering::SeqCst));
+        manager.stop_audio();
+    }
+}
@@ -0,0 +1,180 @@
+// Acoustic echo cancellation, for users on speakers rather than headphones. The microphone
+// picks up the voices being played back; without this, everyone hears themselves again.
+//
+// An NLMS adaptive filter learns how the output mix reaches the microphone and subtracts its
+// estimate of that echo. The reference has to be lined up with the microphone first, by the
+// delay between playing a sample and capturing it. The filter only covers what's left after
+// that delay: the room's reflections.
+//
+// While the local user talks over the far end (double talk), adapting would teach the filter to
+// cancel them too, so it holds still whenever the microphone is louder than the echo could be.
+
+// Taps in the filter; about 10ms of reflections at 48kHz
+pub const DEFAULT_FILTER_LEN: usize = 512;
+
+// NLMS step size; higher converges faster but leaves more residual echo
+const STEP_SIZE: f32 = 0.5;
+
+// Keeps the step finite while the reference is silent
+const REGULARIZATION: f32 = 1e-3;
+
+// Geigel double-talk detector: the echo is never louder than this fraction of the far end's peak
+const DOUBLE_TALK_THRESHOLD: f32 = 0.5;
+
+// Adaptation stays off this long after double talk is last seen, since speech dips below the
+// threshold between peaks; 30ms at 48kHz
+const DOUBLE_TALK_HOLD: usize = 1440;
+
+pub struct EchoCanceller {
+    weights: Vec<f32>,
+    // The last `weights.len()` reference samples, newest first, written twice over so the
+    // window is always one contiguous slice
+    history: Vec<f32>,
+    position: usize,
+    // Samples left before adapting again after double talk
+    double_talk_hold: usize,
+}
+
+impl EchoCanceller {
+    pub fn new(filter_len: usize) -> Self {
+        let filter_len = filter_len.max(1);
+        Self { weights: vec![0.0; filter_len], history: vec![0.0; filter_len * 2], position: 0, double_talk_hold: 0 }
+    }
+    
+    // Remove the echo of `reference` (the output played when these samples were captured) from a
+    // frame of microphone samples (-1.0 - 1.0), in place. The frames must be the same length.
+    pub fn process_frame(&mut self, mic: &mut [f32], reference: &[f32]) {
+        let len = self.weights.len();
+        
+        // Peak of the far end over the filter's window and this frame, for double-talk detection
+        let far_peak = self.window().iter().chain(reference.iter()).fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let mut energy: f32 = self.window().iter().map(|s| s * s).sum();
+        
+        for (sample, far) in mic.iter_mut().zip(reference.iter()) {
+            let oldest = self.history[self.position + len - 1];
+            self.position = (self.position + len - 1) % len;
+            self.history[self.position] = *far;
+            self.history[self.position + len] = *far;
+            energy = (energy + far * far - oldest * oldest).max(0.0);
+            
+            let window = &self.history[self.position..self.position + len];
+            let estimate: f32 = self.weights.iter().zip(window).map(|(w, x)| w * x).sum();
+            let error = *sample - estimate;
+            
+            if sample.abs() > DOUBLE_TALK_THRESHOLD * far_peak {
+                self.double_talk_hold = DOUBLE_TALK_HOLD;
+            } else if self.double_talk_hold > 0 {
+                self.double_talk_hold -= 1;
+            } else {
+                let step = STEP_SIZE * error / (energy + REGULARIZATION);
+                for (weight, x) in self.weights.iter_mut().zip(window) {
+                    *weight += step * x;
+                }
+            }
+            
+            *sample = error;
+        }
+    }
+    
+    // Remove the echo from a frame of 16-bit samples, with the reference as -1.0 - 1.0
+    pub fn process_pcm(&mut self, samples: &mut [i16], reference: &[f32]) {
+        let mut frame: Vec<f32> = samples.iter().map(|s| *s as f32 / i16::MAX as f32).collect();
+        self.process_frame(&mut frame, reference);
+        
+        for (s, f) in samples.iter_mut().zip(frame.iter()) {
+            *s = (f * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+    
+    fn window(&self) -> &[f32] {
+        &self.history[self.position..self.position + self.weights.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // 20ms at 48kHz
+    const FRAME: usize = 960;
+    
+    // White noise from a fixed seed, standing in for the far end's voice
+    fn noise(samples: usize, amplitude: f32, mut seed: u64) -> Vec<f32> {
+        (0..samples)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                ((seed >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0) * amplitude
+            })
+            .collect()
+    }
+    
+    // The far end as the microphone hears it: a direct path and two reflections
+    fn room(far: &[f32]) -> Vec<f32> {
+        (0..far.len())
+            .map(|i| {
+                let tap = |delay: usize, gain: f32| if i >= delay { far[i - delay] * gain } else { 0.0 };
+                tap(3, 0.25) + tap(17, -0.12) + tap(40, 0.06)
+            })
+            .collect()
+    }
+    
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+    
+    #[test]
+    fn echo_is_cancelled_by_at_least_20db() {
+        let far = noise(48_000 * 2, 0.3, 0x2545_f491_4f6c_dd1d);
+        let echo = room(&far);
+        
+        let mut canceller = EchoCanceller::new(64);
+        let mut output = echo.clone();
+        for (mic, reference) in output.chunks_mut(FRAME).zip(far.chunks(FRAME)) {
+            canceller.process_frame(mic, reference);
+        }
+        
+        // Skip the first second while the filter converges
+        let settled = 48_000;
+        let cancelled_db = 20.0 * (rms(&echo[settled..]) / rms(&output[settled..])).log10();
+        assert!(cancelled_db >= 20.0, "only cancelled {:.1}dB", cancelled_db);
+    }
+    
+    #[test]
+    fn the_local_voice_passes_through() {
+        let mut canceller = EchoCanceller::new(64);
+        let near = noise(FRAME * 10, 0.3, 0x9e37_79b9_7f4a_7c15);
+        
+        // Nothing playing, so nothing to cancel
+        let mut output = near.clone();
+        for mic in output.chunks_mut(FRAME) {
+            canceller.process_frame(mic, &[0.0; FRAME]);
+        }
+        assert_eq!(output, near);
+    }
+    
+    #[test]
+    fn talking_over_the_far_end_doesnt_unlearn_the_echo() {
+        let far = noise(48_000 * 2, 0.1, 0x2545_f491_4f6c_dd1d);
+        let echo = room(&far);
+        let mut canceller = EchoCanceller::new(64);
+        
+        let mut output = echo.clone();
+        for (mic, reference) in output.chunks_mut(FRAME).zip(far.chunks(FRAME)).take(50) {
+            canceller.process_frame(mic, reference);
+        }
+        
+        // Loud local speech over the echo for the second half
+        let near = noise(48_000, 0.5, 0x9e37_79b9_7f4a_7c15);
+        let mut mic: Vec<f32> = echo[48_000..].iter().zip(near.iter()).map(|(e, n)| e + n).collect();
+        for (mic, reference) in mic.chunks_mut(FRAME).zip(far[48_000..].chunks(FRAME)) {
+            canceller.process_frame(mic, reference);
+        }
+        
+        // What's left is the local speech, with the echo still taken out
+        let residual: Vec<f32> = mic.iter().zip(near.iter()).map(|(m, n)| m - n).collect();
+        let cancelled_db = 20.0 * (rms(&echo[48_000..]) / rms(&residual)).log10();
+        assert!(cancelled_db >= 10.0, "only cancelled {:.1}dB during double talk", cancelled_db);
+    }
+}
@@ -0,0 +1,215 @@
+// Automatic gain control for microphone input, so quiet microphones are turned up and loud
+// ones turned down to a common level.
+//
+// The level is a running RMS of the input, ignoring silence, that rises slowly and falls fast:
+// a shout or a cough barely moves it (the peak limit stops those clipping), while the gain comes
+// back up soon after one. The gain moves towards whatever brings that level to the target,
+// a fraction of the way each frame and at a limited rate, so it doesn't pump with every syllable.
+
+// Fraction of the way the level moves towards a louder frame (about 400ms), and a quieter one
+// (about 100ms)
+const LEVEL_ATTACK: f32 = 0.05;
+const LEVEL_RELEASE: f32 = 0.2;
+
+// Frames quieter than this (-60dBFS) are silence, and don't count towards the level
+const SILENCE_RMS: f32 = 0.001;
+
+// Range of gain applied, in dB
+const MIN_GAIN_DB: f32 = -20.0;
+const MAX_GAIN_DB: f32 = 30.0;
+
+pub const DEFAULT_TARGET_DBFS: f32 = -18.0;
+
+// Fraction of the way to the wanted gain it moves each frame, and the most it moves, in dB
+const GAIN_SMOOTHING: f32 = 0.25;
+const MAX_GAIN_CHANGE_PER_FRAME: f32 = 1.5;
+
+pub struct AutoGainController {
+    target_rms: f32,
+    // Current gain, before limiting so the frame doesn't clip
+    gain: f32,
+    // Gain applied at the end of the last frame, so the next starts from it without clicking
+    applied_gain: f32,
+    // Smoothed mean square of the frames that weren't silent; None until the first
+    level: Option<f32>,
+}
+
+impl AutoGainController {
+    pub fn new(target_dbfs: f32) -> Self {
+        Self {
+            target_rms: dbfs_to_rms(target_dbfs),
+            gain: 1.0,
+            applied_gain: 1.0,
+            level: None,
+        }
+    }
+    
+    pub fn set_target_dbfs(&mut self, target_dbfs: f32) {
+        self.target_rms = dbfs_to_rms(target_dbfs);
+    }
+    
+    // Positive while turning the microphone down, negative while turning it up
+    pub fn gain_reduction_db(&self) -> f32 {
+        -20.0 * self.applied_gain.log10()
+    }
+    
+    // Apply gain to a frame of samples (-1.0 - 1.0) in place
+    pub fn process_frame(&mut self, frame: &mut [f32]) {
+        if frame.is_empty() {
+            return;
+        }
+        
+        let mean_square = frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32;
+        
+        // Silence holds the level and gain where they are
+        if mean_square.sqrt() > SILENCE_RMS {
+            let level = self.level.get_or_insert(mean_square);
+            let coefficient = if mean_square > *level { LEVEL_ATTACK } else { LEVEL_RELEASE };
+            *level += (mean_square - *level) * coefficient;
+            
+            let wanted_db = (20.0 * (self.target_rms / level.sqrt()).log10()).clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+            let gain_db = 20.0 * self.gain.log10();
+            let step = ((wanted_db - gain_db) * GAIN_SMOOTHING).clamp(-MAX_GAIN_CHANGE_PER_FRAME, MAX_GAIN_CHANGE_PER_FRAME);
+            self.gain = 10f32.powf((gain_db + step) / 20.0);
+        }
+        
+        // Never push the loudest sample past full scale
+        let peak = frame.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let target = if peak > 0.0 { self.gain.min(1.0 / peak) } else { self.gain };
+        
+        // Ramp across the frame from the last gain
+        let start = self.applied_gain;
+        let step = (target - start) / frame.len() as f32;
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample = (*sample * (start + step * (i + 1) as f32)).clamp(-1.0, 1.0);
+        }
+        self.applied_gain = target;
+    }
+    
+    // Apply gain to a frame of 16-bit samples
+    pub fn process_pcm(&mut self, samples: &mut [i16]) {
+        let mut frame: Vec<f32> = samples.iter().map(|s| *s as f32 / i16::MAX as f32).collect();
+        
+        self.process_frame(&mut frame);
+        
+        for (s, f) in samples.iter_mut().zip(frame.iter()) {
+            *s = (f * i16::MAX as f32) as i16;
+        }
+    }
+}
+
+fn dbfs_to_rms(dbfs: f32) -> f32 {
+    10f32.powf(dbfs / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // 20ms at 48kHz
+    const FRAME: usize = 960;
+    
+    fn rms_dbfs(frame: &[f32]) -> f32 {
+        let rms = (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt();
+        20.0 * rms.log10()
+    }
+    
+    // A 440Hz tone at the given RMS level
+    fn tone(level_dbfs: f32, frame_index: usize) -> Vec<f32> {
+        let amplitude = dbfs_to_rms(level_dbfs) * std::f32::consts::SQRT_2;
+        (0..FRAME)
+            .map(|i| {
+                let t = (frame_index * FRAME + i) as f32 / 48_000.0;
+                (t * 440.0 * std::f32::consts::TAU).sin() * amplitude
+            })
+            .collect()
+    }
+    
+    // Output level of the last of `frames` frames of a tone
+    fn settled_level(input_dbfs: f32, frames: usize) -> f32 {
+        let mut agc = AutoGainController::new(DEFAULT_TARGET_DBFS);
+        let mut frame = Vec::new();
+        for i in 0..frames {
+            frame = tone(input_dbfs, i);
+            agc.process_frame(&mut frame);
+        }
+        rms_dbfs(&frame)
+    }
+    
+    #[test]
+    fn converges_to_target_within_50_frames() {
+        for input_dbfs in [-40.0, -30.0, -18.0, -12.0, -3.0] {
+            let output_dbfs = settled_level(input_dbfs, 50);
+            
+            assert!(
+                (output_dbfs - DEFAULT_TARGET_DBFS).abs() < 1.0,
+                "{}dBFS input came out at {:.1}dBFS",
+                input_dbfs,
+                output_dbfs,
+            );
+        }
+    }
+    
+    #[test]
+    fn gain_is_slew_limited() {
+        let mut agc = AutoGainController::new(DEFAULT_TARGET_DBFS);
+        let mut previous = agc.gain_reduction_db();
+        
+        for i in 0..20 {
+            agc.process_frame(&mut tone(-40.0, i));
+            let reduction = agc.gain_reduction_db();
+            assert!((reduction - previous).abs() <= MAX_GAIN_CHANGE_PER_FRAME + 0.001);
+            previous = reduction;
+        }
+    }
+    
+    #[test]
+    fn a_quiet_sine_is_brought_up_within_500ms() {
+        // As loud as settings allow; at the default target a sine settles at about 18% amplitude
+        let mut agc = AutoGainController::new(-6.0);
+        let sine = |frame_index: usize| -> Vec<f32> {
+            (0..FRAME)
+                .map(|i| ((frame_index * FRAME + i) as f32 / 48_000.0 * 440.0 * std::f32::consts::TAU).sin() * 0.1)
+                .collect()
+        };
+        
+        // 25 frames of 20ms
+        let mut frame = Vec::new();
+        for i in 0..25 {
+            frame = sine(i);
+            agc.process_frame(&mut frame);
+        }
+        
+        let peak = frame.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak >= 0.5, "only reached {:.2}", peak);
+    }
+    
+    #[test]
+    fn output_never_clips() {
+        let mut agc = AutoGainController::new(-3.0);
+        
+        // Quiet for a while, so the gain is high when a loud burst arrives
+        for i in 0..100 {
+            agc.process_frame(&mut tone(-40.0, i));
+        }
+        let mut burst = tone(-1.0, 100);
+        agc.process_frame(&mut burst);
+        
+        assert!(burst.iter().all(|s| s.abs() <= 1.0));
+    }
+    
+    #[test]
+    fn silence_holds_the_gain() {
+        let mut agc = AutoGainController::new(DEFAULT_TARGET_DBFS);
+        for i in 0..30 {
+            agc.process_frame(&mut tone(-30.0, i));
+        }
+        let before = agc.gain_reduction_db();
+        
+        for _ in 0..30 {
+            agc.process_frame(&mut vec![0.0; FRAME]);
+        }
+        
+        assert!((agc.gain_reduction_db() - before).abs() < 0.001);
+    }
+}
@@ -0,0 +1,139 @@
+use open_reverb_common::models::{AudioCodec, AudioQuality};
+use open_reverb_common::voice_codec::{self, resample};
+
+// Turns captured mono frames into the format a channel's audio quality asks for. PCM channels
+// are sent uncompressed at their sample rate and channel count. ADPCM channels are compressed
+// to fit their bitrate.
+pub struct VoiceEncoder {
+    quality: AudioQuality,
+}
+
+impl VoiceEncoder {
+    pub fn new(quality: AudioQuality) -> Self {
+        Self { quality }
+    }
+    
+    pub fn quality(&self) -> AudioQuality {
+        self.quality
+    }
+    
+    // Returns true if the quality changed
+    pub fn configure(&mut self, quality: AudioQuality) -> bool {
+        if quality == self.quality {
+            return false;
+        }
+        
+        self.quality = quality;
+        true
+    }
+    
+    // Resample a mono frame captured at `capture_rate`, and encode it. PCM is interleaved 16-bit
+    // little-endian.
+    pub fn encode(&self, samples: &[i16], capture_rate: u32) -> Vec<u8> {
+        if self.quality.codec == AudioCodec::Adpcm {
+            let sample_rate = voice_codec::sample_rate_for(self.quality);
+            return voice_codec::encode(&resample(samples, capture_rate, sample_rate), sample_rate);
+        }
+        
+        let resampled = resample(samples, capture_rate, self.quality.sample_rate);
+        let channels = self.quality.channels.max(1) as usize;
+        
+        resampled
+            .iter()
+            .flat_map(|sample| std::iter::repeat_n(*sample, channels))
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect()
+    }
+}
+
+// Turn voice sent with `quality` back into mono samples at `output_rate`. Frames that can't be
+// decoded come out as silence.
+pub fn decode(data: &[u8], quality: AudioQuality, output_rate: u32) -> Vec<i16> {
+    if quality.codec == AudioCodec::Adpcm {
+        return match voice_codec::decode(data) {
+            Some((samples, sample_rate)) => resample(&samples, sample_rate, output_rate),
+            None => Vec::new(),
+        };
+    }
+    
+    let channels = quality.channels.max(1) as usize;
+    let mono: Vec<i16> = data
+        .chunks_exact(2 * channels)
+        .map(|frame| {
+            let sum: i32 = frame.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as i32).sum();
+            (sum / channels as i32) as i16
+        })
+        .collect();
+    
+    resample(&mono, quality.sample_rate, output_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const CAPTURE_RATE: u32 = 48_000;
+    
+    fn quality(sample_rate: u32, channels: u16) -> AudioQuality {
+        AudioQuality { sample_rate, channels, bitrate_kbps: 64, codec: AudioCodec::PCM }
+    }
+    
+    #[test]
+    fn full_rate_pcm_sends_the_frame_unchanged() {
+        let encoder = VoiceEncoder::new(quality(48_000, 1));
+        let frame: Vec<i16> = (0..960).map(|i| i as i16).collect();
+        
+        let bytes = encoder.encode(&frame, CAPTURE_RATE);
+        
+        let expected: Vec<u8> = frame.iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert_eq!(bytes, expected);
+    }
+    
+    #[test]
+    fn lower_sample_rate_shrinks_the_frame() {
+        let encoder = VoiceEncoder::new(quality(16_000, 1));
+        
+        // 20ms at 16kHz is 320 samples
+        assert_eq!(encoder.encode(&[100; 960], CAPTURE_RATE).len(), 320 * 2);
+    }
+    
+    #[test]
+    fn stereo_duplicates_each_sample() {
+        let encoder = VoiceEncoder::new(quality(48_000, 2));
+        
+        let bytes = encoder.encode(&[1, 2], CAPTURE_RATE);
+        
+        assert_eq!(bytes, [1, 0, 1, 0, 2, 0, 2, 0]);
+    }
+    
+    #[test]
+    fn decode_reverses_encode() {
+        let encoder = VoiceEncoder::new(quality(48_000, 2));
+        let frame: Vec<i16> = (0..960).map(|i| (i * 30) as i16).collect();
+        
+        assert_eq!(decode(&encoder.encode(&frame, CAPTURE_RATE), encoder.quality(), CAPTURE_RATE), frame);
+    }
+    
+    #[test]
+    fn opus_channels_are_compressed_to_their_bitrate() {
+        let encoder = VoiceEncoder::new(AudioQuality::default());
+        let frame: Vec<i16> = (0..960).map(|i| ((i as f32 / 48.0).sin() * 5_000.0) as i16).collect();
+        
+        // 20ms at 16kHz, 4 bits a sample, plus the header
+        let bytes = encoder.encode(&frame, CAPTURE_RATE);
+        assert_eq!(bytes.len(), 9 + 160);
+        
+        let decoded = decode(&bytes, encoder.quality(), CAPTURE_RATE);
+        assert_eq!(decoded.len(), frame.len());
+        assert!(decode(&bytes[..20], encoder.quality(), CAPTURE_RATE).is_empty());
+    }
+    
+    #[test]
+    fn configure_reports_only_changes() {
+        let mut encoder = VoiceEncoder::new(AudioQuality::default());
+        
+        assert!(!encoder.configure(AudioQuality::default()));
+        assert!(encoder.configure(quality(24_000, 1)));
+        assert_eq!(encoder.quality().sample_rate, 24_000);
+    }
+}
@@ -0,0 +1,211 @@
+// Jitter buffer for one user's voice. Frames can arrive late, early, in bursts or out of order;
+// they're put back in order by their sequence number and held until a few are queued, so a
+// frame that's a little late is still there in time to be played.
+//
+// Frames are kept in a ring, each in the slot for its distance from the next one due. Playing
+// starts once `depth` worth of voice is buffered, and starts over the same way whenever the
+// buffer runs dry, e.g. when the user stops talking. A frame that still hasn't arrived by its
+// turn is played as silence.
+
+pub struct JitterBuffer {
+    slots: Vec<Option<Vec<i16>>>,
+    // Seq of the next frame to play, and its slot; None until the first arrives
+    next_seq: Option<u32>,
+    head: usize,
+    // Samples buffered before playing starts
+    depth_samples: usize,
+    buffered_samples: usize,
+    playing: bool,
+    // Samples buffered when playing last started, which is how far behind the sender it plays
+    delay_samples: usize,
+    // Length of the last frame, to stand in for lost ones
+    frame_len: usize,
+}
+
+impl JitterBuffer {
+    // Holds at most `capacity` frames; once later ones arrive, the oldest are skipped
+    pub fn new(capacity: usize, depth_samples: usize) -> Self {
+        Self {
+            slots: vec![None; capacity.max(1)],
+            next_seq: None,
+            head: 0,
+            depth_samples,
+            buffered_samples: 0,
+            playing: false,
+            delay_samples: 0,
+            frame_len: 0,
+        }
+    }
+    
+    pub fn set_depth_samples(&mut self, depth_samples: usize) {
+        self.depth_samples = depth_samples;
+    }
+    
+    pub fn delay_samples(&self) -> usize {
+        self.delay_samples
+    }
+    
+    // Frames older than the one due to play next are too late and dropped, as are duplicates
+    pub fn push(&mut self, seq: u32, samples: Vec<i16>) {
+        let capacity = self.slots.len();
+        // Nothing's waiting, so start from this frame; the sender may have started over from 0
+        if self.buffered_samples == 0 && !self.playing {
+            self.next_seq = Some(seq);
+        }
+        let next_seq = *self.next_seq.get_or_insert(seq);
+        
+        // Compared as a signed distance, so seq can wrap around
+        let mut offset = seq.wrapping_sub(next_seq) as i32;
+        if offset < 0 {
+            // Overtaken by a later frame. Once that's played, this one's too late, but until then
+            // there's still time if the ring has room to go back for it.
+            let behind = offset.unsigned_abs() as usize;
+            let room = behind < capacity && (1..=behind).all(|i| self.slots[(self.head + capacity - i) % capacity].is_none());
+            if self.playing || !room {
+                return;
+            }
+            
+            self.head = (self.head + capacity - behind) % capacity;
+            self.next_seq = Some(seq);
+            offset = 0;
+        }
+        
+        // Too far ahead to fit; make room by giving up on the oldest
+        if offset as usize >= capacity {
+            for _ in 0..(offset as usize + 1 - capacity).min(capacity) {
+                self.skip();
+            }
+            self.next_seq = Some(seq.wrapping_sub(capacity as u32 - 1));
+            offset = capacity as i32 - 1;
+        }
+        
+        let slot = &mut self.slots[(self.head + offset as usize) % capacity];
+        if slot.is_none() {
+            self.buffered_samples += samples.len();
+            *slot = Some(samples);
+        }
+    }
+    
+    // The next frame to play, silence in place of one that's lost, or None while buffering
+    pub fn pop(&mut self) -> Option<Vec<i16>> {
+        if self.buffered_samples == 0 {
+            self.playing = false;
+            return None;
+        }
+        if !self.playing && self.buffered_samples < self.depth_samples {
+            return None;
+        }
+        if !self.playing {
+            self.delay_samples = self.buffered_samples;
+            self.playing = true;
+        }
+        
+        match self.skip() {
+            Some(frame) => {
+                self.frame_len = frame.len();
+                Some(frame)
+            }
+            None => Some(vec![0; self.frame_len]),
+        }
+    }
+    
+    // Move past the frame due next, returning it if it arrived
+    fn skip(&mut self) -> Option<Vec<i16>> {
+        let next_seq = self.next_seq?;
+        self.next_seq = Some(next_seq.wrapping_add(1));
+        
+        let slot = self.head;
+        self.head = (self.head + 1) % self.slots.len();
+        let frame = self.slots[slot].take()?;
+        self.buffered_samples -= frame.len();
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn frame(value: i16) -> Vec<i16> {
+        vec![value; 4]
+    }
+    
+    #[test]
+    fn frames_play_in_order_once_the_buffer_fills() {
+        let mut buffer = JitterBuffer::new(8, 12);
+        buffer.push(0, frame(0));
+        buffer.push(2, frame(2));
+        assert_eq!(buffer.pop(), None);
+        
+        buffer.push(1, frame(1));
+        assert_eq!(buffer.pop(), Some(frame(0)));
+        assert_eq!(buffer.pop(), Some(frame(1)));
+        assert_eq!(buffer.pop(), Some(frame(2)));
+        assert_eq!(buffer.delay_samples(), 12);
+        
+        // Ran dry, so it fills up again before the next frame plays
+        assert_eq!(buffer.pop(), None);
+        buffer.push(3, frame(3));
+        assert_eq!(buffer.pop(), None);
+    }
+    
+    #[test]
+    fn lost_frames_are_played_as_silence_and_late_ones_dropped() {
+        let mut buffer = JitterBuffer::new(8, 0);
+        buffer.push(10, frame(10));
+        buffer.push(12, frame(12));
+        
+        assert_eq!(buffer.pop(), Some(frame(10)));
+        assert_eq!(buffer.pop(), Some(vec![0; 4]));
+        
+        // 11 turns up after its turn
+        buffer.push(11, frame(11));
+        buffer.push(10, frame(10));
+        assert_eq!(buffer.pop(), Some(frame(12)));
+        assert_eq!(buffer.pop(), None);
+    }
+    
+    #[test]
+    fn frames_overtaken_while_buffering_still_play() {
+        let mut buffer = JitterBuffer::new(4, 12);
+        buffer.push(2, frame(2));
+        buffer.push(0, frame(0));
+        buffer.push(1, frame(1));
+        
+        assert_eq!(buffer.pop(), Some(frame(0)));
+        assert_eq!(buffer.pop(), Some(frame(1)));
+        assert_eq!(buffer.pop(), Some(frame(2)));
+    }
+    
+    #[test]
+    fn senders_can_start_over_once_the_buffer_runs_dry() {
+        let mut buffer = JitterBuffer::new(8, 0);
+        buffer.push(500, frame(1));
+        assert_eq!(buffer.pop(), Some(frame(1)));
+        assert_eq!(buffer.pop(), None);
+        
+        buffer.push(0, frame(2));
+        assert_eq!(buffer.pop(), Some(frame(2)));
+    }
+    
+    #[test]
+    fn seq_can_wrap_around() {
+        let mut buffer = JitterBuffer::new(8, 0);
+        buffer.push(u32::MAX, frame(1));
+        buffer.push(0, frame(2));
+        
+        assert_eq!(buffer.pop(), Some(frame(1)));
+        assert_eq!(buffer.pop(), Some(frame(2)));
+    }
+    
+    #[test]
+    fn frames_too_far_ahead_push_out_the_oldest() {
+        let mut buffer = JitterBuffer::new(4, 0);
+        for seq in 0..6 {
+            buffer.push(seq, frame(seq as i16));
+        }
+        
+        assert_eq!(buffer.buffered_samples, 16);
+        assert_eq!(buffer.pop(), Some(frame(2)));
+    }
+}
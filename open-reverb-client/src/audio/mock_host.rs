@@ -0,0 +1,138 @@
+// In-memory stand-in for a cpal host, so AudioManager can run where there are no audio devices,
+// like CI. It has the same shape as the parts of cpal's HostTrait, DeviceTrait and StreamTrait
+// AudioManager uses, without needing cpal (and ALSA) to build.
+//
+// The input device plays back whatever was last set with set_input_data, then silence. The
+// output device appends everything it's given to get_output_data. Both run in real time, one
+// buffer per callback.
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+use std::time::Duration;
+
+static INPUT_DATA: Lazy<Mutex<VecDeque<f32>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+static OUTPUT_DATA: Lazy<Arc<Mutex<Vec<f32>>>> = Lazy::new(|| Arc::new(Mutex::new(Vec::new())));
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Input,
+    Output,
+}
+
+pub struct MockAudioHost;
+
+impl MockAudioHost {
+    // Mono samples (-1.0 - 1.0) for the input device to capture, replacing any not yet captured
+    #[cfg(test)]
+    pub fn set_input_data(samples: Vec<f32>) {
+        *INPUT_DATA.lock() = samples.into();
+    }
+    
+    // Everything played through the output device so far
+    #[cfg(test)]
+    pub fn get_output_data() -> Arc<Mutex<Vec<f32>>> {
+        Arc::clone(&OUTPUT_DATA)
+    }
+    
+    pub fn default_input_device(&self) -> Option<MockDevice> {
+        Some(MockDevice { direction: Direction::Input })
+    }
+    
+    pub fn default_output_device(&self) -> Option<MockDevice> {
+        Some(MockDevice { direction: Direction::Output })
+    }
+}
+
+pub struct MockDevice {
+    direction: Direction,
+}
+
+impl MockDevice {
+    // Calls back with `buffer_size` captured samples, as often as a device at `sample_rate` would
+    pub fn build_input_stream<D>(&self, sample_rate: u32, buffer_size: usize, mut data_callback: D) -> Result<MockStream>
+    where
+        D: FnMut(&[f32]) + Send + 'static,
+    {
+        if !matches!(self.direction, Direction::Input) {
+            return Err(anyhow::anyhow!("Not an input device"));
+        }
+        
+        let mut buffer = vec![0.0; buffer_size];
+        Ok(MockStream::new(sample_rate, buffer_size, move || {
+            let mut input = INPUT_DATA.lock();
+            for sample in buffer.iter_mut() {
+                *sample = input.pop_front().unwrap_or(0.0);
+            }
+            drop(input);
+            
+            data_callback(&buffer);
+        }))
+    }
+    
+    // Asks for `buffer_size` samples to play, as often as a device at `sample_rate` would
+    pub fn build_output_stream<D>(&self, sample_rate: u32, buffer_size: usize, mut data_callback: D) -> Result<MockStream>
+    where
+        D: FnMut(&mut [f32]) + Send + 'static,
+    {
+        if !matches!(self.direction, Direction::Output) {
+            return Err(anyhow::anyhow!("Not an output device"));
+        }
+        
+        let mut buffer = vec![0.0; buffer_size];
+        Ok(MockStream::new(sample_rate, buffer_size, move || {
+            buffer.fill(0.0);
+            data_callback(&mut buffer);
+            OUTPUT_DATA.lock().extend_from_slice(&buffer);
+        }))
+    }
+}
+
+// Runs its callback on a thread once played, until dropped
+pub struct MockStream {
+    interval: Duration,
+    callback: Option<Box<dyn FnMut() + Send>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl MockStream {
+    fn new(sample_rate: u32, buffer_size: usize, callback: impl FnMut() + Send + 'static) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(buffer_size as f64 / sample_rate.max(1) as f64),
+            callback: Some(Box::new(callback)),
+            stop: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+    
+    pub fn play(&mut self) -> Result<()> {
+        let mut callback = match self.callback.take() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        
+        let interval = self.interval;
+        let stop = Arc::clone(&self.stop);
+        self.thread = Some(std::thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                callback();
+                std::thread::sleep(interval);
+            }
+        }));
+        
+        Ok(())
+    }
+}
+
+impl Drop for MockStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// Suppresses steady background noise (fans, air conditioning, hum) in microphone input.
+//
+// The noise floor is tracked as the quietest recent frame level. Frames close to the floor are
+// attenuated and frames well above it, such as speech, pass through untouched.
+
+// Samples processed at a time; 10ms at 48kHz, half a captured frame
+pub const FRAME_SIZE: usize = 480;
+
+// How fast the noise floor estimate creeps up per frame, so it follows noise that gets louder
+const FLOOR_RISE: f32 = 1.005;
+
+// Frames this far above the noise floor (as a ratio of RMS, about 12dB) are passed through
+const OPEN_RATIO: f32 = 4.0;
+
+// Gain applied to frames at the noise floor; -30dB
+const MIN_GAIN: f32 = 0.03;
+
+pub struct NoiseReducer {
+    // RMS of the quietest recent frames
+    noise_floor: Option<f32>,
+    // Gain at the end of the last frame, so the next starts from it without clicking
+    gain: f32,
+    // How much of the denoised signal is used (0.0 - 1.0); the rest is the original
+    strength: f32,
+}
+
+impl NoiseReducer {
+    pub fn new(strength: f32) -> Self {
+        Self {
+            noise_floor: None,
+            gain: 1.0,
+            strength: strength.clamp(0.0, 1.0),
+        }
+    }
+    
+    pub fn set_strength(&mut self, strength: f32) {
+        self.strength = strength.clamp(0.0, 1.0);
+    }
+    
+    // Denoise up to FRAME_SIZE samples (-1.0 - 1.0) in place
+    pub fn process_frame(&mut self, frame: &mut [f32]) {
+        if frame.is_empty() {
+            return;
+        }
+        
+        let rms = (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt();
+        let floor = match self.noise_floor {
+            Some(floor) if rms > floor => floor * FLOOR_RISE,
+            _ => rms,
+        };
+        self.noise_floor = Some(floor);
+        
+        // Interpolate in dB between MIN_GAIN at the floor and 1.0 at OPEN_RATIO above it
+        let ratio = if floor > 0.0 { rms / floor } else { OPEN_RATIO };
+        let openness = (ratio.max(1.0).ln() / OPEN_RATIO.ln()).min(1.0);
+        let denoised_gain = MIN_GAIN.powf(1.0 - openness);
+        
+        // Blending denoised and original samples is the same as blending their gains
+        let target = self.strength * denoised_gain + (1.0 - self.strength);
+        
+        // Ramp across the frame from the last gain
+        let start = self.gain;
+        let step = (target - start) / frame.len() as f32;
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample *= start + step * (i + 1) as f32;
+        }
+        self.gain = target;
+    }
+    
+    // Denoise a frame of 16-bit samples, FRAME_SIZE at a time
+    pub fn process_pcm(&mut self, samples: &mut [i16]) {
+        let mut buffer = [0.0f32; FRAME_SIZE];
+        
+        for chunk in samples.chunks_mut(FRAME_SIZE) {
+            let frame = &mut buffer[..chunk.len()];
+            for (f, s) in frame.iter_mut().zip(chunk.iter()) {
+                *f = *s as f32 / i16::MAX as f32;
+            }
+            
+            self.process_frame(frame);
+            
+            for (s, f) in chunk.iter_mut().zip(frame.iter()) {
+                *s = (f * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // Pink noise from white noise, using Paul Kellet's economy filter and a fixed seed
+    fn pink_noise(samples: usize, amplitude: f32) -> Vec<f32> {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let (mut b0, mut b1, mut b2) = (0.0f32, 0.0f32, 0.0f32);
+        
+        (0..samples)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let white = (seed >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0;
+                
+                b0 = 0.99765 * b0 + white * 0.0990460;
+                b1 = 0.96300 * b1 + white * 0.2965164;
+                b2 = 0.57000 * b2 + white * 1.0526913;
+                (b0 + b1 + b2 + white * 0.1848) * amplitude
+            })
+            .collect()
+    }
+    
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+    
+    fn denoise(input: &[f32], strength: f32) -> Vec<f32> {
+        let mut reducer = NoiseReducer::new(strength);
+        let mut output = input.to_vec();
+        for frame in output.chunks_mut(FRAME_SIZE) {
+            reducer.process_frame(frame);
+        }
+        output
+    }
+    
+    #[test]
+    fn pink_noise_is_reduced_by_at_least_10db() {
+        let input = pink_noise(48_000 * 3, 0.05);
+        let output = denoise(&input, 1.0);
+        
+        // Skip the first second while the noise floor settles
+        let settled = 48_000;
+        let reduction_db = 20.0 * (rms(&input[settled..]) / rms(&output[settled..])).log10();
+        
+        assert!(reduction_db >= 10.0, "only reduced by {:.1}dB", reduction_db);
+    }
+    
+    #[test]
+    fn speech_level_bursts_pass_through() {
+        let mut input = pink_noise(48_000 * 2, 0.01);
+        // A loud tone well above the noise for the last half second
+        for (i, sample) in input[72_000..].iter_mut().enumerate() {
+            *sample += (i as f32 * 440.0 * std::f32::consts::TAU / 48_000.0).sin() * 0.5;
+        }
+        let output = denoise(&input, 1.0);
+        
+        // Leave a frame for the gain to ramp up
+        let burst = 72_000 + FRAME_SIZE;
+        let loss_db = 20.0 * (rms(&input[burst..]) / rms(&output[burst..])).log10();
+        
+        assert!(loss_db < 1.0, "burst lost {:.1}dB", loss_db);
+    }
+    
+    #[test]
+    fn zero_strength_leaves_audio_untouched() {
+        let input = pink_noise(48_000, 0.05);
+        
+        assert_eq!(denoise(&input, 0.0), input);
+    }
+}
@@ -0,0 +1,86 @@
+// Voice activity detection, so the microphone isn't sent while nobody is talking.
+//
+// A frame is voice when its RMS is over the threshold. Sending carries on for a few quiet
+// frames after the last voice (the hangover), so pauses between words and trailing
+// syllables aren't cut off.
+
+pub const DEFAULT_THRESHOLD: f32 = 0.02;
+
+// 160ms of 20ms frames
+pub const DEFAULT_HANGOVER_FRAMES: u32 = 8;
+
+pub struct VoiceActivityDetector {
+    threshold: f32,
+    hangover_frames: u32,
+    // Quiet frames in a row since the last voice; starts past the hangover, so silence isn't sent
+    quiet_frames: u32,
+}
+
+impl VoiceActivityDetector {
+    pub fn new(threshold: f32, hangover_frames: u32) -> Self {
+        Self { threshold, hangover_frames, quiet_frames: u32::MAX }
+    }
+    
+    pub fn configure(&mut self, threshold: f32, hangover_frames: u32) {
+        self.threshold = threshold;
+        self.hangover_frames = hangover_frames;
+    }
+    
+    // Whether the frame (-1.0 - 1.0) should be sent
+    pub fn process_frame(&mut self, frame: &[f32]) -> bool {
+        if rms(frame) > self.threshold {
+            self.quiet_frames = 0;
+        } else {
+            self.quiet_frames = self.quiet_frames.saturating_add(1);
+        }
+        
+        self.quiet_frames <= self.hangover_frames
+    }
+    
+    // Whether a frame of 16-bit samples should be sent
+    pub fn process_pcm(&mut self, samples: &[i16]) -> bool {
+        let frame: Vec<f32> = samples.iter().map(|s| *s as f32 / i16::MAX as f32).collect();
+        self.process_frame(&frame)
+    }
+}
+
+fn rms(frame: &[f32]) -> f32 {
+    if frame.is_empty() {
+        return 0.0;
+    }
+    
+    (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // 20ms at 48kHz
+    const FRAME: usize = 960;
+    
+    fn frame(amplitude: f32) -> Vec<f32> {
+        (0..FRAME).map(|i| (i as f32 / 48_000.0 * 440.0 * std::f32::consts::TAU).sin() * amplitude).collect()
+    }
+    
+    #[test]
+    fn silence_is_never_sent() {
+        let mut vad = VoiceActivityDetector::new(DEFAULT_THRESHOLD, DEFAULT_HANGOVER_FRAMES);
+        
+        assert!((0..20).all(|_| !vad.process_frame(&frame(0.001))));
+    }
+    
+    #[test]
+    fn sending_stops_once_the_hangover_runs_out() {
+        let mut vad = VoiceActivityDetector::new(DEFAULT_THRESHOLD, DEFAULT_HANGOVER_FRAMES);
+        assert!(vad.process_frame(&frame(0.5)));
+        
+        for _ in 0..DEFAULT_HANGOVER_FRAMES {
+            assert!(vad.process_frame(&frame(0.001)));
+        }
+        assert!(!vad.process_frame(&frame(0.001)));
+        
+        // Straight back on with the next word
+        assert!(vad.process_frame(&frame(0.5)));
+    }
+}
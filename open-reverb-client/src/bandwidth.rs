@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Rates are averaged over this long
+const WINDOW: Duration = Duration::from_secs(1);
+
+// Least time between throttling outgoing media, so each cut shows in the rate before the next
+const THROTTLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Bytes the connection sends and receives, for users on metered connections
+pub struct BandwidthMonitor {
+    // Totals since the client started
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    // Bytes sent and received within the last second, by when
+    sent_history: Mutex<VecDeque<(Instant, u64)>>,
+    received_history: Mutex<VecDeque<(Instant, u64)>>,
+    last_throttle: Mutex<Option<Instant>>,
+}
+
+impl BandwidthMonitor {
+    pub fn new() -> Self {
+        Self {
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            sent_history: Mutex::new(VecDeque::new()),
+            received_history: Mutex::new(VecDeque::new()),
+            last_throttle: Mutex::new(None),
+        }
+    }
+    
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        record(&self.sent_history, bytes as u64, Instant::now());
+    }
+    
+    pub fn record_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        record(&self.received_history, bytes as u64, Instant::now());
+    }
+    
+    // Average over the last second
+    pub fn upload_kbps(&self) -> f64 {
+        rolling_kbps(&self.sent_history, Instant::now())
+    }
+    
+    pub fn download_kbps(&self) -> f64 {
+        rolling_kbps(&self.received_history, Instant::now())
+    }
+    
+    // Whether outgoing media should be cut back now to get under `max_upload_kbps`
+    pub fn should_throttle(&self, max_upload_kbps: Option<u32>) -> bool {
+        self.should_throttle_at(max_upload_kbps, Instant::now())
+    }
+    
+    fn should_throttle_at(&self, max_upload_kbps: Option<u32>, now: Instant) -> bool {
+        let Some(max_upload_kbps) = max_upload_kbps else {
+            return false;
+        };
+        if rolling_kbps(&self.sent_history, now) <= max_upload_kbps as f64 {
+            return false;
+        }
+        
+        let mut last_throttle = self.last_throttle.lock().unwrap();
+        if last_throttle.is_some_and(|at| now.duration_since(at) < THROTTLE_INTERVAL) {
+            return false;
+        }
+        *last_throttle = Some(now);
+        true
+    }
+}
+
+fn record(history: &Mutex<VecDeque<(Instant, u64)>>, bytes: u64, now: Instant) {
+    let mut history = history.lock().unwrap();
+    history.push_back((now, bytes));
+    prune(&mut history, now);
+}
+
+fn rolling_kbps(history: &Mutex<VecDeque<(Instant, u64)>>, now: Instant) -> f64 {
+    let mut history = history.lock().unwrap();
+    prune(&mut history, now);
+    
+    let bytes: u64 = history.iter().map(|&(_, bytes)| bytes).sum();
+    bytes as f64 * 8.0 / 1000.0 / WINDOW.as_secs_f64()
+}
+
+fn prune(history: &mut VecDeque<(Instant, u64)>, now: Instant) {
+    while history.front().is_some_and(|&(at, _)| now.duration_since(at) >= WINDOW) {
+        history.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn averages_over_the_last_second() {
+        let monitor = BandwidthMonitor::new();
+        let start = Instant::now();
+        
+        // 16,000 bytes over half a second is 128kbps averaged over the second
+        for i in 0..5 {
+            record(&monitor.sent_history, 3_200, start + Duration::from_millis(i * 100));
+        }
+        assert_eq!(rolling_kbps(&monitor.sent_history, start + Duration::from_millis(500)), 128.0);
+        
+        // The first two have dropped out of the window
+        assert_eq!(rolling_kbps(&monitor.sent_history, start + Duration::from_millis(1_150)), 76.8);
+        assert_eq!(rolling_kbps(&monitor.sent_history, start + Duration::from_secs(2)), 0.0);
+        assert_eq!(rolling_kbps(&monitor.received_history, start), 0.0);
+    }
+    
+    #[test]
+    fn counts_totals() {
+        let monitor = BandwidthMonitor::new();
+        
+        monitor.record_sent(100);
+        monitor.record_sent(50);
+        monitor.record_received(10);
+        
+        assert_eq!(monitor.bytes_sent.load(Ordering::Relaxed), 150);
+        assert_eq!(monitor.bytes_received.load(Ordering::Relaxed), 10);
+        assert_eq!(monitor.download_kbps(), 0.08);
+    }
+    
+    #[test]
+    fn throttles_over_the_limit_at_most_once_a_second() {
+        let monitor = BandwidthMonitor::new();
+        let start = Instant::now();
+        record(&monitor.sent_history, 20_000, start);
+        
+        // 160kbps
+        assert!(!monitor.should_throttle_at(None, start));
+        assert!(!monitor.should_throttle_at(Some(200), start));
+        assert!(monitor.should_throttle_at(Some(128), start));
+        assert!(!monitor.should_throttle_at(Some(128), start + Duration::from_millis(500)));
+        
+        record(&monitor.sent_history, 20_000, start + Duration::from_millis(900));
+        assert!(monitor.should_throttle_at(Some(128), start + Duration::from_millis(1_000)));
+    }
+}
@@ -1,23 +1,167 @@
 use anyhow::Result;
 use directories::ProjectDirs;
+use egui::Color32;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::PathBuf;
+use uuid::Uuid;
+
+use open_reverb_common::models::VideoCodec;
+use crate::audio::{self, agc, vad};
+use crate::connection::e2e;
+use crate::ui::style;
+use crate::video::encoding::VideoEncoding;
+use crate::video::hw_decode::DecoderBackend;
+use crate::video::{VideoFramerate, VideoResolution};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ClientConfig {
+    // Set once the first-run wizard has been completed
+    pub first_run_complete: bool,
     pub server_url: String,
+    pub transport: Transport,
     pub username: Option<String>,
     pub remember_credentials: bool,
     pub theme: Theme,
+    // Colours used while the theme is Custom
+    pub custom_theme: CustomTheme,
     pub notification_sounds: bool,
     
     // Media settings
     pub audio_input_device: Option<String>,
     pub audio_output_device: Option<String>,
     pub video_device: Option<String>,
+    // Monitor shared when screen sharing, by its place in the list of screens
+    pub screen_index: u32,
     pub audio_volume: f32,
     pub microphone_volume: f32,
+    pub delay_compensation_enabled: bool,
+    // Voice buffered from each user before it's played, to ride out network jitter
+    pub jitter_buffer_ms: u32,
+    // Suppress steady background noise in the microphone; strength (0.0 - 1.0) is how much of
+    // the denoised signal is sent, mixed with the original
+    pub noise_reduction_enabled: bool,
+    pub noise_reduction_strength: f32,
+    // Cancel the echo of the speakers from the microphone, for use without headphones.
+    // `aec_delay_ms` is how long sound takes to get from one to the other.
+    pub echo_cancellation: bool,
+    pub aec_delay_ms: u32,
+    // Most voice is sent at, in kbps; the channel's bitrate applies when it's lower
+    pub voice_bitrate_kbps: u32,
+    // Turn the microphone up or down so it's sent at about `agc_target_dbfs`
+    pub agc_enabled: bool,
+    pub agc_target_dbfs: f32,
+    // Only send the microphone while it picks up voice: frames with an RMS (0.0 - 1.0) over
+    // `vad_threshold`, and the `vad_hangover_frames` 20ms frames after them
+    pub vad_enabled: bool,
+    pub vad_threshold: f32,
+    pub vad_hangover_frames: u32,
+    // Only send the microphone while `ptt_key` is held; the key is stored by its egui name, e.g. "V"
+    pub ptt_enabled: bool,
+    pub ptt_key: Option<String>,
+    // Blur what's behind you on camera; strength is 0.0 - 1.0
+    pub background_blur_enabled: bool,
+    pub blur_strength: f32,
+    // Blur and pause the camera while the window isn't focused
+    pub privacy_mode_on_focus_loss: bool,
+    // Show transcripts of the channel's voice, when the server provides them
+    pub transcription_enabled: bool,
+    // Most participants' videos that can be popped out of the grid at once
+    pub max_detached_panes: u8,
+    // Captured frames that can wait to be sent before new ones are dropped. More smooths
+    // over hiccups in the connection, fewer keeps latency down.
+    pub audio_send_buffer_frames: usize,
+    pub video_send_buffer_frames: usize,
+    // Outgoing media is cut back to stay under this, for metered connections; unlimited when unset
+    pub max_upload_kbps: Option<u32>,
+    // Codec outgoing video is encoded with
+    pub preferred_video_codec: VideoCodec,
+    // How each outgoing frame is compressed
+    pub video_encoding: VideoEncoding,
+    // Size and rate the camera and screen are captured at
+    pub selected_video_resolution: VideoResolution,
+    pub selected_video_framerate: VideoFramerate,
+    // Decode incoming H.264 on the GPU, when there's a hardware decoder
+    pub hardware_video_decode: bool,
+    // Voice frames sent with one key in an encrypted channel before it's replaced
+    pub key_rotation_interval_frames: u32,
+    // Colour of the microphone waveform shown while recording and in settings
+    pub waveform_color: Color32,
+    
+    // SHA-256 fingerprint of the server's TLS certificate; when set, no other certificate is accepted
+    pub pinned_cert_fingerprint: Option<String>,
+    // Pin whatever certificate the server presents on the first TLS connection
+    pub trust_on_first_use: bool,
+    
+    // STUN server the connection test checks UDP against, e.g. "stun.example.com:3478"
+    pub stun_server: Option<String>,
+    
+    // Backoff between reconnect attempts; the defaults are used unless enabled
+    pub use_reconnect_config: bool,
+    pub reconnect: ReconnectConfig,
+    
+    // Degrade the connection on purpose when testing; never set in normal use
+    pub simulate_network: Option<NetworkSimParams>,
+    
+    // Where crash reports are sent, once the user has opted in
+    pub crash_report_url: Option<String>,
+    pub send_crash_reports: bool,
+    
+    // Favourite channels, shown at the top of the channel list on their server
+    pub bookmarked_channels: Vec<BookmarkedChannel>,
+}
+
+// Kept by channel ID, so it follows the channel when it's renamed. The name is only shown
+// once the channel no longer exists.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BookmarkedChannel {
+    pub server_url: String,
+    pub channel_id: Uuid,
+    pub display_name: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct NetworkSimParams {
+    pub latency_ms: u32,
+    pub jitter_ms: u32,
+    // Fraction of frames lost (0.0 - 1.0)
+    pub drop_rate: f32,
+    // Fraction of frames with a flipped bit (0.0 - 1.0)
+    pub corrupt_rate: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // Each delay is this many times the one before, up to the max
+    pub multiplier: f64,
+    // Add up to 10% to each delay at random
+    pub jitter: bool,
+    // Give up after this many attempts; None keeps trying
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 500,
+            max_delay_ms: 30_000,
+            multiplier: 2.0,
+            jitter: true,
+            max_attempts: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Tls,
+    WebSocket,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -25,23 +169,163 @@ pub enum Theme {
     Light,
     Dark,
     System,
+    Custom,
+}
+
+// User-chosen colours, as RGB. Starts out as the built-in dark palette.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CustomTheme {
+    pub background: [u8; 3],
+    pub accent: [u8; 3],
+    pub text: [u8; 3],
+    pub secondary_text: [u8; 3],
+}
+
+impl Default for CustomTheme {
+    fn default() -> Self {
+        let rgb = |color: Color32| [color.r(), color.g(), color.b()];
+        Self {
+            background: rgb(style::BACKGROUND_COLOR),
+            accent: rgb(style::ACCENT_COLOR),
+            text: rgb(style::TEXT_COLOR),
+            secondary_text: rgb(style::SECONDARY_TEXT_COLOR),
+        }
+    }
+}
+
+impl CustomTheme {
+    // For sharing with other users
+    pub fn to_json(self) -> String {
+        serde_json::to_string(&self).expect("a theme is always serializable")
+    }
+    
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json.trim())?)
+    }
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
+            first_run_complete: false,
             server_url: "127.0.0.1:8080".to_string(),
+            transport: Transport::Tcp,
             username: None,
             remember_credentials: false,
             theme: Theme::System,
+            custom_theme: CustomTheme::default(),
             notification_sounds: true,
             
             // Media settings
             audio_input_device: None,
             audio_output_device: None,
             video_device: None,
+            screen_index: 0,
             audio_volume: 1.0,
             microphone_volume: 1.0,
+            delay_compensation_enabled: true,
+            jitter_buffer_ms: audio::DEFAULT_JITTER_BUFFER_MS,
+            noise_reduction_enabled: false,
+            noise_reduction_strength: 1.0,
+            echo_cancellation: false,
+            aec_delay_ms: audio::DEFAULT_AEC_DELAY_MS,
+            voice_bitrate_kbps: audio::DEFAULT_VOICE_BITRATE_KBPS,
+            agc_enabled: false,
+            agc_target_dbfs: agc::DEFAULT_TARGET_DBFS,
+            vad_enabled: false,
+            vad_threshold: vad::DEFAULT_THRESHOLD,
+            vad_hangover_frames: vad::DEFAULT_HANGOVER_FRAMES,
+            ptt_enabled: false,
+            ptt_key: None,
+            background_blur_enabled: false,
+            blur_strength: 0.5,
+            privacy_mode_on_focus_loss: false,
+            transcription_enabled: false,
+            max_detached_panes: 4,
+            audio_send_buffer_frames: 10,
+            video_send_buffer_frames: 2,
+            max_upload_kbps: None,
+            preferred_video_codec: VideoCodec::default(),
+            video_encoding: VideoEncoding::default(),
+            selected_video_resolution: VideoResolution::default(),
+            selected_video_framerate: VideoFramerate::default(),
+            hardware_video_decode: false,
+            key_rotation_interval_frames: e2e::DEFAULT_KEY_ROTATION_INTERVAL_FRAMES,
+            waveform_color: style::ACCENT_COLOR,
+            
+            pinned_cert_fingerprint: None,
+            trust_on_first_use: false,
+            
+            stun_server: None,
+            
+            use_reconnect_config: false,
+            reconnect: ReconnectConfig::default(),
+            
+            simulate_network: None,
+            
+            crash_report_url: None,
+            send_crash_reports: false,
+            
+            bookmarked_channels: Vec::new(),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn decoder_backend(&self) -> DecoderBackend {
+        if self.hardware_video_decode {
+            DecoderBackend::Hardware
+        } else {
+            DecoderBackend::Software
+        }
+    }
+    
+    // Push-to-talk only applies once a key has been picked
+    pub fn ptt_configured(&self) -> bool {
+        self.ptt_enabled && self.ptt_key.is_some()
+    }
+    
+    pub fn reconnect_config(&self) -> ReconnectConfig {
+        if self.use_reconnect_config {
+            self.reconnect.clone()
+        } else {
+            ReconnectConfig::default()
+        }
+    }
+    
+    pub fn bookmarks_for_server(&self, server_url: &str) -> Vec<BookmarkedChannel> {
+        self.bookmarked_channels
+            .iter()
+            .filter(|b| b.server_url == server_url)
+            .cloned()
+            .collect()
+    }
+    
+    pub fn is_bookmarked(&self, server_url: &str, channel_id: Uuid) -> bool {
+        self.bookmarked_channels.iter().any(|b| b.server_url == server_url && b.channel_id == channel_id)
+    }
+    
+    // Bookmarking a channel again only updates its name
+    pub fn add_bookmark(&mut self, server_url: &str, channel_id: Uuid, display_name: String) {
+        match self.bookmarked_channels.iter_mut().find(|b| b.server_url == server_url && b.channel_id == channel_id) {
+            Some(bookmark) => bookmark.display_name = display_name,
+            None => self.bookmarked_channels.push(BookmarkedChannel {
+                server_url: server_url.to_string(),
+                channel_id,
+                display_name,
+            }),
+        }
+    }
+    
+    pub fn remove_bookmark(&mut self, server_url: &str, channel_id: Uuid) {
+        self.bookmarked_channels.retain(|b| !(b.server_url == server_url && b.channel_id == channel_id));
+    }
+    
+    pub fn toggle_bookmark(&mut self, server_url: &str, channel_id: Uuid, display_name: String) {
+        if self.is_bookmarked(server_url, channel_id) {
+            self.remove_bookmark(server_url, channel_id);
+        } else {
+            self.add_bookmark(server_url, channel_id, display_name);
         }
     }
 }
@@ -85,4 +369,80 @@ pub fn save_config(config: &ClientConfig) -> Result<()> {
     fs::write(config_path, json)?;
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const SERVER: &str = "127.0.0.1:8080";
+    
+    #[test]
+    fn adding_a_bookmark_twice_keeps_one() {
+        let mut config = ClientConfig::default();
+        let channel_id = Uuid::new_v4();
+        
+        config.add_bookmark(SERVER, channel_id, "General".to_string());
+        config.add_bookmark(SERVER, channel_id, "General (renamed)".to_string());
+        
+        let bookmarks = config.bookmarks_for_server(SERVER);
+        assert_eq!(bookmarks.len(), 1);
+        assert_eq!(bookmarks[0].display_name, "General (renamed)");
+    }
+    
+    #[test]
+    fn removing_a_bookmark_twice_is_harmless() {
+        let mut config = ClientConfig::default();
+        let (kept, removed) = (Uuid::new_v4(), Uuid::new_v4());
+        config.add_bookmark(SERVER, kept, "General".to_string());
+        config.add_bookmark(SERVER, removed, "Gaming".to_string());
+        
+        config.remove_bookmark(SERVER, removed);
+        config.remove_bookmark(SERVER, removed);
+        
+        assert!(!config.is_bookmarked(SERVER, removed));
+        assert!(config.is_bookmarked(SERVER, kept));
+    }
+    
+    #[test]
+    fn toggling_a_bookmark_twice_removes_it() {
+        let mut config = ClientConfig::default();
+        let channel_id = Uuid::new_v4();
+        
+        config.toggle_bookmark(SERVER, channel_id, "General".to_string());
+        assert!(config.is_bookmarked(SERVER, channel_id));
+        
+        config.toggle_bookmark(SERVER, channel_id, "General".to_string());
+        assert!(config.bookmarked_channels.is_empty());
+    }
+    
+    #[test]
+    fn bookmarks_belong_to_their_server() {
+        let mut config = ClientConfig::default();
+        let channel_id = Uuid::new_v4();
+        config.add_bookmark(SERVER, channel_id, "General".to_string());
+        config.add_bookmark("example.com:8080", Uuid::new_v4(), "Elsewhere".to_string());
+        
+        let bookmarks = config.bookmarks_for_server(SERVER);
+        assert_eq!(bookmarks.len(), 1);
+        assert_eq!(bookmarks[0].channel_id, channel_id);
+        assert!(!config.is_bookmarked("example.com:8080", channel_id));
+    }
+    
+    #[test]
+    fn custom_theme_round_trips_through_json() {
+        let theme = CustomTheme {
+            background: [10, 20, 30],
+            accent: [200, 50, 0],
+            text: [255, 255, 240],
+            secondary_text: [128, 128, 128],
+        };
+        
+        let json = theme.to_json();
+        
+        assert_eq!(json, r#"{"background":[10,20,30],"accent":[200,50,0],"text":[255,255,240],"secondary_text":[128,128,128]}"#);
+        assert_eq!(CustomTheme::from_json(&format!("  {}\n", json)).unwrap(), theme);
+        assert!(CustomTheme::from_json(r#"{"background":[10,20,30]}"#).is_err());
+        assert!(CustomTheme::from_json(r#"{"background":[300,0,0],"accent":[0,0,0],"text":[0,0,0],"secondary_text":[0,0,0]}"#).is_err());
+    }
+}
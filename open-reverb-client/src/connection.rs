@@ -1,21 +1,181 @@
-use anyhow::Result;
+pub mod e2e;
+pub mod file_transfer;
+pub mod reconnect;
+pub mod simulator;
+pub mod tester;
+pub mod tls;
+pub mod websocket;
+
+use bytes::Bytes;
+use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::thread;
-use std::sync::{Arc, Mutex};
-use tracing::{error, info};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 use crossbeam_channel::{bounded, Sender, Receiver};
 
+use open_reverb_common::error::{OpenReverbError, Result};
+use open_reverb_common::features::Features;
+use open_reverb_common::models::{AudioQuality, ChannelType, ExportFormat, Server, VideoQuality};
 use open_reverb_common::protocol::Message;
+use crate::bandwidth::BandwidthMonitor;
+use crate::config::{self, ClientConfig};
+use crate::sync;
+use self::e2e::E2EVoice;
+use self::file_transfer::FileTransfers;
+use self::reconnect::{ReconnectScheduler, ReconnectState};
+use self::simulator::NetworkSimulator;
+use self::tls::CertificateCheck;
+use self::websocket::WebSocketConnection;
+
+// Optional features this client has UI for. The server turns off any it doesn't support.
+pub const CLIENT_FEATURES: Features = Features::E2E_VOICE.union(Features::TRANSCRIPTION).union(Features::SERVER_RECORDING);
+
+// A transport carries serialized messages to and from the server, one frame per message
+pub trait Transport: Send + Sync {
+    fn send(&mut self, bytes: Bytes) -> anyhow::Result<()>;
+    
+    // Returns the next complete frame, or None if nothing is available yet
+    fn recv(&mut self) -> anyhow::Result<Option<Bytes>>;
+}
+
+// Raw TCP transport using a 4-byte big-endian length prefix per frame.
+// The stream can be anything byte-oriented, such as a TLS session over TCP.
+pub struct TcpTransport<S = TcpStream> {
+    stream: S,
+    read_buffer: Vec<u8>,
+}
+
+impl TcpTransport {
+    pub fn connect(server_url: &str) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(server_url)?;
+        stream.set_nonblocking(true)?;
+        
+        Ok(Self::new(stream))
+    }
+}
+
+impl<S: Read + Write> TcpTransport<S> {
+    // The stream should already be non-blocking
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            read_buffer: Vec::new(),
+        }
+    }
+    
+    fn take_frame(&mut self) -> Option<Bytes> {
+        if self.read_buffer.len() < 4 {
+            return None;
+        }
+        
+        let len_bytes = [self.read_buffer[0], self.read_buffer[1], self.read_buffer[2], self.read_buffer[3]];
+        let message_len = u32::from_be_bytes(len_bytes) as usize;
+        
+        if self.read_buffer.len() < 4 + message_len {
+            return None;
+        }
+        
+        let frame: Vec<u8> = self.read_buffer.drain(..4 + message_len).skip(4).collect();
+        Some(Bytes::from(frame))
+    }
+}
+
+impl<S: Read + Write + Send + Sync> Transport for TcpTransport<S> {
+    fn send(&mut self, bytes: Bytes) -> anyhow::Result<()> {
+        let len_bytes = (bytes.len() as u32).to_be_bytes();
+        
+        // Send message length
+        self.stream.write_all(&len_bytes)?;
+        
+        // Send message data
+        self.stream.write_all(&bytes)?;
+        
+        self.stream.flush()?;
+        
+        Ok(())
+    }
+    
+    fn recv(&mut self) -> anyhow::Result<Option<Bytes>> {
+        if let Some(frame) = self.take_frame() {
+            return Ok(Some(frame));
+        }
+        
+        let mut buffer = [0; 4096];
+        
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(anyhow::anyhow!("Connection closed by server")),
+                Ok(n) => self.read_buffer.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        
+        Ok(self.take_frame())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectionError {
+    #[error("Server certificate does not match the pinned fingerprint (expected {expected}, got {got})")]
+    CertificatePinMismatch { expected: String, got: String },
+}
+
+// How often the server is pinged to measure latency
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+// Pings not answered within this long are forgotten
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Channels asked for at a time, on servers too big to send everything on login
+pub const CHANNEL_PAGE_SIZE: u32 = 100;
+
+// Live statistics about the quality of the media connection
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionQuality {
+    // Average delay added by the mixer to keep speakers aligned
+    pub mix_latency_ms: f32,
+    // Incoming video is being decoded on the GPU
+    pub hw_decode_active: bool,
+}
 
 pub struct Connection {
     connected: bool,
     user_id: Option<Uuid>,
-    stream: Option<TcpStream>,
+    stream: Option<Box<dyn Transport>>,
     message_sender: Sender<Message>,
     message_receiver: Receiver<Message>,
     current_channel_id: Option<Uuid>,
+    // Fingerprint of the certificate presented by the server, for TLS connections
+    server_cert_fingerprint: Option<String>,
+    e2e: E2EVoice,
+    // Files being sent to or from other users
+    file_transfers: FileTransfers,
+    
+    // Pings awaiting a pong, by nonce
+    ping_sent_at: HashMap<u32, Instant>,
+    last_ping: Option<Instant>,
+    next_ping_nonce: u32,
+    // Round trip time of the most recently answered ping
+    last_latency: Option<Duration>,
+    // Features both we and the server support, once the server answers our Hello
+    negotiated_features: Features,
+    // Bytes sent to and received from the server
+    bandwidth: BandwidthMonitor,
+    
+    // Where and how connect_with_config last connected, to reconnect the same way
+    last_connect: Option<(String, ClientConfig)>,
+    // Backoff for getting back to the server when the connection drops; none unless connected
+    // with a config
+    reconnect_policy: Option<ReconnectScheduler>,
+    reconnect_state: ReconnectState,
+    // Username and password of the last login, only kept when the user asked to remember them
+    credentials: Option<(String, String)>,
+    // From the last successful login, to log back in after reconnecting without the password
+    session_token: Option<String>,
 }
 
 impl Connection {
@@ -28,6 +188,20 @@ impl Connection {
             message_sender: sender,
             message_receiver: receiver,
             current_channel_id: None,
+            server_cert_fingerprint: None,
+            e2e: E2EVoice::new(),
+            file_transfers: FileTransfers::new(file_transfer::default_download_dir()),
+            ping_sent_at: HashMap::new(),
+            last_ping: None,
+            next_ping_nonce: 0,
+            last_latency: None,
+            negotiated_features: Features::empty(),
+            bandwidth: BandwidthMonitor::new(),
+            last_connect: None,
+            reconnect_policy: None,
+            reconnect_state: ReconnectState::Idle,
+            credentials: None,
+            session_token: None,
         }
     }
     
@@ -36,34 +210,142 @@ impl Connection {
     }
     
     pub fn connect(&mut self, server_url: &str) -> Result<()> {
+        self.connect_with_transport(server_url, config::Transport::Tcp)
+    }
+    
+    pub fn connect_with_transport(&mut self, server_url: &str, transport: config::Transport) -> Result<()> {
         if self.connected {
             return Ok(());
         }
         
-        info!("Connecting to server at {}", server_url);
+        info!("Connecting to server at {} over {:?}", server_url, transport);
         
         // Connect to the server
-        let stream = TcpStream::connect(server_url)?;
-        stream.set_nonblocking(true)?;
+        let stream: Box<dyn Transport> = match transport {
+            config::Transport::Tcp => Box::new(TcpTransport::connect(server_url)?),
+            config::Transport::Tls => return self.connect_tls(server_url, CertificateCheck::WebPki),
+            config::Transport::WebSocket => Box::new(WebSocketConnection::connect(server_url)?),
+        };
         
         // Store the stream
         self.stream = Some(stream);
         self.connected = true;
         
+        self.send_hello()
+    }
+    
+    fn connect_tls(&mut self, server_url: &str, check: CertificateCheck) -> Result<()> {
+        let (stream, fingerprint) = tls::connect(server_url, check)?;
+        info!("Server certificate fingerprint: {}", fingerprint);
+        
+        self.stream = Some(Box::new(stream));
+        self.server_cert_fingerprint = Some(fingerprint);
+        self.connected = true;
+        
+        self.send_hello()
+    }
+    
+    // Tell the server which features we support; it answers with a HelloAck
+    fn send_hello(&mut self) -> Result<()> {
+        self.send_message(&Message::Hello { supported_features: CLIENT_FEATURES })
+    }
+    
+    pub fn negotiated_features(&self) -> Features {
+        self.negotiated_features
+    }
+    
+    pub fn has_feature(&self, feature: Features) -> bool {
+        self.negotiated_features.contains(feature)
+    }
+    
+    pub fn bandwidth(&self) -> &BandwidthMonitor {
+        &self.bandwidth
+    }
+    
+    pub fn server_cert_fingerprint(&self) -> Option<&str> {
+        self.server_cert_fingerprint.as_deref()
+    }
+    
+    // Connect using the transport and network simulation settings from the config. If the
+    // connection drops later, it is reconnected with the config's backoff.
+    pub fn connect_with_config(&mut self, server_url: &str, config: &ClientConfig) -> Result<()> {
+        if self.connected {
+            return Ok(());
+        }
+        
+        self.reconnect_state = ReconnectState::Idle;
+        self.reconnect_policy = Some(ReconnectScheduler::new(config.reconnect_config()));
+        self.last_connect = Some((server_url.to_string(), config.clone()));
+        
+        self.open(server_url, config)?;
+        
+        // Reconnect to the same server we trusted on first use, not whichever answers next time
+        if let (Some((_, config)), Some(fingerprint)) = (&mut self.last_connect, &self.server_cert_fingerprint) {
+            if config.trust_on_first_use {
+                config.pinned_cert_fingerprint.get_or_insert_with(|| fingerprint.clone());
+            }
+        }
+        
         Ok(())
     }
     
+    fn open(&mut self, server_url: &str, config: &ClientConfig) -> Result<()> {
+        match config.transport {
+            config::Transport::Tls => {
+                let check = match (&config.pinned_cert_fingerprint, config.trust_on_first_use) {
+                    (Some(fingerprint), _) => CertificateCheck::Pinned(fingerprint),
+                    (None, true) => CertificateCheck::TrustOnFirstUse,
+                    (None, false) => CertificateCheck::WebPki,
+                };
+                info!("Connecting to server at {} over TLS", server_url);
+                self.connect_tls(server_url, check)?;
+            }
+            transport => self.connect_with_transport(server_url, transport)?,
+        }
+        
+        self.e2e.set_rotation_interval(config.key_rotation_interval_frames);
+        
+        if let Some(params) = &config.simulate_network {
+            warn!("Simulating a degraded network: {:?}", params);
+            if let Some(stream) = self.stream.take() {
+                self.stream = Some(Box::new(NetworkSimulator::new(stream, params.clone())));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    // Also stops any reconnecting and forgets how to log back in
     pub fn disconnect(&mut self) {
+        self.close();
+        self.last_connect = None;
+        self.reconnect_policy = None;
+        self.reconnect_state = ReconnectState::Idle;
+        self.credentials = None;
+        self.session_token = None;
+    }
+    
+    fn close(&mut self) {
         self.stream = None;
+        self.server_cert_fingerprint = None;
         self.connected = false;
         self.user_id = None;
+        self.ping_sent_at.clear();
+        self.last_ping = None;
+        self.last_latency = None;
+        self.negotiated_features = Features::empty();
+        // The server gives up on transfers when either side disconnects
+        self.file_transfers.clear();
     }
     
     pub fn login(&mut self, username: &str, password: &str) -> Result<()> {
         if !self.connected || self.stream.is_none() {
-            return Err(anyhow::anyhow!("Not connected to server"));
+            return Err(OpenReverbError::network("Not connected to server"));
         }
         
+        let remember = self.last_connect.as_ref().is_some_and(|(_, config)| config.remember_credentials);
+        self.credentials = remember.then(|| (username.to_string(), password.to_string()));
+        
         let login_request = Message::LoginRequest {
             username: username.to_string(),
             password: password.to_string(),
@@ -74,76 +356,411 @@ impl Connection {
         Ok(())
     }
     
+    // Second step of logging in, when the server asks for a TOTP code
+    pub fn send_totp_code(&mut self, user_id: Uuid, code: String) -> Result<()> {
+        if !self.connected || self.stream.is_none() {
+            return Err(OpenReverbError::network("Not connected to server"));
+        }
+        
+        self.send_message(&Message::TOTPResponse { user_id, code })?;
+        
+        Ok(())
+    }
+    
+    // Log in with the token from an earlier LoginResponse instead of a password
+    pub fn resume_session(&mut self, token: &str) -> Result<()> {
+        if !self.connected || self.stream.is_none() {
+            return Err(OpenReverbError::network("Not connected to server"));
+        }
+        
+        self.send_message(&Message::ResumeSession { token: token.to_string() })?;
+        
+        Ok(())
+    }
+    
     pub fn process_messages(&mut self) -> Vec<Message> {
         let mut messages = Vec::new();
         
+        if let ReconnectState::WaitingRetry { attempt, wake_at } = self.reconnect_state {
+            if Instant::now() >= wake_at {
+                self.reconnect(attempt);
+            }
+        }
+        
         if !self.connected || self.stream.is_none() {
             return messages;
         }
         
-        // Try to read messages from the stream
-        if let Some(stream) = &mut self.stream {
-            let mut buffer = [0; 4096];
-            
-            match stream.read(&mut buffer) {
-                Ok(0) => {
-                    // Connection closed
-                    info!("Connection closed by server");
-                    self.disconnect();
-                }
-                Ok(n) => {
-                    // Process received data
-                    if let Ok(message) = serde_json::from_slice::<Message>(&buffer[..n]) {
+        // Send anything queued through get_sender() since the last call
+        while let Ok(message) = self.message_receiver.try_recv() {
+            if let Err(e) = self.send_message(&message) {
+                error!("Error sending queued message: {}", e);
+                break;
+            }
+        }
+        
+        // Read every complete frame that is currently available
+        while let Some(stream) = &mut self.stream {
+            match stream.recv() {
+                Ok(Some(frame)) => {
+                    self.bandwidth.record_received(frame.len());
+                    if let Ok(message) = serde_json::from_slice::<Message>(&frame) {
+                        if self.handle_reconnect_login(&message) {
+                            continue;
+                        }
+                        
                         // Handle login response to save user ID
                         if let Message::LoginResponse {
                             success: true,
                             user_id: Some(uid),
+                            ref token,
                             ..
                         } = message
                         {
                             self.user_id = Some(uid);
+                            if token.is_some() {
+                                self.session_token = token.clone();
+                            }
+                            
+                            // Announce our key so others can share encrypted channel keys with us
+                            let announce = Message::E2EPublicKey { user_id: uid, public_key: self.e2e.public_key() };
+                            if let Err(e) = self.send_message(&announce) {
+                                error!("Error sending public key: {}", e);
+                            }
+                        }
+                        
+                        if let Message::HelloAck { negotiated_features } = message {
+                            self.negotiated_features = negotiated_features;
+                        }
+                        
+                        // The server pings to check we're still here
+                        if let Message::Ping { nonce } = message {
+                            if let Err(e) = self.send_message(&Message::Pong { nonce }) {
+                                error!("Error answering ping: {}", e);
+                            }
+                            continue;
                         }
                         
-                        messages.push(message);
+                        // Pongs are only used to measure latency
+                        if let Message::Pong { nonce } = message {
+                            if let Some(sent_at) = self.ping_sent_at.remove(&nonce) {
+                                self.last_latency = Some(sent_at.elapsed());
+                            }
+                            continue;
+                        }
+                        
+                        if let Some(message) = self.handle_e2e(message).and_then(|m| self.handle_file_transfer(m)) {
+                            messages.push(message);
+                        }
                     }
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                Ok(None) => {
                     // No data available, that's fine
+                    break;
                 }
                 Err(e) => {
-                    error!("Error reading from socket: {}", e);
-                    self.disconnect();
+                    error!("Error reading from server: {}", e);
+                    self.connection_lost();
+                    break;
                 }
             }
         }
         
+        // Keep accepted files going, a few chunks at a time
+        for message in self.file_transfers.next_chunks() {
+            if let Err(e) = self.send_message(&message) {
+                error!("Error sending file: {}", e);
+                break;
+            }
+        }
+        
         messages
     }
     
+    pub fn reconnect_state(&self) -> ReconnectState {
+        self.reconnect_state
+    }
+    
+    // Retry later if we were logged in and there's a way to log back in
+    fn connection_lost(&mut self) {
+        let attempt = match self.reconnect_state {
+            ReconnectState::Reconnecting { attempt } => attempt + 1,
+            _ => 0,
+        };
+        let can_log_in = self.session_token.is_some() || self.credentials.is_some();
+        let was_logged_in = self.user_id.is_some() || matches!(self.reconnect_state, ReconnectState::Reconnecting { .. });
+        
+        self.close();
+        self.reconnect_state = ReconnectState::Idle;
+        if can_log_in && was_logged_in {
+            self.schedule_reconnect(attempt);
+        }
+    }
+    
+    fn schedule_reconnect(&mut self, attempt: u32) {
+        self.reconnect_state = match self.reconnect_policy.as_ref().and_then(|policy| policy.schedule(attempt)) {
+            Some(delay) => ReconnectState::WaitingRetry { attempt, wake_at: Instant::now() + delay },
+            None => {
+                warn!("Giving up reconnecting after {} attempts", attempt);
+                ReconnectState::Idle
+            }
+        };
+    }
+    
+    fn reconnect(&mut self, attempt: u32) {
+        let Some((server_url, config)) = self.last_connect.clone() else {
+            self.reconnect_state = ReconnectState::Idle;
+            return;
+        };
+        
+        info!("Reconnecting to {} (attempt {})", server_url, attempt + 1);
+        if let Err(e) = self.open(&server_url, &config) {
+            warn!("Failed to reconnect: {}", e);
+            self.schedule_reconnect(attempt + 1);
+            return;
+        }
+        
+        // The session token saves sending the password again; the server answers with a LoginResponse
+        let login = match (&self.session_token, &self.credentials) {
+            (Some(token), _) => Message::ResumeSession { token: token.clone() },
+            (None, Some((username, password))) => Message::LoginRequest { username: username.clone(), password: password.clone() },
+            (None, None) => {
+                self.close();
+                self.reconnect_state = ReconnectState::Idle;
+                return;
+            }
+        };
+        
+        self.reconnect_state = ReconnectState::Reconnecting { attempt };
+        if let Err(e) = self.send_message(&login) {
+            warn!("Failed to log back in: {}", e);
+            self.connection_lost();
+        }
+    }
+    
+    // Finish logging back in after reconnecting: rejoin the channel we were in, or fall back to
+    // the password if the session token is no good. Returns true if the message was dealt with
+    // here and the app doesn't need it.
+    fn handle_reconnect_login(&mut self, message: &Message) -> bool {
+        if !matches!(self.reconnect_state, ReconnectState::Reconnecting { .. }) {
+            return false;
+        }
+        
+        match message {
+            Message::LoginResponse { success: true, .. } => {
+                info!("Reconnected to server");
+                self.reconnect_state = ReconnectState::Idle;
+                if let Some(channel_id) = self.current_channel_id {
+                    if let Err(e) = self.send_message(&Message::JoinChannel { channel_id, ghost: false }) {
+                        error!("Failed to rejoin channel: {}", e);
+                    }
+                }
+                false
+            }
+            Message::Error { code: 401, .. } if self.session_token.is_some() => {
+                self.session_token = None;
+                match self.credentials.clone() {
+                    Some((username, password)) => {
+                        info!("Session token rejected, logging back in with the password");
+                        if let Err(e) = self.send_message(&Message::LoginRequest { username, password }) {
+                            warn!("Failed to log back in: {}", e);
+                            self.connection_lost();
+                        }
+                        true
+                    }
+                    None => {
+                        self.close();
+                        self.reconnect_state = ReconnectState::Idle;
+                        false
+                    }
+                }
+            }
+            Message::LoginResponse { success: false, .. } => {
+                // Logging in again won't go any better; leave it to the user
+                self.close();
+                self.reconnect_state = ReconnectState::Idle;
+                false
+            }
+            _ => false,
+        }
+    }
+    
+    // Keep the encryption state in step with the server, decrypting voice as it arrives.
+    // Returns the message to pass on to the app, if any.
+    fn handle_e2e(&mut self, message: Message) -> Option<Message> {
+        let me = self.user_id.unwrap_or_default();
+        let mut decrypted = None;
+        
+        let replies = match &message {
+            Message::E2EPublicKey { user_id, public_key } if *user_id != me => {
+                self.e2e.add_peer_key(me, *user_id, public_key)
+            }
+            Message::ServerInfo { server: Server { channels, .. } } | Message::ChannelList { channels, .. } => {
+                for channel in channels.iter().filter(|c| c.e2e_encrypted) {
+                    self.e2e.set_channel_encrypted(channel.id);
+                }
+                Vec::new()
+            }
+            Message::ChannelUpdate { channel } if channel.e2e_encrypted => {
+                self.e2e.set_channel_encrypted(channel.id);
+                Vec::new()
+            }
+            Message::E2EChannelEnabled { channel_id } => {
+                self.e2e.set_channel_encrypted(*channel_id);
+                Vec::new()
+            }
+            Message::ChannelMediaState { channel_id, members } => {
+                self.e2e.update_members(me, *channel_id, members.clone())
+            }
+            Message::E2EVoiceKey { channel_id, sender_id, recipient_id, epoch, encrypted_key } => {
+                if *recipient_id == me {
+                    if let Err(e) = self.e2e.receive_key(*channel_id, *sender_id, *epoch, encrypted_key) {
+                        warn!("Ignoring voice key: {}", e);
+                    }
+                }
+                return None;
+            }
+            // The new key follows in an E2EVoiceKey
+            Message::KeyRotationInit { .. } => return None,
+            Message::VoiceData { user_id, channel_id, seq, captured_at_ms, epoch, data } if self.e2e.is_encrypted(*channel_id) => {
+                let data = self.e2e.decrypt_frame(*channel_id, *user_id, *seq, *epoch, data).ok()?;
+                decrypted = Some(Message::VoiceData {
+                    user_id: *user_id,
+                    channel_id: *channel_id,
+                    seq: *seq,
+                    captured_at_ms: *captured_at_ms,
+                    epoch: *epoch,
+                    data,
+                });
+                
+                // Frames we receive count towards rotating too, in case we're the one to rotate
+                // but aren't speaking
+                self.e2e.rotate_if_due(me, *channel_id)
+            }
+            _ => Vec::new(),
+        };
+        
+        for reply in replies {
+            if let Err(e) = self.send_message(&reply) {
+                error!("Error sending voice key: {}", e);
+            }
+        }
+        
+        Some(decrypted.unwrap_or(message))
+    }
+    
+    // Chunks are written out as they arrive; the app is only told about offers and how
+    // transfers end. A download that can't be saved is given up on, and the app told with a
+    // FileTransferReject of our own.
+    fn handle_file_transfer(&mut self, message: Message) -> Option<Message> {
+        let failed = match &message {
+            Message::FileTransferOffer { id, to, filename, size_bytes, .. } if Some(*to) == self.user_id => {
+                self.file_transfers.offered(*id, filename.clone(), *size_bytes);
+                return Some(message);
+            }
+            Message::FileTransferAccept { id } => {
+                return self.file_transfers.accepted(*id).then_some(message);
+            }
+            Message::FileTransferReject { id, .. } => {
+                self.file_transfers.cancel(*id);
+                return Some(message);
+            }
+            Message::FileTransferChunk { id, offset, data } => match self.file_transfers.receive_chunk(*id, *offset, data) {
+                Ok(()) => return None,
+                Err(e) => (*id, format!("Couldn't save the file: {}", e)),
+            },
+            Message::FileTransferComplete { id } => match self.file_transfers.complete(*id) {
+                Ok(_) => return Some(message),
+                Err(e) => (*id, format!("Couldn't save the file: {}", e)),
+            },
+            _ => return Some(message),
+        };
+        
+        let (id, reason) = failed;
+        self.file_transfers.cancel(id);
+        let reject = Message::FileTransferReject { id, reason };
+        if let Err(e) = self.send_message(&reject) {
+            error!("Error giving up on a file transfer: {}", e);
+        }
+        Some(reject)
+    }
+    
     fn send_message(&mut self, message: &Message) -> Result<()> {
+        // Voice in an encrypted channel never leaves unencrypted. Without the key yet, the frame is dropped.
+        let encrypted;
+        let mut rotation = Vec::new();
+        let message = match message {
+            Message::VoiceData { user_id, channel_id, seq, captured_at_ms, data, .. } if self.e2e.is_encrypted(*channel_id) => {
+                let (epoch, data) = match self.e2e.encrypt_frame(*channel_id, *user_id, *seq, data) {
+                    Ok(encrypted) => encrypted,
+                    Err(_) => return Ok(()),
+                };
+                encrypted = Message::VoiceData {
+                    user_id: *user_id,
+                    channel_id: *channel_id,
+                    seq: *seq,
+                    captured_at_ms: *captured_at_ms,
+                    epoch,
+                    data,
+                };
+                
+                // The next frame goes out under the new key
+                rotation = self.e2e.rotate_if_due(*user_id, *channel_id);
+                &encrypted
+            }
+            message => message,
+        };
+        
         if let Some(stream) = &mut self.stream {
-            let message_bytes = serde_json::to_vec(message)?;
-            let message_len = message_bytes.len() as u32;
-            let len_bytes = message_len.to_be_bytes();
-            
-            // Send message length
-            stream.write_all(&len_bytes)?;
-            
-            // Send message data
-            stream.write_all(&message_bytes)?;
-            
-            stream.flush()?;
+            for message in std::iter::once(message).chain(&rotation) {
+                let message_bytes = serde_json::to_vec(message)?;
+                self.bandwidth.record_sent(message_bytes.len());
+                stream.send(Bytes::from(message_bytes))?;
+            }
         }
         
         Ok(())
     }
     
-    pub fn join_channel(&mut self, channel_id: Uuid) -> Result<()> {
+    pub fn send_ping(&mut self) -> Result<()> {
+        if !self.connected {
+            return Err(OpenReverbError::network("Not connected to server"));
+        }
+        
+        let now = Instant::now();
+        let nonce = self.next_ping_nonce;
+        self.next_ping_nonce = self.next_ping_nonce.wrapping_add(1);
+        
+        self.ping_sent_at.retain(|_, sent_at| now.duration_since(*sent_at) < PING_TIMEOUT);
+        self.ping_sent_at.insert(nonce, now);
+        self.last_ping = Some(now);
+        
+        self.send_message(&Message::Ping { nonce })
+    }
+    
+    // Ping the server if it's been long enough since the last one
+    pub fn ping_if_due(&mut self) {
+        if !self.connected || self.last_ping.is_some_and(|t| t.elapsed() < PING_INTERVAL) {
+            return;
+        }
+        
+        if let Err(e) = self.send_ping() {
+            error!("Error sending ping: {}", e);
+        }
+    }
+    
+    pub fn get_ping_latency(&self) -> Option<Duration> {
+        self.last_latency
+    }
+    
+    // Ghosts join without the other members being told; only admins and users the server
+    // allows can
+    pub fn join_channel(&mut self, channel_id: Uuid, ghost: bool) -> Result<()> {
         if !self.connected {
-            return Err(anyhow::anyhow!("Not connected to server"));
+            return Err(OpenReverbError::network("Not connected to server"));
         }
         
-        let join_request = Message::JoinChannel { channel_id };
+        let join_request = Message::JoinChannel { channel_id, ghost };
         self.send_message(&join_request)?;
         
         Ok(())
@@ -151,82 +768,361 @@ impl Connection {
     
     pub fn leave_channel(&mut self, channel_id: Uuid) -> Result<()> {
         if !self.connected {
-            return Err(anyhow::anyhow!("Not connected to server"));
+            return Err(OpenReverbError::network("Not connected to server"));
         }
         
         let leave_request = Message::LeaveChannel { channel_id };
         self.send_message(&leave_request)?;
+        self.e2e.leave_channel(channel_id);
         
         Ok(())
     }
     
-    pub fn update_status(&mut self, status: open_reverb_common::models::UserStatus) -> Result<()> {
+    // Turn on end-to-end encrypted voice for a channel we're in. We create its voice key.
+    pub fn enable_e2e(&mut self, channel_id: Uuid) -> Result<()> {
         if !self.connected || self.user_id.is_none() {
-            return Err(anyhow::anyhow!("Not connected to server or not logged in"));
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
         }
         
-        let status_update = Message::StatusUpdate { 
-            user_id: self.user_id.unwrap(), 
-            status 
-        };
+        if !self.has_feature(Features::E2E_VOICE) {
+            return Err(OpenReverbError::network("The server doesn't support encrypted voice"));
+        }
         
-        self.send_message(&status_update)?;
+        self.e2e.set_channel_encrypted(channel_id);
+        self.e2e.create_channel_key(channel_id);
+        self.send_message(&Message::E2EChannelEnabled { channel_id })?;
         
         Ok(())
     }
     
-    pub fn send_voice_data(&mut self, user_id: Uuid, channel_id: Uuid, data: Vec<u8>) -> Result<()> {
+    pub fn set_channel_topic(&mut self, channel_id: Uuid, topic: String) -> Result<()> {
         if !self.connected || self.user_id.is_none() {
-            return Err(anyhow::anyhow!("Not connected to server or not logged in"));
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
         }
         
-        let voice_data = Message::VoiceData {
-            user_id,
-            channel_id,
-            data,
+        self.send_message(&Message::SetChannelTopic { channel_id, topic })?;
+        
+        Ok(())
+    }
+    
+    // The server's default audio quality is used when `audio_quality` is None
+    pub fn create_channel(
+        &mut self,
+        name: String,
+        description: Option<String>,
+        parent_id: Option<Uuid>,
+        channel_type: ChannelType,
+        audio_quality: Option<AudioQuality>,
+        permanent: bool,
+    ) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::CreateChannel {
+            name,
+            description,
+            parent_id,
+            channel_type,
+            media_bandwidth_limit_kbps: 0,
+            audio_quality,
+            permanent,
+            idle_timeout_secs: None,
+        })?;
+        
+        Ok(())
+    }
+    
+    pub fn delete_channel(&mut self, channel_id: Uuid) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::DeleteChannel { channel_id })?;
+        
+        Ok(())
+    }
+    
+    pub fn set_lobby_mode(&mut self, channel_id: Uuid, enabled: bool) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::SetLobbyMode { channel_id, enabled })?;
+        
+        Ok(())
+    }
+    
+    // Let a user waiting in a channel's lobby in
+    pub fn approve_pending(&mut self, user_id: Uuid, channel_id: Uuid) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::ApprovePending { user_id, channel_id })?;
+        
+        Ok(())
+    }
+    
+    pub fn reject_pending(&mut self, user_id: Uuid, channel_id: Uuid, reason: Option<String>) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::RejectPending { user_id, channel_id, reason })?;
+        
+        Ok(())
+    }
+    
+    // WebRTC signalling, relayed by the server to just the one user. Nothing negotiates a
+    // peer-to-peer connection with these yet.
+    pub fn send_sdp_offer(&mut self, to_user_id: Uuid, sdp: String) -> Result<()> {
+        let from_user_id = match self.user_id {
+            Some(user_id) if self.connected => user_id,
+            _ => return Err(OpenReverbError::network("Not connected to server or not logged in")),
         };
         
-        self.send_message(&voice_data)?;
+        self.send_message(&Message::SDPOffer { from_user_id, to_user_id, sdp })?;
+        
+        Ok(())
+    }
+    
+    pub fn send_sdp_answer(&mut self, to_user_id: Uuid, sdp: String) -> Result<()> {
+        let from_user_id = match self.user_id {
+            Some(user_id) if self.connected => user_id,
+            _ => return Err(OpenReverbError::network("Not connected to server or not logged in")),
+        };
+        
+        self.send_message(&Message::SDPAnswer { from_user_id, to_user_id, sdp })?;
+        
+        Ok(())
+    }
+    
+    pub fn send_ice_candidate(&mut self, to: Uuid, candidate: String) -> Result<()> {
+        let from = match self.user_id {
+            Some(user_id) if self.connected => user_id,
+            _ => return Err(OpenReverbError::network("Not connected to server or not logged in")),
+        };
+        
+        self.send_message(&Message::ICECandidate { from, to, candidate })?;
+        
+        Ok(())
+    }
+    
+    pub fn send_text_message(&mut self, channel_id: Uuid, content: String) -> Result<()> {
+        let user_id = match self.user_id {
+            Some(user_id) if self.connected => user_id,
+            _ => return Err(OpenReverbError::network("Not connected to server or not logged in")),
+        };
+        
+        // The server fills in the timestamp
+        self.send_message(&Message::TextMessage { message_id: Uuid::nil(), user_id, channel_id, content, timestamp: 0 })?;
+        
+        Ok(())
+    }
+    
+    // To a user here or on a federated server; the server fills in the timestamp
+    pub fn send_direct_message(&mut self, to_user_id: Uuid, content: String) -> Result<()> {
+        let user_id = match self.user_id {
+            Some(user_id) if self.connected => user_id,
+            _ => return Err(OpenReverbError::network("Not connected to server or not logged in")),
+        };
+        
+        self.send_message(&Message::DirectMessage { from_user_id: user_id, to_user_id, content, timestamp: 0 })?;
         
         Ok(())
     }
     
-    pub fn send_video_data(&mut self, user_id: Uuid, channel_id: Uuid, data: Vec<u8>) -> Result<()> {
+    // Offer a file to another user. It's sent in chunks once they accept.
+    pub fn send_file(&mut self, to: Uuid, path: PathBuf) -> Result<()> {
+        let from = match self.user_id {
+            Some(user_id) if self.connected => user_id,
+            _ => return Err(OpenReverbError::network("Not connected to server or not logged in")),
+        };
+        
+        let offer = self.file_transfers.offer(from, to, &path)?;
+        self.send_message(&offer)
+    }
+    
+    pub fn accept_file(&mut self, id: Uuid) -> Result<()> {
         if !self.connected || self.user_id.is_none() {
-            return Err(anyhow::anyhow!("Not connected to server or not logged in"));
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
         }
         
-        let video_data = Message::VideoData {
-            user_id,
-            channel_id,
-            data,
+        self.file_transfers.accept(id)?;
+        self.send_message(&Message::FileTransferAccept { id })
+    }
+    
+    pub fn reject_file(&mut self, id: Uuid, reason: String) -> Result<()> {
+        self.file_transfers.cancel(id);
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::FileTransferReject { id, reason })
+    }
+    
+    // Where a download was saved, once its FileTransferComplete has been handled
+    pub fn take_saved_file(&mut self, id: Uuid) -> Option<PathBuf> {
+        self.file_transfers.take_saved(id)
+    }
+    
+    // Have the server echo our voice back, for the loopback test
+    pub fn set_broadcast_self(&mut self, enabled: bool) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::SetBroadcastSelf { enabled })?;
+        
+        Ok(())
+    }
+    
+    pub fn get_speaking_stats(&mut self, channel_id: Uuid) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::GetSpeakingStats { channel_id })?;
+        
+        Ok(())
+    }
+    
+    // Answered with a ChannelList
+    pub fn get_channels(&mut self, offset: u32, limit: u32) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::GetChannels { offset, limit })?;
+        
+        Ok(())
+    }
+    
+    // Everyone online, or only those in `channel_id`. Answered with a UserList.
+    pub fn get_online_users(&mut self, channel_id: Option<Uuid>) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::GetOnlineUsers { channel_id })?;
+        
+        Ok(())
+    }
+    
+    // Admins only. Answered with ServerStats.
+    pub fn get_server_stats(&mut self) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::GetServerStats {})?;
+        
+        Ok(())
+    }
+    
+    // Tell the channel our video is paused, or running again
+    pub fn send_video_quality(&mut self, channel_id: Uuid, quality: VideoQuality) -> Result<()> {
+        let user_id = match (self.connected, self.user_id) {
+            (true, Some(user_id)) => user_id,
+            _ => return Err(OpenReverbError::network("Not connected to server or not logged in")),
         };
         
-        self.send_message(&video_data)?;
+        self.send_message(&Message::VideoQualityUpdate { user_id, channel_id, quality })?;
+        
+        Ok(())
+    }
+    
+    // Admins only. The export arrives in ExportData chunks.
+    pub fn export_messages(&mut self, channel_id: Uuid, start_ts: u64, end_ts: u64, format: ExportFormat) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::ExportMessages { channel_id, start_ts, end_ts, format })?;
+        
+        Ok(())
+    }
+    
+    // An empty `channel_ids` searches every channel
+    pub fn search_messages(&mut self, query: String, channel_ids: Vec<Uuid>, limit: u32, offset: u32) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::SearchMessages { query, channel_ids, limit, offset })?;
+        
+        Ok(())
+    }
+    
+    // Extend the session using the token sent with the expiry warning
+    pub fn renew_session(&mut self, token: String) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        self.send_message(&Message::RenewSession { token })?;
         
         Ok(())
     }
     
-    pub fn send_screen_share_data(&mut self, user_id: Uuid, channel_id: Uuid, data: Vec<u8>) -> Result<()> {
+    pub fn search_users(&mut self, query: String, include_offline: bool) -> Result<()> {
         if !self.connected || self.user_id.is_none() {
-            return Err(anyhow::anyhow!("Not connected to server or not logged in"));
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
         }
         
-        let screen_data = Message::ScreenShareData {
+        self.send_message(&Message::SearchUsers { query, include_offline })?;
+        
+        Ok(())
+    }
+    
+    pub fn send_voice_data(&mut self, user_id: Uuid, channel_id: Uuid, seq: u32, data: Vec<u8>) -> Result<()> {
+        if !self.connected || self.user_id.is_none() {
+            return Err(OpenReverbError::network("Not connected to server or not logged in"));
+        }
+        
+        let voice_data = Message::VoiceData {
             user_id,
             channel_id,
+            seq,
+            captured_at_ms: sync::now_millis(),
+            epoch: 0,
             data,
         };
         
-        self.send_message(&screen_data)?;
+        self.send_message(&voice_data)?;
         
         Ok(())
     }
     
+    // Where our voice and video are, so receivers can keep them in sync
+    pub fn send_sync_hint(&mut self, audio_pts_ms: u64, video_pts_ms: u64) -> Result<()> {
+        let user_id = match self.user_id {
+            Some(user_id) if self.connected => user_id,
+            _ => return Err(OpenReverbError::network("Not connected to server or not logged in")),
+        };
+        
+        self.send_message(&Message::SyncHint { user_id, audio_pts_ms, video_pts_ms })?;
+        
+        Ok(())
+    }
+    
+    // Sequence numbers start at a random value so frames from a previous session aren't mistaken for new ones
+    pub fn initial_seq() -> u32 {
+        Uuid::new_v4().as_u128() as u32
+    }
+    
     pub fn get_sender(&self) -> Sender<Message> {
         self.message_sender.clone()
     }
     
+    // Messages queued through get_sender() and not yet sent, for tests that don't connect. The
+    // tests that use it are left out when built for real audio devices.
+    #[cfg(all(test, not(all(feature = "audio", not(feature = "test-audio")))))]
+    pub(crate) fn take_queued_messages(&self) -> Vec<Message> {
+        self.message_receiver.try_iter().collect()
+    }
+    
     pub fn get_current_channel_id(&self) -> Option<Uuid> {
         self.current_channel_id
     }
@@ -238,4 +1134,131 @@ impl Connection {
     pub fn get_user_id(&self) -> Option<Uuid> {
         self.user_id
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReconnectConfig;
+    use std::net::TcpListener;
+    use std::thread;
+    
+    fn read_message(stream: &mut TcpStream) -> Message {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut frame).unwrap();
+        serde_json::from_slice(&frame).unwrap()
+    }
+    
+    fn write_message(stream: &mut TcpStream, message: &Message) {
+        let frame = serde_json::to_vec(message).unwrap();
+        stream.write_all(&(frame.len() as u32).to_be_bytes()).unwrap();
+        stream.write_all(&frame).unwrap();
+    }
+    
+    // Process messages until `done` holds, or fail after a few seconds
+    fn process_until(connection: &mut Connection, done: impl Fn(&Connection) -> bool) {
+        for _ in 0..500 {
+            connection.process_messages();
+            if done(connection) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("gave up waiting; reconnect state is {:?}", connection.reconnect_state());
+    }
+    
+    #[test]
+    fn dropped_connections_log_back_in_and_rejoin() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_url = listener.local_addr().unwrap().to_string();
+        let config = ClientConfig {
+            use_reconnect_config: true,
+            reconnect: ReconnectConfig { initial_delay_ms: 10, jitter: false, ..ReconnectConfig::default() },
+            ..ClientConfig::default()
+        };
+        let user_id = Uuid::new_v4();
+        let channel_id = Uuid::new_v4();
+        
+        let mut connection = Connection::new();
+        connection.connect_with_config(&server_url, &config).unwrap();
+        connection.login("alice", "secret").unwrap();
+        connection.set_current_channel_id(Some(channel_id));
+        
+        let (mut server, _) = listener.accept().unwrap();
+        assert!(matches!(read_message(&mut server), Message::Hello { .. }));
+        assert!(matches!(read_message(&mut server), Message::LoginRequest { .. }));
+        let login = Message::LoginResponse { success: true, user_id: Some(user_id), token: Some("token".to_string()), error: None };
+        write_message(&mut server, &login);
+        process_until(&mut connection, |c| c.get_user_id().is_some());
+        
+        // The server goes away; the connection comes back with the session token, not the password
+        drop(server);
+        process_until(&mut connection, |c| matches!(c.reconnect_state(), ReconnectState::Reconnecting { .. }));
+        assert!(connection.is_connected());
+        
+        let (mut server, _) = listener.accept().unwrap();
+        assert!(matches!(read_message(&mut server), Message::Hello { .. }));
+        assert!(matches!(read_message(&mut server), Message::ResumeSession { token } if token == "token"));
+        write_message(&mut server, &login);
+        process_until(&mut connection, |c| c.reconnect_state() == ReconnectState::Idle);
+        
+        // Back in the channel it was in
+        assert!(matches!(read_message(&mut server), Message::JoinChannel { channel_id: id, ghost: false } if id == channel_id));
+        assert_eq!(connection.get_user_id(), Some(user_id));
+    }
+    
+    #[test]
+    fn server_pings_are_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut connection = Connection::new();
+        connection.connect(&listener.local_addr().unwrap().to_string()).unwrap();
+        
+        let (mut server, _) = listener.accept().unwrap();
+        assert!(matches!(read_message(&mut server), Message::Hello { .. }));
+        write_message(&mut server, &Message::Ping { nonce: 3 });
+        
+        // Until the answer is waiting for the server
+        server.set_nonblocking(true).unwrap();
+        for _ in 0..500 {
+            connection.process_messages();
+            if server.peek(&mut [0]).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        server.set_nonblocking(false).unwrap();
+        assert!(matches!(read_message(&mut server), Message::Pong { nonce: 3 }));
+    }
+    
+    #[test]
+    fn pongs_are_matched_to_their_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut connection = Connection::new();
+        connection.connect(&listener.local_addr().unwrap().to_string()).unwrap();
+        
+        let (mut server, _) = listener.accept().unwrap();
+        assert!(matches!(read_message(&mut server), Message::Hello { .. }));
+        
+        connection.send_ping().unwrap();
+        thread::sleep(Duration::from_millis(300));
+        connection.send_ping().unwrap();
+        assert!(matches!(read_message(&mut server), Message::Ping { nonce: 0 }));
+        assert!(matches!(read_message(&mut server), Message::Ping { nonce: 1 }));
+        assert_eq!(connection.get_ping_latency(), None);
+        
+        // Answered out of order, each is timed from its own ping
+        write_message(&mut server, &Message::Pong { nonce: 1 });
+        process_until(&mut connection, |c| c.get_ping_latency().is_some());
+        let latest = connection.get_ping_latency().unwrap();
+        assert!(latest < Duration::from_millis(300), "{:?}", latest);
+        
+        // Pongs for pings never sent, or already answered, are ignored
+        write_message(&mut server, &Message::Pong { nonce: 7 });
+        write_message(&mut server, &Message::Pong { nonce: 1 });
+        write_message(&mut server, &Message::Pong { nonce: 0 });
+        process_until(&mut connection, |c| c.get_ping_latency() != Some(latest));
+        assert!(connection.get_ping_latency().unwrap() >= Duration::from_millis(300));
+        assert!(connection.ping_sent_at.is_empty());
+    }
+}
@@ -0,0 +1,552 @@
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::Result;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use open_reverb_common::protocol::Message;
+
+const NONCE_LEN: usize = 12;
+
+// Frames sent with one voice key before it's replaced
+pub const DEFAULT_KEY_ROTATION_INTERVAL_FRAMES: u32 = 10_000;
+
+// Frames handled under a new key before the keys it replaced are forgotten
+const KEY_GRACE_FRAMES: u32 = 100;
+
+// The voice keys of one channel. Each rotation starts a new epoch with a new key; keys of
+// earlier epochs are kept for a grace period, so frames still in flight can be decrypted.
+pub struct CryptoState {
+    current_epoch: u32,
+    keys: HashMap<u32, [u8; 32]>,
+    // Frames encrypted or decrypted since the current epoch began, towards the grace period
+    // and the next rotation
+    frames_in_epoch: u32,
+}
+
+impl CryptoState {
+    pub fn new(epoch: u32, key: [u8; 32]) -> Self {
+        Self {
+            current_epoch: epoch,
+            keys: HashMap::from([(epoch, key)]),
+            frames_in_epoch: 0,
+        }
+    }
+    
+    pub fn current_epoch(&self) -> u32 {
+        self.current_epoch
+    }
+    
+    pub fn key(&self, epoch: u32) -> Option<&[u8; 32]> {
+        self.keys.get(&epoch)
+    }
+    
+    // A key newer than the current one becomes current. Returns false for an epoch we've
+    // already had a key for.
+    pub fn add_key(&mut self, epoch: u32, key: [u8; 32]) -> bool {
+        if epoch <= self.current_epoch {
+            return false;
+        }
+        
+        self.keys.insert(epoch, key);
+        self.current_epoch = epoch;
+        self.frames_in_epoch = 0;
+        true
+    }
+    
+    // Count a frame under the current key, dropping older keys once the grace period is over
+    pub fn frame_handled(&mut self) {
+        self.frames_in_epoch = self.frames_in_epoch.saturating_add(1);
+        if self.frames_in_epoch >= KEY_GRACE_FRAMES {
+            let current_epoch = self.current_epoch;
+            self.keys.retain(|epoch, _| *epoch >= current_epoch);
+        }
+    }
+    
+    pub fn rotation_due(&self, interval_frames: u32) -> bool {
+        self.frames_in_epoch >= interval_frames
+    }
+}
+
+// End-to-end encryption of voice in channels marked as encrypted.
+//
+// Each encrypted channel has a random AES-256 voice key, created by the first member to join.
+// Members who hold the key wrap it for newcomers with a key agreed over X25519, so the server
+// only ever relays ciphertext. For forward secrecy, once enough frames have been sent with a
+// key, the member with the lowest user ID replaces it with a new one and wraps that for
+// everyone. Only that one member rotates, so two members can't start the same epoch with
+// different keys.
+pub struct E2EVoice {
+    secret: StaticSecret,
+    public_key: PublicKey,
+    // Public keys of other users, as announced through the server
+    peer_keys: HashMap<Uuid, PublicKey>,
+    encrypted_channels: HashSet<Uuid>,
+    channel_keys: HashMap<Uuid, CryptoState>,
+    rotation_interval_frames: u32,
+    // Last known members of each channel we're in
+    members: HashMap<Uuid, Vec<Uuid>>,
+    // Members each channel key has already been sent to
+    shared_with: HashMap<Uuid, HashSet<Uuid>>,
+}
+
+impl E2EVoice {
+    pub fn new() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        
+        Self {
+            secret,
+            public_key,
+            peer_keys: HashMap::new(),
+            encrypted_channels: HashSet::new(),
+            channel_keys: HashMap::new(),
+            rotation_interval_frames: DEFAULT_KEY_ROTATION_INTERVAL_FRAMES,
+            members: HashMap::new(),
+            shared_with: HashMap::new(),
+        }
+    }
+    
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.as_bytes().to_vec()
+    }
+    
+    pub fn set_rotation_interval(&mut self, frames: u32) {
+        self.rotation_interval_frames = frames.max(KEY_GRACE_FRAMES);
+    }
+    
+    pub fn is_encrypted(&self, channel_id: Uuid) -> bool {
+        self.encrypted_channels.contains(&channel_id)
+    }
+    
+    pub fn set_channel_encrypted(&mut self, channel_id: Uuid) {
+        self.encrypted_channels.insert(channel_id);
+    }
+    
+    // Create a voice key for a channel we are turning encryption on for
+    pub fn create_channel_key(&mut self, channel_id: Uuid) {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        
+        self.channel_keys.insert(channel_id, CryptoState::new(0, key));
+        self.shared_with.remove(&channel_id);
+    }
+    
+    // Replace a channel's key once it has been used for long enough, if it's ours to replace.
+    // Returns the messages announcing the rotation and carrying the new key to each member.
+    pub fn rotate_if_due(&mut self, me: Uuid, channel_id: Uuid) -> Vec<Message> {
+        if self.members.get(&channel_id).and_then(|members| members.iter().min()) != Some(&me) {
+            return Vec::new();
+        }
+        
+        let new_epoch = match self.channel_keys.get_mut(&channel_id) {
+            Some(state) if state.rotation_due(self.rotation_interval_frames) => {
+                let mut key = [0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                
+                let new_epoch = state.current_epoch() + 1;
+                state.add_key(new_epoch, key);
+                new_epoch
+            }
+            _ => return Vec::new(),
+        };
+        
+        // Everyone needs the new key
+        self.shared_with.remove(&channel_id);
+        
+        let mut messages = vec![Message::KeyRotationInit { channel_id, initiator_id: me, new_epoch }];
+        messages.extend(self.distribute(me, channel_id));
+        messages
+    }
+    
+    // Forget a channel's key once we've left it; whoever joins next may start a new one
+    pub fn leave_channel(&mut self, channel_id: Uuid) {
+        self.channel_keys.remove(&channel_id);
+        self.members.remove(&channel_id);
+        self.shared_with.remove(&channel_id);
+    }
+    
+    // Record a peer's public key, returning any key messages that were waiting on it
+    pub fn add_peer_key(&mut self, me: Uuid, user_id: Uuid, public_key: &[u8]) -> Vec<Message> {
+        let bytes: [u8; 32] = match public_key.try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => return Vec::new(),
+        };
+        self.peer_keys.insert(user_id, PublicKey::from(bytes));
+        
+        let channels: Vec<Uuid> = self.members.keys().copied().collect();
+        channels.into_iter().flat_map(|channel_id| self.distribute(me, channel_id)).collect()
+    }
+    
+    // Called with the current members of an encrypted channel we're in. Returns the key
+    // messages to send to members who don't have the channel's voice key yet.
+    pub fn update_members(&mut self, me: Uuid, channel_id: Uuid, members: Vec<Uuid>) -> Vec<Message> {
+        if !self.is_encrypted(channel_id) {
+            return Vec::new();
+        }
+        
+        // The first one in creates the key
+        if !self.channel_keys.contains_key(&channel_id) && members.iter().all(|m| *m == me) {
+            self.create_channel_key(channel_id);
+        }
+        
+        // Anyone who left and comes back needs the key again
+        if let Some(shared_with) = self.shared_with.get_mut(&channel_id) {
+            shared_with.retain(|member| members.contains(member));
+        }
+        
+        self.members.insert(channel_id, members);
+        self.distribute(me, channel_id)
+    }
+    
+    fn distribute(&mut self, me: Uuid, channel_id: Uuid) -> Vec<Message> {
+        let (epoch, key) = match self.channel_keys.get(&channel_id) {
+            Some(state) => (state.current_epoch(), state.key(state.current_epoch()).copied().unwrap_or_default()),
+            None => return Vec::new(),
+        };
+        let members = self.members.get(&channel_id).cloned().unwrap_or_default();
+        
+        let mut messages = Vec::new();
+        for recipient_id in members {
+            if recipient_id == me || self.shared_with.get(&channel_id).is_some_and(|s| s.contains(&recipient_id)) {
+                continue;
+            }
+            
+            if let Some(encrypted_key) = self.wrap_key(channel_id, recipient_id, epoch, &key) {
+                self.shared_with.entry(channel_id).or_default().insert(recipient_id);
+                messages.push(Message::E2EVoiceKey {
+                    channel_id,
+                    sender_id: me,
+                    recipient_id,
+                    epoch,
+                    encrypted_key,
+                });
+            }
+        }
+        
+        messages
+    }
+    
+    // Accept a channel's voice key sent to us by another member. The first key received for an
+    // epoch is the one kept.
+    pub fn receive_key(&mut self, channel_id: Uuid, sender_id: Uuid, epoch: u32, encrypted_key: &[u8]) -> Result<()> {
+        if self.channel_keys.get(&channel_id).is_some_and(|state| epoch <= state.current_epoch()) {
+            return Ok(());
+        }
+        
+        if encrypted_key.len() < NONCE_LEN {
+            return Err(anyhow::anyhow!("Voice key is too short"));
+        }
+        
+        let cipher = self.wrapping_cipher(channel_id, sender_id, epoch)?;
+        let (nonce, ciphertext) = encrypted_key.split_at(NONCE_LEN);
+        let key = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Voice key from {} could not be decrypted", sender_id))?;
+        
+        let key: [u8; 32] = key.try_into().map_err(|_| anyhow::anyhow!("Voice key has the wrong length"))?;
+        match self.channel_keys.get_mut(&channel_id) {
+            Some(state) => {
+                state.add_key(epoch, key);
+            }
+            None => {
+                self.channel_keys.insert(channel_id, CryptoState::new(epoch, key));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    // Encrypt with the channel's current key, returning its epoch along with the frame
+    pub fn encrypt_frame(&mut self, channel_id: Uuid, user_id: Uuid, seq: u32, data: &[u8]) -> Result<(u32, Vec<u8>)> {
+        let state = self.channel_keys
+            .get_mut(&channel_id)
+            .ok_or_else(|| anyhow::anyhow!("No voice key for channel {}", channel_id))?;
+        let epoch = state.current_epoch();
+        let cipher = voice_cipher(state, epoch)?;
+        
+        let data = cipher
+            .encrypt(&frame_nonce(user_id, seq), data)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt voice frame"))?;
+        state.frame_handled();
+        
+        Ok((epoch, data))
+    }
+    
+    pub fn decrypt_frame(&mut self, channel_id: Uuid, user_id: Uuid, seq: u32, epoch: u32, data: &[u8]) -> Result<Vec<u8>> {
+        let state = self.channel_keys
+            .get_mut(&channel_id)
+            .ok_or_else(|| anyhow::anyhow!("No voice key for channel {}", channel_id))?;
+        let cipher = voice_cipher(state, epoch)?;
+        
+        let data = cipher
+            .decrypt(&frame_nonce(user_id, seq), data)
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt voice frame from {}", user_id))?;
+        if epoch == state.current_epoch() {
+            state.frame_handled();
+        }
+        
+        Ok(data)
+    }
+    
+    // Encrypt a channel key so only `recipient_id` can read it
+    fn wrap_key(&self, channel_id: Uuid, recipient_id: Uuid, epoch: u32, key: &[u8; 32]) -> Option<Vec<u8>> {
+        let cipher = self.wrapping_cipher(channel_id, recipient_id, epoch).ok()?;
+        
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), key.as_slice()).ok()?;
+        Some([nonce.as_slice(), &ciphertext].concat())
+    }
+    
+    // Cipher keyed by the X25519 shared secret with a peer, bound to the channel and key epoch
+    fn wrapping_cipher(&self, channel_id: Uuid, peer_id: Uuid, epoch: u32) -> Result<Aes256Gcm> {
+        let peer_key = self.peer_keys
+            .get(&peer_id)
+            .ok_or_else(|| anyhow::anyhow!("No public key for {}", peer_id))?;
+        
+        let shared_secret = self.secret.diffie_hellman(peer_key);
+        let key = Sha256::new()
+            .chain_update(shared_secret.as_bytes())
+            .chain_update(channel_id.as_bytes())
+            .chain_update(epoch.to_be_bytes())
+            .finalize();
+        
+        Ok(Aes256Gcm::new(&key))
+    }
+}
+
+fn voice_cipher(state: &CryptoState, epoch: u32) -> Result<Aes256Gcm> {
+    let key = state.key(epoch).ok_or_else(|| anyhow::anyhow!("No voice key for epoch {}", epoch))?;
+    
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+}
+
+// Each frame's nonce is derived from its sender and sequence number, which are unique within a session
+fn frame_nonce(user_id: Uuid, seq: u32) -> Nonce<<Aes256Gcm as aes_gcm::AeadCore>::NonceSize> {
+    let digest = Sha256::new()
+        .chain_update(user_id.as_bytes())
+        .chain_update(seq.to_be_bytes())
+        .finalize();
+    
+    *Nonce::from_slice(&digest[..NONCE_LEN])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const ALICE: Uuid = Uuid::from_u128(1);
+    const BOB: Uuid = Uuid::from_u128(2);
+    const CHANNEL: Uuid = Uuid::from_u128(3);
+    
+    // Alice and Bob in an encrypted channel, both holding Alice's first key
+    fn channel_pair() -> (E2EVoice, E2EVoice) {
+        let (mut alice, mut bob) = (E2EVoice::new(), E2EVoice::new());
+        alice.add_peer_key(ALICE, BOB, &bob.public_key());
+        bob.add_peer_key(BOB, ALICE, &alice.public_key());
+        alice.set_channel_encrypted(CHANNEL);
+        bob.set_channel_encrypted(CHANNEL);
+        
+        alice.update_members(ALICE, CHANNEL, vec![ALICE]);
+        let keys = alice.update_members(ALICE, CHANNEL, vec![ALICE, BOB]);
+        deliver(&mut bob, &keys);
+        
+        (alice, bob)
+    }
+    
+    fn deliver(to: &mut E2EVoice, messages: &[Message]) {
+        for message in messages {
+            if let Message::E2EVoiceKey { channel_id, sender_id, epoch, encrypted_key, .. } = message {
+                to.receive_key(*channel_id, *sender_id, *epoch, encrypted_key).unwrap();
+            }
+        }
+    }
+    
+    #[test]
+    fn old_key_is_kept_for_the_grace_period() {
+        let mut state = CryptoState::new(0, [1; 32]);
+        assert!(state.add_key(1, [2; 32]));
+        assert!(!state.add_key(1, [3; 32]));
+        
+        for _ in 0..KEY_GRACE_FRAMES - 1 {
+            state.frame_handled();
+        }
+        assert!(state.key(0).is_some());
+        
+        state.frame_handled();
+        assert!(state.key(0).is_none());
+        assert_eq!(state.key(1), Some(&[2; 32]));
+    }
+    
+    #[test]
+    fn rotation_is_due_after_the_interval() {
+        let mut state = CryptoState::new(0, [1; 32]);
+        for _ in 0..500 {
+            state.frame_handled();
+        }
+        assert!(state.rotation_due(500));
+        
+        state.add_key(1, [2; 32]);
+        assert!(!state.rotation_due(500));
+    }
+    
+    #[test]
+    fn frames_decrypt_across_a_rotation() {
+        let (mut alice, mut bob) = channel_pair();
+        alice.set_rotation_interval(KEY_GRACE_FRAMES);
+        
+        let mut last_old_frame = None;
+        for seq in 0..KEY_GRACE_FRAMES {
+            let (epoch, frame) = alice.encrypt_frame(CHANNEL, ALICE, seq, b"voice").unwrap();
+            assert_eq!(epoch, 0);
+            assert_eq!(bob.decrypt_frame(CHANNEL, ALICE, seq, epoch, &frame).unwrap(), b"voice");
+            last_old_frame = Some((seq, frame));
+        }
+        
+        let rotation = alice.rotate_if_due(ALICE, CHANNEL);
+        assert!(matches!(rotation[0], Message::KeyRotationInit { new_epoch: 1, .. }));
+        deliver(&mut bob, &rotation);
+        
+        // A frame from before the rotation still arrives within the grace period
+        let (old_seq, old_frame) = last_old_frame.unwrap();
+        assert!(bob.decrypt_frame(CHANNEL, ALICE, old_seq, 0, &old_frame).is_ok());
+        
+        for seq in KEY_GRACE_FRAMES..KEY_GRACE_FRAMES * 2 {
+            let (epoch, frame) = alice.encrypt_frame(CHANNEL, ALICE, seq, b"voice").unwrap();
+            assert_eq!(epoch, 1);
+            assert_eq!(bob.decrypt_frame(CHANNEL, ALICE, seq, epoch, &frame).unwrap(), b"voice");
+        }
+        
+        // After it, the old key is gone
+        assert!(bob.decrypt_frame(CHANNEL, ALICE, old_seq, 0, &old_frame).is_err());
+    }
+    
+    #[test]
+    fn only_the_lowest_member_rotates() {
+        let (mut alice, mut bob) = channel_pair();
+        bob.update_members(BOB, CHANNEL, vec![ALICE, BOB]);
+        alice.set_rotation_interval(KEY_GRACE_FRAMES);
+        bob.set_rotation_interval(KEY_GRACE_FRAMES);
+        
+        // Both talking at once, so both see the key fall due
+        for seq in 0..KEY_GRACE_FRAMES / 2 {
+            let (epoch, frame) = alice.encrypt_frame(CHANNEL, ALICE, seq, b"voice").unwrap();
+            bob.decrypt_frame(CHANNEL, ALICE, seq, epoch, &frame).unwrap();
+            let (epoch, frame) = bob.encrypt_frame(CHANNEL, BOB, seq, b"voice").unwrap();
+            alice.decrypt_frame(CHANNEL, BOB, seq, epoch, &frame).unwrap();
+        }
+        
+        assert!(bob.rotate_if_due(BOB, CHANNEL).is_empty());
+        let rotation = alice.rotate_if_due(ALICE, CHANNEL);
+        assert!(matches!(rotation[0], Message::KeyRotationInit { initiator_id: ALICE, new_epoch: 1, .. }));
+        deliver(&mut bob, &rotation);
+        
+        // One key for the new epoch, in both directions
+        let (epoch, frame) = bob.encrypt_frame(CHANNEL, BOB, 1_000, b"voice").unwrap();
+        assert_eq!(epoch, 1);
+        assert_eq!(alice.decrypt_frame(CHANNEL, BOB, 1_000, epoch, &frame).unwrap(), b"voice");
+        let (epoch, frame) = alice.encrypt_frame(CHANNEL, ALICE, 1_000, b"voice").unwrap();
+        assert_eq!(bob.decrypt_frame(CHANNEL, ALICE, 1_000, epoch, &frame).unwrap(), b"voice");
+    }
+    
+    #[test]
+    fn a_silent_lowest_member_still_rotates() {
+        let (mut alice, mut bob) = channel_pair();
+        alice.set_rotation_interval(KEY_GRACE_FRAMES);
+        
+        for seq in 0..KEY_GRACE_FRAMES {
+            let (epoch, frame) = bob.encrypt_frame(CHANNEL, BOB, seq, b"voice").unwrap();
+            alice.decrypt_frame(CHANNEL, BOB, seq, epoch, &frame).unwrap();
+        }
+        
+        assert!(matches!(alice.rotate_if_due(ALICE, CHANNEL)[0], Message::KeyRotationInit { new_epoch: 1, .. }));
+    }
+    
+    #[test]
+    fn members_who_rejoin_get_the_key_again() {
+        let (mut alice, _) = channel_pair();
+        
+        assert!(alice.update_members(ALICE, CHANNEL, vec![ALICE]).is_empty());
+        
+        // Bob comes back with a fresh client, and no key
+        let mut bob = E2EVoice::new();
+        bob.set_channel_encrypted(CHANNEL);
+        bob.add_peer_key(BOB, ALICE, &alice.public_key());
+        alice.add_peer_key(ALICE, BOB, &bob.public_key());
+        let keys = alice.update_members(ALICE, CHANNEL, vec![ALICE, BOB]);
+        assert!(matches!(keys[..], [Message::E2EVoiceKey { recipient_id: BOB, .. }]));
+        deliver(&mut bob, &keys);
+        
+        let (epoch, frame) = alice.encrypt_frame(CHANNEL, ALICE, 0, b"voice").unwrap();
+        assert_eq!(bob.decrypt_frame(CHANNEL, ALICE, 0, epoch, &frame).unwrap(), b"voice");
+    }
+    
+    #[test]
+    fn frame_nonces_are_unique_to_sender_and_sequence_number() {
+        assert_eq!(frame_nonce(ALICE, 7), frame_nonce(ALICE, 7));
+        
+        let nonces: HashSet<_> = [ALICE, BOB]
+            .into_iter()
+            .flat_map(|user_id| (0..1_000).map(move |seq| frame_nonce(user_id, seq)))
+            .collect();
+        assert_eq!(nonces.len(), 2_000);
+    }
+    
+    #[test]
+    fn wrapped_keys_only_open_for_their_recipient_channel_and_epoch() {
+        let (mut alice, mut bob) = (E2EVoice::new(), E2EVoice::new());
+        alice.add_peer_key(ALICE, BOB, &bob.public_key());
+        bob.add_peer_key(BOB, ALICE, &alice.public_key());
+        let wrapped = alice.wrap_key(CHANNEL, BOB, 1, &[7; 32]).unwrap();
+        
+        // The nonce is random, so the same key wraps differently each time
+        assert_ne!(alice.wrap_key(CHANNEL, BOB, 1, &[7; 32]).unwrap(), wrapped);
+        
+        assert!(bob.receive_key(Uuid::from_u128(4), ALICE, 1, &wrapped).is_err());
+        assert!(bob.receive_key(CHANNEL, ALICE, 2, &wrapped).is_err());
+        
+        let mut tampered = wrapped.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(bob.receive_key(CHANNEL, ALICE, 1, &tampered).is_err());
+        assert!(bob.receive_key(CHANNEL, ALICE, 1, &wrapped[..NONCE_LEN - 1]).is_err());
+        
+        bob.receive_key(CHANNEL, ALICE, 1, &wrapped).unwrap();
+        assert_eq!(bob.channel_keys[&CHANNEL].key(1), Some(&[7; 32]));
+    }
+    
+    #[test]
+    fn server_cannot_decrypt_frames() {
+        let (mut alice, mut bob) = (E2EVoice::new(), E2EVoice::new());
+        alice.add_peer_key(ALICE, BOB, &bob.public_key());
+        bob.add_peer_key(BOB, ALICE, &alice.public_key());
+        alice.set_channel_encrypted(CHANNEL);
+        bob.set_channel_encrypted(CHANNEL);
+        
+        // The server relays every key message and frame, and knows everyone's public key
+        const SERVER: Uuid = Uuid::from_u128(9);
+        let mut server = E2EVoice::new();
+        server.set_channel_encrypted(CHANNEL);
+        server.add_peer_key(SERVER, ALICE, &alice.public_key());
+        server.add_peer_key(SERVER, BOB, &bob.public_key());
+        
+        alice.update_members(ALICE, CHANNEL, vec![ALICE]);
+        let keys = alice.update_members(ALICE, CHANNEL, vec![ALICE, BOB]);
+        deliver(&mut bob, &keys);
+        for key in &keys {
+            let Message::E2EVoiceKey { sender_id, epoch, encrypted_key, .. } = key else {
+                panic!("expected a voice key, got {:?}", key);
+            };
+            assert!(server.receive_key(CHANNEL, *sender_id, *epoch, encrypted_key).is_err());
+        }
+        
+        let (epoch, frame) = alice.encrypt_frame(CHANNEL, ALICE, 0, b"voice").unwrap();
+        assert!(!frame.windows(5).any(|w| w == b"voice"));
+        assert!(server.decrypt_frame(CHANNEL, ALICE, 0, epoch, &frame).is_err());
+        assert_eq!(bob.decrypt_frame(CHANNEL, ALICE, 0, epoch, &frame).unwrap(), b"voice");
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use open_reverb_common::protocol::Message;
+
+// Files go out in chunks of this many bytes
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// Chunks sent per update, so sending a big file doesn't hold up the UI
+const CHUNKS_PER_UPDATE: usize = 16;
+
+// A file we offered. Nothing is sent until the recipient accepts.
+struct Outgoing {
+    file: File,
+    size_bytes: u64,
+    // How much has been sent, once accepted
+    sent: Option<u64>,
+}
+
+// A file offered to us. Once accepted it's written to a partial file next to the others in the
+// temp directory, and moved to the download directory when it's complete.
+struct Incoming {
+    filename: String,
+    size_bytes: u64,
+    part: Option<(PathBuf, File)>,
+    received: u64,
+}
+
+pub struct FileTransfers {
+    outgoing: HashMap<Uuid, Outgoing>,
+    incoming: HashMap<Uuid, Incoming>,
+    download_dir: PathBuf,
+    // Where finished downloads were saved, until the app asks
+    saved: HashMap<Uuid, PathBuf>,
+}
+
+impl FileTransfers {
+    pub fn new(download_dir: PathBuf) -> Self {
+        Self {
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            download_dir,
+            saved: HashMap::new(),
+        }
+    }
+    
+    // Open a file to send, returning the offer for it
+    pub fn offer(&mut self, from: Uuid, to: Uuid, path: &Path) -> io::Result<Message> {
+        let file = File::open(path)?;
+        let size_bytes = file.metadata()?.len();
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a file"))?;
+        
+        let id = Uuid::new_v4();
+        self.outgoing.insert(id, Outgoing { file, size_bytes, sent: None });
+        Ok(Message::FileTransferOffer { id, from, to, mime_type: mime_type(&filename).to_string(), filename, size_bytes })
+    }
+    
+    // Returns false for a transfer that isn't ours
+    pub fn accepted(&mut self, id: Uuid) -> bool {
+        match self.outgoing.get_mut(&id) {
+            Some(outgoing) => {
+                outgoing.sent.get_or_insert(0);
+                true
+            }
+            None => false,
+        }
+    }
+    
+    // The next chunks of accepted transfers, oldest first, each followed by a
+    // FileTransferComplete once it's all sent. A file that can't be read is given up on with a
+    // FileTransferReject.
+    pub fn next_chunks(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut finished = Vec::new();
+        
+        for (id, outgoing) in &mut self.outgoing {
+            let Some(sent) = outgoing.sent.as_mut() else {
+                continue;
+            };
+            
+            while messages.len() < CHUNKS_PER_UPDATE && *sent < outgoing.size_bytes {
+                let len = (outgoing.size_bytes - *sent).min(CHUNK_SIZE as u64) as usize;
+                let mut data = vec![0; len];
+                if let Err(e) = outgoing.file.read_exact(&mut data) {
+                    messages.push(Message::FileTransferReject { id: *id, reason: format!("Couldn't read the file: {}", e) });
+                    finished.push(*id);
+                    break;
+                }
+                
+                messages.push(Message::FileTransferChunk { id: *id, offset: *sent, data });
+                *sent += len as u64;
+            }
+            
+            if *sent == outgoing.size_bytes {
+                messages.push(Message::FileTransferComplete { id: *id });
+                finished.push(*id);
+            }
+        }
+        
+        for id in finished {
+            self.outgoing.remove(&id);
+        }
+        messages
+    }
+    
+    pub fn offered(&mut self, id: Uuid, filename: String, size_bytes: u64) {
+        self.incoming.insert(id, Incoming { filename, size_bytes, part: None, received: 0 });
+    }
+    
+    pub fn accept(&mut self, id: Uuid) -> io::Result<()> {
+        let incoming = self.incoming
+            .get_mut(&id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such file transfer"))?;
+        
+        let path = std::env::temp_dir().join(format!("open-reverb-{}.part", id));
+        let file = File::create(&path)?;
+        incoming.part = Some((path, file));
+        Ok(())
+    }
+    
+    // The sender sends chunks in order, so each has to start where the last one ended; anything
+    // else would leave a gap or overwrite what's already there
+    pub fn receive_chunk(&mut self, id: Uuid, offset: u64, data: &[u8]) -> io::Result<()> {
+        let Some(Incoming { size_bytes, part: Some((_, file)), received, .. }) = self.incoming.get_mut(&id) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Not receiving that file"));
+        };
+        if offset != *received {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "A chunk arrived out of order"));
+        }
+        if offset.checked_add(data.len() as u64).is_none_or(|end| end > *size_bytes) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The file is bigger than offered"));
+        }
+        
+        file.write_all(data)?;
+        *received += data.len() as u64;
+        Ok(())
+    }
+    
+    // Move a finished download into the download directory, under a name not already taken
+    pub fn complete(&mut self, id: Uuid) -> io::Result<PathBuf> {
+        let Some(Incoming { filename, size_bytes, part: Some((part_path, mut file)), received }) = self.incoming.remove(&id) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Not receiving that file"));
+        };
+        file.flush()?;
+        drop(file);
+        
+        if received != size_bytes {
+            let _ = fs::remove_file(&part_path);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The file didn't all arrive"));
+        }
+        
+        fs::create_dir_all(&self.download_dir)?;
+        let path = unused_path(&self.download_dir, &filename);
+        // Renaming fails when the temp directory is on another filesystem
+        if fs::rename(&part_path, &path).is_err() {
+            fs::copy(&part_path, &path)?;
+            let _ = fs::remove_file(&part_path);
+        }
+        
+        self.saved.insert(id, path.clone());
+        Ok(path)
+    }
+    
+    pub fn take_saved(&mut self, id: Uuid) -> Option<PathBuf> {
+        self.saved.remove(&id)
+    }
+    
+    // Forget a transfer either way, deleting anything received so far
+    pub fn cancel(&mut self, id: Uuid) {
+        self.outgoing.remove(&id);
+        if let Some(Incoming { part: Some((path, file)), .. }) = self.incoming.remove(&id) {
+            drop(file);
+            let _ = fs::remove_file(path);
+        }
+    }
+    
+    pub fn clear(&mut self) {
+        let ids: Vec<Uuid> = self.outgoing.keys().chain(self.incoming.keys()).copied().collect();
+        for id in ids {
+            self.cancel(id);
+        }
+    }
+}
+
+// The user's downloads folder, or their home directory on systems without one
+pub fn default_download_dir() -> PathBuf {
+    directories::UserDirs::new()
+        .map(|dirs| dirs.download_dir().unwrap_or(dirs.home_dir()).to_path_buf())
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+// Only the last part of the name the sender gave is used, so a file can't be saved outside
+// `dir`. "notes.txt" becomes "notes (1).txt" and so on if it's taken.
+fn unused_path(dir: &Path, filename: &str) -> PathBuf {
+    let name = Path::new(filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "download".to_string());
+    
+    let path = dir.join(&name);
+    if !path.exists() {
+        return path;
+    }
+    
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name.as_str(), String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// Going by the extension; the recipient only uses it to describe the file
+fn mime_type(filename: &str) -> &'static str {
+    let extension = filename.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("txt" | "log" | "md") => "text/plain",
+        Some("csv") => "text/csv",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // A directory of its own under the temp directory, removed when dropped
+    struct TempDir(PathBuf);
+    
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("open-reverb-test-{}", Uuid::new_v4()));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+    
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+    
+    #[test]
+    fn files_arrive_in_the_download_directory() {
+        let dir = TempDir::new();
+        let source = dir.0.join("notes.txt");
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        fs::write(&source, &contents).unwrap();
+        
+        let mut sender = FileTransfers::new(dir.0.join("sent"));
+        let mut receiver = FileTransfers::new(dir.0.join("downloads"));
+        let Message::FileTransferOffer { id, filename, size_bytes, mime_type, .. } = sender.offer(Uuid::nil(), Uuid::nil(), &source).unwrap() else {
+            panic!("not an offer");
+        };
+        assert_eq!((filename.as_str(), size_bytes, mime_type.as_str()), ("notes.txt", contents.len() as u64, "text/plain"));
+        
+        // Nothing goes until it's accepted
+        assert!(sender.next_chunks().is_empty());
+        receiver.offered(id, filename, size_bytes);
+        receiver.accept(id).unwrap();
+        assert!(sender.accepted(id));
+        
+        let messages = sender.next_chunks();
+        assert_eq!(messages.len(), 4);
+        for message in messages {
+            match message {
+                Message::FileTransferChunk { id, offset, data } => receiver.receive_chunk(id, offset, &data).unwrap(),
+                Message::FileTransferComplete { id } => {
+                    let path = receiver.complete(id).unwrap();
+                    assert_eq!(path, dir.0.join("downloads").join("notes.txt"));
+                    assert_eq!(fs::read(&path).unwrap(), contents);
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        assert_eq!(receiver.take_saved(id), Some(dir.0.join("downloads").join("notes.txt")));
+    }
+    
+    #[test]
+    fn chunks_past_the_offered_size_are_refused() {
+        let dir = TempDir::new();
+        let mut receiver = FileTransfers::new(dir.0.clone());
+        let id = Uuid::new_v4();
+        receiver.offered(id, "small.bin".to_string(), 4);
+        
+        // Not accepted yet
+        assert!(receiver.receive_chunk(id, 0, b"abcd").is_err());
+        
+        receiver.accept(id).unwrap();
+        assert!(receiver.receive_chunk(id, 0, b"abcde").is_err());
+        // An offset near the top of the range mustn't wrap past the size check
+        assert!(receiver.receive_chunk(id, u64::MAX - 1, b"abcd").is_err());
+        receiver.receive_chunk(id, 0, b"ab").unwrap();
+        // Not all of it arrived
+        assert!(receiver.complete(id).is_err());
+    }
+    
+    #[test]
+    fn repeated_or_skipped_chunks_are_refused() {
+        let dir = TempDir::new();
+        let mut receiver = FileTransfers::new(dir.0.clone());
+        let id = Uuid::new_v4();
+        receiver.offered(id, "holes.bin".to_string(), 4);
+        receiver.accept(id).unwrap();
+        
+        receiver.receive_chunk(id, 0, b"ab").unwrap();
+        // Sending the first half again would make the byte count add up with the end missing
+        assert!(receiver.receive_chunk(id, 0, b"ab").is_err());
+        assert!(receiver.receive_chunk(id, 3, b"d").is_err());
+        receiver.receive_chunk(id, 2, b"cd").unwrap();
+        
+        let path = receiver.complete(id).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"abcd");
+    }
+    
+    #[test]
+    fn downloads_dont_overwrite_or_escape_the_directory() {
+        let dir = TempDir::new();
+        fs::write(dir.0.join("notes.txt"), b"").unwrap();
+        
+        assert_eq!(unused_path(&dir.0, "notes.txt"), dir.0.join("notes (1).txt"));
+        assert_eq!(unused_path(&dir.0, "../../etc/passwd"), dir.0.join("passwd"));
+        assert_eq!(unused_path(&dir.0, ".."), dir.0.join("download"));
+    }
+}
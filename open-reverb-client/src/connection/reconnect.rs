@@ -0,0 +1,116 @@
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::config::ReconnectConfig;
+
+// Where a connection is in getting back to the server after it dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconnectState {
+    Idle,
+    // Attempts are counted from 0
+    WaitingRetry { attempt: u32, wake_at: Instant },
+    // Connected again and logging back in
+    Reconnecting { attempt: u32 },
+}
+
+// Exponential backoff between attempts to reconnect to the server
+pub struct ReconnectScheduler {
+    config: ReconnectConfig,
+}
+
+impl ReconnectScheduler {
+    pub fn new(config: ReconnectConfig) -> Self {
+        Self { config }
+    }
+    
+    // Delay before the given attempt, counting from 0: min(max, initial * multiplier^attempt),
+    // plus up to 10% more when jitter is on so clients don't all retry at once
+    pub fn next_delay(&self, attempt: u32) -> Duration {
+        let delay = (self.config.initial_delay_ms as f64 * self.config.multiplier.powi(attempt as i32))
+            .min(self.config.max_delay_ms as f64);
+        
+        let delay = if self.config.jitter {
+            delay + rand::random::<f64>() * delay * 0.1
+        } else {
+            delay
+        };
+        
+        Duration::from_millis(delay as u64)
+    }
+    
+    // Delay before the given attempt, or None once the attempts have run out
+    pub fn schedule(&self, attempt: u32) -> Option<Duration> {
+        if self.config.max_attempts.is_some_and(|max| attempt >= max) {
+            return None;
+        }
+        
+        let delay = self.next_delay(attempt);
+        let delay_ms = delay.as_millis() as u64;
+        info!(attempt, delay_ms, "ReconnectAttempted");
+        
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn delays_grow_exponentially_up_to_the_max() {
+        let scheduler = ReconnectScheduler::new(ReconnectConfig {
+            initial_delay_ms: 500,
+            max_delay_ms: 5_000,
+            multiplier: 2.0,
+            jitter: false,
+            max_attempts: None,
+        });
+        
+        let delays: Vec<u64> = (0..7).map(|attempt| scheduler.next_delay(attempt).as_millis() as u64).collect();
+        assert_eq!(delays, [500, 1_000, 2_000, 4_000, 5_000, 5_000, 5_000]);
+        
+        // Without a limit, attempts never run out
+        assert_eq!(scheduler.schedule(1_000), Some(Duration::from_millis(5_000)));
+    }
+    
+    #[test]
+    fn jitter_adds_at_most_ten_percent() {
+        let scheduler = ReconnectScheduler::new(ReconnectConfig {
+            initial_delay_ms: 1_000,
+            multiplier: 1.5,
+            ..Default::default()
+        });
+        
+        for attempt in 0..5 {
+            let base = 1_000.0 * 1.5f64.powi(attempt as i32);
+            for _ in 0..50 {
+                let delay = scheduler.next_delay(attempt).as_millis() as f64;
+                assert!(delay >= base.floor() && delay <= base * 1.1, "attempt {attempt}: {delay}ms");
+            }
+        }
+    }
+    
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let scheduler = ReconnectScheduler::new(ReconnectConfig {
+            initial_delay_ms: 100,
+            max_delay_ms: 1_000,
+            multiplier: 3.0,
+            jitter: false,
+            max_attempts: Some(3),
+        });
+        
+        let delays: Vec<Option<Duration>> = (0..5).map(|attempt| scheduler.schedule(attempt)).collect();
+        assert_eq!(delays, [
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(300)),
+            Some(Duration::from_millis(900)),
+            None,
+            None,
+        ]);
+        
+        // No attempts at all
+        let scheduler = ReconnectScheduler::new(ReconnectConfig { max_attempts: Some(0), ..Default::default() });
+        assert_eq!(scheduler.schedule(0), None);
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::Result;
+use bytes::Bytes;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use super::Transport;
+use crate::config::NetworkSimParams;
+
+// Development wrapper that makes a transport behave like a poor network.
+// Frames are held in queues until their delay has passed rather than sleeping,
+// so the UI thread polling the connection is never blocked.
+pub struct NetworkSimulator {
+    inner: Box<dyn Transport>,
+    params: NetworkSimParams,
+    outgoing: VecDeque<(Instant, Bytes)>,
+    incoming: VecDeque<(Instant, Bytes)>,
+}
+
+impl NetworkSimulator {
+    pub fn new(inner: Box<dyn Transport>, params: NetworkSimParams) -> Self {
+        Self {
+            inner,
+            params,
+            outgoing: VecDeque::new(),
+            incoming: VecDeque::new(),
+        }
+    }
+    
+    // Apply drop and corruption to a frame, returning None if it was lost
+    fn degrade(&self, bytes: Bytes) -> Option<Bytes> {
+        let mut rng = rand::thread_rng();
+        
+        if rng.gen::<f32>() < self.params.drop_rate {
+            debug!("Simulated network dropped a {} byte frame", bytes.len());
+            return None;
+        }
+        
+        if !bytes.is_empty() && rng.gen::<f32>() < self.params.corrupt_rate {
+            let mut corrupted = bytes.to_vec();
+            let index = rng.gen_range(0..corrupted.len());
+            corrupted[index] ^= 1 << rng.gen_range(0..8);
+            return Some(Bytes::from(corrupted));
+        }
+        
+        Some(bytes)
+    }
+    
+    // When a frame queued now should be released. Frames never overtake each other,
+    // since the real connection underneath is still ordered.
+    fn release_time(&self, queue: &VecDeque<(Instant, Bytes)>) -> Instant {
+        let jitter = if self.params.jitter_ms > 0 {
+            rand::thread_rng().gen_range(0..=self.params.jitter_ms)
+        } else {
+            0
+        };
+        
+        let release = Instant::now() + Duration::from_millis((self.params.latency_ms + jitter) as u64);
+        
+        match queue.back() {
+            Some((last, _)) if *last > release => *last,
+            _ => release,
+        }
+    }
+    
+    fn flush_outgoing(&mut self) -> Result<()> {
+        let now = Instant::now();
+        while matches!(self.outgoing.front(), Some((release, _)) if *release <= now) {
+            if let Some((_, bytes)) = self.outgoing.pop_front() {
+                self.inner.send(bytes)?;
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+impl Transport for NetworkSimulator {
+    fn send(&mut self, bytes: Bytes) -> Result<()> {
+        if let Some(bytes) = self.degrade(bytes) {
+            let release = self.release_time(&self.outgoing);
+            self.outgoing.push_back((release, bytes));
+        }
+        
+        self.flush_outgoing()
+    }
+    
+    fn recv(&mut self) -> Result<Option<Bytes>> {
+        // Receiving is polled regularly, so use it to send anything that has become due
+        self.flush_outgoing()?;
+        
+        while let Some(bytes) = self.inner.recv()? {
+            if let Some(bytes) = self.degrade(bytes) {
+                let release = self.release_time(&self.incoming);
+                self.incoming.push_back((release, bytes));
+            }
+        }
+        
+        let now = Instant::now();
+        match self.incoming.front() {
+            Some((release, _)) if *release <= now => Ok(self.incoming.pop_front().map(|(_, bytes)| bytes)),
+            _ => Ok(None),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    
+    // Whatever is sent comes straight back
+    #[derive(Clone, Default)]
+    struct Loopback(Arc<Mutex<VecDeque<Bytes>>>);
+    
+    impl Transport for Loopback {
+        fn send(&mut self, bytes: Bytes) -> Result<()> {
+            self.0.lock().unwrap().push_back(bytes);
+            Ok(())
+        }
+        
+        fn recv(&mut self) -> Result<Option<Bytes>> {
+            Ok(self.0.lock().unwrap().pop_front())
+        }
+    }
+    
+    fn simulator(params: NetworkSimParams) -> (NetworkSimulator, Loopback) {
+        let loopback = Loopback::default();
+        (NetworkSimulator::new(Box::new(loopback.clone()), params), loopback)
+    }
+    
+    #[test]
+    fn frames_are_held_back_in_order() {
+        let (mut simulator, loopback) = simulator(NetworkSimParams { latency_ms: 30, jitter_ms: 20, ..Default::default() });
+        for i in 0..10u8 {
+            simulator.send(Bytes::from(vec![i])).unwrap();
+        }
+        assert!(loopback.0.lock().unwrap().is_empty());
+        
+        // Latency each way, so everything is back after twice the worst case
+        std::thread::sleep(Duration::from_millis(110));
+        simulator.recv().unwrap();
+        std::thread::sleep(Duration::from_millis(110));
+        
+        let mut received = Vec::new();
+        while let Some(bytes) = simulator.recv().unwrap() {
+            received.extend_from_slice(&bytes);
+        }
+        assert_eq!(received, (0..10).collect::<Vec<u8>>());
+    }
+    
+    #[test]
+    fn drop_rate_is_the_fraction_lost() {
+        let (mut simulator, loopback) = simulator(NetworkSimParams { drop_rate: 0.5, ..Default::default() });
+        for _ in 0..1000 {
+            simulator.send(Bytes::from_static(b"x")).unwrap();
+        }
+        let delivered = loopback.0.lock().unwrap().len();
+        assert!((350..650).contains(&delivered), "{} of 1000 delivered", delivered);
+        
+        let (mut simulator, loopback) = self::simulator(NetworkSimParams { drop_rate: 1.0, ..Default::default() });
+        simulator.send(Bytes::from_static(b"x")).unwrap();
+        assert!(loopback.0.lock().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn corruption_flips_a_single_bit() {
+        let (mut simulator, loopback) = simulator(NetworkSimParams { corrupt_rate: 1.0, ..Default::default() });
+        simulator.send(Bytes::from(vec![0u8; 16])).unwrap();
+        
+        let corrupted = loopback.0.lock().unwrap().pop_front().unwrap();
+        assert_eq!(corrupted.iter().map(|b| b.count_ones()).sum::<u32>(), 1);
+    }
+}
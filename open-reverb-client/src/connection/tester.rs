@@ -0,0 +1,392 @@
+use bytes::Bytes;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use open_reverb_common::protocol::Message;
+use crate::config::{self, ClientConfig};
+use crate::stun::StunClient;
+use super::tls::{self, CertificateCheck};
+use super::websocket::WebSocketConnection;
+use super::{TcpTransport, Transport};
+
+// How long each step waits for the server before giving up
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Padding sizes tried, smallest first, when looking for the largest message that gets through
+const MTU_PROBE_SIZES: [u32; 6] = [512, 1200, 1400, 4096, 16 * 1024, 64 * 1024];
+
+// How often the transport is polled while waiting for an answer
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagnosticResult {
+    pub pass: bool,
+    pub detail: String,
+    pub latency_ms: Option<u32>,
+}
+
+impl DiagnosticResult {
+    fn pass(detail: String, latency: Option<Duration>) -> Self {
+        Self { pass: true, detail, latency_ms: latency.map(|d| d.as_millis() as u32) }
+    }
+    
+    fn fail(detail: String) -> Self {
+        Self { pass: false, detail, latency_ms: None }
+    }
+}
+
+// What each step of a connection test found. Steps are None when they don't apply, e.g. TLS
+// over plain TCP, or couldn't run because an earlier step failed.
+#[derive(Debug, Clone)]
+pub struct DiagnosticReport {
+    pub tcp_connect: DiagnosticResult,
+    pub tls: Option<DiagnosticResult>,
+    pub ping: Option<DiagnosticResult>,
+    pub udp: Option<DiagnosticResult>,
+    pub mtu: Option<DiagnosticResult>,
+}
+
+impl DiagnosticReport {
+    // Each step with its name, in the order they run
+    pub fn steps(&self) -> [(&'static str, Option<&DiagnosticResult>); 5] {
+        [
+            ("TCP connect", Some(&self.tcp_connect)),
+            ("TLS certificate", self.tls.as_ref()),
+            ("Ping", self.ping.as_ref()),
+            ("UDP (STUN)", self.udp.as_ref()),
+            ("Largest message", self.mtu.as_ref()),
+        ]
+    }
+    
+    // Every step that ran passed
+    pub fn passed(&self) -> bool {
+        self.steps().iter().all(|(_, result)| result.is_none_or(|r| r.pass))
+    }
+}
+
+// Works through what's needed to reach a server, step by step, so users can see where a
+// connection goes wrong
+#[derive(Clone)]
+pub struct ConnectionTester {
+    transport: config::Transport,
+    pinned_cert_fingerprint: Option<String>,
+    trust_on_first_use: bool,
+    // UDP reachability is only tested when a STUN server is configured
+    stun_server: Option<String>,
+    timeout: Duration,
+}
+
+impl ConnectionTester {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self {
+            transport: config.transport,
+            pinned_cert_fingerprint: config.pinned_cert_fingerprint.clone(),
+            trust_on_first_use: config.trust_on_first_use,
+            stun_server: config.stun_server.clone(),
+            timeout: STEP_TIMEOUT,
+        }
+    }
+    
+    // Runs every step, blocking until they're done; with an unresponsive server that can take
+    // several timeouts
+    pub fn run_diagnostics(&self, server_url: &str) -> DiagnosticReport {
+        info!("Testing the connection to {}", server_url);
+        
+        let mut report = DiagnosticReport {
+            tcp_connect: self.test_tcp_connect(server_url),
+            tls: None,
+            ping: None,
+            udp: self.stun_server.as_ref().map(|server| test_udp(server)),
+            mtu: None,
+        };
+        if !report.tcp_connect.pass {
+            return report;
+        }
+        
+        let mut transport = match self.open_transport(server_url) {
+            Ok((transport, tls)) => {
+                report.tls = tls;
+                transport
+            }
+            Err(result) => {
+                // Without TLS, failing here means the server isn't speaking our protocol
+                match self.transport {
+                    config::Transport::Tls => report.tls = Some(result),
+                    _ => report.ping = Some(result),
+                }
+                return report;
+            }
+        };
+        
+        report.ping = Some(self.test_ping(transport.as_mut()));
+        if report.ping.as_ref().is_some_and(|ping| ping.pass) {
+            report.mtu = Some(self.test_mtu(transport.as_mut()));
+        }
+        
+        report
+    }
+    
+    fn test_tcp_connect(&self, server_url: &str) -> DiagnosticResult {
+        let addr = match resolve(server_url) {
+            Ok(addr) => addr,
+            Err(e) => return DiagnosticResult::fail(e),
+        };
+        
+        let started = Instant::now();
+        match TcpStream::connect_timeout(&addr, self.timeout) {
+            Ok(_) => DiagnosticResult::pass(format!("Connected to {}", addr), Some(started.elapsed())),
+            Err(e) => DiagnosticResult::fail(format!("Couldn't connect to {}: {}", addr, e)),
+        }
+    }
+    
+    // Connect the way the client would, along with the result of checking the certificate
+    // when the transport is TLS
+    fn open_transport(&self, server_url: &str) -> Result<(Box<dyn Transport>, Option<DiagnosticResult>), DiagnosticResult> {
+        match self.transport {
+            config::Transport::Tcp => TcpTransport::connect(server_url)
+                .map(|transport| (Box::new(transport) as Box<dyn Transport>, None))
+                .map_err(|e| DiagnosticResult::fail(format!("Couldn't open a connection: {}", e))),
+            config::Transport::WebSocket => WebSocketConnection::connect(server_url)
+                .map(|transport| (Box::new(transport) as Box<dyn Transport>, None))
+                .map_err(|e| DiagnosticResult::fail(format!("WebSocket handshake failed: {}", e))),
+            config::Transport::Tls => {
+                let check = match (&self.pinned_cert_fingerprint, self.trust_on_first_use) {
+                    (Some(fingerprint), _) => CertificateCheck::Pinned(fingerprint),
+                    (None, true) => CertificateCheck::TrustOnFirstUse,
+                    (None, false) => CertificateCheck::WebPki,
+                };
+                
+                let started = Instant::now();
+                match tls::connect(server_url, check) {
+                    Ok((transport, fingerprint)) => {
+                        let result = DiagnosticResult::pass(format!("Certificate fingerprint {}", fingerprint), Some(started.elapsed()));
+                        Ok((Box::new(transport), Some(result)))
+                    }
+                    Err(e) => Err(DiagnosticResult::fail(format!("Certificate not accepted: {}", e))),
+                }
+            }
+        }
+    }
+    
+    fn test_ping(&self, transport: &mut dyn Transport) -> DiagnosticResult {
+        let nonce = rand::random();
+        let started = Instant::now();
+        
+        let answered = send(transport, &Message::Ping { nonce })
+            .and_then(|()| self.wait_for(transport, |message| matches!(message, Message::Pong { nonce: n } if *n == nonce)));
+        
+        match answered {
+            Ok(true) => {
+                let rtt = started.elapsed();
+                DiagnosticResult::pass(format!("Round trip took {} ms", rtt.as_millis()), Some(rtt))
+            }
+            Ok(false) => DiagnosticResult::fail(format!("No answer within {} s", self.timeout.as_secs_f32())),
+            Err(e) => DiagnosticResult::fail(e.to_string()),
+        }
+    }
+    
+    // Find the largest padded ping that comes back whole, stopping at the first that doesn't
+    fn test_mtu(&self, transport: &mut dyn Transport) -> DiagnosticResult {
+        let mut largest = None;
+        
+        for payload_size in MTU_PROBE_SIZES {
+            let padding = "x".repeat(payload_size as usize);
+            let started = Instant::now();
+            
+            let answered = send(transport, &Message::DiagnosticPing { payload_size, padding }).and_then(|()| {
+                self.wait_for(transport, |message| {
+                    matches!(message, Message::DiagnosticPong { payload_size: n, padding } if *n == payload_size && padding.len() == payload_size as usize)
+                })
+            });
+            
+            match answered {
+                Ok(true) => largest = Some((payload_size, started.elapsed())),
+                _ => break,
+            }
+        }
+        
+        match largest {
+            Some((size, rtt)) if size == MTU_PROBE_SIZES[MTU_PROBE_SIZES.len() - 1] => {
+                DiagnosticResult::pass(format!("Messages of every size tried, up to {} bytes, got through", size), Some(rtt))
+            }
+            Some((size, rtt)) => DiagnosticResult::pass(format!("Messages of up to {} bytes got through", size), Some(rtt)),
+            None => DiagnosticResult::fail(format!("Not even a {} byte message got through", MTU_PROBE_SIZES[0])),
+        }
+    }
+    
+    // Poll for the first message `expected` accepts, giving up after the timeout. Errors from
+    // the server end the wait.
+    fn wait_for(&self, transport: &mut dyn Transport, expected: impl Fn(&Message) -> bool) -> anyhow::Result<bool> {
+        let deadline = Instant::now() + self.timeout;
+        
+        while Instant::now() < deadline {
+            match transport.recv()? {
+                Some(frame) => match serde_json::from_slice::<Message>(&frame) {
+                    Ok(message) if expected(&message) => return Ok(true),
+                    Ok(Message::Error { code, message }) => return Err(anyhow::anyhow!("Server error {}: {}", code, message)),
+                    _ => {}
+                },
+                None => thread::sleep(POLL_INTERVAL),
+            }
+        }
+        
+        Ok(false)
+    }
+}
+
+fn test_udp(server: &str) -> DiagnosticResult {
+    let started = Instant::now();
+    match StunClient::connect(server).and_then(|client| client.binding()) {
+        Ok(public_addr) => DiagnosticResult::pass(
+            format!("{} sees us at {}", server, public_addr),
+            Some(started.elapsed()),
+        ),
+        Err(e) => DiagnosticResult::fail(format!("No STUN answer from {}: {}", server, e)),
+    }
+}
+
+fn send(transport: &mut dyn Transport, message: &Message) -> anyhow::Result<()> {
+    transport.send(Bytes::from(serde_json::to_vec(message)?))
+}
+
+// The host and port to open a TCP connection to, whether the address is plain "host:port" or
+// a WebSocket URL
+fn resolve(server_url: &str) -> Result<SocketAddr, String> {
+    let (without_scheme, default_port) = match server_url.split_once("://") {
+        Some(("wss", rest)) => (rest, Some(443)),
+        Some((_, rest)) => (rest, Some(80)),
+        None => (server_url, None),
+    };
+    let host = without_scheme.split('/').next().unwrap_or_default();
+    
+    let has_port = host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    let with_port = match default_port {
+        Some(port) if !has_port => format!("{}:{}", host, port),
+        _ => host.to_string(),
+    };
+    
+    with_port
+        .to_socket_addrs()
+        .map_err(|e| format!("Couldn't resolve {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    
+    const TIMEOUT: Duration = Duration::from_millis(300);
+    
+    // A server that answers pings, dropping diagnostic pings padded beyond `max_payload`
+    fn mock_server(max_payload: u32) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                thread::spawn(move || loop {
+                    let mut len = [0u8; 4];
+                    if stream.read_exact(&mut len).is_err() {
+                        return;
+                    }
+                    let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+                    stream.read_exact(&mut frame).unwrap();
+                    
+                    let reply = match serde_json::from_slice(&frame).unwrap() {
+                        Message::Ping { nonce } => Message::Pong { nonce },
+                        Message::DiagnosticPing { payload_size, padding } if payload_size <= max_payload => {
+                            Message::DiagnosticPong { payload_size, padding }
+                        }
+                        _ => continue,
+                    };
+                    let bytes = serde_json::to_vec(&reply).unwrap();
+                    stream.write_all(&(bytes.len() as u32).to_be_bytes()).unwrap();
+                    stream.write_all(&bytes).unwrap();
+                });
+            }
+        });
+        
+        addr
+    }
+    
+    // A STUN server that tells everyone they're at 203.0.113.7:4000
+    fn mock_stun_server() -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if len < 20 || buf[..2] != [0x00, 0x01] {
+                    continue;
+                }
+                
+                let mut response = vec![0x01, 0x01, 0x00, 0x0C];
+                response.extend_from_slice(&buf[4..20]);
+                response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+                response.extend_from_slice(&(4000u16 ^ 0x2112).to_be_bytes());
+                response.extend_from_slice(&(u32::from_be_bytes([203, 0, 113, 7]) ^ 0x2112_A442).to_be_bytes());
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+        
+        addr
+    }
+    
+    fn tester(stun_server: Option<String>) -> ConnectionTester {
+        let config = ClientConfig {
+            transport: config::Transport::Tcp,
+            stun_server,
+            ..ClientConfig::default()
+        };
+        ConnectionTester { timeout: TIMEOUT, ..ConnectionTester::new(&config) }
+    }
+    
+    #[test]
+    fn each_step_is_measured_against_a_mock_server() {
+        let server = mock_server(1400);
+        let report = tester(Some(mock_stun_server())).run_diagnostics(&server);
+        
+        assert!(report.tcp_connect.pass);
+        assert!(report.tcp_connect.latency_ms.is_some());
+        // Plain TCP has no certificate to check
+        assert!(report.tls.is_none());
+        
+        let ping = report.ping.as_ref().unwrap();
+        assert!(ping.pass, "{}", ping.detail);
+        assert!(ping.latency_ms.unwrap() < TIMEOUT.as_millis() as u32);
+        
+        let udp = report.udp.as_ref().unwrap();
+        assert!(udp.pass, "{}", udp.detail);
+        assert!(udp.detail.contains("203.0.113.7:4000"));
+        
+        let mtu = report.mtu.as_ref().unwrap();
+        assert!(mtu.pass);
+        assert_eq!(mtu.detail, "Messages of up to 1400 bytes got through");
+        assert!(report.passed());
+    }
+    
+    #[test]
+    fn later_steps_are_skipped_when_the_server_is_unreachable() {
+        // Bind and drop a listener for a port nothing is listening on
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let report = tester(None).run_diagnostics(&addr);
+        
+        assert!(!report.tcp_connect.pass);
+        assert!(report.tls.is_none() && report.ping.is_none() && report.udp.is_none() && report.mtu.is_none());
+        assert!(!report.passed());
+    }
+    
+    #[test]
+    fn websocket_urls_resolve_to_their_host() {
+        assert_eq!(resolve("ws://127.0.0.1:9000/ws").unwrap(), "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(resolve("ws://127.0.0.1/ws").unwrap(), "127.0.0.1:80".parse().unwrap());
+        assert_eq!(resolve("127.0.0.1:8080").unwrap(), "127.0.0.1:8080".parse().unwrap());
+    }
+}
@@ -0,0 +1,182 @@
+use anyhow::Result;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName, StreamOwned};
+use sha2::{Digest, Sha256};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+use super::{ConnectionError, TcpTransport};
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+// How the server's certificate is checked during the handshake
+pub enum CertificateCheck<'a> {
+    // Standard verification against the system's trusted CAs
+    WebPki,
+    // Only the SHA-256 fingerprint of the leaf certificate is checked, so self-signed certificates work
+    Pinned(&'a str),
+    // Accept whatever the server presents, so its fingerprint can be pinned afterwards
+    TrustOnFirstUse,
+}
+
+// Accepts any certificate; the fingerprint is checked once the handshake is done
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// Open a TLS session to the server, returning the framed transport and the server's certificate fingerprint
+pub fn connect(server_url: &str, check: CertificateCheck) -> Result<(TcpTransport<TlsStream>, String)> {
+    let (stream, fingerprint) = handshake(server_url, &check)?;
+    
+    if let CertificateCheck::Pinned(expected) = check {
+        if normalize_fingerprint(expected) != fingerprint {
+            warn!("Certificate for {} does not match the pinned fingerprint", server_url);
+            return Err(ConnectionError::CertificatePinMismatch {
+                expected: expected.to_string(),
+                got: fingerprint,
+            }.into());
+        }
+    }
+    
+    // Reads are polled, so switch to non-blocking once the handshake is done
+    stream.sock.set_nonblocking(true)?;
+    
+    Ok((TcpTransport::new(stream), fingerprint))
+}
+
+// Connect only long enough to read the server's certificate fingerprint
+pub fn fetch_fingerprint(server_url: &str) -> Result<String> {
+    let (_, fingerprint) = handshake(server_url, &CertificateCheck::TrustOnFirstUse)?;
+    Ok(fingerprint)
+}
+
+// SHA-256 of a DER-encoded certificate as lowercase hex
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Fingerprints are often written in upper case with colons, e.g. "AB:CD:..."
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn handshake(server_url: &str, check: &CertificateCheck) -> Result<(TlsStream, String)> {
+    let mut root_store = RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+    
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    
+    if !matches!(check, CertificateCheck::WebPki) {
+        config.dangerous().set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+    }
+    
+    let host = server_url
+        .rsplit_once(':')
+        .map_or(server_url, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let server_name = ServerName::try_from(host)?;
+    
+    info!("Starting TLS handshake with {}", server_url);
+    let socket = TcpStream::connect(server_url)?;
+    let connection = ClientConnection::new(Arc::new(config), server_name)?;
+    let mut stream = StreamOwned::new(connection, socket);
+    
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    
+    let fingerprint = stream
+        .conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| fingerprint(&cert.0))
+        .ok_or_else(|| anyhow::anyhow!("Server did not present a certificate"))?;
+    
+    Ok((stream, fingerprint))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{PrivateKey, ServerConfig, ServerConnection};
+    use std::net::TcpListener;
+    
+    // A self-signed certificate for localhost
+    const CERT_DER: &[u8] = include_bytes!("../../testdata/cert.der");
+    const KEY_DER: &[u8] = include_bytes!("../../testdata/key.der");
+    
+    // Completes the handshake with each of `connections` clients, then hangs up
+    fn serve(connections: usize) -> String {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![Certificate(CERT_DER.to_vec())], PrivateKey(KEY_DER.to_vec()))
+            .unwrap();
+        let config = Arc::new(config);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        
+        std::thread::spawn(move || {
+            for socket in listener.incoming().take(connections) {
+                let mut stream = StreamOwned::new(ServerConnection::new(Arc::clone(&config)).unwrap(), socket.unwrap());
+                while stream.conn.is_handshaking() {
+                    if stream.conn.complete_io(&mut stream.sock).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        
+        addr
+    }
+    
+    #[test]
+    fn only_the_pinned_certificate_is_accepted() {
+        let addr = serve(3);
+        let expected = fingerprint(CERT_DER);
+        
+        assert_eq!(fetch_fingerprint(&addr).unwrap(), expected);
+        
+        let err = connect(&addr, CertificateCheck::Pinned(&"ab".repeat(32))).err().unwrap();
+        match err.downcast_ref::<ConnectionError>() {
+            Some(ConnectionError::CertificatePinMismatch { expected: pinned, got }) => {
+                assert_eq!((pinned.as_str(), got), ("ab".repeat(32).as_str(), &expected));
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        
+        // Written the way certificate viewers show it
+        let pinned = expected
+            .to_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        let (_, fingerprint) = connect(&addr, CertificateCheck::Pinned(&pinned)).unwrap();
+        assert_eq!(fingerprint, expected);
+    }
+}
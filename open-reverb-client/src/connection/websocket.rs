@@ -0,0 +1,69 @@
+use anyhow::Result;
+use bytes::Bytes;
+use std::net::TcpStream;
+use tokio_tungstenite::tungstenite::{self, stream::MaybeTlsStream, WebSocket};
+use tracing::info;
+
+use super::Transport;
+
+// Path the server exposes its WebSocket endpoint on by default
+pub const DEFAULT_WS_PATH: &str = "/ws";
+
+// WebSocket transport for networks that only allow HTTP traffic.
+// Each message is sent as a single binary frame, so no length prefix is needed.
+pub struct WebSocketConnection {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+}
+
+impl WebSocketConnection {
+    pub fn connect(server_url: &str) -> Result<Self> {
+        let url = websocket_url(server_url);
+        info!("Opening WebSocket connection to {}", url);
+        
+        let (socket, _response) = tungstenite::connect(url.as_str())?;
+        
+        // Switch to non-blocking once the handshake is done so reads can be polled
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream.set_nonblocking(true)?;
+        }
+        
+        Ok(Self { socket })
+    }
+}
+
+impl Transport for WebSocketConnection {
+    fn send(&mut self, bytes: Bytes) -> Result<()> {
+        match self.socket.send(tungstenite::Message::Binary(bytes.to_vec())) {
+            Ok(()) => Ok(()),
+            // The frame is queued and will be written on the next flush
+            Err(tungstenite::Error::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    
+    fn recv(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            match self.socket.read() {
+                Ok(tungstenite::Message::Binary(data)) => return Ok(Some(Bytes::from(data))),
+                Ok(tungstenite::Message::Close(_)) => {
+                    return Err(anyhow::anyhow!("Connection closed by server"));
+                }
+                // Ping/pong frames are answered by tungstenite itself
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+// Turn a plain "host:port" server address into a WebSocket URL
+fn websocket_url(server_url: &str) -> String {
+    if server_url.starts_with("ws://") || server_url.starts_with("wss://") {
+        server_url.to_string()
+    } else {
+        format!("ws://{}{}", server_url, DEFAULT_WS_PATH)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,181 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::backtrace::Backtrace;
+use std::fs;
+use std::panic::{self, PanicHookInfo};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::config::{self, ClientConfig};
+
+// How long to wait on the crash report endpoint before giving up
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashReport {
+    pub message: String,
+    pub location: Option<String>,
+    pub backtrace: String,
+    pub os: String,
+    pub app_version: String,
+    // RFC 3339, in UTC
+    pub timestamp: String,
+}
+
+impl CrashReport {
+    fn from_panic(info: &PanicHookInfo) -> Self {
+        let message = if let Some(message) = info.payload().downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = info.payload().downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Unknown panic".to_string()
+        };
+        
+        Self {
+            message,
+            location: info.location().map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column())),
+            backtrace: Backtrace::force_capture().to_string(),
+            os: format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+            app_version: open_reverb_common::version().to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+// Writes a report for every panic, and sends it on if the user has opted in
+pub struct CrashReporter;
+
+impl CrashReporter {
+    pub fn install() {
+        let default_hook = panic::take_hook();
+        
+        panic::set_hook(Box::new(move |info| {
+            if let Some(path) = Self::record(info, Self::reports_dir()) {
+                // Read the config again so an opt-in made since startup is respected
+                let config = config::load_config().unwrap_or_default();
+                if config.send_crash_reports && Self::submit(&config, &path).is_ok() {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+            
+            default_hook(info);
+        }));
+    }
+    
+    // Write a report of the panic into `dir`, returning where it went
+    fn record(info: &PanicHookInfo, dir: Result<PathBuf>) -> Option<PathBuf> {
+        match dir.and_then(|dir| Self::write_report(&dir, &CrashReport::from_panic(info))) {
+            Ok(path) => Some(path),
+            Err(e) => {
+                error!("Failed to write crash report: {}", e);
+                None
+            }
+        }
+    }
+    
+    pub fn reports_dir() -> Result<PathBuf> {
+        let dir = config::get_config_dir()?.join("crash_reports");
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+    
+    pub fn write_report(dir: &Path, report: &CrashReport) -> Result<PathBuf> {
+        let name = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        let path = dir.join(format!("{}.json", name));
+        
+        fs::write(&path, serde_json::to_string_pretty(report)?)?;
+        
+        Ok(path)
+    }
+    
+    // Reports that haven't been sent yet, oldest first
+    pub fn pending_reports() -> Vec<PathBuf> {
+        let mut reports: Vec<PathBuf> = Self::reports_dir()
+            .and_then(|dir| Ok(fs::read_dir(dir)?))
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        
+        // File names are timestamps, so they sort chronologically
+        reports.sort();
+        reports
+    }
+    
+    pub fn submit(config: &ClientConfig, path: &Path) -> Result<()> {
+        let url = config.crash_report_url
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("No crash report URL configured"))?;
+        
+        let report = fs::read_to_string(path)?;
+        
+        ureq::post(url)
+            .timeout(UPLOAD_TIMEOUT)
+            .set("Content-Type", "application/json")
+            .send_string(&report)?;
+        
+        info!("Submitted crash report {}", path.display());
+        Ok(())
+    }
+    
+    // Send every pending report, removing those that went through
+    pub fn submit_pending(config: &ClientConfig) -> Result<()> {
+        for path in Self::pending_reports() {
+            Self::submit(config, &path)?;
+            fs::remove_file(&path)?;
+        }
+        
+        Ok(())
+    }
+    
+    pub fn discard_pending() {
+        for path in Self::pending_reports() {
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Failed to remove crash report {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    
+    #[test]
+    fn panics_are_written_as_json_reports() {
+        let dir = std::env::temp_dir().join(format!("open-reverb-crashes-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        
+        // The installed hook, writing to the test's directory instead of the config directory
+        let written = Arc::new(Mutex::new(None));
+        let previous_hook = panic::take_hook();
+        panic::set_hook({
+            let (dir, written) = (dir.clone(), Arc::clone(&written));
+            Box::new(move |info| *written.lock().unwrap() = CrashReporter::record(info, Ok(dir.clone())))
+        });
+        let line = line!() + 1;
+        let result = panic::catch_unwind(|| panic!("fell over after {} frames", 42));
+        panic::set_hook(previous_hook);
+        assert!(result.is_err());
+        
+        let path = written.lock().unwrap().take().unwrap();
+        assert_eq!(path.parent(), Some(dir.as_path()));
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let report: CrashReport = serde_json::from_value(json.clone()).unwrap();
+        
+        assert_eq!(report.message, "fell over after 42 frames");
+        assert!(report.location.unwrap().contains(&format!("crash_reporter.rs:{}:", line)));
+        assert!(!report.backtrace.is_empty());
+        assert_eq!(report.app_version, open_reverb_common::version());
+        assert!(chrono::DateTime::parse_from_rfc3339(&report.timestamp).is_ok());
+        assert_eq!(json.as_object().unwrap().len(), 6);
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
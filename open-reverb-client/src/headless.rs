@@ -0,0 +1,332 @@
+use anyhow::{anyhow, Result};
+use crossbeam_channel::TryRecvError;
+use serde_json::json;
+use std::io::{BufRead, Write};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use open_reverb_common::models::Server;
+use open_reverb_common::protocol::Message;
+use crate::config::ClientConfig;
+use crate::connection::{Connection, CHANNEL_PAGE_SIZE};
+
+const USAGE: &str = "Usage: open-reverb-client --headless --username <name> [--password <password>] \
+[--server <host:port>] [--channel <name or ID>] [--command stdin]";
+
+// How long to wait for the server to accept the login and describe itself
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// 20ms of 16-bit mono PCM at 48kHz, the size of a captured voice frame
+const VOICE_FRAME_BYTES: usize = 1920;
+const VOICE_FRAME_INTERVAL: Duration = Duration::from_millis(20);
+
+// Options for running without a window, for scripts and integration tests
+#[derive(Debug)]
+pub struct HeadlessArgs {
+    pub server: String,
+    pub username: String,
+    pub password: String,
+    // Name or ID of the channel to join once logged in
+    pub channel: Option<String>,
+}
+
+impl HeadlessArgs {
+    // None when --headless wasn't given, and the GUI should start as usual
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Option<Self>> {
+        let mut headless = false;
+        let mut server = None;
+        let mut username = None;
+        let mut password = None;
+        let mut channel = None;
+        
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value\n{}", arg, USAGE));
+            match arg.as_str() {
+                "--headless" => headless = true,
+                "--server" => server = Some(value()?),
+                "--username" => username = Some(value()?),
+                "--password" => password = Some(value()?),
+                "--channel" => channel = Some(value()?),
+                // Commands can only come from stdin for now
+                "--command" => match value()?.as_str() {
+                    "stdin" => {}
+                    source => return Err(anyhow!("Unsupported command source: {}\n{}", source, USAGE)),
+                },
+                _ => return Err(anyhow!("Unknown argument: {}\n{}", arg, USAGE)),
+            }
+        }
+        
+        if !headless {
+            return Ok(None);
+        }
+        
+        Ok(Some(Self {
+            server: server.unwrap_or_else(|| ClientConfig::default().server_url),
+            username: username.ok_or_else(|| anyhow!("--username is required in headless mode\n{}", USAGE))?,
+            password: password.unwrap_or_default(),
+            channel,
+        }))
+    }
+}
+
+// Connect, log in and join the channel, then run commands read from stdin until `quit` or the
+// end of input. Everything the server sends and the result of every command is written to
+// stdout as a line of JSON.
+pub fn run(args: HeadlessArgs) -> Result<()> {
+    let mut client = HeadlessClient {
+        connection: Connection::new(),
+        server: None,
+        channels_pending: false,
+    };
+    
+    client.connection.connect(&args.server)?;
+    client.connection.login(&args.username, &args.password)?;
+    client.wait_for(|c| c.server.is_some() && !c.channels_pending && c.connection.get_user_id().is_some())?;
+    
+    if let Some(channel) = &args.channel {
+        let channel_id = client.find_channel(channel)?;
+        client.connection.join_channel(channel_id, false)?;
+        client.wait_for(|c| c.connection.get_current_channel_id() == Some(channel_id))?;
+    }
+    
+    print_json(json!({
+        "event": "ready",
+        "user_id": client.connection.get_user_id(),
+        "channel_id": client.connection.get_current_channel_id(),
+    }));
+    
+    // Read stdin on its own thread, so the connection keeps being serviced between commands
+    let (line_tx, line_rx) = crossbeam_channel::unbounded();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    
+    loop {
+        client.poll()?;
+        
+        match line_rx.try_recv() {
+            Ok(line) => {
+                if !client.run_command(line.trim()) {
+                    break;
+                }
+            }
+            Err(TryRecvError::Empty) => std::thread::sleep(POLL_INTERVAL),
+            Err(TryRecvError::Disconnected) => break,
+        }
+    }
+    
+    client.connection.disconnect();
+    Ok(())
+}
+
+struct HeadlessClient {
+    connection: Connection,
+    // Users and channels, kept up to date from what the server sends
+    server: Option<Server>,
+    // Set while a big server's channels are still being paged in
+    channels_pending: bool,
+}
+
+impl HeadlessClient {
+    // Handle and print everything the server has sent
+    fn poll(&mut self) -> Result<()> {
+        self.connection.ping_if_due();
+        
+        for message in self.connection.process_messages() {
+            self.handle_message(&message)?;
+            
+            // Media is too noisy to print, and isn't useful as JSON
+            if !matches!(message, Message::VoiceData { .. } | Message::VideoData { .. } | Message::ScreenShareData { .. }) {
+                print_json(json!({ "event": "message", "message": message }));
+            }
+        }
+        
+        if !self.connection.is_connected() {
+            return Err(anyhow!("Disconnected from server"));
+        }
+        
+        Ok(())
+    }
+    
+    fn handle_message(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::LoginResponse { success: false, error, .. } => {
+                return Err(anyhow!("Login failed: {}", error.as_deref().unwrap_or("unknown error")));
+            }
+            Message::TOTPRequest { .. } => {
+                return Err(anyhow!("The server requires a TOTP code, which headless mode doesn't support"));
+            }
+            Message::ServerInfo { server } => {
+                self.server = Some(server.clone());
+            }
+            Message::ServerInfoSummary { server_id, name, schema_version, .. } => {
+                self.server = Some(Server {
+                    id: *server_id,
+                    name: name.clone(),
+                    description: None,
+                    channels: Vec::new(),
+                    users: Vec::new(),
+                    schema_version: *schema_version,
+                });
+                self.channels_pending = true;
+                self.connection.get_channels(0, CHANNEL_PAGE_SIZE)?;
+                self.connection.get_online_users(None)?;
+            }
+            Message::ChannelList { channels, total } => {
+                if let Some(server) = &mut self.server {
+                    server.channels.extend(channels.iter().cloned());
+                    self.channels_pending = !channels.is_empty() && (server.channels.len() as u32) < *total;
+                    if self.channels_pending {
+                        self.connection.get_channels(server.channels.len() as u32, CHANNEL_PAGE_SIZE)?;
+                    }
+                }
+            }
+            Message::UserList { users } => {
+                if let Some(server) = &mut self.server {
+                    server.users = users.clone();
+                }
+            }
+            Message::ChannelMediaState { channel_id, .. } => {
+                self.connection.set_current_channel_id(Some(*channel_id));
+            }
+            Message::UserJoined { user } | Message::UserUpdated { user } => {
+                if let Some(server) = &mut self.server {
+                    match server.users.iter_mut().find(|u| u.id == user.id) {
+                        Some(existing) => *existing = user.clone(),
+                        None => server.users.push(user.clone()),
+                    }
+                }
+            }
+            Message::UserLeft { user_id } => {
+                if let Some(server) = &mut self.server {
+                    server.users.retain(|u| u.id != *user_id);
+                }
+            }
+            Message::ChannelUpdate { channel } => {
+                if let Some(server) = &mut self.server {
+                    match server.channels.iter_mut().find(|c| c.id == channel.id) {
+                        Some(existing) => *existing = channel.clone(),
+                        None => server.channels.push(channel.clone()),
+                    }
+                }
+            }
+            _ => {}
+        }
+        
+        Ok(())
+    }
+    
+    // Poll until the condition holds, giving up after LOGIN_TIMEOUT
+    fn wait_for(&mut self, condition: impl Fn(&Self) -> bool) -> Result<()> {
+        let started = Instant::now();
+        
+        while !condition(self) {
+            if started.elapsed() > LOGIN_TIMEOUT {
+                return Err(anyhow!("Timed out waiting for the server"));
+            }
+            
+            self.poll()?;
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        
+        Ok(())
+    }
+    
+    fn find_channel(&self, channel: &str) -> Result<Uuid> {
+        let channels = self.server.as_ref().map(|s| s.channels.as_slice()).unwrap_or_default();
+        
+        channels
+            .iter()
+            .find(|c| c.name == channel || c.id.to_string() == channel)
+            .map(|c| c.id)
+            .ok_or_else(|| anyhow!("No channel named {}", channel))
+    }
+    
+    // Returns false once the client should quit
+    fn run_command(&mut self, line: &str) -> bool {
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+        
+        let result = match command {
+            "" => return true,
+            "quit" => return false,
+            "send-text" => self.send_text(argument),
+            "send-voice" => self.send_voice(argument),
+            "sdp-offer" | "sdp-answer" | "ice-candidate" => self.send_signal(command, argument),
+            "dump-users" => Ok(json!({ "users": self.server.as_ref().map(|s| &s.users) })),
+            "dump-channels" => Ok(json!({ "channels": self.server.as_ref().map(|s| &s.channels) })),
+            _ => Err(anyhow!("Unknown command: {}", command)),
+        };
+        
+        match result {
+            Ok(mut output) => {
+                output["result"] = json!(command);
+                print_json(output);
+            }
+            Err(e) => print_json(json!({ "result": command, "error": e.to_string() })),
+        }
+        
+        true
+    }
+    
+    fn send_text(&mut self, content: &str) -> Result<serde_json::Value> {
+        let channel_id = self.connection.get_current_channel_id().ok_or_else(|| anyhow!("Not in a channel"))?;
+        if content.is_empty() {
+            return Err(anyhow!("Usage: send-text <message>"));
+        }
+        
+        self.connection.send_text_message(channel_id, content.to_string())?;
+        Ok(json!({ "channel_id": channel_id }))
+    }
+    
+    // Relay WebRTC signalling to one user
+    fn send_signal(&mut self, command: &str, argument: &str) -> Result<serde_json::Value> {
+        let (to, payload) = argument
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Usage: {} <user ID> <payload>", command))?;
+        let to: Uuid = to.parse()?;
+        let payload = payload.trim().to_string();
+        
+        match command {
+            "sdp-offer" => self.connection.send_sdp_offer(to, payload)?,
+            "sdp-answer" => self.connection.send_sdp_answer(to, payload)?,
+            _ => self.connection.send_ice_candidate(to, payload)?,
+        }
+        Ok(json!({ "to": to }))
+    }
+    
+    // Stream a file of 16-bit mono 48kHz PCM as voice, in real time
+    fn send_voice(&mut self, path: &str) -> Result<serde_json::Value> {
+        let channel_id = self.connection.get_current_channel_id().ok_or_else(|| anyhow!("Not in a channel"))?;
+        let user_id = self.connection.get_user_id().ok_or_else(|| anyhow!("Not logged in"))?;
+        if path.is_empty() {
+            return Err(anyhow!("Usage: send-voice <file>"));
+        }
+        
+        let audio = std::fs::read(path)?;
+        let mut seq = Connection::initial_seq();
+        
+        self.connection.get_sender().send(Message::VoiceStarted { user_id })?;
+        for frame in audio.chunks(VOICE_FRAME_BYTES) {
+            self.connection.send_voice_data(user_id, channel_id, seq, frame.to_vec())?;
+            seq = seq.wrapping_add(1);
+            std::thread::sleep(VOICE_FRAME_INTERVAL);
+        }
+        self.connection.get_sender().send(Message::VoiceStopped { user_id })?;
+        
+        Ok(json!({ "frames": audio.len().div_ceil(VOICE_FRAME_BYTES) }))
+    }
+}
+
+fn print_json(value: serde_json::Value) {
+    let mut stdout = std::io::stdout().lock();
+    // Nothing useful can be done if stdout has gone away
+    let _ = writeln!(stdout, "{}", value);
+    let _ = stdout.flush();
+}
@@ -1,8 +1,14 @@
 mod app;
 mod audio;
+mod bandwidth;
 mod config;
 mod connection;
+mod crash_reporter;
+mod headless;
+mod privacy;
+mod sync;
 mod ui;
+mod stun;
 mod video;
 
 use anyhow::Result;
@@ -11,14 +17,24 @@ use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
+    let headless = headless::HeadlessArgs::parse(std::env::args().skip(1))?;
+    
+    // Initialize logging. Logs go to stderr, leaving stdout for headless output.
     let subscriber = FmtSubscriber::builder()
         .with_max_level(Level::INFO)
+        .with_writer(std::io::stderr)
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
     
     info!("Starting Open Reverb Client version {}", open_reverb_common::version());
     
+    crash_reporter::CrashReporter::install();
+    
+    if let Some(args) = headless {
+        headless::run(args)?;
+        return Ok(());
+    }
+    
     // Set up GUI window options
     let options = NativeOptions {
         initial_window_size: Some(egui::vec2(1280.0, 720.0)),
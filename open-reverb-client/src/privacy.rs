@@ -0,0 +1,49 @@
+// Whether outgoing camera video is blurred and paused because the window lost focus
+pub struct PrivacyMode {
+    active: bool,
+}
+
+impl PrivacyMode {
+    pub fn new() -> Self {
+        Self { active: false }
+    }
+    
+    // Called every frame. `enabled` is whether privacy mode applies at all: the setting is on
+    // and the camera is running. Returns the new state when it changes.
+    pub fn update(&mut self, enabled: bool, focused: bool) -> Option<bool> {
+        let active = enabled && !focused;
+        if active == self.active {
+            return None;
+        }
+        
+        self.active = active;
+        Some(active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn activates_on_focus_loss_and_clears_on_focus_gain() {
+        let mut privacy = PrivacyMode::new();
+        
+        assert_eq!(privacy.update(true, true), None);
+        assert_eq!(privacy.update(true, false), Some(true));
+        // Only changes are reported
+        assert_eq!(privacy.update(true, false), None);
+        assert_eq!(privacy.update(true, true), Some(false));
+        assert_eq!(privacy.update(true, true), None);
+    }
+    
+    #[test]
+    fn clears_when_disabled_while_unfocused() {
+        let mut privacy = PrivacyMode::new();
+        
+        assert_eq!(privacy.update(false, false), None);
+        assert_eq!(privacy.update(true, false), Some(true));
+        // The camera stopped or the setting was turned off
+        assert_eq!(privacy.update(false, false), Some(false));
+    }
+}
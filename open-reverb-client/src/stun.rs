@@ -0,0 +1,302 @@
+use anyhow::{anyhow, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+// Minimal STUN client (RFC 5389): enough to ask a server which address our UDP packets arrive
+// from, which shows whether UDP gets through at all
+
+const DEFAULT_STUN_PORT: u16 = 3478;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+
+// Each request is retransmitted a few times before giving up
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+const REQUEST_ATTEMPTS: usize = 3;
+
+// Message types
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+
+// Attribute types
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+struct StunMessage {
+    msg_type: u16,
+    transaction_id: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl StunMessage {
+    fn new(msg_type: u16) -> Self {
+        Self {
+            msg_type,
+            transaction_id: rand_transaction_id(),
+            attributes: Vec::new(),
+        }
+    }
+    
+    fn attribute(&self, attr_type: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(t, _)| *t == attr_type)
+            .map(|(_, value)| value.as_slice())
+    }
+    
+    // Only ever requests without attributes are sent
+    fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN);
+        packet.extend_from_slice(&self.msg_type.to_be_bytes());
+        packet.extend_from_slice(&0u16.to_be_bytes());
+        packet.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        packet.extend_from_slice(&self.transaction_id);
+        packet
+    }
+    
+    fn decode(packet: &[u8]) -> Option<Self> {
+        if packet.len() < HEADER_LEN || packet[0] & 0xC0 != 0 {
+            return None;
+        }
+        
+        let msg_type = u16::from_be_bytes([packet[0], packet[1]]);
+        let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        let cookie = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        
+        if cookie != MAGIC_COOKIE || packet.len() < HEADER_LEN + length {
+            return None;
+        }
+        
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&packet[8..HEADER_LEN]);
+        
+        let mut attributes = Vec::new();
+        let mut body = &packet[HEADER_LEN..HEADER_LEN + length];
+        while body.len() >= 4 {
+            let attr_type = u16::from_be_bytes([body[0], body[1]]);
+            let attr_len = u16::from_be_bytes([body[2], body[3]]) as usize;
+            if body.len() < 4 + attr_len {
+                return None;
+            }
+            
+            attributes.push((attr_type, body[4..4 + attr_len].to_vec()));
+            
+            let advance = (4 + attr_len + padding(attr_len)).min(body.len());
+            body = &body[advance..];
+        }
+        
+        Some(Self {
+            msg_type,
+            transaction_id,
+            attributes,
+        })
+    }
+}
+
+pub struct StunClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+}
+
+impl StunClient {
+    // `url` is "host", "host:port" or "stun:host:port"
+    pub fn connect(url: &str) -> Result<Self> {
+        let server = resolve_stun_url(url)?;
+        let bind_addr: SocketAddr = if server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        
+        Ok(Self { socket: UdpSocket::bind(bind_addr)?, server })
+    }
+    
+    // Ask the server which address our packets arrive from
+    pub fn binding(&self) -> Result<SocketAddr> {
+        let response = self.transact(StunMessage::new(BINDING_REQUEST))?;
+        if response.msg_type != BINDING_SUCCESS {
+            return Err(anyhow!("STUN binding failed: {}", describe_error(&response)));
+        }
+        
+        response
+            .attribute(ATTR_XOR_MAPPED_ADDRESS)
+            .and_then(|value| decode_xor_address(value, &response.transaction_id))
+            .ok_or_else(|| anyhow!("STUN binding response has no mapped address"))
+    }
+    
+    // Send a request and wait for the matching response, retransmitting on timeout
+    fn transact(&self, request: StunMessage) -> Result<StunMessage> {
+        let packet = request.encode();
+        self.socket.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        
+        let mut buf = [0u8; 2048];
+        for _ in 0..REQUEST_ATTEMPTS {
+            self.socket.send_to(&packet, self.server)?;
+            
+            loop {
+                match self.socket.recv_from(&mut buf) {
+                    Ok((len, from)) if from == self.server => {
+                        if let Some(response) = StunMessage::decode(&buf[..len]) {
+                            if response.transaction_id == request.transaction_id {
+                                return Ok(response);
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) if is_timeout(&e) => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        
+        Err(anyhow!("STUN server {} did not respond", self.server))
+    }
+}
+
+fn resolve_stun_url(url: &str) -> Result<SocketAddr> {
+    let host = url
+        .trim_start_matches("stun:")
+        .split('?')
+        .next()
+        .unwrap_or_default();
+    
+    let has_port = host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    let with_port = if has_port {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, DEFAULT_STUN_PORT)
+    };
+    
+    with_port
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Could not resolve STUN server {}", url))
+}
+
+fn decode_xor_address(value: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    
+    let ip = match value[1] {
+        0x01 if value.len() >= 8 => {
+            let raw = u32::from_be_bytes([value[4], value[5], value[6], value[7]]);
+            IpAddr::V4(Ipv4Addr::from(raw ^ MAGIC_COOKIE))
+        }
+        0x02 if value.len() >= 20 => {
+            let mask = xor_mask(transaction_id);
+            let mut octets = [0u8; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = value[4 + i] ^ mask[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    
+    Some(SocketAddr::new(ip, port))
+}
+
+// IPv6 addresses are XORed with the magic cookie followed by the transaction id
+fn xor_mask(transaction_id: &[u8; 12]) -> [u8; 16] {
+    let mut mask = [0u8; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(transaction_id);
+    mask
+}
+
+fn describe_error(message: &StunMessage) -> String {
+    match message.attribute(ATTR_ERROR_CODE) {
+        Some(value) if value.len() >= 4 => format!(
+            "{} {}",
+            (value[2] & 0x07) as u16 * 100 + value[3] as u16,
+            String::from_utf8_lossy(&value[4..])
+        ),
+        _ => format!("unexpected response {:#06x}", message.msg_type),
+    }
+}
+
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+fn rand_transaction_id() -> [u8; 12] {
+    let mut id = [0u8; 12];
+    id.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..12]);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const TRANSACTION_ID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+    
+    fn response(attributes: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (attr_type, value) in attributes {
+            body.extend_from_slice(&attr_type.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+            body.resize(body.len() + padding(value.len()), 0);
+        }
+        
+        let mut packet = BINDING_SUCCESS.to_be_bytes().to_vec();
+        packet.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        packet.extend_from_slice(&TRANSACTION_ID);
+        packet.extend_from_slice(&body);
+        packet
+    }
+    
+    #[test]
+    fn mapped_addresses_are_unmasked() {
+        // 203.0.113.7:4000
+        let mut v4 = vec![0x00, 0x01];
+        v4.extend_from_slice(&(4000u16 ^ 0x2112).to_be_bytes());
+        v4.extend_from_slice(&(u32::from_be_bytes([203, 0, 113, 7]) ^ MAGIC_COOKIE).to_be_bytes());
+        assert_eq!(decode_xor_address(&v4, &TRANSACTION_ID), Some("203.0.113.7:4000".parse().unwrap()));
+        
+        let ip: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        let mut v6 = vec![0x00, 0x02];
+        v6.extend_from_slice(&(4000u16 ^ 0x2112).to_be_bytes());
+        v6.extend(ip.octets().iter().zip(xor_mask(&TRANSACTION_ID)).map(|(a, b)| a ^ b));
+        assert_eq!(decode_xor_address(&v6, &TRANSACTION_ID), Some("[2001:db8::7]:4000".parse().unwrap()));
+        
+        assert_eq!(decode_xor_address(&v4[..6], &TRANSACTION_ID), None);
+    }
+    
+    #[test]
+    fn responses_are_parsed_past_padding() {
+        let packet = response(&[(0x8022, b"odd"), (ATTR_XOR_MAPPED_ADDRESS, &[0, 1, 0, 0, 0, 0, 0, 0])]);
+        let message = StunMessage::decode(&packet).unwrap();
+        
+        assert_eq!((message.msg_type, message.transaction_id), (BINDING_SUCCESS, TRANSACTION_ID));
+        assert_eq!(message.attribute(0x8022), Some(&b"odd"[..]));
+        assert!(message.attribute(ATTR_XOR_MAPPED_ADDRESS).is_some());
+    }
+    
+    #[test]
+    fn anything_else_is_not_stun() {
+        let packet = response(&[(ATTR_XOR_MAPPED_ADDRESS, &[0, 1, 0, 0, 0, 0, 0, 0])]);
+        
+        // Cut short, wrong cookie, or not starting with two zero bits
+        assert!(StunMessage::decode(&packet[..packet.len() - 1]).is_none());
+        let mut wrong_cookie = packet.clone();
+        wrong_cookie[4] ^= 0xFF;
+        assert!(StunMessage::decode(&wrong_cookie).is_none());
+        let mut channel_data = packet.clone();
+        channel_data[0] = 0x40;
+        assert!(StunMessage::decode(&channel_data).is_none());
+        
+        // An attribute longer than the message
+        let mut overlong = packet;
+        overlong[HEADER_LEN + 3] = 0xFF;
+        assert!(StunMessage::decode(&overlong).is_none());
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+// How often a user sending both voice and video tells everyone where each of them is
+pub const SYNC_HINT_INTERVAL: Duration = Duration::from_secs(2);
+
+// Voice and video further apart than this are out of sync
+const MAX_AV_DRIFT_MS: i64 = 100;
+
+// Wall clock time, as carried in captured_at_ms
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Interarrival jitter as defined in RFC 3550 section 6.4.1: the smoothed difference between
+// how far apart frames arrived and how far apart they were captured
+#[derive(Debug, Default)]
+pub struct JitterEstimator {
+    // Capture and arrival time of the last frame
+    last: Option<(u64, u64)>,
+    jitter_ms: f64,
+}
+
+impl JitterEstimator {
+    pub fn update(&mut self, captured_at_ms: u64, arrived_at_ms: u64) {
+        if let Some((last_captured, last_arrived)) = self.last {
+            let diff = arrived_at_ms as i64 - last_arrived as i64;
+            let expected = captured_at_ms as i64 - last_captured as i64;
+            self.jitter_ms += ((diff - expected).abs() as f64 - self.jitter_ms) / 16.0;
+        }
+        
+        self.last = Some((captured_at_ms, arrived_at_ms));
+    }
+    
+    pub fn jitter_ms(&self) -> f64 {
+        self.jitter_ms
+    }
+}
+
+#[derive(Default)]
+struct UserTiming {
+    voice_jitter: JitterEstimator,
+    video_jitter: JitterEstimator,
+    // Capture times of the latest voice and video frames received
+    audio_pts_ms: Option<u64>,
+    video_pts_ms: Option<u64>,
+    // How far apart the sender's voice and video were when captured, from their last sync hint
+    sender_offset_ms: i64,
+    drifting: bool,
+}
+
+// Timing of the voice and video received from each user
+pub struct MediaTiming {
+    users: HashMap<Uuid, UserTiming>,
+}
+
+impl MediaTiming {
+    pub fn new() -> Self {
+        Self {
+            users: HashMap::new(),
+        }
+    }
+    
+    pub fn on_voice_frame(&mut self, user_id: Uuid, captured_at_ms: u64) {
+        let timing = self.users.entry(user_id).or_default();
+        timing.voice_jitter.update(captured_at_ms, now_millis());
+        timing.audio_pts_ms = Some(captured_at_ms);
+        self.check_drift(user_id);
+    }
+    
+    pub fn on_video_frame(&mut self, user_id: Uuid, captured_at_ms: u64) {
+        let timing = self.users.entry(user_id).or_default();
+        timing.video_jitter.update(captured_at_ms, now_millis());
+        timing.video_pts_ms = Some(captured_at_ms);
+        self.check_drift(user_id);
+    }
+    
+    pub fn on_sync_hint(&mut self, user_id: Uuid, audio_pts_ms: u64, video_pts_ms: u64) {
+        let timing = self.users.entry(user_id).or_default();
+        timing.sender_offset_ms = audio_pts_ms as i64 - video_pts_ms as i64;
+    }
+    
+    pub fn remove_user(&mut self, user_id: Uuid) {
+        self.users.remove(&user_id);
+    }
+    
+    // How far a user's voice has got ahead of their video since it was captured; negative when behind
+    pub fn drift_ms(&self, user_id: Uuid) -> Option<i64> {
+        let timing = self.users.get(&user_id)?;
+        let audio_pts = timing.audio_pts_ms? as i64;
+        let video_pts = timing.video_pts_ms? as i64;
+        
+        Some(audio_pts - video_pts - timing.sender_offset_ms)
+    }
+    
+    // The worst jitter of anyone's voice and video
+    pub fn max_jitter_ms(&self) -> Option<f64> {
+        self.users
+            .values()
+            .flat_map(|t| [t.voice_jitter.jitter_ms(), t.video_jitter.jitter_ms()])
+            .reduce(f64::max)
+    }
+    
+    // Log when a user's voice and video drift apart, and when they come back together
+    fn check_drift(&mut self, user_id: Uuid) {
+        let drift = match self.drift_ms(user_id) {
+            Some(drift) => drift,
+            None => return,
+        };
+        
+        if let Some(timing) = self.users.get_mut(&user_id) {
+            let drifting = drift.abs() > MAX_AV_DRIFT_MS;
+            if drifting && !timing.drifting {
+                warn!("Voice and video from {} are {}ms out of sync", user_id, drift);
+            } else if !drifting && timing.drifting {
+                info!("Voice and video from {} are back in sync", user_id);
+            }
+            timing.drifting = drifting;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn jitter_after(frames: &[(u64, u64)]) -> f64 {
+        let mut estimator = JitterEstimator::default();
+        for &(captured_at_ms, arrived_at_ms) in frames {
+            estimator.update(captured_at_ms, arrived_at_ms);
+        }
+        estimator.jitter_ms()
+    }
+    
+    #[test]
+    fn frames_arriving_as_they_were_sent_have_no_jitter() {
+        // However long they take, as long as it's always the same
+        let frames: Vec<(u64, u64)> = (0..50).map(|i| (1_000 + i * 20, 5_000 + i * 20)).collect();
+        assert_eq!(jitter_after(&frames), 0.0);
+        assert_eq!(jitter_after(&frames[..1]), 0.0);
+    }
+    
+    #[test]
+    fn jitter_follows_rfc_3550() {
+        // Transit differences of 0, +10, -10 and 0ms: J += (|D| - J) / 16 for each
+        let frames = [(0, 100), (20, 120), (40, 150), (60, 160), (80, 180)];
+        let mut expected = 0.0;
+        for d in [0.0, 10.0, 10.0, 0.0] {
+            expected += (f64::abs(d) - expected) / 16.0;
+        }
+        assert_eq!(expected, 1.13525390625);
+        assert!((jitter_after(&frames) - expected).abs() < 1e-9);
+        
+        // Frames arriving out of order count the same as late ones
+        let reordered = [(0, 100), (40, 130), (20, 135)];
+        assert!((jitter_after(&reordered) - (10.0 / 16.0 + (25.0 - 10.0 / 16.0) / 16.0)).abs() < 1e-9);
+    }
+    
+    #[test]
+    fn steady_jitter_settles_on_the_transit_difference() {
+        // Every other frame 5ms late
+        let frames: Vec<(u64, u64)> = (0..500).map(|i| (i * 20, i * 20 + 5 * (i % 2))).collect();
+        assert!((jitter_after(&frames) - 5.0).abs() < 0.01);
+    }
+    
+    #[test]
+    fn worst_jitter_is_of_anyones_voice_or_video() {
+        let mut timing = MediaTiming::new();
+        assert_eq!(timing.max_jitter_ms(), None);
+        
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        timing.users.entry(alice).or_default().voice_jitter.update(0, 0);
+        timing.users.entry(alice).or_default().voice_jitter.update(20, 36);
+        timing.users.entry(bob).or_default().video_jitter.update(0, 0);
+        timing.users.entry(bob).or_default().video_jitter.update(33, 65);
+        assert_eq!(timing.max_jitter_ms(), Some(2.0));
+    }
+}
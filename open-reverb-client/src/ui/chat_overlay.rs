@@ -0,0 +1,270 @@
+use egui::{Align2, CursorIcon, Frame, Id, Rect, ScrollArea, Sense, Stroke, Window};
+
+use crate::ui::chat_panel::{self, ChatPanel};
+use crate::ui::style;
+
+// Messages shown in the overlay; the full history is in the chat panel
+const VISIBLE_MESSAGES: usize = 5;
+
+const OVERLAY_WIDTH: f32 = 320.0;
+const DEFAULT_HEIGHT: f32 = 180.0;
+const MIN_HEIGHT: f32 = 100.0;
+const MAX_HEIGHT: f32 = 500.0;
+
+// Height of the strip along the top edge that is dragged to resize
+const RESIZE_HANDLE_HEIGHT: f32 = 6.0;
+
+// Opacity when the pointer is elsewhere, and how close it has to come to bring the overlay back
+const IDLE_OPACITY: f32 = 0.3;
+const HOVER_DISTANCE: f32 = 20.0;
+const FADE_SECONDS: f32 = 0.25;
+
+// Gap between the overlay and the edges of the video area
+const MARGIN: f32 = 10.0;
+
+// Chat shown on top of the video area, so the call stays in view while typing
+pub struct ChatOverlay {
+    open: bool,
+    height: f32,
+    // Where the overlay was drawn last frame, for the hover check
+    rect: Option<Rect>,
+}
+
+impl ChatOverlay {
+    pub fn new() -> Self {
+        Self {
+            open: false,
+            height: DEFAULT_HEIGHT,
+            rect: None,
+        }
+    }
+    
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+    
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        self.rect = None;
+    }
+    
+    // Draws the overlay in the bottom-right corner of `area`. Returns the message to send, if one was submitted.
+    pub fn show(&mut self, ctx: &egui::Context, chat: &mut ChatPanel, area: Rect) -> Option<String> {
+        if !self.open {
+            return None;
+        }
+        
+        let hovered = match (self.rect, ctx.pointer_hover_pos()) {
+            (Some(rect), Some(pos)) => rect.distance_to_pos(pos) <= HOVER_DISTANCE,
+            _ => false,
+        };
+        let opacity = ctx.animate_value_with_time(
+            Id::new("chat_overlay_opacity"),
+            target_opacity(hovered, chat.is_typing()),
+            FADE_SECONDS,
+        );
+        
+        let frame = Frame::window(&ctx.style())
+            .fill(style::SECONDARY_BACKGROUND.gamma_multiply(0.85 * opacity))
+            .stroke(Stroke::new(1.0, style::SECONDARY_TEXT_COLOR.gamma_multiply(0.3 * opacity)));
+        
+        let mut sent = None;
+        let response = Window::new("In-call chat")
+            .title_bar(false)
+            .resizable(false)
+            .frame(frame)
+            .pivot(Align2::RIGHT_BOTTOM)
+            .fixed_pos(area.right_bottom() - egui::vec2(MARGIN, MARGIN))
+            .fixed_size(egui::vec2(OVERLAY_WIDTH, self.height))
+            .show(ctx, |ui| {
+                self.resize_handle(ui, opacity);
+                
+                ScrollArea::vertical()
+                    .id_source("chat_overlay_messages")
+                    .max_height(self.height - 40.0)
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        ui.set_min_width(ui.available_width());
+                        for message in chat.recent(VISIBLE_MESSAGES) {
+                            chat_panel::render_message(ui, message, opacity);
+                        }
+                    });
+                
+                sent = chat.input(ui, "chat_overlay_input");
+            });
+        
+        self.rect = response.map(|r| r.response.rect);
+        
+        sent
+    }
+    
+    // Dragging the top edge makes the overlay taller or shorter; its bottom stays put
+    fn resize_handle(&mut self, ui: &mut egui::Ui, opacity: f32) {
+        let (rect, response) = ui.allocate_exact_size(
+            egui::vec2(ui.available_width(), RESIZE_HANDLE_HEIGHT),
+            Sense::drag(),
+        );
+        
+        if response.dragged() {
+            self.height = (self.height - response.drag_delta().y).clamp(MIN_HEIGHT, MAX_HEIGHT);
+        }
+        
+        if response.hovered() || response.dragged() {
+            ui.ctx().set_cursor_icon(CursorIcon::ResizeVertical);
+        }
+        
+        ui.painter().hline(
+            rect.center().x - 15.0..=rect.center().x + 15.0,
+            rect.center().y,
+            Stroke::new(2.0, style::SECONDARY_TEXT_COLOR.gamma_multiply(opacity)),
+        );
+    }
+}
+
+// Fully visible while it's in use, faded otherwise
+fn target_opacity(hovered: bool, typing: bool) -> f32 {
+    if hovered || typing {
+        1.0
+    } else {
+        IDLE_OPACITY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::{Event, Key, Modifiers, Pos2, PointerButton, RawInput, Shape};
+    
+    const AREA: Rect = Rect {
+        min: Pos2 { x: 0.0, y: 0.0 },
+        max: Pos2 { x: 800.0, y: 600.0 },
+    };
+    
+    // Runs one frame at `time`, returning what the overlay sent and where each piece of text was drawn
+    fn frame(
+        ctx: &egui::Context,
+        overlay: &mut ChatOverlay,
+        chat: &mut ChatPanel,
+        time: f64,
+        events: Vec<Event>,
+    ) -> (Option<String>, Vec<(String, Pos2)>) {
+        fn collect(shape: &Shape, text: &mut Vec<(String, Pos2)>) {
+            match shape {
+                Shape::Text(shape) => text.push((shape.galley.text().to_string(), shape.pos)),
+                Shape::Vec(shapes) => shapes.iter().for_each(|shape| collect(shape, text)),
+                _ => {}
+            }
+        }
+        
+        let input = RawInput {
+            screen_rect: Some(AREA),
+            time: Some(time),
+            events,
+            ..Default::default()
+        };
+        let mut sent = None;
+        let output = ctx.run(input, |ctx| {
+            sent = overlay.show(ctx, chat, AREA);
+        });
+        
+        let mut text = Vec::new();
+        for shape in &output.shapes {
+            collect(&shape.shape, &mut text);
+        }
+        (sent, text)
+    }
+    
+    fn opacity(ctx: &egui::Context, target: f32) -> f32 {
+        ctx.animate_value_with_time(Id::new("chat_overlay_opacity"), target, FADE_SECONDS)
+    }
+    
+    fn click(pos: Pos2) -> Vec<Event> {
+        vec![
+            Event::PointerMoved(pos),
+            Event::PointerButton { pos, button: PointerButton::Primary, pressed: true, modifiers: Modifiers::NONE },
+            Event::PointerButton { pos, button: PointerButton::Primary, pressed: false, modifiers: Modifiers::NONE },
+        ]
+    }
+    
+    #[test]
+    fn target_opacity_is_full_while_in_use() {
+        assert_eq!(target_opacity(false, false), IDLE_OPACITY);
+        assert_eq!(target_opacity(true, false), 1.0);
+        assert_eq!(target_opacity(false, true), 1.0);
+        assert_eq!(target_opacity(true, true), 1.0);
+    }
+    
+    #[test]
+    fn closed_overlay_draws_nothing() {
+        let ctx = egui::Context::default();
+        let mut overlay = ChatOverlay::new();
+        let mut chat = ChatPanel::new();
+        
+        let (sent, text) = frame(&ctx, &mut overlay, &mut chat, 0.0, Vec::new());
+        
+        assert!(!overlay.is_open());
+        assert_eq!(sent, None);
+        assert!(text.is_empty());
+    }
+    
+    #[test]
+    fn fades_in_on_hover_and_out_when_the_pointer_leaves() {
+        let ctx = egui::Context::default();
+        let mut overlay = ChatOverlay::new();
+        let mut chat = ChatPanel::new();
+        overlay.toggle();
+        
+        frame(&ctx, &mut overlay, &mut chat, 0.0, Vec::new());
+        assert_eq!(opacity(&ctx, IDLE_OPACITY), IDLE_OPACITY);
+        
+        // Pointer over the overlay starts the fade in, which takes FADE_SECONDS
+        let center = overlay.rect.expect("overlay was drawn").center();
+        frame(&ctx, &mut overlay, &mut chat, 1.0, vec![Event::PointerMoved(center)]);
+        frame(&ctx, &mut overlay, &mut chat, 1.1, Vec::new());
+        let halfway = opacity(&ctx, 1.0);
+        assert!(halfway > IDLE_OPACITY && halfway < 1.0, "opacity {halfway}");
+        
+        frame(&ctx, &mut overlay, &mut chat, 1.5, Vec::new());
+        assert_eq!(opacity(&ctx, 1.0), 1.0);
+        
+        // And back out once the pointer is gone
+        frame(&ctx, &mut overlay, &mut chat, 2.0, vec![Event::PointerGone]);
+        frame(&ctx, &mut overlay, &mut chat, 2.1, Vec::new());
+        let halfway = opacity(&ctx, IDLE_OPACITY);
+        assert!(halfway > IDLE_OPACITY && halfway < 1.0, "opacity {halfway}");
+        
+        frame(&ctx, &mut overlay, &mut chat, 2.5, Vec::new());
+        assert_eq!(opacity(&ctx, IDLE_OPACITY), IDLE_OPACITY);
+    }
+    
+    #[test]
+    fn forwards_typed_messages_and_stays_visible_while_typing() {
+        let ctx = egui::Context::default();
+        let mut overlay = ChatOverlay::new();
+        let mut chat = ChatPanel::new();
+        overlay.toggle();
+        
+        // Windows are sized on their first frame and drawn from the second
+        frame(&ctx, &mut overlay, &mut chat, 0.0, Vec::new());
+        let (_, text) = frame(&ctx, &mut overlay, &mut chat, 0.05, Vec::new());
+        let input = text.iter().find(|(t, _)| t == "Message").expect("input hint is drawn").1;
+        
+        // Focus the input, then move the pointer away; typing keeps the overlay opaque
+        frame(&ctx, &mut overlay, &mut chat, 0.1, click(input + egui::vec2(2.0, 2.0)));
+        frame(&ctx, &mut overlay, &mut chat, 0.2, vec![Event::PointerGone]);
+        assert!(chat.is_typing());
+        frame(&ctx, &mut overlay, &mut chat, 1.0, Vec::new());
+        assert_eq!(opacity(&ctx, 1.0), 1.0);
+        
+        let (sent, _) = frame(&ctx, &mut overlay, &mut chat, 1.1, vec![Event::Text("  hello ".to_string())]);
+        assert_eq!(sent, None);
+        
+        let enter = Event::Key { key: Key::Enter, pressed: true, repeat: false, modifiers: Modifiers::NONE };
+        let (sent, _) = frame(&ctx, &mut overlay, &mut chat, 1.2, vec![enter.clone()]);
+        assert_eq!(sent.as_deref(), Some("hello"));
+        
+        // The input is emptied, and nothing is sent for a blank one
+        let (sent, _) = frame(&ctx, &mut overlay, &mut chat, 1.3, vec![enter]);
+        assert_eq!(sent, None);
+    }
+}
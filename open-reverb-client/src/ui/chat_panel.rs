@@ -0,0 +1,223 @@
+use egui::{Align, Frame, Key, Response, RichText, ScrollArea, TextEdit, Ui};
+use uuid::Uuid;
+
+use crate::ui::message_formatter::MessageFormatter;
+use crate::ui::style;
+
+// Oldest messages are dropped past this many
+const MAX_CHAT_MESSAGES: usize = 500;
+
+pub struct ChatMessage {
+    pub message_id: Uuid,
+    // Nil for messages from the server itself
+    pub user_id: Uuid,
+    pub username: String,
+    pub content: String,
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+// Text chat for the current channel. Shared by the channel view and the in-call overlay.
+pub struct ChatPanel {
+    messages: Vec<ChatMessage>,
+    draft: String,
+    // Whether the input had keyboard focus last frame
+    typing: bool,
+    // Message picked from search results, highlighted until another is picked
+    highlighted: Option<Uuid>,
+    // Set until the highlighted message has been scrolled into view
+    scroll_pending: bool,
+}
+
+impl ChatPanel {
+    pub fn new() -> Self {
+        Self {
+            messages: Vec::new(),
+            draft: String::new(),
+            typing: false,
+            highlighted: None,
+            scroll_pending: false,
+        }
+    }
+    
+    // Add a message, skipping ones already shown (such as the recent chat sent again when
+    // rejoining) and putting any that arrive late in timestamp order. Messages from the server
+    // itself stay at the top.
+    pub fn push(&mut self, message: ChatMessage) {
+        if self.messages.iter().any(|m| m.message_id == message.message_id) {
+            return;
+        }
+        
+        let index = self.messages.partition_point(|m| m.user_id.is_nil() || m.timestamp <= message.timestamp);
+        self.messages.insert(index, message);
+        if self.messages.len() > MAX_CHAT_MESSAGES {
+            self.messages.remove(0);
+        }
+    }
+    
+    pub fn clear(&mut self) {
+        self.messages.clear();
+        self.draft.clear();
+        self.highlighted = None;
+    }
+    
+    // Drop messages sent before `timestamp`, once the server has deleted them
+    pub fn remove_before(&mut self, timestamp: u64) {
+        self.messages.retain(|m| m.timestamp >= timestamp);
+        if let Some(highlighted) = self.highlighted {
+            if !self.messages.iter().any(|m| m.message_id == highlighted) {
+                self.highlighted = None;
+            }
+        }
+    }
+    
+    // Scroll to and highlight a message. Returns false if it isn't in the history.
+    pub fn scroll_to(&mut self, message_id: Uuid) -> bool {
+        if !self.messages.iter().any(|m| m.message_id == message_id) {
+            return false;
+        }
+        
+        self.highlighted = Some(message_id);
+        self.scroll_pending = true;
+        true
+    }
+    
+    pub fn is_typing(&self) -> bool {
+        self.typing
+    }
+    
+    // The last `count` messages, oldest first
+    pub fn recent(&self, count: usize) -> &[ChatMessage] {
+        &self.messages[self.messages.len().saturating_sub(count)..]
+    }
+    
+    // The full history with an input below it. Returns the message to send, if one was submitted.
+    pub fn ui(&mut self, ui: &mut Ui) -> Option<String> {
+        ui.heading(style::subheading("Chat"));
+        
+        ScrollArea::vertical()
+            .id_source("chat_history")
+            .max_height(200.0)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                if self.messages.is_empty() {
+                    ui.label(style::secondary_text("No messages yet"));
+                }
+                
+                for message in &self.messages {
+                    if self.highlighted != Some(message.message_id) {
+                        render_message(ui, message, 1.0);
+                        continue;
+                    }
+                    
+                    let response = Frame::none()
+                        .fill(style::ACCENT_COLOR.gamma_multiply(0.3))
+                        .show(ui, |ui| render_message(ui, message, 1.0))
+                        .response;
+                    
+                    if self.scroll_pending {
+                        response.scroll_to_me(Some(Align::Center));
+                        self.scroll_pending = false;
+                    }
+                }
+            });
+        
+        self.input(ui, "chat_input")
+    }
+    
+    // Text input with a send button. Enter sends too.
+    pub fn input(&mut self, ui: &mut Ui, id: &str) -> Option<String> {
+        let mut submitted = false;
+        
+        ui.horizontal(|ui| {
+            let response = ui.add(
+                TextEdit::singleline(&mut self.draft)
+                    .id_source(id)
+                    .hint_text("Message")
+                    .desired_width(ui.available_width() - 60.0),
+            );
+            
+            self.typing = response.has_focus();
+            
+            if response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter)) {
+                submitted = true;
+                response.request_focus();
+            }
+            
+            if ui.button("Send").clicked() {
+                submitted = true;
+            }
+        });
+        
+        if submitted {
+            self.take_draft()
+        } else {
+            None
+        }
+    }
+    
+    // Empties the input, returning what was typed unless it was blank
+    fn take_draft(&mut self) -> Option<String> {
+        let content = std::mem::take(&mut self.draft).trim().to_string();
+        (!content.is_empty()).then_some(content)
+    }
+}
+
+// `opacity` fades the text, for the overlay
+pub fn render_message(ui: &mut Ui, message: &ChatMessage, opacity: f32) -> Response {
+    let time = chrono::DateTime::from_timestamp(message.timestamp as i64, 0)
+        .map(|t| t.with_timezone(&chrono::Local).format("%H:%M").to_string())
+        .unwrap_or_default();
+    
+    let from_server = message.user_id.is_nil();
+    let render = |ui: &mut Ui| {
+        ui.horizontal_wrapped(|ui| {
+            ui.label(style::secondary_text(&time).color(style::SECONDARY_TEXT_COLOR.gamma_multiply(opacity)));
+            let username = if from_server { "🤖 Server" } else { message.username.as_str() };
+            ui.label(RichText::new(username).strong().color(style::TEXT_COLOR.gamma_multiply(opacity)));
+            ui.visuals_mut().override_text_color = Some(style::TEXT_COLOR.gamma_multiply(opacity));
+            MessageFormatter::render(ui, &message.content);
+        })
+        .response
+    };
+    
+    if !from_server {
+        return render(ui);
+    }
+    
+    Frame::none()
+        .fill(style::SUCCESS_COLOR.gamma_multiply(0.25 * opacity))
+        .rounding(4.0)
+        .inner_margin(4.0)
+        .show(ui, render)
+        .response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn message(id: u128, user_id: Uuid, timestamp: u64) -> ChatMessage {
+        ChatMessage {
+            message_id: Uuid::from_u128(id),
+            user_id,
+            username: "alice".to_string(),
+            content: format!("message {}", id),
+            timestamp,
+        }
+    }
+    
+    #[test]
+    fn messages_are_ordered_and_not_repeated() {
+        let mut chat = ChatPanel::new();
+        let alice = Uuid::from_u128(100);
+        
+        chat.push(message(1, Uuid::nil(), 500));
+        chat.push(message(2, alice, 20));
+        chat.push(message(3, alice, 10));
+        chat.push(message(2, alice, 20));
+        
+        let ids: Vec<u128> = chat.messages.iter().map(|m| m.message_id.as_u128()).collect();
+        assert_eq!(ids, vec![1, 3, 2]);
+    }
+}
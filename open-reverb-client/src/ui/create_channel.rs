@@ -0,0 +1,148 @@
+use egui::{ComboBox, Slider, TextEdit, Window};
+use uuid::Uuid;
+
+use open_reverb_common::models::{AudioCodec, AudioQuality, ChannelType, Server};
+use crate::ui::style;
+
+// A channel the user filled in the dialog for
+pub struct NewChannel {
+    pub name: String,
+    pub description: Option<String>,
+    pub parent_id: Option<Uuid>,
+    pub channel_type: ChannelType,
+    // None uses the server's default
+    pub audio_quality: Option<AudioQuality>,
+    // Kept even when it's been idle past the server's timeout
+    pub permanent: bool,
+}
+
+pub struct CreateChannelDialog {
+    open: bool,
+    name: String,
+    description: String,
+    parent_id: Option<Uuid>,
+    channel_type: ChannelType,
+    custom_quality: bool,
+    quality: AudioQuality,
+    permanent: bool,
+}
+
+impl CreateChannelDialog {
+    pub fn new() -> Self {
+        Self {
+            open: false,
+            name: String::new(),
+            description: String::new(),
+            parent_id: None,
+            channel_type: ChannelType::Voice,
+            custom_quality: false,
+            quality: AudioQuality::default(),
+            permanent: false,
+        }
+    }
+    
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+    
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+    }
+    
+    // Only admins are offered permanent channels; the server refuses them from anyone else
+    pub fn show(&mut self, ctx: &egui::Context, server: Option<&Server>, is_admin: bool) -> Option<NewChannel> {
+        let mut created = None;
+        let mut open = self.open;
+        
+        Window::new("Create Channel")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.add(TextEdit::singleline(&mut self.name).hint_text("Name"));
+                ui.add(TextEdit::singleline(&mut self.description).hint_text("Description (optional)"));
+                
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.channel_type, ChannelType::Voice, "🔊 Voice");
+                    ui.radio_value(&mut self.channel_type, ChannelType::Text, "💬 Text");
+                });
+                
+                let parent_name = server
+                    .and_then(|s| s.channels.iter().find(|c| Some(c.id) == self.parent_id))
+                    .map_or("None", |c| c.name.as_str());
+                ComboBox::from_label("Parent channel")
+                    .selected_text(parent_name)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.parent_id, None, "None");
+                        for channel in server.map(|s| s.channels.as_slice()).unwrap_or_default() {
+                            ui.selectable_value(&mut self.parent_id, Some(channel.id), &channel.name);
+                        }
+                    });
+                
+                if self.channel_type == ChannelType::Voice {
+                    ui.separator();
+                    ui.checkbox(&mut self.custom_quality, "Custom audio quality")
+                        .on_hover_text("Otherwise the server's default is used");
+                    
+                    if self.custom_quality {
+                        self.render_quality(ui);
+                    }
+                }
+                
+                if is_admin {
+                    ui.separator();
+                    ui.checkbox(&mut self.permanent, "Keep when idle")
+                        .on_hover_text("Otherwise the server may delete the channel once it's been empty for a while");
+                }
+                
+                ui.separator();
+                let name = self.name.trim();
+                if ui.add_enabled(!name.is_empty(), egui::Button::new("Create")).clicked() {
+                    let description = self.description.trim();
+                    created = Some(NewChannel {
+                        name: name.to_string(),
+                        description: (!description.is_empty()).then(|| description.to_string()),
+                        parent_id: self.parent_id,
+                        channel_type: self.channel_type,
+                        audio_quality: (self.custom_quality && self.channel_type == ChannelType::Voice).then_some(self.quality),
+                        permanent: is_admin && self.permanent,
+                    });
+                }
+            });
+        
+        if created.is_some() {
+            *self = Self::new();
+        } else {
+            self.open = open;
+        }
+        
+        created
+    }
+    
+    fn render_quality(&mut self, ui: &mut egui::Ui) {
+        ComboBox::from_label("Codec")
+            .selected_text(self.quality.codec.label())
+            .show_ui(ui, |ui| {
+                for codec in AudioCodec::ALL {
+                    ui.selectable_value(&mut self.quality.codec, codec, codec.label());
+                }
+            });
+        
+        ComboBox::from_label("Sample rate")
+            .selected_text(format!("{} kHz", self.quality.sample_rate / 1000))
+            .show_ui(ui, |ui| {
+                for rate in AudioQuality::SAMPLE_RATES {
+                    ui.selectable_value(&mut self.quality.sample_rate, rate, format!("{} kHz", rate / 1000));
+                }
+            });
+        
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.quality.channels, 1, "Mono");
+            ui.radio_value(&mut self.quality.channels, 2, "Stereo");
+        });
+        
+        ui.add(Slider::new(&mut self.quality.bitrate_kbps, 6..=510).text("kbps"));
+        if self.quality.codec == AudioCodec::PCM {
+            ui.label(style::secondary_text("PCM isn't compressed, so the bitrate doesn't apply"));
+        }
+    }
+}
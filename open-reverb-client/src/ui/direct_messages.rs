@@ -0,0 +1,172 @@
+use egui::{Button, RichText, ScrollArea, Ui};
+use uuid::Uuid;
+
+use crate::ui::chat_panel::{self, ChatMessage};
+
+// Oldest messages in a conversation are dropped past this many
+const MAX_CONVERSATION_MESSAGES: usize = 200;
+
+struct Conversation {
+    user_id: Uuid,
+    username: String,
+    messages: Vec<ChatMessage>,
+    unread: usize,
+}
+
+// Private conversations with other users, listed in the sidebar below the channels, most recent
+// first. Only kept for the session; the server doesn't store direct messages once delivered.
+pub struct DirectMessages {
+    conversations: Vec<Conversation>,
+    // The conversation open in its own window, and the draft being written in it
+    open: Option<(Uuid, String)>,
+}
+
+impl DirectMessages {
+    pub fn new() -> Self {
+        Self { conversations: Vec::new(), open: None }
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.conversations.is_empty()
+    }
+    
+    pub fn is_open_with(&self, user_id: Uuid) -> bool {
+        self.open.as_ref().is_some_and(|(open, _)| *open == user_id)
+    }
+    
+    // Start or go back to a conversation
+    pub fn open(&mut self, user_id: Uuid, username: &str) {
+        self.conversation(user_id, username).unread = 0;
+        if !self.is_open_with(user_id) {
+            self.open = Some((user_id, String::new()));
+        }
+    }
+    
+    pub fn receive(&mut self, from_user_id: Uuid, username: &str, content: String, timestamp: u64) {
+        let open = self.is_open_with(from_user_id);
+        let conversation = self.conversation(from_user_id, username);
+        if !open {
+            conversation.unread += 1;
+        }
+        let username = conversation.username.clone();
+        push(conversation, ChatMessage { message_id: Uuid::new_v4(), user_id: from_user_id, username, content, timestamp });
+    }
+    
+    // The server doesn't send our own messages back, so they're added as they're sent
+    pub fn sent(&mut self, our_user_id: Uuid, to_user_id: Uuid, username: &str, content: String, timestamp: u64) {
+        let conversation = self.conversation(to_user_id, username);
+        push(conversation, ChatMessage { message_id: Uuid::new_v4(), user_id: our_user_id, username: "You".to_string(), content, timestamp });
+    }
+    
+    // The conversation with a user, moved to the top of the list
+    fn conversation(&mut self, user_id: Uuid, username: &str) -> &mut Conversation {
+        let conversation = match self.conversations.iter().position(|c| c.user_id == user_id) {
+            Some(index) => self.conversations.remove(index),
+            None => Conversation { user_id, username: username.to_string(), messages: Vec::new(), unread: 0 },
+        };
+        self.conversations.insert(0, conversation);
+        &mut self.conversations[0]
+    }
+    
+    // Returns the user whose conversation was clicked
+    pub fn render_list(&self, ui: &mut Ui) -> Option<(Uuid, String)> {
+        let mut clicked = None;
+        for conversation in &self.conversations {
+            let label = if conversation.unread > 0 {
+                RichText::new(format!("💬 {} ({})", conversation.username, conversation.unread)).strong()
+            } else {
+                RichText::new(format!("💬 {}", conversation.username))
+            };
+            
+            if ui.selectable_label(self.is_open_with(conversation.user_id), label).clicked() {
+                clicked = Some((conversation.user_id, conversation.username.clone()));
+            }
+        }
+        clicked
+    }
+    
+    // Returns the recipient and message once Send is clicked
+    pub fn show_window(&mut self, ctx: &egui::Context) -> Option<(Uuid, String)> {
+        let (user_id, draft) = self.open.as_mut()?;
+        let user_id = *user_id;
+        let conversation = self.conversations.iter().find(|c| c.user_id == user_id);
+        let username = conversation.map_or("Unknown user", |c| c.username.as_str());
+        
+        let mut open = true;
+        let mut send = false;
+        egui::Window::new(format!("Message {}", username))
+            .id(egui::Id::new("direct_message"))
+            .open(&mut open)
+            .collapsible(false)
+            .default_width(320.0)
+            .show(ctx, |ui| {
+                ScrollArea::vertical().max_height(240.0).stick_to_bottom(true).show(ui, |ui| {
+                    for message in conversation.iter().flat_map(|c| &c.messages) {
+                        chat_panel::render_message(ui, message, 1.0);
+                    }
+                });
+                ui.separator();
+                
+                let response = ui.add(egui::TextEdit::singleline(draft).hint_text("Message"));
+                let entered = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                let clicked = ui.add_enabled(!draft.trim().is_empty(), Button::new("Send")).clicked();
+                send = (clicked || entered) && !draft.trim().is_empty();
+                if entered {
+                    response.request_focus();
+                }
+            });
+        
+        if !open {
+            self.open = None;
+            return None;
+        }
+        if !send {
+            return None;
+        }
+        
+        let content = std::mem::take(draft).trim().to_string();
+        Some((user_id, content))
+    }
+}
+
+fn push(conversation: &mut Conversation, message: ChatMessage) {
+    conversation.messages.push(message);
+    if conversation.messages.len() > MAX_CONVERSATION_MESSAGES {
+        conversation.messages.remove(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn unread_until_opened() {
+        let mut dms = DirectMessages::new();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        
+        dms.receive(alice, "alice", "hi".to_string(), 1);
+        dms.receive(alice, "alice", "you there?".to_string(), 2);
+        dms.receive(bob, "bob", "hey".to_string(), 3);
+        assert_eq!(dms.conversations[1].unread, 2);
+        
+        dms.open(alice, "alice");
+        dms.receive(alice, "alice", "ok".to_string(), 4);
+        assert_eq!(dms.conversations[0].unread, 0);
+        assert_eq!(dms.conversations[0].messages.len(), 3);
+    }
+    
+    #[test]
+    fn most_recent_conversation_first() {
+        let mut dms = DirectMessages::new();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        
+        dms.receive(alice, "alice", "hi".to_string(), 1);
+        dms.receive(bob, "bob", "hey".to_string(), 2);
+        dms.sent(Uuid::from_u128(3), alice, "alice", "hello".to_string(), 3);
+        
+        let order: Vec<Uuid> = dms.conversations.iter().map(|c| c.user_id).collect();
+        assert_eq!(order, vec![alice, bob]);
+        assert_eq!(dms.conversations[0].messages[1].username, "You");
+    }
+}
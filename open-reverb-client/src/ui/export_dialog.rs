@@ -0,0 +1,111 @@
+use chrono::{Days, NaiveDate, NaiveTime, Utc};
+use egui::Window;
+use egui_extras::DatePickerButton;
+use uuid::Uuid;
+
+use open_reverb_common::models::ExportFormat;
+use crate::ui::style;
+
+// The history an admin asked to export. Timestamps are seconds since the Unix epoch, both
+// inclusive.
+pub struct ExportRequest {
+    pub channel_id: Uuid,
+    pub start_ts: u64,
+    pub end_ts: u64,
+    pub format: ExportFormat,
+}
+
+pub struct ExportDialog {
+    open: bool,
+    channel_id: Uuid,
+    channel_name: String,
+    from: NaiveDate,
+    to: NaiveDate,
+    format: ExportFormat,
+}
+
+impl ExportDialog {
+    pub fn new() -> Self {
+        let today = Utc::now().date_naive();
+        
+        Self {
+            open: false,
+            channel_id: Uuid::nil(),
+            channel_name: String::new(),
+            from: today.checked_sub_days(Days::new(30)).unwrap_or(today),
+            to: today,
+            format: ExportFormat::JSON,
+        }
+    }
+    
+    // Open the dialog for a channel, keeping the last range and format chosen
+    pub fn open_for(&mut self, channel_id: Uuid, channel_name: &str) {
+        self.open = true;
+        self.channel_id = channel_id;
+        self.channel_name = channel_name.to_string();
+    }
+    
+    pub fn show(&mut self, ctx: &egui::Context) -> Option<ExportRequest> {
+        let mut requested = None;
+        let mut open = self.open;
+        
+        Window::new(format!("Export History: {}", self.channel_name))
+            .id(egui::Id::new("export_dialog"))
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("From");
+                    ui.add(DatePickerButton::new(&mut self.from).id_source("export_from"));
+                    ui.label("to");
+                    ui.add(DatePickerButton::new(&mut self.to).id_source("export_to"));
+                });
+                
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.format, ExportFormat::JSON, "JSON");
+                    ui.radio_value(&mut self.format, ExportFormat::CSV, "CSV");
+                });
+                
+                ui.label(style::secondary_text("Dates are in UTC. Only messages the server still has are exported."));
+                
+                ui.separator();
+                let valid = self.from <= self.to;
+                if !valid {
+                    ui.label(style::secondary_text("The range ends before it starts"));
+                }
+                if ui.add_enabled(valid, egui::Button::new("Export")).clicked() {
+                    let (start_ts, end_ts) = day_range(self.from, self.to);
+                    requested = Some(ExportRequest {
+                        channel_id: self.channel_id,
+                        start_ts,
+                        end_ts,
+                        format: self.format,
+                    });
+                }
+            });
+        
+        self.open = open && requested.is_none();
+        
+        requested
+    }
+}
+
+// From the start of the first day to the end of the last, in UTC
+fn day_range(from: NaiveDate, to: NaiveDate) -> (u64, u64) {
+    let start = from.and_time(NaiveTime::MIN).and_utc().timestamp();
+    let end = to.and_time(NaiveTime::MIN).and_utc().timestamp() + 24 * 60 * 60 - 1;
+    
+    (start.max(0) as u64, end.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn range_covers_whole_days() {
+        let day = NaiveDate::from_ymd_opt(2023, 11, 14).unwrap();
+        
+        assert_eq!(day_range(day, day), (1_699_920_000, 1_700_006_399));
+    }
+}
@@ -0,0 +1,105 @@
+use egui::{RichText, Ui};
+use uuid::Uuid;
+
+use crate::ui::style;
+
+pub struct FileOffer {
+    pub id: Uuid,
+    pub from_username: String,
+    pub filename: String,
+    pub size_bytes: u64,
+    pub mime_type: String,
+}
+
+pub enum FileOfferResponse {
+    Accept(Uuid),
+    Decline(Uuid),
+}
+
+// Files other users want to send us, waiting for an answer. Shown in a window of their own
+// while there are any.
+pub struct FileOffers {
+    offers: Vec<FileOffer>,
+}
+
+impl FileOffers {
+    pub fn new() -> Self {
+        Self { offers: Vec::new() }
+    }
+    
+    pub fn push(&mut self, offer: FileOffer) {
+        self.offers.push(offer);
+    }
+    
+    // The sender gave up, or the server did
+    pub fn remove(&mut self, id: Uuid) {
+        self.offers.retain(|offer| offer.id != id);
+    }
+    
+    pub fn show(&mut self, ctx: &egui::Context) -> Option<FileOfferResponse> {
+        if self.offers.is_empty() {
+            return None;
+        }
+        
+        let mut response = None;
+        egui::Window::new("Incoming Files")
+            .id(egui::Id::new("file_offers"))
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                for offer in &self.offers {
+                    if let Some(answer) = render_offer(ui, offer) {
+                        response = Some(answer);
+                    }
+                    ui.separator();
+                }
+            });
+        
+        if let Some(FileOfferResponse::Accept(id) | FileOfferResponse::Decline(id)) = &response {
+            self.remove(*id);
+        }
+        response
+    }
+}
+
+fn render_offer(ui: &mut Ui, offer: &FileOffer) -> Option<FileOfferResponse> {
+    ui.label(format!("{} wants to send you a file:", offer.from_username));
+    ui.label(RichText::new(&offer.filename).strong());
+    ui.label(style::secondary_text(&format!("{}, {}", format_size(offer.size_bytes), offer.mime_type)));
+    
+    ui.horizontal(|ui| {
+        if ui.button("Accept").clicked() {
+            return Some(FileOfferResponse::Accept(offer.id));
+        }
+        if ui.button("Decline").clicked() {
+            return Some(FileOfferResponse::Decline(offer.id));
+        }
+        None
+    })
+    .inner
+}
+
+pub fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 * 1024 {
+        format!("{:.1} GB", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
+    } else if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    } else if bytes >= 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn sizes_are_readable() {
+        assert_eq!(format_size(512), "512 bytes");
+        assert_eq!(format_size(64 * 1024), "64.0 KB");
+        assert_eq!(format_size(5 * 1024 * 1024 + 512 * 1024), "5.5 MB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+}
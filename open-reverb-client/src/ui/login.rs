@@ -1,109 +1,79 @@
-use egui::{Align, Button, Layout, Ui};
+use crossbeam_channel::{bounded, Receiver};
+use egui::{Button, Grid, RichText, Ui};
+use std::thread;
+use std::time::Duration;
 
 use crate::config::ClientConfig;
+use crate::connection::tester::{ConnectionTester, DiagnosticReport};
 use crate::ui::style;
 
-pub struct LoginScreen {
-    server_url: String,
-    username: String,
-    password: String,
-    remember_credentials: bool,
-    error_message: Option<String>,
-    connecting: bool,
+// A "Test Connection" button, and the results of the last test
+pub struct ConnectionTestPanel {
+    // Set while a test runs in the background
+    running: Option<Receiver<DiagnosticReport>>,
+    report: Option<DiagnosticReport>,
 }
 
-impl LoginScreen {
-    pub fn new(config: &ClientConfig) -> Self {
+impl ConnectionTestPanel {
+    pub fn new() -> Self {
         Self {
-            server_url: config.server_url.clone(),
-            username: config.username.clone().unwrap_or_default(),
-            password: String::new(),
-            remember_credentials: config.remember_credentials,
-            error_message: None,
-            connecting: false,
+            running: None,
+            report: None,
         }
     }
     
-    pub fn ui(&mut self, ui: &mut Ui) -> Option<(String, String, String)> {
-        let mut login_info = None;
+    pub fn ui(&mut self, ui: &mut Ui, server_url: &str, config: &ClientConfig) {
+        if let Some(report) = self.running.as_ref().and_then(|rx| rx.try_recv().ok()) {
+            self.report = Some(report);
+            self.running = None;
+        }
         
-        ui.vertical_centered(|ui| {
-            ui.add_space(50.0);
-            ui.heading(style::heading("Open Reverb"));
-            ui.add_space(20.0);
-            
-            // Server URL
-            ui.label(style::body_text("Server Address:"));
-            ui.text_edit_singleline(&mut self.server_url);
-            ui.add_space(10.0);
-            
-            // Username
-            ui.label(style::body_text("Username:"));
-            ui.text_edit_singleline(&mut self.username);
-            ui.add_space(10.0);
-            
-            // Password
-            ui.label(style::body_text("Password:"));
-            let password_response = ui.add(egui::TextEdit::singleline(&mut self.password).password(true));
-            
-            // Remember credentials
-            ui.checkbox(&mut self.remember_credentials, "Remember credentials");
-            ui.add_space(20.0);
-            
-            // Error message if any
-            if let Some(error) = &self.error_message {
-                ui.label(style::error_text(error));
-                ui.add_space(10.0);
-            }
+        let running = self.running.is_some();
+        let label = if running { "Testing..." } else { "Test Connection" };
+        if ui.add_enabled(!running && !server_url.trim().is_empty(), Button::new(label)).clicked() {
+            let (tx, rx) = bounded(1);
+            let tester = ConnectionTester::new(config);
+            let server_url = server_url.trim().to_string();
             
-            // Connect button
-            let connect_text = if self.connecting { "Connecting..." } else { "Connect" };
-            let connect_button = ui.add_sized(
-                [200.0, 40.0],
-                Button::new(style::body_text(connect_text))
-                    .fill(style::ACCENT_COLOR)
-            );
-            
-            // Handle connect button click or enter key in password field
-            if (connect_button.clicked() || password_response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)))
-                && !self.connecting
-                && !self.username.is_empty()
-                && !self.password.is_empty()
-            {
-                self.connecting = true;
-                self.error_message = None;
-                
-                // Return login info to be processed by the caller
-                login_info = Some((
-                    self.server_url.clone(),
-                    self.username.clone(),
-                    self.password.clone(),
-                ));
-            }
-            
-            ui.with_layout(Layout::bottom_up(Align::Center), |ui| {
-                ui.add_space(10.0);
-                ui.label(style::secondary_text(&format!("Version {}", open_reverb_common::version())));
+            // Steps can wait seconds for an unresponsive server
+            thread::spawn(move || {
+                let _ = tx.send(tester.run_diagnostics(&server_url));
             });
-        });
+            self.running = Some(rx);
+            self.report = None;
+        }
         
-        login_info
-    }
-    
-    pub fn set_error(&mut self, error: String) {
-        self.error_message = Some(error);
-        self.connecting = false;
-    }
-    
-    pub fn is_connecting(&self) -> bool {
-        self.connecting
-    }
-    
-    pub fn get_credentials(&self) -> (String, String, bool) {
-        (self.username.clone(), self.password.clone(), self.remember_credentials)
+        if running {
+            ui.ctx().request_repaint_after(Duration::from_millis(100));
+        }
+        
+        if let Some(report) = &self.report {
+            render_report(ui, report);
+        }
     }
-    
-    pub fn get_server_url(&self) -> &str {
-        &self.server_url
+}
+
+fn render_report(ui: &mut Ui, report: &DiagnosticReport) {
+    ui.add_space(5.0);
+    if report.passed() {
+        ui.label(style::success_text("Everything checked out"));
+    } else {
+        ui.label(style::error_text("Some checks failed"));
     }
-}
\ No newline at end of file
+    Grid::new("connection_test").striped(true).num_columns(4).show(ui, |ui| {
+        for (name, result) in report.steps() {
+            match result {
+                Some(result) if result.pass => ui.label(RichText::new("✔").color(style::SUCCESS_COLOR)),
+                Some(_) => ui.label(RichText::new("✖").color(style::ERROR_COLOR)),
+                None => ui.label(style::secondary_text("–")),
+            };
+            ui.label(style::body_text(name));
+            match result {
+                Some(result) => ui.label(style::body_text(&result.detail)),
+                None => ui.label(style::secondary_text("Not run")),
+            };
+            ui.label(style::secondary_text(&result.and_then(|r| r.latency_ms).map(|ms| format!("{} ms", ms)).unwrap_or_default()));
+            ui.end_row();
+        }
+    });
+}
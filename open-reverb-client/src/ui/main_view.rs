@@ -1,26 +1,233 @@
-use egui::{Button, Color32, Label, RichText, SidePanel, TopBottomPanel, Ui, Vec2};
+use egui::{Button, Color32, ComboBox, Label, RichText, ScrollArea, SidePanel, Slider, TopBottomPanel, Ui, Vec2};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
-use open_reverb_common::models::{Channel, Server, User, UserStatus};
+use open_reverb_common::features::Features;
+use open_reverb_common::models::{AudioQuality, Channel, ChannelType, ExportFormat, SearchResult, Server, ServerStatistics, User, UserStatus};
+use crate::audio::{self, BufferStats};
+use crate::config::BookmarkedChannel;
+use crate::connection::ConnectionQuality;
+use crate::ui::chat_overlay::ChatOverlay;
+use crate::ui::chat_panel::{self, ChatMessage, ChatPanel};
+use crate::ui::create_channel::{CreateChannelDialog, NewChannel};
+use crate::ui::direct_messages::DirectMessages;
+use crate::ui::export_dialog::{ExportDialog, ExportRequest};
+use crate::ui::file_offers::{FileOffer, FileOfferResponse, FileOffers};
+use crate::ui::search_panel::{SearchPanel, SearchPanelAction};
+use crate::ui::server_stats::{self, ServerStatsPanel};
+use crate::ui::speaking_stats::SpeakingStatsPanel;
 use crate::ui::style;
+use crate::ui::widgets::{AudioVisualizerWidget, SkeletonRows, Spinner, WaveformDisplay};
 use crate::video::VideoPlayback;
 
+// How long a toast notification stays on screen
+const TOAST_DURATION: Duration = Duration::from_secs(4);
+
+// Longest channel topic the server accepts, in characters
+const MAX_TOPIC_LENGTH: usize = 256;
+
+// Latency to the server below these is shown as good and acceptable
+const GOOD_LATENCY: Duration = Duration::from_millis(80);
+const FAIR_LATENCY: Duration = Duration::from_millis(200);
+
+// Oldest transcript lines are dropped past this many
+const MAX_TRANSCRIPT_ENTRIES: usize = 200;
+
+// How long typing has to pause before the user search is sent
+const USER_SEARCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+// Voice levels fall by this much each frame, so a user's bar dies away once they stop talking,
+// and are forgotten once they're below MIN_AUDIO_LEVEL
+const AUDIO_LEVEL_DECAY: f32 = 0.95;
+const MIN_AUDIO_LEVEL: f32 = 0.001;
+
+// Size of the voice level bar beside each user, at full scale
+const LEVEL_BAR_SIZE: Vec2 = Vec2::new(40.0, 6.0);
+
+// Channel clicked and whether to join it as a ghost, bookmark change or deletion, and channel to export
+type ChannelListResult = (Option<(Uuid, bool)>, Option<MainViewAction>, Option<(Uuid, String)>);
+
+// Actions taken in the main view that need to be sent to the server
+pub enum MainViewAction {
+    RespondToRecording { accepted: bool },
+    JoinChannel { channel_id: Uuid, ghost: bool },
+    LeaveChannel { channel_id: Uuid },
+    SetChannelTopic { channel_id: Uuid, topic: String },
+    EnableE2E { channel_id: Uuid },
+    CreateChannel {
+        name: String,
+        description: Option<String>,
+        parent_id: Option<Uuid>,
+        channel_type: ChannelType,
+        audio_quality: Option<AudioQuality>,
+        permanent: bool,
+    },
+    DeleteChannel { channel_id: Uuid },
+    SetUserVolume { user_id: Uuid, volume: f32 },
+    SetLobbyMode { channel_id: Uuid, enabled: bool },
+    ToggleBookmark { channel_id: Uuid, display_name: String },
+    RemoveBookmark { channel_id: Uuid },
+    ExportMessages { channel_id: Uuid, start_ts: u64, end_ts: u64, format: ExportFormat },
+    GetSpeakingStats { channel_id: Uuid },
+    GetServerStats,
+    ApprovePending { user_id: Uuid, channel_id: Uuid },
+    RejectPending { user_id: Uuid, channel_id: Uuid },
+    SendTextMessage { channel_id: Uuid, content: String },
+    SendDirectMessage { to_user_id: Uuid, content: String },
+    // Asks which file, then offers it to the user
+    SendFile { to_user_id: Uuid },
+    AcceptFile { id: Uuid },
+    DeclineFile { id: Uuid },
+    SearchMessages { query: String, offset: u32 },
+    SearchUsers { query: String, include_offline: bool },
+    RenewSession { token: String },
+    // Pop a participant's video out of the grid into its own window
+    DetachVideo { user_id: Uuid },
+    ToggleAudio,
+    ToggleVideo,
+    ToggleScreenShare,
+    // Pick part of the screen to share instead of all of it
+    SelectScreenRegion,
+    ToggleRecording,
+    Disconnect,
+}
+
+// Order of the user list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserSort {
+    Name,
+    JoinTime,
+    Status,
+}
+
+impl UserSort {
+    const ALL: [UserSort; 3] = [UserSort::Name, UserSort::JoinTime, UserSort::Status];
+    
+    fn label(self) -> &'static str {
+        match self {
+            UserSort::Name => "Name",
+            UserSort::JoinTime => "Join Time",
+            UserSort::Status => "Status",
+        }
+    }
+}
+
+// Picked from a user's context menu
+enum UserMenuChoice {
+    Message(Uuid),
+    SendFile(Uuid),
+}
+
+struct TranscriptEntry {
+    user_id: Uuid,
+    text: String,
+    timestamp: u64,
+}
+
+// How far through paging in a big server's channels we are
+struct ChannelLoading {
+    received: u32,
+    total: u32,
+}
+
 pub struct MainView {
     current_user_id: Option<Uuid>,
     current_channel_id: Option<Uuid>,
+    // Channel we've asked to join and are waiting on the server to confirm
+    joining_channel_id: Option<Uuid>,
+    // Set while that channel's lobby has us waiting for an admin
+    awaiting_approval: bool,
     server_info: Option<Server>,
+    // Set while a big server's channels are still being paged in
+    channel_loading: Option<ChannelLoading>,
+    // Set while waiting for a big server's list of who is online
+    users_loading: bool,
+    // Favourite channels on this server, from the config
+    bookmarks: Vec<BookmarkedChannel>,
     
     // Audio state for visualization
     audio_levels: std::collections::HashMap<Uuid, f32>,
+    audio_visualizers: std::collections::HashMap<Uuid, AudioVisualizerWidget>,
     audio_active: bool,
     video_active: bool,
     screen_share_active: bool,
+    recording_active: bool,
+    // Our microphone, shown while we're recording
+    waveform: WaveformDisplay,
+    
+    // Video playback, shared with the app which feeds it frames
+    video_playback: Option<Arc<Mutex<VideoPlayback>>>,
+    // Users whose video has been popped out, and so isn't shown in the grid
+    detached_users: Vec<Uuid>,
+    // Users whose video is paused by privacy mode
+    paused_video: HashSet<Uuid>,
+    
+    // Transcribed voice for the current channel
+    transcripts: Vec<TranscriptEntry>,
+    transcription_enabled: bool,
     
-    // Video playback
-    video_playback: Option<VideoPlayback>,
+    // Features agreed with the server; UI for the others is hidden
+    features: Features,
+    
+    // Text chat for the current channel, shown below the call or over it
+    chat: ChatPanel,
+    chat_overlay: ChatOverlay,
+    
+    // Round trip time to the server, once measured
+    latency: Option<Duration>,
+    // Upload and download over the last second
+    bandwidth_kbps: (f64, f64),
+    // Worst jitter of the media being received
+    jitter_ms: Option<f64>,
+    // Whether our microphone is picking up voice that's being sent
+    self_speaking: bool,
+    // How loud each user's voice is played, for those turned up or down
+    user_volumes: std::collections::HashMap<Uuid, f32>,
+    audio_buffer_stats: Option<BufferStats>,
+    connection_quality: ConnectionQuality,
+    video_buffer_stats: Option<BufferStats>,
+    
+    // Channel load, only shown to admins
+    server_stats: ServerStatsPanel,
+    search_panel: SearchPanel,
+    create_channel_dialog: CreateChannelDialog,
+    export_dialog: ExportDialog,
+    speaking_stats: SpeakingStatsPanel,
+    // Sent by the server on our first login
+    welcome_message: Option<ChatMessage>,
+    
+    // When the session runs out, and the token that renews it, once the server has warned us
+    session_expiry: Option<(Instant, String)>,
+    // Announcements from the server not yet dismissed, oldest first
+    announcements: Vec<String>,
+    // Users waiting for an admin to let them into a channel, only sent to admins
+    lobby: Vec<(User, Uuid)>,
+    // Users in each channel as ghosts, only sent to admins
+    ghost_members: std::collections::HashMap<Uuid, Vec<User>>,
+    // Private conversations, listed below the channels
+    direct_messages: DirectMessages,
+    // Files other users want to send us
+    file_offers: FileOffers,
+    
+    // Users currently recording the channel, and those still waiting on our consent
+    active_recordings: Vec<Uuid>,
+    consent_requests: Vec<Uuid>,
     
     // UI state
     show_settings: bool,
+    sort_users_by: UserSort,
+    // Username search; the results replace the user list until the query is cleared
+    user_query: String,
+    include_offline_users: bool,
+    user_search_results: Option<Vec<User>>,
+    // When the query last changed, and whether it has been searched for since
+    last_search_time: Instant,
+    user_search_pending: bool,
+    // Topic being edited for the current channel
+    topic_draft: Option<String>,
+    toasts: Vec<(String, Instant)>,
 }
 
 impl MainView {
@@ -28,17 +235,142 @@ impl MainView {
         Self {
             current_user_id: None,
             current_channel_id: None,
+            joining_channel_id: None,
+            awaiting_approval: false,
             server_info: None,
+            channel_loading: None,
+            users_loading: false,
+            bookmarks: Vec::new(),
             audio_levels: std::collections::HashMap::new(),
+            audio_visualizers: std::collections::HashMap::new(),
             audio_active: false,
             video_active: false,
             screen_share_active: false,
-            video_playback: Some(VideoPlayback::new()),
+            recording_active: false,
+            waveform: WaveformDisplay::new(style::ACCENT_COLOR),
+            video_playback: None,
+            detached_users: Vec::new(),
+            paused_video: HashSet::new(),
+            transcripts: Vec::new(),
+            transcription_enabled: false,
+            features: Features::empty(),
+            chat: ChatPanel::new(),
+            chat_overlay: ChatOverlay::new(),
+            latency: None,
+            bandwidth_kbps: (0.0, 0.0),
+            jitter_ms: None,
+            self_speaking: false,
+            user_volumes: std::collections::HashMap::new(),
+            audio_buffer_stats: None,
+            connection_quality: ConnectionQuality::default(),
+            video_buffer_stats: None,
+            server_stats: ServerStatsPanel::new(),
+            search_panel: SearchPanel::new(),
+            create_channel_dialog: CreateChannelDialog::new(),
+            export_dialog: ExportDialog::new(),
+            speaking_stats: SpeakingStatsPanel::new(),
+            welcome_message: None,
+            session_expiry: None,
+            announcements: Vec::new(),
+            lobby: Vec::new(),
+            ghost_members: std::collections::HashMap::new(),
+            direct_messages: DirectMessages::new(),
+            file_offers: FileOffers::new(),
+            active_recordings: Vec::new(),
+            consent_requests: Vec::new(),
             show_settings: false,
+            sort_users_by: UserSort::Name,
+            user_query: String::new(),
+            include_offline_users: false,
+            user_search_results: None,
+            last_search_time: Instant::now(),
+            user_search_pending: false,
+            topic_draft: None,
+            toasts: Vec::new(),
         }
     }
     
-    pub fn ui(&mut self, ui: &mut Ui) {
+    pub fn ui(&mut self, ui: &mut Ui) -> Option<MainViewAction> {
+        let mut action = None;
+        
+        // Recording notice, shown for as long as anyone is recording
+        if !self.active_recordings.is_empty() || self.recording_active {
+            TopBottomPanel::top("recording_banner").show_inside(ui, |ui| {
+                for recording_user_id in self.active_recordings.clone() {
+                    // The server records under the nil user ID
+                    let recorder = if recording_user_id.is_nil() {
+                        "the server".to_string()
+                    } else {
+                        self.get_user(recording_user_id)
+                            .map(|u| u.username.clone())
+                            .unwrap_or_else(|| "Unknown user".to_string())
+                    };
+                    
+                    ui.horizontal(|ui| {
+                        ui.label(RichText::new(format!("⚠️ This call is being recorded by {}", recorder))
+                            .color(style::AWAY_COLOR)
+                            .strong());
+                        
+                        if self.consent_requests.contains(&recording_user_id) {
+                            if ui.button("Consent").clicked() {
+                                self.consent_requests.retain(|id| *id != recording_user_id);
+                                action = Some(MainViewAction::RespondToRecording { accepted: true });
+                            }
+                            
+                            if ui.button("Decline").clicked() {
+                                self.consent_requests.retain(|id| *id != recording_user_id);
+                                action = Some(MainViewAction::RespondToRecording { accepted: false });
+                            }
+                        }
+                    });
+                }
+                
+                if self.recording_active {
+                    self.waveform.render(ui, Vec2::new(ui.available_width(), 40.0));
+                }
+            });
+        }
+        
+        if let Some((expires_at, token)) = &self.session_expiry {
+            let minutes = expires_at.saturating_duration_since(Instant::now()).as_secs().div_ceil(60);
+            let mut renew = false;
+            
+            TopBottomPanel::top("session_banner").show_inside(ui, |ui| {
+                let text = RichText::new(format!(
+                    "⏱ Session expires in {} minute{} — click to renew.",
+                    minutes,
+                    if minutes == 1 { "" } else { "s" }
+                ));
+                renew = ui.add(egui::Link::new(text.color(style::AWAY_COLOR).strong())).clicked();
+            });
+            
+            if renew {
+                action = Some(MainViewAction::RenewSession { token: token.clone() });
+                self.session_expiry = None;
+            }
+        }
+        
+        if !self.announcements.is_empty() {
+            let mut dismissed = None;
+            
+            TopBottomPanel::top("announcement_banner").show_inside(ui, |ui| {
+                for (i, text) in self.announcements.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(RichText::new(format!("📢 {}", text)).color(style::ACCENT_COLOR).strong());
+                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                            if ui.small_button("✕").on_hover_text("Dismiss").clicked() {
+                                dismissed = Some(i);
+                            }
+                        });
+                    });
+                }
+            });
+            
+            if let Some(i) = dismissed {
+                self.announcements.remove(i);
+            }
+        }
+        
         // Top bar with server name and controls
         TopBottomPanel::top("top_panel").show_inside(ui, |ui| {
             ui.horizontal(|ui| {
@@ -49,10 +381,18 @@ impl MainView {
                 
                 ui.heading(style::heading(server_name));
                 ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if ui.button("Disconnect").clicked() {
+                        action = Some(MainViewAction::Disconnect);
+                    }
+                    
                     if ui.button("Settings").clicked() {
                         self.show_settings = true;
                     }
                     
+                    if ui.selectable_label(self.search_panel.is_open(), "🔍").on_hover_text("Search messages").clicked() {
+                        self.search_panel.toggle();
+                    }
+                    
                     // Status selector
                     let status = self.get_current_user_status();
                     let status_color = style::status_color(status);
@@ -90,116 +430,1036 @@ impl MainView {
                     if let Some(user) = self.get_current_user() {
                         ui.label(style::body_text(&user.username));
                     }
+                    
+                    if let Some(latency) = self.latency {
+                        let color = match latency {
+                            l if l < GOOD_LATENCY => style::SUCCESS_COLOR,
+                            l if l < FAIR_LATENCY => style::AWAY_COLOR,
+                            _ => style::ERROR_COLOR,
+                        };
+                        ui.label(RichText::new(format!("Latency: {}ms", latency.as_millis())).color(color))
+                            .on_hover_ui(|ui| self.render_connection_quality(ui));
+                    }
+                    
+                    let (upload_kbps, download_kbps) = self.bandwidth_kbps;
+                    ui.label(style::secondary_text(&format!("↑ {:.0}kbps ↓ {:.0}kbps", upload_kbps, download_kbps)));
+                });
+            });
+        });
+        
+        // Side panel with channels and users
+        let mut clicked_channel = None;
+        let mut lobby_decision = None;
+        let mut user_choice = None;
+        let mut open_conversation = None;
+        let mut volume_change = None;
+        let mut channel_action = None;
+        let mut export_channel = None;
+        let mut sort_users_by = self.sort_users_by;
+        let mut user_query = self.user_query.clone();
+        let mut include_offline_users = self.include_offline_users;
+        SidePanel::left("channels_panel")
+            .resizable(true)
+            .default_width(250.0)
+            .show_inside(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.heading(style::subheading("Channels"));
+                    
+                    let open = self.create_channel_dialog.is_open();
+                    if ui.selectable_label(open, "➕").on_hover_text("Create a channel").clicked() {
+                        self.create_channel_dialog.toggle();
+                    }
+                    
+                    if self.server_stats.is_admin(self.current_user_id)
+                        && ui.selectable_label(self.server_stats.is_open(), "🖥")
+                            .on_hover_text(format!("Server stats ({})", ui.ctx().format_shortcut(&server_stats::TOGGLE_SHORTCUT)))
+                            .clicked()
+                    {
+                        self.server_stats.toggle();
+                    }
+                });
+                ui.separator();
+                
+                if let Some(server) = &self.server_info {
+                    (clicked_channel, channel_action, export_channel) = self.render_channels(ui, server);
+                    if self.channel_loading.is_some() {
+                        ui.add(SkeletonRows::new(3));
+                    }
+                    
+                    if !self.lobby.is_empty() {
+                        ui.add_space(20.0);
+                        ui.heading(style::subheading("Lobby"));
+                        ui.separator();
+                        lobby_decision = self.render_lobby(ui, server);
+                    }
+                    
+                    if !self.direct_messages.is_empty() {
+                        ui.add_space(20.0);
+                        ui.heading(style::subheading("Direct Messages"));
+                        ui.separator();
+                        open_conversation = self.direct_messages.render_list(ui);
+                    }
+                    
+                    ui.add_space(20.0);
+                    ui.horizontal(|ui| {
+                        ui.heading(style::subheading("Users"));
+                        
+                        ComboBox::from_id_source("sort_users_by")
+                            .selected_text(format!("Sort: {}", sort_users_by.label()))
+                            .show_ui(ui, |ui| {
+                                for sort in UserSort::ALL {
+                                    ui.selectable_value(&mut sort_users_by, sort, sort.label());
+                                }
+                            });
+                    });
+                    ui.separator();
+                    
+                    ui.add(egui::TextEdit::singleline(&mut user_query).hint_text("Search users"));
+                    ui.checkbox(&mut include_offline_users, "Search all users (including offline)");
+                    
+                    (user_choice, volume_change) = match &self.user_search_results {
+                        Some(users) if users.is_empty() => {
+                            ui.label(style::secondary_text("No users found"));
+                            (None, None)
+                        }
+                        Some(users) => self.render_users(ui, users, sort_users_by),
+                        None if self.users_loading => {
+                            ui.add(SkeletonRows::new(4));
+                            (None, None)
+                        }
+                        None => self.render_users(ui, &server.users, sort_users_by),
+                    };
+                } else {
+                    ui.label(style::secondary_text("Not connected to a server"));
+                }
+            });
+        
+        self.sort_users_by = sort_users_by;
+        self.update_user_query(user_query, include_offline_users);
+        
+        match user_choice {
+            Some(UserMenuChoice::Message(user_id)) => {
+                let username = self.dm_username(user_id);
+                self.direct_messages.open(user_id, &username);
+            }
+            Some(UserMenuChoice::SendFile(to_user_id)) => action = Some(MainViewAction::SendFile { to_user_id }),
+            None => {}
+        }
+        if let Some((user_id, username)) = open_conversation {
+            self.direct_messages.open(user_id, &username);
+        }
+        
+        if let Some((user_id, volume)) = volume_change {
+            self.user_volumes.insert(user_id, volume);
+            action = Some(MainViewAction::SetUserVolume { user_id, volume });
+        }
+        
+        if let Some((channel_id, ghost)) = clicked_channel {
+            self.joining_channel_id = Some(channel_id);
+            action = Some(MainViewAction::JoinChannel { channel_id, ghost });
+        }
+        
+        if channel_action.is_some() {
+            action = channel_action;
+        }
+        
+        if let Some((channel_id, channel_name)) = export_channel {
+            self.export_dialog.open_for(channel_id, &channel_name);
+        }
+        
+        if let Some((i, approved)) = lobby_decision {
+            let (user, channel_id) = self.lobby.remove(i);
+            action = Some(if approved {
+                MainViewAction::ApprovePending { user_id: user.id, channel_id }
+            } else {
+                MainViewAction::RejectPending { user_id: user.id, channel_id }
+            });
+        }
+        
+        let is_admin = self.server_stats.is_admin(self.current_user_id);
+        if is_admin && ui.input_mut(|i| i.consume_shortcut(&server_stats::TOGGLE_SHORTCUT)) {
+            self.server_stats.toggle();
+        }
+        SidePanel::right("server_stats_panel")
+            .resizable(true)
+            .default_width(320.0)
+            .show_animated_inside(ui, is_admin && self.server_stats.is_open(), |ui| {
+                if self.server_stats.ui(ui) {
+                    action = Some(MainViewAction::GetServerStats);
+                }
+            });
+        
+        // Main content area
+        let mut call_area = None;
+        egui::CentralPanel::default().show_inside(ui, |ui| {
+            if let Some(channel_id) = self.current_channel_id {
+                if let Some(channel) = self.get_channel(channel_id) {
+                    ui.heading(style::heading(&channel.name));
+                    
+                    if let Some(description) = &channel.description {
+                        ui.label(style::secondary_text(description));
+                    }
+                    
+                    if channel.e2e_encrypted {
+                        ui.label(style::success_text("🔒 Voice in this channel is end-to-end encrypted"));
+                    }
+                    
+                    let encrypted = channel.e2e_encrypted;
+                    let lobby_mode = channel.lobby_mode;
+                    let channel_type = channel.channel_type;
+                    let topic = channel.topic.clone();
+                    let is_admin = self.server_stats.is_admin(self.current_user_id);
+                    if let Some(topic) = self.render_topic(ui, topic, is_admin) {
+                        action = Some(MainViewAction::SetChannelTopic { channel_id, topic });
+                    }
+                    
+                    ui.separator();
+                    
+                    // Text channels are just the chat
+                    if channel_type == ChannelType::Text {
+                        if ui.button("Leave Channel").clicked() {
+                            action = Some(MainViewAction::LeaveChannel { channel_id });
+                            self.current_channel_id = None;
+                        }
+                        
+                        ui.separator();
+                        if let Some(content) = self.chat.ui(ui) {
+                            action = Some(MainViewAction::SendTextMessage { channel_id, content });
+                        }
+                        return;
+                    }
+                    
+                    // Media controls
+                    ui.horizontal(|ui| {
+                        if ui.button(if self.audio_active { "Mute" } else { "Unmute" }).clicked() {
+                            action = Some(MainViewAction::ToggleAudio);
+                        }
+                        
+                        if ui.button(if self.video_active { "Stop Video" } else { "Start Video" }).clicked() {
+                            action = Some(MainViewAction::ToggleVideo);
+                        }
+                        
+                        if ui.button(if self.screen_share_active { "Stop Sharing" } else { "Share Screen" }).clicked() {
+                            action = Some(MainViewAction::ToggleScreenShare);
+                        }
+                        
+                        if ui.button("⬚").on_hover_text("Choose part of the screen to share").clicked() {
+                            action = Some(MainViewAction::SelectScreenRegion);
+                        }
+                        
+                        if ui.button(if self.recording_active { "Stop Recording" } else { "Record" }).clicked() {
+                            action = Some(MainViewAction::ToggleRecording);
+                        }
+                        
+                        if ui.selectable_label(self.chat_overlay.is_open(), "💬").on_hover_text("Chat over the call").clicked() {
+                            self.chat_overlay.toggle();
+                        }
+                        
+                        if ui.selectable_label(self.speaking_stats.is_open(), "📊 Session Stats").clicked() {
+                            self.speaking_stats.toggle();
+                        }
+                        
+                        // Only admins can encrypt a channel or change its lobby
+                        if is_admin && !encrypted && self.features.contains(Features::E2E_VOICE) && ui.button("Encrypt Voice").clicked() {
+                            action = Some(MainViewAction::EnableE2E { channel_id });
+                        }
+                        
+                        if is_admin
+                            && ui.selectable_label(lobby_mode, "🚪 Lobby")
+                                .on_hover_text("Make users wait for an admin to let them in")
+                                .clicked()
+                        {
+                            action = Some(MainViewAction::SetLobbyMode { channel_id, enabled: !lobby_mode });
+                        }
+                        
+                        if ui.button("Leave Channel").clicked() {
+                            action = Some(MainViewAction::LeaveChannel { channel_id });
+                            self.current_channel_id = None;
+                        }
+                    });
+                    
+                    ui.separator();
+                    
+                    // Display area for video/screen sharing
+                    call_area = Some(ui.max_rect());
+                    if self.video_active || self.screen_share_active {
+                        let (area, detach) = self.render_video_area(ui);
+                        call_area = Some(area);
+                        
+                        if let Some(user_id) = detach {
+                            action = Some(MainViewAction::DetachVideo { user_id });
+                        }
+                    }
+                    
+                    if self.transcription_enabled && self.features.contains(Features::TRANSCRIPTION) {
+                        self.render_transcript(ui);
+                    }
+                    
+                    // The overlay takes the place of the chat panel while it's open
+                    if !self.chat_overlay.is_open() {
+                        ui.separator();
+                        if let Some(content) = self.chat.ui(ui) {
+                            action = Some(MainViewAction::SendTextMessage { channel_id, content });
+                        }
+                    }
+                }
+            } else {
+                ui.vertical_centered(|ui| {
+                    ui.add_space(100.0);
+                    ui.heading(style::heading("Welcome to Open Reverb"));
+                    ui.label(style::body_text("Select a channel from the list to join"));
+                });
+                
+                if let Some(welcome) = &self.welcome_message {
+                    ui.add_space(20.0);
+                    chat_panel::render_message(ui, welcome, 1.0);
+                }
+            }
+        });
+        
+        if let (Some(channel_id), Some(area)) = (self.current_channel_id, call_area) {
+            if let Some(content) = self.chat_overlay.show(ui.ctx(), &mut self.chat, area) {
+                action = Some(MainViewAction::SendTextMessage { channel_id, content });
+            }
+        }
+        
+        if let Some((to_user_id, content)) = self.direct_messages.show_window(ui.ctx()) {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+            let username = self.dm_username(to_user_id);
+            self.direct_messages.sent(self.current_user_id.unwrap_or_default(), to_user_id, &username, content.clone(), timestamp);
+            action = Some(MainViewAction::SendDirectMessage { to_user_id, content });
+        }
+        
+        match self.file_offers.show(ui.ctx()) {
+            Some(FileOfferResponse::Accept(id)) => action = Some(MainViewAction::AcceptFile { id }),
+            Some(FileOfferResponse::Decline(id)) => action = Some(MainViewAction::DeclineFile { id }),
+            None => {}
+        }
+        
+        if let Some(ExportRequest { channel_id, start_ts, end_ts, format }) = self.export_dialog.show(ui.ctx()) {
+            action = Some(MainViewAction::ExportMessages { channel_id, start_ts, end_ts, format });
+        }
+        
+        if let Some(NewChannel { name, description, parent_id, channel_type, audio_quality, permanent }) =
+            self.create_channel_dialog.show(ui.ctx(), self.server_info.as_ref(), self.server_stats.is_admin(self.current_user_id))
+        {
+            action = Some(MainViewAction::CreateChannel { name, description, parent_id, channel_type, audio_quality, permanent });
+        }
+        
+        match self.search_panel.show(ui.ctx(), self.server_info.as_ref()) {
+            Some(SearchPanelAction::Search { query, offset }) => {
+                action = Some(MainViewAction::SearchMessages { query, offset });
+            }
+            Some(SearchPanelAction::Open { channel_id, message_id }) => {
+                if self.current_channel_id == Some(channel_id) {
+                    if !self.chat.scroll_to(message_id) {
+                        self.show_toast("That message is from before you joined the channel");
+                    }
+                } else {
+                    // History isn't fetched on joining, so the message itself can't be shown
+                    self.joining_channel_id = Some(channel_id);
+                    action = Some(MainViewAction::JoinChannel { channel_id, ghost: false });
+                }
+            }
+            None => {}
+        }
+        
+        let users = self.server_info.as_ref().map(|s| s.users.as_slice()).unwrap_or_default();
+        self.speaking_stats.show(ui.ctx(), users);
+        match self.current_channel_id {
+            Some(channel_id) if action.is_none() && self.speaking_stats.refresh_due(channel_id) => {
+                action = Some(MainViewAction::GetSpeakingStats { channel_id });
+            }
+            Some(_) => {}
+            // Stats only cover the time since joining
+            None if self.speaking_stats.is_open() => self.speaking_stats.reset(),
+            None => {}
+        }
+        
+        self.render_toasts(ui);
+        
+        // Wait for typing to pause, and for nothing else to be sent this frame
+        if self.user_search_pending && action.is_none() {
+            let waited = self.last_search_time.elapsed();
+            if waited >= USER_SEARCH_DEBOUNCE {
+                self.user_search_pending = false;
+                action = Some(MainViewAction::SearchUsers {
+                    query: self.user_query.trim().to_string(),
+                    include_offline: self.include_offline_users,
+                });
+            } else {
+                ui.ctx().request_repaint_after(USER_SEARCH_DEBOUNCE - waited);
+            }
+        }
+        
+        action
+    }
+    
+    fn update_user_query(&mut self, query: String, include_offline: bool) {
+        if query == self.user_query && include_offline == self.include_offline_users {
+            return;
+        }
+        
+        self.user_query = query;
+        self.include_offline_users = include_offline;
+        self.last_search_time = Instant::now();
+        
+        // An empty query goes straight back to the full list
+        self.user_search_pending = !self.user_query.trim().is_empty();
+        if !self.user_search_pending {
+            self.user_search_results = None;
+        }
+    }
+    
+    pub fn set_user_search_results(&mut self, users: Vec<User>) {
+        // Results for a query that has since been cleared
+        if self.user_query.trim().is_empty() {
+            return;
+        }
+        
+        self.user_search_results = Some(users);
+    }
+    
+    pub fn set_session_expiring(&mut self, remaining: Duration, renew_token: String) {
+        self.session_expiry = Some((Instant::now() + remaining, renew_token));
+    }
+    
+    pub fn add_announcement(&mut self, text: String) {
+        self.announcements.push(text);
+    }
+    
+    // A user asked to join a channel in lobby mode
+    pub fn add_pending_member(&mut self, user: User, channel_id: Uuid) {
+        self.lobby.retain(|(u, _)| u.id != user.id);
+        self.lobby.push((user, channel_id));
+    }
+    
+    pub fn set_ghost_members(&mut self, channel_id: Uuid, users: Vec<User>) {
+        if users.is_empty() {
+            self.ghost_members.remove(&channel_id);
+        } else {
+            self.ghost_members.insert(channel_id, users);
+        }
+    }
+    
+    // The channel we asked to join has put us in its lobby
+    pub fn waiting_for_approval(&mut self, channel_id: Uuid) {
+        if self.joining_channel_id == Some(channel_id) {
+            self.awaiting_approval = true;
+        }
+    }
+    
+    pub fn push_waveform_sample(&mut self, rms: f32) {
+        self.waveform.push(rms);
+    }
+    
+    pub fn set_waveform_color(&mut self, color: Color32) {
+        self.waveform.set_color(color);
+    }
+    
+    pub fn set_current_user_id(&mut self, user_id: Uuid) {
+        self.current_user_id = Some(user_id);
+    }
+    
+    pub fn set_server_info(&mut self, server: Server) {
+        self.server_info = Some(server);
+        self.channel_loading = None;
+        self.users_loading = false;
+    }
+    
+    // A big server only sent a summary; its channels and users follow as we ask for them
+    pub fn set_server_summary(&mut self, server_id: Uuid, name: String, channel_count: u32, schema_version: u32) {
+        self.server_info = Some(Server {
+            id: server_id,
+            name,
+            description: None,
+            channels: Vec::new(),
+            users: Vec::new(),
+            schema_version,
+        });
+        self.channel_loading = Some(ChannelLoading { received: 0, total: channel_count });
+        self.users_loading = true;
+    }
+    
+    // A page of a big server's channels arrived. Returns where the next page starts, while
+    // there are more to ask for.
+    pub fn add_channel_page(&mut self, channels: Vec<Channel>, total: u32) -> Option<u32> {
+        let count = channels.len() as u32;
+        for channel in channels {
+            self.update_channel(channel);
+        }
+        
+        let loading = self.channel_loading.as_mut()?;
+        loading.received += count;
+        loading.total = total;
+        if count == 0 || loading.received >= loading.total {
+            self.channel_loading = None;
+            return None;
+        }
+        Some(loading.received)
+    }
+    
+    // Who is online, once a big server has sent it
+    pub fn set_online_users(&mut self, users: Vec<User>) {
+        if let Some(server) = &mut self.server_info {
+            server.users = users;
+        }
+        self.users_loading = false;
+    }
+    
+    pub fn set_video_playback(&mut self, video_playback: Arc<Mutex<VideoPlayback>>) {
+        self.video_playback = Some(video_playback);
+    }
+    
+    pub fn set_detached_users(&mut self, detached_users: Vec<Uuid>) {
+        self.detached_users = detached_users;
+    }
+    
+    pub fn set_bookmarks(&mut self, bookmarks: Vec<BookmarkedChannel>) {
+        self.bookmarks = bookmarks;
+    }
+    
+    pub fn set_speaking_stats(&mut self, channel_id: Uuid, stats: Vec<(Uuid, u64)>) {
+        if self.current_channel_id == Some(channel_id) {
+            self.speaking_stats.set_stats(channel_id, stats);
+        }
+    }
+    
+    // Media state is owned by the app; the view only reflects it
+    pub fn set_media_state(&mut self, audio_active: bool, video_active: bool, screen_share_active: bool, recording_active: bool) {
+        self.audio_active = audio_active;
+        self.video_active = video_active;
+        self.screen_share_active = screen_share_active;
+        self.recording_active = recording_active;
+    }
+    
+    pub fn has_server_info(&self) -> bool {
+        self.server_info.is_some()
+    }
+    
+    pub fn is_joining(&self) -> bool {
+        self.joining_channel_id.is_some()
+    }
+    
+    // The server confirmed that we joined the channel
+    pub fn channel_joined(&mut self, channel_id: Uuid) {
+        if self.joining_channel_id == Some(channel_id) {
+            self.joining_channel_id = None;
+            self.awaiting_approval = false;
+        }
+        if self.current_channel_id != Some(channel_id) {
+            self.transcripts.clear();
+            self.chat.clear();
+            self.topic_draft = None;
+            
+            if let Some(welcome) = self.welcome_message.take() {
+                self.chat.push(welcome);
+            }
+        }
+        self.current_channel_id = Some(channel_id);
+    }
+    
+    // A channel was created or changed on the server
+    pub fn update_channel(&mut self, channel: Channel) {
+        if let Some(server) = &mut self.server_info {
+            match server.channels.iter_mut().find(|c| c.id == channel.id) {
+                Some(existing) => *existing = channel,
+                None => server.channels.push(channel),
+            }
+        }
+    }
+    
+    // An admin deleted channels; leaves the current channel if it was one of them
+    pub fn remove_channels(&mut self, channel_ids: &[Uuid]) {
+        if let Some(server) = &mut self.server_info {
+            server.channels.retain(|c| !channel_ids.contains(&c.id));
+        }
+        if self.current_channel_id.is_some_and(|id| channel_ids.contains(&id)) {
+            self.current_channel_id = None;
+            self.chat.clear();
+            self.transcripts.clear();
+        }
+    }
+    
+    // Someone connected to the server
+    pub fn add_user(&mut self, user: User) {
+        if let Some(server) = &mut self.server_info {
+            match server.users.iter_mut().find(|u| u.id == user.id) {
+                Some(existing) => *existing = user,
+                None => server.users.push(user),
+            }
+        }
+    }
+    
+    pub fn remove_user(&mut self, user_id: Uuid) {
+        if let Some(server) = &mut self.server_info {
+            server.users.retain(|u| u.id != user_id);
+        }
+        
+        self.audio_levels.remove(&user_id);
+        self.audio_visualizers.remove(&user_id);
+    }
+    
+    // A user's status or profile changed
+    pub fn update_user(&mut self, user: User) {
+        if let Some(server) = &mut self.server_info {
+            if let Some(existing) = server.users.iter_mut().find(|u| u.id == user.id) {
+                *existing = user;
+            }
+        }
+    }
+    
+    pub fn join_failed(&mut self, message: &str) {
+        self.joining_channel_id = None;
+        self.awaiting_approval = false;
+        self.show_toast(format!("Couldn't join channel: {}", message));
+    }
+    
+    pub fn set_latency(&mut self, latency: Option<Duration>) {
+        self.latency = latency;
+    }
+    
+    pub fn set_bandwidth_kbps(&mut self, upload_kbps: f64, download_kbps: f64) {
+        self.bandwidth_kbps = (upload_kbps, download_kbps);
+    }
+    
+    pub fn set_jitter_ms(&mut self, jitter_ms: Option<f64>) {
+        self.jitter_ms = jitter_ms;
+    }
+    
+    pub fn user_volume(&self, user_id: Uuid) -> f32 {
+        self.user_volumes.get(&user_id).copied().unwrap_or(1.0)
+    }
+    
+    pub fn user_volumes(&self) -> impl Iterator<Item = (Uuid, f32)> + '_ {
+        self.user_volumes.iter().map(|(user_id, volume)| (*user_id, *volume))
+    }
+    
+    pub fn set_self_speaking(&mut self, speaking: bool) {
+        self.self_speaking = speaking;
+    }
+    
+    // Called once per update, so levels fall away when voice stops arriving
+    pub fn decay_audio_levels(&mut self) {
+        for level in self.audio_levels.values_mut() {
+            *level *= AUDIO_LEVEL_DECAY;
+        }
+        self.audio_levels.retain(|_, level| *level >= MIN_AUDIO_LEVEL);
+    }
+    
+    // Send buffers of whatever media is being captured
+    pub fn set_buffer_stats(&mut self, audio: Option<BufferStats>, video: Option<BufferStats>) {
+        self.audio_buffer_stats = audio;
+        self.video_buffer_stats = video;
+    }
+    
+    pub fn set_connection_quality(&mut self, quality: ConnectionQuality) {
+        self.connection_quality = quality;
+    }
+    
+    fn render_connection_quality(&self, ui: &mut Ui) {
+        if let Some(jitter_ms) = self.jitter_ms {
+            ui.label(style::body_text(&format!("Jitter: {:.1}ms", jitter_ms)));
+        }
+        
+        let quality = &self.connection_quality;
+        if quality.mix_latency_ms >= 1.0 {
+            ui.label(style::body_text(&format!("Mixer delay: {:.0}ms", quality.mix_latency_ms)));
+        }
+        if quality.hw_decode_active {
+            ui.label(style::body_text("Video decoded in hardware"));
+        }
+        
+        if self.audio_buffer_stats.is_none() && self.video_buffer_stats.is_none() {
+            ui.label(style::secondary_text("Not sending any media"));
+        }
+        
+        for (name, stats) in [("Audio", self.audio_buffer_stats), ("Video", self.video_buffer_stats)] {
+            if let Some(stats) = stats {
+                ui.label(style::body_text(&format!(
+                    "{} buffer: {}/{} frames, {} dropped",
+                    name, stats.current_depth, stats.capacity, stats.dropped_frames
+                )));
+            }
+        }
+    }
+    
+    pub fn set_features(&mut self, features: Features) {
+        self.features = features;
+    }
+    
+    pub fn set_transcription_enabled(&mut self, enabled: bool) {
+        self.transcription_enabled = enabled;
+    }
+    
+    pub fn add_transcript(&mut self, user_id: Uuid, channel_id: Uuid, text: String, timestamp: u64) {
+        if self.current_channel_id != Some(channel_id) {
+            return;
+        }
+        
+        self.transcripts.push(TranscriptEntry { user_id, text, timestamp });
+        if self.transcripts.len() > MAX_TRANSCRIPT_ENTRIES {
+            self.transcripts.remove(0);
+        }
+    }
+    
+    pub fn add_text_message(&mut self, message_id: Uuid, user_id: Uuid, channel_id: Uuid, content: String, timestamp: u64) {
+        if self.current_channel_id != Some(channel_id) {
+            return;
+        }
+        
+        let username = self.get_user(user_id)
+            .map(|u| u.username.clone())
+            .unwrap_or_else(|| "Unknown user".to_string());
+        
+        self.chat.push(ChatMessage { message_id, user_id, username, content, timestamp });
+    }
+    
+    // Shown until a channel is joined, then kept at the top of its chat
+    pub fn set_welcome_message(&mut self, content: String) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        
+        self.welcome_message = Some(ChatMessage {
+            message_id: Uuid::new_v4(),
+            user_id: Uuid::nil(),
+            username: "Server".to_string(),
+            content,
+            timestamp,
+        });
+    }
+    
+    // The server deleted the channel's messages older than `oldest_remaining`
+    pub fn messages_deleted(&mut self, channel_id: Uuid, oldest_remaining: u64) {
+        if self.current_channel_id == Some(channel_id) {
+            self.chat.remove_before(oldest_remaining);
+        }
+    }
+    
+    pub fn add_search_results(&mut self, results: Vec<SearchResult>) {
+        self.search_panel.add_results(results);
+    }
+    
+    // The server only sends statistics to admins
+    pub fn set_server_stats(&mut self, stats: ServerStatistics) {
+        self.server_stats.update(stats);
+    }
+    
+    pub fn show_toast(&mut self, message: impl Into<String>) {
+        self.toasts.push((message.into(), Instant::now()));
+    }
+    
+    // RMS (0.0 - 1.0) of a frame of the user's voice. The bar jumps up to it, then decays.
+    pub fn update_audio_level(&mut self, user_id: Uuid, level: f32) {
+        let current = self.audio_levels.entry(user_id).or_insert(0.0);
+        *current = current.max(level.clamp(0.0, 1.0));
+        self.audio_visualizers
+            .entry(user_id)
+            .or_insert_with(|| AudioVisualizerWidget::new(user_id))
+            .push_level(level);
+    }
+    
+    // Gain AGC is applying to our microphone, or None while it's off
+    pub fn set_gain_reduction_db(&mut self, gain_reduction_db: Option<f32>) {
+        if let Some(user_id) = self.current_user_id {
+            self.audio_visualizers
+                .entry(user_id)
+                .or_insert_with(|| AudioVisualizerWidget::new(user_id))
+                .set_gain_reduction_db(gain_reduction_db);
+        }
+    }
+    
+    pub fn set_video_paused(&mut self, user_id: Uuid, paused: bool) {
+        if paused {
+            self.paused_video.insert(user_id);
+        } else {
+            self.paused_video.remove(&user_id);
+        }
+    }
+    
+    pub fn set_recording_state(&mut self, recording_user_id: Uuid, recording_started: bool) {
+        if recording_started {
+            if !self.active_recordings.contains(&recording_user_id) {
+                self.active_recordings.push(recording_user_id);
+            }
+            
+            // Our own recording doesn't need our consent
+            if self.current_user_id != Some(recording_user_id) && !self.consent_requests.contains(&recording_user_id) {
+                self.consent_requests.push(recording_user_id);
+            }
+        } else {
+            self.active_recordings.retain(|id| *id != recording_user_id);
+            self.consent_requests.retain(|id| *id != recording_user_id);
+        }
+    }
+    
+    // Returns the channel clicked and whether to join it as a ghost, any bookmark change or
+    // deletion, and the channel an admin wants to export
+    fn render_channels(&self, ui: &mut Ui, server: &Server) -> ChannelListResult {
+        let mut clicked = None;
+        let mut channel_action = None;
+        let mut export_channel = None;
+        let is_admin = self.server_stats.is_admin(self.current_user_id);
+        
+        if !self.bookmarks.is_empty() {
+            ui.label(RichText::new("⭐ Favorites").strong());
+            
+            for bookmark in &self.bookmarks {
+                let response = match server.channels.iter().find(|c| c.id == bookmark.channel_id) {
+                    // Under the channel's current name, in case it's been renamed
+                    Some(channel) => {
+                        let is_active = self.current_channel_id == Some(channel.id);
+                        let response = ui.selectable_label(is_active, style::body_text(&channel.name));
+                        if response.clicked() && !is_active && self.joining_channel_id.is_none() {
+                            clicked = Some((channel.id, false));
+                        }
+                        response
+                    }
+                    None => ui
+                        .add(Label::new(RichText::new(&bookmark.display_name).weak()).sense(egui::Sense::click()))
+                        .on_hover_text("This channel no longer exists"),
+                };
+                
+                response.context_menu(|ui| {
+                    if ui.button("Remove bookmark").clicked() {
+                        channel_action = Some(MainViewAction::RemoveBookmark { channel_id: bookmark.channel_id });
+                        ui.close_menu();
+                    }
                 });
-            });
-        });
+            }
+            
+            ui.separator();
+        }
         
-        // Side panel with channels and users
-        SidePanel::left("channels_panel")
-            .resizable(true)
-            .default_width(250.0)
-            .show_inside(ui, |ui| {
-                ui.heading(style::subheading("Channels"));
-                ui.separator();
-                
-                if let Some(server) = &self.server_info {
-                    self.render_channels(ui, server);
-                    
-                    ui.add_space(20.0);
-                    ui.heading(style::subheading("Users"));
-                    ui.separator();
-                    
-                    self.render_users(ui, server);
-                } else {
-                    ui.label(style::secondary_text("Not connected to a server"));
+        for channel in &server.channels {
+            let is_active = self.current_channel_id == Some(channel.id);
+            let is_joining = self.joining_channel_id == Some(channel.id);
+            let icon = match channel.channel_type {
+                ChannelType::Voice => "🔊",
+                ChannelType::Text => "💬",
+            };
+            let name = if channel.e2e_encrypted {
+                format!("{} 🔒 {}", icon, channel.name)
+            } else {
+                format!("{} {}", icon, channel.name)
+            };
+            let text = if is_active {
+                RichText::new(name).color(style::ACCENT_COLOR).strong()
+            } else {
+                style::body_text(&name)
+            };
+            
+            ui.horizontal(|ui| {
+                // Only one join can be in flight at a time
+                let response = ui.selectable_label(is_active, text);
+                if response.clicked() && !is_active && self.joining_channel_id.is_none() {
+                    clicked = Some((channel.id, false));
                 }
-            });
-        
-        // Main content area
-        egui::CentralPanel::default().show_inside(ui, |ui| {
-            if let Some(channel_id) = self.current_channel_id {
-                if let Some(channel) = self.get_channel(channel_id) {
-                    ui.heading(style::heading(&channel.name));
-                    
-                    if let Some(description) = &channel.description {
-                        ui.label(style::secondary_text(description));
-                    }
-                    
-                    ui.separator();
-                    
-                    // Media controls
-                    ui.horizontal(|ui| {
-                        if ui.button(if self.audio_active { "Mute" } else { "Unmute" }).clicked() {
-                            self.audio_active = !self.audio_active;
-                            // In a real implementation, this would toggle audio capture
-                        }
-                        
-                        if ui.button(if self.video_active { "Stop Video" } else { "Start Video" }).clicked() {
-                            self.video_active = !self.video_active;
-                            // In a real implementation, this would toggle video capture
-                        }
-                        
-                        if ui.button(if self.screen_share_active { "Stop Sharing" } else { "Share Screen" }).clicked() {
-                            self.screen_share_active = !self.screen_share_active;
-                            // In a real implementation, this would toggle screen sharing
+                
+                response.context_menu(|ui| {
+                    if is_admin {
+                        if ui.button("Export History").clicked() {
+                            export_channel = Some((channel.id, channel.name.clone()));
+                            ui.close_menu();
                         }
-                        
-                        if ui.button("Leave Channel").clicked() {
-                            // Leave the channel in a real implementation
-                            self.current_channel_id = None;
+                        let can_join = !is_active && self.joining_channel_id.is_none();
+                        if ui.add_enabled(can_join, Button::new("Join as Ghost")).clicked() {
+                            clicked = Some((channel.id, true));
+                            ui.close_menu();
                         }
-                    });
+                    }
                     
-                    ui.separator();
+                    // The server refuses the rest, so don't offer them
+                    let has_children = server.channels.iter().any(|c| c.parent_id == Some(channel.id));
+                    let can_delete = !has_children && (is_admin || !channel.permanent);
+                    let delete = ui.add_enabled(can_delete, Button::new("Delete Channel"));
+                    if has_children {
+                        delete.on_disabled_hover_text("Delete its sub-channels first");
+                    } else if delete.clicked() {
+                        channel_action = Some(MainViewAction::DeleteChannel { channel_id: channel.id });
+                        ui.close_menu();
+                    }
+                });
+                
+                // Shown on hover, and always once bookmarked
+                let bookmarked = self.bookmarks.iter().any(|b| b.channel_id == channel.id);
+                if bookmarked || ui.ui_contains_pointer() {
+                    let (color, hint) = if bookmarked {
+                        (style::AWAY_COLOR, "Remove from favorites")
+                    } else {
+                        (style::SECONDARY_TEXT_COLOR, "Add to favorites")
+                    };
                     
-                    // Display area for video/screen sharing
-                    if self.video_active || self.screen_share_active {
-                        self.render_video_area(ui);
+                    if ui.add(Button::new(RichText::new("★").color(color)).frame(false)).on_hover_text(hint).clicked() {
+                        channel_action = Some(MainViewAction::ToggleBookmark {
+                            channel_id: channel.id,
+                            display_name: channel.name.clone(),
+                        });
                     }
                 }
-            } else {
-                ui.vertical_centered(|ui| {
-                    ui.add_space(100.0);
-                    ui.heading(style::heading("Welcome to Open Reverb"));
-                    ui.label(style::body_text("Select a channel from the list to join"));
+                
+                if is_joining {
+                    ui.add(Spinner::new());
+                    ui.label(style::secondary_text(if self.awaiting_approval { "Waiting for approval..." } else { "Joining..." }));
+                }
+            });
+            
+            // Only admins are told about ghosts
+            if let Some(ghosts) = self.ghost_members.get(&channel.id) {
+                ui.indent(channel.id, |ui| {
+                    for ghost in ghosts {
+                        ui.label(style::secondary_text(&format!("👻 {}", ghost.username)));
+                    }
                 });
             }
-        });
+        }
+        
+        (clicked, channel_action, export_channel)
     }
     
-    pub fn set_current_user_id(&mut self, user_id: Uuid) {
-        self.current_user_id = Some(user_id);
+    // Returns the index of the waiting user an admin approved (true) or rejected (false), if any
+    fn render_lobby(&self, ui: &mut Ui, server: &Server) -> Option<(usize, bool)> {
+        let mut decision = None;
+        
+        for (i, (user, channel_id)) in self.lobby.iter().enumerate() {
+            let channel_name = server.channels
+                .iter()
+                .find(|c| c.id == *channel_id)
+                .map_or("Unknown channel", |c| c.name.as_str());
+            
+            ui.horizontal(|ui| {
+                ui.label(style::body_text(&user.username));
+                ui.label(style::secondary_text(&format!("→ {}", channel_name)));
+                
+                if ui.small_button("Approve").clicked() {
+                    decision = Some((i, true));
+                }
+                if ui.small_button("Reject").clicked() {
+                    decision = Some((i, false));
+                }
+            });
+        }
+        
+        decision
     }
     
-    pub fn set_server_info(&mut self, server: Server) {
-        self.server_info = Some(server);
+    // Shows the channel topic, which can be clicked to edit it. Returns the new topic once submitted.
+    // Only admins can change the topic, so only they can click it to edit
+    fn render_topic(&mut self, ui: &mut Ui, topic: Option<String>, editable: bool) -> Option<String> {
+        if !editable {
+            if let Some(topic) = &topic {
+                ui.label(style::secondary_text(topic));
+            }
+            return None;
+        }
+        
+        let mut draft = match self.topic_draft.take() {
+            Some(draft) => draft,
+            None => {
+                let text = match &topic {
+                    Some(topic) => style::secondary_text(topic),
+                    None => style::secondary_text("Click to set a topic").italics(),
+                };
+                
+                if ui.add(Label::new(text).sense(egui::Sense::click()))
+                    .on_hover_text("Edit topic")
+                    .clicked()
+                {
+                    self.topic_draft = Some(topic.unwrap_or_default());
+                }
+                return None;
+            }
+        };
+        
+        let (submitted, cancelled) = ui.horizontal(|ui| {
+            let response = ui.add(egui::TextEdit::singleline(&mut draft)
+                .char_limit(MAX_TOPIC_LENGTH)
+                .hint_text("Channel topic"));
+            response.request_focus();
+            
+            let submitted = ui.button("Save").clicked()
+                || (response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)));
+            let cancelled = ui.button("Cancel").clicked() || ui.input(|i| i.key_pressed(egui::Key::Escape));
+            (submitted, cancelled)
+        }).inner;
+        
+        if submitted {
+            return Some(draft);
+        }
+        if !cancelled {
+            self.topic_draft = Some(draft);
+        }
+        None
     }
     
-    pub fn update_audio_level(&mut self, user_id: Uuid, level: f32) {
-        self.audio_levels.insert(user_id, level);
+    fn render_transcript(&self, ui: &mut Ui) {
+        ui.separator();
+        ui.heading(style::subheading("Transcript"));
+        
+        ScrollArea::vertical()
+            .id_source("transcript")
+            .max_height(150.0)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                if self.transcripts.is_empty() {
+                    ui.label(style::secondary_text("Nothing has been transcribed yet"));
+                }
+                
+                for entry in &self.transcripts {
+                    let username = self.get_user(entry.user_id)
+                        .map(|u| u.username.as_str())
+                        .unwrap_or("Unknown user");
+                    
+                    let time = chrono::DateTime::from_timestamp(entry.timestamp as i64, 0)
+                        .map(|t| t.with_timezone(&chrono::Local).format("%H:%M:%S").to_string())
+                        .unwrap_or_default();
+                    
+                    ui.horizontal_wrapped(|ui| {
+                        ui.label(style::secondary_text(&time));
+                        ui.label(RichText::new(username).strong());
+                        ui.label(style::body_text(&entry.text));
+                    });
+                }
+            });
     }
     
-    fn render_channels(&self, ui: &mut Ui, server: &Server) {
-        for channel in &server.channels {
-            let is_active = self.current_channel_id == Some(channel.id);
-            let text = if is_active {
-                RichText::new(&channel.name).color(style::ACCENT_COLOR).strong()
-            } else {
-                style::body_text(&channel.name)
-            };
-            
-            if ui.selectable_label(is_active, text).clicked() && !is_active {
-                // This would join the channel in a real implementation
-                // self.current_channel_id = Some(channel.id);
-            }
+    fn render_toasts(&mut self, ui: &mut Ui) {
+        self.toasts.retain(|(_, shown_at)| shown_at.elapsed() < TOAST_DURATION);
+        
+        if self.toasts.is_empty() {
+            return;
         }
+        
+        // Repaint when the oldest toast is due to disappear
+        ui.ctx().request_repaint_after(TOAST_DURATION.saturating_sub(self.toasts[0].1.elapsed()));
+        
+        egui::Area::new("toasts")
+            .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-10.0, -10.0))
+            .show(ui.ctx(), |ui| {
+                for (message, _) in &self.toasts {
+                    egui::Frame::popup(ui.style())
+                        .fill(style::SECONDARY_BACKGROUND)
+                        .show(ui, |ui| {
+                            ui.label(style::body_text(message));
+                        });
+                }
+            });
     }
     
-    fn render_users(&self, ui: &mut Ui, server: &Server) {
-        for user in &server.users {
+    // Returns the user chosen to send a direct message to, if any, and any change to a user's volume
+    fn render_users(&self, ui: &mut Ui, users: &[User], sort: UserSort) -> (Option<UserMenuChoice>, Option<(Uuid, f32)>) {
+        let mut user_choice = None;
+        let mut volume_change = None;
+        
+        // Keep the level bars moving until they've died away
+        if !self.audio_levels.is_empty() {
+            ui.ctx().request_repaint();
+        }
+        
+        for user in sort_users(users, sort) {
             let status_color = style::status_color(user.status);
             let is_current_user = self.current_user_id == Some(user.id);
-            let is_speaking = self.audio_levels.get(&user.id).copied().unwrap_or(0.0) > 0.05;
+            let level = self.audio_levels.get(&user.id).copied().unwrap_or(0.0);
+            let is_speaking = if is_current_user { self.self_speaking } else { level > 0.05 };
             
             ui.horizontal(|ui| {
                 // Status indicator
@@ -214,7 +1474,55 @@ impl MainView {
                     style::body_text(&user.username)
                 };
                 
-                ui.add(Label::new(username_text));
+                let joined = chrono::DateTime::from_timestamp_millis(user.joined_at as i64)
+                    .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_else(|| "unknown".to_string());
+                
+                // Users on federated servers only have a name to show
+                let response = match &user.federated_from {
+                    Some(server) => {
+                        ui.add(Label::new("🌐"));
+                        ui.add(Label::new(username_text)).on_hover_text(format!("On {}", server))
+                    }
+                    None => ui.add(Label::new(username_text)).on_hover_text(format!(
+                        "Joined: {}\nOnline for {}",
+                        joined,
+                        format_duration(user.session_duration_secs())
+                    )),
+                };
+                
+                if !is_current_user {
+                    response.context_menu(|ui| {
+                        if ui.button("Send Direct Message").clicked() {
+                            user_choice = Some(UserMenuChoice::Message(user.id));
+                            ui.close_menu();
+                        }
+                        
+                        if ui.button("Send File…").clicked() {
+                            user_choice = Some(UserMenuChoice::SendFile(user.id));
+                            ui.close_menu();
+                        }
+                        
+                        ui.separator();
+                        ui.label("Volume");
+                        let mut volume = self.user_volume(user.id);
+                        let slider = Slider::new(&mut volume, 0.0..=audio::MAX_USER_VOLUME)
+                            .custom_formatter(|volume, _| format!("{:.0}%", volume * 100.0));
+                        if ui.add(slider).changed() {
+                            volume_change = Some((user.id, volume));
+                        }
+                        if ui.add_enabled(volume != 1.0, Button::new("Reset Volume")).clicked() {
+                            volume_change = Some((user.id, 1.0));
+                        }
+                    });
+                }
+                
+                // Voice level, left-aligned in a fixed space so the bars line up
+                let (rect, _) = ui.allocate_exact_size(LEVEL_BAR_SIZE, egui::Sense::hover());
+                if level > 0.0 {
+                    let bar = egui::Rect::from_min_size(rect.min, Vec2::new(LEVEL_BAR_SIZE.x * level, LEVEL_BAR_SIZE.y));
+                    ui.painter().rect_filled(bar, 2.0, style::level_color(level));
+                }
                 
                 // Speaking indicator
                 if is_speaking {
@@ -222,6 +1530,34 @@ impl MainView {
                 }
             });
         }
+        
+        (user_choice, volume_change)
+    }
+    
+    // A direct message from another user, here or on a federated server
+    pub fn receive_direct_message(&mut self, from_user_id: Uuid, content: String, timestamp: u64) {
+        let sender = self.dm_username(from_user_id);
+        if !self.direct_messages.is_open_with(from_user_id) {
+            self.show_toast(format!("💬 {}: {}", sender, content));
+        }
+        self.direct_messages.receive(from_user_id, &sender, content, timestamp);
+    }
+    
+    pub fn receive_file_offer(&mut self, id: Uuid, from_user_id: Uuid, filename: String, size_bytes: u64, mime_type: String) {
+        let from_username = self.dm_username(from_user_id);
+        self.file_offers.push(FileOffer { id, from_username, filename, size_bytes, mime_type });
+    }
+    
+    // The sender gave up on a file before it was answered
+    pub fn withdraw_file_offer(&mut self, id: Uuid) {
+        self.file_offers.remove(id);
+    }
+    
+    // Offline users are only known from searches
+    fn dm_username(&self, user_id: Uuid) -> String {
+        self.get_user(user_id)
+            .or_else(|| self.user_search_results.iter().flatten().find(|u| u.id == user_id))
+            .map_or_else(|| "Unknown user".to_string(), |u| u.username.clone())
     }
     
     fn get_current_user(&self) -> Option<&User> {
@@ -266,15 +1602,23 @@ impl MainView {
         self.show_settings = false;
     }
     
-    fn render_video_area(&mut self, ui: &mut Ui) {
+    // Returns the area the video was drawn in, and the user whose video was popped out, if any
+    fn render_video_area(&mut self, ui: &mut Ui) -> (egui::Rect, Option<Uuid>) {
         // Allocate space for the video display
         let available_width = ui.available_width();
         let video_height = 400.0;
+        let mut detach = None;
         
-        ui.allocate_ui(Vec2::new(available_width, video_height), |ui| {
+        let rect = ui.allocate_ui(Vec2::new(available_width, video_height), |ui| {
             if let Some(video_playback) = &self.video_playback {
+                let mut video_playback = video_playback.lock().unwrap();
+                video_playback.prune_textures();
+                
                 // Calculate participant layout
-                let active_users = self.get_active_video_users();
+                let active_users: Vec<Uuid> = self.get_active_video_users()
+                    .into_iter()
+                    .filter(|user_id| !self.detached_users.contains(user_id))
+                    .collect();
                 
                 if active_users.is_empty() {
                     // No active video users
@@ -301,17 +1645,57 @@ impl MainView {
                         egui::vec2(cell_width, cell_height),
                     );
                     
-                    let response = ui.allocate_rect(rect, egui::Sense::hover());
+                    let mut response = ui.allocate_rect(rect, egui::Sense::hover());
+                    if let Some(codec) = video_playback.codec(user_id) {
+                        response = response.on_hover_text(format!("Codec: {}", codec.label()));
+                    }
                     
                     // Draw video frame or placeholder
                     if let Some(user) = self.get_user(user_id) {
-                        // In a real implementation, we would render the video frame here
-                        // using the texture from the video data
-                        ui.painter().rect_filled(
-                            rect.shrink(4.0),
-                            4.0,
-                            Color32::from_rgb(40, 40, 40),
-                        );
+                        if !video_playback.can_decode(user_id) {
+                            ui.painter().rect_filled(
+                                rect.shrink(4.0),
+                                4.0,
+                                Color32::from_rgb(40, 40, 40),
+                            );
+                            ui.painter().text(
+                                rect.center(),
+                                egui::Align2::CENTER_CENTER,
+                                format!("Unsupported codec ({})", video_playback.codec(user_id).map_or("?", |c| c.label())),
+                                egui::TextStyle::Body.resolve(ui.style()),
+                                style::SECONDARY_TEXT_COLOR,
+                            );
+                        } else if let Some(texture) = video_playback.get_or_update_texture(user_id, ui.ctx()) {
+                            ui.painter().rect_filled(
+                                rect.shrink(4.0),
+                                4.0,
+                                Color32::from_rgb(40, 40, 40),
+                            );
+                            
+                            // Fit the frame in the cell, keeping its aspect ratio
+                            let cell = rect.shrink(4.0);
+                            let size = texture.size_vec2();
+                            let size = size * (cell.width() / size.x).min(cell.height() / size.y);
+                            ui.put(egui::Rect::from_center_size(cell.center(), size), egui::Image::new((texture.id(), size)));
+                        } else {
+                            // No video from this user, show their voice level instead
+                            match self.audio_visualizers.get(&user_id) {
+                                Some(visualizer) => visualizer.paint(ui, rect.shrink(4.0)),
+                                None => AudioVisualizerWidget::new(user_id).paint(ui, rect.shrink(4.0)),
+                            }
+                        }
+                        
+                        // Covers the (blurred) video of anyone in privacy mode
+                        if self.paused_video.contains(&user_id) {
+                            ui.painter().rect_filled(rect.shrink(4.0), 4.0, Color32::from_rgba_premultiplied(0, 0, 0, 160));
+                            ui.painter().text(
+                                rect.center(),
+                                egui::Align2::CENTER_CENTER,
+                                "🔒 Paused",
+                                egui::FontId::proportional(24.0),
+                                Color32::WHITE,
+                            );
+                        }
                         
                         // Draw username
                         let text_rect = egui::Rect::from_min_max(
@@ -332,6 +1716,18 @@ impl MainView {
                             egui::TextStyle::Body.resolve(ui.style()),
                             Color32::WHITE,
                         );
+                        
+                        // Offer to pop the video out while hovering over it
+                        if ui.rect_contains_pointer(rect) {
+                            let button_rect = egui::Rect::from_min_size(
+                                rect.right_top() + egui::vec2(-92.0, 8.0),
+                                egui::vec2(84.0, 22.0),
+                            );
+                            
+                            if ui.put(button_rect, Button::new("⬡ Pop out")).clicked() {
+                                detach = Some(user_id);
+                            }
+                        }
                     }
                     
                     // Update grid position
@@ -347,14 +1743,16 @@ impl MainView {
                     ui.label(style::body_text("Video playback not available"));
                 });
             }
-        });
+        }).response.rect;
+        
+        (rect, detach)
     }
     
     fn get_active_video_users(&self) -> Vec<Uuid> {
         // In a real implementation, this would track active video participants
         // For demo purposes, we'll use all users in the current channel
         if let Some(server) = &self.server_info {
-            if let Some(channel_id) = self.current_channel_id {
+            if self.current_channel_id.is_some() {
                 // Get users in the current channel
                 return server.users.iter()
                     .filter(|_| true) // In a real implementation, filter by active video users
@@ -365,6 +1763,10 @@ impl MainView {
         Vec::new()
     }
     
+    pub fn username(&self, user_id: Uuid) -> Option<&str> {
+        self.get_user(user_id).map(|u| u.username.as_str())
+    }
+    
     fn get_user(&self, user_id: Uuid) -> Option<&User> {
         if let Some(server) = &self.server_info {
             return server.users.iter().find(|u| u.id == user_id);
@@ -379,7 +1781,7 @@ impl MainView {
             2 => (2, 1),
             3 | 4 => (2, 2),
             5 | 6 => (3, 2),
-            7 | 8 | 9 => (3, 3),
+            7..=9 => (3, 3),
             _ => {
                 // For more than 9 participants, use a scrollable grid
                 let cols = 3;
@@ -388,10 +1790,332 @@ impl MainView {
             }
         }
     }
+}
+
+// Sorts are stable, and ties fall back to join time and then name so the list doesn't jump around
+fn sort_users(users: &[User], sort: UserSort) -> Vec<&User> {
+    let mut sorted: Vec<&User> = users.iter().collect();
+    
+    match sort {
+        UserSort::Name => sorted.sort_by_key(|u| u.username.to_lowercase()),
+        UserSort::JoinTime => sorted.sort_by(|a, b| {
+            a.joined_at.cmp(&b.joined_at).then_with(|| a.username.to_lowercase().cmp(&b.username.to_lowercase()))
+        }),
+        UserSort::Status => sorted.sort_by(|a, b| {
+            status_rank(a.status)
+                .cmp(&status_rank(b.status))
+                .then(a.joined_at.cmp(&b.joined_at))
+                .then_with(|| a.username.to_lowercase().cmp(&b.username.to_lowercase()))
+        }),
+    }
+    
+    sorted
+}
+
+// Most available first
+fn status_rank(status: UserStatus) -> u8 {
+    match status {
+        UserStatus::Online => 0,
+        UserStatus::Away => 1,
+        UserStatus::DoNotDisturb => 2,
+        UserStatus::Offline => 3,
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s >= 3600 => format!("{}h {}m", s / 3600, (s % 3600) / 60),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::epaint::Shape;
+    
+    const CHANNEL_ID: Uuid = Uuid::from_u128(1);
+    
+    // A main view in a voice channel, with the given features negotiated
+    fn in_voice_channel(features: Features) -> MainView {
+        let mut view = MainView::new();
+        view.set_server_info(Server {
+            id: Uuid::from_u128(2),
+            name: "Test".to_string(),
+            description: None,
+            channels: vec![Channel { id: CHANNEL_ID, ..channel("General") }],
+            users: Vec::new(),
+            schema_version: 0,
+        });
+        view.channel_joined(CHANNEL_ID);
+        view.set_features(features);
+        view
+    }
+    
+    fn channel(name: &str) -> Channel {
+        Channel {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            description: None,
+            parent_id: None,
+            members: Vec::new(),
+            topic: None,
+            e2e_encrypted: false,
+            channel_type: ChannelType::Voice,
+            media_bandwidth_limit_kbps: 0,
+            lobby_mode: false,
+            audio_quality: AudioQuality::default(),
+            override_retention_days: None,
+            permanent: false,
+            idle_timeout_secs: None,
+        }
+    }
+    
+    // Every piece of text drawn in one frame of the view
+    fn rendered_text(view: &mut MainView) -> Vec<String> {
+        fn collect(shape: &Shape, text: &mut Vec<String>) {
+            match shape {
+                Shape::Text(shape) => text.push(shape.galley.text().to_string()),
+                Shape::Vec(shapes) => shapes.iter().for_each(|shape| collect(shape, text)),
+                _ => {}
+            }
+        }
+        
+        let ctx = egui::Context::default();
+        let output = ctx.run(egui::RawInput::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                view.ui(ui);
+            });
+        });
+        
+        let mut text = Vec::new();
+        for clipped in &output.shapes {
+            collect(&clipped.shape, &mut text);
+        }
+        text
+    }
+    
+    // Log the view's user in, as an admin or not
+    fn log_in(view: &mut MainView, admin: bool) {
+        let user_id = Uuid::from_u128(3);
+        view.set_current_user_id(user_id);
+        view.set_server_stats(ServerStatistics {
+            total_connections: 1,
+            per_channel: Vec::new(),
+            admin_ids: if admin { vec![user_id] } else { Vec::new() },
+        });
+    }
+    
+    #[test]
+    fn encrypt_button_needs_e2e_negotiated_and_an_admin() {
+        let shown = |features, admin| {
+            let mut view = in_voice_channel(features);
+            log_in(&mut view, admin);
+            rendered_text(&mut view).iter().any(|t| t == "Encrypt Voice")
+        };
+        
+        assert!(shown(Features::E2E_VOICE, true));
+        assert!(!shown(Features::E2E_VOICE, false));
+        assert!(!shown(Features::empty(), true));
+        assert!(!shown(Features::all() - Features::E2E_VOICE, true));
+    }
+    
+    #[test]
+    fn only_admins_can_edit_the_topic() {
+        let editable = |admin| {
+            let mut view = in_voice_channel(Features::empty());
+            log_in(&mut view, admin);
+            rendered_text(&mut view).iter().any(|t| t == "Click to set a topic")
+        };
+        
+        assert!(editable(true));
+        assert!(!editable(false));
+    }
+    
+    #[test]
+    fn transcript_needs_transcription_negotiated() {
+        let shown = |features| {
+            let mut view = in_voice_channel(features);
+            view.set_transcription_enabled(true);
+            rendered_text(&mut view).iter().any(|t| t == "Transcript")
+        };
+        
+        assert!(shown(Features::TRANSCRIPTION));
+        assert!(!shown(Features::E2E_VOICE));
+    }
+    
+    #[test]
+    fn big_servers_page_in_channels_until_all_have_arrived() {
+        let mut view = MainView::new();
+        view.set_server_summary(Uuid::from_u128(2), "Big".to_string(), 5, 0);
+        assert!(view.users_loading);
+        
+        assert_eq!(view.add_channel_page(vec![channel("a"), channel("b")], 5), Some(2));
+        assert_eq!(view.add_channel_page(vec![channel("c"), channel("d")], 5), Some(4));
+        assert_eq!(view.add_channel_page(vec![channel("e")], 5), None);
+        assert!(view.channel_loading.is_none());
+        assert_eq!(view.server_info.as_ref().unwrap().channels.len(), 5);
+        
+        view.set_online_users(Vec::new());
+        assert!(!view.users_loading);
+    }
+    
+    #[test]
+    fn paging_stops_when_the_server_runs_out_early() {
+        let mut view = MainView::new();
+        // Channels were deleted after the summary was sent
+        view.set_server_summary(Uuid::from_u128(2), "Big".to_string(), 5, 0);
+        assert_eq!(view.add_channel_page(vec![channel("a"), channel("b")], 3), Some(2));
+        assert_eq!(view.add_channel_page(Vec::new(), 2), None);
+        assert!(view.channel_loading.is_none());
+    }
     
-    pub fn update_video_frame(&mut self, user_id: Uuid, frame_data: Vec<u8>) {
-        if let Some(video_playback) = &mut self.video_playback {
-            video_playback.process_video_data(user_id, frame_data);
+    #[test]
+    fn voice_levels_jump_up_and_die_away() {
+        let mut view = MainView::new();
+        let user_id = Uuid::new_v4();
+        
+        view.update_audio_level(user_id, 0.6);
+        view.update_audio_level(user_id, 0.2);
+        assert_eq!(view.audio_levels[&user_id], 0.6);
+        
+        view.decay_audio_levels();
+        assert!((view.audio_levels[&user_id] - 0.57).abs() < 0.0001);
+        
+        // Two and a half seconds at 60fps with no more voice
+        for _ in 0..150 {
+            view.decay_audio_levels();
         }
+        assert!(view.audio_levels.is_empty());
+    }
+    
+    #[test]
+    fn joining_waits_on_the_server() {
+        let mut view = in_voice_channel(Features::empty());
+        let other = Uuid::new_v4();
+        assert!(!view.is_joining());
+        
+        // What clicking a channel does
+        view.joining_channel_id = Some(other);
+        assert!(view.is_joining());
+        
+        // A confirmation for some other channel doesn't finish the join
+        view.channel_joined(CHANNEL_ID);
+        assert!(view.is_joining());
+        
+        view.waiting_for_approval(other);
+        assert!(view.awaiting_approval);
+        view.channel_joined(other);
+        assert!(!view.is_joining() && !view.awaiting_approval);
+        assert_eq!(view.current_channel_id, Some(other));
+    }
+    
+    #[test]
+    fn failed_joins_stay_in_the_current_channel() {
+        let mut view = in_voice_channel(Features::empty());
+        view.joining_channel_id = Some(Uuid::new_v4());
+        view.waiting_for_approval(Uuid::new_v4());
+        assert!(!view.awaiting_approval);
+        
+        view.join_failed("Channel not found");
+        assert!(!view.is_joining());
+        assert_eq!(view.current_channel_id, Some(CHANNEL_ID));
+        assert_eq!(view.toasts.last().unwrap().0, "Couldn't join channel: Channel not found");
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn users_with_the_same_status_are_sorted_by_join_time() {
+        let user = |username: &str, status, joined_at| User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            status,
+            joined_at,
+            federated_from: None,
+        };
+        let users = vec![
+            user("carol", UserStatus::Away, 100),
+            user("dave", UserStatus::Online, 300),
+            user("alice", UserStatus::Online, 200),
+            user("Bob", UserStatus::Online, 200),
+            user("erin", UserStatus::Offline, 50),
+            user("bob", UserStatus::Online, 200),
+        ];
+        let names = |sort| sort_users(&users, sort).iter().map(|u| u.username.as_str()).collect::<Vec<_>>();
+        
+        // Ties on join time fall back to the name, and users equal on both keep their order
+        assert_eq!(names(UserSort::Status), ["alice", "Bob", "bob", "dave", "carol", "erin"]);
+        assert_eq!(names(UserSort::JoinTime), ["erin", "carol", "alice", "Bob", "bob", "dave"]);
+        assert_eq!(names(UserSort::Name), ["alice", "Bob", "bob", "carol", "dave", "erin"]);
+    }
+    
+    #[test]
+    fn clearing_the_user_search_restores_the_full_list() {
+        let mut view = in_voice_channel(Features::empty());
+        let user = |username: &str| User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            status: UserStatus::Online,
+            joined_at: 0,
+            federated_from: None,
+        };
+        view.server_info.as_mut().unwrap().users = vec![user("alice"), user("bob")];
+        let shown = |view: &mut MainView| {
+            let text = rendered_text(view);
+            ["alice", "bob", "Alicia"].into_iter().filter(|name| text.iter().any(|t| t == name)).collect::<Vec<_>>()
+        };
+        assert_eq!(shown(&mut view), ["alice", "bob"]);
+        
+        view.update_user_query("ali".to_string(), true);
+        assert!(view.user_search_pending);
+        view.set_user_search_results(vec![user("alice"), user("Alicia")]);
+        assert_eq!(shown(&mut view), ["alice", "Alicia"]);
+        
+        // A blank query isn't searched for
+        view.update_user_query("  ".to_string(), true);
+        assert!(!view.user_search_pending && view.user_search_results.is_none());
+        assert_eq!(shown(&mut view), ["alice", "bob"]);
+        
+        // Nor are results that arrive after it was cleared shown
+        view.set_user_search_results(vec![user("Alicia")]);
+        assert_eq!(shown(&mut view), ["alice", "bob"]);
+    }
+    
+    #[test]
+    fn session_banner_counts_down_and_renews_when_clicked() {
+        let mut view = in_voice_channel(Features::empty());
+        let banner = |view: &mut MainView| {
+            rendered_text(view).into_iter().find(|t| t.starts_with("⏱"))
+        };
+        assert_eq!(banner(&mut view), None);
+        
+        // Whole minutes, rounded up
+        view.set_session_expiring(Duration::from_secs(270), "token".to_string());
+        assert_eq!(banner(&mut view).unwrap(), "⏱ Session expires in 5 minutes — click to renew.");
+        view.set_session_expiring(Duration::from_secs(90), "token".to_string());
+        assert_eq!(banner(&mut view).unwrap(), "⏱ Session expires in 2 minutes — click to renew.");
+        view.set_session_expiring(Duration::from_secs(30), "token".to_string());
+        assert_eq!(banner(&mut view).unwrap(), "⏱ Session expires in 1 minute — click to renew.");
+        
+        // Clicking it sends the token back, and the banner goes
+        let ctx = egui::Context::default();
+        let run = |view: &mut MainView, events: Vec<egui::Event>| {
+            let mut action = None;
+            let output = ctx.run(egui::RawInput { events, ..Default::default() }, |ctx| {
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    action = view.ui(ui);
+                });
+            });
+            let banner = output.shapes.iter().find_map(|clipped| match &clipped.shape {
+                Shape::Text(text) if text.galley.text().starts_with("⏱") => Some(text.pos),
+                _ => None,
+            });
+            (action, banner)
+        };
+        let (_, pos) = run(&mut view, Vec::new());
+        let pos = pos.unwrap() + egui::vec2(5.0, 5.0);
+        let click = |pressed| egui::Event::PointerButton { pos, button: egui::PointerButton::Primary, pressed, modifiers: Default::default() };
+        let (action, _) = run(&mut view, vec![egui::Event::PointerMoved(pos), click(true), click(false)]);
+        assert!(matches!(action, Some(MainViewAction::RenewSession { token }) if token == "token"));
+        assert_eq!(banner(&mut view), None);
+    }
+}
@@ -0,0 +1,395 @@
+use egui::{Color32, Frame, RichText, Ui};
+
+use crate::ui::style;
+
+// Characters a backslash makes literal
+const ESCAPABLE: &str = "\\*`~_";
+
+// A run of text with one set of inline formatting
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Span {
+    pub text: String,
+    pub bold: bool,
+    pub italic: bool,
+    pub strikethrough: bool,
+    pub code: bool,
+    pub link: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Block {
+    Inline(Vec<Span>),
+    // A fenced code block, without its fences
+    Code(String),
+}
+
+#[derive(Clone, Copy, Default)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    strikethrough: bool,
+}
+
+// Renders the inline Markdown people type in chat: **bold**, *italic*, `code`, ~~strikethrough~~,
+// ``` fenced code blocks ``` and links. Anything that doesn't parse is shown as typed.
+pub struct MessageFormatter;
+
+impl MessageFormatter {
+    // Lays the message out in the current (wrapping) row. Text takes the UI's override colour,
+    // so callers can fade it.
+    pub fn render(ui: &mut Ui, text: &str) {
+        let color = ui.visuals().override_text_color.unwrap_or(style::TEXT_COLOR);
+        let item_spacing = ui.spacing().item_spacing.x;
+        
+        for block in parse(text) {
+            match block {
+                Block::Inline(spans) => {
+                    // Spans of one line sit right next to each other
+                    ui.spacing_mut().item_spacing.x = 0.0;
+                    for span in spans {
+                        render_span(ui, span, color);
+                    }
+                    ui.spacing_mut().item_spacing.x = item_spacing;
+                }
+                Block::Code(code) => {
+                    ui.end_row();
+                    Frame::none()
+                        .fill(style::SECONDARY_BACKGROUND)
+                        .rounding(4.0)
+                        .inner_margin(6.0)
+                        .show(ui, |ui| ui.label(RichText::new(code).monospace().color(color)));
+                    ui.end_row();
+                }
+            }
+        }
+    }
+}
+
+fn render_span(ui: &mut Ui, span: Span, color: Color32) {
+    if span.link {
+        ui.hyperlink(&span.text);
+        return;
+    }
+    
+    let mut text = RichText::new(span.text).color(color);
+    if span.bold {
+        text = text.strong();
+    }
+    if span.italic {
+        text = text.italics();
+    }
+    if span.strikethrough {
+        text = text.strikethrough();
+    }
+    if span.code {
+        text = text.code();
+    }
+    ui.label(text);
+}
+
+// Split off fenced code blocks, then parse the inline formatting of the rest
+pub fn parse(text: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut rest = text;
+    
+    while let Some(start) = rest.find("```") {
+        let Some(length) = rest[start + 3..].find("```") else {
+            break;
+        };
+        
+        push_inline(&mut blocks, &rest[..start]);
+        
+        let code = &rest[start + 3..start + 3 + length];
+        // The language after the opening fence, if any, isn't shown
+        let code = match code.split_once('\n') {
+            Some((first_line, body)) if !first_line.trim().contains(' ') => body,
+            _ => code,
+        };
+        blocks.push(Block::Code(code.trim_end_matches('\n').to_string()));
+        
+        rest = &rest[start + 3 + length + 3..];
+    }
+    
+    push_inline(&mut blocks, rest);
+    blocks
+}
+
+fn push_inline(blocks: &mut Vec<Block>, text: &str) {
+    let text = text.trim_matches('\n');
+    if !text.is_empty() {
+        blocks.push(Block::Inline(parse_inline(text)));
+    }
+}
+
+pub fn parse_inline(text: &str) -> Vec<Span> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut spans = Vec::new();
+    let mut i = 0;
+    
+    parse_until(&chars, &mut i, Style::default(), None, &mut spans);
+    merge(spans)
+}
+
+// Parse until `closer` (or the end of the text), returning whether the closer was found. Markers
+// that are never closed are kept as text.
+fn parse_until(chars: &[char], i: &mut usize, style: Style, closer: Option<&str>, spans: &mut Vec<Span>) -> bool {
+    let start = *i;
+    let mut text = String::new();
+    
+    while *i < chars.len() {
+        // A marker with nothing between it and its opener doesn't close it
+        if let Some(closer) = closer {
+            if *i > start && closes(chars, *i, closer) {
+                push_text(spans, &mut text, style);
+                *i += closer.len();
+                return true;
+            }
+        }
+        
+        let c = chars[*i];
+        
+        if c == '\\' && chars.get(*i + 1).is_some_and(|next| ESCAPABLE.contains(*next)) {
+            text.push(chars[*i + 1]);
+            *i += 2;
+            continue;
+        }
+        
+        if c == '`' {
+            if let Some(length) = chars[*i + 1..].iter().position(|&c| c == '`').filter(|&length| length > 0) {
+                push_text(spans, &mut text, style);
+                spans.push(Span {
+                    text: chars[*i + 1..*i + 1 + length].iter().collect(),
+                    code: true,
+                    ..span(style)
+                });
+                *i += length + 2;
+                continue;
+            }
+        }
+        
+        if let Some((marker, inner)) = opener(chars, *i, style) {
+            push_text(spans, &mut text, style);
+            
+            let (before, marker_at) = (spans.len(), *i);
+            *i += marker.len();
+            if !parse_until(chars, i, inner, Some(marker), spans) {
+                // Unclosed: show the marker as typed and carry on after it
+                spans.truncate(before);
+                *i = marker_at + marker.len();
+                text.push_str(marker);
+            }
+            continue;
+        }
+        
+        if is_url_start(chars, *i) {
+            push_text(spans, &mut text, style);
+            let url = url_at(chars, *i);
+            *i += url.chars().count();
+            spans.push(Span {
+                text: url,
+                link: true,
+                ..Span::default()
+            });
+            continue;
+        }
+        
+        text.push(c);
+        *i += 1;
+    }
+    
+    push_text(spans, &mut text, style);
+    false
+}
+
+// Whether `closer` ends the formatting at `i`. A single `*` doesn't close italics where it
+// starts a `**` pair, so bold can sit inside italics; a `***` run closes both.
+fn closes(chars: &[char], i: usize, closer: &str) -> bool {
+    if !starts_with(chars, i, closer) || chars[i - 1].is_whitespace() {
+        return false;
+    }
+    
+    closer != "*" || chars.get(i + 1) != Some(&'*') || chars.get(i + 2) == Some(&'*')
+}
+
+// The formatting marker starting at `i`, if any, and the style inside it. Formatting already
+// applied isn't opened again, and a marker followed by a space is just punctuation.
+fn opener(chars: &[char], i: usize, style: Style) -> Option<(&'static str, Style)> {
+    let (marker, inner) = if starts_with(chars, i, "**") && !style.bold {
+        ("**", Style { bold: true, ..style })
+    } else if starts_with(chars, i, "~~") && !style.strikethrough {
+        ("~~", Style { strikethrough: true, ..style })
+    } else if starts_with(chars, i, "*") && !style.italic {
+        ("*", Style { italic: true, ..style })
+    } else {
+        return None;
+    };
+    
+    chars.get(i + marker.len()).filter(|c| !c.is_whitespace())?;
+    Some((marker, inner))
+}
+
+fn starts_with(chars: &[char], i: usize, marker: &str) -> bool {
+    marker.chars().enumerate().all(|(offset, c)| chars.get(i + offset) == Some(&c))
+}
+
+// URLs start a word
+fn is_url_start(chars: &[char], i: usize) -> bool {
+    (i == 0 || chars[i - 1].is_whitespace() || chars[i - 1] == '(')
+        && (starts_with(chars, i, "https://") || starts_with(chars, i, "http://"))
+}
+
+// The URL starting at `i`, up to the next space. Punctuation at the end is taken to belong to
+// the sentence.
+fn url_at(chars: &[char], i: usize) -> String {
+    let url: String = chars[i..].iter().take_while(|c| !c.is_whitespace()).collect();
+    url.trim_end_matches(['.', ',', ')', '!', '?', ';', ':']).to_string()
+}
+
+fn span(style: Style) -> Span {
+    Span {
+        bold: style.bold,
+        italic: style.italic,
+        strikethrough: style.strikethrough,
+        ..Span::default()
+    }
+}
+
+fn push_text(spans: &mut Vec<Span>, text: &mut String, style: Style) {
+    if !text.is_empty() {
+        spans.push(Span {
+            text: std::mem::take(text),
+            ..span(style)
+        });
+    }
+}
+
+// Join neighbouring spans with the same formatting, e.g. text either side of an unclosed marker
+fn merge(spans: Vec<Span>) -> Vec<Span> {
+    let mut merged: Vec<Span> = Vec::new();
+    for span in spans {
+        match merged.last_mut() {
+            Some(last) if same_format(last, &span) => last.text.push_str(&span.text),
+            _ => merged.push(span),
+        }
+    }
+    merged
+}
+
+// Links are never joined, so each stays one hyperlink
+fn same_format(a: &Span, b: &Span) -> bool {
+    !a.link
+        && !b.link
+        && a.bold == b.bold
+        && a.italic == b.italic
+        && a.strikethrough == b.strikethrough
+        && a.code == b.code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn plain(text: &str) -> Span {
+        Span { text: text.to_string(), ..Span::default() }
+    }
+    
+    fn bold(text: &str) -> Span {
+        Span { text: text.to_string(), bold: true, ..Span::default() }
+    }
+    
+    fn italic(text: &str) -> Span {
+        Span { text: text.to_string(), italic: true, ..Span::default() }
+    }
+    
+    #[test]
+    fn plain_text_is_one_span() {
+        assert_eq!(parse_inline("hello there"), vec![plain("hello there")]);
+    }
+    
+    #[test]
+    fn each_marker_formats_its_text() {
+        assert_eq!(parse_inline("a **b** c"), vec![plain("a "), bold("b"), plain(" c")]);
+        assert_eq!(parse_inline("*b*"), vec![italic("b")]);
+        assert_eq!(
+            parse_inline("~~gone~~"),
+            vec![Span { text: "gone".to_string(), strikethrough: true, ..Span::default() }]
+        );
+        assert_eq!(
+            parse_inline("run `cargo test`"),
+            vec![plain("run "), Span { text: "cargo test".to_string(), code: true, ..Span::default() }]
+        );
+    }
+    
+    #[test]
+    fn formatting_nests() {
+        assert_eq!(
+            parse_inline("**bold *both* bold**"),
+            vec![bold("bold "), Span { text: "both".to_string(), bold: true, italic: true, ..Span::default() }, bold(" bold")]
+        );
+        assert_eq!(
+            parse_inline("*a **b** c*"),
+            vec![italic("a "), Span { text: "b".to_string(), bold: true, italic: true, ..Span::default() }, italic(" c")]
+        );
+        assert_eq!(
+            parse_inline("***both***"),
+            vec![Span { text: "both".to_string(), bold: true, italic: true, ..Span::default() }]
+        );
+        assert_eq!(
+            parse_inline("~~**x**~~"),
+            vec![Span { text: "x".to_string(), bold: true, strikethrough: true, ..Span::default() }]
+        );
+    }
+    
+    #[test]
+    fn code_is_not_formatted_inside() {
+        assert_eq!(
+            parse_inline("`**not bold**`"),
+            vec![Span { text: "**not bold**".to_string(), code: true, ..Span::default() }]
+        );
+    }
+    
+    #[test]
+    fn unclosed_and_empty_markers_stay_as_text() {
+        assert_eq!(parse_inline("**unclosed"), vec![plain("**unclosed")]);
+        assert_eq!(parse_inline("a * b"), vec![plain("a * b")]);
+        assert_eq!(parse_inline("2 * 3 = *six*"), vec![plain("2 * 3 = "), italic("six")]);
+        assert_eq!(parse_inline("****"), vec![plain("****")]);
+        assert_eq!(parse_inline("``"), vec![plain("``")]);
+        assert_eq!(parse_inline("`open"), vec![plain("`open")]);
+        assert_eq!(parse_inline("~~"), vec![plain("~~")]);
+    }
+    
+    #[test]
+    fn escaped_markers_are_literal() {
+        assert_eq!(parse_inline(r"\*not italic\*"), vec![plain("*not italic*")]);
+        assert_eq!(parse_inline(r"back\\slash"), vec![plain(r"back\slash")]);
+        assert_eq!(parse_inline(r"\n stays"), vec![plain(r"\n stays")]);
+    }
+    
+    #[test]
+    fn urls_become_links() {
+        assert_eq!(
+            parse_inline("see https://example.com/a_b*c, then"),
+            vec![
+                plain("see "),
+                Span { text: "https://example.com/a_b*c".to_string(), link: true, ..Span::default() },
+                plain(", then"),
+            ]
+        );
+        assert_eq!(parse_inline("nothttps://x"), vec![plain("nothttps://x")]);
+    }
+    
+    #[test]
+    fn fenced_code_blocks_are_split_out() {
+        assert_eq!(
+            parse("before\n```rust\nfn main() {}\n```\nafter"),
+            vec![
+                Block::Inline(vec![plain("before")]),
+                Block::Code("fn main() {}".to_string()),
+                Block::Inline(vec![plain("after")]),
+            ]
+        );
+        assert_eq!(parse("```**raw**```"), vec![Block::Code("**raw**".to_string())]);
+        assert_eq!(parse("```unclosed"), vec![Block::Inline(vec![plain("```unclosed")])]);
+    }
+}
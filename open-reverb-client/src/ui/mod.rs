@@ -1,4 +1,19 @@
+pub mod chat_overlay;
+pub mod chat_panel;
+pub mod create_channel;
+pub mod direct_messages;
+pub mod export_dialog;
+pub mod file_offers;
 pub mod login;
 pub mod main_view;
+pub mod message_formatter;
+pub mod region_selector;
+pub mod search_panel;
+pub mod server_stats;
 pub mod settings;
-pub mod style;
\ No newline at end of file
+pub mod speaking_stats;
+pub mod style;
+pub mod totp;
+pub mod video_pane;
+pub mod widgets;
+pub mod wizard;
\ No newline at end of file
@@ -0,0 +1,110 @@
+use egui::{Align2, Color32, FontId, Key, Order, Pos2, Rect, Sense, Stroke};
+
+use open_reverb_common::models::ScreenRegion;
+use crate::ui::style;
+
+// Smallest region worth sharing, in pixels; anything less is taken as a stray click
+const MIN_REGION_SIZE: u32 = 32;
+
+// How dark the overlay makes what's under it
+const DIM_ALPHA: u8 = 140;
+
+pub enum RegionSelection {
+    Region(ScreenRegion),
+    WholeScreen,
+    Cancelled,
+}
+
+// Covers the window for dragging out the part of the screen to share. The app goes fullscreen
+// while it's up so the whole monitor can be covered; egui 0.23 can't make the window see-through,
+// so what shows under the overlay is the client itself, dimmed.
+pub struct RegionSelector {
+    // Where the current drag started, in points
+    drag_start: Option<Pos2>,
+}
+
+impl RegionSelector {
+    pub fn new() -> Self {
+        Self { drag_start: None }
+    }
+    
+    // `window_origin` is the window's top left on the desktop, in points. Returns None until
+    // the user has chosen.
+    pub fn select_region(&mut self, ctx: &egui::Context, window_origin: Pos2) -> Option<RegionSelection> {
+        let screen = ctx.screen_rect();
+        let mut selection = None;
+        
+        egui::Area::new("screen_region_selector")
+            .order(Order::Foreground)
+            .fixed_pos(screen.min)
+            .show(ctx, |ui| {
+                let response = ui.allocate_rect(screen, Sense::drag());
+                let painter = ui.painter();
+                painter.rect_filled(screen, 0.0, Color32::from_black_alpha(DIM_ALPHA));
+                painter.text(
+                    screen.center_top() + egui::vec2(0.0, 40.0),
+                    Align2::CENTER_CENTER,
+                    "Drag over the part of the screen to share. Enter shares all of it, Esc cancels.",
+                    FontId::proportional(20.0),
+                    Color32::WHITE,
+                );
+                
+                if response.drag_started() {
+                    self.drag_start = response.interact_pointer_pos();
+                }
+                
+                let (Some(start), Some(end)) = (self.drag_start, response.interact_pointer_pos()) else {
+                    return;
+                };
+                let rect = Rect::from_two_pos(start, end);
+                painter.rect_stroke(rect, 0.0, Stroke::new(2.0, style::ACCENT_COLOR));
+                
+                if response.drag_released() {
+                    self.drag_start = None;
+                    selection = to_screen_region(rect, window_origin, ctx.pixels_per_point()).map(RegionSelection::Region);
+                }
+            });
+        
+        ctx.input(|i| {
+            if i.key_pressed(Key::Escape) {
+                selection = Some(RegionSelection::Cancelled);
+            } else if i.key_pressed(Key::Enter) {
+                selection = Some(RegionSelection::WholeScreen);
+            }
+        });
+        selection
+    }
+}
+
+// A rectangle dragged in the window, in points, as pixels on the desktop. None when it's too
+// small to share.
+fn to_screen_region(rect: Rect, window_origin: Pos2, pixels_per_point: f32) -> Option<ScreenRegion> {
+    let min = (window_origin.to_vec2() + rect.min.to_vec2()) * pixels_per_point;
+    let size = rect.size() * pixels_per_point;
+    let (width, height) = (size.x.round() as u32, size.y.round() as u32);
+    if width < MIN_REGION_SIZE || height < MIN_REGION_SIZE {
+        return None;
+    }
+    
+    Some(ScreenRegion { x: min.x.round() as i32, y: min.y.round() as i32, width, height })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn drags_are_placed_on_the_desktop_in_pixels() {
+        let rect = Rect::from_min_max(Pos2::new(10.0, 20.0), Pos2::new(410.0, 320.0));
+        
+        // A window on the second monitor of a HiDPI desktop
+        let region = to_screen_region(rect, Pos2::new(960.0, 0.0), 2.0).unwrap();
+        assert_eq!(region, ScreenRegion { x: 1940, y: 40, width: 800, height: 600 });
+    }
+    
+    #[test]
+    fn clicks_arent_regions() {
+        let rect = Rect::from_min_size(Pos2::new(100.0, 100.0), egui::vec2(3.0, 2.0));
+        assert!(to_screen_region(rect, Pos2::ZERO, 1.0).is_none());
+    }
+}
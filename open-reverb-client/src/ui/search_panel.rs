@@ -0,0 +1,140 @@
+use egui::{Frame, Key, RichText, ScrollArea, Sense, TextEdit, Window};
+use uuid::Uuid;
+
+use open_reverb_common::models::{SearchResult, Server};
+use crate::ui::style;
+
+// Results asked for at a time
+pub const SEARCH_PAGE_SIZE: u32 = 25;
+
+pub enum SearchPanelAction {
+    // Ask the server for results, starting `offset` results in
+    Search { query: String, offset: u32 },
+    // A result was clicked
+    Open { channel_id: Uuid, message_id: Uuid },
+}
+
+// Searches chat history across every channel
+pub struct SearchPanel {
+    open: bool,
+    query: String,
+    // Query the results are for, which may differ from what's been typed since
+    searched: Option<String>,
+    results: Vec<SearchResult>,
+    // Whether the last page came back full, so there may be more
+    more_available: bool,
+    waiting: bool,
+}
+
+impl SearchPanel {
+    pub fn new() -> Self {
+        Self {
+            open: false,
+            query: String::new(),
+            searched: None,
+            results: Vec::new(),
+            more_available: false,
+            waiting: false,
+        }
+    }
+    
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+    
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+    }
+    
+    // A page of results from the server; later pages are added to the end
+    pub fn add_results(&mut self, results: Vec<SearchResult>) {
+        self.more_available = results.len() as u32 == SEARCH_PAGE_SIZE;
+        self.results.extend(results);
+        self.waiting = false;
+    }
+    
+    pub fn show(&mut self, ctx: &egui::Context, server: Option<&Server>) -> Option<SearchPanelAction> {
+        let mut action = None;
+        let mut open = self.open;
+        
+        Window::new("Search Messages")
+            .open(&mut open)
+            .resizable(true)
+            .default_size(egui::vec2(400.0, 450.0))
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    let response = ui.add(
+                        TextEdit::singleline(&mut self.query)
+                            .hint_text("Search")
+                            .desired_width(ui.available_width() - 70.0),
+                    );
+                    let entered = response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+                    
+                    if (ui.button("🔍 Search").clicked() || entered) && !self.query.trim().is_empty() {
+                        let query = self.query.trim().to_string();
+                        self.searched = Some(query.clone());
+                        self.results.clear();
+                        self.waiting = true;
+                        action = Some(SearchPanelAction::Search { query, offset: 0 });
+                    }
+                });
+                ui.separator();
+                
+                ScrollArea::vertical().id_source("search_results").show(ui, |ui| {
+                    if self.searched.is_some() && self.results.is_empty() && !self.waiting {
+                        ui.label(style::secondary_text("No messages found"));
+                    }
+                    
+                    for result in &self.results {
+                        if render_result(ui, result, server) {
+                            action = Some(SearchPanelAction::Open {
+                                channel_id: result.channel_id,
+                                message_id: result.message_id,
+                            });
+                        }
+                    }
+                    
+                    if self.waiting {
+                        ui.label(style::secondary_text("Searching..."));
+                    } else if self.more_available && ui.button("Load more").clicked() {
+                        if let Some(query) = self.searched.clone() {
+                            self.waiting = true;
+                            action = Some(SearchPanelAction::Search { query, offset: self.results.len() as u32 });
+                        }
+                    }
+                });
+            });
+        
+        self.open = open;
+        action
+    }
+}
+
+// A card for one result. Returns true when it was clicked.
+fn render_result(ui: &mut egui::Ui, result: &SearchResult, server: Option<&Server>) -> bool {
+    let channel = server
+        .and_then(|s| s.channels.iter().find(|c| c.id == result.channel_id))
+        .map_or("unknown channel", |c| c.name.as_str());
+    let username = server
+        .and_then(|s| s.users.iter().find(|u| u.id == result.user_id))
+        .map_or("Unknown user", |u| u.username.as_str());
+    let time = chrono::DateTime::from_timestamp(result.timestamp as i64, 0)
+        .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default();
+    
+    let response = Frame::group(ui.style())
+        .show(ui, |ui| {
+            ui.set_min_width(ui.available_width());
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(username).strong());
+                ui.label(style::secondary_text(&format!("in #{} · {}", channel, time)));
+            });
+            ui.label(style::body_text(&result.snippet));
+        })
+        .response
+        .interact(Sense::click())
+        .on_hover_cursor(egui::CursorIcon::PointingHand)
+        .on_hover_text(&result.content);
+    
+    response.clicked()
+}
@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+
+use egui::{Color32, Grid, Key, KeyboardShortcut, Modifiers, Pos2, Rect, RichText, ScrollArea, Sense, Stroke, Ui, Vec2};
+use uuid::Uuid;
+
+use open_reverb_common::models::{ChannelStats, ServerStatistics};
+use crate::ui::style;
+
+// Forwarding rates above these are shown as busy and overloaded
+const BUSY_BYTES_PER_SEC: u64 = 256 * 1024;
+const OVERLOADED_BYTES_PER_SEC: u64 = 1024 * 1024;
+
+// How many updates the connections sparkline covers
+const HISTORY_LEN: usize = 60;
+const SPARKLINE_HEIGHT: f32 = 40.0;
+
+pub const TOGGLE_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::CTRL.plus(Modifiers::SHIFT), Key::S);
+
+// Server health for admins: connections over time and the load on each channel
+pub struct ServerStatsPanel {
+    stats: Option<ServerStatistics>,
+    // Total connections of the last updates, oldest first
+    connections: VecDeque<u32>,
+    open: bool,
+}
+
+impl ServerStatsPanel {
+    pub fn new() -> Self {
+        Self {
+            stats: None,
+            connections: VecDeque::with_capacity(HISTORY_LEN),
+            open: true,
+        }
+    }
+    
+    pub fn update(&mut self, stats: ServerStatistics) {
+        push_windowed(&mut self.connections, stats.total_connections);
+        self.stats = Some(stats);
+    }
+    
+    // Whether the user is one of the server's admins, who are the only ones sent stats
+    pub fn is_admin(&self, user_id: Option<Uuid>) -> bool {
+        is_admin(self.stats.as_ref(), user_id)
+    }
+    
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+    
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+    }
+    
+    // Returns true when a refresh was asked for
+    pub fn ui(&self, ui: &mut Ui) -> bool {
+        let stats = match &self.stats {
+            Some(stats) => stats,
+            None => return false,
+        };
+        
+        let mut refresh = false;
+        ui.horizontal(|ui| {
+            ui.heading(style::subheading("Server"));
+            refresh = ui.button("🔄 Refresh").clicked();
+        });
+        ui.separator();
+        
+        ui.label(RichText::new(stats.total_connections.to_string()).size(32.0).strong());
+        ui.label(style::secondary_text("connections"));
+        ui.add_space(5.0);
+        
+        self.sparkline(ui);
+        ui.add_space(10.0);
+        
+        ScrollArea::vertical().id_source("server_stats_channels").show(ui, |ui| {
+            Grid::new("server_stats")
+                .striped(true)
+                .num_columns(6)
+                .show(ui, |ui| {
+                    for header in ["", "Channel", "Members", "Voice", "Video", "Traffic"] {
+                        ui.label(RichText::new(header).strong());
+                    }
+                    ui.end_row();
+                    
+                    for channel in &stats.per_channel {
+                        ui.label(RichText::new("●").color(load_color(channel)));
+                        if channel.recording_active {
+                            ui.label(style::body_text(&format!("{} (recording)", channel.name)));
+                        } else {
+                            ui.label(style::body_text(&channel.name));
+                        }
+                        ui.label(channel.members.to_string());
+                        ui.label(channel.voice_streams.to_string());
+                        ui.label(channel.video_streams.to_string());
+                        ui.label(format_rate(channel.bytes_forwarded_per_sec));
+                        ui.end_row();
+                    }
+                });
+        });
+        
+        refresh
+    }
+    
+    fn sparkline(&self, ui: &mut Ui) {
+        let (rect, response) = ui.allocate_exact_size(Vec2::new(ui.available_width(), SPARKLINE_HEIGHT), Sense::hover());
+        let painter = ui.painter();
+        painter.rect_filled(rect, 2.0, style::SECONDARY_BACKGROUND);
+        
+        let points = sparkline_points(&self.connections, rect);
+        for pair in points.windows(2) {
+            painter.line_segment([pair[0], pair[1]], Stroke::new(1.5, style::ACCENT_COLOR));
+        }
+        
+        if let (Some(min), Some(max)) = (self.connections.iter().min(), self.connections.iter().max()) {
+            response.on_hover_text(format!("{} to {} connections over the last {} updates", min, max, self.connections.len()));
+        }
+    }
+}
+
+pub fn is_admin(stats: Option<&ServerStatistics>, user_id: Option<Uuid>) -> bool {
+    match (stats, user_id) {
+        (Some(stats), Some(user_id)) => stats.admin_ids.contains(&user_id),
+        _ => false,
+    }
+}
+
+// Keep only the last HISTORY_LEN values
+fn push_windowed(history: &mut VecDeque<u32>, value: u32) {
+    if history.len() == HISTORY_LEN {
+        history.pop_front();
+    }
+    history.push_back(value);
+}
+
+// The history spread across the rect, scaled so the busiest update touches the top. The newest
+// value is always at the right edge, so a short history fills in from the right.
+fn sparkline_points(history: &VecDeque<u32>, rect: Rect) -> Vec<Pos2> {
+    let max = history.iter().copied().max().unwrap_or(0).max(1) as f32;
+    let step = rect.width() / (HISTORY_LEN - 1) as f32;
+    let offset = HISTORY_LEN - history.len();
+    
+    history
+        .iter()
+        .enumerate()
+        .map(|(i, &value)| {
+            Pos2::new(
+                rect.left() + (offset + i) as f32 * step,
+                rect.bottom() - value as f32 / max * rect.height(),
+            )
+        })
+        .collect()
+}
+
+fn load_color(channel: &ChannelStats) -> Color32 {
+    match channel.bytes_forwarded_per_sec {
+        rate if rate >= OVERLOADED_BYTES_PER_SEC => style::ERROR_COLOR,
+        rate if rate >= BUSY_BYTES_PER_SEC => style::AWAY_COLOR,
+        _ => style::SUCCESS_COLOR,
+    }
+}
+
+fn format_rate(bytes_per_sec: u64) -> String {
+    if bytes_per_sec >= 1024 * 1024 {
+        format!("{:.1} MB/s", bytes_per_sec as f64 / (1024.0 * 1024.0))
+    } else if bytes_per_sec >= 1024 {
+        format!("{:.1} KB/s", bytes_per_sec as f64 / 1024.0)
+    } else {
+        format!("{} B/s", bytes_per_sec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn stats(admin_ids: Vec<Uuid>) -> ServerStatistics {
+        ServerStatistics {
+            total_connections: 1,
+            per_channel: Vec::new(),
+            admin_ids,
+        }
+    }
+    
+    #[test]
+    fn history_keeps_the_last_updates() {
+        let mut history = VecDeque::new();
+        for value in 0..100 {
+            push_windowed(&mut history, value);
+        }
+        
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert_eq!(history.front(), Some(&40));
+        assert_eq!(history.back(), Some(&99));
+    }
+    
+    #[test]
+    fn sparkline_ends_at_the_right_and_peaks_at_the_top() {
+        let rect = Rect::from_min_size(Pos2::ZERO, Vec2::new(590.0, 40.0));
+        let history: VecDeque<u32> = [2, 4, 0].into_iter().collect();
+        
+        let points = sparkline_points(&history, rect);
+        
+        assert_eq!(points, vec![Pos2::new(570.0, 20.0), Pos2::new(580.0, 0.0), Pos2::new(590.0, 40.0)]);
+    }
+    
+    #[test]
+    fn only_listed_admins_see_the_panel() {
+        let admin = Uuid::from_u128(1);
+        let stats = stats(vec![admin]);
+        
+        assert!(is_admin(Some(&stats), Some(admin)));
+        assert!(!is_admin(Some(&stats), Some(Uuid::from_u128(2))));
+        assert!(!is_admin(Some(&stats), None));
+        assert!(!is_admin(None, Some(admin)));
+    }
+}
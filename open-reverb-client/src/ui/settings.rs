@@ -1,9 +1,17 @@
-use egui::{Button, ComboBox, Slider, Ui, Window};
+use egui::{Button, ComboBox, Slider, TextureHandle, Ui, Window};
+use std::sync::Arc;
 
-use crate::audio::AudioManager;
-use crate::config::{ClientConfig, Theme};
+use crate::audio;
+use crate::config::{ClientConfig, CustomTheme, Theme, Transport};
+use crate::connection::{tls, Connection};
+use crate::crash_reporter::CrashReporter;
 use crate::ui::style;
-use crate::video::VideoManager;
+use crate::ui::widgets::WaveformDisplay;
+use crate::video::encoding::VideoEncoding;
+use crate::video::{self, CaptureType, VideoManager};
+
+// Size of the camera preview thumbnail
+const PREVIEW_SIZE: egui::Vec2 = egui::vec2(240.0, 180.0);
 
 pub struct SettingsScreen {
     config: ClientConfig,
@@ -11,14 +19,35 @@ pub struct SettingsScreen {
     available_audio_inputs: Vec<String>,
     available_audio_outputs: Vec<String>,
     available_video_devices: Vec<String>,
+    available_screens: Vec<String>,
+    // Fingerprint of the certificate the connected server presented, if any
+    server_cert_fingerprint: Option<String>,
+    certificate_error: Option<String>,
+    // Contents of the most recent crash report, while it is being viewed
+    viewed_crash_report: Option<String>,
+    // The microphone, while audio is on
+    waveform: WaveformDisplay,
+    // Camera, while previewing how the background blur looks
+    camera_preview: Option<VideoManager>,
+    preview_texture: Option<TextureHandle>,
+    preview_error: Option<String>,
+    // Server echo test: hearing our own voice back through the server, and how long it took
+    loopback_test: bool,
+    loopback_latency_ms: Option<u64>,
+    // Set when the test was switched on or off, until the app has told the server
+    loopback_changed: Option<bool>,
+    // Custom theme JSON pasted in to import, and why the last import failed
+    theme_import: String,
+    theme_import_error: Option<String>,
+    // Set while waiting for the key to use for push-to-talk
+    capturing_ptt_key: bool,
 }
 
 impl SettingsScreen {
     pub fn new(config: ClientConfig) -> Self {
         // Get available devices
-        // In a real implementation, we would get these from the OS
-        let available_audio_inputs = vec!["Default Microphone".to_string(), "Headset Microphone".to_string()];
-        let available_audio_outputs = vec!["Default Speakers".to_string(), "Headphones".to_string()];
+        let available_audio_inputs = audio::input_device_names();
+        let available_audio_outputs = audio::output_device_names();
         let available_video_devices = VideoManager::get_available_video_devices();
         
         // If we have no video devices, add a placeholder
@@ -28,14 +57,52 @@ impl SettingsScreen {
             available_video_devices
         };
         Self {
+            waveform: WaveformDisplay::new(config.waveform_color),
             config,
             modified: false,
             available_audio_inputs,
             available_audio_outputs,
             available_video_devices,
+            available_screens: VideoManager::get_available_screens(),
+            server_cert_fingerprint: None,
+            certificate_error: None,
+            viewed_crash_report: None,
+            camera_preview: None,
+            preview_texture: None,
+            preview_error: None,
+            loopback_test: false,
+            loopback_latency_ms: None,
+            loopback_changed: None,
+            theme_import: String::new(),
+            theme_import_error: None,
+            capturing_ptt_key: false,
         }
     }
     
+    pub fn push_waveform_sample(&mut self, rms: f32) {
+        self.waveform.push(rms);
+    }
+    
+    // Round trip time of the latest echoed voice frame
+    pub fn set_loopback_latency(&mut self, latency_ms: u64) {
+        if self.loopback_test {
+            self.loopback_latency_ms = Some(latency_ms);
+        }
+    }
+    
+    pub fn is_loopback_test_on(&self) -> bool {
+        self.loopback_test
+    }
+    
+    // Whether the loopback test was just switched on (true) or off (false)
+    pub fn take_loopback_change(&mut self) -> Option<bool> {
+        self.loopback_changed.take()
+    }
+    
+    pub fn set_server_cert_fingerprint(&mut self, fingerprint: Option<String>) {
+        self.server_cert_fingerprint = fingerprint;
+    }
+    
     pub fn show(&mut self, ctx: &egui::Context, open: &mut bool) -> Option<ClientConfig> {
         let mut result = None;
         let is_open = *open;
@@ -63,11 +130,146 @@ impl SettingsScreen {
                     }
                 });
                 
+                ui.horizontal(|ui| {
+                    ui.label("Transport:");
+                    ComboBox::from_id_source("transport_selector")
+                        .selected_text(self.transport_name(self.config.transport))
+                        .show_ui(ui, |ui| {
+                            for transport in [Transport::Tcp, Transport::Tls, Transport::WebSocket] {
+                                if ui.selectable_label(self.config.transport == transport, self.transport_name(transport)).clicked() {
+                                    self.config.transport = transport;
+                                    self.modified = true;
+                                }
+                            }
+                        });
+                });
+                
+                if self.config.transport == Transport::Tls {
+                    if let Some(fingerprint) = &self.server_cert_fingerprint {
+                        ui.label(style::secondary_text(&format!("Server certificate: {}", fingerprint)));
+                    }
+                    
+                    match &self.config.pinned_cert_fingerprint {
+                        Some(pinned) => {
+                            ui.label(style::secondary_text(&format!("Pinned certificate: {}", pinned)));
+                            if ui.button("Unpin Certificate").clicked() {
+                                self.config.pinned_cert_fingerprint = None;
+                                self.modified = true;
+                            }
+                        }
+                        None => {
+                            if ui.button("Pin Current Certificate").clicked() {
+                                match tls::fetch_fingerprint(&self.config.server_url) {
+                                    Ok(fingerprint) => {
+                                        self.config.pinned_cert_fingerprint = Some(fingerprint);
+                                        self.certificate_error = None;
+                                        self.modified = true;
+                                    }
+                                    Err(e) => self.certificate_error = Some(e.to_string()),
+                                }
+                            }
+                        }
+                    }
+                    
+                    if let Some(error) = &self.certificate_error {
+                        ui.label(style::error_text(error));
+                    }
+                    
+                    if ui.checkbox(&mut self.config.trust_on_first_use, "Pin the certificate on first connection").changed() {
+                        self.modified = true;
+                    }
+                }
+                
+                ui.add_space(10.0);
+                
+                // Only used by the connection test
+                ui.horizontal(|ui| {
+                    ui.label("STUN Server:");
+                    let mut url = self.config.stun_server.clone().unwrap_or_default();
+                    if ui.text_edit_singleline(&mut url).changed() {
+                        self.config.stun_server = (!url.is_empty()).then_some(url);
+                        self.modified = true;
+                    }
+                });
+                
+                ui.add_space(20.0);
+                
+                // Reconnect backoff
+                ui.heading(style::subheading("Connection"));
+                
+                if ui.checkbox(&mut self.config.use_reconnect_config, "Custom Reconnect Settings").changed() {
+                    self.modified = true;
+                }
+                
+                if self.config.use_reconnect_config {
+                    let reconnect = &mut self.config.reconnect;
+                    
+                    ui.horizontal(|ui| {
+                        ui.label("First Retry After (ms):");
+                        if ui.add(Slider::new(&mut reconnect.initial_delay_ms, 100..=10_000)).changed() {
+                            self.modified = true;
+                        }
+                    });
+                    
+                    ui.horizontal(|ui| {
+                        ui.label("Longest Wait (ms):");
+                        if ui.add(Slider::new(&mut reconnect.max_delay_ms, 1_000..=120_000)).changed() {
+                            self.modified = true;
+                        }
+                    });
+                    
+                    ui.horizontal(|ui| {
+                        ui.label("Backoff Multiplier:");
+                        if ui.add(Slider::new(&mut reconnect.multiplier, 1.0..=5.0)).changed() {
+                            self.modified = true;
+                        }
+                    });
+                    
+                    if ui.checkbox(&mut reconnect.jitter, "Randomize Delays").changed() {
+                        self.modified = true;
+                    }
+                    
+                    let mut limit_attempts = reconnect.max_attempts.is_some();
+                    if ui.checkbox(&mut limit_attempts, "Limit Attempts").changed() {
+                        reconnect.max_attempts = limit_attempts.then_some(10);
+                        self.modified = true;
+                    }
+                    
+                    if let Some(max_attempts) = &mut reconnect.max_attempts {
+                        ui.horizontal(|ui| {
+                            ui.label("Max Attempts:");
+                            if ui.add(Slider::new(max_attempts, 1..=50)).changed() {
+                                self.modified = true;
+                            }
+                        });
+                    }
+                }
+                
+                let mut limit_upload = self.config.max_upload_kbps.is_some();
+                if ui.checkbox(&mut limit_upload, "Limit Upload")
+                    .on_hover_text("Lower the video bitrate and voice quality to stay under a limit, for metered connections")
+                    .changed()
+                {
+                    self.config.max_upload_kbps = limit_upload.then_some(1000);
+                    self.modified = true;
+                }
+                
+                if let Some(max_upload_kbps) = &mut self.config.max_upload_kbps {
+                    ui.horizontal(|ui| {
+                        ui.label("Max Upload (kbps):");
+                        if ui.add(Slider::new(max_upload_kbps, 64..=10_000).logarithmic(true)).changed() {
+                            self.modified = true;
+                        }
+                    });
+                }
+                
                 ui.add_space(20.0);
                 
                 // User interface settings
                 ui.heading(style::subheading("User Interface"));
                 
+                let theme_before = (self.config.theme, self.config.custom_theme);
+                
                 ui.horizontal(|ui| {
                     ui.label("Theme:");
                     ComboBox::from_id_source("theme_selector")
@@ -85,9 +287,22 @@ impl SettingsScreen {
                                 self.config.theme = Theme::System;
                                 self.modified = true;
                             }
+                            if ui.selectable_label(self.config.theme == Theme::Custom, "Custom").clicked() {
+                                self.config.theme = Theme::Custom;
+                                self.modified = true;
+                            }
                         });
                 });
                 
+                if self.config.theme == Theme::Custom {
+                    self.render_custom_theme(ui);
+                }
+                
+                // Shown straight away; the app puts the saved theme back if settings are closed without saving
+                if (self.config.theme, self.config.custom_theme) != theme_before {
+                    style::apply_theme(ui.ctx(), self.config.theme, &self.config.custom_theme);
+                }
+                
                 ui.add_space(10.0);
                 
                 if ui.checkbox(&mut self.config.notification_sounds, "Notification Sounds").changed() {
@@ -98,6 +313,10 @@ impl SettingsScreen {
                     self.modified = true;
                 }
                 
+                if ui.checkbox(&mut self.config.transcription_enabled, "Show Voice Transcripts").changed() {
+                    self.modified = true;
+                }
+                
                 ui.add_space(20.0);
                 
                 // Audio settings
@@ -110,6 +329,10 @@ impl SettingsScreen {
                     ComboBox::from_id_source("audio_input_selector")
                         .selected_text(&selected_input)
                         .show_ui(ui, |ui| {
+                            if ui.selectable_label(self.config.audio_input_device.is_none(), "Default").clicked() {
+                                self.config.audio_input_device = None;
+                                self.modified = true;
+                            }
                             for device in &self.available_audio_inputs {
                                 if ui.selectable_label(
                                     self.config.audio_input_device.as_ref() == Some(device),
@@ -129,6 +352,10 @@ impl SettingsScreen {
                     ComboBox::from_id_source("audio_output_selector")
                         .selected_text(&selected_output)
                         .show_ui(ui, |ui| {
+                            if ui.selectable_label(self.config.audio_output_device.is_none(), "Default").clicked() {
+                                self.config.audio_output_device = None;
+                                self.modified = true;
+                            }
                             for device in &self.available_audio_outputs {
                                 if ui.selectable_label(
                                     self.config.audio_output_device.as_ref() == Some(device),
@@ -156,6 +383,178 @@ impl SettingsScreen {
                     }
                 });
                 
+                if ui.checkbox(&mut self.config.noise_reduction_enabled, "Reduce Background Noise").changed() {
+                    self.modified = true;
+                }
+                
+                ui.add_enabled_ui(self.config.noise_reduction_enabled, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Noise Reduction Strength:");
+                        if ui.add(Slider::new(&mut self.config.noise_reduction_strength, 0.0..=1.0)).changed() {
+                            self.modified = true;
+                        }
+                    });
+                });
+                
+                ui.horizontal(|ui| {
+                    ui.label("Jitter Buffer:");
+                    if ui.add(Slider::new(&mut self.config.jitter_buffer_ms, 0..=audio::MAX_JITTER_BUFFER_MS).suffix(" ms"))
+                        .on_hover_text("How much of each person's voice to hold before playing it. More smooths over a bad connection, but adds delay.")
+                        .changed()
+                    {
+                        self.modified = true;
+                    }
+                });
+                
+                if ui.checkbox(&mut self.config.delay_compensation_enabled, "Delay Compensation")
+                    .on_hover_text("Holds back voices that arrive sooner, so everyone plays in step.")
+                    .changed()
+                {
+                    self.modified = true;
+                }
+                
+                #[cfg(feature = "aec")]
+                {
+                    if ui.checkbox(&mut self.config.echo_cancellation, "Echo Cancellation")
+                        .on_hover_text("Stops others hearing themselves through your speakers. Not needed with headphones.")
+                        .changed()
+                    {
+                        self.modified = true;
+                    }
+                    
+                    ui.add_enabled_ui(self.config.echo_cancellation, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("Speaker Delay:");
+                            if ui.add(Slider::new(&mut self.config.aec_delay_ms, 0..=audio::MAX_AEC_DELAY_MS).suffix(" ms"))
+                                .on_hover_text("How long sound takes from your speakers to your microphone, including the devices' buffering")
+                                .changed()
+                            {
+                                self.modified = true;
+                            }
+                        });
+                    });
+                }
+                
+                ui.horizontal(|ui| {
+                    ui.label("Voice Bitrate:");
+                    if ui.add(Slider::new(&mut self.config.voice_bitrate_kbps, 16..=128).suffix(" kbps"))
+                        .on_hover_text("Higher sounds better but uses more bandwidth. Channels can ask for less.")
+                        .changed()
+                    {
+                        self.modified = true;
+                    }
+                });
+                
+                if ui.checkbox(&mut self.config.agc_enabled, "Automatic Gain Control")
+                    .on_hover_text("Turn quiet microphones up and loud ones down")
+                    .changed()
+                {
+                    self.modified = true;
+                }
+                
+                ui.add_enabled_ui(self.config.agc_enabled, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Target Level:");
+                        if ui.add(Slider::new(&mut self.config.agc_target_dbfs, -40.0..=-6.0).suffix(" dBFS")).changed() {
+                            self.modified = true;
+                        }
+                    });
+                });
+                
+                if ui.checkbox(&mut self.config.vad_enabled, "Voice Activity Detection")
+                    .on_hover_text("Only send your microphone while it picks up your voice")
+                    .changed()
+                {
+                    self.modified = true;
+                }
+                
+                ui.add_enabled_ui(self.config.vad_enabled, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Sensitivity:");
+                        if ui.add(Slider::new(&mut self.config.vad_threshold, 0.001..=0.2).logarithmic(true))
+                            .on_hover_text("How loud the microphone has to be to count as voice; lower picks up quieter speech")
+                            .changed()
+                        {
+                            self.modified = true;
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Hold Open For:");
+                        if ui.add(Slider::new(&mut self.config.vad_hangover_frames, 0..=50).custom_formatter(|frames, _| format!("{} ms", frames * 20.0)))
+                            .on_hover_text("How long to keep sending after you stop talking")
+                            .changed()
+                        {
+                            self.modified = true;
+                        }
+                    });
+                });
+                
+                if ui.checkbox(&mut self.config.ptt_enabled, "Push to Talk")
+                    .on_hover_text("Only send your microphone while a key is held")
+                    .changed()
+                {
+                    self.modified = true;
+                }
+                
+                ui.add_enabled_ui(self.config.ptt_enabled, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Talk Key:");
+                        let label = if self.capturing_ptt_key {
+                            "Press a key..."
+                        } else {
+                            self.config.ptt_key.as_deref().unwrap_or("Not set")
+                        };
+                        if ui.button(label).on_hover_text("Click, then press the key to hold while talking").clicked() {
+                            self.capturing_ptt_key = true;
+                        }
+                    });
+                });
+                
+                if self.capturing_ptt_key {
+                    let pressed = ui.input(|i| {
+                        i.events.iter().find_map(|event| match event {
+                            egui::Event::Key { key, pressed: true, .. } => Some(*key),
+                            _ => None,
+                        })
+                    });
+                    match pressed {
+                        // Escape cancels, keeping the key there was
+                        Some(egui::Key::Escape) => self.capturing_ptt_key = false,
+                        Some(key) => {
+                            self.config.ptt_key = Some(key.name().to_string());
+                            self.capturing_ptt_key = false;
+                            self.modified = true;
+                        }
+                        None => {}
+                    }
+                }
+                
+                ui.horizontal(|ui| {
+                    ui.label("Waveform Colour:");
+                    if ui.color_edit_button_srgba(&mut self.config.waveform_color).changed() {
+                        self.waveform.set_color(self.config.waveform_color);
+                        self.modified = true;
+                    }
+                });
+                self.waveform.render(ui, egui::vec2(ui.available_width(), 60.0))
+                    .on_hover_text("Your microphone over the last minute, while audio is on");
+                
+                if ui.checkbox(&mut self.loopback_test, "Server echo test (hear your own voice)")
+                    .on_hover_text("Loopback test: the server sends your voice back, so you hear it after the full round trip")
+                    .changed()
+                {
+                    self.loopback_changed = Some(self.loopback_test);
+                    self.loopback_latency_ms = None;
+                }
+                
+                if self.loopback_test {
+                    let latency = match self.loopback_latency_ms {
+                        Some(ms) => format!("Round trip: {} ms", ms),
+                        None => "Turn audio on and speak to measure the round trip".to_string(),
+                    };
+                    ui.label(style::secondary_text(&latency));
+                }
+                
                 ui.add_space(20.0);
                 
                 // Video settings
@@ -180,8 +579,193 @@ impl SettingsScreen {
                         });
                 });
                 
+                ui.horizontal(|ui| {
+                    ui.label("Screen:");
+                    let selected_screen = self.available_screens
+                        .get(self.config.screen_index as usize)
+                        .cloned()
+                        .unwrap_or_else(|| "Default".to_string());
+                    ComboBox::from_id_source("screen_selector")
+                        .selected_text(&selected_screen)
+                        .show_ui(ui, |ui| {
+                            for (index, screen) in self.available_screens.iter().enumerate() {
+                                if ui.selectable_label(self.config.screen_index as usize == index, screen).clicked() {
+                                    self.config.screen_index = index as u32;
+                                    self.modified = true;
+                                }
+                            }
+                        });
+                });
+                
+                if ui.checkbox(&mut self.config.background_blur_enabled, "Blur Background")
+                    .on_hover_text("Blur what's behind you, keeping you sharp")
+                    .changed()
+                {
+                    self.modified = true;
+                }
+                
+                ui.add_enabled_ui(self.config.background_blur_enabled, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Blur Strength:");
+                        if ui.add(Slider::new(&mut self.config.blur_strength, 0.0..=1.0)).changed() {
+                            self.modified = true;
+                        }
+                    });
+                });
+                
+                if ui.checkbox(&mut self.config.privacy_mode_on_focus_loss, "Privacy Mode When Unfocused")
+                    .on_hover_text("Blur and pause your camera while Open Reverb isn't the focused window")
+                    .changed()
+                {
+                    self.modified = true;
+                }
+                
+                ui.horizontal(|ui| {
+                    ui.label("Video Quality:");
+                    let selected = (self.config.selected_video_resolution, self.config.selected_video_framerate);
+                    ComboBox::from_id_source("video_quality_selector")
+                        .selected_text(video::video_preset_label(selected.0, selected.1))
+                        .show_ui(ui, |ui| {
+                            for (resolution, framerate) in video::VIDEO_PRESETS {
+                                if ui.selectable_label(selected == (resolution, framerate), video::video_preset_label(resolution, framerate)).clicked() {
+                                    self.config.selected_video_resolution = resolution;
+                                    self.config.selected_video_framerate = framerate;
+                                    self.modified = true;
+                                }
+                            }
+                        })
+                        .response
+                        .on_hover_text("Lower this on a weak connection. Takes effect the next time video starts.");
+                });
+                
+                ui.horizontal(|ui| {
+                    ui.label("Video Encoding:");
+                    ComboBox::from_id_source("video_encoding_selector")
+                        .selected_text(self.config.video_encoding.label())
+                        .show_ui(ui, |ui| {
+                            let jpeg = match self.config.video_encoding {
+                                VideoEncoding::Jpeg { quality } => VideoEncoding::Jpeg { quality },
+                                _ => VideoEncoding::default(),
+                            };
+                            for encoding in [jpeg, VideoEncoding::H264, VideoEncoding::Raw] {
+                                if ui.selectable_label(self.config.video_encoding == encoding, encoding.label()).clicked() {
+                                    self.config.video_encoding = encoding;
+                                    self.modified = true;
+                                }
+                            }
+                        })
+                        .response
+                        .on_hover_text("H.264 is sent as JPEG until the capture pipeline has an H.264 encoder");
+                });
+                
+                if let VideoEncoding::Jpeg { quality } = &mut self.config.video_encoding {
+                    ui.horizontal(|ui| {
+                        ui.label("JPEG Quality:");
+                        if ui.add(Slider::new(quality, 1..=100)).changed() {
+                            self.modified = true;
+                        }
+                    });
+                }
+                
+                if ui.checkbox(&mut self.config.hardware_video_decode, "Hardware Video Decoding")
+                    .on_hover_text("Decode H.264 video on the GPU, falling back to the CPU when it can't")
+                    .changed()
+                {
+                    self.modified = true;
+                }
+                
+                self.render_camera_preview(ui);
+                
                 ui.add_space(20.0);
                 
+                // Crash reporting
+                ui.heading(style::subheading("Crash Reports"));
+                
+                if ui.checkbox(&mut self.config.send_crash_reports, "Send Crash Reports").changed() {
+                    self.modified = true;
+                }
+                
+                ui.horizontal(|ui| {
+                    ui.label("Report URL:");
+                    let mut url = self.config.crash_report_url.clone().unwrap_or_default();
+                    if ui.text_edit_singleline(&mut url).changed() {
+                        self.config.crash_report_url = (!url.is_empty()).then_some(url);
+                        self.modified = true;
+                    }
+                });
+                
+                match CrashReporter::pending_reports().last() {
+                    Some(latest) => {
+                        let label = if self.viewed_crash_report.is_some() { "Hide Crash Report" } else { "View Crash Report" };
+                        if ui.button(label).clicked() {
+                            self.viewed_crash_report = match self.viewed_crash_report {
+                                Some(_) => None,
+                                None => Some(std::fs::read_to_string(latest).unwrap_or_else(|e| e.to_string())),
+                            };
+                        }
+                    }
+                    None => {
+                        ui.label(style::secondary_text("No crash reports"));
+                    }
+                }
+                
+                if let Some(report) = &self.viewed_crash_report {
+                    egui::ScrollArea::vertical()
+                        .id_source("crash_report")
+                        .max_height(200.0)
+                        .show(ui, |ui| {
+                            ui.monospace(report);
+                        });
+                }
+                
+                ui.add_space(20.0);
+                
+                // Network simulation for testing under poor conditions, debug builds only
+                #[cfg(debug_assertions)]
+                {
+                    ui.heading(style::subheading("Developer"));
+                    
+                    let mut simulate = self.config.simulate_network.is_some();
+                    if ui.checkbox(&mut simulate, "Simulate Poor Network").changed() {
+                        self.config.simulate_network = simulate.then(crate::config::NetworkSimParams::default);
+                        self.modified = true;
+                    }
+                    
+                    if let Some(params) = &mut self.config.simulate_network {
+                        ui.horizontal(|ui| {
+                            ui.label("Latency (ms):");
+                            if ui.add(Slider::new(&mut params.latency_ms, 0..=2000)).changed() {
+                                self.modified = true;
+                            }
+                        });
+                        
+                        ui.horizontal(|ui| {
+                            ui.label("Jitter (ms):");
+                            if ui.add(Slider::new(&mut params.jitter_ms, 0..=500)).changed() {
+                                self.modified = true;
+                            }
+                        });
+                        
+                        ui.horizontal(|ui| {
+                            ui.label("Drop Rate:");
+                            if ui.add(Slider::new(&mut params.drop_rate, 0.0..=1.0)).changed() {
+                                self.modified = true;
+                            }
+                        });
+                        
+                        ui.horizontal(|ui| {
+                            ui.label("Corrupt Rate:");
+                            if ui.add(Slider::new(&mut params.corrupt_rate, 0.0..=1.0)).changed() {
+                                self.modified = true;
+                            }
+                        });
+                        
+                        ui.label(style::secondary_text("Takes effect on the next connection"));
+                    }
+                    
+                    ui.add_space(20.0);
+                }
+                
                 // Buttons
                 ui.separator();
                 ui.add_space(10.0);
@@ -210,15 +794,153 @@ impl SettingsScreen {
         result
     }
     
+    fn render_custom_theme(&mut self, ui: &mut Ui) {
+        let theme = &mut self.config.custom_theme;
+        let colors = [
+            ("Background:", &mut theme.background),
+            ("Accent:", &mut theme.accent),
+            ("Text:", &mut theme.text),
+            ("Secondary Text:", &mut theme.secondary_text),
+        ];
+        
+        for (label, color) in colors {
+            ui.horizontal(|ui| {
+                ui.label(label);
+                if ui.color_edit_button_srgb(color).changed() {
+                    self.modified = true;
+                }
+            });
+        }
+        
+        ui.horizontal(|ui| {
+            if ui.button("Export").on_hover_text("Copy this theme to the clipboard to share it").clicked() {
+                ui.output_mut(|o| o.copied_text = self.config.custom_theme.to_json());
+            }
+            if ui.button("Reset").clicked() {
+                self.config.custom_theme = CustomTheme::default();
+                self.modified = true;
+            }
+        });
+        
+        // Pasted in with Ctrl+V, as egui can't read the clipboard on its own
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut self.theme_import).hint_text("Paste a shared theme"));
+            if ui.add_enabled(!self.theme_import.trim().is_empty(), Button::new("Import")).clicked() {
+                match CustomTheme::from_json(&self.theme_import) {
+                    Ok(theme) => {
+                        self.config.custom_theme = theme;
+                        self.modified = true;
+                        self.theme_import.clear();
+                        self.theme_import_error = None;
+                    }
+                    Err(e) => self.theme_import_error = Some(format!("That isn't a theme: {}", e)),
+                }
+            }
+        });
+        
+        if let Some(error) = &self.theme_import_error {
+            ui.label(style::error_text(error));
+        }
+    }
+    
+    fn render_camera_preview(&mut self, ui: &mut Ui) {
+        if self.camera_preview.is_none() {
+            if ui.button("Preview Camera").clicked() {
+                self.start_camera_preview();
+            }
+        } else if ui.button("Stop Preview").clicked() {
+            self.stop_camera_preview();
+        }
+        
+        if let Some(error) = &self.preview_error {
+            ui.label(style::error_text(error));
+        }
+        
+        if let Some(camera_preview) = &self.camera_preview {
+            // Follows the settings as they're changed, before they're saved
+            camera_preview.set_background_blur(self.config.background_blur_enabled, self.config.blur_strength);
+            
+            // Already initialized by starting the preview, so this doesn't hold up the frame
+            if !VideoManager::is_gstreamer_available() {
+                ui.label(style::secondary_text("GStreamer isn't available, so this is a test pattern"));
+            }
+            
+            if let Some(frame) = camera_preview.preview_frame() {
+                let (width, height) = camera_preview.get_dimensions();
+                let image = egui::ColorImage::from_rgb([width as usize, height as usize], &frame);
+                
+                match &mut self.preview_texture {
+                    Some(texture) => texture.set(image, Default::default()),
+                    None => self.preview_texture = Some(ui.ctx().load_texture("settings_camera_preview", image, Default::default())),
+                }
+            }
+            ui.ctx().request_repaint();
+        }
+        
+        match &self.preview_texture {
+            Some(texture) => {
+                ui.add(egui::Image::new((texture.id(), PREVIEW_SIZE)));
+            }
+            None if self.camera_preview.is_some() => {
+                ui.label(style::secondary_text("Waiting for the camera..."));
+            }
+            None => {}
+        }
+    }
+    
+    fn start_camera_preview(&mut self) {
+        // The preview never sends anything, so it doesn't need a live connection
+        let mut camera_preview = VideoManager::new(
+            uuid::Uuid::nil(),
+            uuid::Uuid::nil(),
+            Arc::new(Connection::new()),
+            CaptureType::Camera,
+            self.config.preferred_video_codec,
+            self.config.video_send_buffer_frames,
+        );
+        
+        if let Some(device) = &self.config.video_device {
+            camera_preview.set_device(device);
+        }
+        camera_preview.set_background_blur(self.config.background_blur_enabled, self.config.blur_strength);
+        camera_preview.set_format(self.config.selected_video_resolution, self.config.selected_video_framerate);
+        
+        match camera_preview.start_preview() {
+            Ok(()) => {
+                self.camera_preview = Some(camera_preview);
+                self.preview_error = None;
+            }
+            Err(e) => self.preview_error = Some(format!("Couldn't start the camera: {}", e)),
+        }
+    }
+    
+    fn stop_camera_preview(&mut self) {
+        if let Some(mut camera_preview) = self.camera_preview.take() {
+            camera_preview.stop();
+        }
+        self.preview_texture = None;
+    }
+    
+    fn transport_name(&self, transport: Transport) -> &'static str {
+        match transport {
+            Transport::Tcp => "TCP",
+            Transport::Tls => "TLS",
+            Transport::WebSocket => "WebSocket",
+        }
+    }
+    
     fn theme_name(&self, theme: Theme) -> &'static str {
         match theme {
             Theme::Light => "Light",
             Theme::Dark => "Dark",
             Theme::System => "System",
+            Theme::Custom => "Custom",
         }
     }
-    
-    pub fn is_modified(&self) -> bool {
-        self.modified
+}
+
+impl Drop for SettingsScreen {
+    fn drop(&mut self) {
+        self.stop_camera_preview();
     }
-}
\ No newline at end of file
+}
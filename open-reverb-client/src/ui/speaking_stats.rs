@@ -0,0 +1,122 @@
+use egui::{Align2, FontId, Rounding, Sense, Vec2, Window};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use open_reverb_common::models::User;
+use crate::ui::style;
+
+// How often the stats are asked for again while the panel is open
+const REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+const BAR_HEIGHT: f32 = 18.0;
+const NAME_WIDTH: f32 = 120.0;
+const DURATION_WIDTH: f32 = 64.0;
+
+// How long each member of the current channel has spoken, as a bar chart
+pub struct SpeakingStatsPanel {
+    open: bool,
+    channel_id: Option<Uuid>,
+    // Milliseconds per user, most first
+    stats: Vec<(Uuid, u64)>,
+    last_requested: Option<Instant>,
+}
+
+impl SpeakingStatsPanel {
+    pub fn new() -> Self {
+        Self {
+            open: false,
+            channel_id: None,
+            stats: Vec::new(),
+            last_requested: None,
+        }
+    }
+    
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+    
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        self.last_requested = None;
+    }
+    
+    pub fn set_stats(&mut self, channel_id: Uuid, mut stats: Vec<(Uuid, u64)>) {
+        stats.sort_by_key(|(_, ms)| std::cmp::Reverse(*ms));
+        self.channel_id = Some(channel_id);
+        self.stats = stats;
+    }
+    
+    // Forget the stats of a channel that was left, and close the panel
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+    
+    // Whether to ask the server for fresh stats of `channel_id` now
+    pub fn refresh_due(&mut self, channel_id: Uuid) -> bool {
+        if !self.open {
+            return false;
+        }
+        
+        if self.channel_id != Some(channel_id) {
+            self.stats.clear();
+        }
+        
+        let due = self.last_requested.is_none_or(|t| t.elapsed() >= REFRESH_INTERVAL);
+        if due {
+            self.last_requested = Some(Instant::now());
+        }
+        due
+    }
+    
+    pub fn show(&mut self, ctx: &egui::Context, users: &[User]) {
+        let mut open = self.open;
+        
+        Window::new("Session Stats")
+            .open(&mut open)
+            .default_width(360.0)
+            .show(ctx, |ui| {
+                ui.label(style::secondary_text("Speaking time since joining the channel"));
+                ui.separator();
+                
+                if self.stats.is_empty() {
+                    ui.label(style::secondary_text("No speaking time recorded yet"));
+                    return;
+                }
+                
+                let most = self.stats[0].1.max(1) as f32;
+                for &(user_id, ms) in &self.stats {
+                    let name = users.iter().find(|u| u.id == user_id).map_or("Unknown user", |u| u.username.as_str());
+                    
+                    let (rect, _) = ui.allocate_exact_size(Vec2::new(ui.available_width(), BAR_HEIGHT), Sense::hover());
+                    let painter = ui.painter();
+                    
+                    painter.text(rect.left_center(), Align2::LEFT_CENTER, name, FontId::proportional(13.0), style::TEXT_COLOR);
+                    
+                    let bar_space = (rect.width() - NAME_WIDTH - DURATION_WIDTH).max(0.0);
+                    let mut bar = rect;
+                    bar.set_left(rect.left() + NAME_WIDTH);
+                    bar.set_width((bar_space * ms as f32 / most).max(2.0));
+                    painter.rect_filled(bar.shrink2(Vec2::new(0.0, 2.0)), Rounding::same(2.0), style::ACCENT_COLOR);
+                    
+                    painter.text(
+                        rect.right_center(),
+                        Align2::RIGHT_CENTER,
+                        format_duration(ms),
+                        FontId::proportional(12.0),
+                        style::SECONDARY_TEXT_COLOR,
+                    );
+                }
+            });
+        
+        self.open = open;
+    }
+}
+
+fn format_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    if secs >= 3600 {
+        format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+    } else {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    }
+}
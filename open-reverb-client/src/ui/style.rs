@@ -1,4 +1,7 @@
 use egui::{Color32, Context, FontFamily, FontId, RichText, Stroke, TextStyle, Visuals};
+use std::sync::RwLock;
+
+use crate::config::{CustomTheme, Theme};
 
 // Color scheme
 pub const ACCENT_COLOR: Color32 = Color32::from_rgb(88, 101, 242); // Discord-like blue
@@ -13,6 +16,9 @@ pub const AWAY_COLOR: Color32 = Color32::from_rgb(250, 166, 26);
 pub const DND_COLOR: Color32 = Color32::from_rgb(237, 66, 69);
 pub const OFFLINE_COLOR: Color32 = Color32::from_rgb(116, 127, 141);
 
+// Text and secondary text colours of the theme in use, for the text helpers below
+static TEXT_COLORS: RwLock<(Color32, Color32)> = RwLock::new((TEXT_COLOR, SECONDARY_TEXT_COLOR));
+
 // Status colors
 pub fn status_color(status: open_reverb_common::models::UserStatus) -> Color32 {
     match status {
@@ -23,8 +29,19 @@ pub fn status_color(status: open_reverb_common::models::UserStatus) -> Color32 {
     }
 }
 
-// Apply the OpenReverb theme to the UI context
-pub fn setup_style(ctx: &Context) {
+// Color of a voice level bar for an RMS level (0.0 - 1.0): green, then yellow over 0.5 and red over 0.8
+pub fn level_color(level: f32) -> Color32 {
+    if level >= 0.8 {
+        ERROR_COLOR
+    } else if level >= 0.5 {
+        AWAY_COLOR
+    } else {
+        SUCCESS_COLOR
+    }
+}
+
+// Apply the OpenReverb theme to the UI context, with the custom colours when it's Custom
+pub fn setup_style(ctx: &Context, theme: Theme, custom: &CustomTheme) {
     let mut style = (*ctx.style()).clone();
     
     // Configure text styles
@@ -37,25 +54,54 @@ pub fn setup_style(ctx: &Context) {
     ]
     .into();
     
+    ctx.set_style(style);
+    apply_theme(ctx, theme, custom);
+}
+
+// Switch the UI's colours, e.g. while they're being changed in settings. Only the dark
+// palette is built in so far, so the other themes use it unless they're Custom.
+pub fn apply_theme(ctx: &Context, theme: Theme, custom: &CustomTheme) {
+    let (background, secondary_background, accent, accent_hover, text, secondary_text) = match theme {
+        Theme::Custom => {
+            let background = Color32::from_rgb(custom.background[0], custom.background[1], custom.background[2]);
+            let accent = Color32::from_rgb(custom.accent[0], custom.accent[1], custom.accent[2]);
+            (
+                background,
+                darken(background),
+                accent,
+                darken(accent),
+                Color32::from_rgb(custom.text[0], custom.text[1], custom.text[2]),
+                Color32::from_rgb(custom.secondary_text[0], custom.secondary_text[1], custom.secondary_text[2]),
+            )
+        }
+        Theme::Light | Theme::Dark | Theme::System => {
+            (BACKGROUND_COLOR, SECONDARY_BACKGROUND, ACCENT_COLOR, ACCENT_HOVER_COLOR, TEXT_COLOR, SECONDARY_TEXT_COLOR)
+        }
+    };
+    
+    let mut style = (*ctx.style()).clone();
+    
     // Set up dark theme
     let mut visuals = Visuals::dark();
     
     // Customize colors
-    visuals.widgets.noninteractive.bg_fill = BACKGROUND_COLOR;
-    visuals.widgets.noninteractive.fg_stroke = Stroke::new(1.0, TEXT_COLOR);
+    visuals.widgets.noninteractive.bg_fill = background;
+    visuals.widgets.noninteractive.fg_stroke = Stroke::new(1.0, text);
+    
+    visuals.widgets.inactive.bg_fill = secondary_background;
+    visuals.widgets.inactive.fg_stroke = Stroke::new(1.0, secondary_text);
     
-    visuals.widgets.inactive.bg_fill = SECONDARY_BACKGROUND;
-    visuals.widgets.inactive.fg_stroke = Stroke::new(1.0, SECONDARY_TEXT_COLOR);
+    visuals.widgets.active.bg_fill = accent;
+    visuals.widgets.active.fg_stroke = Stroke::new(1.0, text);
     
-    visuals.widgets.active.bg_fill = ACCENT_COLOR;
-    visuals.widgets.active.fg_stroke = Stroke::new(1.0, TEXT_COLOR);
+    visuals.widgets.hovered.bg_fill = accent_hover;
+    visuals.widgets.hovered.fg_stroke = Stroke::new(1.0, text);
     
-    visuals.widgets.hovered.bg_fill = ACCENT_HOVER_COLOR;
-    visuals.widgets.hovered.fg_stroke = Stroke::new(1.0, TEXT_COLOR);
+    visuals.selection.bg_fill = accent;
     
     // Window colors
-    visuals.window_fill = BACKGROUND_COLOR;
-    visuals.panel_fill = BACKGROUND_COLOR;
+    visuals.window_fill = background;
+    visuals.panel_fill = background;
     
     // Misc
     visuals.window_shadow.extrusion = 8.0;
@@ -64,23 +110,38 @@ pub fn setup_style(ctx: &Context) {
     style.visuals = visuals;
     
     ctx.set_style(style);
+    *TEXT_COLORS.write().unwrap() = (text, secondary_text);
+}
+
+// The shade used behind inactive widgets and for hovered accents
+fn darken(color: Color32) -> Color32 {
+    let scale = |c: u8| (c as f32 * 0.85) as u8;
+    Color32::from_rgb(scale(color.r()), scale(color.g()), scale(color.b()))
+}
+
+fn text_color() -> Color32 {
+    TEXT_COLORS.read().unwrap().0
+}
+
+fn secondary_text_color() -> Color32 {
+    TEXT_COLORS.read().unwrap().1
 }
 
 // Helper functions for text styling
 pub fn heading(text: &str) -> RichText {
-    RichText::new(text).color(TEXT_COLOR).size(24.0).strong()
+    RichText::new(text).color(text_color()).size(24.0).strong()
 }
 
 pub fn subheading(text: &str) -> RichText {
-    RichText::new(text).color(TEXT_COLOR).size(18.0).strong()
+    RichText::new(text).color(text_color()).size(18.0).strong()
 }
 
 pub fn body_text(text: &str) -> RichText {
-    RichText::new(text).color(TEXT_COLOR).size(16.0)
+    RichText::new(text).color(text_color()).size(16.0)
 }
 
 pub fn secondary_text(text: &str) -> RichText {
-    RichText::new(text).color(SECONDARY_TEXT_COLOR).size(16.0)
+    RichText::new(text).color(secondary_text_color()).size(16.0)
 }
 
 pub fn error_text(text: &str) -> RichText {
@@ -89,4 +150,41 @@ pub fn error_text(text: &str) -> RichText {
 
 pub fn success_text(text: &str) -> RichText {
     RichText::new(text).color(SUCCESS_COLOR).size(16.0)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn level_colors_go_from_green_to_red() {
+        assert_eq!(level_color(0.1), SUCCESS_COLOR);
+        assert_eq!(level_color(0.5), AWAY_COLOR);
+        assert_eq!(level_color(0.79), AWAY_COLOR);
+        assert_eq!(level_color(0.8), ERROR_COLOR);
+    }
+    
+    #[test]
+    fn custom_theme_sets_the_visuals() {
+        let ctx = Context::default();
+        let custom = CustomTheme {
+            background: [250, 240, 230],
+            accent: [200, 0, 100],
+            text: [20, 20, 20],
+            secondary_text: [90, 90, 90],
+        };
+        
+        apply_theme(&ctx, Theme::Custom, &custom);
+        
+        let visuals = ctx.style().visuals.clone();
+        assert_eq!(visuals.panel_fill, Color32::from_rgb(250, 240, 230));
+        assert_eq!(visuals.window_fill, Color32::from_rgb(250, 240, 230));
+        assert_eq!(visuals.widgets.active.bg_fill, Color32::from_rgb(200, 0, 100));
+        assert_eq!(visuals.widgets.noninteractive.fg_stroke.color, Color32::from_rgb(20, 20, 20));
+        assert_eq!(visuals.widgets.inactive.fg_stroke.color, Color32::from_rgb(90, 90, 90));
+        
+        // Back to the built-in palette when another theme is picked
+        apply_theme(&ctx, Theme::Dark, &custom);
+        assert_eq!(ctx.style().visuals.panel_fill, BACKGROUND_COLOR);
+    }
+}
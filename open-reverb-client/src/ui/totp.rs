@@ -0,0 +1,93 @@
+use egui::{Key, TextEdit, Window};
+use uuid::Uuid;
+
+use crate::ui::style;
+
+const CODE_LENGTH: usize = 6;
+
+// Asks for the code from the user's authenticator app after the password has been accepted
+pub struct TOTPDialog {
+    user_id: Option<Uuid>,
+    // Sent by the server when the user has no authenticator set up yet
+    setup_secret: Option<String>,
+    code: String,
+    error: Option<String>,
+}
+
+impl TOTPDialog {
+    pub fn new() -> Self {
+        Self {
+            user_id: None,
+            setup_secret: None,
+            code: String::new(),
+            error: None,
+        }
+    }
+    
+    pub fn request_code(&mut self, user_id: Uuid) {
+        self.user_id = Some(user_id);
+    }
+    
+    pub fn set_setup_secret(&mut self, secret: String) {
+        self.setup_secret = Some(secret);
+    }
+    
+    // The server turned the code down; let the user try again
+    pub fn show_error(&mut self, error: String) {
+        self.error = Some(error);
+        self.code.clear();
+    }
+    
+    // Returns the user ID and code once a full code has been entered
+    pub fn show(&mut self, ctx: &egui::Context) -> Option<(Uuid, String)> {
+        let mut submitted = None;
+        
+        Window::new("Two-Factor Authentication")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+            .show(ctx, |ui| {
+                if let Some(secret) = &self.setup_secret {
+                    ui.label(style::body_text("Add this key to your authenticator app:"));
+                    ui.label(style::subheading(&group_secret(secret)));
+                    ui.add_space(10.0);
+                }
+                
+                ui.label(style::body_text("Enter the 6-digit code from your authenticator app:"));
+                let response = ui.add(
+                    TextEdit::singleline(&mut self.code)
+                        .char_limit(CODE_LENGTH)
+                        .hint_text("123456")
+                        .desired_width(120.0),
+                );
+                self.code.retain(|c| c.is_ascii_digit());
+                
+                if let Some(error) = &self.error {
+                    ui.label(style::error_text(error));
+                }
+                ui.add_space(10.0);
+                
+                let complete = self.code.len() == CODE_LENGTH;
+                let entered = response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+                
+                if (ui.add_enabled(complete, egui::Button::new("Verify")).clicked() || entered && complete)
+                    && self.user_id.is_some()
+                {
+                    submitted = self.user_id.map(|id| (id, std::mem::take(&mut self.code)));
+                    self.error = None;
+                }
+            });
+        
+        submitted
+    }
+}
+
+// Split into groups of four so the key is easier to type
+fn group_secret(secret: &str) -> String {
+    secret
+        .as_bytes()
+        .chunks(4)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,120 @@
+use egui::{Id, TextureHandle, Window};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::ui::style;
+use crate::video::VideoPlayback;
+
+// One participant's video, popped out of the grid into its own window
+pub struct VideoPane {
+    user_id: Uuid,
+    // Shared with the grid; kept here so the last frame stays up
+    texture: Option<TextureHandle>,
+}
+
+impl VideoPane {
+    pub fn detach(user_id: Uuid) -> Self {
+        Self {
+            user_id,
+            texture: None,
+        }
+    }
+    
+    // Returns false once the window has been closed, and the pane should go back to the grid
+    pub fn show(&mut self, ctx: &egui::Context, playback: &Mutex<VideoPlayback>, title: &str) -> bool {
+        self.update_texture(ctx, playback);
+        let unsupported_codec = {
+            let playback = playback.lock().unwrap();
+            playback.codec(self.user_id).filter(|_| !playback.can_decode(self.user_id))
+        };
+        
+        let mut open = true;
+        Window::new(title)
+            .id(Id::new(("video_pane", self.user_id)))
+            .open(&mut open)
+            .resizable(true)
+            .default_size(egui::vec2(480.0, 360.0))
+            .show(ctx, |ui| match (&self.texture, unsupported_codec) {
+                (_, Some(codec)) => {
+                    ui.centered_and_justified(|ui| {
+                        ui.label(style::secondary_text(&format!("Unsupported codec ({})", codec.label())));
+                    });
+                }
+                (Some(texture), None) => {
+                    // Fill the window, keeping the frame's aspect ratio
+                    let size = texture.size_vec2();
+                    let scale = (ui.available_width() / size.x).min(ui.available_height() / size.y);
+                    ui.centered_and_justified(|ui| {
+                        ui.add(egui::Image::new((texture.id(), size * scale)));
+                    });
+                }
+                (None, None) => {
+                    ui.centered_and_justified(|ui| {
+                        ui.label(style::secondary_text("Waiting for video..."));
+                    });
+                }
+            });
+        
+        open
+    }
+    
+    fn update_texture(&mut self, ctx: &egui::Context, playback: &Mutex<VideoPlayback>) {
+        // The last frame stays up after the video stops, until the pane is closed
+        if let Some(texture) = playback.lock().unwrap().get_or_update_texture(self.user_id, ctx) {
+            self.texture = Some(texture);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::encoding::{self, VideoEncoding};
+    
+    #[test]
+    fn detaching_and_reattaching_frees_the_texture() {
+        let ctx = egui::Context::default();
+        let playback = Mutex::new(VideoPlayback::new());
+        let user_id = Uuid::new_v4();
+        let textures = || ctx.tex_manager().read().num_allocated();
+        let new_frame = || {
+            let rgb = vec![128u8; 64 * 48 * 3];
+            playback.lock().unwrap().process_video_data(user_id, encoding::encode_frame(VideoEncoding::Raw, &rgb, 64, 48).unwrap());
+        };
+        
+        // The font texture
+        let _ = ctx.run(Default::default(), |_| {});
+        let before = textures();
+        
+        for _ in 0..10 {
+            let mut pane = VideoPane::detach(user_id);
+            for _ in 0..3 {
+                new_frame();
+                let _ = ctx.run(Default::default(), |ctx| {
+                    assert!(pane.show(ctx, &playback, "alice"));
+                });
+            }
+            
+            // One texture, shared with the grid
+            let grid = playback.lock().unwrap().get_or_update_texture(user_id, &ctx).unwrap();
+            assert_eq!(pane.texture.as_ref().unwrap().id(), grid.id());
+            assert_eq!(textures(), before + 1);
+            drop(pane);
+        }
+        
+        // Once the video stops, the pane is all that keeps the last frame up
+        let mut pane = VideoPane::detach(user_id);
+        let _ = ctx.run(Default::default(), |ctx| {
+            pane.show(ctx, &playback, "alice");
+        });
+        playback.lock().unwrap().remove_user(user_id);
+        let _ = ctx.run(Default::default(), |ctx| {
+            pane.show(ctx, &playback, "alice");
+        });
+        assert!(pane.texture.is_some());
+        assert_eq!(textures(), before + 1);
+        
+        drop(pane);
+        assert_eq!(textures(), before);
+    }
+}
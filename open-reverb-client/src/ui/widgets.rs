@@ -0,0 +1,406 @@
+use egui::epaint::{Mesh, PathShape};
+use egui::util::History;
+use egui::{Align2, Color32, FontId, Pos2, Rect, Response, Sense, Shape, Stroke, Ui, Vec2, Widget};
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+use crate::ui::style;
+
+// Number of bars drawn for each participant
+pub const VISUALIZER_BARS: usize = 8;
+
+// Frames of audio level averaged together so the bars move smoothly
+const LEVEL_HISTORY_FRAMES: usize = 4;
+
+// Bars never drop below this fraction of the available height, so silence still shows something
+const MIN_BAR_FRACTION: f32 = 0.05;
+
+// Spinner rotation speed in radians per second
+const SPINNER_SPEED: f64 = 6.0;
+
+// Number of line segments used to draw the spinner arc
+const SPINNER_SEGMENTS: usize = 16;
+
+// Skeleton rows fade in and out this many times a second, roughly
+const SKELETON_PULSE_SPEED: f64 = 3.0;
+
+// Widths of successive skeleton rows, as fractions of the available width
+const SKELETON_ROW_WIDTHS: [f32; 4] = [0.8, 0.55, 0.7, 0.45];
+
+// RMS values kept by the waveform: a minute of audio at 10 samples a second
+pub const WAVEFORM_CAPACITY: usize = 600;
+
+// Animated level bars shown in place of video for participants without a camera.
+// There is no frequency data available, so the bars are pseudo-bands: the voice level
+// spread across the bars with a per-user shape derived from the user id.
+pub struct AudioVisualizerWidget {
+    band_weights: [f32; VISUALIZER_BARS],
+    history: History<f32>,
+    frame: u64,
+    // Gain AGC is taking off our own microphone, shown on our tile while AGC is on
+    gain_reduction_db: Option<f32>,
+}
+
+impl AudioVisualizerWidget {
+    pub fn new(user_id: Uuid) -> Self {
+        Self {
+            band_weights: band_weights(user_id),
+            history: History::new(0..LEVEL_HISTORY_FRAMES, f32::INFINITY),
+            frame: 0,
+            gain_reduction_db: None,
+        }
+    }
+    
+    pub fn set_gain_reduction_db(&mut self, gain_reduction_db: Option<f32>) {
+        self.gain_reduction_db = gain_reduction_db;
+    }
+    
+    // Called with each new audio level for the user (0.0 - 1.0)
+    pub fn push_level(&mut self, level: f32) {
+        self.history.add(self.frame as f64, level.clamp(0.0, 1.0));
+        self.frame += 1;
+    }
+    
+    pub fn smoothed_level(&self) -> f32 {
+        self.history.average().unwrap_or(0.0)
+    }
+    
+    pub fn bar_heights(&self, max_height: f32) -> [f32; VISUALIZER_BARS] {
+        bar_heights(self.smoothed_level(), &self.band_weights, max_height)
+    }
+    
+    pub fn paint(&self, ui: &mut Ui, rect: Rect) {
+        let painter = ui.painter();
+        painter.rect_filled(rect, 4.0, Color32::from_rgb(40, 40, 40));
+        
+        // Leave room for the microphone icon above and the username overlay below
+        let bars_rect = Rect::from_min_max(
+            rect.left_top() + egui::vec2(rect.width() * 0.2, rect.height() * 0.3),
+            rect.right_bottom() - egui::vec2(rect.width() * 0.2, 35.0),
+        );
+        
+        if bars_rect.width() > 0.0 && bars_rect.height() > 0.0 {
+            let slot_width = bars_rect.width() / VISUALIZER_BARS as f32;
+            let bar_width = slot_width * 0.6;
+            
+            for (i, height) in self.bar_heights(bars_rect.height()).iter().enumerate() {
+                let x = bars_rect.left() + slot_width * i as f32 + (slot_width - bar_width) / 2.0;
+                let bar = Rect::from_min_max(
+                    egui::pos2(x, bars_rect.bottom() - height),
+                    egui::pos2(x + bar_width, bars_rect.bottom()),
+                );
+                painter.rect_filled(bar, 2.0, style::ACCENT_COLOR);
+            }
+        }
+        
+        painter.text(
+            egui::pos2(rect.center().x, rect.top() + rect.height() * 0.15),
+            Align2::CENTER_CENTER,
+            "🎤",
+            FontId::proportional(24.0),
+            style::SECONDARY_TEXT_COLOR,
+        );
+        
+        // Shown as the gain applied, so boosting a quiet microphone reads as positive
+        if let Some(gain_reduction_db) = self.gain_reduction_db {
+            painter.text(
+                rect.right_top() + egui::vec2(-8.0, 8.0),
+                Align2::RIGHT_TOP,
+                format!("AGC {:+.1} dB", -gain_reduction_db),
+                FontId::proportional(12.0),
+                style::SECONDARY_TEXT_COLOR,
+            );
+        }
+    }
+}
+
+// Per-user bar shape: higher in the middle like a voice spectrum, with some variation
+// taken from the user id so participants don't all look the same
+fn band_weights(user_id: Uuid) -> [f32; VISUALIZER_BARS] {
+    let bytes = user_id.as_bytes();
+    let mut weights = [0.0; VISUALIZER_BARS];
+    
+    for (i, weight) in weights.iter_mut().enumerate() {
+        let position = (i as f32 + 0.5) / VISUALIZER_BARS as f32;
+        let envelope = (position * std::f32::consts::PI).sin();
+        let jitter = bytes[i % bytes.len()] as f32 / 255.0;
+        *weight = envelope * (0.6 + 0.4 * jitter);
+    }
+    
+    weights
+}
+
+// Height of each bar for the given level, between MIN_BAR_FRACTION and 1.0 of max_height
+fn bar_heights(level: f32, weights: &[f32; VISUALIZER_BARS], max_height: f32) -> [f32; VISUALIZER_BARS] {
+    let level = level.clamp(0.0, 1.0);
+    let mut heights = [0.0; VISUALIZER_BARS];
+    
+    for (height, weight) in heights.iter_mut().zip(weights) {
+        let fraction = (level * weight).clamp(MIN_BAR_FRACTION, 1.0);
+        *height = fraction * max_height;
+    }
+    
+    heights
+}
+
+// Small rotating arc shown while waiting on the server
+pub struct Spinner {
+    size: f32,
+}
+
+impl Spinner {
+    pub fn new() -> Self {
+        Self { size: 14.0 }
+    }
+}
+
+impl Widget for Spinner {
+    fn ui(self, ui: &mut Ui) -> Response {
+        let (rect, response) = ui.allocate_exact_size(Vec2::splat(self.size), Sense::hover());
+        
+        if ui.is_rect_visible(rect) {
+            // Keep animating for as long as the spinner is on screen
+            ui.ctx().request_repaint();
+            
+            let start = (ui.input(|i| i.time) * SPINNER_SPEED) as f32;
+            let sweep = std::f32::consts::PI * 1.5;
+            let radius = self.size / 2.0 - 1.0;
+            
+            let points = (0..=SPINNER_SEGMENTS)
+                .map(|i| {
+                    let angle = start + sweep * i as f32 / SPINNER_SEGMENTS as f32;
+                    rect.center() + radius * Vec2::angled(angle)
+                })
+                .collect();
+            
+            ui.painter().add(Shape::line(points, Stroke::new(2.0, style::ACCENT_COLOR)));
+        }
+        
+        response
+    }
+}
+// Grey placeholder rows that pulse in place of a list that's still loading
+pub struct SkeletonRows {
+    rows: usize,
+}
+
+impl SkeletonRows {
+    pub fn new(rows: usize) -> Self {
+        Self { rows }
+    }
+}
+
+impl Widget for SkeletonRows {
+    fn ui(self, ui: &mut Ui) -> Response {
+        let row_height = 14.0;
+        let spacing = ui.spacing().item_spacing.y + 4.0;
+        let size = Vec2::new(ui.available_width(), self.rows as f32 * (row_height + spacing));
+        let (rect, response) = ui.allocate_exact_size(size, Sense::hover());
+        
+        if ui.is_rect_visible(rect) {
+            ui.ctx().request_repaint();
+            
+            let pulse = ((ui.input(|i| i.time) * SKELETON_PULSE_SPEED).sin() * 0.5 + 0.5) as f32;
+            let color = style::SECONDARY_TEXT_COLOR.gamma_multiply(0.15 + 0.2 * pulse);
+            
+            for row in 0..self.rows {
+                let width = rect.width() * SKELETON_ROW_WIDTHS[row % SKELETON_ROW_WIDTHS.len()];
+                let top = rect.top() + row as f32 * (row_height + spacing);
+                let bar = Rect::from_min_size(Pos2::new(rect.left(), top), Vec2::new(width, row_height));
+                ui.painter().rect_filled(bar, 4.0, color);
+            }
+        }
+        
+        response
+    }
+}
+
+// Fixed-size buffer that drops its oldest item to make room for a new one
+pub struct CircularBuffer<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> CircularBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+    
+    pub fn push(&mut self, item: T) {
+        if self.items.len() == self.capacity {
+            self.items.pop_front();
+        }
+        
+        if self.capacity > 0 {
+            self.items.push_back(item);
+        }
+    }
+    
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    
+    // Oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+}
+
+// Scrolling waveform of the microphone's recent RMS level, newest on the right
+pub struct WaveformDisplay {
+    samples: CircularBuffer<f32>,
+    color: Color32,
+}
+
+impl WaveformDisplay {
+    pub fn new(color: Color32) -> Self {
+        Self {
+            samples: CircularBuffer::new(WAVEFORM_CAPACITY),
+            color,
+        }
+    }
+    
+    pub fn set_color(&mut self, color: Color32) {
+        self.color = color;
+    }
+    
+    // RMS level (0.0 - 1.0) of the latest stretch of audio
+    pub fn push(&mut self, rms: f32) {
+        self.samples.push(rms.clamp(0.0, 1.0));
+    }
+    
+    pub fn render(&self, ui: &mut Ui, size: Vec2) -> Response {
+        let (rect, response) = ui.allocate_exact_size(size, Sense::hover());
+        if !ui.is_rect_visible(rect) {
+            return response;
+        }
+        
+        let painter = ui.painter();
+        painter.rect_filled(rect, 4.0, Color32::from_rgb(40, 40, 40));
+        
+        // Mirrored around the middle, with a full buffer spanning the whole width
+        let step = rect.width() / (WAVEFORM_CAPACITY - 1) as f32;
+        let left = rect.right() - step * self.samples.len().saturating_sub(1) as f32;
+        let half_height = rect.height() / 2.0;
+        let (top, bottom): (Vec<Pos2>, Vec<Pos2>) = self
+            .samples
+            .iter()
+            .enumerate()
+            .map(|(i, rms)| {
+                let x = left + step * i as f32;
+                let offset = rms * half_height;
+                (egui::pos2(x, rect.center().y - offset), egui::pos2(x, rect.center().y + offset))
+            })
+            .unzip();
+        
+        if top.len() < 2 {
+            return response;
+        }
+        
+        // The outline isn't convex, so it's filled as a strip of quads rather than a closed path
+        let fill = Color32::from_rgba_unmultiplied(self.color.r(), self.color.g(), self.color.b(), 120);
+        let mut mesh = Mesh::default();
+        for (i, (t, b)) in top.iter().zip(&bottom).enumerate() {
+            mesh.colored_vertex(*t, fill);
+            mesh.colored_vertex(*b, fill);
+            
+            if i > 0 {
+                let v = (i * 2) as u32;
+                mesh.add_triangle(v - 2, v - 1, v);
+                mesh.add_triangle(v - 1, v, v + 1);
+            }
+        }
+        painter.add(Shape::mesh(mesh));
+        
+        let stroke = Stroke::new(1.0, self.color);
+        painter.add(Shape::Path(PathShape::line(top, stroke)));
+        painter.add(Shape::Path(PathShape::line(bottom, stroke)));
+        
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn bars_follow_the_level_within_their_bounds() {
+        let weights = [0.5, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.01];
+        
+        // Silence still shows the shortest bars
+        assert_eq!(bar_heights(0.0, &weights, 100.0), [5.0; VISUALIZER_BARS]);
+        
+        let heights = bar_heights(0.5, &weights, 100.0);
+        assert_eq!((heights[0], heights[1], heights[7]), (25.0, 50.0, 5.0));
+        
+        // Levels past full are taken as full
+        assert_eq!(bar_heights(3.0, &weights, 100.0), bar_heights(1.0, &weights, 100.0));
+        assert_eq!(bar_heights(1.0, &weights, 100.0)[1], 100.0);
+    }
+    
+    #[test]
+    fn bars_are_highest_in_the_middle_and_differ_between_users() {
+        let weights = band_weights(Uuid::new_v4());
+        assert!(weights.iter().all(|weight| (0.0..=1.0).contains(weight)));
+        assert!(weights[VISUALIZER_BARS / 2] > weights[0]);
+        assert!(weights[VISUALIZER_BARS / 2 - 1] > weights[VISUALIZER_BARS - 1]);
+        
+        assert_ne!(band_weights(Uuid::from_bytes([0; 16])), band_weights(Uuid::from_bytes([255; 16])));
+    }
+    
+    #[test]
+    fn levels_are_smoothed_over_recent_frames() {
+        let mut visualizer = AudioVisualizerWidget::new(Uuid::new_v4());
+        assert_eq!(visualizer.smoothed_level(), 0.0);
+        
+        for level in [1.0, 1.0, 0.0, 0.0] {
+            visualizer.push_level(level);
+        }
+        assert_eq!(visualizer.smoothed_level(), 0.5);
+        
+        // Older frames fall out of the average
+        for _ in 0..LEVEL_HISTORY_FRAMES {
+            visualizer.push_level(2.0);
+        }
+        assert_eq!(visualizer.smoothed_level(), 1.0);
+    }
+    
+    #[test]
+    fn circular_buffers_drop_the_oldest_when_full() {
+        let mut buffer = CircularBuffer::new(3);
+        for item in 1..=2 {
+            buffer.push(item);
+        }
+        assert_eq!(buffer.iter().copied().collect::<Vec<_>>(), [1, 2]);
+        
+        // Wrapping around more than once
+        for item in 3..=8 {
+            buffer.push(item);
+        }
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.iter().copied().collect::<Vec<_>>(), [6, 7, 8]);
+        
+        // With no room, nothing is kept
+        let mut empty = CircularBuffer::new(0);
+        empty.push(1);
+        assert_eq!(empty.len(), 0);
+    }
+    
+    #[test]
+    fn waveform_keeps_recent_levels_within_range() {
+        let mut waveform = WaveformDisplay::new(Color32::WHITE);
+        for i in 0..WAVEFORM_CAPACITY + 10 {
+            waveform.push(i as f32);
+        }
+        waveform.push(-1.0);
+        
+        assert_eq!(waveform.samples.len(), WAVEFORM_CAPACITY);
+        let samples: Vec<f32> = waveform.samples.iter().copied().collect();
+        assert_eq!(samples.last(), Some(&0.0));
+        assert!(samples[..WAVEFORM_CAPACITY - 1].iter().all(|&rms| rms == 1.0));
+    }
+}
@@ -0,0 +1,452 @@
+use egui::{Button, ComboBox, ProgressBar, TextureHandle, Ui, Window};
+use std::sync::Arc;
+
+use crate::audio::{self, AudioLoopback};
+use crate::config::ClientConfig;
+use crate::connection::Connection;
+use crate::ui::style;
+use crate::video::{CaptureType, VideoManager};
+
+// Size of the camera preview thumbnail
+const PREVIEW_SIZE: egui::Vec2 = egui::vec2(240.0, 180.0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WizardPage {
+    Welcome,
+    ServerSetup,
+    AudioDevice,
+    CameraTest,
+    Summary,
+}
+
+impl WizardPage {
+    const ALL: [WizardPage; 5] = [
+        WizardPage::Welcome,
+        WizardPage::ServerSetup,
+        WizardPage::AudioDevice,
+        WizardPage::CameraTest,
+        WizardPage::Summary,
+    ];
+    
+    fn index(self) -> usize {
+        Self::ALL.iter().position(|page| *page == self).unwrap()
+    }
+    
+    fn title(self) -> &'static str {
+        match self {
+            WizardPage::Welcome => "Welcome",
+            WizardPage::ServerSetup => "Server",
+            WizardPage::AudioDevice => "Microphone",
+            WizardPage::CameraTest => "Camera",
+            WizardPage::Summary => "All Set",
+        }
+    }
+}
+
+// Guides a new user through setting up their server and devices
+pub struct FirstRunWizard {
+    config: ClientConfig,
+    page: WizardPage,
+    error: Option<String>,
+    
+    // Result of the last "Test Connection" on the server page
+    connection_test: Option<Result<(), String>>,
+    
+    available_audio_inputs: Vec<String>,
+    available_video_devices: Vec<String>,
+    
+    loopback: AudioLoopback,
+    camera_preview: Option<VideoManager>,
+    preview_texture: Option<TextureHandle>,
+}
+
+impl FirstRunWizard {
+    pub fn new(config: ClientConfig) -> Self {
+        Self {
+            config,
+            page: WizardPage::Welcome,
+            error: None,
+            connection_test: None,
+            available_audio_inputs: audio::input_device_names(),
+            available_video_devices: VideoManager::get_available_video_devices(),
+            loopback: AudioLoopback::new(),
+            camera_preview: None,
+            preview_texture: None,
+        }
+    }
+    
+    // Check that the current page is filled in well enough to move on
+    pub fn validate(&self) -> Result<(), String> {
+        match self.page {
+            WizardPage::ServerSetup => validate_server_url(&self.config.server_url),
+            // Without any microphones to pick from, the default is used
+            WizardPage::AudioDevice if self.config.audio_input_device.is_none() && !self.available_audio_inputs.is_empty() => {
+                Err("Choose a microphone".to_string())
+            }
+            WizardPage::CameraTest if self.config.video_device.is_none() => {
+                Err("Choose a camera".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+    
+    // Move to the next page, if the current one is valid
+    pub fn next(&mut self) -> bool {
+        if let Err(e) = self.validate() {
+            self.error = Some(e);
+            return false;
+        }
+        
+        match WizardPage::ALL.get(self.page.index() + 1) {
+            Some(page) => {
+                self.change_page(*page);
+                true
+            }
+            None => false,
+        }
+    }
+    
+    pub fn back(&mut self) {
+        if let Some(index) = self.page.index().checked_sub(1) {
+            self.change_page(WizardPage::ALL[index]);
+        }
+    }
+    
+    // Returns the finished config once the user clicks "Finish"
+    pub fn show(&mut self, ctx: &egui::Context) -> Option<ClientConfig> {
+        let mut result = None;
+        
+        Window::new("Set Up Open Reverb")
+            .collapsible(false)
+            .resizable(false)
+            .default_width(450.0)
+            .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+            .show(ctx, |ui| {
+                ui.label(style::secondary_text(&format!(
+                    "Step {} of {}: {}",
+                    self.page.index() + 1,
+                    WizardPage::ALL.len(),
+                    self.page.title()
+                )));
+                ui.separator();
+                ui.add_space(10.0);
+                
+                match self.page {
+                    WizardPage::Welcome => self.render_welcome(ui),
+                    WizardPage::ServerSetup => self.render_server_setup(ui),
+                    WizardPage::AudioDevice => self.render_audio_device(ui),
+                    WizardPage::CameraTest => self.render_camera_test(ui),
+                    WizardPage::Summary => self.render_summary(ui),
+                }
+                
+                if let Some(error) = &self.error {
+                    ui.add_space(10.0);
+                    ui.label(style::error_text(error));
+                }
+                
+                ui.add_space(10.0);
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.add_enabled(self.page != WizardPage::Welcome, Button::new("Back")).clicked() {
+                        self.back();
+                    }
+                    
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        if self.page == WizardPage::Summary {
+                            if ui.add(Button::new(style::body_text("Finish")).fill(style::ACCENT_COLOR)).clicked() {
+                                result = Some(self.finish());
+                            }
+                        } else if ui.button("Next").clicked() {
+                            self.next();
+                        }
+                    });
+                });
+            });
+        
+        // Keep the level meter and camera preview moving
+        if self.loopback.is_active() || self.camera_preview.is_some() {
+            ctx.request_repaint();
+        }
+        
+        result
+    }
+    
+    fn finish(&mut self) -> ClientConfig {
+        self.stop_tests();
+        self.config.first_run_complete = true;
+        self.config.clone()
+    }
+    
+    fn change_page(&mut self, page: WizardPage) {
+        // Tests only run while their page is showing
+        self.stop_tests();
+        self.error = None;
+        self.page = page;
+    }
+    
+    fn stop_tests(&mut self) {
+        self.loopback.stop();
+        
+        if let Some(mut camera_preview) = self.camera_preview.take() {
+            camera_preview.stop();
+        }
+        self.preview_texture = None;
+    }
+    
+    fn render_welcome(&mut self, ui: &mut Ui) {
+        ui.heading(style::heading("Welcome to Open Reverb"));
+        ui.add_space(10.0);
+        ui.label(style::body_text(
+            "Let's get you set up. This will take a minute: you'll pick a server to connect to, \
+             then check that your microphone and camera work.",
+        ));
+    }
+    
+    fn render_server_setup(&mut self, ui: &mut Ui) {
+        ui.heading(style::subheading("Which server do you want to connect to?"));
+        ui.add_space(10.0);
+        
+        ui.horizontal(|ui| {
+            ui.label("Server Address:");
+            if ui.text_edit_singleline(&mut self.config.server_url).changed() {
+                self.connection_test = None;
+            }
+        });
+        ui.label(style::secondary_text("For example 127.0.0.1:8080"));
+        ui.add_space(10.0);
+        
+        ui.horizontal(|ui| {
+            if ui.button("Test Connection").clicked() {
+                self.connection_test = Some(self.test_connection());
+            }
+            
+            match &self.connection_test {
+                Some(Ok(())) => {
+                    ui.label(style::success_text("Connected successfully"));
+                }
+                Some(Err(e)) => {
+                    ui.label(style::error_text(e));
+                }
+                None => {}
+            }
+        });
+    }
+    
+    fn render_audio_device(&mut self, ui: &mut Ui) {
+        ui.heading(style::subheading("Choose your microphone"));
+        ui.add_space(10.0);
+        
+        ui.horizontal(|ui| {
+            ui.label("Microphone:");
+            let selected = self.config.audio_input_device.clone().unwrap_or_else(|| "Select...".to_string());
+            ComboBox::from_id_source("wizard_audio_input")
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    for device in &self.available_audio_inputs {
+                        if ui.selectable_label(self.config.audio_input_device.as_ref() == Some(device), device).clicked() {
+                            self.config.audio_input_device = Some(device.clone());
+                            self.loopback.stop();
+                        }
+                    }
+                });
+        });
+        ui.add_space(10.0);
+        
+        ui.label(style::body_text("Speak and you should hear yourself back."));
+        ui.horizontal(|ui| {
+            if self.loopback.is_active() {
+                if ui.button("Stop Test").clicked() {
+                    self.loopback.stop();
+                }
+            } else if ui.button("Test Microphone").clicked() {
+                if let Err(e) = self.loopback.start(self.config.audio_input_device.as_deref()) {
+                    self.error = Some(format!("Couldn't start the microphone test: {}", e));
+                }
+            }
+            
+            ui.add(ProgressBar::new(self.loopback.level()).desired_width(200.0));
+        });
+    }
+    
+    fn render_camera_test(&mut self, ui: &mut Ui) {
+        ui.heading(style::subheading("Check your camera"));
+        ui.add_space(10.0);
+        
+        ui.horizontal(|ui| {
+            ui.label("Camera:");
+            let selected = self.config.video_device.clone().unwrap_or_else(|| "Select...".to_string());
+            ComboBox::from_id_source("wizard_video_device")
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    for device in &self.available_video_devices {
+                        if ui.selectable_label(self.config.video_device.as_ref() == Some(device), device).clicked() {
+                            self.config.video_device = Some(device.clone());
+                        }
+                    }
+                });
+        });
+        ui.add_space(10.0);
+        
+        if self.camera_preview.is_none() {
+            if ui.button("Start Preview").clicked() {
+                self.start_camera_preview();
+            }
+        } else if ui.button("Stop Preview").clicked() {
+            self.stop_tests();
+        }
+        
+        if let Some(camera_preview) = &self.camera_preview {
+            if let Some(frame) = camera_preview.preview_frame() {
+                let (width, height) = camera_preview.get_dimensions();
+                let image = egui::ColorImage::from_rgb([width as usize, height as usize], &frame);
+                
+                match &mut self.preview_texture {
+                    Some(texture) => texture.set(image, Default::default()),
+                    None => self.preview_texture = Some(ui.ctx().load_texture("camera_preview", image, Default::default())),
+                }
+            }
+        }
+        
+        match &self.preview_texture {
+            Some(texture) => {
+                ui.add(egui::Image::new((texture.id(), PREVIEW_SIZE)));
+            }
+            None if self.camera_preview.is_some() => {
+                ui.label(style::secondary_text("Waiting for the camera..."));
+            }
+            None => {}
+        }
+    }
+    
+    fn render_summary(&mut self, ui: &mut Ui) {
+        ui.heading(style::subheading("You're all set"));
+        ui.add_space(10.0);
+        
+        ui.label(style::body_text(&format!("Server: {}", self.config.server_url)));
+        ui.label(style::body_text(&format!(
+            "Microphone: {}",
+            self.config.audio_input_device.as_deref().unwrap_or("Default")
+        )));
+        ui.label(style::body_text(&format!(
+            "Camera: {}",
+            self.config.video_device.as_deref().unwrap_or("Default")
+        )));
+        
+        ui.add_space(10.0);
+        ui.label(style::secondary_text("You can change any of these later in Settings."));
+    }
+    
+    fn test_connection(&self) -> Result<(), String> {
+        validate_server_url(&self.config.server_url)?;
+        
+        let mut connection = Connection::new();
+        connection
+            .connect_with_config(&self.config.server_url, &self.config)
+            .map_err(|e| format!("Couldn't connect: {}", e))?;
+        connection.disconnect();
+        
+        Ok(())
+    }
+    
+    fn start_camera_preview(&mut self) {
+        // The preview never sends anything, so it doesn't need a live connection
+        let mut camera_preview = VideoManager::new(
+            uuid::Uuid::nil(),
+            uuid::Uuid::nil(),
+            Arc::new(Connection::new()),
+            CaptureType::Camera,
+            self.config.preferred_video_codec,
+            self.config.video_send_buffer_frames,
+        );
+        
+        if let Some(device) = &self.config.video_device {
+            camera_preview.set_device(device);
+        }
+        
+        match camera_preview.start_preview() {
+            Ok(()) => self.camera_preview = Some(camera_preview),
+            Err(e) => self.error = Some(format!("Couldn't start the camera: {}", e)),
+        }
+    }
+}
+
+impl Drop for FirstRunWizard {
+    fn drop(&mut self) {
+        self.stop_tests();
+    }
+}
+
+// A server address must be a host and a port, e.g. "127.0.0.1:8080"
+fn validate_server_url(server_url: &str) -> Result<(), String> {
+    let (host, port) = server_url
+        .trim()
+        .rsplit_once(':')
+        .ok_or_else(|| "Enter the server address as host:port".to_string())?;
+    
+    if host.is_empty() {
+        return Err("Enter the server's host name or IP address".to_string());
+    }
+    
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(()),
+        _ => Err("Enter a valid port number".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn pages_come_in_order_once_each_is_filled_in() {
+        let mut wizard = FirstRunWizard::new(ClientConfig { server_url: String::new(), video_device: None, ..ClientConfig::default() });
+        wizard.available_audio_inputs = vec!["Headset".to_string()];
+        assert_eq!(wizard.page, WizardPage::Welcome);
+        
+        assert!(wizard.next());
+        assert_eq!(wizard.page, WizardPage::ServerSetup);
+        
+        // Held on a page until it's valid, and told why
+        assert!(!wizard.next());
+        assert_eq!((wizard.page, wizard.error.as_deref()), (WizardPage::ServerSetup, Some("Enter the server address as host:port")));
+        wizard.config.server_url = "127.0.0.1:8080".to_string();
+        assert!(wizard.next());
+        assert_eq!((wizard.page, wizard.error.as_deref()), (WizardPage::AudioDevice, None));
+        
+        assert!(!wizard.next());
+        wizard.config.audio_input_device = Some("Headset".to_string());
+        assert!(wizard.next());
+        assert_eq!(wizard.page, WizardPage::CameraTest);
+        
+        assert!(!wizard.next());
+        wizard.config.video_device = Some("Webcam".to_string());
+        assert!(wizard.next());
+        assert_eq!(wizard.page, WizardPage::Summary);
+        
+        // Nothing after the summary but finishing
+        assert!(!wizard.next());
+        assert_eq!(wizard.page, WizardPage::Summary);
+        let config = wizard.finish();
+        assert!(config.first_run_complete);
+        assert_eq!((config.server_url.as_str(), config.video_device.as_deref()), ("127.0.0.1:8080", Some("Webcam")));
+    }
+    
+    #[test]
+    fn back_goes_to_the_previous_page_and_stops_at_the_first() {
+        let mut wizard = FirstRunWizard::new(ClientConfig::default());
+        wizard.back();
+        assert_eq!(wizard.page, WizardPage::Welcome);
+        
+        wizard.change_page(WizardPage::CameraTest);
+        wizard.error = Some("Choose a camera".to_string());
+        for page in [WizardPage::AudioDevice, WizardPage::ServerSetup, WizardPage::Welcome] {
+            wizard.back();
+            assert_eq!((wizard.page, wizard.error.as_deref()), (page, None));
+        }
+        
+        // A machine without microphones uses the default
+        wizard.available_audio_inputs.clear();
+        wizard.change_page(WizardPage::AudioDevice);
+        assert!(wizard.next());
+    }
+}
@@ -1,27 +1,70 @@
+pub mod background;
+pub mod encoding;
+pub mod hw_decode;
+#[cfg(feature = "video")]
+pub mod screen;
+
 use anyhow::Result;
 use crossbeam_channel::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex, OnceLock,
 };
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-use crate::connection::Connection;
+use open_reverb_common::models::{ScreenRegion, VideoCodec};
+use crate::audio::{self, BufferStats};
+use crate::connection::{Connection, ConnectionQuality};
+use crate::sync;
+use self::background::BackgroundBlur;
+use self::encoding::{FrameFormat, VideoEncoding, VideoFrame};
+use self::hw_decode::{DecoderBackend, HardwareDecoderPool};
 
 // Video configuration constants
-const VIDEO_WIDTH: i32 = 640;
-const VIDEO_HEIGHT: i32 = 480;
-const VIDEO_FRAMERATE: i32 = 30;
 const VIDEO_BITRATE: i32 = 1_000_000; // 1 Mbps
 
+// Lowest bitrate congestion can push video down to
+const MIN_VIDEO_BITRATE: u32 = 100_000;
+// Time without congestion before the bitrate is raised again
+const BITRATE_RECOVERY_INTERVAL: Duration = Duration::from_secs(5);
+// Gaussian blur applied to the camera in privacy mode
+const PRIVACY_BLUR_SIGMA: f32 = 20.0;
+// A user's video counts as stopped after this long without a frame
+const VIDEO_ACTIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[cfg(feature = "video")]
 use gstreamer as gst;
 #[cfg(feature = "video")]
-use gstreamer_app as gst_app;
+use gstreamer::prelude::*;
 #[cfg(feature = "video")]
-use gstreamer_video as gst_video;
+use gstreamer_app as gst_app;
+
+// GStreamer takes 100 - 500ms to initialize, so it's only done the first time video is used.
+// A failure is kept too, so later calls don't try again.
+struct GstreamerContext;
+
+impl GstreamerContext {
+    fn get() -> &'static Result<(), String> {
+        static CONTEXT: OnceLock<Result<(), String>> = OnceLock::new();
+        
+        CONTEXT.get_or_init(Self::init)
+    }
+    
+    #[cfg(feature = "video")]
+    fn init() -> Result<(), String> {
+        gst::init().map_err(|e| e.to_string())
+    }
+    
+    // Nothing to initialize without GStreamer
+    #[cfg(not(feature = "video"))]
+    fn init() -> Result<(), String> {
+        Ok(())
+    }
+}
+
 pub struct VideoManager {
     // State
     active: Arc<AtomicBool>,
@@ -32,6 +75,10 @@ pub struct VideoManager {
     // Channels for video data
     tx: Sender<Vec<u8>>,
     rx: Receiver<Vec<u8>>,
+    // Frames captured while the send buffer was full
+    dropped_frames: Arc<AtomicU64>,
+    // Capture time of the last frame sent, for sync hints
+    last_pts_ms: Arc<AtomicU64>,
     
     // User and channel info
     user_id: Uuid,
@@ -42,58 +89,301 @@ pub struct VideoManager {
     
     // Type of capture
     capture_type: CaptureType,
+    // Codec outgoing frames are encoded with
+    codec: VideoCodec,
+    // How each frame is compressed before it's sent
+    encoding: Arc<Mutex<VideoEncoding>>,
+    // Size and rate frames are captured at
+    resolution: VideoResolution,
+    framerate: VideoFramerate,
+    // Monitor shared, from `get_available_screens`, and the part of the desktop shared instead
+    // when one has been picked
+    screen_index: u32,
+    region: Option<ScreenRegion>,
+    
+    // Target bitrate, lowered when the server says the channel is congested
+    bitrate: BitrateController,
+    // Bits of the background blur strength (f32), for the camera only; 0.0 while it's off
+    background_blur: Arc<AtomicU32>,
+    // Blurs camera frames beyond recognition while the window isn't focused
+    privacy_mode: Arc<AtomicBool>,
+    // Sends captured frames to the server; joined when capture stops
+    sender_thread: Option<thread::JoinHandle<()>>,
     
     // Video pipeline (when using gstreamer)
     #[cfg(feature = "video")]
     pipeline: Option<gst::Pipeline>,
 }
 
+// Target bitrate for outgoing video. It halves each time the server reports congestion
+// and creeps back up once the congestion has passed.
+#[derive(Clone)]
+pub struct BitrateController {
+    bitrate: Arc<AtomicU32>,
+    last_change: Arc<Mutex<Instant>>,
+}
+
+impl BitrateController {
+    pub fn new() -> Self {
+        Self {
+            bitrate: Arc::new(AtomicU32::new(VIDEO_BITRATE as u32)),
+            last_change: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+    
+    pub fn signal_congestion(&self) {
+        let bitrate = (self.bitrate() / 2).max(MIN_VIDEO_BITRATE);
+        self.bitrate.store(bitrate, Ordering::SeqCst);
+        *self.last_change.lock().unwrap() = Instant::now();
+        
+        tracing::info!("Channel is congested, lowering video bitrate to {} bps", bitrate);
+    }
+    
+    // Raise the bitrate by 10% if there's been no congestion for a while
+    pub fn recover(&self) {
+        let mut last_change = self.last_change.lock().unwrap();
+        let bitrate = self.bitrate();
+        
+        if bitrate < VIDEO_BITRATE as u32 && last_change.elapsed() >= BITRATE_RECOVERY_INTERVAL {
+            self.bitrate.store((bitrate + bitrate / 10).min(VIDEO_BITRATE as u32), Ordering::SeqCst);
+            *last_change = Instant::now();
+        }
+    }
+    
+    // In bits per second
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate.load(Ordering::SeqCst)
+    }
+}
+
+// What this client is able to play back
+#[derive(Debug, Clone)]
+pub struct ClientCapabilities {
+    pub supported_video_codecs: Vec<VideoCodec>,
+}
+
+impl ClientCapabilities {
+    pub fn detect() -> Self {
+        // A codec can be played when GStreamer has a decoder for it
+        #[cfg(feature = "video")]
+        let supported_video_codecs = VideoCodec::ALL
+            .into_iter()
+            .filter(|codec| {
+                let decoder = match codec {
+                    VideoCodec::H264 => "avdec_h264",
+                    VideoCodec::VP8 => "vp8dec",
+                    VideoCodec::VP9 => "vp9dec",
+                    VideoCodec::AV1 => "dav1ddec",
+                };
+                VideoManager::is_gstreamer_available() && gst::ElementFactory::find(decoder).is_some()
+            })
+            .collect();
+        
+        // Without GStreamer frames are sent raw, whatever codec they're labelled with
+        #[cfg(not(feature = "video"))]
+        let supported_video_codecs = VideoCodec::ALL.to_vec();
+        
+        Self { supported_video_codecs }
+    }
+    
+    pub fn supports(&self, codec: VideoCodec) -> bool {
+        self.supported_video_codecs.contains(&codec)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CaptureType {
     Camera,
     Screen,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VideoResolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for VideoResolution {
+    fn default() -> Self {
+        Self { width: 640, height: 480 }
+    }
+}
+
+// In frames per second
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VideoFramerate(pub u32);
+
+impl Default for VideoFramerate {
+    fn default() -> Self {
+        Self(30)
+    }
+}
+
+impl VideoFramerate {
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.0.max(1)
+    }
+}
+
+// Capture settings offered in Settings, from weak connections up to a LAN
+pub const VIDEO_PRESETS: [(VideoResolution, VideoFramerate); 4] = [
+    (VideoResolution { width: 640, height: 360 }, VideoFramerate(30)),
+    (VideoResolution { width: 640, height: 480 }, VideoFramerate(30)),
+    (VideoResolution { width: 1280, height: 720 }, VideoFramerate(30)),
+    (VideoResolution { width: 1920, height: 1080 }, VideoFramerate(15)),
+];
+
+pub fn video_preset_label(resolution: VideoResolution, framerate: VideoFramerate) -> String {
+    format!("{}p / {}fps", resolution.height, framerate.0)
+}
+
 // VideoPlayback is responsible for rendering received video streams
 pub struct VideoPlayback {
-    // Video data buffers for each user
-    video_buffers: std::collections::HashMap<Uuid, Vec<u8>>,
-    
-    // Video frame dimensions
-    width: i32,
-    height: i32,
+    // Latest decoded frame from each user
+    video_buffers: std::collections::HashMap<Uuid, VideoFrame>,
+    // Textures showing those frames, and the users whose frame has changed since it was uploaded
+    textures: std::collections::HashMap<Uuid, egui::TextureHandle>,
+    fresh_frames: std::collections::HashSet<Uuid>,
     
     // Last update time for each user
     last_updates: std::collections::HashMap<Uuid, std::time::Instant>,
+    
+    // Codec each user's video is encoded with, and which of them can be played here
+    codecs: std::collections::HashMap<Uuid, VideoCodec>,
+    capabilities: ClientCapabilities,
+    // Whether each user's last frame could be decoded
+    decodable: std::collections::HashMap<Uuid, bool>,
+    
+    // Decoders for H.264 video
+    decoders: HardwareDecoderPool,
 }
 
 impl VideoPlayback {
     pub fn new() -> Self {
         Self {
             video_buffers: std::collections::HashMap::new(),
-            width: VIDEO_WIDTH,
-            height: VIDEO_HEIGHT,
+            textures: std::collections::HashMap::new(),
+            fresh_frames: std::collections::HashSet::new(),
             last_updates: std::collections::HashMap::new(),
+            codecs: std::collections::HashMap::new(),
+            capabilities: ClientCapabilities::detect(),
+            decodable: std::collections::HashMap::new(),
+            decoders: HardwareDecoderPool::new(DecoderBackend::Software),
         }
     }
     
+    pub fn set_codec(&mut self, user_id: Uuid, codec: VideoCodec) {
+        self.codecs.insert(user_id, codec);
+    }
+    
+    pub fn codec(&self, user_id: Uuid) -> Option<VideoCodec> {
+        self.codecs.get(&user_id).copied()
+    }
+    
+    // False when the user's video is in a codec this client has no decoder for. Until their first
+    // frame arrives, that's judged by the codec they said they'd send.
+    pub fn can_decode(&self, user_id: Uuid) -> bool {
+        match self.decodable.get(&user_id) {
+            Some(decodable) => *decodable,
+            None => self.codec(user_id).is_none_or(|codec| self.capabilities.supports(codec)),
+        }
+    }
+    
+    pub fn set_decoder_backend(&mut self, backend: DecoderBackend) {
+        self.decoders.set_backend(backend);
+    }
+    
+    // Frames we can't decode are dropped, and a placeholder is shown instead
     pub fn process_video_data(&mut self, user_id: Uuid, data: Vec<u8>) {
-        self.video_buffers.insert(user_id, data);
+        let Some(encoded) = encoding::parse_frame(&data) else {
+            tracing::debug!("Dropped a video frame from {} in an unknown format", user_id);
+            return;
+        };
+        
+        let frame = match encoded.format {
+            FrameFormat::Raw => VideoFrame::from_rgb(encoded.width, encoded.height, encoded.payload),
+            FrameFormat::Jpeg => encoding::decode_jpeg(encoded.payload),
+            FrameFormat::H264 => {
+                let codec = self.codec(user_id).unwrap_or(VideoCodec::H264);
+                if !self.capabilities.supports(codec) {
+                    self.decodable.insert(user_id, false);
+                    return;
+                }
+                
+                // The last frame stays up until the decoder has the next one
+                match self.decoders.decode(user_id, encoded.payload.to_vec()) {
+                    Some(rgb) => VideoFrame::from_rgb(encoded.width, encoded.height, &rgb),
+                    None => return,
+                }
+            }
+        };
+        self.decodable.insert(user_id, true);
+        
+        let Some(frame) = frame else {
+            tracing::debug!("Dropped a corrupt video frame from {}", user_id);
+            return;
+        };
+        self.video_buffers.insert(user_id, frame);
+        self.fresh_frames.insert(user_id);
         self.last_updates.insert(user_id, std::time::Instant::now());
     }
     
-    pub fn get_video_frame(&self, user_id: Uuid) -> Option<&Vec<u8>> {
-        self.video_buffers.get(&user_id)
+    // The user is now sharing a different part of their screen. Frames are sized as they arrive,
+    // so it's only the current one, in the old shape, that has to go.
+    pub fn set_screen_region(&mut self, user_id: Uuid, region: Option<ScreenRegion>) {
+        tracing::debug!("{} is now sharing {:?}", user_id, region);
+        self.video_buffers.remove(&user_id);
+        self.textures.remove(&user_id);
+        self.fresh_frames.remove(&user_id);
     }
     
-    pub fn get_dimensions(&self) -> (i32, i32) {
-        (self.width, self.height)
+    pub fn remove_user(&mut self, user_id: Uuid) {
+        self.video_buffers.remove(&user_id);
+        self.textures.remove(&user_id);
+        self.fresh_frames.remove(&user_id);
+        self.last_updates.remove(&user_id);
+        self.codecs.remove(&user_id);
+        self.decodable.remove(&user_id);
+        self.decoders.remove_user(user_id);
+    }
+    
+    pub fn update_quality(&self, quality: &mut ConnectionQuality) {
+        quality.hw_decode_active = self.decoders.hw_decode_active();
+    }
+    
+    // Texture showing the user's latest frame; it's only uploaded again once a new frame arrives
+    pub fn get_or_update_texture(&mut self, user_id: Uuid, ctx: &egui::Context) -> Option<egui::TextureHandle> {
+        if !self.is_active(user_id) {
+            self.textures.remove(&user_id);
+            return None;
+        }
+        
+        if self.fresh_frames.remove(&user_id) {
+            if let Some(frame) = self.video_buffers.get(&user_id) {
+                let image = egui::ColorImage::from_rgba_unmultiplied([frame.width as usize, frame.height as usize], &frame.rgba);
+                match self.textures.get_mut(&user_id) {
+                    Some(texture) => texture.set(image, Default::default()),
+                    None => {
+                        let texture = ctx.load_texture(format!("video_{}", user_id), image, Default::default());
+                        self.textures.insert(user_id, texture);
+                    }
+                }
+            }
+        }
+        
+        self.textures.get(&user_id).cloned()
+    }
+    
+    // Free the textures of users whose video has stopped, since nothing else will
+    pub fn prune_textures(&mut self) {
+        let last_updates = &self.last_updates;
+        self.textures.retain(|user_id, _| last_updates.get(user_id).is_some_and(|t| t.elapsed() < VIDEO_ACTIVE_TIMEOUT));
     }
     
     pub fn is_active(&self, user_id: Uuid) -> bool {
         if let Some(last_update) = self.last_updates.get(&user_id) {
             // Consider the stream active if we received data in the last 5 seconds
-            last_update.elapsed() < Duration::from_secs(5)
+            last_update.elapsed() < VIDEO_ACTIVE_TIMEOUT
         } else {
             false
         }
@@ -101,18 +391,38 @@ impl VideoPlayback {
 }
 
 impl VideoManager {
-    pub fn new(user_id: Uuid, channel_id: Uuid, connection: Arc<Connection>, capture_type: CaptureType) -> Self {
-        let (tx, rx) = crossbeam_channel::bounded(2);
+    // `buffer_frames` is how many captured frames can wait to be sent before new ones are dropped
+    pub fn new(
+        user_id: Uuid,
+        channel_id: Uuid,
+        connection: Arc<Connection>,
+        capture_type: CaptureType,
+        codec: VideoCodec,
+        buffer_frames: usize,
+    ) -> Self {
+        let (tx, rx) = crossbeam_channel::bounded(buffer_frames.max(1));
         
         Self {
             active: Arc::new(AtomicBool::new(false)),
             device_name: None,
             tx,
             rx,
+            dropped_frames: Arc::new(AtomicU64::new(0)),
+            last_pts_ms: Arc::new(AtomicU64::new(0)),
             user_id,
             channel_id,
             connection,
             capture_type,
+            codec,
+            encoding: Arc::new(Mutex::new(VideoEncoding::default())),
+            resolution: VideoResolution::default(),
+            framerate: VideoFramerate::default(),
+            screen_index: 0,
+            region: None,
+            bitrate: BitrateController::new(),
+            background_blur: Arc::new(AtomicU32::new(0.0f32.to_bits())),
+            privacy_mode: Arc::new(AtomicBool::new(false)),
+            sender_thread: None,
             #[cfg(feature = "video")]
             pipeline: None,
         }
@@ -122,18 +432,75 @@ impl VideoManager {
         self.active.load(Ordering::SeqCst)
     }
     
+    pub fn buffer_stats(&self) -> BufferStats {
+        BufferStats::of(&self.tx, &self.dropped_frames)
+    }
+    
+    // Capture time of the last frame sent, in milliseconds since the Unix epoch
+    pub fn last_pts_ms(&self) -> u64 {
+        self.last_pts_ms.load(Ordering::Relaxed)
+    }
+    
+    // The server dropped our frames for going over the channel's bandwidth limit
+    pub fn signal_congestion(&self) {
+        self.bitrate.signal_congestion();
+    }
+    
+    // Takes effect from the next captured frame
+    pub fn set_background_blur(&self, enabled: bool, strength: f32) {
+        let strength = if enabled { strength.clamp(0.0, 1.0) } else { 0.0 };
+        self.background_blur.store(strength.to_bits(), Ordering::Relaxed);
+    }
+    
+    // Takes effect from the next captured frame
+    pub fn set_encoding(&self, encoding: VideoEncoding) {
+        *self.encoding.lock().unwrap() = encoding;
+    }
+    
+    // Takes effect from the next captured frame
+    pub fn set_privacy_mode(&self, active: bool) {
+        self.privacy_mode.store(active, Ordering::Relaxed);
+    }
+    
+    // Takes effect the next time capture starts
+    pub fn set_format(&mut self, resolution: VideoResolution, framerate: VideoFramerate) {
+        self.resolution = resolution;
+        self.framerate = framerate;
+    }
+    
+    // Takes effect the next time screen sharing starts
+    pub fn set_screen(&mut self, index: u32) {
+        self.screen_index = index;
+    }
+    
+    // Takes effect the next time screen sharing starts; None shares the whole screen
+    pub fn set_region(&mut self, region: Option<ScreenRegion>) {
+        self.region = region;
+    }
+    
+    // Size frames are captured and sent at. A region keeps its shape, shrunk to fit within the
+    // selected resolution.
+    fn capture_size(&self) -> VideoResolution {
+        match self.region.filter(|_| self.capture_type == CaptureType::Screen) {
+            Some(region) => fit_region(region, self.resolution),
+            None => self.resolution,
+        }
+    }
+    
     pub fn set_device(&mut self, device_name: &str) {
         self.device_name = Some(device_name.to_string());
     }
     
-    pub fn initialize(&mut self) -> Result<()> {
-        // Initialize video backend if needed
-        #[cfg(feature = "video")]
-        {
-            gst::init()?;
-        }
-        
-        Ok(())
+    // Initialize GStreamer, if it hasn't been already. Safe to call from any thread.
+    pub fn ensure_initialized() -> Result<()> {
+        GstreamerContext::get()
+            .clone()
+            .map_err(|e| anyhow::anyhow!("Failed to initialize GStreamer: {}", e))
+    }
+    
+    // False when built without video support, or when GStreamer isn't installed
+    pub fn is_gstreamer_available() -> bool {
+        cfg!(feature = "video") && Self::ensure_initialized().is_ok()
     }
     
     pub fn start_camera(&mut self) -> Result<()> {
@@ -155,6 +522,8 @@ impl VideoManager {
     }
     
     fn start_capture(&mut self) -> Result<()> {
+        Self::ensure_initialized()?;
+        
         // Start sender task for video data
         let rx = self.rx.clone();
         let connection = self.connection.clone();
@@ -162,39 +531,18 @@ impl VideoManager {
         let channel_id = self.channel_id;
         let active = self.active.clone();
         let is_screen_share = self.capture_type == CaptureType::Screen;
+        let video_codec = self.codec;
+        let encoding = Arc::clone(&self.encoding);
+        let resolution = self.capture_size();
+        let region = self.region;
+        let bitrate = self.bitrate.clone();
+        let last_pts_ms = Arc::clone(&self.last_pts_ms);
         
-        #[cfg(feature = "video")]
-        {
-            // In a real implementation with gstreamer, we would initialize the pipeline here
-            // For simplicity, we're omitting the actual video capture code
-            tracing::info!("Video capture would be initialized with GStreamer in a full implementation");
-        }
+        // Set before the threads start, so stopping straight away can't be missed
+        self.active.store(true, Ordering::SeqCst);
+        self.spawn_frame_source();
         
-        // Generate mock video data for demonstration
-        let tx = self.tx.clone();
-        std::thread::spawn(move || {
-            // Generate mock frame data (RGB data)
-            let frame_size = (VIDEO_WIDTH * VIDEO_HEIGHT * 3) as usize;
-            let mut dummy_frame = vec![0u8; frame_size];
-            
-            // Generate some pattern for the frame
-            for i in 0..frame_size / 3 {
-                let x = (i % VIDEO_WIDTH as usize) as f32 / VIDEO_WIDTH as f32;
-                let y = (i / VIDEO_WIDTH as usize) as f32 / VIDEO_HEIGHT as f32;
-                
-                dummy_frame[i * 3] = (x * 255.0) as u8;      // R
-                dummy_frame[i * 3 + 1] = (y * 255.0) as u8;  // G
-                dummy_frame[i * 3 + 2] = 128;                 // B
-            }
-            
-            // Send a frame periodically
-            let _frame_interval = std::time::Duration::from_millis(1000 / VIDEO_FRAMERATE as u64);
-            let _ = tx.try_send(dummy_frame);
-        });
-        
-        std::thread::spawn(move || {
-            active.store(true, Ordering::SeqCst);
-            
+        self.sender_thread = Some(std::thread::spawn(move || {
             // Send started message
             let started_message = if is_screen_share {
                 open_reverb_common::protocol::Message::ScreenShareStarted { user_id }
@@ -206,19 +554,63 @@ impl VideoManager {
                 tracing::error!("Failed to send video/screenshare started message: {}", e);
             }
             
+            // Viewers may still have a frame from a share of a different shape
+            if is_screen_share {
+                let region_message = open_reverb_common::protocol::Message::ScreenShareRegionChanged { user_id, region };
+                if let Err(e) = connection.get_sender().send(region_message) {
+                    tracing::error!("Failed to send screen share region: {}", e);
+                }
+            }
+            
+            let mut seq = Connection::initial_seq();
+            
+            // Bytes sent in the current second, to keep within the target bitrate
+            let mut window_start = Instant::now();
+            let mut window_bytes = 0u64;
+            
             while active.load(Ordering::SeqCst) {
-                if let Ok(data) = rx.recv_timeout(std::time::Duration::from_millis(100)) {
+                if let Ok(frame) = rx.recv_timeout(std::time::Duration::from_millis(100)) {
+                    bitrate.recover();
+                    
+                    if window_start.elapsed() >= Duration::from_secs(1) {
+                        window_start = Instant::now();
+                        window_bytes = 0;
+                    }
+                    
+                    // Skip frames once this second's budget is spent, lowering the frame rate
+                    if window_bytes >= bitrate.bitrate() as u64 / 8 {
+                        continue;
+                    }
+                    
+                    // Encoded only once it's known to be sent, so skipped frames cost nothing
+                    let encoding = *encoding.lock().unwrap();
+                    let data = match encoding::encode_frame(encoding, &frame, resolution.width, resolution.height) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            tracing::error!("Failed to encode video frame: {}", e);
+                            continue;
+                        }
+                    };
+                    window_bytes += data.len() as u64;
+                    
+                    let captured_at_ms = sync::now_millis();
+                    last_pts_ms.store(captured_at_ms, Ordering::Relaxed);
+                    
                     // Send video data
                     let message = if is_screen_share {
                         open_reverb_common::protocol::Message::ScreenShareData {
                             user_id,
                             channel_id,
+                            seq,
                             data,
                         }
                     } else {
                         open_reverb_common::protocol::Message::VideoData {
                             user_id,
                             channel_id,
+                            seq,
+                            captured_at_ms,
+                            video_codec,
                             data,
                         }
                     };
@@ -226,6 +618,7 @@ impl VideoManager {
                     if let Err(e) = connection.get_sender().send(message) {
                         tracing::error!("Failed to send video/screenshare data: {}", e);
                     }
+                    seq = seq.wrapping_add(1);
                 }
             }
             
@@ -239,11 +632,143 @@ impl VideoManager {
             if let Err(e) = connection.get_sender().send(stopped_message) {
                 tracing::error!("Failed to send video/screenshare stopped message: {}", e);
             }
-        });
+        }));
         
         Ok(())
     }
     
+    // Capture frames locally without sending them, e.g. for a camera preview.
+    // Frames are collected with `preview_frame`.
+    pub fn start_preview(&mut self) -> Result<()> {
+        if self.is_active() {
+            return Ok(());
+        }
+        
+        Self::ensure_initialized()?;
+        
+        self.active.store(true, Ordering::SeqCst);
+        self.spawn_frame_source();
+        
+        Ok(())
+    }
+    
+    // Most recent frame captured for the preview, as RGB
+    pub fn preview_frame(&self) -> Option<Vec<u8>> {
+        self.rx.try_iter().last()
+    }
+    
+    pub fn get_dimensions(&self) -> (i32, i32) {
+        let size = self.capture_size();
+        (size.width as i32, size.height as i32)
+    }
+    
+    // Frames come from the camera or screen through GStreamer when it can be opened, and
+    // otherwise from a test pattern
+    fn spawn_frame_source(&mut self) {
+        #[cfg(feature = "video")]
+        {
+            let description = match self.capture_type {
+                CaptureType::Camera => Ok(camera_pipeline_description(self.device_name.as_deref(), self.resolution, self.framerate)),
+                CaptureType::Screen => screen::detect_source(self.screen_index, self.region)
+                    .map(|source| screen::pipeline_description(&source, self.capture_size(), self.framerate)),
+            };
+            
+            match description.and_then(|description| self.start_pipeline(&description)) {
+                Ok(pipeline) => {
+                    self.pipeline = Some(pipeline);
+                    return;
+                }
+                Err(e) => {
+                    let source = if self.capture_type == CaptureType::Camera { "camera" } else { "screen" };
+                    tracing::error!("Couldn't capture the {}, sending a test pattern instead: {}", source, e);
+                }
+            }
+        }
+        
+        self.spawn_test_pattern();
+    }
+    
+    #[cfg(feature = "video")]
+    fn start_pipeline(&self, description: &str) -> Result<gst::Pipeline> {
+        let pipeline = gst::parse_launch(description)?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow::anyhow!("Capture isn't a pipeline"))?;
+        let appsink = pipeline
+            .by_name("sink")
+            .and_then(|e| e.downcast::<gst_app::AppSink>().ok())
+            .ok_or_else(|| anyhow::anyhow!("Capture has no appsink"))?;
+        
+        // Only the newest frames are worth sending; older ones are dropped rather than queued
+        appsink.set_max_buffers(2);
+        appsink.set_drop(true);
+        
+        let tx = self.tx.clone();
+        let dropped_frames = Arc::clone(&self.dropped_frames);
+        let mut effects = self.camera_effects();
+        let VideoResolution { width, height } = self.capture_size();
+        appsink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    
+                    let frame = effects.apply(map.as_slice().to_vec(), width, height);
+                    audio::queue_frame(&tx, frame, &dropped_frames);
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+        
+        if let Err(e) = pipeline.set_state(gst::State::Playing) {
+            let _ = pipeline.set_state(gst::State::Null);
+            return Err(e.into());
+        }
+        
+        Ok(pipeline)
+    }
+    
+    fn spawn_test_pattern(&self) {
+        let tx = self.tx.clone();
+        let active = Arc::clone(&self.active);
+        let dropped_frames = Arc::clone(&self.dropped_frames);
+        let mut effects = self.camera_effects();
+        let VideoResolution { width, height } = self.capture_size();
+        let frame_interval = self.framerate.frame_interval();
+        std::thread::spawn(move || {
+            // Generate mock frame data (RGB data)
+            let frame_size = (width * height * 3) as usize;
+            let mut pattern = vec![0u8; frame_size];
+            
+            // Generate some pattern for the frame
+            for i in 0..frame_size / 3 {
+                let x = (i % width as usize) as f32 / width as f32;
+                let y = (i / width as usize) as f32 / height as f32;
+                
+                pattern[i * 3] = (x * 255.0) as u8;      // R
+                pattern[i * 3 + 1] = (y * 255.0) as u8;  // G
+                pattern[i * 3 + 2] = 128;                 // B
+            }
+            
+            // Send a frame periodically, until capture stops
+            while active.load(Ordering::SeqCst) {
+                let started = Instant::now();
+                let frame = effects.apply(pattern.clone(), width, height);
+                audio::queue_frame(&tx, frame, &dropped_frames);
+                thread::sleep(frame_interval.saturating_sub(started.elapsed()));
+            }
+        });
+    }
+    
+    fn camera_effects(&self) -> CameraEffects {
+        CameraEffects {
+            is_camera: self.capture_type == CaptureType::Camera,
+            background_blur: Arc::clone(&self.background_blur),
+            privacy_mode: Arc::clone(&self.privacy_mode),
+            blur: BackgroundBlur::new(0.0),
+        }
+    }
+    
     pub fn stop(&mut self) {
         self.active.store(false, Ordering::SeqCst);
         
@@ -252,6 +777,11 @@ impl VideoManager {
             let _ = pipeline.set_state(gst::State::Null);
             self.pipeline = None;
         }
+        
+        // Its receive times out every 100ms, so this doesn't wait on the next frame
+        if let Some(handle) = self.sender_thread.take() {
+            let _ = handle.join();
+        }
     }
     
     pub fn get_available_video_devices() -> Vec<String> {
@@ -259,8 +789,232 @@ impl VideoManager {
         vec!["Default Camera".to_string(), "External Webcam".to_string()]
     }
     
+    // Monitors that can be shared, by index for `set_screen`
     pub fn get_available_screens() -> Vec<String> {
-        // For screen sharing, we typically just return a list of monitors
+        #[cfg(feature = "video")]
+        {
+            let monitors = screen::monitors();
+            if !monitors.is_empty() {
+                return monitors.iter().map(|m| m.label()).collect();
+            }
+        }
+        
         vec!["Primary Display".to_string(), "Secondary Display".to_string()]
     }
-}
\ No newline at end of file
+}
+// The largest size with the region's shape that fits within `bounds`, without scaling it up.
+// Kept to even dimensions, which video encoders need.
+fn fit_region(region: ScreenRegion, bounds: VideoResolution) -> VideoResolution {
+    let scale = (bounds.width as f32 / region.width.max(1) as f32)
+        .min(bounds.height as f32 / region.height.max(1) as f32)
+        .min(1.0);
+    let even = |size: u32| (((size as f32 * scale).round() as u32) & !1).max(2);
+    
+    VideoResolution { width: even(region.width), height: even(region.height) }
+}
+
+// Background blur and privacy mode, applied to camera frames after capture and before they're
+// queued to be sent. Settings are read for every frame, so changes apply straight away.
+struct CameraEffects {
+    is_camera: bool,
+    background_blur: Arc<AtomicU32>,
+    privacy_mode: Arc<AtomicBool>,
+    blur: BackgroundBlur,
+}
+
+impl CameraEffects {
+    fn apply(&mut self, mut frame: Vec<u8>, width: u32, height: u32) -> Vec<u8> {
+        if !self.is_camera {
+            return frame;
+        }
+        
+        let strength = f32::from_bits(self.background_blur.load(Ordering::Relaxed));
+        if strength > 0.0 {
+            self.blur.set_strength(strength);
+            self.blur.process_frame(&mut frame, width, height);
+        }
+        if self.privacy_mode.load(Ordering::Relaxed) {
+            frame = privacy_blur(frame, width, height);
+        }
+        frame
+    }
+}
+
+// v4l2src takes a device path. The names in the device list aren't paths yet, so anything else
+// opens the default camera.
+#[cfg(feature = "video")]
+fn camera_pipeline_description(device_name: Option<&str>, resolution: VideoResolution, framerate: VideoFramerate) -> String {
+    let device = match device_name {
+        Some(path) if path.starts_with("/dev/") => format!(" device={}", path),
+        _ => String::new(),
+    };
+    format!(
+        "v4l2src{} ! videoconvert ! videoscale ! videorate \
+         ! video/x-raw,format=RGB,width={},height={},framerate={}/1 ! appsink name=sink sync=false",
+        device, resolution.width, resolution.height, framerate.0
+    )
+}
+
+// Blur a frame heavily enough that nothing in it can be made out
+fn privacy_blur(frame: Vec<u8>, width: u32, height: u32) -> Vec<u8> {
+    match image::RgbImage::from_raw(width, height, frame) {
+        Some(image) => image::imageops::blur(&image, PRIVACY_BLUR_SIGMA).into_raw(),
+        None => vec![0; (width * height * 3) as usize],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn concurrent_initialization_happens_once() {
+        let threads: Vec<_> = (0..8)
+            .map(|_| thread::spawn(|| VideoManager::ensure_initialized().map_err(|e| e.to_string())))
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().expect("initialization panicked")).collect();
+        
+        // Every caller sees the one result, whether or not GStreamer is installed
+        assert!(results.windows(2).all(|pair| pair[0] == pair[1]));
+        assert_eq!(results[0].is_ok(), VideoManager::ensure_initialized().is_ok());
+    }
+    
+    #[test]
+    fn gstreamer_availability_follows_initialization() {
+        let available = VideoManager::is_gstreamer_available();
+        
+        if cfg!(feature = "video") {
+            assert_eq!(available, VideoManager::ensure_initialized().is_ok());
+        } else {
+            assert!(!available);
+        }
+    }
+    
+    #[cfg(feature = "video")]
+    #[test]
+    fn camera_pipeline_opens_the_selected_device_at_the_selected_format() {
+        let description = camera_pipeline_description(Some("/dev/video2"), VIDEO_PRESETS[2].0, VIDEO_PRESETS[2].1);
+        assert!(description.starts_with("v4l2src device=/dev/video2 !"));
+        assert!(description.contains("video/x-raw,format=RGB,width=1280,height=720,framerate=30/1"));
+        
+        // Not a path, so the default camera
+        assert!(camera_pipeline_description(Some("Default Camera"), Default::default(), Default::default()).starts_with("v4l2src !"));
+    }
+    
+    #[test]
+    fn textures_follow_the_latest_frame_until_the_video_stops() {
+        let ctx = egui::Context::default();
+        let mut playback = VideoPlayback::new();
+        let user_id = Uuid::new_v4();
+        assert!(playback.get_or_update_texture(user_id, &ctx).is_none());
+        
+        let rgb = vec![128u8; 320 * 240 * 3];
+        playback.process_video_data(user_id, encoding::encode_frame(VideoEncoding::Raw, &rgb, 320, 240).unwrap());
+        let texture = playback.get_or_update_texture(user_id, &ctx).unwrap();
+        assert_eq!(texture.size(), [320, 240]);
+        
+        // The same texture is reused, not reloaded
+        assert_eq!(playback.get_or_update_texture(user_id, &ctx).unwrap().id(), texture.id());
+        
+        // Nothing for longer than the timeout, so it's freed
+        playback.last_updates.insert(user_id, Instant::now() - VIDEO_ACTIVE_TIMEOUT);
+        playback.prune_textures();
+        assert!(playback.textures.is_empty());
+        assert!(playback.get_or_update_texture(user_id, &ctx).is_none());
+    }
+    
+    #[test]
+    fn full_send_buffers_drop_and_count_new_frames() {
+        let connection = Arc::new(Connection::new());
+        let manager = VideoManager::new(Uuid::new_v4(), Uuid::new_v4(), connection, CaptureType::Camera, VideoCodec::H264, 2);
+        
+        for frame in 0..6u8 {
+            audio::queue_frame(&manager.tx, vec![frame], &manager.dropped_frames);
+        }
+        let stats = manager.buffer_stats();
+        assert_eq!((stats.capacity, stats.current_depth, stats.dropped_frames), (2, 2, 4));
+        assert_eq!(manager.rx.try_iter().collect::<Vec<_>>(), [vec![0], vec![1]]);
+    }
+    
+    #[test]
+    fn video_in_codecs_without_a_decoder_is_flagged() {
+        let ctx = egui::Context::default();
+        let mut playback = VideoPlayback::new();
+        playback.capabilities = ClientCapabilities { supported_video_codecs: vec![VideoCodec::VP8] };
+        let (vp8, av1, unknown) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        
+        // Judged by what they said they'd send until a frame arrives
+        playback.set_codec(vp8, VideoCodec::VP8);
+        playback.set_codec(av1, VideoCodec::AV1);
+        assert!(playback.can_decode(vp8));
+        assert!(!playback.can_decode(av1));
+        assert!(playback.can_decode(unknown));
+        
+        // Encoded frames in it are dropped, with nothing to show
+        let h264 = Uuid::new_v4();
+        playback.set_codec(h264, VideoCodec::H264);
+        // H.264 format byte, then a 2x2 frame's width and height
+        playback.process_video_data(h264, vec![2, 0, 2, 0, 2, 0, 0, 0, 1]);
+        assert!(!playback.can_decode(h264));
+        assert!(playback.get_or_update_texture(h264, &ctx).is_none());
+        
+        // Raw frames play whatever the codec is labelled
+        let rgb = vec![0u8; 4 * 4 * 3];
+        playback.process_video_data(av1, encoding::encode_frame(VideoEncoding::Raw, &rgb, 4, 4).unwrap());
+        assert!(playback.can_decode(av1));
+        assert!(playback.get_or_update_texture(av1, &ctx).is_some());
+        
+        // Forgotten once they leave
+        playback.remove_user(h264);
+        assert_eq!(playback.codec(h264), None);
+        assert!(playback.can_decode(h264));
+    }
+    
+    #[test]
+    fn regions_keep_their_shape_within_the_resolution() {
+        let hd = VideoResolution { width: 1280, height: 720 };
+        
+        // A tall window, shrunk to fit the height
+        let tall = ScreenRegion { x: 100, y: 0, width: 800, height: 1400 };
+        assert_eq!(fit_region(tall, hd), VideoResolution { width: 410, height: 720 });
+        
+        // Already small enough, so not scaled up
+        let small = ScreenRegion { x: 0, y: 0, width: 301, height: 200 };
+        assert_eq!(fit_region(small, hd), VideoResolution { width: 300, height: 200 });
+    }
+    
+    #[test]
+    fn changing_the_shared_region_drops_the_old_frame() {
+        let ctx = egui::Context::default();
+        let mut playback = VideoPlayback::new();
+        let user_id = Uuid::new_v4();
+        playback.process_video_data(user_id, encoding::encode_frame(VideoEncoding::Raw, &[0; 4 * 4 * 3], 4, 4).unwrap());
+        assert!(playback.get_or_update_texture(user_id, &ctx).is_some());
+        
+        playback.set_screen_region(user_id, Some(ScreenRegion { x: 0, y: 0, width: 16, height: 9 }));
+        assert!(playback.get_or_update_texture(user_id, &ctx).is_none());
+        
+        playback.process_video_data(user_id, encoding::encode_frame(VideoEncoding::Raw, &[0; 16 * 9 * 3], 16, 9).unwrap());
+        assert_eq!(playback.get_or_update_texture(user_id, &ctx).unwrap().size(), [16, 9]);
+    }
+    
+    #[test]
+    fn stop_returns_promptly_while_frames_are_flowing() {
+        if VideoManager::ensure_initialized().is_err() {
+            return;
+        }
+        
+        let mut manager = VideoManager::new(Uuid::new_v4(), Uuid::new_v4(), Arc::new(Connection::new()), CaptureType::Screen, VideoCodec::default(), 4);
+        manager.start_screen_sharing().unwrap();
+        
+        // The mock frame source keeps sending; the sender checks for stopping between frames
+        std::thread::sleep(Duration::from_millis(150));
+        
+        let started = Instant::now();
+        manager.stop();
+        
+        assert!(started.elapsed() < Duration::from_millis(200), "stop took {:?}", started.elapsed());
+        assert!(!manager.is_active());
+        assert!(manager.sender_thread.is_none());
+    }
+}
@@ -0,0 +1,217 @@
+// Blurs the background of camera frames, keeping the person in front of it sharp.
+//
+// Which pixels are the person comes from a segmentation mask: 1.0 for the person, 0.0 for the
+// background. There's no segmentation model in the client yet (it would need an ONNX or
+// WebAssembly runtime), so process_frame assumes a head and shoulders in the middle of the
+// frame, as in most webcam shots. A model's mask can be given to process_frame_with_mask
+// instead.
+
+// Blur radius at full strength, in pixels
+const MAX_BLUR_RADIUS: f32 = 12.0;
+
+// How much of the portrait mask's edge fades from person to background, as a fraction of its size
+const MASK_FEATHER: f32 = 0.2;
+
+pub struct BackgroundBlur {
+    // 0.0 - 1.0
+    strength: f32,
+    // Portrait mask for the last frame size, so it isn't rebuilt every frame
+    mask: Vec<f32>,
+    mask_size: (u32, u32),
+}
+
+impl BackgroundBlur {
+    pub fn new(strength: f32) -> Self {
+        Self {
+            strength: strength.clamp(0.0, 1.0),
+            mask: Vec::new(),
+            mask_size: (0, 0),
+        }
+    }
+    
+    pub fn set_strength(&mut self, strength: f32) {
+        self.strength = strength.clamp(0.0, 1.0);
+    }
+    
+    // Blur the background of an RGB or RGBA frame in place
+    pub fn process_frame(&mut self, frame: &mut [u8], width: u32, height: u32) {
+        if self.mask_size != (width, height) {
+            self.mask = portrait_mask(width, height);
+            self.mask_size = (width, height);
+        }
+        
+        let mask = std::mem::take(&mut self.mask);
+        self.process_frame_with_mask(frame, width, height, &mask);
+        self.mask = mask;
+    }
+    
+    // As process_frame, with a mask of one value (0.0 - 1.0) per pixel
+    pub fn process_frame_with_mask(&self, frame: &mut [u8], width: u32, height: u32, mask: &[f32]) {
+        let (width, height) = (width as usize, height as usize);
+        let pixels = width * height;
+        if pixels == 0 || mask.len() != pixels || !frame.len().is_multiple_of(pixels) {
+            return;
+        }
+        
+        let radius = (self.strength * MAX_BLUR_RADIUS).round() as usize;
+        if radius == 0 {
+            return;
+        }
+        
+        let channels = frame.len() / pixels;
+        let blurred = gaussian_blur(frame, width, height, channels, radius);
+        
+        for (pixel, foreground) in mask.iter().enumerate() {
+            let foreground = foreground.clamp(0.0, 1.0);
+            for c in pixel * channels..(pixel + 1) * channels {
+                let sharp = frame[c] as f32;
+                frame[c] = (sharp * foreground + blurred[c] * (1.0 - foreground)).round() as u8;
+            }
+        }
+    }
+}
+
+// A head and shoulders: an ellipse in the lower middle of the frame, with a soft edge
+fn portrait_mask(width: u32, height: u32) -> Vec<f32> {
+    let (width, height) = (width as f32, height as f32);
+    let (centre_x, centre_y) = (width * 0.5, height * 0.65);
+    let (radius_x, radius_y) = (width * 0.3, height * 0.55);
+    
+    let mut mask = Vec::with_capacity((width * height) as usize);
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            let dx = (x as f32 + 0.5 - centre_x) / radius_x;
+            let dy = (y as f32 + 0.5 - centre_y) / radius_y;
+            let distance = (dx * dx + dy * dy).sqrt();
+            mask.push(((1.0 + MASK_FEATHER / 2.0 - distance) / MASK_FEATHER).clamp(0.0, 1.0));
+        }
+    }
+    mask
+}
+
+// Three box blurs in a row, which is close enough to a Gaussian and doesn't slow down as the
+// radius grows
+fn gaussian_blur(frame: &[u8], width: usize, height: usize, channels: usize, radius: usize) -> Vec<f32> {
+    let mut image: Vec<f32> = frame.iter().map(|v| *v as f32).collect();
+    let mut scratch = vec![0.0; image.len()];
+    
+    // Three passes of half the radius spread about as far as a Gaussian with a standard
+    // deviation of half the radius
+    let pass_radius = (radius / 2).max(1);
+    for _ in 0..3 {
+        box_blur(&image, &mut scratch, width, height, channels, pass_radius, true);
+        box_blur(&scratch, &mut image, width, height, channels, pass_radius, false);
+    }
+    image
+}
+
+// Average each value with the `radius` either side of it, along rows or columns. The edges
+// are extended, so they don't darken.
+fn box_blur(src: &[f32], dst: &mut [f32], width: usize, height: usize, channels: usize, radius: usize, horizontal: bool) {
+    let (lines, length) = if horizontal { (height, width) } else { (width, height) };
+    let index = |line: usize, i: usize, c: usize| {
+        let (x, y) = if horizontal { (i, line) } else { (line, i) };
+        (y * width + x) * channels + c
+    };
+    let window = (2 * radius + 1) as f32;
+    
+    for line in 0..lines {
+        for c in 0..channels {
+            let at = |i: isize| src[index(line, i.clamp(0, length as isize - 1) as usize, c)];
+            
+            let mut sum: f32 = (-(radius as isize)..=radius as isize).map(at).sum();
+            for i in 0..length {
+                dst[index(line, i, c)] = sum / window;
+                sum += at(i as isize + radius as isize + 1) - at(i as isize - radius as isize);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 48;
+    
+    // Black and white squares, so blurring turns them grey
+    fn checkerboard(channels: usize) -> Vec<u8> {
+        let mut frame = Vec::new();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let value = if (x / 4 + y / 4) % 2 == 0 { 255 } else { 0 };
+                frame.extend(std::iter::repeat_n(value, channels));
+            }
+        }
+        frame
+    }
+    
+    // The left half of the frame is the person
+    fn left_half_mask() -> Vec<f32> {
+        (0..WIDTH * HEIGHT).map(|i| if i % WIDTH < WIDTH / 2 { 1.0 } else { 0.0 }).collect()
+    }
+    
+    fn pixel(frame: &[u8], x: u32, y: u32, channels: usize) -> &[u8] {
+        let start = (y * WIDTH + x) as usize * channels;
+        &frame[start..start + channels]
+    }
+    
+    #[test]
+    fn foreground_stays_sharp_and_background_is_blurred() {
+        let original = checkerboard(3);
+        let mut frame = original.clone();
+        
+        BackgroundBlur::new(1.0).process_frame_with_mask(&mut frame, WIDTH, HEIGHT, &left_half_mask());
+        
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH / 2 {
+                assert_eq!(pixel(&frame, x, y, 3), pixel(&original, x, y, 3));
+            }
+        }
+        
+        // Well away from the edges, the squares blur to grey
+        for x in WIDTH / 2 + 8..WIDTH - 8 {
+            let value = pixel(&frame, x, HEIGHT / 2, 3)[0];
+            assert!((96..=160).contains(&value), "({}, {}) is {}", x, HEIGHT / 2, value);
+        }
+    }
+    
+    #[test]
+    fn rgba_frames_keep_their_size() {
+        let mut frame = checkerboard(4);
+        
+        BackgroundBlur::new(0.5).process_frame_with_mask(&mut frame, WIDTH, HEIGHT, &left_half_mask());
+        
+        assert_eq!(frame.len(), (WIDTH * HEIGHT * 4) as usize);
+        assert_ne!(frame, checkerboard(4));
+    }
+    
+    #[test]
+    fn zero_strength_leaves_the_frame_untouched() {
+        let mut frame = checkerboard(3);
+        
+        BackgroundBlur::new(0.0).process_frame(&mut frame, WIDTH, HEIGHT);
+        
+        assert_eq!(frame, checkerboard(3));
+    }
+    
+    #[test]
+    fn plain_background_is_unchanged_by_blurring() {
+        let mut frame = vec![200u8; (WIDTH * HEIGHT * 3) as usize];
+        
+        BackgroundBlur::new(1.0).process_frame_with_mask(&mut frame, WIDTH, HEIGHT, &vec![0.0; (WIDTH * HEIGHT) as usize]);
+        
+        assert!(frame.iter().all(|v| *v == 200));
+    }
+    
+    #[test]
+    fn portrait_mask_keeps_the_middle_and_blurs_the_corners() {
+        let mask = portrait_mask(WIDTH, HEIGHT);
+        let at = |x: u32, y: u32| mask[(y * WIDTH + x) as usize];
+        
+        assert_eq!(at(WIDTH / 2, HEIGHT * 2 / 3), 1.0);
+        assert_eq!(at(0, 0), 0.0);
+        assert_eq!(at(WIDTH - 1, 0), 0.0);
+    }
+}
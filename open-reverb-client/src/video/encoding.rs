@@ -0,0 +1,179 @@
+// How outgoing video frames are compressed. Every frame goes out with a header naming its format
+// and size, so receivers decode each frame the way it was sent, and frames in a format from a
+// newer client can be told apart and skipped:
+//
+//   format (1 byte) | width (u16, big-endian) | height (u16, big-endian) | payload
+
+use image::codecs::jpeg::JpegEncoder;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+// Format header bytes. H.264 frames are whatever codec the VideoData says, decoded by GStreamer.
+const FORMAT_RAW: u8 = 0;
+const FORMAT_JPEG: u8 = 1;
+const FORMAT_H264: u8 = 2;
+
+const HEADER_LEN: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VideoEncoding {
+    // Uncompressed RGB, 27 MB/s at 640x480 and 30fps; only for testing on one machine
+    Raw,
+    // Each frame compressed on its own, at a quality of 1 - 100
+    Jpeg { quality: u8 },
+    H264,
+}
+
+impl Default for VideoEncoding {
+    fn default() -> Self {
+        VideoEncoding::Jpeg { quality: DEFAULT_JPEG_QUALITY }
+    }
+}
+
+impl VideoEncoding {
+    pub fn label(&self) -> &'static str {
+        match self {
+            VideoEncoding::Raw => "Uncompressed",
+            VideoEncoding::Jpeg { .. } => "JPEG",
+            VideoEncoding::H264 => "H.264",
+        }
+    }
+}
+
+// A decoded frame, ready to upload as a texture
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoFrame {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+impl VideoFrame {
+    pub fn from_rgb(width: u32, height: u32, rgb: &[u8]) -> Option<Self> {
+        if rgb.len() != (width * height * 3) as usize {
+            return None;
+        }
+        
+        let rgba = rgb.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect();
+        Some(Self { width, height, rgba })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    Raw,
+    Jpeg,
+    H264,
+}
+
+// A received frame, split into its header and payload
+pub struct EncodedFrame<'a> {
+    pub format: FrameFormat,
+    pub width: u32,
+    pub height: u32,
+    pub payload: &'a [u8],
+}
+
+// Encode an RGB frame to send, header included. The capture pipeline has no H.264 encoder yet,
+// so until it does, H.264 is sent as JPEG.
+pub fn encode_frame(encoding: VideoEncoding, rgb: &[u8], width: u32, height: u32) -> Result<Vec<u8>, String> {
+    let (Ok(w), Ok(h)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(format!("{}x{} is too big to send", width, height));
+    };
+    let header = |format: u8| {
+        let mut data = Vec::with_capacity(HEADER_LEN + rgb.len());
+        data.push(format);
+        data.extend_from_slice(&w.to_be_bytes());
+        data.extend_from_slice(&h.to_be_bytes());
+        data
+    };
+    
+    let quality = match encoding {
+        VideoEncoding::Raw => {
+            let mut data = header(FORMAT_RAW);
+            data.extend_from_slice(rgb);
+            return Ok(data);
+        }
+        VideoEncoding::Jpeg { quality } => quality.clamp(1, 100),
+        VideoEncoding::H264 => DEFAULT_JPEG_QUALITY,
+    };
+    
+    let mut data = header(FORMAT_JPEG);
+    JpegEncoder::new_with_quality(&mut data, quality)
+        .encode(rgb, width, height, image::ColorType::Rgb8)
+        .map_err(|e| format!("Couldn't encode a frame as JPEG: {}", e))?;
+    Ok(data)
+}
+
+// Split off the header; None for a truncated frame or a format this client doesn't know
+pub fn parse_frame(data: &[u8]) -> Option<EncodedFrame<'_>> {
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+    
+    let format = match data[0] {
+        FORMAT_RAW => FrameFormat::Raw,
+        FORMAT_JPEG => FrameFormat::Jpeg,
+        FORMAT_H264 => FrameFormat::H264,
+        _ => return None,
+    };
+    Some(EncodedFrame {
+        format,
+        width: u16::from_be_bytes([data[1], data[2]]) as u32,
+        height: u16::from_be_bytes([data[3], data[4]]) as u32,
+        payload: &data[HEADER_LEN..],
+    })
+}
+
+pub fn decode_jpeg(data: &[u8]) -> Option<VideoFrame> {
+    let image = image::load_from_memory_with_format(data, image::ImageFormat::Jpeg).ok()?.to_rgba8();
+    Some(VideoFrame { width: image.width(), height: image.height(), rgba: image.into_raw() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 48;
+    
+    // A smooth gradient, like the mock camera's
+    fn gradient() -> Vec<u8> {
+        (0..WIDTH * HEIGHT)
+            .flat_map(|i| [(i % WIDTH * 255 / WIDTH) as u8, (i / WIDTH * 255 / HEIGHT) as u8, 128])
+            .collect()
+    }
+    
+    #[test]
+    fn jpeg_frames_are_smaller_and_decode_close_to_the_original() {
+        let rgb = gradient();
+        let data = encode_frame(VideoEncoding::default(), &rgb, WIDTH, HEIGHT).unwrap();
+        assert!(data.len() < rgb.len() / 5, "{} bytes", data.len());
+        
+        let encoded = parse_frame(&data).unwrap();
+        assert_eq!((encoded.format, encoded.width, encoded.height), (FrameFormat::Jpeg, WIDTH, HEIGHT));
+        let frame = decode_jpeg(encoded.payload).unwrap();
+        assert_eq!((frame.width, frame.height), (WIDTH, HEIGHT));
+        
+        let original = VideoFrame::from_rgb(WIDTH, HEIGHT, &rgb).unwrap();
+        let worst = frame.rgba.iter().zip(original.rgba.iter()).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+        assert!(worst < 16, "off by up to {}", worst);
+    }
+    
+    #[test]
+    fn raw_frames_pass_through() {
+        let rgb = gradient();
+        let data = encode_frame(VideoEncoding::Raw, &rgb, WIDTH, HEIGHT).unwrap();
+        
+        let encoded = parse_frame(&data).unwrap();
+        assert_eq!((encoded.format, encoded.width, encoded.height), (FrameFormat::Raw, WIDTH, HEIGHT));
+        assert_eq!(encoded.payload, rgb.as_slice());
+    }
+    
+    #[test]
+    fn unknown_formats_are_skipped() {
+        assert!(parse_frame(&[FORMAT_JPEG, 0, 64]).is_none());
+        assert!(parse_frame(&[200, 0, 64, 0, 48, 1, 2, 3]).is_none());
+    }
+}
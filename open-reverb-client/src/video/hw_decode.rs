@@ -0,0 +1,283 @@
+// Decodes incoming H.264 video, on the GPU when there's a hardware decoder. Each sender's video
+// goes through a GStreamer pipeline of its own:
+//
+//   appsrc ! h264parse ! <decoder> ! videoconvert ! appsink
+//
+// where the decoder is VA-API on Linux, VideoToolbox on macOS or Direct3D 11 on Windows, and
+// avdec_h264 when decoding in software. A hardware pipeline that fails to start falls back to
+// software.
+//
+// Without the video feature frames are sent raw, so they're passed through as they are.
+
+#[cfg(feature = "video")]
+use std::collections::HashMap;
+#[cfg(feature = "video")]
+use std::time::Duration;
+use uuid::Uuid;
+
+#[cfg(feature = "video")]
+use gstreamer as gst;
+#[cfg(feature = "video")]
+use gstreamer::prelude::*;
+#[cfg(feature = "video")]
+use gstreamer_app as gst_app;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecoderBackend {
+    #[default]
+    Software,
+    Hardware,
+}
+
+// How long to wait for a decoded frame before moving on; a late frame is picked up with the next one
+#[cfg(feature = "video")]
+const DECODE_TIMEOUT: Duration = Duration::from_millis(20);
+
+// How long a pipeline has to start
+#[cfg(feature = "video")]
+const START_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[cfg(feature = "video")]
+const SOFTWARE_DECODER: &str = "avdec_h264";
+
+// The platform's hardware H.264 decoder element, if it has one
+#[cfg(feature = "video")]
+fn hardware_decoder() -> Option<&'static str> {
+    if cfg!(target_os = "linux") {
+        Some("vaapidecode")
+    } else if cfg!(target_os = "macos") {
+        Some("vtdec_hw")
+    } else if cfg!(target_os = "windows") {
+        Some("d3d11h264dec")
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "video")]
+fn pipeline_description(decoder: &str) -> String {
+    format!(
+        "appsrc name=src is-live=true do-timestamp=true caps=video/x-h264,stream-format=byte-stream,alignment=au \
+         ! h264parse ! {} ! videoconvert ! video/x-raw,format=RGB ! appsink name=sink sync=false",
+        decoder
+    )
+}
+
+// Decoders for every user sending H.264, created as their first frame arrives
+pub struct HardwareDecoderPool {
+    backend: DecoderBackend,
+    #[cfg(feature = "video")]
+    decoders: HashMap<Uuid, H264Decoder>,
+}
+
+impl HardwareDecoderPool {
+    pub fn new(backend: DecoderBackend) -> Self {
+        Self {
+            backend,
+            #[cfg(feature = "video")]
+            decoders: HashMap::new(),
+        }
+    }
+    
+    // Existing decoders are rebuilt with the new backend as their next frames arrive
+    pub fn set_backend(&mut self, backend: DecoderBackend) {
+        if backend == self.backend {
+            return;
+        }
+        
+        self.backend = backend;
+        #[cfg(feature = "video")]
+        self.decoders.clear();
+    }
+    
+    // Decode one access unit into an RGB frame. None until the decoder has a frame ready.
+    pub fn decode(&mut self, user_id: Uuid, data: Vec<u8>) -> Option<Vec<u8>> {
+        #[cfg(feature = "video")]
+        {
+            let decoder = match self.decoders.entry(user_id) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => match H264Decoder::new(self.backend) {
+                    Ok(decoder) => entry.insert(decoder),
+                    Err(e) => {
+                        tracing::error!("Failed to create a video decoder for {}: {}", user_id, e);
+                        return None;
+                    }
+                },
+            };
+            
+            match decoder.decode(data) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    tracing::error!("Failed to decode video from {}: {}", user_id, e);
+                    None
+                }
+            }
+        }
+        
+        #[cfg(not(feature = "video"))]
+        {
+            let _ = user_id;
+            Some(data)
+        }
+    }
+    
+    pub fn remove_user(&mut self, user_id: Uuid) {
+        #[cfg(feature = "video")]
+        self.decoders.remove(&user_id);
+        #[cfg(not(feature = "video"))]
+        let _ = user_id;
+    }
+    
+    // Whether any user's video is being decoded on the GPU
+    #[cfg(feature = "video")]
+    pub fn hw_decode_active(&self) -> bool {
+        self.decoders.values().any(|d| d.hardware)
+    }
+    
+    #[cfg(not(feature = "video"))]
+    pub fn hw_decode_active(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "video")]
+struct H264Decoder {
+    pipeline: gst::Pipeline,
+    appsrc: gst_app::AppSrc,
+    appsink: gst_app::AppSink,
+    // Set when decoding on the GPU
+    hardware: bool,
+}
+
+#[cfg(feature = "video")]
+impl H264Decoder {
+    fn new(backend: DecoderBackend) -> anyhow::Result<Self> {
+        crate::video::VideoManager::ensure_initialized()?;
+        
+        if backend == DecoderBackend::Hardware {
+            let hardware = hardware_decoder()
+                .ok_or_else(|| anyhow::anyhow!("No hardware decoder on this platform"))
+                .and_then(|decoder| Self::with_decoder(decoder, true));
+            
+            match hardware {
+                Ok(decoder) => return Ok(decoder),
+                Err(e) => tracing::error!("Hardware video decoding unavailable, decoding in software: {}", e),
+            }
+        }
+        
+        Self::with_decoder(SOFTWARE_DECODER, false)
+    }
+    
+    fn with_decoder(decoder: &str, hardware: bool) -> anyhow::Result<Self> {
+        if gst::ElementFactory::find(decoder).is_none() {
+            anyhow::bail!("GStreamer has no {} element", decoder);
+        }
+        
+        let pipeline = gst::parse_launch(&pipeline_description(decoder))?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow::anyhow!("Decoder isn't a pipeline"))?;
+        let appsrc = pipeline
+            .by_name("src")
+            .and_then(|e| e.downcast::<gst_app::AppSrc>().ok())
+            .ok_or_else(|| anyhow::anyhow!("Decoder has no appsrc"))?;
+        let appsink = pipeline
+            .by_name("sink")
+            .and_then(|e| e.downcast::<gst_app::AppSink>().ok())
+            .ok_or_else(|| anyhow::anyhow!("Decoder has no appsink"))?;
+        
+        // Hardware decoders open their device while starting, so this is where a missing one shows up
+        pipeline.set_state(gst::State::Playing)?;
+        if let (Err(e), ..) = pipeline.state(gst::ClockTime::from_mseconds(START_TIMEOUT.as_millis() as u64)) {
+            let _ = pipeline.set_state(gst::State::Null);
+            return Err(e.into());
+        }
+        
+        Ok(Self { pipeline, appsrc, appsink, hardware })
+    }
+    
+    fn decode(&self, data: Vec<u8>) -> anyhow::Result<Option<Vec<u8>>> {
+        self.appsrc.push_buffer(gst::Buffer::from_mut_slice(data))?;
+        Ok(self.pull_frame(DECODE_TIMEOUT))
+    }
+    
+    fn pull_frame(&self, timeout: Duration) -> Option<Vec<u8>> {
+        let sample = self.appsink.try_pull_sample(gst::ClockTime::from_mseconds(timeout.as_millis() as u64))?;
+        let buffer = sample.buffer()?;
+        let map = buffer.map_readable().ok()?;
+        Some(map.as_slice().to_vec())
+    }
+}
+
+#[cfg(feature = "video")]
+impl Drop for H264Decoder {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[cfg(not(feature = "video"))]
+    #[test]
+    fn frames_pass_through_without_gstreamer() {
+        let mut pool = HardwareDecoderPool::new(DecoderBackend::Hardware);
+        let frame = vec![1, 2, 3];
+        
+        assert_eq!(pool.decode(Uuid::new_v4(), frame.clone()), Some(frame));
+        assert!(!pool.hw_decode_active());
+    }
+    
+    // One 16x16 macroblock of pure red (Y 81, Cb 90, Cr 240), stored uncompressed as I_PCM so it
+    // decodes to exactly that colour
+    #[cfg(feature = "video")]
+    const RED_FRAME: &[u8] = include_bytes!("../../tests/fixtures/red_16x16.h264");
+    
+    // Decode the red frame, returning it and whether it was decoded in hardware
+    #[cfg(feature = "video")]
+    fn decode_red_frame(backend: DecoderBackend) -> (Vec<u8>, bool) {
+        let decoder = H264Decoder::new(backend).expect("no H.264 decoder");
+        decoder.appsrc.push_buffer(gst::Buffer::from_slice(RED_FRAME)).unwrap();
+        // Flushes out the frame, however many the decoder holds on to
+        let _ = decoder.appsrc.end_of_stream();
+        
+        let frame = decoder.pull_frame(Duration::from_secs(5)).expect("no frame decoded");
+        (frame, decoder.hardware)
+    }
+    
+    #[cfg(feature = "video")]
+    fn assert_red(frame: &[u8]) {
+        assert_eq!(frame.len(), 16 * 16 * 3);
+        for pixel in frame.chunks_exact(3) {
+            assert!(pixel[0] >= 240 && pixel[1] <= 15 && pixel[2] <= 15, "{:?} isn't red", pixel);
+        }
+    }
+    
+    #[cfg(feature = "video")]
+    #[test]
+    fn software_decoder_produces_rgb() {
+        let (frame, hardware) = decode_red_frame(DecoderBackend::Software);
+        
+        assert!(!hardware);
+        assert_red(&frame);
+    }
+    
+    // Decodes on the GPU where there is one, and in software everywhere else
+    #[cfg(feature = "video")]
+    #[test]
+    fn hardware_decoder_produces_rgb_or_falls_back() {
+        let (frame, _) = decode_red_frame(DecoderBackend::Hardware);
+        
+        assert_red(&frame);
+    }
+    
+    #[cfg(feature = "video")]
+    #[test]
+    fn pipelines_use_the_chosen_decoder() {
+        let description = pipeline_description("vaapidecode");
+        
+        assert!(description.contains("h264parse ! vaapidecode ! videoconvert"));
+        assert_eq!(hardware_decoder().is_some(), cfg!(any(target_os = "linux", target_os = "macos", target_os = "windows")));
+    }
+}
@@ -0,0 +1,161 @@
+// Screen capture through GStreamer. On X11 the screen is read with ximagesrc, cropped to the
+// chosen monitor. Wayland doesn't let clients read the screen, so there it has to come from
+// PipeWire, as a stream the desktop hands over through the ScreenCast portal:
+//
+//   ximagesrc / pipewiresrc ! videoconvert ! videoscale ! videorate ! appsink
+
+use anyhow::Result;
+
+use open_reverb_common::models::ScreenRegion;
+use super::{VideoFramerate, VideoResolution};
+
+// One monitor, in the root window's coordinates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Monitor {
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Monitor {
+    pub fn label(&self) -> String {
+        format!("{} ({}x{})", self.name, self.width, self.height)
+    }
+    
+    pub fn region(&self) -> ScreenRegion {
+        ScreenRegion { x: self.x, y: self.y, width: self.width, height: self.height }
+    }
+}
+
+// Where the screen is captured from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScreenSource {
+    // The whole X display when there's nothing to crop to
+    X11 { display: String, area: Option<ScreenRegion> },
+    PipeWire { node_id: u32 },
+}
+
+// Monitors on the X display, or none when it can't be reached (e.g. on Wayland without XWayland)
+pub fn monitors() -> Vec<Monitor> {
+    #[cfg(target_os = "linux")]
+    match x11_monitors() {
+        Ok(monitors) => return monitors,
+        Err(e) => tracing::debug!("Couldn't list monitors: {}", e),
+    }
+    
+    Vec::new()
+}
+
+#[cfg(target_os = "linux")]
+fn x11_monitors() -> Result<Vec<Monitor>> {
+    use x11rb::connection::Connection as _;
+    use x11rb::protocol::randr::ConnectionExt as _;
+    use x11rb::protocol::xproto::ConnectionExt as _;
+    
+    let (conn, screen) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen].root;
+    
+    let reply = conn.randr_get_monitors(root, true)?.reply()?;
+    reply
+        .monitors
+        .iter()
+        .map(|monitor| {
+            let name = conn.get_atom_name(monitor.name)?.reply()?.name;
+            Ok(Monitor {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                x: monitor.x as i32,
+                y: monitor.y as i32,
+                width: monitor.width as u32,
+                height: monitor.height as u32,
+            })
+        })
+        .collect()
+}
+
+// Picked by the display server the client is running under. On X11 a region picked by the user
+// is captured in place of the monitor; on Wayland the portal asks the user what to share.
+pub fn detect_source(screen_index: u32, region: Option<ScreenRegion>) -> Result<ScreenSource> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        let node_id = ScreenCastPortal::new().start(screen_index)?;
+        return Ok(ScreenSource::PipeWire { node_id });
+    }
+    
+    Ok(ScreenSource::X11 {
+        display: std::env::var("DISPLAY").unwrap_or_else(|_| ":0".to_string()),
+        area: region.or_else(|| monitors().get(screen_index as usize).map(Monitor::region)),
+    })
+}
+
+pub fn pipeline_description(source: &ScreenSource, resolution: VideoResolution, framerate: VideoFramerate) -> String {
+    let src = match source {
+        // The end coordinates are inclusive
+        ScreenSource::X11 { display, area: Some(area) } => format!(
+            "ximagesrc display-name={} use-damage=false startx={} starty={} endx={} endy={}",
+            display,
+            area.x,
+            area.y,
+            area.x + area.width as i32 - 1,
+            area.y + area.height as i32 - 1
+        ),
+        ScreenSource::X11 { display, area: None } => format!("ximagesrc display-name={} use-damage=false", display),
+        ScreenSource::PipeWire { node_id } => format!("pipewiresrc path={} do-timestamp=true", node_id),
+    };
+    
+    format!(
+        "{} ! videoconvert ! videoscale ! videorate \
+         ! video/x-raw,format=RGB,width={},height={},framerate={}/1 ! appsink name=sink sync=false",
+        src, resolution.width, resolution.height, framerate.0
+    )
+}
+
+// The xdg-desktop-portal ScreenCast flow: CreateSession, SelectSources and Start, each answered
+// on a Request object named after a handle token. Start asks the user which screen to share and
+// returns the PipeWire node to read it from. This needs a D-Bus connection, which the client
+// doesn't have yet, so for now it goes as far as making the tokens and then fails.
+struct ScreenCastPortal {
+    session_token: String,
+    request_token: String,
+}
+
+impl ScreenCastPortal {
+    fn new() -> Self {
+        let token = || format!("open_reverb_{}", rand::random::<u32>());
+        Self { session_token: token(), request_token: token() }
+    }
+    
+    fn start(&self, screen_index: u32) -> Result<u32> {
+        tracing::debug!(
+            "ScreenCast portal session {} (request {}) for screen {}",
+            self.session_token,
+            self.request_token,
+            screen_index
+        );
+        anyhow::bail!("Sharing the screen on Wayland needs the ScreenCast portal, which isn't supported yet")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn x11_capture_is_cropped_to_the_monitor() {
+        let source = ScreenSource::X11 {
+            display: ":1".to_string(),
+            area: Some(Monitor { name: "DP-2".to_string(), x: 1920, y: 0, width: 2560, height: 1440 }.region()),
+        };
+        let description = pipeline_description(&source, VideoResolution { width: 1280, height: 720 }, VideoFramerate(30));
+        
+        assert!(description.starts_with("ximagesrc display-name=:1 use-damage=false startx=1920 starty=0 endx=4479 endy=1439 !"));
+        assert!(description.contains("video/x-raw,format=RGB,width=1280,height=720,framerate=30/1"));
+    }
+    
+    #[test]
+    fn wayland_capture_reads_the_portals_pipewire_node() {
+        let description = pipeline_description(&ScreenSource::PipeWire { node_id: 42 }, Default::default(), Default::default());
+        
+        assert!(description.starts_with("pipewiresrc path=42 "));
+    }
+}
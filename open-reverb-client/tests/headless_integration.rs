@@ -0,0 +1,202 @@
+// Runs a real server and headless clients, and checks that messages sent by one reach the
+// others they should.
+
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const CHANNEL: &str = "general-text";
+
+// Killed when dropped, so a failing test doesn't leave processes behind
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+struct HeadlessClient {
+    _process: Process,
+    stdin: ChildStdin,
+    // Lines of JSON from stdout, read on a separate thread
+    output: Receiver<Value>,
+}
+
+impl HeadlessClient {
+    fn start(port: u16, username: &str) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_open-reverb-client"))
+            .args(["--headless", "--server", &format!("127.0.0.1:{}", port)])
+            .args(["--username", username, "--channel", CHANNEL, "--command", "stdin"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the client");
+        
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        
+        let (tx, output) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                let value = serde_json::from_str(&line).unwrap_or_else(|_| panic!("not JSON: {}", line));
+                if tx.send(value).is_err() {
+                    break;
+                }
+            }
+        });
+        
+        Self { _process: Process(child), stdin, output }
+    }
+    
+    fn send(&mut self, command: &str) {
+        writeln!(self.stdin, "{}", command).unwrap();
+    }
+    
+    // The first line of output matching the predicate, skipping any others
+    fn wait_for(&self, description: &str, predicate: impl Fn(&Value) -> bool) -> Value {
+        let deadline = Instant::now() + TIMEOUT;
+        
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.output.recv_timeout(remaining) {
+                Ok(value) if predicate(&value) => return value,
+                Ok(_) => {}
+                Err(_) => panic!("timed out waiting for {}", description),
+            }
+        }
+    }
+}
+
+// The server binary sits next to the client's, once built
+fn server_binary() -> PathBuf {
+    let client = Path::new(env!("CARGO_BIN_EXE_open-reverb-client"));
+    let server = client.with_file_name(format!("open-reverb-server{}", std::env::consts::EXE_SUFFIX));
+    
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--quiet", "-p", "open-reverb-server"])
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "failed to build the server");
+    
+    server
+}
+
+// Start a server on a free port, with its config in a fresh directory
+fn start_server() -> (Process, u16) {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    
+    let dir = std::env::temp_dir().join(format!("open-reverb-headless-{}", port));
+    std::fs::create_dir_all(dir.join("config")).unwrap();
+    std::fs::write(dir.join("config/local.toml"), format!("host = \"127.0.0.1\"\nport = {}\n", port)).unwrap();
+    
+    let process = Process(
+        Command::new(server_binary())
+            .current_dir(&dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the server"),
+    );
+    
+    let deadline = Instant::now() + TIMEOUT;
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "server didn't start listening");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    
+    (process, port)
+}
+
+fn is_ready(value: &Value) -> bool {
+    value["event"] == "ready" && !value["channel_id"].is_null()
+}
+
+#[test]
+fn text_message_reaches_other_client() {
+    let (_server, port) = start_server();
+    
+    let mut alice = HeadlessClient::start(port, "alice");
+    let mut bob = HeadlessClient::start(port, "bob");
+    alice.wait_for("alice to join", is_ready);
+    bob.wait_for("bob to join", is_ready);
+    
+    alice.send("send-text hello from alice");
+    alice.wait_for("send-text result", |v| v["result"] == "send-text" && v["error"].is_null());
+    
+    let received = bob.wait_for("the message to reach bob", |v| {
+        v["event"] == "message" && v["message"]["TextMessage"]["content"] == "hello from alice"
+    });
+    assert!(received["message"]["TextMessage"]["user_id"].is_string());
+    
+    alice.send("quit");
+    bob.send("quit");
+}
+
+#[test]
+fn dump_commands_describe_the_server() {
+    let (_server, port) = start_server();
+    
+    let mut client = HeadlessClient::start(port, "carol");
+    client.wait_for("carol to join", is_ready);
+    
+    client.send("dump-channels");
+    let channels = client.wait_for("dump-channels result", |v| v["result"] == "dump-channels");
+    assert!(channels["channels"].as_array().unwrap().iter().any(|c| c["name"] == CHANNEL));
+    
+    client.send("dump-users");
+    let users = client.wait_for("dump-users result", |v| v["result"] == "dump-users");
+    assert!(users["users"].as_array().unwrap().iter().any(|u| u["username"] == "carol"));
+    
+    client.send("quit");
+}
+
+#[test]
+fn signalling_reaches_only_its_target() {
+    let (_server, port) = start_server();
+    
+    let mut alice = HeadlessClient::start(port, "alice");
+    let bob = HeadlessClient::start(port, "bob");
+    let carol = HeadlessClient::start(port, "carol");
+    alice.wait_for("alice to join", is_ready);
+    let bob_id = bob.wait_for("bob to join", is_ready)["user_id"].as_str().unwrap().to_string();
+    carol.wait_for("carol to join", is_ready);
+    
+    alice.send(&format!("sdp-offer {} v=0", bob_id));
+    alice.wait_for("sdp-offer result", |v| v["result"] == "sdp-offer" && v["error"].is_null());
+    let offer = bob.wait_for("the offer to reach bob", |v| v["event"] == "message" && !v["message"]["SDPOffer"].is_null());
+    assert_eq!(offer["message"]["SDPOffer"]["sdp"], "v=0");
+    
+    // Sent after the offer, so carol would have seen the offer first if it had reached her
+    alice.send("send-text after the offer");
+    let first = carol.wait_for("carol to hear from alice", |v| {
+        v["event"] == "message"
+            && (!v["message"]["SDPOffer"].is_null() || v["message"]["TextMessage"]["content"] == "after the offer")
+    });
+    assert!(first["message"]["SDPOffer"].is_null());
+    
+    alice.send("quit");
+}
+
+#[test]
+fn signalling_an_offline_user_is_rejected() {
+    let (_server, port) = start_server();
+    
+    let mut alice = HeadlessClient::start(port, "alice");
+    alice.wait_for("alice to join", is_ready);
+    
+    alice.send(&format!("ice-candidate {} candidate:1 1 UDP 1 127.0.0.1 9 typ host", uuid::Uuid::new_v4()));
+    let error = alice.wait_for("the server to reject the candidate", |v| {
+        v["event"] == "message" && !v["message"]["Error"].is_null()
+    });
+    assert_eq!(error["message"]["Error"]["code"], 404);
+    
+    alice.send("quit");
+}
@@ -1,27 +1,99 @@
 use thiserror::Error;
 
+// The error that caused another, kept so the whole chain can be reported
+pub type ErrorSource = Box<dyn std::error::Error + Send + Sync + 'static>;
+
 #[derive(Error, Debug)]
 pub enum OpenReverbError {
-    #[error("Network error: {0}")]
-    NetworkError(String),
+    #[error("Network error: {message}")]
+    NetworkError { message: String, #[source] source: Option<ErrorSource> },
+    
+    #[error("Authentication error: {message}")]
+    AuthError { message: String, #[source] source: Option<ErrorSource> },
+    
+    #[error("Serialization error: {message}")]
+    SerializationError { message: String, #[source] source: Option<ErrorSource> },
+    
+    #[error("Audio error: {message}")]
+    AudioError { message: String, #[source] source: Option<ErrorSource> },
+    
+    #[error("Video error: {message}")]
+    VideoError { message: String, #[source] source: Option<ErrorSource> },
+    
+    #[error("Screen sharing error: {message}")]
+    ScreenShareError { message: String, #[source] source: Option<ErrorSource> },
+    
+    #[error("Unknown error: {message}")]
+    Unknown { message: String, #[source] source: Option<ErrorSource> },
+}
+
+impl OpenReverbError {
+    pub fn network(message: impl Into<String>) -> Self {
+        Self::NetworkError { message: message.into(), source: None }
+    }
     
-    #[error("Authentication error: {0}")]
-    AuthError(String),
+    pub fn auth(message: impl Into<String>) -> Self {
+        Self::AuthError { message: message.into(), source: None }
+    }
     
-    #[error("Serialization error: {0}")]
-    SerializationError(String),
+    pub fn serialization(message: impl Into<String>) -> Self {
+        Self::SerializationError { message: message.into(), source: None }
+    }
     
-    #[error("Audio error: {0}")]
-    AudioError(String),
+    pub fn audio(message: impl Into<String>) -> Self {
+        Self::AudioError { message: message.into(), source: None }
+    }
     
-    #[error("Video error: {0}")]
-    VideoError(String),
+    pub fn video(message: impl Into<String>) -> Self {
+        Self::VideoError { message: message.into(), source: None }
+    }
     
-    #[error("Screen sharing error: {0}")]
-    ScreenShareError(String),
+    pub fn screen_share(message: impl Into<String>) -> Self {
+        Self::ScreenShareError { message: message.into(), source: None }
+    }
     
-    #[error("Unknown error: {0}")]
-    Unknown(String),
+    pub fn unknown(message: impl Into<String>) -> Self {
+        Self::Unknown { message: message.into(), source: None }
+    }
+    
+    // Record the error that caused this one
+    pub fn with_source(mut self, error: impl Into<ErrorSource>) -> Self {
+        match &mut self {
+            Self::NetworkError { source, .. }
+            | Self::AuthError { source, .. }
+            | Self::SerializationError { source, .. }
+            | Self::AudioError { source, .. }
+            | Self::VideoError { source, .. }
+            | Self::ScreenShareError { source, .. }
+            | Self::Unknown { source, .. } => *source = Some(error.into()),
+        }
+        self
+    }
+}
+
+impl From<std::io::Error> for OpenReverbError {
+    fn from(error: std::io::Error) -> Self {
+        Self::network(error.to_string()).with_source(error)
+    }
+}
+
+impl From<serde_json::Error> for OpenReverbError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::serialization(error.to_string()).with_source(error)
+    }
+}
+
+impl From<tokio::sync::broadcast::error::RecvError> for OpenReverbError {
+    fn from(error: tokio::sync::broadcast::error::RecvError) -> Self {
+        Self::network(error.to_string()).with_source(error)
+    }
+}
+
+// Keeps anyhow's own chain of causes below this error
+impl From<anyhow::Error> for OpenReverbError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::unknown(error.to_string()).with_source(error)
+    }
 }
 
-pub type Result<T> = std::result::Result<T, OpenReverbError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, OpenReverbError>;
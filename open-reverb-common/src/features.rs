@@ -0,0 +1,34 @@
+use bitflags::bitflags;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+bitflags! {
+    // Optional features a client or server supports, agreed on with Hello and HelloAck
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub struct Features: u32 {
+        const E2E_VOICE = 1 << 0;
+        const FILE_TRANSFER = 1 << 1;
+        const LINK_PREVIEW = 1 << 2;
+        const TRANSCRIPTION = 1 << 3;
+        const SERVER_RECORDING = 1 << 4;
+    }
+}
+
+impl Features {
+    // What both sides support. Bits this version doesn't know about are dropped.
+    pub fn negotiate(self, other: Features) -> Features {
+        (self & other) & Features::all()
+    }
+}
+
+// Sent as the raw bits, so a peer with features this version doesn't know about can still be read
+impl Serialize for Features {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.bits().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Features {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(Features::from_bits_retain)
+    }
+}
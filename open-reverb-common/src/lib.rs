@@ -1,5 +1,7 @@
+pub mod features;
 pub mod models;
 pub mod protocol;
+pub mod voice_codec;
 pub mod error;
 
 pub fn version() -> &'static str {
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -6,6 +7,41 @@ pub struct User {
     pub id: Uuid,
     pub username: String,
     pub status: UserStatus,
+    // When the user last logged in, in milliseconds since the Unix epoch
+    #[serde(default)]
+    pub joined_at: u64,
+    // Address of the federated server the user is on; None for users on this server
+    #[serde(default)]
+    pub federated_from: Option<String>,
+}
+
+impl User {
+    // How long the user has been logged in for
+    pub fn session_duration_secs(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        
+        now.saturating_sub(self.joined_at) / 1000
+    }
+}
+
+// Usernames are 3 to 32 characters from [a-zA-Z0-9_-]
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+// Why a username can't be used, if it can't
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err("Usernames can only contain letters, numbers, underscores and hyphens".to_string());
+    }
+    // All ASCII by now, so bytes are characters
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&username.len()) {
+        return Err(format!("Usernames must be {} to {} characters long", MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH));
+    }
+    
+    Ok(())
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
@@ -23,8 +59,166 @@ pub struct Channel {
     pub description: Option<String>,
     pub parent_id: Option<Uuid>,
     pub members: Vec<Uuid>,
+    // What the channel is currently about, shown under its name
+    #[serde(default)]
+    pub topic: Option<String>,
+    // Voice is end-to-end encrypted between members; the server only relays ciphertext
+    #[serde(default)]
+    pub e2e_encrypted: bool,
+    #[serde(default)]
+    pub channel_type: ChannelType,
+    // Most media the channel forwards, summed over its members; 0 means no limit
+    #[serde(default)]
+    pub media_bandwidth_limit_kbps: u32,
+    // Users other than admins wait in a lobby until an admin lets them in
+    #[serde(default)]
+    pub lobby_mode: bool,
+    // How members encode their voice; new channels take the server's default
+    #[serde(default)]
+    pub audio_quality: AudioQuality,
+    // Days chat is kept for, in place of the server's default
+    #[serde(default)]
+    pub override_retention_days: Option<u32>,
+    // Never deleted for being idle
+    #[serde(default)]
+    pub permanent: bool,
+    // Seconds the channel can sit empty and quiet before it's deleted, in place of the server's default
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+// Voice channels carry voice, video and screen sharing, along with in-call chat.
+// Text channels only carry chat.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ChannelType {
+    #[default]
+    Voice,
+    Text,
+}
+
+// How a user's outgoing video is being sent
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum VideoQuality {
+    #[default]
+    Normal,
+    // Still sent, but blurred beyond recognition, e.g. while the sender has switched away from
+    // the client
+    Paused,
+}
+
+// Part of the screen being shared, in pixels from the top left of the desktop
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScreenRegion {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// File format of a chat history export
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ExportFormat {
+    JSON,
+    CSV,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::JSON => "json",
+            ExportFormat::CSV => "csv",
+        }
+    }
+}
+
+// Codec a user's video is encoded with
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum VideoCodec {
+    #[default]
+    H264,
+    VP8,
+    VP9,
+    AV1,
+}
+
+impl VideoCodec {
+    pub const ALL: [VideoCodec; 4] = [VideoCodec::H264, VideoCodec::VP8, VideoCodec::VP9, VideoCodec::AV1];
+    
+    pub fn label(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "H.264",
+            VideoCodec::VP8 => "VP8",
+            VideoCodec::VP9 => "VP9",
+            VideoCodec::AV1 => "AV1",
+        }
+    }
+}
+
+// Codec voice is sent with. Channels used to be labelled Opus before there was any Opus
+// encoder, so configs and peers that still say "Opus" get ADPCM.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum AudioCodec {
+    #[default]
+    #[serde(rename = "ADPCM", alias = "Opus")]
+    Adpcm,
+    PCM,
+}
+
+impl AudioCodec {
+    pub const ALL: [AudioCodec; 2] = [AudioCodec::Adpcm, AudioCodec::PCM];
+    
+    pub fn label(&self) -> &'static str {
+        match self {
+            AudioCodec::Adpcm => "ADPCM",
+            AudioCodec::PCM => "PCM",
+        }
+    }
+}
+
+// How voice in a channel is encoded, e.g. higher quality for a music channel
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AudioQuality {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bitrate_kbps: u32,
+    pub codec: AudioCodec,
+}
+
+impl AudioQuality {
+    pub const SAMPLE_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+    
+    // Settings an encoder can't use, if any
+    pub fn validate(&self) -> Result<(), String> {
+        if !Self::SAMPLE_RATES.contains(&self.sample_rate) {
+            return Err(format!("Unsupported sample rate: {}Hz", self.sample_rate));
+        }
+        if !(1..=2).contains(&self.channels) {
+            return Err("Audio must be mono or stereo".to_string());
+        }
+        if !(6..=510).contains(&self.bitrate_kbps) {
+            return Err("Bitrate must be between 6 and 510 kbps".to_string());
+        }
+        
+        Ok(())
+    }
+}
+
+// Mono speech at 48kHz
+impl Default for AudioQuality {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48_000,
+            channels: 1,
+            bitrate_kbps: 64,
+            codec: AudioCodec::Adpcm,
+        }
+    }
 }
 
+// Version of the models in this file, as major * 1000 + minor. A minor version only adds fields
+// older peers can do without; a major version changes or removes them.
+pub const SCHEMA_VERSION: u32 = 1_000;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Server {
     pub id: Uuid,
@@ -32,4 +226,118 @@ pub struct Server {
     pub description: Option<String>,
     pub channels: Vec<Channel>,
     pub users: Vec<User>,
-}
\ No newline at end of file
+    // The server's SCHEMA_VERSION; 0 from servers older than the field
+    #[serde(default)]
+    pub schema_version: u32,
+}
+
+// How a server's schema version compares to the client's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaCompatibility {
+    Compatible,
+    // The server is at least a major version ahead
+    ServerNewer,
+    ClientNewer,
+}
+
+impl SchemaCompatibility {
+    pub fn check(server_version: u32, client_version: u32) -> Self {
+        if server_version / 1000 > client_version / 1000 {
+            SchemaCompatibility::ServerNewer
+        } else if client_version > server_version {
+            SchemaCompatibility::ClientNewer
+        } else {
+            SchemaCompatibility::Compatible
+        }
+    }
+    
+    // What to warn the user about, if anything
+    pub fn warning(&self) -> Option<&'static str> {
+        match self {
+            SchemaCompatibility::Compatible => None,
+            SchemaCompatibility::ServerNewer => Some("Server uses a newer schema; some features may not work."),
+            SchemaCompatibility::ClientNewer => Some("Server may be outdated; consider upgrading."),
+        }
+    }
+}
+
+// Load on the server, sent periodically to admins
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStatistics {
+    pub total_connections: u32,
+    pub per_channel: Vec<ChannelStats>,
+    // Users the server treats as admins
+    #[serde(default)]
+    pub admin_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelStats {
+    pub channel_id: Uuid,
+    pub name: String,
+    pub members: u32,
+    pub voice_streams: u32,
+    pub video_streams: u32,
+    pub bytes_forwarded_per_sec: u64,
+    // Whether the server is recording the channel's voice
+    #[serde(default)]
+    pub recording_active: bool,
+}
+
+// One connection to the server, from the audit log kept for admins. Times are in
+// milliseconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionAuditEntry {
+    // Unset until the connection logs in
+    pub user_id: Option<Uuid>,
+    pub username: Option<String>,
+    pub ip_addr: String,
+    pub connected_at: u64,
+    // Unset while still connected
+    pub disconnected_at: Option<u64>,
+    pub disconnect_reason: Option<DisconnectReason>,
+    // Things worth noting that happened on the connection, oldest first
+    #[serde(default)]
+    pub events: Vec<AuditEvent>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AuditEvent {
+    // The user's video was blurred because they switched away from the client
+    PrivacyModeTriggered { at: u64 },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DisconnectReason {
+    // The client closed the connection
+    Clean,
+    // The connection dropped or stopped responding
+    Timeout,
+    Kicked,
+    Banned,
+    // The session went past the server's maximum duration
+    Expired,
+}
+
+// A chat message matching a search. Timestamp is in seconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub message_id: Uuid,
+    pub channel_id: Uuid,
+    pub user_id: Uuid,
+    pub content: String,
+    pub timestamp: u64,
+    // The part of the content around the first match
+    pub snippet: String,
+}
+
+// An announcement an admin has scheduled to be sent to everyone connected. send_at is in
+// seconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledAnnouncement {
+    pub id: Uuid,
+    pub message: String,
+    pub send_at: u64,
+    pub sent: bool,
+}
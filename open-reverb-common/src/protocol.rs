@@ -1,45 +1,262 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::models::{Channel, Server, User, UserStatus};
+use crate::features::Features;
+use crate::models::{AudioQuality, Channel, ChannelType, ConnectionAuditEntry, ExportFormat, ScheduledAnnouncement, ScreenRegion, SearchResult, Server, ServerStatistics, User, UserStatus, VideoCodec, VideoQuality};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
+    // Sent by the client right after connecting, with the features it supports. The server
+    // answers with those both sides support; anything else stays off for the connection.
+    Hello { supported_features: Features },
+    HelloAck { negotiated_features: Features },
+    
     // Authentication
     LoginRequest { username: String, password: String },
-    LoginResponse { success: bool, user_id: Option<Uuid>, error: Option<String> },
+    // A successful login carries a signed session token, good for a ResumeSession if the
+    // connection drops
+    LoginResponse {
+        success: bool,
+        user_id: Option<Uuid>,
+        #[serde(default)]
+        token: Option<String>,
+        error: Option<String>,
+    },
+    // Sent after the password when the server requires a TOTP code; answered with a TOTPResponse
+    TOTPRequest { user_id: Uuid },
+    TOTPResponse { user_id: Uuid, code: String },
+    // Base32 secret for a user who hasn't set up an authenticator yet. The first valid code
+    // confirms it.
+    SetupTOTP { secret: String, qr_code_svg: String },
+    // Sent when the server limits how long a session lasts. SessionExpiring comes a few minutes
+    // before the end, with a token that extends the session when sent back in a RenewSession.
+    // SessionExpired comes at the end, just before the server closes the connection.
+    SessionExpiring { remaining_secs: u32, renew_token: String },
+    SessionExpired {},
+    RenewSession { token: String },
+    // Log in again with the token from an earlier LoginResponse, instead of a password. Answered
+    // with a LoginResponse, or an Error with code 401 if the token is invalid or has expired.
+    ResumeSession { token: String },
+    // Sent by the server after a user's first login, from the server's welcome_message setting
+    WelcomeMessage { content: String },
     
     // User status
     StatusUpdate { user_id: Uuid, status: UserStatus },
     UserJoined { user: User },
     UserLeft { user_id: Uuid },
+    // A user's status or profile changed
+    UserUpdated { user: User },
     
     // Channels
-    JoinChannel { channel_id: Uuid },
+    // A ghost joins without the other members being told, for moderators keeping an eye on a
+    // channel; only users allowed to may.
+    JoinChannel {
+        channel_id: Uuid,
+        #[serde(default)]
+        ghost: bool,
+    },
     LeaveChannel { channel_id: Uuid },
     ChannelUpdate { channel: Channel },
+    CreateChannel {
+        name: String,
+        description: Option<String>,
+        parent_id: Option<Uuid>,
+        channel_type: ChannelType,
+        media_bandwidth_limit_kbps: u32,
+        // The server's default when unset
+        #[serde(default)]
+        audio_quality: Option<AudioQuality>,
+        // Keep the channel even when it's been idle past the server's timeout
+        #[serde(default)]
+        permanent: bool,
+        // The server's default when unset
+        #[serde(default)]
+        idle_timeout_secs: Option<u64>,
+    },
+    CreateChannelResponse { success: bool, channel_id: Option<Uuid>, error: Option<String> },
+    // Channels with sub-channels can't be deleted, nor can permanent ones except by admins.
+    // Members are sent LeaveChannel, then everyone is sent ChannelDeleted.
+    DeleteChannel { channel_id: Uuid },
+    ChannelDeleted { channel_id: Uuid },
+    // Admins only. Re-reads the server's channels file, creating or updating the channels in it;
+    // members are sent a ChannelUpdate for each.
+    ReloadChannelConfig {},
+    // Admins only. Deletes every channel whose name starts with `prefix`, along with the
+    // channels nested under them; everyone is sent ChannelsPurged.
+    PurgeChannels { prefix: String },
+    ChannelsPurged { channel_ids: Vec<Uuid> },
+    // Admins only. Lets an address or CIDR range connect, when the server has an allowlist,
+    // until it restarts.
+    AddAllowlistEntry { entry: String },
+    UpdateChannel { channel_id: Uuid, parent_id: Option<Uuid> },
+    // An empty topic clears it
+    SetChannelTopic { channel_id: Uuid, topic: String },
+    // Sent to a user once they have joined a channel
+    ChannelMediaState { channel_id: Uuid, members: Vec<Uuid> },
+    // Follows ChannelMediaState, telling the joiner how to encode their voice
+    JoinChannelAck { channel_id: Uuid, audio_quality: AudioQuality },
+    // Admins only
+    SetChannelAudioQuality { channel_id: Uuid, quality: AudioQuality },
+    // Channels in lobby mode only let users in once an admin approves. Joining one is answered
+    // with WaitingForApproval, and admins are sent a JoinPending. An approved user then gets the
+    // usual ChannelMediaState; a rejected one gets JoinRejected.
+    SetLobbyMode { channel_id: Uuid, enabled: bool },
+    WaitingForApproval { channel_id: Uuid },
+    JoinPending { user: User, channel_id: Uuid },
+    ApprovePending { user_id: Uuid, channel_id: Uuid },
+    RejectPending { user_id: Uuid, channel_id: Uuid, reason: Option<String> },
+    // Sent to admins when a ghost joins or leaves a channel, with the ghosts now in it
+    GhostMembers { channel_id: Uuid, users: Vec<User> },
+    JoinRejected { channel_id: Uuid, reason: Option<String> },
+    
+    // Text chat; the ID and timestamp (in seconds since the Unix epoch) are set by the server
+    TextMessage { message_id: Uuid, user_id: Uuid, channel_id: Uuid, content: String, timestamp: u64 },
+    // A private message to one user, who may be on a federated server. The timestamp (in seconds
+    // since the Unix epoch) is set by the server.
+    DirectMessage { from_user_id: Uuid, to_user_id: Uuid, content: String, timestamp: u64 },
+    // Search chat history, newest first. An empty channel list searches every channel.
+    SearchMessages { query: String, channel_ids: Vec<Uuid>, limit: u32, offset: u32 },
+    SearchResults { results: Vec<SearchResult> },
+    // Find users whose username contains the query, ignoring case. Only connected users are
+    // searched unless include_offline is set.
+    SearchUsers { query: String, include_offline: bool },
+    SearchUsersResult { users: Vec<User> },
+    // Admins only. Chat in the channel is deleted once it's older than retention_days; None
+    // falls back to the server's default.
+    RetentionPolicyUpdate { channel_id: Uuid, retention_days: Option<u32> },
+    // Sent to a channel's members after its old messages were deleted. Messages sent before
+    // oldest_remaining (seconds since the Unix epoch) are gone.
+    MessagesDeleted { channel_id: Uuid, deleted_count: u32, oldest_remaining: u64 },
+    // Admins only. Export a channel's chat sent between start_ts and end_ts (seconds since the
+    // Unix epoch, both inclusive). The export comes back in ExportData chunks of the same
+    // transfer_id, numbered from 0 to chunk_count - 1.
+    ExportMessages { channel_id: Uuid, start_ts: u64, end_ts: u64, format: ExportFormat },
+    ExportData { transfer_id: Uuid, format: ExportFormat, chunk_index: u32, chunk_count: u32, data: Vec<u8> },
+    
+    // Sending a file to another user. The sender offers it, and once the recipient accepts, sends
+    // it in FileTransferChunks in order, then FileTransferComplete. The server only relays these
+    // between the two users, going by the ID given in the offer.
+    FileTransferOffer { id: Uuid, from: Uuid, to: Uuid, filename: String, size_bytes: u64, mime_type: String },
+    FileTransferAccept { id: Uuid },
+    // Sent by the recipient when they decline, or by either side (or the server) to give up
+    FileTransferReject { id: Uuid, reason: String },
+    FileTransferChunk { id: Uuid, offset: u64, data: Vec<u8> },
+    FileTransferComplete { id: Uuid },
     
-    // Voice
-    VoiceData { user_id: Uuid, channel_id: Uuid, data: Vec<u8> },
+    // Voice. captured_at_ms is the sender's wall clock, in milliseconds since the Unix epoch.
+    // In encrypted channels, epoch says which voice key the frame was encrypted with.
+    VoiceData {
+        user_id: Uuid,
+        channel_id: Uuid,
+        seq: u32,
+        captured_at_ms: u64,
+        #[serde(default)]
+        epoch: u32,
+        data: Vec<u8>,
+    },
     VoiceStarted { user_id: Uuid },
     VoiceStopped { user_id: Uuid },
+    // Have the server send our own voice back to us, for testing latency and echo
+    SetBroadcastSelf { enabled: bool },
+    // How long each member has spoken in a channel since joining it, in milliseconds, most first
+    GetSpeakingStats { channel_id: Uuid },
+    SpeakingStats { channel_id: Uuid, stats: Vec<(Uuid, u64)> },
+    // End-to-end encrypted voice. Users announce their X25519 public key after logging in, and
+    // a channel's voice key is sent to each member wrapped with a key agreed from it.
+    E2EPublicKey { user_id: Uuid, public_key: Vec<u8> },
+    E2EChannelEnabled { channel_id: Uuid },
+    E2EVoiceKey {
+        channel_id: Uuid,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        #[serde(default)]
+        epoch: u32,
+        encrypted_key: Vec<u8>,
+    },
+    // A member replaced a channel's voice key after using it for a while. The new key follows
+    // in an E2EVoiceKey for each member, and frames under the old one are still accepted for a
+    // short grace period.
+    KeyRotationInit { channel_id: Uuid, initiator_id: Uuid, new_epoch: u32 },
+    // WebRTC signalling for a future peer-to-peer media path. The server only relays these to
+    // the user they're addressed to.
+    SDPOffer { from_user_id: Uuid, to_user_id: Uuid, sdp: String },
+    SDPAnswer { from_user_id: Uuid, to_user_id: Uuid, sdp: String },
+    ICECandidate { from: Uuid, to: Uuid, candidate: String },
+    // Text transcribed from a user's voice; timestamp is in seconds since the Unix epoch
+    TranscriptionResult { user_id: Uuid, channel_id: Uuid, text: String, timestamp: u64 },
     
-    // Video
-    VideoData { user_id: Uuid, channel_id: Uuid, data: Vec<u8> },
+    // Video; captured_at_ms is as for voice
+    VideoData { user_id: Uuid, channel_id: Uuid, seq: u32, captured_at_ms: u64, video_codec: VideoCodec, data: Vec<u8> },
     VideoStarted { user_id: Uuid },
     VideoStopped { user_id: Uuid },
+    // Sent by the server when a user's video starts, or changes codec
+    VideoCodecInfo { user_id: Uuid, codec: VideoCodec },
+    // Relayed to the other members of the channel
+    VideoQualityUpdate { user_id: Uuid, channel_id: Uuid, quality: VideoQuality },
     
     // Screen sharing
-    ScreenShareData { user_id: Uuid, channel_id: Uuid, data: Vec<u8> },
+    ScreenShareData { user_id: Uuid, channel_id: Uuid, seq: u32, data: Vec<u8> },
     ScreenShareStarted { user_id: Uuid },
     ScreenShareStopped { user_id: Uuid },
+    // Sent when the sharer picks part of the screen to share, or goes back to all of it (None),
+    // since the picture's shape changes with it
+    ScreenShareRegionChanged { user_id: Uuid, region: Option<ScreenRegion> },
+    
+    // The capture times of a user's latest voice and video frames, taken at the same moment.
+    // Sent every few seconds while both are on, so receivers can tell how far apart they've drifted.
+    SyncHint { user_id: Uuid, audio_pts_ms: u64, video_pts_ms: u64 },
+    
+    // Recording consent
+    RecordingConsent { recording_user_id: Uuid, recording_started: bool },
+    ConsentAcknowledged { user_id: Uuid },
+    ConsentRejected { user_id: Uuid },
     
     // Server info
+    // Sent after login when the server is small enough to send whole
     ServerInfo { server: Server },
+    // Sent after login in place of ServerInfo on larger servers. Clients then page through the
+    // channels and ask for the users online as they need them.
+    ServerInfoSummary { server_id: Uuid, name: String, channel_count: u32, user_count: u32, schema_version: u32 },
+    // Channels ordered by name; answered with a ChannelList
+    GetChannels { offset: u32, limit: u32 },
+    ChannelList { channels: Vec<Channel>, total: u32 },
+    // Everyone online on the server, or only those in a channel; answered with a UserList
+    GetOnlineUsers { channel_id: Option<Uuid> },
+    UserList { users: Vec<User> },
+    // Sent by a client whose schema version doesn't match the one in ServerInfo, so the
+    // server can log it
+    SchemaVersionMismatch { server_version: u32, client_version: u32 },
+    // Admins only. Answered with the latest ServerStats, which admins are also sent periodically.
+    GetServerStats {},
+    // Sent to admins only
+    ServerStats { stats: ServerStatistics },
+    // Admins only. Returns connections made after `since` (milliseconds since the Unix epoch),
+    // oldest first; pass the last entry's connected_at as `since` for the next page.
+    AuditLogQuery { user_id: Option<Uuid>, since: u64, limit: u32 },
+    AuditLogEntries { entries: Vec<ConnectionAuditEntry> },
+    // Admins only. Each is answered with the full list of scheduled announcements.
+    ScheduleAnnouncement { message: String, send_at: u64 },
+    ListAnnouncements {},
+    DeleteAnnouncement { id: Uuid },
+    Announcements { announcements: Vec<ScheduledAnnouncement> },
+    // Sent to everyone connected when a scheduled announcement is due
+    ServerAnnouncement { text: String },
+    
+    // Between federated servers. Each server sends its peers the usernames of everyone online on
+    // it, and relays direct messages to their users. The signature is a hex HMAC-SHA256 of the
+    // other fields under the secret the two servers share; the secret itself is never sent.
+    // `timestamp` is seconds since the Unix epoch, so old messages can't be replayed.
+    FederationPresence { usernames: Vec<String>, timestamp: u64, signature: String },
+    FederationRelay { from_username: String, target_username: String, message: String, timestamp: u64, signature: String },
     
-    // Ping/pong for keeping connection alive
-    Ping,
-    Pong,
+    // Ping/pong for keeping connection alive and measuring latency. The pong echoes the
+    // ping's nonce, so replies can be matched up when several pings are in flight.
+    Ping { nonce: u32 },
+    Pong { nonce: u32 },
+    // Padded with payload_size bytes, for finding the largest message that makes it to the
+    // server and back. The server answers with a pong carrying the same padding.
+    DiagnosticPing { payload_size: u32, padding: String },
+    DiagnosticPong { payload_size: u32, padding: String },
     
     // Error messages
     Error { code: u32, message: String },
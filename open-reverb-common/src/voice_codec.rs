@@ -0,0 +1,231 @@
+// IMA ADPCM compression for voice in ADPCM channels: 4 bits a sample, at a sample rate picked
+// to fit the channel's bitrate. There's no Opus encoder available to build against, so this is
+// what channels get instead of raw PCM.
+//
+// Each frame is self-contained, so a lost frame doesn't garble the ones after it:
+// sample rate (u32), sample count (u16), first sample (i16), step index (u8), then a nibble
+// for each sample after the first, low nibble first. All little-endian.
+
+use crate::models::AudioQuality;
+
+const HEADER_LEN: usize = 9;
+
+const STEP_SIZES: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66, 73,
+    80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449, 494,
+    544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272, 2499,
+    2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493, 10442, 11487,
+    12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+const INDEX_ADJUST: [i32; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+
+// Highest of the supported sample rates that keeps voice at 4 bits a sample within the
+// bitrate, and no higher than the channel's own rate
+pub fn sample_rate_for(quality: AudioQuality) -> u32 {
+    let fits = quality.bitrate_kbps.saturating_mul(1000) / 4;
+    
+    AudioQuality::SAMPLE_RATES
+        .iter()
+        .copied()
+        .filter(|rate| *rate <= fits && *rate <= quality.sample_rate)
+        .max()
+        .unwrap_or(AudioQuality::SAMPLE_RATES[0])
+}
+
+// Encode mono samples recorded at `sample_rate`. Frames are at most 65,535 samples; the rest
+// is dropped.
+pub fn encode(samples: &[i16], sample_rate: u32) -> Vec<u8> {
+    let samples = &samples[..samples.len().min(u16::MAX as usize)];
+    let Some((&first, rest)) = samples.split_first() else {
+        let mut frame = sample_rate.to_le_bytes().to_vec();
+        frame.extend_from_slice(&[0; HEADER_LEN - 4]);
+        return frame;
+    };
+    
+    let mut frame = Vec::with_capacity(HEADER_LEN + rest.len().div_ceil(2));
+    frame.extend_from_slice(&sample_rate.to_le_bytes());
+    frame.extend_from_slice(&(samples.len() as u16).to_le_bytes());
+    frame.extend_from_slice(&first.to_le_bytes());
+    
+    let mut state = State { predictor: first as i32, index: 0 };
+    frame.push(0);
+    
+    for pair in rest.chunks(2) {
+        let low = state.encode(pair[0]);
+        let high = pair.get(1).map_or(0, |sample| state.encode(*sample));
+        frame.push(low | (high << 4));
+    }
+    
+    frame
+}
+
+// Decode a frame back to mono samples, with the sample rate they're at. None if the frame is
+// too short for the samples it claims to hold.
+pub fn decode(frame: &[u8]) -> Option<(Vec<i16>, u32)> {
+    if frame.len() < HEADER_LEN {
+        return None;
+    }
+    
+    let sample_rate = u32::from_le_bytes(frame[0..4].try_into().unwrap());
+    let count = u16::from_le_bytes([frame[4], frame[5]]) as usize;
+    let first = i16::from_le_bytes([frame[6], frame[7]]);
+    let index = (frame[8] as i32).min(STEP_SIZES.len() as i32 - 1);
+    
+    if count == 0 {
+        return Some((Vec::new(), sample_rate));
+    }
+    let nibbles = &frame[HEADER_LEN..];
+    if nibbles.len() * 2 < count - 1 {
+        return None;
+    }
+    
+    let mut state = State { predictor: first as i32, index };
+    let mut samples = Vec::with_capacity(count);
+    samples.push(first);
+    samples.extend(
+        nibbles
+            .iter()
+            .flat_map(|byte| [byte & 0x0f, byte >> 4])
+            .take(count - 1)
+            .map(|nibble| state.decode(nibble)),
+    );
+    
+    Some((samples, sample_rate))
+}
+
+// Number of samples in a frame and the rate they're at, without decoding it
+pub fn frame_len(frame: &[u8]) -> Option<(usize, u32)> {
+    if frame.len() < HEADER_LEN {
+        return None;
+    }
+    
+    let sample_rate = u32::from_le_bytes(frame[0..4].try_into().unwrap());
+    let count = u16::from_le_bytes([frame[4], frame[5]]) as usize;
+    Some((count, sample_rate))
+}
+
+// Linear interpolation; good enough for speech, and a no-op when the rates match
+pub fn resample(samples: &[i16], from_rate: u32, to_rate: u32) -> Vec<i16> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+    
+    let out_len = (samples.len() as u64 * to_rate as u64 / from_rate.max(1) as u64) as usize;
+    let step = from_rate as f64 / to_rate as f64;
+    
+    (0..out_len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let index = pos as usize;
+            let frac = pos - index as f64;
+            let a = samples[index] as f64;
+            let b = samples.get(index + 1).copied().unwrap_or(samples[index]) as f64;
+            (a + (b - a) * frac).round() as i16
+        })
+        .collect()
+}
+
+// Predictor shared by the encoder and decoder, so they stay in step
+struct State {
+    predictor: i32,
+    index: i32,
+}
+
+impl State {
+    fn encode(&mut self, sample: i16) -> u8 {
+        let step = STEP_SIZES[self.index as usize];
+        let mut diff = sample as i32 - self.predictor;
+        let mut nibble = 0u8;
+        
+        if diff < 0 {
+            nibble = 8;
+            diff = -diff;
+        }
+        if diff >= step {
+            nibble |= 4;
+            diff -= step;
+        }
+        if diff >= step / 2 {
+            nibble |= 2;
+            diff -= step / 2;
+        }
+        if diff >= step / 4 {
+            nibble |= 1;
+        }
+        
+        // Decoded here too, so the encoder predicts from what the decoder will have
+        self.decode(nibble);
+        nibble
+    }
+    
+    fn decode(&mut self, nibble: u8) -> i16 {
+        let step = STEP_SIZES[self.index as usize];
+        
+        let mut diff = step >> 3;
+        if nibble & 4 != 0 {
+            diff += step;
+        }
+        if nibble & 2 != 0 {
+            diff += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            diff += step >> 2;
+        }
+        if nibble & 8 != 0 {
+            diff = -diff;
+        }
+        
+        self.predictor = (self.predictor + diff).clamp(i16::MIN as i32, i16::MAX as i32);
+        self.index = (self.index + INDEX_ADJUST[(nibble & 7) as usize]).clamp(0, STEP_SIZES.len() as i32 - 1);
+        self.predictor as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::AudioCodec;
+    
+    fn quality(sample_rate: u32, bitrate_kbps: u32) -> AudioQuality {
+        AudioQuality { sample_rate, channels: 1, bitrate_kbps, codec: AudioCodec::Adpcm }
+    }
+    
+    #[test]
+    fn sample_rate_fits_the_bitrate() {
+        assert_eq!(sample_rate_for(quality(48_000, 64)), 16_000);
+        assert_eq!(sample_rate_for(quality(48_000, 128)), 24_000);
+        assert_eq!(sample_rate_for(quality(12_000, 510)), 12_000);
+        // Nothing lower to fall back to
+        assert_eq!(sample_rate_for(quality(48_000, 6)), 8_000);
+    }
+    
+    #[test]
+    fn round_trip_stays_close_to_the_input() {
+        let samples: Vec<i16> = (0..320)
+            .map(|i| ((i as f32 / 16_000.0 * 440.0 * std::f32::consts::TAU).sin() * 8_000.0) as i16)
+            .collect();
+        
+        let frame = encode(&samples, 16_000);
+        assert_eq!(frame.len(), HEADER_LEN + 160);
+        
+        let (decoded, sample_rate) = decode(&frame).unwrap();
+        assert_eq!(sample_rate, 16_000);
+        assert_eq!(decoded.len(), samples.len());
+        assert_eq!(frame_len(&frame), Some((320, 16_000)));
+        
+        // Once the step size has adapted to the signal
+        for (decoded, sample) in decoded.iter().zip(samples.iter()).skip(20) {
+            assert!((*decoded as i32 - *sample as i32).abs() < 800, "{} decoded as {}", sample, decoded);
+        }
+    }
+    
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let frame = encode(&[100; 320], 16_000);
+        
+        assert!(decode(&frame[..HEADER_LEN - 1]).is_none());
+        assert!(decode(&frame[..frame.len() - 1]).is_none());
+        assert_eq!(decode(&encode(&[], 8_000)), Some((Vec::new(), 8_000)));
+    }
+}
@@ -0,0 +1,73 @@
+// Checks that errors converted into OpenReverbError keep the error that caused them, so the
+// whole chain can be reported.
+
+use std::error::Error;
+use std::io;
+
+use open_reverb_common::error::OpenReverbError;
+
+// Every error in the chain, starting with the one given
+fn chain(error: &dyn Error) -> Vec<String> {
+    let mut messages = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(error) = source {
+        messages.push(error.to_string());
+        source = error.source();
+    }
+    messages
+}
+
+fn read_config() -> open_reverb_common::error::Result<Vec<u8>> {
+    Err(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused"))?
+}
+
+#[test]
+fn io_errors_become_network_errors() {
+    let error = OpenReverbError::from(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset"));
+    
+    assert!(matches!(error, OpenReverbError::NetworkError { .. }));
+    assert_eq!(error.to_string(), "Network error: connection reset");
+}
+
+#[test]
+fn io_error_is_the_source_of_the_network_error() {
+    let error = read_config().unwrap_err();
+    
+    let source = error.source().expect("no source");
+    let io_error = source.downcast_ref::<io::Error>().expect("source isn't an io::Error");
+    assert_eq!(io_error.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn io_error_chains_through_anyhow() {
+    let error = anyhow::Error::from(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+        .context("Failed to reach the server");
+    let error = OpenReverbError::from(error);
+    
+    assert!(matches!(error, OpenReverbError::Unknown { .. }));
+    assert_eq!(chain(&error), ["Unknown error: Failed to reach the server", "Failed to reach the server", "timed out"]);
+}
+
+#[test]
+fn json_errors_become_serialization_errors() {
+    let error = OpenReverbError::from(serde_json::from_str::<u32>("not a number").unwrap_err());
+    
+    assert!(matches!(error, OpenReverbError::SerializationError { .. }));
+    assert!(error.source().unwrap().downcast_ref::<serde_json::Error>().is_some());
+}
+
+#[test]
+fn broadcast_errors_become_network_errors() {
+    let error = OpenReverbError::from(tokio::sync::broadcast::error::RecvError::Closed);
+    
+    assert!(matches!(error, OpenReverbError::NetworkError { .. }));
+    assert!(error.source().is_some());
+}
+
+#[test]
+fn errors_built_from_a_message_have_no_source() {
+    let error = OpenReverbError::auth("Invalid password");
+    
+    assert_eq!(error.to_string(), "Authentication error: Invalid password");
+    assert!(error.source().is_none());
+}
@@ -0,0 +1,28 @@
+// Checks how the features a client and server support are agreed on and sent.
+
+use open_reverb_common::features::Features;
+
+#[test]
+fn negotiation_keeps_features_both_sides_support() {
+    let client = Features::E2E_VOICE | Features::FILE_TRANSFER | Features::TRANSCRIPTION;
+    let server = Features::E2E_VOICE | Features::TRANSCRIPTION | Features::SERVER_RECORDING;
+    
+    assert_eq!(client.negotiate(server), Features::E2E_VOICE | Features::TRANSCRIPTION);
+    assert_eq!(client.negotiate(Features::empty()), Features::empty());
+}
+
+#[test]
+fn features_are_sent_as_bits() {
+    let features = Features::E2E_VOICE | Features::SERVER_RECORDING;
+    
+    assert_eq!(serde_json::to_string(&features).unwrap(), "17");
+    assert_eq!(serde_json::from_str::<Features>("17").unwrap(), features);
+}
+
+#[test]
+fn unknown_features_are_read_but_never_negotiated() {
+    // A newer peer supporting a feature this version doesn't have
+    let newer: Features = serde_json::from_str(&((1u32 << 31) | 1).to_string()).unwrap();
+    
+    assert_eq!(newer.negotiate(Features::all() | Features::from_bits_retain(1 << 31)), Features::E2E_VOICE);
+}
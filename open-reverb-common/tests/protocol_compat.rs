@@ -0,0 +1,588 @@
+// Checks that the wire format of every message stays the same, and that clients which don't
+// know about newer messages can still read the stream.
+//
+// Golden files live in tests/golden. After an intentional change to the format, regenerate
+// them with `cargo test -p open-reverb-common --features generate-goldens`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use open_reverb_common::features::Features;
+use open_reverb_common::models::{
+    AudioCodec, AudioQuality, AuditEvent, Channel, ChannelStats, ChannelType, ConnectionAuditEntry, DisconnectReason, ExportFormat, ScheduledAnnouncement, ScreenRegion, SearchResult,
+    Server, ServerStatistics, User, UserStatus, VideoCodec, VideoQuality,
+};
+use open_reverb_common::protocol::Message;
+
+// Fixed IDs so the golden files don't change between runs
+const USER_ID: Uuid = Uuid::from_u128(0x0000_0001_0000_0000_0000_0000_0000_0001);
+const OTHER_USER_ID: Uuid = Uuid::from_u128(0x0000_0001_0000_0000_0000_0000_0000_0002);
+const CHANNEL_ID: Uuid = Uuid::from_u128(0x0000_0002_0000_0000_0000_0000_0000_0001);
+const PARENT_CHANNEL_ID: Uuid = Uuid::from_u128(0x0000_0002_0000_0000_0000_0000_0000_0002);
+const MESSAGE_ID: Uuid = Uuid::from_u128(0x0000_0003_0000_0000_0000_0000_0000_0001);
+const SERVER_ID: Uuid = Uuid::from_u128(0x0000_0004_0000_0000_0000_0000_0000_0001);
+const ANNOUNCEMENT_ID: Uuid = Uuid::from_u128(0x0000_0005_0000_0000_0000_0000_0000_0001);
+const FILE_TRANSFER_ID: Uuid = Uuid::from_u128(0x0000_0006_0000_0000_0000_0000_0000_0001);
+
+const VARIANT_COUNT: usize = 104;
+
+// Fails to compile when a variant is added, as a reminder to give it the next index, bump
+// VARIANT_COUNT, and add an example to `all_messages`
+fn variant_index(message: &Message) -> usize {
+    match message {
+        Message::Hello { .. } => 0,
+        Message::HelloAck { .. } => 1,
+        Message::LoginRequest { .. } => 2,
+        Message::LoginResponse { .. } => 3,
+        Message::TOTPRequest { .. } => 4,
+        Message::TOTPResponse { .. } => 5,
+        Message::SetupTOTP { .. } => 6,
+        Message::SessionExpiring { .. } => 7,
+        Message::SessionExpired {} => 8,
+        Message::RenewSession { .. } => 9,
+        Message::ResumeSession { .. } => 10,
+        Message::WelcomeMessage { .. } => 11,
+        Message::StatusUpdate { .. } => 12,
+        Message::UserJoined { .. } => 13,
+        Message::UserLeft { .. } => 14,
+        Message::UserUpdated { .. } => 15,
+        Message::JoinChannel { .. } => 16,
+        Message::LeaveChannel { .. } => 17,
+        Message::ChannelUpdate { .. } => 18,
+        Message::CreateChannel { .. } => 19,
+        Message::CreateChannelResponse { .. } => 20,
+        Message::DeleteChannel { .. } => 21,
+        Message::ChannelDeleted { .. } => 22,
+        Message::ReloadChannelConfig { .. } => 23,
+        Message::PurgeChannels { .. } => 24,
+        Message::ChannelsPurged { .. } => 25,
+        Message::AddAllowlistEntry { .. } => 26,
+        Message::UpdateChannel { .. } => 27,
+        Message::SetChannelTopic { .. } => 28,
+        Message::ChannelMediaState { .. } => 29,
+        Message::JoinChannelAck { .. } => 30,
+        Message::SetChannelAudioQuality { .. } => 31,
+        Message::SetLobbyMode { .. } => 32,
+        Message::WaitingForApproval { .. } => 33,
+        Message::JoinPending { .. } => 34,
+        Message::ApprovePending { .. } => 35,
+        Message::RejectPending { .. } => 36,
+        Message::GhostMembers { .. } => 37,
+        Message::JoinRejected { .. } => 38,
+        Message::TextMessage { .. } => 39,
+        Message::DirectMessage { .. } => 40,
+        Message::SearchMessages { .. } => 41,
+        Message::SearchResults { .. } => 42,
+        Message::SearchUsers { .. } => 43,
+        Message::SearchUsersResult { .. } => 44,
+        Message::RetentionPolicyUpdate { .. } => 45,
+        Message::MessagesDeleted { .. } => 46,
+        Message::ExportMessages { .. } => 47,
+        Message::ExportData { .. } => 48,
+        Message::FileTransferOffer { .. } => 49,
+        Message::FileTransferAccept { .. } => 50,
+        Message::FileTransferReject { .. } => 51,
+        Message::FileTransferChunk { .. } => 52,
+        Message::FileTransferComplete { .. } => 53,
+        Message::VoiceData { .. } => 54,
+        Message::VoiceStarted { .. } => 55,
+        Message::VoiceStopped { .. } => 56,
+        Message::SetBroadcastSelf { .. } => 57,
+        Message::GetSpeakingStats { .. } => 58,
+        Message::SpeakingStats { .. } => 59,
+        Message::E2EPublicKey { .. } => 60,
+        Message::E2EChannelEnabled { .. } => 61,
+        Message::E2EVoiceKey { .. } => 62,
+        Message::KeyRotationInit { .. } => 63,
+        Message::SDPOffer { .. } => 64,
+        Message::SDPAnswer { .. } => 65,
+        Message::ICECandidate { .. } => 66,
+        Message::TranscriptionResult { .. } => 67,
+        Message::VideoData { .. } => 68,
+        Message::VideoStarted { .. } => 69,
+        Message::VideoStopped { .. } => 70,
+        Message::VideoCodecInfo { .. } => 71,
+        Message::VideoQualityUpdate { .. } => 72,
+        Message::ScreenShareData { .. } => 73,
+        Message::ScreenShareStarted { .. } => 74,
+        Message::ScreenShareStopped { .. } => 75,
+        Message::ScreenShareRegionChanged { .. } => 76,
+        Message::SyncHint { .. } => 77,
+        Message::RecordingConsent { .. } => 78,
+        Message::ConsentAcknowledged { .. } => 79,
+        Message::ConsentRejected { .. } => 80,
+        Message::ServerInfo { .. } => 81,
+        Message::ServerInfoSummary { .. } => 82,
+        Message::GetChannels { .. } => 83,
+        Message::ChannelList { .. } => 84,
+        Message::GetOnlineUsers { .. } => 85,
+        Message::UserList { .. } => 86,
+        Message::SchemaVersionMismatch { .. } => 87,
+        Message::GetServerStats { .. } => 88,
+        Message::ServerStats { .. } => 89,
+        Message::AuditLogQuery { .. } => 90,
+        Message::AuditLogEntries { .. } => 91,
+        Message::ScheduleAnnouncement { .. } => 92,
+        Message::ListAnnouncements { .. } => 93,
+        Message::DeleteAnnouncement { .. } => 94,
+        Message::Announcements { .. } => 95,
+        Message::ServerAnnouncement { .. } => 96,
+        Message::FederationPresence { .. } => 97,
+        Message::FederationRelay { .. } => 98,
+        Message::Ping { .. } => 99,
+        Message::Pong { .. } => 100,
+        Message::DiagnosticPing { .. } => 101,
+        Message::DiagnosticPong { .. } => 102,
+        Message::Error { .. } => 103,
+    }
+}
+
+fn user() -> User {
+    User {
+        id: USER_ID,
+        username: "alice".to_string(),
+        status: UserStatus::Online,
+        joined_at: 1_700_000_000_000,
+        federated_from: None,
+    }
+}
+
+fn channel() -> Channel {
+    Channel {
+        id: CHANNEL_ID,
+        name: "General".to_string(),
+        description: Some("General discussion".to_string()),
+        parent_id: Some(PARENT_CHANNEL_ID),
+        members: vec![USER_ID, OTHER_USER_ID],
+        topic: Some("Release planning".to_string()),
+        e2e_encrypted: true,
+        channel_type: ChannelType::Voice,
+        media_bandwidth_limit_kbps: 5000,
+        lobby_mode: true,
+        audio_quality: music_quality(),
+        override_retention_days: Some(30),
+        permanent: false,
+        idle_timeout_secs: Some(3_600),
+    }
+}
+
+fn music_quality() -> AudioQuality {
+    AudioQuality { sample_rate: 48_000, channels: 2, bitrate_kbps: 128, codec: AudioCodec::Adpcm }
+}
+
+fn server() -> Server {
+    Server {
+        id: SERVER_ID,
+        name: "Open Reverb Server".to_string(),
+        description: None,
+        channels: vec![channel()],
+        users: vec![user()],
+        schema_version: 1_000,
+    }
+}
+
+// One example of every message
+fn all_messages() -> Vec<Message> {
+    vec![
+        Message::Hello { supported_features: Features::E2E_VOICE | Features::TRANSCRIPTION },
+        Message::HelloAck { negotiated_features: Features::E2E_VOICE },
+        Message::LoginRequest { username: "alice".to_string(), password: "hunter2".to_string() },
+        Message::LoginResponse { success: true, user_id: Some(USER_ID), token: Some("eyJhbGciOiJIUzI1NiJ9.e30.c2ln".to_string()), error: None },
+        Message::TOTPRequest { user_id: USER_ID },
+        Message::TOTPResponse { user_id: USER_ID, code: "123456".to_string() },
+        Message::SetupTOTP { secret: "JBSWY3DPEHPK3PXP".to_string(), qr_code_svg: String::new() },
+        Message::SessionExpiring { remaining_secs: 300, renew_token: "abc123".to_string() },
+        Message::SessionExpired {},
+        Message::RenewSession { token: "abc123".to_string() },
+        Message::ResumeSession { token: "eyJhbGciOiJIUzI1NiJ9.e30.c2ln".to_string() },
+        Message::WelcomeMessage { content: "Welcome! Read #rules first.".to_string() },
+        Message::StatusUpdate { user_id: USER_ID, status: UserStatus::Away },
+        Message::UserJoined { user: user() },
+        Message::UserLeft { user_id: USER_ID },
+        Message::UserUpdated { user: User { status: UserStatus::DoNotDisturb, ..user() } },
+        Message::JoinChannel { channel_id: CHANNEL_ID, ghost: false },
+        Message::LeaveChannel { channel_id: CHANNEL_ID },
+        Message::ChannelUpdate { channel: channel() },
+        Message::CreateChannel {
+            name: "Announcements".to_string(),
+            description: None,
+            parent_id: Some(PARENT_CHANNEL_ID),
+            channel_type: ChannelType::Text,
+            media_bandwidth_limit_kbps: 0,
+            audio_quality: None,
+            permanent: true,
+            idle_timeout_secs: None,
+        },
+        Message::CreateChannelResponse { success: false, channel_id: None, error: Some("Channel exists".to_string()) },
+        Message::DeleteChannel { channel_id: CHANNEL_ID },
+        Message::ChannelDeleted { channel_id: CHANNEL_ID },
+        Message::ReloadChannelConfig {},
+        Message::PurgeChannels { prefix: "spam-".to_string() },
+        Message::ChannelsPurged { channel_ids: vec![CHANNEL_ID] },
+        Message::AddAllowlistEntry { entry: "192.168.1.0/24".to_string() },
+        Message::UpdateChannel { channel_id: CHANNEL_ID, parent_id: None },
+        Message::SetChannelTopic { channel_id: CHANNEL_ID, topic: "Release planning".to_string() },
+        Message::ChannelMediaState { channel_id: CHANNEL_ID, members: vec![USER_ID, OTHER_USER_ID] },
+        Message::JoinChannelAck { channel_id: CHANNEL_ID, audio_quality: music_quality() },
+        Message::SetChannelAudioQuality {
+            channel_id: CHANNEL_ID,
+            quality: AudioQuality { sample_rate: 16_000, channels: 1, bitrate_kbps: 24, codec: AudioCodec::PCM },
+        },
+        Message::SetLobbyMode { channel_id: CHANNEL_ID, enabled: true },
+        Message::WaitingForApproval { channel_id: CHANNEL_ID },
+        Message::JoinPending { user: user(), channel_id: CHANNEL_ID },
+        Message::ApprovePending { user_id: USER_ID, channel_id: CHANNEL_ID },
+        Message::RejectPending { user_id: USER_ID, channel_id: CHANNEL_ID, reason: Some("Private meeting".to_string()) },
+        Message::GhostMembers { channel_id: CHANNEL_ID, users: vec![user()] },
+        Message::JoinRejected { channel_id: CHANNEL_ID, reason: Some("Private meeting".to_string()) },
+        Message::TextMessage {
+            message_id: MESSAGE_ID,
+            user_id: USER_ID,
+            channel_id: CHANNEL_ID,
+            content: "Hello, world".to_string(),
+            timestamp: 1_700_000_000,
+        },
+        Message::DirectMessage { from_user_id: USER_ID, to_user_id: OTHER_USER_ID, content: "Hi Bob".to_string(), timestamp: 1_700_000_000 },
+        Message::SearchMessages { query: "hello".to_string(), channel_ids: vec![CHANNEL_ID], limit: 25, offset: 0 },
+        Message::SearchResults {
+            results: vec![SearchResult {
+                message_id: MESSAGE_ID,
+                channel_id: CHANNEL_ID,
+                user_id: USER_ID,
+                content: "Hello, world".to_string(),
+                timestamp: 1_700_000_000,
+                snippet: "Hello, world".to_string(),
+            }],
+        },
+        Message::SearchUsers { query: "ali".to_string(), include_offline: true },
+        Message::SearchUsersResult { users: vec![user(), User { id: OTHER_USER_ID, status: UserStatus::Offline, ..user() }] },
+        Message::RetentionPolicyUpdate { channel_id: CHANNEL_ID, retention_days: Some(30) },
+        Message::MessagesDeleted { channel_id: CHANNEL_ID, deleted_count: 12, oldest_remaining: 1_700_000_000 },
+        Message::ExportMessages { channel_id: CHANNEL_ID, start_ts: 1_700_000_000, end_ts: 1_700_086_400, format: ExportFormat::CSV },
+        Message::ExportData {
+            transfer_id: OTHER_USER_ID,
+            format: ExportFormat::JSON,
+            chunk_index: 0,
+            chunk_count: 1,
+            data: b"[]".to_vec(),
+        },
+        Message::FileTransferOffer {
+            id: FILE_TRANSFER_ID,
+            from: USER_ID,
+            to: OTHER_USER_ID,
+            filename: "notes.txt".to_string(),
+            size_bytes: 5,
+            mime_type: "text/plain".to_string(),
+        },
+        Message::FileTransferAccept { id: FILE_TRANSFER_ID },
+        Message::FileTransferReject { id: FILE_TRANSFER_ID, reason: "Declined".to_string() },
+        Message::FileTransferChunk { id: FILE_TRANSFER_ID, offset: 0, data: b"notes".to_vec() },
+        Message::FileTransferComplete { id: FILE_TRANSFER_ID },
+        Message::VoiceData {
+            user_id: USER_ID,
+            channel_id: CHANNEL_ID,
+            seq: 7,
+            captured_at_ms: 1_700_000_000_123,
+            epoch: 2,
+            data: vec![1, 2, 3],
+        },
+        Message::VoiceStarted { user_id: USER_ID },
+        Message::VoiceStopped { user_id: USER_ID },
+        Message::SetBroadcastSelf { enabled: true },
+        Message::GetSpeakingStats { channel_id: CHANNEL_ID },
+        Message::SpeakingStats { channel_id: CHANNEL_ID, stats: vec![(USER_ID, 90_500), (OTHER_USER_ID, 12_000)] },
+        Message::E2EPublicKey { user_id: USER_ID, public_key: vec![9; 32] },
+        Message::E2EChannelEnabled { channel_id: CHANNEL_ID },
+        Message::E2EVoiceKey {
+            channel_id: CHANNEL_ID,
+            sender_id: USER_ID,
+            recipient_id: OTHER_USER_ID,
+            epoch: 2,
+            encrypted_key: vec![4, 5, 6],
+        },
+        Message::KeyRotationInit { channel_id: CHANNEL_ID, initiator_id: USER_ID, new_epoch: 3 },
+        Message::SDPOffer { from_user_id: USER_ID, to_user_id: OTHER_USER_ID, sdp: "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\n".to_string() },
+        Message::SDPAnswer { from_user_id: OTHER_USER_ID, to_user_id: USER_ID, sdp: "v=0\r\no=- 1 0 IN IP4 127.0.0.1\r\n".to_string() },
+        Message::ICECandidate {
+            from: USER_ID,
+            to: OTHER_USER_ID,
+            candidate: "candidate:1 1 UDP 2130706431 192.0.2.1 54400 typ host".to_string(),
+        },
+        Message::TranscriptionResult { user_id: USER_ID, channel_id: CHANNEL_ID, text: "hello".to_string(), timestamp: 1_700_000_000 },
+        Message::VideoData {
+            user_id: USER_ID,
+            channel_id: CHANNEL_ID,
+            seq: 8,
+            captured_at_ms: 1_700_000_000_456,
+            video_codec: VideoCodec::VP9,
+            data: vec![7, 8, 9],
+        },
+        Message::VideoStarted { user_id: USER_ID },
+        Message::VideoStopped { user_id: USER_ID },
+        Message::VideoCodecInfo { user_id: USER_ID, codec: VideoCodec::AV1 },
+        Message::VideoQualityUpdate { user_id: USER_ID, channel_id: CHANNEL_ID, quality: VideoQuality::Paused },
+        Message::ScreenShareData { user_id: USER_ID, channel_id: CHANNEL_ID, seq: 9, data: vec![10, 11] },
+        Message::ScreenShareStarted { user_id: USER_ID },
+        Message::ScreenShareStopped { user_id: USER_ID },
+        Message::ScreenShareRegionChanged { user_id: USER_ID, region: Some(ScreenRegion { x: 1920, y: 0, width: 1280, height: 720 }) },
+        Message::SyncHint { user_id: USER_ID, audio_pts_ms: 1_700_000_000_123, video_pts_ms: 1_700_000_000_100 },
+        Message::RecordingConsent { recording_user_id: USER_ID, recording_started: true },
+        Message::ConsentAcknowledged { user_id: OTHER_USER_ID },
+        Message::ConsentRejected { user_id: OTHER_USER_ID },
+        Message::ServerInfo { server: server() },
+        Message::ServerInfoSummary {
+            server_id: SERVER_ID,
+            name: "Open Reverb Server".to_string(),
+            channel_count: 1_200,
+            user_count: 3_500,
+            schema_version: 1_000,
+        },
+        Message::GetChannels { offset: 100, limit: 50 },
+        Message::ChannelList { channels: vec![channel()], total: 1_200 },
+        Message::GetOnlineUsers { channel_id: Some(CHANNEL_ID) },
+        Message::UserList { users: vec![user()] },
+        Message::SchemaVersionMismatch { server_version: 2_000, client_version: 1_000 },
+        Message::GetServerStats {},
+        Message::ServerStats {
+            stats: ServerStatistics {
+                total_connections: 2,
+                per_channel: vec![ChannelStats {
+                    channel_id: CHANNEL_ID,
+                    name: "General".to_string(),
+                    members: 2,
+                    voice_streams: 1,
+                    video_streams: 0,
+                    bytes_forwarded_per_sec: 4096,
+                    recording_active: true,
+                }],
+                admin_ids: vec![USER_ID],
+            },
+        },
+        Message::AuditLogQuery { user_id: Some(USER_ID), since: 0, limit: 100 },
+        Message::AuditLogEntries {
+            entries: vec![ConnectionAuditEntry {
+                user_id: Some(USER_ID),
+                username: Some("alice".to_string()),
+                ip_addr: "127.0.0.1:50000".to_string(),
+                connected_at: 1_700_000_000_000,
+                disconnected_at: Some(1_700_000_060_000),
+                disconnect_reason: Some(DisconnectReason::Expired),
+                events: vec![AuditEvent::PrivacyModeTriggered { at: 1_700_000_030_000 }],
+            }],
+        },
+        Message::ScheduleAnnouncement { message: "Maintenance at midnight".to_string(), send_at: 1_700_000_000 },
+        Message::ListAnnouncements {},
+        Message::DeleteAnnouncement { id: ANNOUNCEMENT_ID },
+        Message::Announcements {
+            announcements: vec![ScheduledAnnouncement {
+                id: ANNOUNCEMENT_ID,
+                message: "Maintenance at midnight".to_string(),
+                send_at: 1_700_000_000,
+                sent: false,
+            }],
+        },
+        Message::ServerAnnouncement { text: "Maintenance at midnight".to_string() },
+        Message::FederationPresence {
+            usernames: vec!["alice".to_string(), "bob".to_string()],
+            timestamp: 1_700_000_000,
+            signature: "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string(),
+        },
+        Message::FederationRelay {
+            from_username: "alice".to_string(),
+            target_username: "bob".to_string(),
+            message: "Hi Bob".to_string(),
+            timestamp: 1_700_000_000,
+            signature: "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string(),
+        },
+        Message::Ping { nonce: 42 },
+        Message::Pong { nonce: 42 },
+        Message::DiagnosticPing { payload_size: 4, padding: "xxxx".to_string() },
+        Message::DiagnosticPong { payload_size: 4, padding: "xxxx".to_string() },
+        Message::Error { code: 403, message: "Forbidden".to_string() },
+    ]
+}
+
+// Serialized messages are objects with the variant's name as their only key
+fn variant_name(json: &Value) -> String {
+    json.as_object()
+        .and_then(|object| object.keys().next())
+        .expect("messages serialize as a single-key object")
+        .clone()
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("{}.json", name))
+}
+
+// Compare against the golden file, or write it when generating
+fn check_golden(name: &str, json: &Value) {
+    let path = golden_path(name);
+    
+    if cfg!(feature = "generate-goldens") {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, serde_json::to_string_pretty(json).unwrap() + "\n").unwrap();
+        return;
+    }
+    
+    let golden = fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!("No golden file for {}; generate it with --features generate-goldens", name)
+    });
+    let expected: Value = serde_json::from_str(&golden).unwrap();
+    
+    assert_eq!(&expected, json, "{} no longer serializes as in {}", name, path.display());
+}
+
+// Serializing, deserializing and serializing again gives the same JSON
+fn assert_round_trip<T: Serialize + DeserializeOwned>(value: &T) -> Value {
+    let json = serde_json::to_value(value).unwrap();
+    let decoded: T = serde_json::from_value(json.clone()).unwrap();
+    
+    assert_eq!(json, serde_json::to_value(&decoded).unwrap());
+    json
+}
+
+#[test]
+fn every_variant_has_an_example() {
+    let covered: BTreeSet<usize> = all_messages().iter().map(variant_index).collect();
+    let missing: Vec<usize> = (0..VARIANT_COUNT).filter(|i| !covered.contains(i)).collect();
+    
+    assert!(missing.is_empty(), "No example in all_messages for variants {:?}", missing);
+    assert_eq!(all_messages().len(), VARIANT_COUNT, "all_messages has more than one example of a variant");
+}
+
+#[test]
+fn every_variant_round_trips() {
+    for message in all_messages() {
+        assert_round_trip(&message);
+    }
+}
+
+#[test]
+fn every_variant_matches_its_golden_file() {
+    for message in all_messages() {
+        let json = serde_json::to_value(&message).unwrap();
+        check_golden(&variant_name(&json), &json);
+    }
+}
+
+#[test]
+fn nested_types_match_their_golden_files() {
+    check_golden("User", &assert_round_trip(&user()));
+    check_golden("Channel", &assert_round_trip(&channel()));
+    check_golden("Server", &assert_round_trip(&server()));
+    
+    let statuses = [UserStatus::Online, UserStatus::Away, UserStatus::DoNotDisturb, UserStatus::Offline];
+    check_golden("UserStatus", &assert_round_trip(&statuses));
+}
+
+#[test]
+fn nested_enums_round_trip() {
+    for status in [UserStatus::Online, UserStatus::Away, UserStatus::DoNotDisturb, UserStatus::Offline] {
+        assert_round_trip(&status);
+    }
+    for channel_type in [ChannelType::Voice, ChannelType::Text] {
+        assert_round_trip(&channel_type);
+    }
+    for codec in VideoCodec::ALL {
+        assert_round_trip(&codec);
+    }
+    for reason in [
+        DisconnectReason::Clean,
+        DisconnectReason::Timeout,
+        DisconnectReason::Kicked,
+        DisconnectReason::Banned,
+        DisconnectReason::Expired,
+    ] {
+        assert_round_trip(&reason);
+    }
+}
+
+// A client built before most messages existed. Serde's #[serde(other)] only works with
+// internally or adjacently tagged enums, so anything it doesn't recognise falls through to
+// an untagged catch-all instead.
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+enum OldMessage {
+    LoginResponse { success: bool, user_id: Option<Uuid>, error: Option<String> },
+    Ping { nonce: u32 },
+    Pong { nonce: u32 },
+    Error { code: u32, message: String },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum OldClientMessage {
+    Known(OldMessage),
+    Unknown(Value),
+}
+
+#[test]
+fn old_clients_can_read_every_variant() {
+    for message in all_messages() {
+        let json = serde_json::to_string(&message).unwrap();
+        let decoded: OldClientMessage = serde_json::from_str(&json)
+            .unwrap_or_else(|e| panic!("Old client failed to read {}: {}", json, e));
+        
+        let known = matches!(
+            message,
+            Message::LoginResponse { .. } | Message::Ping { .. } | Message::Pong { .. } | Message::Error { .. }
+        );
+        assert_eq!(known, matches!(decoded, OldClientMessage::Known(_)), "{}", json);
+    }
+}
+
+#[test]
+fn unknown_variant_is_an_error() {
+    let result = serde_json::from_str::<Message>(r#"{"FutureMessage":{"value":1}}"#);
+    
+    assert!(result.is_err());
+}
+
+#[test]
+fn unknown_fields_are_ignored() {
+    let message: Message = serde_json::from_str(r#"{"Ping":{"nonce":7,"added_later":true}}"#).unwrap();
+    
+    assert!(matches!(message, Message::Ping { nonce: 7 }));
+}
+
+#[test]
+fn fields_added_with_defaults_can_be_missing() {
+    // As sent before topics, encryption, channel types, bandwidth limits and audio quality were added
+    let channel: Channel = serde_json::from_value(serde_json::json!({
+        "id": CHANNEL_ID,
+        "name": "General",
+        "description": null,
+        "parent_id": null,
+        "members": [],
+    }))
+    .unwrap();
+    assert_eq!(channel.topic, None);
+    assert!(!channel.e2e_encrypted);
+    assert_eq!(channel.channel_type, ChannelType::Voice);
+    assert_eq!(channel.media_bandwidth_limit_kbps, 0);
+    assert_eq!(channel.audio_quality, AudioQuality::default());
+    
+    // And before join times
+    let user: User = serde_json::from_value(serde_json::json!({
+        "id": USER_ID,
+        "username": "alice",
+        "status": "Online",
+    }))
+    .unwrap();
+    assert_eq!(user.joined_at, 0);
+}
+
+#[test]
+fn channels_labelled_opus_get_adpcm() {
+    let codec: AudioCodec = serde_json::from_str(r#""Opus""#).unwrap();
+    
+    assert_eq!(codec, AudioCodec::Adpcm);
+    assert_eq!(serde_json::to_string(&codec).unwrap(), r#""ADPCM""#);
+}
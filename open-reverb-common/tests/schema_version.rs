@@ -0,0 +1,39 @@
+// Checks how a server's schema version is compared with the client's, and which differences
+// the user is warned about.
+
+use open_reverb_common::models::SchemaCompatibility;
+
+#[test]
+fn equal_versions_are_compatible() {
+    let compatibility = SchemaCompatibility::check(1_000, 1_000);
+    
+    assert_eq!(compatibility, SchemaCompatibility::Compatible);
+    assert_eq!(compatibility.warning(), None);
+}
+
+#[test]
+fn newer_minor_server_is_compatible() {
+    let compatibility = SchemaCompatibility::check(1_003, 1_000);
+    
+    assert_eq!(compatibility, SchemaCompatibility::Compatible);
+    assert_eq!(compatibility.warning(), None);
+}
+
+#[test]
+fn newer_major_server_warns_features_may_not_work() {
+    let compatibility = SchemaCompatibility::check(2_000, 1_005);
+    
+    assert_eq!(compatibility, SchemaCompatibility::ServerNewer);
+    assert_eq!(compatibility.warning(), Some("Server uses a newer schema; some features may not work."));
+}
+
+#[test]
+fn newer_client_warns_server_may_be_outdated() {
+    let compatibility = SchemaCompatibility::check(1_000, 1_001);
+    
+    assert_eq!(compatibility, SchemaCompatibility::ClientNewer);
+    assert_eq!(compatibility.warning(), Some("Server may be outdated; consider upgrading."));
+    
+    // Servers from before the version was sent
+    assert_eq!(SchemaCompatibility::check(0, 1_000), SchemaCompatibility::ClientNewer);
+}
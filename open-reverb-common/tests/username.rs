@@ -0,0 +1,31 @@
+// Checks which usernames the client and server accept.
+
+use open_reverb_common::models::validate_username;
+
+#[test]
+fn accepts_letters_digits_underscores_and_hyphens() {
+    for username in ["bob", "Alice_99", "dark-knight", "___", "a1-", &"x".repeat(32)] {
+        assert_eq!(validate_username(username), Ok(()), "{}", username);
+    }
+}
+
+#[test]
+fn rejects_names_too_short_or_too_long() {
+    for username in ["", "a", "ab", &"x".repeat(33)] {
+        assert!(validate_username(username).unwrap_err().contains("3 to 32"), "{}", username);
+    }
+}
+
+#[test]
+fn rejects_every_other_character() {
+    let invalid = (' '..='~')
+        .filter(|c| !c.is_ascii_alphanumeric() && *c != '_' && *c != '-')
+        .chain(['é', 'ß', 'Ω', '名', '\t', '\n', '\0', '😀']);
+    
+    for c in invalid {
+        // At the start, in the middle and at the end
+        for username in [format!("{}abc", c), format!("ab{}c", c), format!("abc{}", c)] {
+            assert!(validate_username(&username).unwrap_err().contains("only contain"), "{:?}", username);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use open_reverb_common::models::ScheduledAnnouncement;
+
+// Longest announcement an admin can schedule, in characters
+pub const MAX_ANNOUNCEMENT_LENGTH: usize = 500;
+
+// Announcements admins have scheduled for maintenance windows and the like
+pub struct AnnouncementScheduler {
+    announcements: Vec<ScheduledAnnouncement>,
+}
+
+impl AnnouncementScheduler {
+    pub fn new() -> Self {
+        Self {
+            announcements: Vec::new(),
+        }
+    }
+    
+    // `send_at` is in seconds since the Unix epoch; a time in the past sends it on the next check
+    pub fn schedule(&mut self, message: String, send_at: u64) -> Uuid {
+        let id = Uuid::new_v4();
+        self.announcements.push(ScheduledAnnouncement { id, message, send_at, sent: false });
+        id
+    }
+    
+    // Every announcement, sent or not, soonest first
+    pub fn list(&self) -> Vec<ScheduledAnnouncement> {
+        let mut announcements = self.announcements.clone();
+        announcements.sort_by_key(|a| a.send_at);
+        announcements
+    }
+    
+    // Returns false if there was no such announcement
+    pub fn remove(&mut self, id: Uuid) -> bool {
+        let before = self.announcements.len();
+        self.announcements.retain(|a| a.id != id);
+        self.announcements.len() != before
+    }
+    
+    // Messages of the announcements due by `now`, which are marked sent so they only go out once
+    pub fn take_due(&mut self, now: u64) -> Vec<String> {
+        let mut due: Vec<&mut ScheduledAnnouncement> = self.announcements
+            .iter_mut()
+            .filter(|a| !a.sent && a.send_at <= now)
+            .collect();
+        due.sort_by_key(|a| a.send_at);
+        
+        due.into_iter()
+            .map(|announcement| {
+                announcement.sent = true;
+                announcement.message.clone()
+            })
+            .collect()
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn announcement_is_sent_once_due() {
+        let mut scheduler = AnnouncementScheduler::new();
+        scheduler.schedule("Maintenance tonight".to_string(), 1_000);
+        
+        assert!(scheduler.take_due(999).is_empty());
+        assert_eq!(scheduler.take_due(1_000), ["Maintenance tonight"]);
+    }
+    
+    #[test]
+    fn announcement_is_not_resent() {
+        let mut scheduler = AnnouncementScheduler::new();
+        scheduler.schedule("Maintenance tonight".to_string(), 1_000);
+        
+        assert_eq!(scheduler.take_due(1_010).len(), 1);
+        assert!(scheduler.take_due(1_020).is_empty());
+        assert!(scheduler.list()[0].sent);
+    }
+    
+    #[test]
+    fn due_announcements_are_sent_in_order() {
+        let mut scheduler = AnnouncementScheduler::new();
+        scheduler.schedule("second".to_string(), 2_000);
+        scheduler.schedule("later".to_string(), 5_000);
+        scheduler.schedule("first".to_string(), 1_000);
+        
+        assert_eq!(scheduler.take_due(3_000), ["first", "second"]);
+        assert_eq!(scheduler.take_due(5_000), ["later"]);
+    }
+    
+    #[test]
+    fn removed_announcement_is_never_sent() {
+        let mut scheduler = AnnouncementScheduler::new();
+        let id = scheduler.schedule("Cancelled".to_string(), 1_000);
+        
+        assert!(scheduler.remove(id));
+        assert!(!scheduler.remove(id));
+        assert!(scheduler.take_due(2_000).is_empty());
+    }
+}
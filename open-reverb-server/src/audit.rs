@@ -0,0 +1,201 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use open_reverb_common::models::{AuditEvent, ConnectionAuditEntry, DisconnectReason};
+
+// Oldest entries are dropped past this many
+const MAX_AUDIT_ENTRIES: usize = 10_000;
+
+// Most entries returned for one query
+pub const MAX_AUDIT_QUERY_LIMIT: u32 = 500;
+
+// Who connected from where and when, for admins
+pub struct ConnectionAuditLog {
+    entries: VecDeque<ConnectionAuditEntry>,
+    // Sequence number of each open connection's entry, by session ID
+    open: HashMap<Uuid, u64>,
+    // Sequence number of the entry at the front of `entries`
+    first_seq: u64,
+}
+
+impl ConnectionAuditLog {
+    pub fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            open: HashMap::new(),
+            first_seq: 0,
+        }
+    }
+    
+    pub fn connected(&mut self, session_id: Uuid, addr: &str) {
+        if self.entries.len() >= MAX_AUDIT_ENTRIES {
+            self.entries.pop_front();
+            self.first_seq += 1;
+        }
+        
+        self.open.insert(session_id, self.first_seq + self.entries.len() as u64);
+        self.entries.push_back(ConnectionAuditEntry {
+            user_id: None,
+            username: None,
+            ip_addr: ip_addr(addr),
+            connected_at: now_millis(),
+            disconnected_at: None,
+            disconnect_reason: None,
+            events: Vec::new(),
+        });
+    }
+    
+    pub fn logged_in(&mut self, session_id: Uuid, user_id: Uuid, username: &str) {
+        if let Some(entry) = self.open_entry(session_id) {
+            entry.user_id = Some(user_id);
+            entry.username = Some(username.to_string());
+        }
+    }
+    
+    pub fn privacy_mode_triggered(&mut self, session_id: Uuid) {
+        if let Some(entry) = self.open_entry(session_id) {
+            entry.events.push(AuditEvent::PrivacyModeTriggered { at: now_millis() });
+        }
+    }
+    
+    pub fn disconnected(&mut self, session_id: Uuid, reason: DisconnectReason) {
+        if let Some(entry) = self.open_entry(session_id) {
+            entry.disconnected_at = Some(now_millis());
+            entry.disconnect_reason = Some(reason);
+        }
+        self.open.remove(&session_id);
+    }
+    
+    // Connections made after `since`, oldest first, optionally only those of one user
+    pub fn query(&self, user_id: Option<Uuid>, since: u64, limit: u32) -> Vec<ConnectionAuditEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.connected_at > since)
+            .filter(|entry| user_id.is_none_or(|id| entry.user_id == Some(id)))
+            .take(limit.min(MAX_AUDIT_QUERY_LIMIT) as usize)
+            .cloned()
+            .collect()
+    }
+    
+    fn open_entry(&mut self, session_id: Uuid) -> Option<&mut ConnectionAuditEntry> {
+        let seq = *self.open.get(&session_id)?;
+        let index = seq.checked_sub(self.first_seq)?;
+        self.entries.get_mut(index as usize)
+    }
+}
+
+// Why reading from a connection failed. Running out of input means the client hung up.
+pub fn classify_disconnect(error: &io::Error) -> DisconnectReason {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof => DisconnectReason::Clean,
+        _ => DisconnectReason::Timeout,
+    }
+}
+
+// Session addresses are "ip:port"; anything else is logged as it is
+fn ip_addr(addr: &str) -> String {
+    addr.parse::<SocketAddr>()
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|_| addr.to_string())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn hangups_are_clean_and_everything_else_a_timeout() {
+        let reason = |kind| classify_disconnect(&io::Error::from(kind));
+        
+        assert_eq!(reason(io::ErrorKind::UnexpectedEof), DisconnectReason::Clean);
+        assert_eq!(reason(io::ErrorKind::ConnectionReset), DisconnectReason::Timeout);
+        assert_eq!(reason(io::ErrorKind::ConnectionAborted), DisconnectReason::Timeout);
+        assert_eq!(reason(io::ErrorKind::BrokenPipe), DisconnectReason::Timeout);
+        assert_eq!(reason(io::ErrorKind::TimedOut), DisconnectReason::Timeout);
+        assert_eq!(reason(io::ErrorKind::InvalidData), DisconnectReason::Timeout);
+    }
+    
+    #[test]
+    fn entries_follow_a_connection_from_start_to_finish() {
+        let mut log = ConnectionAuditLog::new();
+        let session_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        
+        log.connected(session_id, "192.168.1.7:50123");
+        log.logged_in(session_id, user_id, "alice");
+        log.privacy_mode_triggered(session_id);
+        log.disconnected(session_id, DisconnectReason::Kicked);
+        // Nothing more is recorded once it's closed
+        log.privacy_mode_triggered(session_id);
+        log.disconnected(session_id, DisconnectReason::Clean);
+        
+        let entries = log.query(None, 0, 10);
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!((entry.user_id, entry.username.as_deref()), (Some(user_id), Some("alice")));
+        assert_eq!(entry.ip_addr, "192.168.1.7");
+        assert_eq!(entry.disconnect_reason, Some(DisconnectReason::Kicked));
+        assert!(entry.disconnected_at.unwrap() >= entry.connected_at);
+        assert_eq!(entry.events.len(), 1);
+        
+        // Addresses that aren't "ip:port" are kept as they are
+        log.connected(Uuid::new_v4(), "ws-client");
+        assert_eq!(log.query(None, 0, 10)[1].ip_addr, "ws-client");
+    }
+    
+    #[test]
+    fn queries_page_through_one_users_connections() {
+        let mut log = ConnectionAuditLog::new();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        
+        for i in 0..25 {
+            let session_id = Uuid::new_v4();
+            log.connected(session_id, "10.0.0.1:5000");
+            log.logged_in(session_id, if i % 5 == 0 { bob } else { alice }, "user");
+            log.entries.back_mut().unwrap().connected_at = 1_000 + i;
+        }
+        
+        // Each page starts after the last connection of the one before
+        let mut pages = Vec::new();
+        let mut since = 0;
+        loop {
+            let page = log.query(Some(alice), since, 8);
+            let Some(last) = page.last() else { break };
+            since = last.connected_at;
+            pages.push(page.iter().map(|e| e.connected_at).collect::<Vec<_>>());
+        }
+        let times: Vec<u64> = (0..25).filter(|i| i % 5 != 0).map(|i| 1_000 + i).collect();
+        assert_eq!(pages, [&times[0..8], &times[8..16], &times[16..20]]);
+        
+        assert_eq!(log.query(Some(bob), 0, 100).len(), 5);
+        assert_eq!(log.query(None, 1_019, 100).len(), 5);
+    }
+    
+    #[test]
+    fn queries_and_the_log_are_capped() {
+        let mut log = ConnectionAuditLog::new();
+        let first = Uuid::new_v4();
+        log.connected(first, "10.0.0.1:5000");
+        for _ in 0..MAX_AUDIT_ENTRIES {
+            log.connected(Uuid::new_v4(), "10.0.0.2:5000");
+        }
+        
+        assert_eq!(log.entries.len(), MAX_AUDIT_ENTRIES);
+        assert_eq!(log.query(None, 0, u32::MAX).len(), MAX_AUDIT_QUERY_LIMIT as usize);
+        
+        // The oldest entry is gone, so closing its connection changes nothing
+        log.disconnected(first, DisconnectReason::Clean);
+        assert!(log.entries.iter().all(|e| e.ip_addr == "10.0.0.2" && e.disconnect_reason.is_none()));
+    }
+}
@@ -4,11 +4,6 @@ use argon2::{
 };
 use std::error::Error;
 
-pub struct Credentials {
-    pub username: String,
-    pub password_hash: String,
-}
-
 pub fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
@@ -24,4 +19,51 @@ pub fn verify_password(password: &str, password_hash: &str) -> Result<bool, Box<
     let result = Argon2::default().verify_password(password.as_bytes(), &parsed_hash);
     
     Ok(result.is_ok())
-}
\ No newline at end of file
+}
+
+// What the password a user logged in with turned out to be
+#[derive(Debug, PartialEq)]
+pub enum PasswordCheck {
+    Correct,
+    Wrong,
+    // The user had no password yet, so this hash of the one given becomes theirs
+    New(String),
+    // The password couldn't be hashed
+    Failed,
+}
+
+impl PasswordCheck {
+    // Slow on purpose, so run it without the server state locked. Hashes this can't read
+    // never match.
+    pub fn run(password: &str, stored_hash: Option<&str>) -> Self {
+        match stored_hash {
+            Some(hash) if verify_password(password, hash).unwrap_or(false) => PasswordCheck::Correct,
+            Some(_) => PasswordCheck::Wrong,
+            None => hash_password(password).map_or(PasswordCheck::Failed, PasswordCheck::New),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn only_the_same_password_verifies() {
+        let PasswordCheck::New(stored) = PasswordCheck::run("hunter2", None) else {
+            panic!("a new password should be hashed");
+        };
+        
+        assert_eq!(PasswordCheck::run("hunter2", Some(&stored)), PasswordCheck::Correct);
+        assert_eq!(PasswordCheck::run("hunter3", Some(&stored)), PasswordCheck::Wrong);
+        assert_eq!(PasswordCheck::run("", Some(&stored)), PasswordCheck::Wrong);
+        // Salted, so the same password hashes differently each time
+        assert_ne!(hash_password("hunter2").unwrap(), stored);
+    }
+    
+    #[test]
+    fn unreadable_hashes_never_verify() {
+        assert_eq!(PasswordCheck::run("hunter2", Some("")), PasswordCheck::Wrong);
+        assert_eq!(PasswordCheck::run("hunter2", Some("pbkdf2-sha256$0$AAAA$AAAA")), PasswordCheck::Wrong);
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use config::{Config, File, FileFormat};
+use open_reverb_common::models::{AudioQuality, Channel, ChannelType};
+use serde::Deserialize;
+use uuid::Uuid;
+
+// One channel in the channels file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ChannelDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(rename = "type", default)]
+    pub kind: ChannelKind,
+    // Name of the channel this one is nested under
+    #[serde(default)]
+    pub parent: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    #[default]
+    Voice,
+    Text,
+}
+
+impl From<ChannelKind> for ChannelType {
+    fn from(kind: ChannelKind) -> Self {
+        match kind {
+            ChannelKind::Voice => ChannelType::Voice,
+            ChannelKind::Text => ChannelType::Text,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ChannelsFile {
+    #[serde(default)]
+    channel: Vec<ChannelDefinition>,
+}
+
+// Read the channels file, as YAML if its extension says so and TOML otherwise. Returns None
+// when the file doesn't exist.
+pub fn load(path: &str, max_depth: usize) -> Result<Option<Vec<ChannelDefinition>>, String> {
+    let path = Path::new(path);
+    if !path.exists() {
+        return Ok(None);
+    }
+    
+    let text = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    let format = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => FileFormat::Yaml,
+        _ => FileFormat::Toml,
+    };
+    
+    parse(&text, format, max_depth)
+        .map(Some)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Parse and check channel definitions, ordered so every parent comes before its children
+pub fn parse(text: &str, format: FileFormat, max_depth: usize) -> Result<Vec<ChannelDefinition>, String> {
+    let file: ChannelsFile = Config::builder()
+        .add_source(File::from_str(text, format))
+        .build()
+        .and_then(Config::try_deserialize)
+        .map_err(|e| e.to_string())?;
+    
+    let mut seen = HashSet::new();
+    for definition in &file.channel {
+        if definition.name.trim().is_empty() {
+            return Err("Channel names can't be empty".to_string());
+        }
+        if !seen.insert(definition.name.as_str()) {
+            return Err(format!("Channel \"{}\" is defined more than once", definition.name));
+        }
+    }
+    
+    let parents: HashMap<&str, Option<&str>> = file.channel
+        .iter()
+        .map(|definition| (definition.name.as_str(), definition.parent.as_deref()))
+        .collect();
+    
+    let mut depths = HashMap::new();
+    for definition in &file.channel {
+        let depth = depth(&definition.name, &parents)?;
+        if depth > max_depth {
+            return Err(format!("Channel hierarchy cannot be deeper than {} levels, but \"{}\" is {} deep", max_depth, definition.name, depth));
+        }
+        depths.insert(definition.name.clone(), depth);
+    }
+    
+    let mut definitions = file.channel;
+    definitions.sort_by_key(|definition| depths[&definition.name]);
+    Ok(definitions)
+}
+
+// Levels from the top down to `name`, counting top-level channels as 1
+fn depth(name: &str, parents: &HashMap<&str, Option<&str>>) -> Result<usize, String> {
+    let mut path = vec![name];
+    
+    while let Some(parent) = parents[path[path.len() - 1]] {
+        if !parents.contains_key(parent) {
+            return Err(format!("Channel \"{}\" has unknown parent \"{}\"", path[path.len() - 1], parent));
+        }
+        if path.contains(&parent) {
+            path.push(parent);
+            return Err(format!("Channel hierarchy has a cycle: {}", path.join(" -> ")));
+        }
+        path.push(parent);
+    }
+    
+    Ok(path.len())
+}
+
+// Create the defined channels, or update existing channels with the same name. Channels that
+// aren't defined are left alone. Returns the channels created or changed.
+pub fn apply(channels: &mut HashMap<Uuid, Channel>, definitions: Vec<ChannelDefinition>, audio_quality: AudioQuality) -> Vec<Channel> {
+    let mut ids: HashMap<String, Uuid> = HashMap::new();
+    let mut changed = Vec::new();
+    
+    // Parents come first, so their IDs are known by the time their children get here
+    for definition in definitions {
+        let parent_id = definition.parent.as_ref().and_then(|parent| ids.get(parent).copied());
+        let description = definition.description;
+        let channel_type = ChannelType::from(definition.kind);
+        
+        match channels.values_mut().find(|channel| channel.name == definition.name) {
+            Some(channel) => {
+                ids.insert(definition.name, channel.id);
+                if channel.description != description || channel.channel_type != channel_type || channel.parent_id != parent_id {
+                    channel.description = description;
+                    channel.channel_type = channel_type;
+                    channel.parent_id = parent_id;
+                    changed.push(channel.clone());
+                }
+            }
+            None => {
+                let channel = Channel {
+                    id: Uuid::new_v4(),
+                    name: definition.name.clone(),
+                    description,
+                    parent_id,
+                    members: Vec::new(),
+                    topic: None,
+                    e2e_encrypted: false,
+                    channel_type,
+                    media_bandwidth_limit_kbps: 0,
+                    lobby_mode: false,
+                    audio_quality,
+                    override_retention_days: None,
+                    // Admins add and remove these through the file
+                    permanent: true,
+                    idle_timeout_secs: None,
+                };
+                ids.insert(definition.name, channel.id);
+                channels.insert(channel.id, channel.clone());
+                changed.push(channel);
+            }
+        }
+    }
+    
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const HIERARCHY: &str = r#"
+[[channel]]
+name = "Lobby"
+parent = "Games"
+
+[[channel]]
+name = "Games"
+description = "Everything gaming"
+
+[[channel]]
+name = "Squad"
+parent = "Lobby"
+
+[[channel]]
+name = "chat"
+type = "text"
+"#;
+    
+    fn names(definitions: &[ChannelDefinition]) -> Vec<&str> {
+        definitions.iter().map(|d| d.name.as_str()).collect()
+    }
+    
+    #[test]
+    fn parses_multi_level_hierarchy_parents_first() {
+        let definitions = parse(HIERARCHY, FileFormat::Toml, 3).unwrap();
+        
+        assert_eq!(names(&definitions), vec!["Games", "chat", "Lobby", "Squad"]);
+        assert_eq!(definitions[0].description.as_deref(), Some("Everything gaming"));
+        assert_eq!(definitions[1].kind, ChannelKind::Text);
+        assert_eq!(definitions[3].parent.as_deref(), Some("Lobby"));
+        
+        let mut channels = HashMap::new();
+        apply(&mut channels, definitions, AudioQuality::default());
+        let by_name = |name: &str| channels.values().find(|c| c.name == name).unwrap();
+        assert_eq!(by_name("Squad").parent_id, Some(by_name("Lobby").id));
+        assert_eq!(by_name("Lobby").parent_id, Some(by_name("Games").id));
+        assert_eq!(by_name("chat").channel_type, ChannelType::Text);
+    }
+    
+    #[test]
+    fn parses_yaml() {
+        let yaml = "channel:\n  - name: General\n  - name: Music\n    parent: General\n";
+        
+        let definitions = parse(yaml, FileFormat::Yaml, 3).unwrap();
+        
+        assert_eq!(names(&definitions), vec!["General", "Music"]);
+    }
+    
+    #[test]
+    fn reports_cycles_duplicates_and_unknown_parents() {
+        let cycle = "[[channel]]\nname = \"A\"\nparent = \"B\"\n[[channel]]\nname = \"B\"\nparent = \"A\"\n";
+        assert_eq!(parse(cycle, FileFormat::Toml, 3).unwrap_err(), "Channel hierarchy has a cycle: A -> B -> A");
+        
+        let own_parent = "[[channel]]\nname = \"A\"\nparent = \"A\"\n";
+        assert_eq!(parse(own_parent, FileFormat::Toml, 3).unwrap_err(), "Channel hierarchy has a cycle: A -> A");
+        
+        let duplicate = "[[channel]]\nname = \"A\"\n[[channel]]\nname = \"A\"\n";
+        assert_eq!(parse(duplicate, FileFormat::Toml, 3).unwrap_err(), "Channel \"A\" is defined more than once");
+        
+        let unknown = "[[channel]]\nname = \"A\"\nparent = \"Nowhere\"\n";
+        assert_eq!(parse(unknown, FileFormat::Toml, 3).unwrap_err(), "Channel \"A\" has unknown parent \"Nowhere\"");
+        
+        assert!(parse(HIERARCHY, FileFormat::Toml, 2).unwrap_err().contains("\"Squad\" is 3 deep"));
+    }
+    
+    #[test]
+    fn reapplying_updates_channels_by_name() {
+        let mut channels = HashMap::new();
+        apply(&mut channels, parse(HIERARCHY, FileFormat::Toml, 3).unwrap(), AudioQuality::default());
+        let squad_id = channels.values().find(|c| c.name == "Squad").unwrap().id;
+        
+        let moved = "[[channel]]\nname = \"Games\"\n[[channel]]\nname = \"Squad\"\nparent = \"Games\"\n";
+        let changed = apply(&mut channels, parse(moved, FileFormat::Toml, 3).unwrap(), AudioQuality::default());
+        
+        // Games lost its description and Squad moved; nothing else was touched
+        assert_eq!(changed.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["Games", "Squad"]);
+        assert_eq!(channels.len(), 4);
+        assert_eq!(channels[&squad_id].parent_id, changed.iter().find(|c| c.name == "Games").map(|c| c.id));
+    }
+}
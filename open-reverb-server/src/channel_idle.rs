@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use open_reverb_common::models::Channel;
+
+// How often channels are checked for having gone idle
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// When each channel was last used, so ones nobody uses any more can be deleted
+pub struct ChannelActivity {
+    // For channels that don't set their own; channels are kept forever when neither does
+    default_timeout: Option<Duration>,
+    last_activity_at: HashMap<Uuid, Instant>,
+}
+
+impl ChannelActivity {
+    pub fn new(default_timeout_secs: Option<u64>) -> Self {
+        Self {
+            default_timeout: default_timeout_secs.map(Duration::from_secs),
+            last_activity_at: HashMap::new(),
+        }
+    }
+    
+    // Someone joined, spoke or chatted in the channel
+    pub fn touch(&mut self, channel_id: Uuid, now: Instant) {
+        self.last_activity_at.insert(channel_id, now);
+    }
+    
+    // Channels that have gone unused for longer than their timeout and have nobody in them.
+    // Channels with children are kept until their children are gone. Channels seen for the
+    // first time count as used `now`.
+    pub fn idle_channels(
+        &mut self,
+        channels: &HashMap<Uuid, Channel>,
+        is_occupied: impl Fn(Uuid) -> bool,
+        now: Instant,
+    ) -> Vec<Uuid> {
+        let mut idle = Vec::new();
+        
+        for channel in channels.values() {
+            let last_activity_at = *self.last_activity_at.entry(channel.id).or_insert(now);
+            if channel.permanent {
+                continue;
+            }
+            
+            let Some(timeout) = channel.idle_timeout_secs.map(Duration::from_secs).or(self.default_timeout) else {
+                continue;
+            };
+            if now.saturating_duration_since(last_activity_at) <= timeout {
+                continue;
+            }
+            
+            let has_children = channels.values().any(|other| other.parent_id == Some(channel.id));
+            if !has_children && !is_occupied(channel.id) {
+                idle.push(channel.id);
+            }
+        }
+        
+        idle
+    }
+    
+    // Stop tracking channels that have been deleted
+    pub fn forget(&mut self, channel_ids: &[Uuid]) {
+        for channel_id in channel_ids {
+            self.last_activity_at.remove(channel_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use open_reverb_common::models::{AudioQuality, ChannelType};
+    
+    fn channel(name: &str) -> Channel {
+        Channel {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            description: None,
+            parent_id: None,
+            members: Vec::new(),
+            topic: None,
+            e2e_encrypted: false,
+            channel_type: ChannelType::Voice,
+            media_bandwidth_limit_kbps: 0,
+            lobby_mode: false,
+            audio_quality: AudioQuality::default(),
+            override_retention_days: None,
+            permanent: false,
+            idle_timeout_secs: None,
+        }
+    }
+    
+    fn by_id(channels: Vec<Channel>) -> HashMap<Uuid, Channel> {
+        channels.into_iter().map(|c| (c.id, c)).collect()
+    }
+    
+    #[test]
+    fn empty_channels_go_idle_after_the_timeout() {
+        let mut activity = ChannelActivity::new(Some(600));
+        let quiet = channel("quiet");
+        let busy = channel("busy");
+        let (quiet_id, busy_id) = (quiet.id, busy.id);
+        let channels = by_id(vec![quiet, busy]);
+        let start = Instant::now();
+        
+        assert!(activity.idle_channels(&channels, |_| false, start).is_empty());
+        activity.touch(busy_id, start + Duration::from_secs(300));
+        
+        assert!(activity.idle_channels(&channels, |_| false, start + Duration::from_secs(600)).is_empty());
+        assert_eq!(activity.idle_channels(&channels, |_| false, start + Duration::from_secs(601)), vec![quiet_id]);
+        
+        // Still in use by someone, however long since anything happened
+        assert!(activity.idle_channels(&channels, |_| true, start + Duration::from_secs(3_600)).is_empty());
+    }
+    
+    #[test]
+    fn permanent_channels_survive() {
+        let mut activity = ChannelActivity::new(Some(60));
+        let kept = Channel { permanent: true, ..channel("kept") };
+        let channels = by_id(vec![kept]);
+        let start = Instant::now();
+        
+        activity.idle_channels(&channels, |_| false, start);
+        assert!(activity.idle_channels(&channels, |_| false, start + Duration::from_secs(86_400)).is_empty());
+    }
+    
+    #[test]
+    fn channels_can_override_the_timeout() {
+        let mut activity = ChannelActivity::new(None);
+        let default = channel("default");
+        let short = Channel { idle_timeout_secs: Some(30), ..channel("short") };
+        let short_id = short.id;
+        let channels = by_id(vec![default, short]);
+        let start = Instant::now();
+        
+        activity.idle_channels(&channels, |_| false, start);
+        assert_eq!(activity.idle_channels(&channels, |_| false, start + Duration::from_secs(31)), vec![short_id]);
+    }
+    
+    #[test]
+    fn parents_wait_for_their_children() {
+        let mut activity = ChannelActivity::new(Some(60));
+        let parent = channel("parent");
+        let child = Channel { parent_id: Some(parent.id), permanent: true, ..channel("child") };
+        let channels = by_id(vec![parent, child]);
+        let start = Instant::now();
+        
+        activity.idle_channels(&channels, |_| false, start);
+        assert!(activity.idle_channels(&channels, |_| false, start + Duration::from_secs(120)).is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::metrics;
+
+// Window the per-user creation rate is counted over
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelLimitError {
+    // The user created too many channels in the last minute
+    RateLimited,
+    // The server has as many channels as it allows
+    TooManyChannels,
+}
+
+impl ChannelLimitError {
+    pub fn code(self) -> u32 {
+        match self {
+            ChannelLimitError::RateLimited => 429,
+            ChannelLimitError::TooManyChannels => 507,
+        }
+    }
+    
+    pub fn message(self) -> &'static str {
+        match self {
+            ChannelLimitError::RateLimited => "Channel creation rate limit exceeded",
+            ChannelLimitError::TooManyChannels => "The server has reached its channel limit",
+        }
+    }
+}
+
+// Keeps users from flooding the server with channels
+pub struct ChannelCreationLimiter {
+    max_per_minute: u32,
+    max_total: usize,
+    // When each user created their channels within the last minute, oldest first. Kept across
+    // reconnects, so reconnecting doesn't reset the limit.
+    channel_creation_rate: HashMap<Uuid, VecDeque<Instant>>,
+}
+
+impl ChannelCreationLimiter {
+    pub fn new(max_per_minute: u32, max_total: usize) -> Self {
+        Self {
+            max_per_minute,
+            max_total,
+            channel_creation_rate: HashMap::new(),
+        }
+    }
+    
+    // Whether `user_id` may create another channel, with `total_channels` on the server already
+    pub fn check(&mut self, user_id: Uuid, total_channels: usize, now: Instant) -> Result<(), ChannelLimitError> {
+        if total_channels >= self.max_total {
+            return Err(ChannelLimitError::TooManyChannels);
+        }
+        
+        let Some(created) = self.channel_creation_rate.get_mut(&user_id) else {
+            return Ok(());
+        };
+        created.retain(|&at| now.duration_since(at) < RATE_WINDOW);
+        
+        if created.len() >= self.max_per_minute as usize {
+            return Err(ChannelLimitError::RateLimited);
+        }
+        Ok(())
+    }
+    
+    // A channel was created, after `check` allowed it
+    pub fn record(&mut self, user_id: Uuid, now: Instant) {
+        self.channel_creation_rate.entry(user_id).or_default().push_back(now);
+        metrics::get_metrics().channel_creation_total.inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn sixth_channel_in_a_minute_is_rejected() {
+        let mut limiter = ChannelCreationLimiter::new(5, 500);
+        let (user, other_user) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+        let created_before = metrics::get_metrics().channel_creation_total.get();
+        
+        for i in 0..5 {
+            let now = start + Duration::from_secs(i);
+            assert_eq!(limiter.check(user, i as usize, now), Ok(()));
+            limiter.record(user, now);
+        }
+        
+        let sixth = start + Duration::from_secs(10);
+        assert_eq!(limiter.check(user, 5, sixth), Err(ChannelLimitError::RateLimited));
+        // Other users have their own allowance
+        assert_eq!(limiter.check(other_user, 5, sixth), Ok(()));
+        // A minute after the first, there's room for one more
+        assert_eq!(limiter.check(user, 5, start + RATE_WINDOW), Ok(()));
+        
+        assert_eq!(metrics::get_metrics().channel_creation_total.get() - created_before, 5);
+    }
+    
+    #[test]
+    fn rejects_channels_over_the_server_total() {
+        let mut limiter = ChannelCreationLimiter::new(5, 3);
+        
+        assert_eq!(limiter.check(Uuid::new_v4(), 2, Instant::now()), Ok(()));
+        assert_eq!(limiter.check(Uuid::new_v4(), 3, Instant::now()), Err(ChannelLimitError::TooManyChannels));
+        assert_eq!(ChannelLimitError::TooManyChannels.code(), 507);
+    }
+}
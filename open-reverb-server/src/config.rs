@@ -1,14 +1,102 @@
-use std::error::Error;
 use config::{Config, ConfigError, File};
 use lazy_static::lazy_static;
+use open_reverb_common::models::AudioQuality;
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub max_connections: usize,
-    pub database_url: String,
+    // JSON file users and channels are kept in; they're forgotten on restart when unset
+    pub database_path: Option<String>,
+    // Path of the WebSocket endpoint, e.g. "/ws"; disabled when unset
+    pub ws_path: Option<String>,
+    // PEM certificate chain and private key; TCP connections are TLS when both are set
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    // Maximum number of levels in the channel tree, counting top-level channels
+    pub max_hierarchy_depth: usize,
+    // Require every channel member to consent before a recording can start
+    pub require_recording_consent: bool,
+    // Redis server used to share sessions between instances; kept in memory when unset
+    pub redis_url: Option<String>,
+    // Degrade every connection on purpose when testing clients; never set in production
+    pub simulate_network: Option<NetworkSimParams>,
+    // Transcribe voice and send the text to clients
+    pub transcription_enabled: bool,
+    pub transcription_language: String,
+    // Usernames of users who receive server statistics
+    pub admin_users: Vec<String>,
+    // Usernames of users, besides admins, who may join channels as ghosts
+    pub ghost_users: Vec<String>,
+    // Ask for a TOTP code after the password; users without an authenticator set one up on login
+    pub totp_required: bool,
+    // Log users out after this long, unless they renew the session; sessions last forever when unset
+    pub max_session_duration_secs: Option<u64>,
+    // How often connections are pinged, and how long they get to answer before they're dropped;
+    // 0 turns pings off
+    pub ping_interval_secs: u64,
+    pub ping_timeout_secs: u64,
+    // How long a session token can be resumed with after it is issued
+    pub session_token_ttl_secs: u64,
+    // Audio quality of channels created without one
+    pub default_audio_quality: AudioQuality,
+    // Delete chat messages after this many days, unless a channel overrides it; kept forever when unset
+    pub message_retention_days: Option<u32>,
+    // Record each channel member's voice to a file under recording_dir, once everyone in the channel consents
+    pub recording_enabled: bool,
+    pub recording_dir: String,
+    // Track how long each member speaks in a channel; turn off for privacy
+    pub speaking_stats_enabled: bool,
+    // Sent to each user on their first login; either the text itself or the path of a file, e.g. Markdown, holding it
+    pub welcome_message: Option<String>,
+    // TOML or YAML file of the channels to create at startup; the built-in channels are used when it doesn't exist
+    pub channels_config_file: Option<String>,
+    // Channels one user can create per minute, and channels the server holds at most
+    pub max_channels_per_minute: u32,
+    pub max_channels_total: usize,
+    // CIDR ranges or addresses, e.g. "192.168.1.0/24"; when the allowlist isn't empty, only
+    // addresses in it can connect
+    pub ip_allowlist: Vec<String>,
+    pub ip_blocklist: Vec<String>,
+    // Other servers whose online users are shown here, and ours there
+    pub federation: Vec<FederatedServer>,
+    // Let users message users on federated servers, and be messaged by them
+    pub allow_federated_dm: bool,
+    // Channels left empty and quiet for this long are deleted, unless they're permanent or set
+    // their own timeout; never when unset
+    pub channel_idle_timeout_secs: Option<u64>,
+    // Servers with fewer channels and online users than this send clients everything on login;
+    // bigger ones send a summary and let clients fetch the rest as they need it
+    pub eager_load_threshold: u32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NetworkSimParams {
+    pub latency_ms: u32,
+    pub jitter_ms: u32,
+    // Fraction of messages lost (0.0 - 1.0)
+    pub drop_rate: f32,
+    // Fraction of messages with a flipped bit (0.0 - 1.0)
+    pub corrupt_rate: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FederatedServer {
+    // Address of the peer's client port, e.g. "chat.example.com:8080"
+    pub url: String,
+    // Must be the same in both servers' configs. Messages are signed with it, never sent it.
+    pub shared_secret: String,
+    // Peers are connected to with TLS, their certificate checked against this CA certificate
+    // (PEM) when set, or the usual public CAs otherwise
+    #[serde(default)]
+    pub ca_cert_path: Option<String>,
+    // Connect without TLS, for a peer with no certificate configured, e.g. on a private network
+    #[serde(default)]
+    pub plaintext: bool,
 }
 
 impl Default for ServerConfig {
@@ -17,7 +105,38 @@ impl Default for ServerConfig {
             host: "0.0.0.0".to_string(),
             port: 8080,
             max_connections: 1000,
-            database_url: "sqlite::memory:".to_string(),
+            database_path: None,
+            ws_path: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            max_hierarchy_depth: 3,
+            require_recording_consent: false,
+            redis_url: None,
+            simulate_network: None,
+            transcription_enabled: false,
+            transcription_language: "en".to_string(),
+            admin_users: Vec::new(),
+            ghost_users: Vec::new(),
+            totp_required: false,
+            max_session_duration_secs: None,
+            ping_interval_secs: 30,
+            ping_timeout_secs: 10,
+            session_token_ttl_secs: 24 * 60 * 60,
+            default_audio_quality: AudioQuality::default(),
+            message_retention_days: None,
+            recording_enabled: false,
+            recording_dir: "recordings".to_string(),
+            speaking_stats_enabled: true,
+            welcome_message: None,
+            channels_config_file: None,
+            max_channels_per_minute: 5,
+            max_channels_total: 500,
+            ip_allowlist: Vec::new(),
+            ip_blocklist: Vec::new(),
+            federation: Vec::new(),
+            allow_federated_dm: false,
+            channel_idle_timeout_secs: None,
+            eager_load_threshold: 500,
         }
     }
 }
@@ -1,58 +1,376 @@
-// This is a placeholder for a real database implementation.
-// In a production application, you would use a proper database like PostgreSQL, SQLite, etc.
+// Users and channels kept on disk, so they survive a restart. The two tables are written
+// together as one JSON file, replaced whole on each change; there's no SQLite driver in this
+// build to put them in a real database. Changes are made in memory and the file written in the
+// background, so the server state isn't locked while it's on disk.
+//
+// The file carries the version of the tables' layout. Older files are migrated when they're
+// opened, and files from a newer server are refused rather than misread.
 
+use open_reverb_common::models::{Channel, ChannelType};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::error;
 use uuid::Uuid;
 
-use crate::auth::Credentials;
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserRow {
+    pub id: Uuid,
+    pub username: String,
+    // An argon2 hash, see auth.rs. None for users from before passwords were checked, until they next log in.
+    pub password_hash: Option<String>,
+    // Base32, once the user has confirmed it with a code
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    // Seconds since the Unix epoch
+    pub created_at: u64,
+}
 
-// Simple in-memory database for demonstration purposes
-pub struct Database {
-    users: HashMap<String, Credentials>,
-    user_ids: HashMap<String, Uuid>,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelRow {
+    pub id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub parent_id: Option<Uuid>,
+    // Kept so text channels come back as text, and built-in channels are never deleted for being idle
+    pub channel_type: ChannelType,
+    pub permanent: bool,
 }
 
-impl Database {
-    pub fn new() -> Self {
+impl ChannelRow {
+    fn from_channel(channel: &Channel) -> Self {
         Self {
-            users: HashMap::new(),
-            user_ids: HashMap::new(),
+            id: channel.id,
+            name: channel.name.clone(),
+            description: channel.description.clone(),
+            parent_id: channel.parent_id,
+            channel_type: channel.channel_type,
+            permanent: channel.permanent,
         }
     }
-    
-    pub fn add_user(&mut self, username: &str, password_hash: &str, user_id: Uuid) -> bool {
-        if self.users.contains_key(username) {
-            return false;
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    // Another user has the username, ignoring case
+    UsernameTaken(String),
+    Io(String),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatabaseError::UsernameTaken(username) => write!(f, "Username {} is taken (case-insensitive match)", username),
+            DatabaseError::Io(message) => write!(f, "Database error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+// Raise when the tables change in a way older servers can't read, and teach migrate() to
+// bring files from the version before up to date
+const TABLES_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Tables {
+    // Files from before the layout was versioned have none, and count as version 0
+    #[serde(default)]
+    version: u32,
+    users: Vec<UserRow>,
+    channels: Vec<ChannelRow>,
+}
+
+impl Default for Tables {
+    fn default() -> Self {
+        Self { version: TABLES_VERSION, users: Vec::new(), channels: Vec::new() }
+    }
+}
+
+impl Tables {
+    fn migrate(mut self) -> Result<Self, String> {
+        if self.version > TABLES_VERSION {
+            return Err(format!("written by a newer server (version {}, this one reads up to {})", self.version, TABLES_VERSION));
+        }
+        
+        // Version 0 has the same tables; users from before TOTP get no secret by default
+        self.version = TABLES_VERSION;
+        Ok(self)
+    }
+}
+
+// The file, shared with the writes in flight. Each write is numbered, and one is skipped if a
+// later one got there first, so the file always ends up with the latest tables.
+struct DatabaseFile {
+    path: PathBuf,
+    written: Mutex<u64>,
+}
+
+impl DatabaseFile {
+    fn write(&self, generation: u64, json: Vec<u8>) {
+        let mut written = self.written.lock().unwrap();
+        if *written > generation {
+            return;
         }
         
-        self.users.insert(
-            username.to_string(),
-            Credentials {
-                username: username.to_string(),
-                password_hash: password_hash.to_string(),
-            },
-        );
+        match write_atomically(&self.path, &json) {
+            Ok(()) => *written = generation,
+            Err(e) => error!("Failed to write the database to {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+pub struct Database {
+    // None keeps everything in memory
+    file: Option<Arc<DatabaseFile>>,
+    tables: Tables,
+    // Lowercased usernames, for the uniqueness check
+    usernames: HashMap<String, Uuid>,
+    generation: u64,
+    last_write: Option<JoinHandle<()>>,
+}
+
+impl Database {
+    pub fn in_memory() -> Self {
+        Self { file: None, tables: Tables::default(), usernames: HashMap::new(), generation: 0, last_write: None }
+    }
+    
+    // The file is created on the first change if it doesn't exist
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, DatabaseError> {
+        let path = path.into();
+        let tables = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<Tables>(&bytes)
+                .map_err(|e| e.to_string())
+                .and_then(Tables::migrate)
+                .map_err(|e| DatabaseError::Io(format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Tables::default(),
+            Err(e) => return Err(DatabaseError::Io(format!("{}: {}", path.display(), e))),
+        };
         
-        self.user_ids.insert(username.to_string(), user_id);
+        let usernames = tables.users.iter().map(|user| (user.username.to_lowercase(), user.id)).collect();
+        let file = Arc::new(DatabaseFile { path, written: Mutex::new(0) });
+        Ok(Self { file: Some(file), tables, usernames, generation: 0, last_write: None })
+    }
+    
+    pub fn add_user(&mut self, user: UserRow) -> Result<(), DatabaseError> {
+        let key = user.username.to_lowercase();
+        if self.usernames.contains_key(&key) {
+            return Err(DatabaseError::UsernameTaken(user.username));
+        }
         
-        true
+        self.usernames.insert(key, user.id);
+        self.tables.users.push(user);
+        self.save()
     }
     
-    pub fn get_user(&self, username: &str) -> Option<&Credentials> {
-        self.users.get(username)
+    // The user with exactly this username
+    pub fn get_user(&self, username: &str) -> Option<&UserRow> {
+        let id = self.get_user_id(username)?;
+        self.tables.users.iter().find(|user| user.id == id && user.username == username)
     }
     
     pub fn get_user_id(&self, username: &str) -> Option<Uuid> {
-        self.user_ids.get(username).copied()
+        self.usernames.get(&username.to_lowercase()).copied()
+    }
+    
+    pub fn user_by_id(&self, id: Uuid) -> Option<&UserRow> {
+        self.tables.users.iter().find(|user| user.id == id)
+    }
+    
+    // Change a user's password hash or TOTP secret. Not the username, which `usernames` indexes.
+    pub fn update_user(&mut self, id: Uuid, update: impl FnOnce(&mut UserRow)) -> Result<(), DatabaseError> {
+        let Some(user) = self.tables.users.iter_mut().find(|user| user.id == id) else {
+            return Err(DatabaseError::Io(format!("No user {}", id)));
+        };
+        
+        update(user);
+        self.save()
+    }
+    
+    pub fn users(&self) -> &[UserRow] {
+        &self.tables.users
+    }
+    
+    pub fn channels(&self) -> &[ChannelRow] {
+        &self.tables.channels
+    }
+    
+    // Make the channels table match `channels`, writing it out if anything changed
+    pub fn save_channels<'a>(&mut self, channels: impl IntoIterator<Item = &'a Channel>) -> Result<(), DatabaseError> {
+        let mut rows: Vec<ChannelRow> = channels.into_iter().map(ChannelRow::from_channel).collect();
+        rows.sort_by_key(|row| row.id);
+        
+        if rows == self.tables.channels {
+            return Ok(());
+        }
+        self.tables.channels = rows;
+        self.save()
+    }
+    
+    // Write the tables as they are now. Needs a Tokio runtime when the database is a file.
+    fn save(&mut self) -> Result<(), DatabaseError> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        
+        let json = serde_json::to_vec_pretty(&self.tables).map_err(|e| DatabaseError::Io(e.to_string()))?;
+        self.generation += 1;
+        let (file, generation) = (Arc::clone(file), self.generation);
+        self.last_write = Some(tokio::task::spawn_blocking(move || file.write(generation, json)));
+        Ok(())
+    }
+    
+    // Wait until the last change is on disk
+    #[cfg(test)]
+    pub async fn flush(&mut self) {
+        if let Some(write) = self.last_write.take() {
+            let _ = write.await;
+        }
     }
 }
 
-// Global database instance
-lazy_static::lazy_static! {
-    static ref DB: Arc<Mutex<Database>> = Arc::new(Mutex::new(Database::new()));
+// Written beside the file and renamed over it, so a crash never leaves half a database
+fn write_atomically(path: &Path, json: &[u8]) -> io::Result<()> {
+    let temp = temp_path(path);
+    let mut file = File::create(&temp)?;
+    file.write_all(json)?;
+    // On disk before it replaces the old file, or a crash could leave an empty one
+    file.sync_all()?;
+    std::fs::rename(&temp, path)
 }
 
-pub fn get_db() -> Arc<Mutex<Database>> {
-    DB.clone()
-}
\ No newline at end of file
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use open_reverb_common::models::AudioQuality;
+    
+    fn user(username: &str) -> UserRow {
+        UserRow { id: Uuid::new_v4(), username: username.to_string(), password_hash: None, totp_secret: None, created_at: 1_000 }
+    }
+    
+    fn channel(name: &str, parent_id: Option<Uuid>) -> Channel {
+        Channel {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            description: None,
+            parent_id,
+            members: Vec::new(),
+            topic: None,
+            e2e_encrypted: false,
+            channel_type: ChannelType::Text,
+            media_bandwidth_limit_kbps: 0,
+            lobby_mode: false,
+            audio_quality: AudioQuality::default(),
+            override_retention_days: None,
+            permanent: false,
+            idle_timeout_secs: None,
+        }
+    }
+    
+    #[test]
+    fn usernames_are_unique_ignoring_case() {
+        let mut db = Database::in_memory();
+        let alice = user("Alice");
+        db.add_user(alice.clone()).unwrap();
+        
+        assert!(matches!(db.add_user(user("alice")), Err(DatabaseError::UsernameTaken(name)) if name == "alice"));
+        assert_eq!(db.get_user_id("ALICE"), Some(alice.id));
+        assert_eq!(db.get_user("Alice"), Some(&alice));
+        assert_eq!(db.get_user("alice"), None);
+        assert_eq!(db.users().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn users_and_channels_survive_reopening() {
+        let dir = std::env::temp_dir().join(format!("open-reverb-db-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.db");
+        
+        let alice = user("alice");
+        let parent = channel("Games", None);
+        let child = channel("Chess", Some(parent.id));
+        {
+            let mut db = Database::open(&path).unwrap();
+            db.add_user(alice.clone()).unwrap();
+            db.update_user(alice.id, |user| user.totp_secret = Some("JBSWY3DPEHPK3PXP".to_string())).unwrap();
+            db.save_channels([&parent, &child]).unwrap();
+            db.flush().await;
+        }
+        
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(db.user_by_id(alice.id).unwrap().totp_secret.as_deref(), Some("JBSWY3DPEHPK3PXP"));
+        assert_eq!(db.users().len(), 1);
+        assert_eq!(db.channels().len(), 2);
+        let loaded = db.channels().iter().find(|row| row.id == child.id).unwrap();
+        assert_eq!((loaded.name.as_str(), loaded.parent_id, loaded.channel_type), ("Chess", Some(parent.id), ChannelType::Text));
+        
+        db.save_channels([&parent]).unwrap();
+        db.flush().await;
+        assert_eq!(Database::open(&path).unwrap().channels(), &[ChannelRow::from_channel(&parent)]);
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn the_latest_write_wins() {
+        let dir = std::env::temp_dir().join(format!("open-reverb-db-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.db");
+        
+        let mut db = Database::open(&path).unwrap();
+        let names: Vec<String> = (0..20).map(|i| format!("user{}", i)).collect();
+        for name in &names {
+            db.add_user(user(name)).unwrap();
+        }
+        db.flush().await;
+        
+        let reopened = Database::open(&path).unwrap();
+        let usernames: Vec<&String> = reopened.users().iter().map(|user| &user.username).collect();
+        assert_eq!(usernames, names.iter().collect::<Vec<_>>());
+        
+        // A stale write arriving late leaves the file alone
+        db.file.as_ref().unwrap().write(1, b"{}".to_vec());
+        assert_eq!(Database::open(&path).unwrap().users().len(), 20);
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }    
+    #[tokio::test]
+    async fn files_are_migrated_and_newer_ones_refused() {
+        let dir = std::env::temp_dir().join(format!("open-reverb-db-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.db");
+        
+        // From before versioning, and before TOTP secrets
+        let alice = user("alice");
+        let unversioned = serde_json::json!({
+            "users": [{ "id": alice.id, "username": "alice", "password_hash": null, "created_at": 1_000 }],
+            "channels": [],
+        });
+        std::fs::write(&path, unversioned.to_string()).unwrap();
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(db.user_by_id(alice.id), Some(&alice));
+        
+        // Saved at the current version
+        db.add_user(user("bob")).unwrap();
+        db.flush().await;
+        let saved: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved["version"], TABLES_VERSION);
+        assert!(!temp_path(&path).exists());
+        
+        std::fs::write(&path, serde_json::json!({ "version": TABLES_VERSION + 1, "users": [], "channels": [] }).to_string()).unwrap();
+        assert!(matches!(Database::open(&path), Err(DatabaseError::Io(message)) if message.contains("newer server")));
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+use chrono::{DateTime, SecondsFormat};
+use serde::Serialize;
+
+use open_reverb_common::models::ExportFormat;
+
+// Largest ExportData payload; bigger exports are split across several messages
+pub const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+// One exported chat message. Timestamps are RFC 3339 in UTC.
+#[derive(Debug, Serialize)]
+pub struct ExportRow {
+    pub timestamp: String,
+    pub channel: String,
+    pub username: String,
+    pub message: String,
+}
+
+impl ExportRow {
+    pub fn new(timestamp: u64, channel: &str, username: &str, message: &str) -> Self {
+        let timestamp = DateTime::from_timestamp(timestamp as i64, 0)
+            .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_default();
+        
+        Self {
+            timestamp,
+            channel: channel.to_string(),
+            username: username.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+pub fn render(rows: &[ExportRow], format: ExportFormat) -> Vec<u8> {
+    match format {
+        ExportFormat::JSON => serde_json::to_vec_pretty(rows).unwrap_or_default(),
+        ExportFormat::CSV => {
+            let mut csv = String::from("timestamp,channel,username,message\r\n");
+            for row in rows {
+                let fields = [&row.timestamp, &row.channel, &row.username, &row.message];
+                let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+                csv.push_str(&line.join(","));
+                csv.push_str("\r\n");
+            }
+            csv.into_bytes()
+        }
+    }
+}
+
+// Split an export into ExportData payloads. There's always at least one, so an empty export
+// still reaches the client.
+pub fn chunks(data: &[u8]) -> Vec<&[u8]> {
+    if data.is_empty() {
+        return vec![data];
+    }
+    data.chunks(EXPORT_CHUNK_SIZE).collect()
+}
+
+// Quote a field per RFC 4180 if it contains a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn rows() -> Vec<ExportRow> {
+        vec![
+            ExportRow::new(1_700_000_000, "general", "alice", "hello"),
+            ExportRow::new(1_700_000_060, "general", "bob", "well, \"hi\"\nthere"),
+        ]
+    }
+    
+    #[test]
+    fn json_is_an_array_of_rows() {
+        let data = render(&rows(), ExportFormat::JSON);
+        let value: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        let array = value.as_array().unwrap();
+        
+        assert_eq!(array.len(), 2);
+        assert_eq!(array[0]["timestamp"], "2023-11-14T22:13:20Z");
+        assert_eq!(array[0]["channel"], "general");
+        assert_eq!(array[0]["username"], "alice");
+        assert_eq!(array[1]["message"], "well, \"hi\"\nthere");
+    }
+    
+    #[test]
+    fn csv_has_a_header_and_quotes_fields() {
+        let data = String::from_utf8(render(&rows(), ExportFormat::CSV)).unwrap();
+        
+        assert_eq!(
+            data,
+            "timestamp,channel,username,message\r\n\
+             2023-11-14T22:13:20Z,general,alice,hello\r\n\
+             2023-11-14T22:14:20Z,general,bob,\"well, \"\"hi\"\"\nthere\"\r\n"
+        );
+    }
+    
+    #[test]
+    fn empty_export_is_one_chunk() {
+        assert_eq!(chunks(&[]).len(), 1);
+        assert_eq!(chunks(&vec![0; EXPORT_CHUNK_SIZE + 1]).len(), 2);
+    }
+}
@@ -0,0 +1,386 @@
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+use open_reverb_common::models::{User, UserStatus};
+use open_reverb_common::protocol::Message;
+use crate::config::FederatedServer;
+use crate::tls;
+
+// How often each server tells its peers who is online on it
+pub const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+
+// A peer's users are forgotten once it has missed this many updates, e.g. because it's down
+const MISSED_UPDATES: u32 = 3;
+
+// Signed messages are refused once this old, or this far ahead of our clock. Within it, each
+// signature is only accepted once.
+const MAX_MESSAGE_AGE_SECS: u64 = 300;
+
+// Users online on federated servers, as last reported by each
+pub struct Federation {
+    peers: Vec<FederatedServer>,
+    // Whether direct messages may cross to and from peers
+    allow_dm: bool,
+    // By peer URL
+    presence: HashMap<String, PeerPresence>,
+    // Signatures accepted in the last MAX_MESSAGE_AGE_SECS, with their timestamps
+    seen_signatures: HashMap<String, u64>,
+}
+
+struct PeerPresence {
+    users: Vec<User>,
+    updated_at: Instant,
+}
+
+// Federated users who came online or went offline with an update
+#[derive(Debug, Default)]
+pub struct PresenceChange {
+    pub joined: Vec<User>,
+    pub left: Vec<Uuid>,
+}
+
+impl Federation {
+    pub fn new(peers: Vec<FederatedServer>, allow_dm: bool) -> Self {
+        Self {
+            peers,
+            allow_dm,
+            presence: HashMap::new(),
+            seen_signatures: HashMap::new(),
+        }
+    }
+    
+    pub fn peers(&self) -> &[FederatedServer] {
+        &self.peers
+    }
+    
+    pub fn allows_dm(&self) -> bool {
+        self.allow_dm
+    }
+    
+    pub fn peer(&self, url: &str) -> Option<&FederatedServer> {
+        self.peers.iter().find(|peer| peer.url == url)
+    }
+    
+    // The peer that signed `message` with the secret it shares with us, if any. Stale and
+    // replayed messages aren't accepted from anyone.
+    pub fn authenticate(&mut self, message: &Message, now: u64) -> Option<&FederatedServer> {
+        let (timestamp, signature) = match message {
+            Message::FederationPresence { timestamp, signature, .. } | Message::FederationRelay { timestamp, signature, .. } => {
+                (*timestamp, signature)
+            }
+            _ => return None,
+        };
+        if now.abs_diff(timestamp) > MAX_MESSAGE_AGE_SECS || self.seen_signatures.contains_key(signature) {
+            return None;
+        }
+        
+        let signed = signed_bytes(message)?;
+        let tag = HEXLOWER.decode(signature.as_bytes()).ok()?;
+        // verify_slice compares in constant time
+        let peer = self.peers
+            .iter()
+            .filter(|peer| !peer.shared_secret.is_empty())
+            .find(|peer| mac(&peer.shared_secret, &signed).verify_slice(&tag).is_ok())?;
+        
+        self.seen_signatures.retain(|_, seen| now.abs_diff(*seen) <= MAX_MESSAGE_AGE_SECS);
+        self.seen_signatures.insert(signature.clone(), timestamp);
+        Some(peer)
+    }
+    
+    // Replace a peer's online users. Users it already reported keep their IDs.
+    pub fn update_presence(&mut self, peer_url: &str, usernames: Vec<String>, now: Instant) -> PresenceChange {
+        let previous = self.presence.remove(peer_url).map(|p| p.users).unwrap_or_default();
+        let mut change = PresenceChange::default();
+        
+        let mut users = Vec::with_capacity(usernames.len());
+        for username in usernames {
+            if users.iter().any(|u: &User| u.username == username) {
+                continue;
+            }
+            
+            match previous.iter().find(|u| u.username == username) {
+                Some(user) => users.push(user.clone()),
+                None => {
+                    let user = User {
+                        id: Uuid::new_v4(),
+                        username,
+                        status: UserStatus::Online,
+                        joined_at: 0,
+                        federated_from: Some(peer_url.to_string()),
+                    };
+                    change.joined.push(user.clone());
+                    users.push(user);
+                }
+            }
+        }
+        
+        change.left = previous
+            .iter()
+            .filter(|old| !users.iter().any(|u| u.id == old.id))
+            .map(|old| old.id)
+            .collect();
+        
+        self.presence.insert(peer_url.to_string(), PeerPresence { users, updated_at: now });
+        change
+    }
+    
+    // Forget the users of peers that have stopped sending updates, returning their IDs
+    pub fn expire(&mut self, now: Instant) -> Vec<Uuid> {
+        let max_age = PRESENCE_INTERVAL * MISSED_UPDATES;
+        let mut left = Vec::new();
+        
+        self.presence.retain(|_, presence| {
+            let fresh = now.saturating_duration_since(presence.updated_at) < max_age;
+            if !fresh {
+                left.extend(presence.users.iter().map(|u| u.id));
+            }
+            fresh
+        });
+        
+        left
+    }
+    
+    pub fn users(&self) -> impl Iterator<Item = &User> {
+        self.presence.values().flat_map(|p| p.users.iter())
+    }
+    
+    pub fn user(&self, user_id: Uuid) -> Option<&User> {
+        self.users().find(|u| u.id == user_id)
+    }
+    
+    // A user a peer has reported online, by username
+    pub fn find(&self, peer_url: &str, username: &str) -> Option<&User> {
+        self.presence.get(peer_url)?.users.iter().find(|u| u.username == username)
+    }
+}
+
+pub fn presence(peer: &FederatedServer, usernames: Vec<String>, now: u64) -> Message {
+    sign(peer, Message::FederationPresence { usernames, timestamp: now, signature: String::new() })
+}
+
+pub fn relay(peer: &FederatedServer, from_username: String, target_username: String, message: String, now: u64) -> Message {
+    sign(peer, Message::FederationRelay { from_username, target_username, message, timestamp: now, signature: String::new() })
+}
+
+fn sign(peer: &FederatedServer, mut message: Message) -> Message {
+    let tag = signed_bytes(&message).map(|signed| HEXLOWER.encode(&mac(&peer.shared_secret, &signed).finalize().into_bytes()));
+    if let (Message::FederationPresence { signature, .. } | Message::FederationRelay { signature, .. }, Some(tag)) = (&mut message, tag) {
+        *signature = tag;
+    }
+    message
+}
+
+// Everything in a federation message but its signature, tagged with its kind so one can't be
+// passed off as the other
+fn signed_bytes(message: &Message) -> Option<Vec<u8>> {
+    match message {
+        Message::FederationPresence { usernames, timestamp, .. } => serde_json::to_vec(&("presence", timestamp, usernames)).ok(),
+        Message::FederationRelay { from_username, target_username, message, timestamp, .. } => {
+            serde_json::to_vec(&("relay", timestamp, from_username, target_username, message)).ok()
+        }
+        _ => None,
+    }
+}
+
+fn mac(secret: &str, signed: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(signed);
+    mac
+}
+
+// Send one message to a peer over a new connection, framed the way clients frame theirs
+pub async fn send_to_peer(peer: &FederatedServer, message: &Message) -> io::Result<()> {
+    let bytes = serde_json::to_vec(message)?;
+    let mut frame = (bytes.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&bytes);
+    
+    let mut socket = TcpStream::connect(&peer.url).await?;
+    if peer.plaintext {
+        socket.write_all(&frame).await?;
+        socket.flush().await?;
+        return socket.shutdown().await;
+    }
+    
+    let config = tls::client_config(peer.ca_cert_path.as_deref()).map_err(io::Error::other)?;
+    let host = host(&peer.url).to_string();
+    let (mut local, remote) = tokio::io::duplex(64 * 1024);
+    let session = tokio::spawn(async move { tls::connect(socket, config, &host, remote).await });
+    
+    // Closing our end ends the session once the frame is through
+    local.write_all(&frame).await?;
+    local.shutdown().await?;
+    session.await.map_err(io::Error::other)?
+}
+
+// The host part of "host:port" or "[v6 address]:port", for checking the peer's certificate
+fn host(url: &str) -> &str {
+    let host = url.rsplit_once(':').map_or(url, |(host, _)| host);
+    host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncRead, AsyncReadExt};
+    use tokio::net::TcpListener;
+    
+    const PEER: &str = "peer.example.com:8080";
+    
+    fn peer() -> FederatedServer {
+        FederatedServer { url: PEER.to_string(), shared_secret: "s3cret".to_string(), ca_cert_path: None, plaintext: false }
+    }
+    
+    fn names(users: &[User]) -> Vec<&str> {
+        users.iter().map(|u| u.username.as_str()).collect()
+    }
+    
+    #[test]
+    fn presence_updates_report_who_came_and_went() {
+        let mut federation = Federation::new(vec![peer()], false);
+        let now = Instant::now();
+        
+        let change = federation.update_presence(PEER, vec!["alice".to_string(), "bob".to_string()], now);
+        assert_eq!(names(&change.joined), vec!["alice", "bob"]);
+        assert!(change.joined.iter().all(|u| u.federated_from.as_deref() == Some(PEER)));
+        let alice = federation.find(PEER, "alice").unwrap().id;
+        let bob = federation.find(PEER, "bob").unwrap().id;
+        
+        let change = federation.update_presence(PEER, vec!["alice".to_string(), "carol".to_string()], now);
+        assert_eq!(names(&change.joined), vec!["carol"]);
+        assert_eq!(change.left, vec![bob]);
+        assert_eq!(federation.find(PEER, "alice").unwrap().id, alice);
+        assert_eq!(federation.users().count(), 2);
+    }
+    
+    #[test]
+    fn silent_peers_are_forgotten() {
+        let mut federation = Federation::new(vec![peer()], false);
+        let start = Instant::now();
+        federation.update_presence(PEER, vec!["alice".to_string()], start);
+        let alice = federation.find(PEER, "alice").unwrap().id;
+        
+        assert!(federation.expire(start + PRESENCE_INTERVAL).is_empty());
+        assert_eq!(federation.expire(start + PRESENCE_INTERVAL * MISSED_UPDATES), vec![alice]);
+        assert!(federation.user(alice).is_none());
+    }
+    
+    #[test]
+    fn only_messages_signed_with_a_configured_secret_authenticate() {
+        let unsecured = FederatedServer { url: "open.example.com:8080".to_string(), shared_secret: String::new(), ..peer() };
+        let mut federation = Federation::new(vec![peer(), unsecured.clone()], false);
+        let now = 1_700_000_000;
+        
+        let presence = super::presence(&peer(), vec!["alice".to_string()], now);
+        assert!(!serde_json::to_string(&presence).unwrap().contains("s3cret"));
+        assert_eq!(federation.authenticate(&presence, now).map(|p| p.url.as_str()), Some(PEER));
+        
+        let guess = FederatedServer { shared_secret: "guess".to_string(), ..peer() };
+        assert!(federation.authenticate(&super::presence(&guess, vec!["alice".to_string()], now), now).is_none());
+        assert!(federation.authenticate(&super::presence(&unsecured, vec!["alice".to_string()], now), now).is_none());
+        
+        // Changing anything but the signature breaks it
+        let relay = super::relay(&peer(), "alice".to_string(), "bob".to_string(), "hi".to_string(), now);
+        let Message::FederationRelay { signature, .. } = &relay else { unreachable!() };
+        let tampered = Message::FederationRelay {
+            from_username: "alice".to_string(),
+            target_username: "carol".to_string(),
+            message: "hi".to_string(),
+            timestamp: now,
+            signature: signature.clone(),
+        };
+        assert!(federation.authenticate(&tampered, now).is_none());
+        assert!(federation.authenticate(&relay, now).is_some());
+    }
+    
+    #[test]
+    fn stale_or_replayed_messages_are_refused() {
+        let mut federation = Federation::new(vec![peer()], false);
+        let now = 1_700_000_000;
+        
+        let old = super::presence(&peer(), vec!["alice".to_string()], now - MAX_MESSAGE_AGE_SECS - 1);
+        assert!(federation.authenticate(&old, now).is_none());
+        let early = super::presence(&peer(), vec!["alice".to_string()], now + MAX_MESSAGE_AGE_SECS + 1);
+        assert!(federation.authenticate(&early, now).is_none());
+        
+        let relay = super::relay(&peer(), "alice".to_string(), "bob".to_string(), "hi".to_string(), now);
+        assert!(federation.authenticate(&relay, now).is_some());
+        assert!(federation.authenticate(&relay, now + 1).is_none());
+    }
+    
+    // A mock peer that reads one message
+    async fn receive_one<S: AsyncRead + Unpin>(mut socket: S) -> Message {
+        let mut len = [0u8; 4];
+        socket.read_exact(&mut len).await.unwrap();
+        let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+        socket.read_exact(&mut buf).await.unwrap();
+        serde_json::from_slice::<Message>(&buf).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn messages_reach_a_plaintext_peer_framed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = FederatedServer { url: listener.local_addr().unwrap().to_string(), plaintext: true, ..peer() };
+        
+        let received = tokio::spawn(async move { receive_one(listener.accept().await.unwrap().0).await });
+        
+        let presence = super::presence(&peer, vec!["alice".to_string()], 1_700_000_000);
+        send_to_peer(&peer, &presence).await.unwrap();
+        
+        match received.await.unwrap() {
+            Message::FederationPresence { usernames, timestamp, .. } => {
+                assert_eq!(usernames, vec!["alice".to_string()]);
+                assert_eq!(timestamp, 1_700_000_000);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn messages_reach_a_peer_over_tls() {
+        let listener = TcpListener::bind("localhost:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let peer = FederatedServer {
+            url: format!("localhost:{}", port),
+            ca_cert_path: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/ca.pem").to_string()),
+            ..peer()
+        };
+        
+        // A peer with the test certificate for localhost
+        let received = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let config = tls::server_config(include_str!("../testdata/cert.pem"), include_str!("../testdata/key.pem")).unwrap();
+            let (local, remote) = tokio::io::duplex(1024);
+            tokio::spawn(tls::accept(socket, config, remote));
+            receive_one(local).await
+        });
+        
+        let relay = super::relay(&peer, "alice".to_string(), "bob".to_string(), "hi".to_string(), 1_700_000_000);
+        send_to_peer(&peer, &relay).await.unwrap();
+        assert!(matches!(received.await.unwrap(), Message::FederationRelay { message, .. } if message == "hi"));
+        
+        // A certificate the CA didn't sign is refused
+        let listener = TcpListener::bind("localhost:0").await.unwrap();
+        let untrusted = FederatedServer { url: format!("localhost:{}", listener.local_addr().unwrap().port()), ca_cert_path: None, ..peer.clone() };
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let config = tls::server_config(include_str!("../testdata/cert.pem"), include_str!("../testdata/key.pem")).unwrap();
+            let (_local, remote) = tokio::io::duplex(1024);
+            let _ = tls::accept(socket, config, remote).await;
+        });
+        assert!(send_to_peer(&untrusted, &relay).await.is_err());
+    }
+    
+    #[test]
+    fn hosts_are_taken_from_peer_urls() {
+        assert_eq!(host("chat.example.com:8080"), "chat.example.com");
+        assert_eq!(host("[::1]:8080"), "::1");
+        assert_eq!(host("chat.example.com"), "chat.example.com");
+    }
+}
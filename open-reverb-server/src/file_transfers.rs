@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+struct Transfer {
+    from: Uuid,
+    to: Uuid,
+    accepted: bool,
+}
+
+// Files being sent between users, by transfer ID. Only the offer names the two users; everything
+// after it is routed by the ID, and only ever to the other side.
+pub struct FileTransfers {
+    transfers: HashMap<Uuid, Transfer>,
+}
+
+impl FileTransfers {
+    pub fn new() -> Self {
+        Self { transfers: HashMap::new() }
+    }
+    
+    // Returns false when the ID is already in use
+    pub fn offer(&mut self, id: Uuid, from: Uuid, to: Uuid) -> bool {
+        if self.transfers.contains_key(&id) {
+            return false;
+        }
+        
+        self.transfers.insert(id, Transfer { from, to, accepted: false });
+        true
+    }
+    
+    // Each of these returns who to relay the message to, or None if `user_id` isn't the one who
+    // may send it
+    
+    // Only the recipient accepts
+    pub fn accept(&mut self, id: Uuid, user_id: Uuid) -> Option<Uuid> {
+        let transfer = self.transfers.get_mut(&id).filter(|t| t.to == user_id)?;
+        transfer.accepted = true;
+        Some(transfer.from)
+    }
+    
+    // Only the sender sends, once the recipient has accepted
+    pub fn chunk(&self, id: Uuid, user_id: Uuid) -> Option<Uuid> {
+        self.transfers.get(&id).filter(|t| t.from == user_id && t.accepted).map(|t| t.to)
+    }
+    
+    pub fn complete(&mut self, id: Uuid, user_id: Uuid) -> Option<Uuid> {
+        let to = self.chunk(id, user_id)?;
+        self.transfers.remove(&id);
+        Some(to)
+    }
+    
+    // Either side can give up
+    pub fn reject(&mut self, id: Uuid, user_id: Uuid) -> Option<Uuid> {
+        let transfer = self.transfers.get(&id)?;
+        let other = match user_id {
+            user_id if user_id == transfer.from => transfer.to,
+            user_id if user_id == transfer.to => transfer.from,
+            _ => return None,
+        };
+        self.transfers.remove(&id);
+        Some(other)
+    }
+    
+    // Transfers a user who went offline was part of, with the user on the other side of each
+    pub fn remove_user(&mut self, user_id: Uuid) -> Vec<(Uuid, Uuid)> {
+        let mut removed = Vec::new();
+        self.transfers.retain(|id, transfer| {
+            let other = match user_id {
+                user_id if user_id == transfer.from => transfer.to,
+                user_id if user_id == transfer.to => transfer.from,
+                _ => return true,
+            };
+            removed.push((*id, other));
+            false
+        });
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn relayed_only_between_the_two_users() {
+        let mut transfers = FileTransfers::new();
+        let (alice, bob, mallory) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let id = Uuid::from_u128(10);
+        
+        assert!(transfers.offer(id, alice, bob));
+        assert!(!transfers.offer(id, mallory, bob));
+        
+        // Nothing is sent before Bob accepts, and only Bob can accept
+        assert_eq!(transfers.chunk(id, alice), None);
+        assert_eq!(transfers.accept(id, mallory), None);
+        assert_eq!(transfers.accept(id, bob), Some(alice));
+        
+        assert_eq!(transfers.chunk(id, alice), Some(bob));
+        assert_eq!(transfers.chunk(id, bob), None);
+        assert_eq!(transfers.reject(id, mallory), None);
+        assert_eq!(transfers.complete(id, alice), Some(bob));
+        assert_eq!(transfers.chunk(id, alice), None);
+    }
+    
+    #[test]
+    fn ended_when_either_side_leaves() {
+        let mut transfers = FileTransfers::new();
+        let (alice, bob, carol) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let (first, second, third) = (Uuid::from_u128(10), Uuid::from_u128(11), Uuid::from_u128(12));
+        transfers.offer(first, alice, bob);
+        transfers.offer(second, carol, alice);
+        transfers.offer(third, bob, carol);
+        
+        let mut removed = transfers.remove_user(alice);
+        removed.sort();
+        assert_eq!(removed, vec![(first, bob), (second, carol)]);
+        assert_eq!(transfers.reject(third, carol), Some(bob));
+    }
+}
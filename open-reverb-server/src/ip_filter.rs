@@ -0,0 +1,141 @@
+use std::net::IpAddr;
+use ipnet::IpNet;
+
+// Which addresses may connect, from CIDR ranges or exact addresses in the config
+pub struct IpFilter {
+    // Only these may connect, unless it's empty
+    allowlist: Vec<IpNet>,
+    blocklist: Vec<IpNet>,
+}
+
+impl IpFilter {
+    // Entries that don't parse are left out, with an error for the log
+    pub fn new(allowlist: &[String], blocklist: &[String]) -> (Self, Vec<String>) {
+        let mut errors = Vec::new();
+        let mut parse_all = |entries: &[String]| -> Vec<IpNet> {
+            entries
+                .iter()
+                .filter_map(|entry| parse_entry(entry).map_err(|e| errors.push(e)).ok())
+                .collect()
+        };
+        
+        let filter = Self {
+            allowlist: parse_all(allowlist),
+            blocklist: parse_all(blocklist),
+        };
+        (filter, errors)
+    }
+    
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !is_blocked(ip, &self.blocklist) && (self.allowlist.is_empty() || is_allowed(ip, &self.allowlist))
+    }
+    
+    // Allow another range until the server restarts
+    pub fn allow(&mut self, entry: &str) -> Result<(), String> {
+        let net = parse_entry(entry)?;
+        if !self.allowlist.contains(&net) {
+            self.allowlist.push(net);
+        }
+        Ok(())
+    }
+}
+
+// A CIDR range such as "192.168.1.0/24" or "2001:db8::/32", or a single address
+pub fn parse_entry(entry: &str) -> Result<IpNet, String> {
+    let entry = entry.trim();
+    entry
+        .parse::<IpNet>()
+        .map(|net| net.trunc())
+        .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("\"{}\" is not an IP address or CIDR range", entry))
+}
+
+pub fn is_blocked(ip: IpAddr, blocklist: &[IpNet]) -> bool {
+    contains(blocklist, ip)
+}
+
+pub fn is_allowed(ip: IpAddr, allowlist: &[IpNet]) -> bool {
+    contains(allowlist, ip)
+}
+
+fn contains(nets: &[IpNet], ip: IpAddr) -> bool {
+    // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+    let ip = ip.to_canonical();
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+    
+    fn nets(entries: &[&str]) -> Vec<IpNet> {
+        entries.iter().map(|e| parse_entry(e).unwrap()).collect()
+    }
+    
+    #[test]
+    fn matches_ipv4_subnet_boundaries() {
+        let list = nets(&["192.168.1.0/24", "10.0.0.7"]);
+        
+        assert!(is_blocked(ip("192.168.1.0"), &list));
+        assert!(is_blocked(ip("192.168.1.255"), &list));
+        assert!(!is_blocked(ip("192.168.0.255"), &list));
+        assert!(!is_blocked(ip("192.168.2.0"), &list));
+        assert!(is_blocked(ip("10.0.0.7"), &list));
+        assert!(!is_blocked(ip("10.0.0.8"), &list));
+        // Mapped addresses from a dual-stack listener
+        assert!(is_blocked(ip("::ffff:192.168.1.20"), &list));
+    }
+    
+    #[test]
+    fn matches_ipv6_ranges() {
+        let list = nets(&["2001:db8::/32", "::1"]);
+        
+        assert!(is_allowed(ip("2001:db8::"), &list));
+        assert!(is_allowed(ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"), &list));
+        assert!(!is_allowed(ip("2001:db9::"), &list));
+        assert!(is_allowed(ip("::1"), &list));
+        assert!(!is_allowed(ip("127.0.0.1"), &list));
+    }
+    
+    #[test]
+    fn blocklist_wins_over_allowlist() {
+        let allow = ["10.0.0.0/8".to_string()];
+        let block = ["10.1.0.0/16".to_string()];
+        let (mut filter, errors) = IpFilter::new(&allow, &block);
+        
+        assert!(errors.is_empty());
+        assert!(filter.permits(ip("10.2.3.4")));
+        assert!(!filter.permits(ip("10.1.3.4")));
+        assert!(!filter.permits(ip("172.16.0.1")));
+        
+        filter.allow("172.16.0.0/12").unwrap();
+        assert!(filter.permits(ip("172.31.255.255")));
+        assert!(!filter.permits(ip("172.32.0.0")));
+    }
+    
+    #[test]
+    fn empty_allowlist_allows_everyone_not_blocked() {
+        let (filter, _) = IpFilter::new(&[], &["203.0.113.9".to_string()]);
+        
+        assert!(filter.permits(ip("203.0.113.8")));
+        assert!(!filter.permits(ip("203.0.113.9")));
+    }
+    
+    #[test]
+    fn reports_entries_that_dont_parse() {
+        let entries = ["10.0.0.0/8".to_string(), "10.0.0.0/33".to_string(), "example.com".to_string()];
+        let (filter, errors) = IpFilter::new(&entries, &[]);
+        
+        assert_eq!(errors, vec![
+            "\"10.0.0.0/33\" is not an IP address or CIDR range".to_string(),
+            "\"example.com\" is not an IP address or CIDR range".to_string(),
+        ]);
+        assert!(filter.permits(ip("10.9.9.9")));
+        // Host bits in a range are ignored
+        assert_eq!(parse_entry("192.168.1.77/24").unwrap(), parse_entry("192.168.1.0/24").unwrap());
+    }
+}
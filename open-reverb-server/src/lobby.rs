@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Users waiting for an admin to let them into a channel in lobby mode. A user waits for one
+// channel at a time.
+pub struct Lobby {
+    // Channel each waiting user asked to join, by user
+    pending_members: HashMap<Uuid, Uuid>,
+}
+
+impl Lobby {
+    pub fn new() -> Self {
+        Self {
+            pending_members: HashMap::new(),
+        }
+    }
+    
+    // Asking for another channel replaces the earlier request
+    pub fn wait(&mut self, user_id: Uuid, channel_id: Uuid) {
+        self.pending_members.insert(user_id, channel_id);
+    }
+    
+    // Stop a user waiting for the channel, once they've been approved or rejected. Returns
+    // false if they weren't waiting for it.
+    pub fn resolve(&mut self, user_id: Uuid, channel_id: Uuid) -> bool {
+        if self.pending_members.get(&user_id) != Some(&channel_id) {
+            return false;
+        }
+        
+        self.pending_members.remove(&user_id);
+        true
+    }
+    
+    // The user gave up waiting, e.g. by disconnecting
+    pub fn remove_user(&mut self, user_id: Uuid) {
+        self.pending_members.remove(&user_id);
+    }
+    
+    pub fn is_waiting(&self, user_id: Uuid, channel_id: Uuid) -> bool {
+        self.pending_members.get(&user_id) == Some(&channel_id)
+    }
+    
+    // Channels somebody is waiting to get into
+    pub fn waiting_channels(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.pending_members.values().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn approved_user_stops_waiting() {
+        let mut lobby = Lobby::new();
+        let (user, channel) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        lobby.wait(user, channel);
+        assert!(lobby.is_waiting(user, channel));
+        
+        assert!(lobby.resolve(user, channel));
+        assert!(!lobby.is_waiting(user, channel));
+        // Approving twice does nothing the second time
+        assert!(!lobby.resolve(user, channel));
+    }
+    
+    #[test]
+    fn user_can_only_be_resolved_for_the_channel_they_asked_for() {
+        let mut lobby = Lobby::new();
+        let (user, channel, other_channel) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        
+        lobby.wait(user, channel);
+        
+        assert!(!lobby.resolve(user, other_channel));
+        assert!(lobby.is_waiting(user, channel));
+    }
+    
+    #[test]
+    fn asking_for_another_channel_replaces_the_request() {
+        let mut lobby = Lobby::new();
+        let (user, channel, other_channel) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        
+        lobby.wait(user, channel);
+        lobby.wait(user, other_channel);
+        
+        assert!(!lobby.is_waiting(user, channel));
+        assert!(lobby.resolve(user, other_channel));
+    }
+    
+    #[test]
+    fn disconnected_user_cannot_be_approved() {
+        let mut lobby = Lobby::new();
+        let (user, channel) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        lobby.wait(user, channel);
+        lobby.remove_user(user);
+        
+        assert!(!lobby.resolve(user, channel));
+    }
+}
@@ -1,460 +1,4955 @@
-use std::collections::HashMap;
+mod announcements;
+mod audit;
+mod auth;
+mod channel_config;
+mod channel_idle;
+mod channel_limits;
+mod config;
+mod database;
+mod export;
+mod federation;
+mod file_transfers;
+mod ip_filter;
+mod lobby;
+mod metrics;
+mod network_sim;
+mod pending_dms;
+mod recording;
+mod retention;
+mod search;
+mod session_store;
+mod session_token;
+mod speaking;
+mod tls;
+mod totp;
+mod transcription;
+mod welcome;
+
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::num::NonZeroUsize;
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use data_encoding::HEXLOWER;
+use futures_util::{SinkExt, StreamExt};
+use lru::LruCache;
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
-use tracing::{error, info, Level};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 use uuid::Uuid;
 
-use open_reverb_common::models::{Channel, Server, User, UserStatus};
+use announcements::{AnnouncementScheduler, MAX_ANNOUNCEMENT_LENGTH};
+use audit::ConnectionAuditLog;
+use auth::PasswordCheck;
+use channel_idle::ChannelActivity;
+use channel_limits::ChannelCreationLimiter;
+use database::{Database, DatabaseError, UserRow};
+use federation::Federation;
+use file_transfers::FileTransfers;
+use ip_filter::IpFilter;
+use lobby::Lobby;
+use metrics::Counter;
+use open_reverb_common::error::OpenReverbError;
+use open_reverb_common::features::Features;
+use open_reverb_common::models::{AudioQuality, Channel, ChannelStats, ChannelType, DisconnectReason, ExportFormat, SearchResult, Server, ServerStatistics, User, UserStatus, VideoCodec, VideoQuality, validate_username, SCHEMA_VERSION};
 use open_reverb_common::protocol::Message;
+use network_sim::ServerNetworkSimulator;
+use pending_dms::PendingDirectMessages;
+use recording::Recorder;
+use search::MessageArchive;
+use speaking::SpeakingTime;
+use session_store::{InMemorySessionStore, RedisSessionStore, SessionStore, StoredSession};
+use session_token::SessionTokens;
+use transcription::{Transcriber, WhisperBackend, VOICE_SAMPLE_RATE};
+use welcome::Welcome;
+
+// How long channel members have to consent before a recording request is refused
+const RECORDING_CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Number of recent media frames remembered per channel for deduplication
+const SEEN_SEQ_CAPACITY: usize = 256;
+
+// How often admins are sent server statistics
+const STATS_INTERVAL: Duration = Duration::from_secs(30);
+
+// Longest channel topic accepted, in characters
+const MAX_TOPIC_LENGTH: usize = 256;
+
+// Longest channel name, in characters
+const MAX_CHANNEL_NAME_LENGTH: usize = 64;
+
+// Longest chat message accepted, in characters
+const MAX_TEXT_MESSAGE_LENGTH: usize = 4096;
+
+// Chat messages sent to someone joining a channel, so they can catch up
+const RECENT_MESSAGES_ON_JOIN: usize = 200;
+
+// Largest message accepted, in bytes. Well above the codec's default of 8 MiB, since video
+// frames are sent as JSON arrays of bytes.
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+// Messages waiting to be written to a client before the broadcasts for it wait too
+const OUTGOING_QUEUE_LEN: usize = 64;
+
+// How long a client gets to take its last messages once its connection is closing
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a TLS session gets to send what's left once its connection is done
+const TLS_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a new connection gets to send its first bytes, when they decide whether it's a WebSocket
+const FIRST_BYTES_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Voice and video frames a slow session can fall behind by in a channel before it skips ahead
+const CHANNEL_MEDIA_BUFFER: usize = 100;
+
+// Name clients show for the server
+const SERVER_NAME: &str = "Open Reverb Server";
+
+// Largest padding a diagnostic ping can carry, in bytes
+const MAX_DIAGNOSTIC_PAYLOAD: u32 = 64 * 1024;
+
+// Most channels sent in one ChannelList
+const MAX_CHANNEL_PAGE_SIZE: u32 = 200;
+
+// Most users returned for a user search
+const MAX_USER_SEARCH_RESULTS: usize = 100;
+
+// How often sessions are checked against the maximum session duration, and how long before
+// the end users are warned
+const SESSION_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const SESSION_EXPIRY_WARNING: Duration = Duration::from_secs(5 * 60);
+
+// How often scheduled announcements are checked for any that are due
+const ANNOUNCEMENT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Window over which channel media bandwidth limits are enforced
+const MEDIA_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// How often chat messages past their retention period are deleted
+const RETENTION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
 // Server state containing users, channels, and sessions
 struct ServerState {
     users: HashMap<Uuid, User>,
     channels: HashMap<Uuid, Channel>,
-    sessions: HashMap<String, SessionInfo>,
+    // Voice, video and screen share for each channel, only heard by sessions in it. Created when
+    // the first session subscribes.
+    channel_senders: HashMap<Uuid, broadcast::Sender<(Uuid, Message)>>,
+    // Where users and channels are kept across restarts
+    database: Database,
+    // Keyed by an ID given to each connection, as addresses are reused
+    sessions: HashMap<Uuid, SessionInfo>,
+    // Recording requests waiting on consent, keyed by the recording user
+    pending_recordings: HashMap<Uuid, PendingRecording>,
+    // Where sessions are persisted so they can be recovered by other instances
+    session_store: Arc<dyn SessionStore>,
+    // Recently forwarded media frames per channel, keyed by sender and sequence number
+    seen_seq: HashMap<Uuid, LruCache<(Uuid, u32), ()>>,
+    // Set when transcription is enabled in the config
+    transcriber: Option<Transcriber>,
+    // Set when recording is enabled in the config
+    recorder: Option<Recorder>,
+    // Media bytes forwarded per channel since startup
+    bytes_forwarded: HashMap<Uuid, Counter>,
+    // Users currently sending each kind of media
+    voice_streams: HashSet<Uuid>,
+    video_streams: HashSet<Uuid>,
+    screen_streams: HashSet<Uuid>,
+    // Codec of each user's video, announced to everyone when their video starts
+    video_codecs: HashMap<Uuid, VideoCodec>,
+    // X25519 public keys users announced for end-to-end encrypted voice
+    public_keys: HashMap<Uuid, Vec<u8>>,
+    // Media bytes accepted per channel in the current rate window
+    bytes_sent_this_second: HashMap<Uuid, u64>,
+    // Senders already told they went over a channel's limit this window, by channel
+    rate_limited: HashSet<(Uuid, Uuid)>,
+    // The last time step a TOTP code was accepted for, so it can't be reused. Secrets are in `database`.
+    last_totp_step: HashMap<Uuid, u64>,
+    // Signs the tokens clients resume a session with
+    session_tokens: SessionTokens,
+    // Every connection made since startup, for admins
+    audit_log: ConnectionAuditLog,
+    // Chat messages, for searching
+    message_archive: MessageArchive,
+    // Announcements admins have scheduled
+    announcements: AnnouncementScheduler,
+    // Users waiting to be let into channels in lobby mode
+    lobby: Lobby,
+    // Message for users logging in for the first time
+    welcome: Welcome,
+    // Direct messages waiting for users to come back online
+    pending_dms: PendingDirectMessages,
+    // Files being sent from one user to another
+    file_transfers: FileTransfers,
+    // The statistics last sent to admins, for those asking in between
+    latest_stats: Option<ServerStatistics>,
+    // Limits how fast users can create channels, and how many the server holds
+    channel_limiter: ChannelCreationLimiter,
+    // Addresses that may connect
+    ip_filter: IpFilter,
+    // Federated servers and the users online on them
+    federation: Federation,
+    // When channels were last used, to delete idle ones
+    channel_activity: ChannelActivity,
+    // Stays the same for as long as the server runs
+    server_id: Uuid,
+    // Usernames of the users treated as admins
+    admin_users: Vec<String>,
+}
+
+// A channel as asked for in a CreateChannel
+#[derive(Default)]
+struct NewChannel {
+    name: String,
+    description: Option<String>,
+    parent_id: Option<Uuid>,
+    channel_type: ChannelType,
+    media_bandwidth_limit_kbps: u32,
+    // The server's default when unset
+    audio_quality: Option<AudioQuality>,
+    permanent: bool,
+    idle_timeout_secs: Option<u64>,
 }
 
 struct SessionInfo {
     user_id: Option<Uuid>,
     channels: Vec<Uuid>,
     addr: String,
+    // Messages addressed to this session only
+    direct_tx: mpsc::UnboundedSender<Message>,
+    // Set once the password is accepted, while waiting for a TOTP code
+    pending_totp: Option<PendingTotp>,
+    // When the user logged in or last renewed, for the maximum session duration
+    session_started_at: Instant,
+    // Sent with the expiry warning; set once the user has been warned
+    renew_token: Option<String>,
+    // How long the user has spoken on this session, per channel
+    speaking_time: SpeakingTime,
+    // Send the user's own voice back to them, for echo and latency tests
+    broadcast_self: bool,
+    // Agreed on with the client's Hello; none until then
+    features: Features,
+    // Joined its channel as a ghost, so the other members weren't told
+    ghost_mode: bool,
+    // Notified whenever `channels` changes, so the connection subscribes to the right channels
+    channels_changed: Arc<Notify>,
+}
+
+struct PendingTotp {
+    user_id: Uuid,
+    // Secret sent to a user setting up TOTP; kept once they answer with a valid code
+    new_secret: Option<String>,
+    // Wrong codes so far; the login is abandoned after MAX_TOTP_ATTEMPTS
+    failed_attempts: u32,
+}
+
+// Wrong TOTP codes allowed before the password has to be sent again
+const MAX_TOTP_ATTEMPTS: u32 = 3;
+
+struct PendingRecording {
+    request_id: Uuid,
+    // Members who still need to acknowledge
+    awaiting: HashSet<Uuid>,
+    // Everyone who was notified about the request
+    members: Vec<Uuid>,
 }
 
 impl ServerState {
-    fn new() -> Self {
-        // Create a default server with some channels
-        let mut channels = HashMap::new();
-        
-        // General channel
-        let general_id = Uuid::new_v4();
-        channels.insert(general_id, Channel {
-            id: general_id,
-            name: "General".to_string(),
-            description: Some("General voice channel".to_string()),
-            parent_id: None,
-            members: Vec::new(),
-        });
+    fn new(session_store: Arc<dyn SessionStore>) -> Self {
+        let config = config::get_config();
         
-        // Gaming channel
-        let gaming_id = Uuid::new_v4();
-        channels.insert(gaming_id, Channel {
-            id: gaming_id,
-            name: "Gaming".to_string(),
-            description: Some("For gaming sessions".to_string()),
-            parent_id: None,
-            members: Vec::new(),
-        });
+        let channels = initial_channels(config);
         
         Self {
             users: HashMap::new(),
             channels,
+            channel_senders: HashMap::new(),
+            database: Database::in_memory(),
             sessions: HashMap::new(),
+            pending_recordings: HashMap::new(),
+            session_store,
+            seen_seq: HashMap::new(),
+            transcriber: config.transcription_enabled.then(|| {
+                Transcriber::new(Arc::new(WhisperBackend), config.transcription_language.clone())
+            }),
+            recorder: config.recording_enabled.then(|| Recorder::new(&config.recording_dir)),
+            bytes_forwarded: HashMap::new(),
+            voice_streams: HashSet::new(),
+            video_streams: HashSet::new(),
+            video_codecs: HashMap::new(),
+            screen_streams: HashSet::new(),
+            public_keys: HashMap::new(),
+            bytes_sent_this_second: HashMap::new(),
+            rate_limited: HashSet::new(),
+            last_totp_step: HashMap::new(),
+            session_tokens: SessionTokens::new(config.session_token_ttl_secs),
+            audit_log: ConnectionAuditLog::new(),
+            message_archive: MessageArchive::new(),
+            announcements: AnnouncementScheduler::new(),
+            lobby: Lobby::new(),
+            welcome: Welcome::new(config.welcome_message.as_deref()),
+            pending_dms: PendingDirectMessages::new(),
+            file_transfers: FileTransfers::new(),
+            latest_stats: None,
+            channel_limiter: ChannelCreationLimiter::new(config.max_channels_per_minute, config.max_channels_total),
+            ip_filter: ip_filter(config),
+            federation: Federation::new(config.federation.clone(), config.allow_federated_dm),
+            channel_activity: ChannelActivity::new(config.channel_idle_timeout_secs),
+            server_id: Uuid::new_v4(),
+            admin_users: config.admin_users.clone(),
         }
     }
     
-    // Add a new session
-    fn add_session(&mut self, addr: String) {
-        self.sessions.insert(addr.clone(), SessionInfo {
+    // Add a new session, returning its ID
+    fn add_session(&mut self, addr: String, direct_tx: mpsc::UnboundedSender<Message>) -> Uuid {
+        let session_id = Uuid::new_v4();
+        self.audit_log.connected(session_id, &addr);
+        self.sessions.insert(session_id, SessionInfo {
             user_id: None,
             channels: Vec::new(),
             addr,
+            direct_tx,
+            pending_totp: None,
+            session_started_at: Instant::now(),
+            renew_token: None,
+            speaking_time: SpeakingTime::default(),
+            broadcast_self: false,
+            features: Features::empty(),
+            ghost_mode: false,
+            channels_changed: Arc::new(Notify::new()),
         });
+        session_id
     }
     
-    // Remove a session
-    fn remove_session(&mut self, addr: &str) -> Option<SessionInfo> {
-        let session = self.sessions.remove(addr);
+    // Sender for media in a channel, if anyone is listening to it
+    fn get_channel_sender(&self, channel_id: &Uuid) -> Option<broadcast::Sender<(Uuid, Message)>> {
+        self.channel_senders.get(channel_id).cloned()
+    }
+    
+    // Subscribe to the channels the session is in and drop those it has left
+    fn sync_channel_subscriptions(&mut self, session_id: Uuid, subscriptions: &mut HashMap<Uuid, broadcast::Receiver<(Uuid, Message)>>) {
+        let channels = self.sessions.get(&session_id).map(|s| s.channels.clone()).unwrap_or_default();
         
-        // If the session had a user, mark them as offline
-        if let Some(session_info) = &session {
-            if let Some(user_id) = session_info.user_id {
-                if let Some(user) = self.users.get_mut(&user_id) {
-                    user.status = UserStatus::Offline;
-                }
+        subscriptions.retain(|channel_id, _| channels.contains(channel_id));
+        for channel_id in channels {
+            if subscriptions.contains_key(&channel_id) || !self.channels.contains_key(&channel_id) {
+                continue;
             }
+            
+            let sender = self.channel_senders
+                .entry(channel_id)
+                .or_insert_with(|| broadcast::channel(CHANNEL_MEDIA_BUFFER).0);
+            subscriptions.insert(channel_id, sender.subscribe());
         }
-        
-        session
     }
     
-    // Handle login request
-    fn handle_login(&mut self, addr: &str, username: String, _password: String) -> Message {
-        // In a real implementation, we would validate the password
-        // For this demo, we'll accept any password
-        
-        // Check if user already exists by username
-        let user_id = {
-            let user_by_name = self.users.iter().find(|(_, user)| user.username == username);
+    // Warn sessions nearing the maximum duration, and end those that have gone past it
+    fn check_session_expiry(&mut self, max_duration: Duration) {
+        for session in self.sessions.values_mut().filter(|s| s.user_id.is_some()) {
+            let remaining = max_duration.saturating_sub(session.session_started_at.elapsed());
             
-            if let Some((id, _)) = user_by_name {
-                // User exists
-                let user_id = *id;
-                // Update status to Online
-                if let Some(user) = self.users.get_mut(&user_id) {
-                    user.status = UserStatus::Online;
-                }
-                user_id
-            } else {
-                // Create a new user
-                let new_id = Uuid::new_v4();
-                self.users.insert(new_id, User {
-                    id: new_id,
-                    username: username.clone(),
-                    status: UserStatus::Online,
+            if remaining.is_zero() {
+                let _ = session.direct_tx.send(Message::SessionExpired {});
+            } else if remaining <= SESSION_EXPIRY_WARNING && session.renew_token.is_none() {
+                let renew_token = new_renew_token();
+                session.renew_token = Some(renew_token.clone());
+                let _ = session.direct_tx.send(Message::SessionExpiring {
+                    remaining_secs: remaining.as_secs() as u32,
+                    renew_token,
                 });
-                new_id
             }
+        }
+    }
+    
+    // Start the session's duration afresh, if `token` is the one it was sent
+    fn renew_session(&mut self, session_id: Uuid, token: &str) -> bool {
+        match self.sessions.get_mut(&session_id) {
+            Some(session) if session.renew_token.as_deref() == Some(token) => {
+                session.session_started_at = Instant::now();
+                session.renew_token = None;
+                true
+            }
+            _ => false,
+        }
+    }
+    
+    // Record a media frame, returning true if it was already forwarded to the channel
+    fn is_duplicate_frame(&mut self, channel_id: Uuid, user_id: Uuid, seq: u32) -> bool {
+        let seen = self.seen_seq
+            .entry(channel_id)
+            .or_insert_with(|| LruCache::new(NonZeroUsize::new(SEEN_SEQ_CAPACITY).unwrap()));
+        
+        if seen.contains(&(user_id, seq)) {
+            let deduplicated = &metrics::get_metrics().deduplicated_frames_total;
+            deduplicated.inc();
+            debug!("Dropped duplicate frame {} from {} ({} so far)", seq, user_id, deduplicated.get());
+            return true;
+        }
+        
+        seen.put((user_id, seq), ());
+        false
+    }
+    
+    // Count a media frame against its channel's bandwidth limit. Returns false if it would
+    // go over, in which case the frame is dropped.
+    fn admit_media(&mut self, channel_id: Uuid, bytes: usize) -> bool {
+        let limit_kbps = self.channels.get(&channel_id).map_or(0, |c| c.media_bandwidth_limit_kbps);
+        let sent = self.bytes_sent_this_second.entry(channel_id).or_default();
+        
+        if limit_kbps > 0 && *sent + bytes as u64 > limit_kbps as u64 * 1024 / 8 {
+            return false;
+        }
+        
+        *sent += bytes as u64;
+        true
+    }
+    
+    // Whether to tell a sender their frame was dropped; once per window is enough
+    fn notify_rate_limited(&mut self, channel_id: Uuid, user_id: Uuid) -> bool {
+        self.rate_limited.insert((channel_id, user_id))
+    }
+    
+    fn record_forwarded(&mut self, channel_id: Uuid, bytes: usize) {
+        self.bytes_forwarded.entry(channel_id).or_default().add(bytes as u64);
+    }
+    
+    // A member of a voice channel is only recorded once they consent, so ask them. The server
+    // is the recording user as far as clients are concerned.
+    fn recording_member_joined(&mut self, channel_id: Uuid, user_id: Uuid) {
+        if self.is_text_channel(channel_id) {
+            return;
+        }
+        
+        if let Some(recorder) = &mut self.recorder {
+            recorder.member_joined(channel_id, user_id);
+            self.send_to_user(user_id, Message::RecordingConsent { recording_user_id: Uuid::nil(), recording_started: true });
+        }
+    }
+    
+    // Call once the user is no longer in the channel from any session
+    fn recording_member_left(&mut self, channel_id: Uuid, user_id: Uuid) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.member_left(channel_id, user_id) {
+                error!("Failed to finish recording of {} in {}: {}", user_id, channel_id, e);
+            }
+        }
+    }
+    
+    fn record_voice(&mut self, channel_id: Uuid, user_id: Uuid, data: &[u8]) {
+        // Encrypted voice can't be recorded
+        let quality = match self.channels.get(&channel_id) {
+            Some(channel) if !channel.e2e_encrypted => channel.audio_quality,
+            _ => return,
         };
         
-        // Update session
-        if let Some(session) = self.sessions.get_mut(addr) {
-            session.user_id = Some(user_id);
-            
-            // Return successful login response
-            Message::LoginResponse {
-                success: true,
-                user_id: Some(user_id),
-                error: None,
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record_voice(channel_id, user_id, data, quality) {
+                error!("Failed to record voice from {} in {}: {}", user_id, channel_id, e);
             }
-        } else {
-            // Session not found
-            Message::LoginResponse {
-                success: false,
-                user_id: None,
-                error: Some("Session not found".to_string()),
+        }
+    }
+    
+    // Load per channel. `previous_bytes` holds the byte counts from the last call,
+    // `elapsed` the time since then, and is updated for the next call.
+    fn server_statistics(&self, previous_bytes: &mut HashMap<Uuid, u64>, elapsed: Duration) -> ServerStatistics {
+        let mut per_channel: Vec<ChannelStats> = self.channels
+            .values()
+            .map(|channel| {
+                let members = self.channel_members(channel.id);
+                let streaming = |streams: &HashSet<Uuid>| members.iter().filter(|m| streams.contains(m)).count() as u32;
+                
+                let bytes = self.bytes_forwarded.get(&channel.id).map_or(0, Counter::get);
+                let previous = previous_bytes.insert(channel.id, bytes).unwrap_or(0);
+                let bytes_forwarded_per_sec = if elapsed.is_zero() {
+                    0
+                } else {
+                    (bytes.saturating_sub(previous) as f64 / elapsed.as_secs_f64()) as u64
+                };
+                
+                ChannelStats {
+                    channel_id: channel.id,
+                    name: channel.name.clone(),
+                    members: members.len() as u32,
+                    voice_streams: streaming(&self.voice_streams),
+                    video_streams: streaming(&self.video_streams) + streaming(&self.screen_streams),
+                    bytes_forwarded_per_sec,
+                    recording_active: self.recorder.as_ref().is_some_and(|r| r.is_active(channel.id)),
+                }
+            })
+            .collect();
+        per_channel.sort_by(|a, b| a.name.cmp(&b.name));
+        
+        ServerStatistics {
+            total_connections: self.sessions.len() as u32,
+            per_channel,
+            admin_ids: self.users.keys().copied().filter(|&id| self.is_admin(id)).collect(),
+        }
+    }
+    
+    fn is_admin(&self, user_id: Uuid) -> bool {
+        self.users
+            .get(&user_id)
+            .is_some_and(|user| self.admin_users.contains(&user.username))
+    }
+    
+    // Send a message to every session of a logged-in admin
+    fn send_to_admins(&self, message: Message) {
+        for session in self.sessions.values() {
+            if session.user_id.is_some_and(|id| self.is_admin(id)) {
+                let _ = session.direct_tx.send(message.clone());
             }
         }
     }
     
+    // Snapshot of a logged-in session for the session store
+    fn stored_session(&self, session_id: Uuid) -> Option<StoredSession> {
+        let session = self.sessions.get(&session_id)?;
+        
+        Some(StoredSession {
+            user_id: session.user_id?,
+            channels: session.channels.clone(),
+            addr: session.addr.clone(),
+        })
+    }
     
-    // Get server info
-    fn get_server_info(&self) -> Server {
-        Server {
-            id: Uuid::new_v4(), // Generate a server ID
-            name: "Open Reverb Server".to_string(),
-            description: Some("A voice, video, and text communication server".to_string()),
-            channels: self.channels.values().cloned().collect(),
-            users: self.users.values().cloned().collect(),
+    // Send a message to one session, after anything already sent to it
+    fn send_to_session(&self, session_id: Uuid, message: Message) {
+        if let Some(session) = self.sessions.get(&session_id) {
+            let _ = session.direct_tx.send(message);
         }
     }
-}
-
-// Handle a client connection
-async fn handle_connection(
-    socket: TcpStream,
-    addr: String,
-    server_state: Arc<Mutex<ServerState>>,
-    tx: Arc<broadcast::Sender<(Uuid, Message)>>
-) -> Result<(), Box<dyn Error>> {
-    // Add the session
-    {
-        let mut state = server_state.lock().unwrap();
-        state.add_session(addr.clone());
+    
+    // Send a message to every session of a single user
+    fn send_to_user(&self, user_id: Uuid, message: Message) {
+        for session in self.sessions.values().filter(|s| s.user_id == Some(user_id)) {
+            let _ = session.direct_tx.send(message.clone());
+        }
     }
     
-    // Create a channel for receiving broadcasts
-    let mut rx = tx.subscribe();
+    // Users with a session in the channel
+    fn channel_members(&self, channel_id: Uuid) -> Vec<Uuid> {
+        let mut members: Vec<Uuid> = self.sessions
+            .values()
+            .filter(|s| s.channels.contains(&channel_id))
+            .filter_map(|s| s.user_id)
+            .collect();
+        members.sort();
+        members.dedup();
+        members
+    }
     
-    // Split the socket for reading and writing
-    let (mut reader, writer) = tokio::io::split(socket);
+    // Members other members know about, leaving out ghosts
+    fn visible_channel_members(&self, channel_id: Uuid) -> Vec<Uuid> {
+        let ghosts: HashSet<Uuid> = self.ghost_members(channel_id).iter().map(|u| u.id).collect();
+        self.channel_members(channel_id).into_iter().filter(|id| !ghosts.contains(id)).collect()
+    }
     
-    // Buffer for incoming data
-    let mut len_buf = [0u8; 4];
-    let mut user_id = None;
+    fn ghost_members(&self, channel_id: Uuid) -> Vec<User> {
+        let mut ghosts: Vec<User> = self.sessions
+            .values()
+            .filter(|s| s.ghost_mode && s.channels.contains(&channel_id))
+            .filter_map(|s| s.user_id.and_then(|id| self.users.get(&id)).cloned())
+            .collect();
+        ghosts.sort_by_key(|u| u.id);
+        ghosts.dedup_by_key(|u| u.id);
+        ghosts
+    }
     
-    // Writer needs to be used across tasks, so we need to wrap it in an Arc<Mutex>
-    let writer = Arc::new(tokio::sync::Mutex::new(writer));
+    // Admins keep track of the ghosts in each channel
+    fn notify_ghost_members(&self, channel_id: Uuid) {
+        self.send_to_admins(Message::GhostMembers { channel_id, users: self.ghost_members(channel_id) });
+    }
     
-    // Setup a task to forward messages from the broadcast channel to this client
-    let addr_clone = addr.clone();
-    let server_state_clone = Arc::clone(&server_state);
-    let writer_clone = Arc::clone(&writer);
+    fn can_ghost(&self, user_id: Uuid) -> bool {
+        self.is_admin(user_id)
+            || self.users.get(&user_id).is_some_and(|user| config::get_config().ghost_users.contains(&user.username))
+    }
     
-    let forward_task = tokio::spawn(async move {
-        while let Ok((sender_id, message)) = rx.recv().await {
-            // Don't send messages back to the sender
-            let current_user_id = {
-                let state = server_state_clone.lock().unwrap();
-                state.sessions.get(&addr_clone).and_then(|s| s.user_id)
-            };
+    fn channel_members_of(&self, user_id: Uuid) -> Vec<Uuid> {
+        let channels: Vec<Uuid> = self.sessions
+            .values()
+            .filter(|s| s.user_id == Some(user_id))
+            .flat_map(|s| s.channels.iter().copied())
+            .collect();
+        
+        let mut members: Vec<Uuid> = self.sessions
+            .values()
+            .filter(|s| s.channels.iter().any(|c| channels.contains(c)))
+            .filter_map(|s| s.user_id)
+            .filter(|id| *id != user_id)
+            .collect();
+        members.sort();
+        members.dedup();
+        members
+    }
+    
+    // Handle a user starting or stopping a recording. Returns the request ID when
+    // consent is required and has to be collected before the recording may begin.
+    fn handle_recording_request(&mut self, recording_user_id: Uuid, recording_started: bool, require_consent: bool) -> Option<Uuid> {
+        let members = self.channel_members_of(recording_user_id);
+        let notice = Message::RecordingConsent { recording_user_id, recording_started };
+        
+        if !recording_started {
+            self.pending_recordings.remove(&recording_user_id);
+            
+            for member in members {
+                self.send_to_user(member, notice.clone());
+            }
+            self.send_to_user(recording_user_id, notice);
             
-            if current_user_id.is_none() || current_user_id.unwrap() != sender_id {
-                let message_bytes = serde_json::to_vec(&message).unwrap_or_default();
-                let message_len = message_bytes.len() as u32;
-                let len_bytes = message_len.to_be_bytes();
+            return None;
+        }
+        
+        // Everyone in the channel is told about the recording either way
+        for member in &members {
+            self.send_to_user(*member, notice.clone());
+        }
+        
+        if !require_consent || members.is_empty() {
+            // Confirm to the recording user that they may start
+            self.send_to_user(recording_user_id, notice);
+            return None;
+        }
+        
+        let request_id = Uuid::new_v4();
+        self.pending_recordings.insert(recording_user_id, PendingRecording {
+            request_id,
+            awaiting: members.iter().copied().collect(),
+            members,
+        });
+        
+        Some(request_id)
+    }
+    
+    // Record a member's answer to any recording requests they were asked about
+    fn handle_consent_response(&mut self, user_id: Uuid, accepted: bool) {
+        let recorders: Vec<Uuid> = self.pending_recordings
+            .iter()
+            .filter(|(_, pending)| pending.awaiting.contains(&user_id))
+            .map(|(recorder, _)| *recorder)
+            .collect();
+        
+        if accepted {
+            if let Some(recorder) = &mut self.recorder {
+                recorder.acknowledge(user_id);
+            }
+        }
+        
+        for recorder in recorders {
+            if accepted {
+                let complete = match self.pending_recordings.get_mut(&recorder) {
+                    Some(pending) => {
+                        pending.awaiting.remove(&user_id);
+                        pending.awaiting.is_empty()
+                    }
+                    None => false,
+                };
                 
-                let mut writer = writer_clone.lock().await;
+                if complete {
+                    self.pending_recordings.remove(&recorder);
+                    self.send_to_user(recorder, Message::RecordingConsent {
+                        recording_user_id: recorder,
+                        recording_started: true,
+                    });
+                }
+            } else {
+                self.refuse_recording(recorder);
+            }
+        }
+    }
+    
+    // Refuse a pending recording request, e.g. after a rejection or timeout
+    fn refuse_recording(&mut self, recording_user_id: Uuid) {
+        if let Some(pending) = self.pending_recordings.remove(&recording_user_id) {
+            for member in pending.members {
+                self.send_to_user(member, Message::RecordingConsent {
+                    recording_user_id,
+                    recording_started: false,
+                });
+            }
+            
+            self.send_to_user(recording_user_id, Message::Error {
+                code: 451,
+                message: "Recording not permitted".to_string(),
+            });
+        }
+    }
+    
+    // Remove a session
+    fn remove_session(&mut self, session_id: Uuid) -> Option<SessionInfo> {
+        let session = self.sessions.remove(&session_id);
+        
+        // If the session had a user, mark them as offline
+        if let Some(session_info) = &session {
+            if let Some(user_id) = session_info.user_id {
+                self.pending_recordings.remove(&user_id);
                 
-                if writer.write_all(&len_bytes).await.is_err() {
-                    break;
+                if let Some(transcriber) = &mut self.transcriber {
+                    transcriber.remove_user(user_id);
                 }
                 
-                if writer.write_all(&message_bytes).await.is_err() {
-                    break;
+                self.public_keys.remove(&user_id);
+                self.voice_streams.remove(&user_id);
+                self.video_streams.remove(&user_id);
+                self.video_codecs.remove(&user_id);
+                self.screen_streams.remove(&user_id);
+                
+                for channel_id in &session_info.channels {
+                    if !self.channel_members(*channel_id).contains(&user_id) {
+                        self.recording_member_left(*channel_id, user_id);
+                    }
                 }
                 
-                if writer.flush().await.is_err() {
-                    break;
+                // They're still online if connected from elsewhere
+                if !self.is_connected(user_id) {
+                    self.lobby.remove_user(user_id);
+                    
+                    for (id, other) in self.file_transfers.remove_user(user_id) {
+                        self.send_to_user(other, Message::FileTransferReject { id, reason: "The other user went offline".to_string() });
+                    }
+                    
+                    if let Some(user) = self.users.get_mut(&user_id) {
+                        user.status = UserStatus::Offline;
+                    }
                 }
             }
         }
-    });
+        
+        session
+    }
     
-    // Main loop for handling incoming messages
-    loop {
-        // Read message length (4 bytes)
-        match reader.read_exact(&mut len_buf).await {
-            Ok(_) => {
-                let message_len = u32::from_be_bytes(len_buf) as usize;
-                
-                // Read message data
-                let mut message_buf = vec![0u8; message_len];
-                if let Err(e) = reader.read_exact(&mut message_buf).await {
-                    error!("Error reading message data: {}", e);
-                    break;
+    fn is_connected(&self, user_id: Uuid) -> bool {
+        self.sessions.values().any(|s| s.user_id == Some(user_id))
+    }
+    
+    // The steps of logging in after the password: a TOTP code when the server requires one, or
+    // a session token instead of both. Passwords go through login_with_password.
+    fn handle_login_step(&mut self, session_id: Uuid, message: Message) -> Message {
+        match message {
+            Message::TOTPResponse { user_id, code } => self.handle_totp_response(session_id, user_id, code),
+            Message::ResumeSession { token } => self.handle_resume_session(session_id, token),
+            _ => Message::Error { code: 400, message: "Not a login message".to_string() },
+        }
+    }
+    
+    // The user logging in, created if they're new, and their password hash if they have one
+    fn stored_password(&mut self, username: String) -> Result<(Uuid, Option<String>), String> {
+        let user_id = self.find_or_add_user(username)?;
+        let hash = self.database.user_by_id(user_id).and_then(|user| user.password_hash.clone());
+        Ok((user_id, hash))
+    }
+    
+    // Carry on logging in once the password is checked. Users without a password hash (new ones,
+    // and those from before passwords were checked) keep the password they logged in with.
+    fn finish_password_login(&mut self, session_id: Uuid, user_id: Uuid, check: PasswordCheck) -> Message {
+        let failed = |error: &str| Message::LoginResponse { success: false, user_id: None, token: None, error: Some(error.to_string()) };
+        
+        match check {
+            PasswordCheck::Correct => {}
+            PasswordCheck::Wrong => {
+                warn!("Wrong password for {}", user_id);
+                return failed("Invalid username or password");
+            }
+            PasswordCheck::Failed => return failed("Couldn't check the password, try again later"),
+            // Another login may have set one while this was hashed
+            PasswordCheck::New(_) if self.database.user_by_id(user_id).is_some_and(|user| user.password_hash.is_some()) => {
+                return failed("The password was just set by another login, try again");
+            }
+            PasswordCheck::New(hash) => {
+                if let Err(e) = self.database.update_user(user_id, |user| user.password_hash = Some(hash)) {
+                    error!("Failed to save the password for {}: {}", user_id, e);
+                    return failed("Couldn't save the password, try again later");
+                }
+            }
+        }
+        
+        if !config::get_config().totp_required {
+            let token = self.session_tokens.issue(user_id, announcements::now_secs());
+            return self.complete_login(session_id, user_id, token);
+        }
+        self.request_totp(session_id, user_id)
+    }
+    
+    // Ask for a code once the password is accepted
+    fn request_totp(&mut self, session_id: Uuid, user_id: Uuid) -> Message {
+        // Users without a secret get one now, and confirm it with their first code
+        let has_secret = self.database.user_by_id(user_id).is_some_and(|user| user.totp_secret.is_some());
+        let new_secret = (!has_secret).then(totp::generate_secret);
+        
+        match self.sessions.get_mut(&session_id) {
+            Some(session) => {
+                if let Some(secret) = &new_secret {
+                    let _ = session.direct_tx.send(Message::SetupTOTP {
+                        secret: secret.clone(),
+                        // Drawing the QR code needs an encoder the server doesn't have yet
+                        qr_code_svg: String::new(),
+                    });
                 }
                 
-                // Parse message
-                match serde_json::from_slice::<Message>(&message_buf) {
-                    Ok(message) => {
-                        info!("Received message: {:?}", message);
-                        
-                        // Handle message based on type
-                        let response = match message {
-                            Message::LoginRequest { username, password } => {
-                                let response = {
-                                    let mut state = server_state.lock().unwrap();
-                                    state.handle_login(&addr, username, password)
-                                };
-                                
-                                if let Message::LoginResponse { success: true, user_id: Some(id), .. } = &response {
-                                    user_id = Some(*id);
-                                    
-                                    // Send server info after successful login
-                                    let server_info = {
-                                        let state = server_state.lock().unwrap();
-                                        state.get_server_info()
-                                    };
-                                    
-                                    // First send login response
-                                    let login_bytes = serde_json::to_vec(&response)?;
-                                    let login_len = login_bytes.len() as u32;
-                                    let login_len_bytes = login_len.to_be_bytes();
-                                    
-                                    let mut writer_lock = writer.lock().await;
-                                    writer_lock.write_all(&login_len_bytes).await?;
-                                    writer_lock.write_all(&login_bytes).await?;
-                                    writer_lock.flush().await?;
-                                    drop(writer_lock); // Release the lock explicitly
-                                    
-                                    // Then send server info
-                                    let server_info_msg = Message::ServerInfo { server: server_info };
-                                    let server_bytes = serde_json::to_vec(&server_info_msg)?;
-                                    let server_len = server_bytes.len() as u32;
-                                    let server_len_bytes = server_len.to_be_bytes();
-                                    
-                                    let mut writer_lock = writer.lock().await;
-                                    writer_lock.write_all(&server_len_bytes).await?;
-                                    writer_lock.write_all(&server_bytes).await?;
-                                    writer_lock.flush().await?;
-                                    
-                                    // No need for another response
-                                    continue;
-                                }
-                                
-                                Some(response)
-                            },
-                            Message::Ping => {
-                                Some(Message::Pong)
-                            },
-                            Message::StatusUpdate { user_id, status } => {
-                                // Update user status
-                                {
-                                    let mut state = server_state.lock().unwrap();
-                                    if let Some(user) = state.users.get_mut(&user_id) {
-                                        user.status = status;
-                                    }
-                                }
-                                
-                                // Broadcast status update to all clients
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            Message::JoinChannel { channel_id } => {
-                                // Add user to channel
-                                {
-                                    let mut state = server_state.lock().unwrap();
-                                    if let Some(session) = state.sessions.get_mut(&addr) {
-                                        if !session.channels.contains(&channel_id) {
-                                            session.channels.push(channel_id);
-                                        }
-                                    }
-                                }
-                                
-                                // Broadcast to all clients
-                                let _ = tx.send((user_id.unwrap(), message.clone()));
-                                
-                                None
-                            },
-                            Message::LeaveChannel { channel_id } => {
-                                // Remove user from channel
-                                {
-                                    let mut state = server_state.lock().unwrap();
-                                    if let Some(session) = state.sessions.get_mut(&addr) {
-                                        session.channels.retain(|&id| id != channel_id);
-                                    }
-                                }
-                                
-                                // Broadcast to all clients
-                                let _ = tx.send((user_id.unwrap(), message.clone()));
-                                
-                                None
-                            },
-                            Message::VoiceData { user_id, channel_id: _, ref data } => {
-                                // Broadcast voice data to all clients in the channel
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            Message::VideoData { user_id, channel_id: _, ref data } => {
-                                // Broadcast video data to all clients in the channel
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            Message::ScreenShareData { user_id, channel_id: _, ref data } => {
-                                // Broadcast screen share data to all clients in the channel
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            Message::VoiceStarted { user_id } => {
-                                // Broadcast voice started to all clients
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            Message::VoiceStopped { user_id } => {
-                                // Broadcast voice stopped to all clients
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            Message::VideoStarted { user_id } => {
-                                // Broadcast video started to all clients
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            Message::VideoStopped { user_id } => {
-                                // Broadcast video stopped to all clients
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            Message::ScreenShareStarted { user_id } => {
-                                // Broadcast screen share started to all clients
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            Message::ScreenShareStopped { user_id } => {
-                                // Broadcast screen share stopped to all clients
-                                let _ = tx.send((user_id, message.clone()));
-                                
-                                None
-                            },
-                            _ => None,
-                        };
-                        
-                        // Send response if needed
-                        if let Some(response) = response {
-                            let response_bytes = serde_json::to_vec(&response)?;
-                            let response_len = response_bytes.len() as u32;
-                            let response_len_bytes = response_len.to_be_bytes();
-                            
-                            let mut writer_lock = writer.lock().await;
-                            writer_lock.write_all(&response_len_bytes).await?;
-                            writer_lock.write_all(&response_bytes).await?;
-                            writer_lock.flush().await?;
-                        }
-                    },
-                    Err(e) => {
-                        error!("Error parsing message: {}", e);
-                        break;
-                    }
-                }
+                session.pending_totp = Some(PendingTotp { user_id, new_secret, failed_attempts: 0 });
+                Message::TOTPRequest { user_id }
+            }
+            None => Message::LoginResponse {
+                success: false,
+                user_id: None,
+                token: None,
+                error: Some("Session not found".to_string()),
             },
+        }
+    }
+    
+    // The user with this exact username, or a new one if no user has it in any case
+    fn find_or_add_user(&mut self, username: String) -> Result<Uuid, String> {
+        validate_username(&username)?;
+        
+        // Names differing only in case can't both exist
+        if self.database.get_user_id(&username).is_some() {
+            return match self.database.get_user(&username) {
+                Some(user) => Ok(user.id),
+                None => Err("Username taken (case-insensitive match)".to_string()),
+            };
+        }
+        
+        let id = Uuid::new_v4();
+        let row = UserRow { id, username: username.clone(), password_hash: None, totp_secret: None, created_at: announcements::now_secs() };
+        match self.database.add_user(row) {
+            Ok(()) => {}
+            Err(DatabaseError::UsernameTaken(_)) => return Err("Username taken (case-insensitive match)".to_string()),
             Err(e) => {
-                if e.kind() != std::io::ErrorKind::UnexpectedEof {
-                    error!("Error reading message length: {}", e);
+                error!("Failed to save user {}: {}", username, e);
+                return Err("Couldn't create the user, try again later".to_string());
+            }
+        }
+        
+        self.users.insert(id, User {
+            id,
+            username,
+            status: UserStatus::Online,
+            joined_at: 0,
+            federated_from: None,
+        });
+        Ok(id)
+    }
+    
+    // Take the users and channels kept in `database`. A new database is filled with the
+    // channels the server started with instead.
+    fn load_database(&mut self, database: Database) {
+        for row in database.users() {
+            self.users.insert(row.id, User {
+                id: row.id,
+                username: row.username.clone(),
+                status: UserStatus::Offline,
+                joined_at: 0,
+                federated_from: None,
+            });
+        }
+        
+        if !database.channels().is_empty() {
+            let audio_quality = config::get_config().default_audio_quality;
+            self.channels = database
+                .channels()
+                .iter()
+                .map(|row| (row.id, Channel {
+                    id: row.id,
+                    name: row.name.clone(),
+                    description: row.description.clone(),
+                    parent_id: row.parent_id,
+                    members: Vec::new(),
+                    topic: None,
+                    e2e_encrypted: false,
+                    channel_type: row.channel_type,
+                    media_bandwidth_limit_kbps: 0,
+                    lobby_mode: false,
+                    audio_quality,
+                    override_retention_days: None,
+                    permanent: row.permanent,
+                    idle_timeout_secs: None,
+                }))
+                .collect();
+        }
+        
+        self.database = database;
+        self.save_channels();
+    }
+    
+    // Called after anything that adds, removes, renames or moves a channel
+    fn save_channels(&mut self) {
+        if let Err(e) = self.database.save_channels(self.channels.values()) {
+            error!("Failed to save channels: {}", e);
+        }
+    }
+    
+    fn handle_totp_response(&mut self, session_id: Uuid, user_id: Uuid, code: String) -> Message {
+        let failed = |error: &str| Message::LoginResponse {
+            success: false,
+            user_id: None,
+            token: None,
+            error: Some(error.to_string()),
+        };
+        
+        let Some(session) = self.sessions.get_mut(&session_id) else {
+            return failed("Session not found");
+        };
+        let pending = match session.pending_totp.as_mut() {
+            Some(pending) if pending.user_id == user_id => pending,
+            _ => return failed("No login is waiting for a code"),
+        };
+        
+        let stored = self.database.user_by_id(user_id).and_then(|user| user.totp_secret.clone());
+        let secret = match (pending.new_secret.clone(), stored) {
+            // Someone else finished setting up this account first
+            (Some(_), Some(_)) => {
+                session.pending_totp = None;
+                return failed("TOTP is already set up for this account, log in again");
+            }
+            (Some(secret), None) | (None, Some(secret)) => secret,
+            (None, None) => return failed("TOTP is not set up"),
+        };
+        
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        
+        // Each code is only good once, even within its window
+        let step = match totp::verify(&secret, &code, now) {
+            Some(step) if self.last_totp_step.get(&user_id).is_none_or(|last| step > *last) => step,
+            _ => {
+                warn!("Rejected TOTP code for {}", user_id);
+                pending.failed_attempts += 1;
+                if pending.failed_attempts >= MAX_TOTP_ATTEMPTS {
+                    session.pending_totp = None;
+                    return failed("Too many invalid codes, log in again");
                 }
+                return failed("Invalid code");
+            }
+        };
+        
+        let new_secret = pending.new_secret.take();
+        session.pending_totp = None;
+        if let Some(secret) = new_secret {
+            if let Err(e) = self.database.update_user(user_id, |user| user.totp_secret = Some(secret)) {
+                error!("Failed to save the TOTP secret for {}: {}", user_id, e);
+                return failed("Couldn't save the TOTP secret, try again later");
+            }
+        }
+        self.last_totp_step.insert(user_id, step);
+        
+        
+        let token = self.session_tokens.issue(user_id, announcements::now_secs());
+        self.complete_login(session_id, user_id, token)
+    }
+    
+    // Log in the user a token from an earlier login was issued to. The token is handed back
+    // as is rather than renewed, so resuming can't keep a session going past its expiry.
+    fn handle_resume_session(&mut self, session_id: Uuid, token: String) -> Message {
+        let unauthorized = |message: String| Message::Error { code: 401, message };
+        let now = announcements::now_secs();
+        
+        let claims = match self.session_tokens.verify(&token, now) {
+            Ok(claims) => claims,
+            Err(error) => return unauthorized(error),
+        };
+        
+        if !self.users.contains_key(&claims.user_id) {
+            return unauthorized("Unknown user".to_string());
+        }
+        
+        // The session limit counts from the original login, not from each resume
+        let age = now.saturating_sub(claims.iat);
+        if config::get_config().max_session_duration_secs.is_some_and(|max| age >= max) {
+            return unauthorized("Session expired".to_string());
+        }
+        
+        let response = self.complete_login(session_id, claims.user_id, token);
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.session_started_at = Instant::now()
+                .checked_sub(Duration::from_secs(age))
+                .unwrap_or(session.session_started_at);
+        }
+        response
+    }
+    
+    fn complete_login(&mut self, session_id: Uuid, user_id: Uuid, token: String) -> Message {
+        let joined_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.status = UserStatus::Online;
+            user.joined_at = joined_at;
+            self.audit_log.logged_in(session_id, user_id, &user.username);
+        }
+        
+        // Update session
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.user_id = Some(user_id);
+            session.session_started_at = Instant::now();
+            session.renew_token = None;
+            
+            // Return successful login response
+            Message::LoginResponse {
+                success: true,
+                user_id: Some(user_id),
+                token: Some(token),
+                error: None,
+            }
+        } else {
+            // Session not found
+            Message::LoginResponse {
+                success: false,
+                user_id: None,
+                token: None,
+                error: Some("Session not found".to_string()),
+            }
+        }
+    }
+    
+    // Create a new channel, optionally nested under a parent
+    fn create_channel(&mut self, new_channel: NewChannel, is_admin: bool) -> Result<Channel, String> {
+        let NewChannel { name, description, parent_id, channel_type, media_bandwidth_limit_kbps, audio_quality, permanent, idle_timeout_secs } = new_channel;
+        if permanent && !is_admin {
+            return Err("Only admins can create permanent channels".to_string());
+        }
+        
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return Err("Channel name cannot be empty".to_string());
+        }
+        if name.chars().count() > MAX_CHANNEL_NAME_LENGTH {
+            return Err(format!("Channel name cannot be longer than {} characters", MAX_CHANNEL_NAME_LENGTH));
+        }
+        if self.channels.values().any(|channel| channel.name.eq_ignore_ascii_case(&name)) {
+            return Err(format!("A channel named {} already exists", name));
+        }
+        
+        let audio_quality = audio_quality.unwrap_or(config::get_config().default_audio_quality);
+        audio_quality.validate()?;
+        
+        if let Some(parent_id) = parent_id {
+            if !self.channels.contains_key(&parent_id) {
+                return Err("Parent channel not found".to_string());
+            }
+            
+            let max_depth = config::get_config().max_hierarchy_depth;
+            if channel_depth(parent_id, &self.channels) + 1 > max_depth {
+                return Err(format!("Channel hierarchy cannot be deeper than {} levels", max_depth));
+            }
+        }
+        
+        let channel = Channel {
+            id: Uuid::new_v4(),
+            name,
+            description,
+            parent_id,
+            members: Vec::new(),
+            topic: None,
+            e2e_encrypted: false,
+            channel_type,
+            media_bandwidth_limit_kbps,
+            lobby_mode: false,
+            audio_quality,
+            override_retention_days: None,
+            permanent,
+            idle_timeout_secs,
+        };
+        
+        self.channels.insert(channel.id, channel.clone());
+        self.channel_activity.touch(channel.id, Instant::now());
+        self.save_channels();
+        
+        Ok(channel)
+    }
+    
+    // Delete a single channel, taking everyone in it out. Returns the sessions that were in it.
+    fn delete_channel(&mut self, channel_id: Uuid, is_admin: bool) -> Result<Vec<Uuid>, String> {
+        let channel = self.channels.get(&channel_id).ok_or("Channel not found")?;
+        if channel.permanent && !is_admin {
+            return Err("Only admins can delete permanent channels".to_string());
+        }
+        if self.channels.values().any(|c| c.parent_id == Some(channel_id)) {
+            return Err("Delete the channel's sub-channels first".to_string());
+        }
+        
+        self.channels.remove(&channel_id);
+        let mut members = Vec::new();
+        for (session_id, session) in self.sessions.iter_mut() {
+            if session.channels.contains(&channel_id) {
+                session.channels.retain(|id| *id != channel_id);
+                session.channels_changed.notify_one();
+                members.push(*session_id);
+            }
+        }
+        
+        self.forget_channels(&[channel_id]);
+        self.save_channels();
+        Ok(members)
+    }
+    
+    // Delete the channels whose names start with `prefix`, and every channel nested under them.
+    // Returns the IDs of the channels deleted.
+    fn purge_channels(&mut self, prefix: &str) -> Result<Vec<Uuid>, String> {
+        if prefix.is_empty() {
+            return Err("A prefix is needed to purge channels".to_string());
+        }
+        
+        let mut purged: HashSet<Uuid> = self.channels
+            .values()
+            .filter(|channel| channel.name.starts_with(prefix))
+            .map(|channel| channel.id)
+            .collect();
+        
+        // Pull in children until there are no more, however deep the tree
+        loop {
+            let children: Vec<Uuid> = self.channels
+                .values()
+                .filter(|channel| !purged.contains(&channel.id) && channel.parent_id.is_some_and(|p| purged.contains(&p)))
+                .map(|channel| channel.id)
+                .collect();
+            if children.is_empty() {
                 break;
             }
+            purged.extend(children);
+        }
+        
+        self.channels.retain(|id, _| !purged.contains(id));
+        for session in self.sessions.values_mut() {
+            session.channels.retain(|id| !purged.contains(id));
+            session.channels_changed.notify_one();
         }
+        
+        let purged: Vec<Uuid> = purged.into_iter().collect();
+        self.forget_channels(&purged);
+        self.save_channels();
+        Ok(purged)
     }
     
-    // Connection closed, cleanup
-    {
-        let mut state = server_state.lock().unwrap();
-        if let Some(session) = state.remove_session(&addr) {
-            if let Some(uid) = session.user_id {
-                // Broadcast that user left
-                let _ = tx.send((uid, Message::UserLeft { user_id: uid }));
+    // Drop everything kept about channels that have been deleted, finishing their recordings
+    fn forget_channels(&mut self, channel_ids: &[Uuid]) {
+        for channel_id in channel_ids {
+            self.channel_senders.remove(channel_id);
+            self.seen_seq.remove(channel_id);
+            self.bytes_forwarded.remove(channel_id);
+            self.bytes_sent_this_second.remove(channel_id);
+            if let Some(recorder) = &mut self.recorder {
+                if let Err(e) = recorder.channel_deleted(*channel_id) {
+                    error!("Failed to finish recordings in {}: {}", channel_id, e);
+                }
             }
         }
+        self.rate_limited.retain(|(channel_id, _)| !channel_ids.contains(channel_id));
+        self.channel_activity.forget(channel_ids);
     }
     
-    // Cancel the forward task
-    forward_task.abort();
+    // Delete the channels that have sat empty and unused for longer than their idle timeout.
+    // Returns the IDs of the channels deleted.
+    fn remove_idle_channels(&mut self, now: Instant) -> Vec<Uuid> {
+        let occupied: HashSet<Uuid> = self.sessions
+            .values()
+            .flat_map(|s| s.channels.iter().copied())
+            .chain(self.lobby.waiting_channels())
+            .collect();
+        let idle = self.channel_activity.idle_channels(&self.channels, |id| occupied.contains(&id), now);
+        
+        self.channels.retain(|id, _| !idle.contains(id));
+        self.forget_channels(&idle);
+        metrics::get_metrics().channel_deleted_total.add(idle.len() as u64);
+        if !idle.is_empty() {
+            self.save_channels();
+        }
+        idle
+    }
     
-    info!("Connection closed for {}", addr);
-    Ok(())
-}
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Initialize logging
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+    // A session's user did something in the channels they're in
+    fn touch_session_channels(&mut self, session_id: Uuid) {
+        let now = Instant::now();
+        if let Some(session) = self.sessions.get(&session_id) {
+            for &channel_id in &session.channels {
+                self.channel_activity.touch(channel_id, now);
+            }
+        }
+    }
     
-    info!("Starting Open Reverb Server");
+    // Apply the channels file again, returning the channels created or changed
+    fn reload_channel_config(&mut self) -> Result<Vec<Channel>, String> {
+        let config = config::get_config();
+        let path = config.channels_config_file.as_ref().ok_or("No channels file is configured")?;
+        let definitions = channel_config::load(path, config.max_hierarchy_depth)?
+            .ok_or_else(|| format!("Channels file {} not found", path))?;
+        
+        let changed = channel_config::apply(&mut self.channels, definitions, config.default_audio_quality);
+        self.save_channels();
+        Ok(changed)
+    }
     
-    // Bind to address
-    let addr = "0.0.0.0:8080";
-    let listener = TcpListener::bind(addr).await?;
-    info!("Server listening on {}", addr);
+    // Move a channel to a new place in the hierarchy
+    fn update_channel(&mut self, channel_id: Uuid, parent_id: Option<Uuid>) -> Result<Channel, String> {
+        if !self.channels.contains_key(&channel_id) {
+            return Err("Channel not found".to_string());
+        }
+        
+        if let Some(parent_id) = parent_id {
+            if !self.channels.contains_key(&parent_id) {
+                return Err("Parent channel not found".to_string());
+            }
+            
+            if !validate_no_cycle(channel_id, parent_id, &self.channels) {
+                return Err("Circular channel hierarchy detected".to_string());
+            }
+            
+            // The whole subtree moves along with the channel
+            let max_depth = config::get_config().max_hierarchy_depth;
+            if channel_depth(parent_id, &self.channels) + subtree_height(channel_id, &self.channels) > max_depth {
+                return Err(format!("Channel hierarchy cannot be deeper than {} levels", max_depth));
+            }
+        }
+        
+        let channel = self.channels.get_mut(&channel_id).unwrap();
+        channel.parent_id = parent_id;
+        let channel = channel.clone();
+        
+        self.save_channels();
+        Ok(channel)
+    }
     
-    // Create a server state
-    let server_state = Arc::new(Mutex::new(ServerState::new()));
+    // Turn on end-to-end encrypted voice for a channel. It can't be turned off again.
+    fn enable_e2e(&mut self, channel_id: Uuid) -> Result<Channel, String> {
+        let channel = self.channels.get_mut(&channel_id).ok_or("Channel not found")?;
+        if channel.channel_type != ChannelType::Voice {
+            return Err("Only voice channels can be encrypted".to_string());
+        }
+        channel.e2e_encrypted = true;
+        
+        Ok(channel.clone())
+    }
     
-    // Create a broadcast channel for messages
-    let (tx, _) = broadcast::channel::<(Uuid, Message)>(100);
-    let tx = Arc::new(tx);
+    // Tell the members of an encrypted channel who is in it, so key holders can pass the voice key on.
+    // `skip` is a member who is told some other way.
+    fn notify_e2e_members(&self, channel_id: Uuid, skip: Uuid) {
+        if !self.channels.get(&channel_id).is_some_and(|c| c.e2e_encrypted) {
+            return;
+        }
+        
+        let members = self.channel_members(channel_id);
+        for member in members.iter().filter(|m| **m != skip) {
+            self.send_to_user(*member, Message::ChannelMediaState { channel_id, members: members.clone() });
+        }
+    }
     
-    // Accept connections
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        info!("New connection from {}", addr);
+    fn set_audio_quality(&mut self, channel_id: Uuid, quality: AudioQuality) -> Result<Channel, String> {
+        quality.validate()?;
         
-        // Clone the server state and channel for this connection
-        let server_state = Arc::clone(&server_state);
-        let tx = Arc::clone(&tx);
+        let channel = self.channels.get_mut(&channel_id).ok_or("Channel not found")?;
+        channel.audio_quality = quality;
         
-        // Spawn a new task for each connection
-        tokio::spawn(async move {
-            info!("Connection established with {}", addr);
+        Ok(channel.clone())
+    }
+    
+    fn set_retention_days(&mut self, channel_id: Uuid, retention_days: Option<u32>) -> Result<Channel, String> {
+        if retention_days == Some(0) {
+            return Err("Messages must be kept for at least a day".to_string());
+        }
+        
+        let channel = self.channels.get_mut(&channel_id).ok_or("Channel not found")?;
+        channel.override_retention_days = retention_days;
+        
+        Ok(channel.clone())
+    }
+    
+    // Store a chat message, returning its timestamp. Timestamps never go backwards within a
+    // channel, even if the clock does, so clients can put messages in order.
+    fn archive_text_message(&mut self, message_id: Uuid, channel_id: Uuid, user_id: Uuid, content: &str, now_secs: u64) -> u64 {
+        let timestamp = self.message_archive.newest_timestamp(channel_id).map_or(now_secs, |newest| newest.max(now_secs));
+        self.message_archive.add(message_id, channel_id, user_id, content, timestamp);
+        self.channel_activity.touch(channel_id, Instant::now());
+        timestamp
+    }
+    
+    // Send a session the channel's recent chat after it joins
+    fn send_recent_messages(&self, session_id: Uuid, channel_id: Uuid) {
+        for (message_id, user_id, content, timestamp) in self.message_archive.recent(channel_id, RECENT_MESSAGES_ON_JOIN) {
+            self.send_to_session(session_id, Message::TextMessage {
+                message_id,
+                user_id,
+                channel_id,
+                content: content.to_string(),
+                timestamp,
+            });
+        }
+    }
+    
+    // Delete chat messages past their channel's retention period, and tell each channel's
+    // members what went
+    fn apply_retention(&mut self, now_secs: u64) {
+        let server_default = config::get_config().message_retention_days;
+        let deletions = retention::apply(&mut self.message_archive, self.channels.values(), server_default, now_secs);
+        
+        for deletion in deletions {
+            info!("Deleted {} messages past their retention period from channel {}", deletion.deleted_count, deletion.channel_id);
+            metrics::get_metrics().messages_deleted_total.add(deletion.deleted_count as u64);
             
-            if let Err(e) = handle_connection(socket, addr.to_string(), server_state, tx).await {
-                error!("Error handling connection from {}: {}", addr, e);
+            let message = Message::MessagesDeleted {
+                channel_id: deletion.channel_id,
+                deleted_count: deletion.deleted_count,
+                oldest_remaining: deletion.oldest_remaining,
+            };
+            for member in self.channel_members(deletion.channel_id) {
+                self.send_to_user(member, message.clone());
             }
-        });
+        }
+    }
+    
+    // A channel's chat between start_ts and end_ts, rendered in the given format
+    fn export_messages(&self, channel_id: Uuid, start_ts: u64, end_ts: u64, format: ExportFormat) -> Result<Vec<u8>, String> {
+        let channel = self.channels.get(&channel_id).ok_or("Channel not found")?;
+        
+        let rows: Vec<export::ExportRow> = self
+            .message_archive
+            .between(channel_id, start_ts, end_ts)
+            .into_iter()
+            .map(|(user_id, content, timestamp)| {
+                let username = self.users.get(&user_id).map_or("unknown", |u| u.username.as_str());
+                export::ExportRow::new(timestamp, &channel.name, username, content)
+            })
+            .collect();
+        
+        Ok(export::render(&rows, format))
     }
-}
\ No newline at end of file
+    
+    fn voice_started(&mut self, session_id: Uuid) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.speaking_time.started(Instant::now());
+        }
+    }
+    
+    fn voice_stopped(&mut self, session_id: Uuid) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.speaking_time.stopped(Instant::now(), &session.channels);
+        }
+    }
+    
+    // How long each member of a channel has spoken in it, most first
+    fn speaking_stats(&self, channel_id: Uuid) -> Vec<(Uuid, u64)> {
+        let now = Instant::now();
+        let per_session = self.sessions.values().filter(|s| s.channels.contains(&channel_id)).filter_map(|s| {
+            Some((s.user_id?, s.speaking_time.channel_ms(channel_id, now)))
+        });
+        
+        speaking::totals(per_session)
+    }
+    
+    fn set_lobby_mode(&mut self, channel_id: Uuid, enabled: bool) -> Result<Channel, String> {
+        let channel = self.channels.get_mut(&channel_id).ok_or("Channel not found")?;
+        channel.lobby_mode = enabled;
+        
+        Ok(channel.clone())
+    }
+    
+    // Admins and users already in the channel skip the lobby
+    fn must_wait_in_lobby(&self, user_id: Option<Uuid>, channel_id: Uuid) -> bool {
+        let Some(user_id) = user_id else {
+            return false;
+        };
+        
+        self.channels.get(&channel_id).is_some_and(|c| c.lobby_mode)
+            && !self.is_admin(user_id)
+            && !self.channel_members(channel_id).contains(&user_id)
+    }
+    
+    // Let a waiting user into the channel on all their sessions. Returns the IDs of those
+    // sessions and the channel's members, or None if the user wasn't waiting for it.
+    fn admit_from_lobby(&mut self, user_id: Uuid, channel_id: Uuid) -> Option<(Vec<Uuid>, Vec<Uuid>)> {
+        if !self.channels.contains_key(&channel_id) || !self.lobby.resolve(user_id, channel_id) {
+            return None;
+        }
+        
+        let mut session_ids = Vec::new();
+        for (session_id, session) in self.sessions.iter_mut().filter(|(_, s)| s.user_id == Some(user_id)) {
+            if !session.channels.contains(&channel_id) {
+                session.channels.push(channel_id);
+                session.channels_changed.notify_one();
+            }
+            session_ids.push(*session_id);
+        }
+        
+        Some((session_ids, self.visible_channel_members(channel_id)))
+    }
+    
+    // Media is only relayed from the session's own user, into a channel the session is in
+    fn may_send_media(&self, session_id: Uuid, user_id: Uuid, channel_id: Uuid) -> bool {
+        self.sessions
+            .get(&session_id)
+            .is_some_and(|session| session.user_id == Some(user_id) && session.channels.contains(&channel_id))
+    }
+    
+    fn is_text_channel(&self, channel_id: Uuid) -> bool {
+        self.channels.get(&channel_id).is_some_and(|c| c.channel_type == ChannelType::Text)
+    }
+    
+    // Set or clear the topic of a channel
+    fn set_channel_topic(&mut self, channel_id: Uuid, topic: String) -> Result<Channel, String> {
+        let topic = topic.trim();
+        if topic.chars().count() > MAX_TOPIC_LENGTH {
+            return Err(format!("Channel topic cannot be longer than {} characters", MAX_TOPIC_LENGTH));
+        }
+        
+        let channel = self.channels.get_mut(&channel_id).ok_or("Channel not found")?;
+        channel.topic = (!topic.is_empty()).then(|| topic.to_string());
+        
+        Ok(channel.clone())
+    }
+    
+    // Search the chat of the given channels, or all channels when there are none, leaving out
+    // channels in lobby mode or encrypted end to end unless the user is in them
+    fn search_messages(&self, user_id: Uuid, query: &str, channel_ids: &[Uuid], limit: u32, offset: u32) -> Vec<SearchResult> {
+        let readable: Vec<Uuid> = self.channels
+            .values()
+            .filter(|c| channel_ids.is_empty() || channel_ids.contains(&c.id))
+            .filter(|c| !(c.lobby_mode || c.e2e_encrypted) || self.channel_members(c.id).contains(&user_id))
+            .map(|c| c.id)
+            .collect();
+        
+        // The archive reads no channels as every channel
+        if readable.is_empty() {
+            return Vec::new();
+        }
+        self.message_archive.search(query, &readable, limit, offset)
+    }
+    
+    // Users whose username contains `query`, ignoring case, sorted by username
+    fn search_users(&self, query: &str, include_offline: bool) -> Vec<User> {
+        let query = query.to_lowercase();
+        let mut users: Vec<User> = self
+            .users
+            .values()
+            .filter(|u| include_offline || self.is_connected(u.id))
+            .filter(|u| u.username.to_lowercase().contains(&query))
+            .cloned()
+            .collect();
+        
+        users.sort_by_cached_key(|u| u.username.to_lowercase());
+        users.truncate(MAX_USER_SEARCH_RESULTS);
+        users
+    }
+    
+    // Get server info
+    fn get_server_info(&self) -> Server {
+        Server {
+            id: self.server_id,
+            name: SERVER_NAME.to_string(),
+            description: Some("A voice, video, and text communication server".to_string()),
+            channels: self.channels.values().cloned().collect(),
+            // Only those connected; clients are told about the rest as they come and go
+            users: self.online_users(None),
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+    
+    // What a user is sent on logging in: everything on a small server, a summary otherwise
+    fn login_server_info(&self) -> Message {
+        let threshold = config::get_config().eager_load_threshold as usize;
+        let user_count = self.online_users(None).len();
+        
+        if self.channels.len() < threshold && user_count < threshold {
+            return Message::ServerInfo { server: self.get_server_info() };
+        }
+        
+        Message::ServerInfoSummary {
+            server_id: self.server_id,
+            name: SERVER_NAME.to_string(),
+            channel_count: self.channels.len() as u32,
+            user_count: user_count as u32,
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+    
+    // Up to `limit` channels ordered by name, starting at `offset`, and how many there are in all
+    fn channel_page(&self, offset: u32, limit: u32) -> (Vec<Channel>, u32) {
+        let mut channels: Vec<&Channel> = self.channels.values().collect();
+        channels.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+        
+        let page = channels
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_CHANNEL_PAGE_SIZE) as usize)
+            .cloned()
+            .collect();
+        (page, self.channels.len() as u32)
+    }
+    
+    // Users online here or on federated servers, or only the members of a channel others can see
+    fn online_users(&self, channel_id: Option<Uuid>) -> Vec<User> {
+        match channel_id {
+            Some(channel_id) => self.visible_channel_members(channel_id)
+                .iter()
+                .filter_map(|id| self.users.get(id))
+                .cloned()
+                .collect(),
+            None => self.users
+                .values()
+                .filter(|u| self.is_connected(u.id))
+                .chain(self.federation.users())
+                .cloned()
+                .collect(),
+        }
+    }
+    
+    // Usernames of everyone logged in here, for federated servers
+    fn online_usernames(&self) -> Vec<String> {
+        let mut usernames: Vec<String> = self.users
+            .values()
+            .filter(|u| self.is_connected(u.id))
+            .map(|u| u.username.clone())
+            .collect();
+        usernames.sort();
+        usernames
+    }
+    
+    // A federated server's list of who is online on it
+    fn update_federated_presence(&mut self, presence: Message, now: u64) -> Result<federation::PresenceChange, Box<Message>> {
+        let peer_url = match self.federation.authenticate(&presence, now) {
+            Some(peer) => peer.url.clone(),
+            None => return Err(Box::new(Message::Error { code: 401, message: "Invalid federation signature".to_string() })),
+        };
+        let Message::FederationPresence { usernames, .. } = presence else {
+            return Err(Box::new(Message::Error { code: 400, message: "Not a presence update".to_string() }));
+        };
+        
+        Ok(self.federation.update_presence(&peer_url, usernames, Instant::now()))
+    }
+    
+    // Deliver a direct message to a user here, or return where to relay it for a federated user.
+    // Users here who are offline get it when they next log in.
+    fn route_direct_message(&mut self, from: Uuid, to: Uuid, content: String, timestamp: u64) -> Result<Option<(config::FederatedServer, Message)>, Box<Message>> {
+        if self.is_connected(to) {
+            self.send_to_user(to, Message::DirectMessage { from_user_id: from, to_user_id: to, content, timestamp });
+            return Ok(None);
+        }
+        
+        let target = match self.federation.user(to) {
+            Some(target) => target,
+            None if self.users.contains_key(&to) => {
+                self.pending_dms.queue(to, Message::DirectMessage { from_user_id: from, to_user_id: to, content, timestamp });
+                return Ok(None);
+            }
+            None => return Err(Box::new(Message::Error { code: 404, message: "User not found".to_string() })),
+        };
+        if !self.federation.allows_dm() {
+            return Err(Box::new(Message::Error { code: 403, message: "Messaging users on other servers is disabled".to_string() }));
+        }
+        
+        let peer = target.federated_from.as_deref().and_then(|url| self.federation.peer(url));
+        let (peer, sender) = match (peer, self.users.get(&from)) {
+            (Some(peer), Some(sender)) => (peer, sender),
+            _ => return Err(Box::new(Message::Error { code: 404, message: "That user isn't online".to_string() })),
+        };
+        
+        let relay = federation::relay(peer, sender.username.clone(), target.username.clone(), content, timestamp);
+        Ok(Some((peer.clone(), relay)))
+    }
+    
+    // Relay a file transfer message to the other user in the transfer, or return an error for
+    // the sender
+    fn relay_file_transfer(&mut self, user_id: Uuid, message: Message) -> Option<Message> {
+        let to = match &message {
+            Message::FileTransferOffer { id, from, to, .. } => {
+                if *from != user_id {
+                    return Some(Message::Error { code: 403, message: "Cannot send files on behalf of another user".to_string() });
+                }
+                if !self.is_connected(*to) {
+                    return Some(Message::Error { code: 404, message: "User is not online".to_string() });
+                }
+                if !self.file_transfers.offer(*id, *from, *to) {
+                    return Some(Message::Error { code: 409, message: "File transfer ID already in use".to_string() });
+                }
+                Some(*to)
+            }
+            Message::FileTransferAccept { id } => self.file_transfers.accept(*id, user_id),
+            Message::FileTransferReject { id, .. } => self.file_transfers.reject(*id, user_id),
+            Message::FileTransferChunk { id, .. } => self.file_transfers.chunk(*id, user_id),
+            Message::FileTransferComplete { id } => self.file_transfers.complete(*id, user_id),
+            _ => None,
+        };
+        
+        match to {
+            Some(to) => {
+                self.send_to_user(to, message);
+                None
+            }
+            None => Some(Message::Error { code: 404, message: "Unknown file transfer".to_string() }),
+        }
+    }
+    
+    // A direct message a federated server relayed for one of its users
+    fn deliver_federated_dm(&mut self, relay: Message, timestamp: u64) -> Result<(), Box<Message>> {
+        let peer_url = match self.federation.authenticate(&relay, timestamp) {
+            Some(peer) => peer.url.clone(),
+            None => return Err(Box::new(Message::Error { code: 401, message: "Invalid federation signature".to_string() })),
+        };
+        let Message::FederationRelay { from_username, target_username, message: content, .. } = relay else {
+            return Err(Box::new(Message::Error { code: 400, message: "Not a relayed message".to_string() }));
+        };
+        if !self.federation.allows_dm() {
+            return Err(Box::new(Message::Error { code: 403, message: "Messages from other servers are disabled".to_string() }));
+        }
+        
+        // The sender's ID is the one this server gave them, from the peer's presence updates
+        let sender = match self.federation.find(&peer_url, &from_username) {
+            Some(sender) => sender.id,
+            None => return Err(Box::new(Message::Error { code: 404, message: format!("{} isn't online on {}", from_username, peer_url) })),
+        };
+        let target = self.database
+            .get_user_id(&target_username)
+            .filter(|&id| self.is_connected(id));
+        let target = match target {
+            Some(target) => target,
+            None => return Err(Box::new(Message::Error { code: 404, message: format!("{} isn't online", target_username) })),
+        };
+        
+        self.send_to_user(target, Message::DirectMessage { from_user_id: sender, to_user_id: target, content, timestamp });
+        Ok(())
+    }
+}
+
+// Tell federated servers who is online here, and forget the users of any that have stopped
+// telling us
+async fn sync_federation(server_state: Arc<Mutex<ServerState>>, tx: Arc<broadcast::Sender<(Uuid, Message)>>) {
+    let mut interval = tokio::time::interval(federation::PRESENCE_INTERVAL);
+    
+    loop {
+        interval.tick().await;
+        
+        let (peers, usernames) = {
+            let mut state = server_state.lock().unwrap();
+            for user_id in state.federation.expire(Instant::now()) {
+                let _ = tx.send((Uuid::nil(), Message::UserLeft { user_id }));
+            }
+            (state.federation.peers().to_vec(), state.online_usernames())
+        };
+        
+        for peer in peers {
+            let presence = federation::presence(&peer, usernames.clone(), announcements::now_secs());
+            if let Err(e) = federation::send_to_peer(&peer, &presence).await {
+                warn!("Failed to send presence to federated server {}: {}", peer.url, e);
+            }
+        }
+    }
+}
+
+// Periodically tell admins how loaded each channel is
+async fn broadcast_stats(server_state: Arc<Mutex<ServerState>>) {
+    let mut interval = tokio::time::interval(STATS_INTERVAL);
+    let mut previous_bytes = HashMap::new();
+    
+    // The first tick completes immediately
+    interval.tick().await;
+    let mut last_tick = Instant::now();
+    
+    loop {
+        interval.tick().await;
+        
+        let mut state = server_state.lock().unwrap();
+        let stats = state.server_statistics(&mut previous_bytes, last_tick.elapsed());
+        state.send_to_admins(Message::ServerStats { stats: stats.clone() });
+        state.latest_stats = Some(stats);
+        
+        last_tick = Instant::now();
+    }
+}
+
+// Send scheduled announcements to everyone connected once they're due
+async fn send_announcements(server_state: Arc<Mutex<ServerState>>, tx: Arc<broadcast::Sender<(Uuid, Message)>>) {
+    let mut interval = tokio::time::interval(ANNOUNCEMENT_CHECK_INTERVAL);
+    
+    loop {
+        interval.tick().await;
+        
+        let due = server_state.lock().unwrap().announcements.take_due(announcements::now_secs());
+        for text in due {
+            info!("Sending scheduled announcement: {}", text);
+            let _ = tx.send((Uuid::nil(), Message::ServerAnnouncement { text }));
+        }
+    }
+}
+
+// Delete chat messages past their retention period once a day
+async fn enforce_retention(server_state: Arc<Mutex<ServerState>>) {
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+    
+    loop {
+        interval.tick().await;
+        server_state.lock().unwrap().apply_retention(announcements::now_secs());
+    }
+}
+
+// Start each channel's media bandwidth allowance afresh every window
+async fn reset_media_rates(server_state: Arc<Mutex<ServerState>>) {
+    let mut interval = tokio::time::interval(MEDIA_RATE_WINDOW);
+    
+    loop {
+        interval.tick().await;
+        
+        let mut state = server_state.lock().unwrap();
+        state.bytes_sent_this_second.clear();
+        state.rate_limited.clear();
+    }
+}
+
+// Delete channels that have gone unused, telling everyone they're gone
+async fn clean_up_idle_channels(server_state: Arc<Mutex<ServerState>>, tx: Arc<broadcast::Sender<(Uuid, Message)>>) {
+    let mut interval = tokio::time::interval(channel_idle::IDLE_CHECK_INTERVAL);
+    
+    loop {
+        interval.tick().await;
+        
+        let channel_ids = server_state.lock().unwrap().remove_idle_channels(Instant::now());
+        if !channel_ids.is_empty() {
+            info!("Deleted {} idle channels", channel_ids.len());
+            let _ = tx.send((Uuid::nil(), Message::ChannelsPurged { channel_ids }));
+        }
+    }
+}
+
+// Log out sessions that have gone past the maximum session duration
+async fn expire_sessions(server_state: Arc<Mutex<ServerState>>, max_duration: Duration) {
+    let mut interval = tokio::time::interval(SESSION_EXPIRY_CHECK_INTERVAL);
+    
+    loop {
+        interval.tick().await;
+        server_state.lock().unwrap().check_session_expiry(max_duration);
+    }
+}
+
+// Random token for renewing a session, hex encoded
+fn new_renew_token() -> String {
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    
+    HEXLOWER.encode(&token)
+}
+
+// Log in with a password. Hashing it takes long enough to hold up every other connection,
+// so it's done on a blocking thread with the server state unlocked.
+async fn login_with_password(server_state: &Arc<Mutex<ServerState>>, session_id: Uuid, username: String, password: String) -> Message {
+    let stored = server_state.lock().unwrap().stored_password(username);
+    let (user_id, hash) = match stored {
+        Ok(stored) => stored,
+        Err(error) => return Message::LoginResponse { success: false, user_id: None, token: None, error: Some(error) },
+    };
+    
+    let check = tokio::task::spawn_blocking(move || PasswordCheck::run(&password, hash.as_deref()))
+        .await
+        .unwrap_or(PasswordCheck::Failed);
+    server_state.lock().unwrap().finish_password_login(session_id, user_id, check)
+}
+
+// Save the current state of a logged-in session to the session store
+async fn persist_session(server_state: &Arc<Mutex<ServerState>>, session_id: Uuid) {
+    let (session_store, stored) = {
+        let state = server_state.lock().unwrap();
+        (Arc::clone(&state.session_store), state.stored_session(session_id))
+    };
+    
+    if let Some(stored) = stored {
+        if let Err(e) = session_store.save_session(&stored).await {
+            error!("Failed to save session for {}: {}", stored.addr, e);
+        }
+    }
+}
+
+// The configured allowlist and blocklist, leaving out entries that don't parse
+fn ip_filter(config: &config::ServerConfig) -> IpFilter {
+    let (filter, errors) = IpFilter::new(&config.ip_allowlist, &config.ip_blocklist);
+    for e in errors {
+        error!("Ignoring IP filter entry: {}", e);
+    }
+    filter
+}
+
+// Channels from the channels file, or the built-in ones when there's no file or it's invalid
+fn initial_channels(config: &config::ServerConfig) -> HashMap<Uuid, Channel> {
+    if let Some(path) = &config.channels_config_file {
+        match channel_config::load(path, config.max_hierarchy_depth) {
+            Ok(Some(definitions)) => {
+                let mut channels = HashMap::new();
+                channel_config::apply(&mut channels, definitions, config.default_audio_quality);
+                info!("Created {} channels from {}", channels.len(), path);
+                return channels;
+            }
+            Ok(None) => warn!("Channels file {} not found, using the built-in channels", path),
+            Err(e) => error!("Invalid channels file, using the built-in channels: {}", e),
+        }
+    }
+    
+    default_channels(config)
+}
+
+fn default_channels(config: &config::ServerConfig) -> HashMap<Uuid, Channel> {
+    let mut channels = HashMap::new();
+    
+    // General channel
+    let general_id = Uuid::new_v4();
+    channels.insert(general_id, Channel {
+        id: general_id,
+        name: "General".to_string(),
+        description: Some("General voice channel".to_string()),
+        parent_id: None,
+        members: Vec::new(),
+        topic: None,
+        e2e_encrypted: false,
+        channel_type: ChannelType::Voice,
+        media_bandwidth_limit_kbps: 0,
+        lobby_mode: false,
+        audio_quality: config.default_audio_quality,
+        override_retention_days: None,
+        permanent: true,
+        idle_timeout_secs: None,
+    });
+    
+    // General text channel
+    let general_text_id = Uuid::new_v4();
+    channels.insert(general_text_id, Channel {
+        id: general_text_id,
+        name: "general-text".to_string(),
+        description: Some("General text chat".to_string()),
+        parent_id: None,
+        members: Vec::new(),
+        topic: None,
+        e2e_encrypted: false,
+        channel_type: ChannelType::Text,
+        media_bandwidth_limit_kbps: 0,
+        lobby_mode: false,
+        audio_quality: config.default_audio_quality,
+        override_retention_days: None,
+        permanent: true,
+        idle_timeout_secs: None,
+    });
+    
+    // Gaming channel
+    let gaming_id = Uuid::new_v4();
+    channels.insert(gaming_id, Channel {
+        id: gaming_id,
+        name: "Gaming".to_string(),
+        description: Some("For gaming sessions".to_string()),
+        parent_id: None,
+        members: Vec::new(),
+        topic: None,
+        e2e_encrypted: false,
+        channel_type: ChannelType::Voice,
+        media_bandwidth_limit_kbps: 0,
+        lobby_mode: false,
+        audio_quality: config.default_audio_quality,
+        override_retention_days: None,
+        permanent: true,
+        idle_timeout_secs: None,
+    });
+    
+    channels
+}
+
+// Walk up the parent chain from `parent_id` and check that `channel_id` is never reached
+fn validate_no_cycle(channel_id: Uuid, parent_id: Uuid, channels: &HashMap<Uuid, Channel>) -> bool {
+    let mut current = Some(parent_id);
+    let mut hops = 0;
+    
+    while let Some(id) = current {
+        if id == channel_id {
+            return false;
+        }
+        
+        // An existing cycle that doesn't involve `channel_id` would otherwise loop forever
+        hops += 1;
+        if hops > channels.len() {
+            return false;
+        }
+        
+        current = channels.get(&id).and_then(|channel| channel.parent_id);
+    }
+    
+    true
+}
+
+// Number of levels from the top of the tree down to and including `channel_id`
+fn channel_depth(channel_id: Uuid, channels: &HashMap<Uuid, Channel>) -> usize {
+    let mut depth = 0;
+    let mut current = Some(channel_id);
+    
+    while let Some(id) = current {
+        depth += 1;
+        if depth > channels.len() {
+            break;
+        }
+        
+        current = channels.get(&id).and_then(|channel| channel.parent_id);
+    }
+    
+    depth
+}
+
+// Number of levels in the subtree rooted at `channel_id`, including the channel itself
+fn subtree_height(channel_id: Uuid, channels: &HashMap<Uuid, Channel>) -> usize {
+    let children_height = channels
+        .values()
+        .filter(|channel| channel.parent_id == Some(channel_id) && channel.id != channel_id)
+        .map(|channel| subtree_height(channel.id, channels))
+        .max()
+        .unwrap_or(0);
+    
+    children_height + 1
+}
+
+// Features this server offers; transcription and recording only when they're switched on
+fn server_features(config: &config::ServerConfig) -> Features {
+    let mut features = Features::E2E_VOICE;
+    features.set(Features::TRANSCRIPTION, config.transcription_enabled);
+    features.set(Features::SERVER_RECORDING, config.recording_enabled);
+    features
+}
+
+// The feature a session must have negotiated to send or be sent a message
+fn required_feature(message: &Message) -> Option<Features> {
+    match message {
+        Message::E2EChannelEnabled { .. } | Message::E2EVoiceKey { .. } | Message::KeyRotationInit { .. } => Some(Features::E2E_VOICE),
+        Message::TranscriptionResult { .. } => Some(Features::TRANSCRIPTION),
+        _ => None,
+    }
+}
+
+// A received message as it's logged, leaving out passwords, TOTP codes and session tokens
+fn loggable(message: &Message) -> String {
+    match message {
+        Message::LoginRequest { username, .. } => format!("LoginRequest {{ username: {:?}, .. }}", username),
+        Message::TOTPResponse { user_id, .. } => format!("TOTPResponse {{ user_id: {}, .. }}", user_id),
+        Message::RenewSession { .. } => "RenewSession { .. }".to_string(),
+        Message::ResumeSession { .. } => "ResumeSession { .. }".to_string(),
+        message => format!("{:?}", message),
+    }
+}
+
+// Echo a diagnostic ping's padding back, after checking it all arrived
+fn diagnostic_pong(payload_size: u32, padding: String) -> Message {
+    if payload_size > MAX_DIAGNOSTIC_PAYLOAD {
+        Message::Error { code: 413, message: format!("Diagnostic pings carry at most {} bytes", MAX_DIAGNOSTIC_PAYLOAD) }
+    } else if padding.len() != payload_size as usize {
+        Message::Error { code: 400, message: format!("Expected {} bytes of padding, got {}", payload_size, padding.len()) }
+    } else {
+        Message::DiagnosticPong { payload_size, padding }
+    }
+}
+
+fn send_to_channel(server_state: &Mutex<ServerState>, channel_id: Uuid, sender_id: Uuid, message: &Message) {
+    if let Some(sender) = server_state.lock().unwrap().get_channel_sender(&channel_id) {
+        let _ = sender.send((sender_id, message.clone()));
+    }
+}
+
+// The next message from any of a session's channels. Deleted channels are dropped, and
+// frames missed by falling behind are skipped, since a late frame is no use anyway.
+async fn recv_channel_media(subscriptions: &mut HashMap<Uuid, broadcast::Receiver<(Uuid, Message)>>) -> (Uuid, Message) {
+    loop {
+        if subscriptions.is_empty() {
+            return std::future::pending().await;
+        }
+        
+        let ((channel_id, result), _, _) = futures_util::future::select_all(
+            subscriptions
+                .iter_mut()
+                .map(|(channel_id, rx)| Box::pin(async move { (*channel_id, rx.recv().await) })),
+        )
+        .await;
+        
+        match result {
+            Ok(media) => return media,
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => {
+                subscriptions.remove(&channel_id);
+            }
+        }
+    }
+}
+
+// Messages aren't sent back to their sender, except voice to a session testing with its own
+fn should_forward(recipient: Option<Uuid>, sender_id: Uuid, broadcast_self: bool, message: &Message) -> bool {
+    match recipient {
+        Some(recipient) if recipient == sender_id => broadcast_self && matches!(message, Message::VoiceData { .. }),
+        _ => true,
+    }
+}
+
+// Handle a client connection
+async fn handle_connection<S>(
+    socket: S,
+    addr: String,
+    server_state: Arc<Mutex<ServerState>>,
+    tx: Arc<broadcast::Sender<(Uuid, Message)>>
+) -> open_reverb_common::error::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    // Create a channel for messages addressed to this session only
+    let (direct_tx, mut direct_rx) = mpsc::unbounded_channel::<Message>();
+    // Fired once the client has been told its session expired, to close the connection
+    let expired = Arc::new(Notify::new());
+    
+    // Add the session
+    let session_id = server_state.lock().unwrap().add_session(addr.clone(), direct_tx);
+    
+    // Create a channel for receiving broadcasts
+    let mut rx = tx.subscribe();
+    
+    // Messages are length-delimited JSON
+    let (reader, writer) = tokio::io::split(socket);
+    let mut reader = FramedRead::new(reader, frame_codec());
+    let mut writer = FramedWrite::new(writer, frame_codec());
+    
+    let mut user_id = None;
+    let mut disconnect_reason = DisconnectReason::Clean;
+    
+    // Everything sent to the client goes through one queue, so only the write task touches
+    // the writer
+    let (out_tx, mut out_rx) = mpsc::channel::<Bytes>(OUTGOING_QUEUE_LEN);
+    let write_task = tokio::spawn(async move {
+        while let Some(frame) = out_rx.recv().await {
+            if writer.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    
+    let simulator = config::get_config()
+        .simulate_network
+        .clone()
+        .map(ServerNetworkSimulator::new);
+    
+    // Setup a task to forward messages from the broadcast channel to this client
+    let server_state_clone = Arc::clone(&server_state);
+    let out_tx_clone = out_tx.clone();
+    let simulator_clone = simulator.clone();
+    let expired_clone = Arc::clone(&expired);
+    let channels_changed = server_state.lock().unwrap().sessions[&session_id].channels_changed.clone();
+    
+    let forward_task = tokio::spawn(async move {
+        // Media comes from the channels the session is in, everything else from the broadcast
+        let mut subscriptions = HashMap::new();
+        
+        loop {
+            let (sender_id, message) = tokio::select! {
+                result = rx.recv() => match result {
+                    Ok(broadcast) => broadcast,
+                    // Missed some while the client was slow, but it should still get what follows
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Session {} fell behind and missed {} broadcasts", session_id, missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                Some(message) = direct_rx.recv() => (Uuid::nil(), message),
+                media = recv_channel_media(&mut subscriptions) => media,
+                _ = channels_changed.notified() => {
+                    server_state_clone.lock().unwrap().sync_channel_subscriptions(session_id, &mut subscriptions);
+                    continue;
+                }
+            };
+            
+            let (current_user_id, broadcast_self, features) = {
+                let state = server_state_clone.lock().unwrap();
+                state.sessions
+                    .get(&session_id)
+                    .map_or((None, false, Features::empty()), |s| (s.user_id, s.broadcast_self, s.features))
+            };
+            
+            let supported = required_feature(&message).is_none_or(|feature| features.contains(feature));
+            if supported && should_forward(current_user_id, sender_id, broadcast_self, &message) {
+                let mut message_bytes = serde_json::to_vec(&message).unwrap_or_default();
+                
+                if let Some(simulator) = &simulator_clone {
+                    match simulator.degrade(message_bytes).await {
+                        Some(degraded) => message_bytes = degraded,
+                        None => continue,
+                    }
+                }
+                
+                if out_tx_clone.send(Bytes::from(message_bytes)).await.is_err() {
+                    break;
+                }
+                
+                if let Message::SessionExpired {} = message {
+                    expired_clone.notify_one();
+                    break;
+                }
+            }
+        }
+    });
+    
+    // Clients that vanish without closing the connection, e.g. on sleep, are pinged and dropped
+    // once they stop answering. Anything the client sends counts as an answer.
+    let ping_interval = Duration::from_secs(config::get_config().ping_interval_secs);
+    let ping_timeout = Duration::from_secs(config::get_config().ping_timeout_secs);
+    let pings_enabled = !ping_interval.is_zero();
+    let mut ping_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + ping_interval,
+        ping_interval.max(Duration::from_secs(1)),
+    );
+    let mut answer_deadline: Option<tokio::time::Instant> = None;
+    let mut ping_nonce: u32 = 0;
+    
+    // Main loop for handling incoming messages
+    loop {
+        let read = tokio::select! {
+            frame = reader.next() => frame,
+            _ = expired.notified() => {
+                info!("Session for {} expired", addr);
+                disconnect_reason = DisconnectReason::Expired;
+                break;
+            }
+            _ = ping_timer.tick(), if pings_enabled => {
+                answer_deadline.get_or_insert_with(|| tokio::time::Instant::now() + ping_timeout);
+                ping_nonce = ping_nonce.wrapping_add(1);
+                if out_tx.send(encode_frame(&Message::Ping { nonce: ping_nonce })?).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            _ = tokio::time::sleep_until(answer_deadline.unwrap_or_else(tokio::time::Instant::now)), if answer_deadline.is_some() => {
+                info!("{} stopped answering pings", addr);
+                disconnect_reason = DisconnectReason::Timeout;
+                break;
+            }
+        };
+        
+        match read {
+            Some(Ok(frame)) => {
+                answer_deadline = None;
+                let mut message_buf = frame.to_vec();
+                
+                if let Some(simulator) = &simulator {
+                    match simulator.degrade(message_buf).await {
+                        Some(degraded) => message_buf = degraded,
+                        None => continue,
+                    }
+                }
+                
+                // Parse message
+                match serde_json::from_slice::<Message>(&message_buf) {
+                    Ok(message) => {
+                        info!("Received message: {}", loggable(&message));
+                        
+                        // Handle message based on type
+                        let features = server_state.lock().unwrap().sessions.get(&session_id).map_or(Features::empty(), |s| s.features);
+                        let response = match message {
+                            Message::Hello { supported_features } => {
+                                let negotiated_features = server_features(config::get_config()).negotiate(supported_features);
+                                if let Some(session) = server_state.lock().unwrap().sessions.get_mut(&session_id) {
+                                    session.features = negotiated_features;
+                                }
+                                Some(Message::HelloAck { negotiated_features })
+                            },
+                            ref message if required_feature(message).is_some_and(|feature| !features.contains(feature)) => {
+                                Some(Message::Error { code: 400, message: "This feature wasn't negotiated for the connection".to_string() })
+                            },
+                            Message::LoginRequest { .. } | Message::TOTPResponse { .. } | Message::ResumeSession { .. } => {
+                                let response = match message {
+                                    Message::LoginRequest { username, password } => {
+                                        login_with_password(&server_state, session_id, username, password).await
+                                    }
+                                    message => server_state.lock().unwrap().handle_login_step(session_id, message),
+                                };
+                                
+                                if let Message::LoginResponse { success: true, user_id: Some(id), .. } = &response {
+                                    user_id = Some(*id);
+                                    
+                                    // Recover the channels of a session that was cut off, e.g. by a server restart
+                                    let session_store = {
+                                        let state = server_state.lock().unwrap();
+                                        Arc::clone(&state.session_store)
+                                    };
+                                    
+                                    match session_store.load_session(*id).await {
+                                        Ok(Some(previous)) => {
+                                            let restored: Vec<Uuid> = {
+                                                let mut state = server_state.lock().unwrap();
+                                                let channels: Vec<Uuid> = previous.channels
+                                                    .into_iter()
+                                                    .filter(|c| state.channels.contains_key(c))
+                                                    .collect();
+                                                
+                                                if let Some(session) = state.sessions.get_mut(&session_id) {
+                                                    session.channels = channels.clone();
+                                                    session.channels_changed.notify_one();
+                                                }
+                                                channels
+                                            };
+                                            
+                                            for channel_id in restored {
+                                                let _ = tx.send((*id, Message::JoinChannel { channel_id, ghost: false }));
+                                            }
+                                        }
+                                        Ok(None) => {}
+                                        Err(e) => error!("Failed to load session for {}: {}", id, e),
+                                    }
+                                    
+                                    persist_session(&server_state, session_id).await;
+                                    
+                                    // Send server info after successful login
+                                    let server_info_msg = server_state.lock().unwrap().login_server_info();
+                                    
+                                    // First send login response, then server info
+                                    if out_tx.send(encode_frame(&response)?).await.is_err()
+                                        || out_tx.send(encode_frame(&server_info_msg)?).await.is_err()
+                                    {
+                                        break;
+                                    }
+                                    
+                                    // Everyone else adds the user to their list
+                                    let user = server_state.lock().unwrap().users.get(id).cloned();
+                                    if let Some(user) = user {
+                                        let _ = tx.send((*id, Message::UserJoined { user }));
+                                    }
+                                    
+                                    // Public keys of users already online, for end-to-end encrypted channels
+                                    {
+                                        let mut state = server_state.lock().unwrap();
+                                        for (uid, public_key) in &state.public_keys {
+                                            state.send_to_user(*id, Message::E2EPublicKey { user_id: *uid, public_key: public_key.clone() });
+                                        }
+                                        
+                                        if let Some(content) = state.welcome.for_login(*id) {
+                                            state.send_to_session(session_id, Message::WelcomeMessage { content });
+                                        }
+                                        
+                                        // Direct messages sent while they were away
+                                        for message in state.pending_dms.take(*id) {
+                                            state.send_to_session(session_id, message);
+                                        }
+                                    }
+                                    
+                                    // No need for another response
+                                    continue;
+                                }
+                                
+                                Some(response)
+                            },
+                            Message::Ping { nonce } => {
+                                Some(Message::Pong { nonce })
+                            },
+                            Message::GetChannels { offset, limit } => {
+                                if user_id.is_none() {
+                                    Some(Message::Error { code: 401, message: "Not logged in".to_string() })
+                                } else {
+                                    let (channels, total) = server_state.lock().unwrap().channel_page(offset, limit);
+                                    Some(Message::ChannelList { channels, total })
+                                }
+                            },
+                            Message::GetOnlineUsers { channel_id } => {
+                                if user_id.is_none() {
+                                    Some(Message::Error { code: 401, message: "Not logged in".to_string() })
+                                } else {
+                                    Some(Message::UserList { users: server_state.lock().unwrap().online_users(channel_id) })
+                                }
+                            },
+                            Message::DiagnosticPing { payload_size, padding } => {
+                                Some(diagnostic_pong(payload_size, padding))
+                            },
+                            Message::SchemaVersionMismatch { server_version, client_version } => {
+                                warn!("Client at {} has schema version {}, the server {}", addr, client_version, server_version);
+                                None
+                            },
+                            Message::CreateChannel { name, description, parent_id, channel_type, media_bandwidth_limit_kbps, audio_quality, permanent, idle_timeout_secs } => {
+                                if let Some(creator) = user_id {
+                                    let now = Instant::now();
+                                    let new_channel = NewChannel { name, description, parent_id, channel_type, media_bandwidth_limit_kbps, audio_quality, permanent, idle_timeout_secs };
+                                    let result = {
+                                        let mut state = server_state.lock().unwrap();
+                                        let total_channels = state.channels.len();
+                                        let is_admin = state.is_admin(creator);
+                                        state.channel_limiter.check(creator, total_channels, now).map(|()| {
+                                            let created = state.create_channel(new_channel, is_admin);
+                                            if created.is_ok() {
+                                                state.channel_limiter.record(creator, now);
+                                            }
+                                            created
+                                        })
+                                    };
+                                    
+                                    match result {
+                                        Err(limit) => {
+                                            warn!("Refused a channel from {}: {}", addr, limit.message());
+                                            Some(Message::Error { code: limit.code(), message: limit.message().to_string() })
+                                        }
+                                        Ok(Ok(channel)) => {
+                                            let channel_id = channel.id;
+                                            
+                                            // Broadcast the new channel to everyone, including the creator
+                                            let _ = tx.send((Uuid::nil(), Message::ChannelUpdate { channel }));
+                                            
+                                            Some(Message::CreateChannelResponse {
+                                                success: true,
+                                                channel_id: Some(channel_id),
+                                                error: None,
+                                            })
+                                        }
+                                        Ok(Err(e)) => Some(Message::CreateChannelResponse {
+                                            success: false,
+                                            channel_id: None,
+                                            error: Some(e),
+                                        }),
+                                    }
+                                } else {
+                                    Some(Message::CreateChannelResponse {
+                                        success: false,
+                                        channel_id: None,
+                                        error: Some("Not logged in".to_string()),
+                                    })
+                                }
+                            },
+                            Message::DeleteChannel { channel_id } => {
+                                match user_id {
+                                    None => Some(Message::Error { code: 401, message: "Not logged in".to_string() }),
+                                    Some(id) => {
+                                        let mut state = server_state.lock().unwrap();
+                                        let is_admin = state.is_admin(id);
+                                        match state.delete_channel(channel_id, is_admin) {
+                                            Ok(members) => {
+                                                info!("{} deleted channel {}", addr, channel_id);
+                                                for member in members {
+                                                    state.send_to_session(member, Message::LeaveChannel { channel_id });
+                                                }
+                                                let _ = tx.send((Uuid::nil(), Message::ChannelDeleted { channel_id }));
+                                                None
+                                            }
+                                            Err(e) => Some(Message::Error { code: 400, message: e }),
+                                        }
+                                    }
+                                }
+                            },
+                            Message::RecordingConsent { recording_user_id, recording_started } => {
+                                if user_id != Some(recording_user_id) {
+                                    Some(Message::Error { code: 403, message: "Cannot record on behalf of another user".to_string() })
+                                } else {
+                                    let request_id = {
+                                        let mut state = server_state.lock().unwrap();
+                                        state.handle_recording_request(recording_user_id, recording_started, config::get_config().require_recording_consent)
+                                    };
+                                    
+                                    // Refuse the recording if consent isn't collected in time
+                                    if let Some(request_id) = request_id {
+                                        let server_state = Arc::clone(&server_state);
+                                        tokio::spawn(async move {
+                                            tokio::time::sleep(RECORDING_CONSENT_TIMEOUT).await;
+                                            
+                                            let mut state = server_state.lock().unwrap();
+                                            let timed_out = state.pending_recordings
+                                                .get(&recording_user_id)
+                                                .is_some_and(|pending| pending.request_id == request_id);
+                                            
+                                            if timed_out {
+                                                state.refuse_recording(recording_user_id);
+                                            }
+                                        });
+                                    }
+                                    
+                                    None
+                                }
+                            },
+                            Message::ConsentAcknowledged { user_id: uid } | Message::ConsentRejected { user_id: uid } if user_id != Some(uid) => {
+                                Some(Message::Error { code: 403, message: "Cannot answer on behalf of another user".to_string() })
+                            },
+                            Message::ConsentAcknowledged { user_id: uid } => {
+                                let mut state = server_state.lock().unwrap();
+                                state.handle_consent_response(uid, true);
+                                None
+                            },
+                            Message::ConsentRejected { user_id: uid } => {
+                                let mut state = server_state.lock().unwrap();
+                                state.handle_consent_response(uid, false);
+                                None
+                            },
+                            Message::UpdateChannel { channel_id, parent_id } => {
+                                match user_id {
+                                    None => Some(Message::Error { code: 401, message: "Not logged in".to_string() }),
+                                    Some(id) if !server_state.lock().unwrap().is_admin(id) => {
+                                        Some(Message::Error { code: 403, message: "Only admins can move channels".to_string() })
+                                    }
+                                    Some(_) => {
+                                        let result = {
+                                            let mut state = server_state.lock().unwrap();
+                                            state.update_channel(channel_id, parent_id)
+                                        };
+                                        
+                                        match result {
+                                            Ok(channel) => {
+                                                let _ = tx.send((Uuid::nil(), Message::ChannelUpdate { channel }));
+                                                None
+                                            }
+                                            Err(e) => Some(Message::Error { code: 400, message: e }),
+                                        }
+                                    }
+                                }
+                            },
+                            Message::AuditLogQuery { user_id: query_user_id, since, limit } => {
+                                let state = server_state.lock().unwrap();
+                                if user_id.is_some_and(|id| state.is_admin(id)) {
+                                    Some(Message::AuditLogEntries { entries: state.audit_log.query(query_user_id, since, limit) })
+                                } else {
+                                    Some(Message::Error { code: 403, message: "Only admins can read the audit log".to_string() })
+                                }
+                            },
+                            Message::ReloadChannelConfig {} => {
+                                let mut state = server_state.lock().unwrap();
+                                if !user_id.is_some_and(|id| state.is_admin(id)) {
+                                    Some(Message::Error { code: 403, message: "Only admins can reload the channels file".to_string() })
+                                } else {
+                                    match state.reload_channel_config() {
+                                        Ok(changed) => {
+                                            info!("Reloaded the channels file, {} channels changed", changed.len());
+                                            for channel in changed {
+                                                let _ = tx.send((Uuid::nil(), Message::ChannelUpdate { channel }));
+                                            }
+                                            None
+                                        }
+                                        Err(e) => Some(Message::Error { code: 400, message: e }),
+                                    }
+                                }
+                            },
+                            Message::PurgeChannels { prefix } => {
+                                let mut state = server_state.lock().unwrap();
+                                if !user_id.is_some_and(|id| state.is_admin(id)) {
+                                    Some(Message::Error { code: 403, message: "Only admins can purge channels".to_string() })
+                                } else {
+                                    match state.purge_channels(&prefix) {
+                                        Ok(channel_ids) => {
+                                            info!("Purged {} channels starting with {:?}", channel_ids.len(), prefix);
+                                            let _ = tx.send((Uuid::nil(), Message::ChannelsPurged { channel_ids }));
+                                            None
+                                        }
+                                        Err(e) => Some(Message::Error { code: 400, message: e }),
+                                    }
+                                }
+                            },
+                            Message::AddAllowlistEntry { entry } => {
+                                let mut state = server_state.lock().unwrap();
+                                if !user_id.is_some_and(|id| state.is_admin(id)) {
+                                    Some(Message::Error { code: 403, message: "Only admins can change the allowlist".to_string() })
+                                } else {
+                                    match state.ip_filter.allow(&entry) {
+                                        Ok(()) => {
+                                            info!("Added {} to the IP allowlist", entry);
+                                            None
+                                        }
+                                        Err(e) => Some(Message::Error { code: 400, message: e }),
+                                    }
+                                }
+                            },
+                            Message::GetServerStats {} => {
+                                let state = server_state.lock().unwrap();
+                                if user_id.is_some_and(|id| state.is_admin(id)) {
+                                    // Before the first broadcast there are no rates to report yet
+                                    let stats = state.latest_stats.clone()
+                                        .unwrap_or_else(|| state.server_statistics(&mut HashMap::new(), Duration::ZERO));
+                                    Some(Message::ServerStats { stats })
+                                } else {
+                                    Some(Message::Error { code: 403, message: "Only admins can read server statistics".to_string() })
+                                }
+                            },
+                            Message::ScheduleAnnouncement { .. } | Message::ListAnnouncements {} | Message::DeleteAnnouncement { .. }
+                                if !user_id.is_some_and(|id| server_state.lock().unwrap().is_admin(id)) =>
+                            {
+                                Some(Message::Error { code: 403, message: "Only admins can manage announcements".to_string() })
+                            },
+                            Message::ScheduleAnnouncement { message, send_at } => {
+                                let message = message.trim().to_string();
+                                
+                                if message.is_empty() {
+                                    Some(Message::Error { code: 400, message: "Announcement is empty".to_string() })
+                                } else if message.chars().count() > MAX_ANNOUNCEMENT_LENGTH {
+                                    Some(Message::Error {
+                                        code: 400,
+                                        message: format!("Announcements cannot be longer than {} characters", MAX_ANNOUNCEMENT_LENGTH),
+                                    })
+                                } else {
+                                    let mut state = server_state.lock().unwrap();
+                                    state.announcements.schedule(message, send_at);
+                                    Some(Message::Announcements { announcements: state.announcements.list() })
+                                }
+                            },
+                            Message::ListAnnouncements {} => {
+                                let announcements = server_state.lock().unwrap().announcements.list();
+                                Some(Message::Announcements { announcements })
+                            },
+                            Message::DeleteAnnouncement { id } => {
+                                let mut state = server_state.lock().unwrap();
+                                if state.announcements.remove(id) {
+                                    Some(Message::Announcements { announcements: state.announcements.list() })
+                                } else {
+                                    Some(Message::Error { code: 404, message: "Announcement not found".to_string() })
+                                }
+                            },
+                            Message::SetChannelAudioQuality { .. } if !user_id.is_some_and(|id| server_state.lock().unwrap().is_admin(id)) => {
+                                Some(Message::Error { code: 403, message: "Only admins can change a channel's audio quality".to_string() })
+                            },
+                            Message::SetChannelAudioQuality { channel_id, quality } => {
+                                let result = server_state.lock().unwrap().set_audio_quality(channel_id, quality);
+                                
+                                // Members pick the new quality up from the update
+                                match result {
+                                    Ok(channel) => {
+                                        let _ = tx.send((Uuid::nil(), Message::ChannelUpdate { channel }));
+                                        None
+                                    }
+                                    Err(e) => Some(Message::Error { code: 400, message: e }),
+                                }
+                            },
+                            Message::RetentionPolicyUpdate { .. } if !user_id.is_some_and(|id| server_state.lock().unwrap().is_admin(id)) => {
+                                Some(Message::Error { code: 403, message: "Only admins can change how long messages are kept".to_string() })
+                            },
+                            Message::RetentionPolicyUpdate { channel_id, retention_days } => {
+                                let mut state = server_state.lock().unwrap();
+                                
+                                match state.set_retention_days(channel_id, retention_days) {
+                                    Ok(channel) => {
+                                        // Anything already past the new period goes straight away
+                                        state.apply_retention(announcements::now_secs());
+                                        let _ = tx.send((Uuid::nil(), Message::ChannelUpdate { channel }));
+                                        None
+                                    }
+                                    Err(e) => Some(Message::Error { code: 400, message: e }),
+                                }
+                            },
+                            Message::ExportMessages { .. } if !user_id.is_some_and(|id| server_state.lock().unwrap().is_admin(id)) => {
+                                Some(Message::Error { code: 403, message: "Only admins can export chat history".to_string() })
+                            },
+                            Message::ExportMessages { start_ts, end_ts, .. } if start_ts > end_ts => {
+                                Some(Message::Error { code: 400, message: "Export range ends before it starts".to_string() })
+                            },
+                            Message::ExportMessages { channel_id, start_ts, end_ts, format } => {
+                                let state = server_state.lock().unwrap();
+                                
+                                match state.export_messages(channel_id, start_ts, end_ts, format) {
+                                    Ok(data) => {
+                                        info!("Exporting {} bytes of chat history from channel {}", data.len(), channel_id);
+                                        
+                                        let transfer_id = Uuid::new_v4();
+                                        let chunks = export::chunks(&data);
+                                        let chunk_count = chunks.len() as u32;
+                                        for (chunk_index, chunk) in chunks.into_iter().enumerate() {
+                                            state.send_to_session(session_id, Message::ExportData {
+                                                transfer_id,
+                                                format,
+                                                chunk_index: chunk_index as u32,
+                                                chunk_count,
+                                                data: chunk.to_vec(),
+                                            });
+                                        }
+                                        None
+                                    }
+                                    Err(e) => Some(Message::Error { code: 404, message: e }),
+                                }
+                            },
+                            Message::SetLobbyMode { .. } | Message::ApprovePending { .. } | Message::RejectPending { .. }
+                                if !user_id.is_some_and(|id| server_state.lock().unwrap().is_admin(id)) =>
+                            {
+                                Some(Message::Error { code: 403, message: "Only admins can manage channel lobbies".to_string() })
+                            },
+                            Message::SetLobbyMode { channel_id, enabled } => {
+                                let result = server_state.lock().unwrap().set_lobby_mode(channel_id, enabled);
+                                
+                                match result {
+                                    Ok(channel) => {
+                                        let _ = tx.send((Uuid::nil(), Message::ChannelUpdate { channel }));
+                                        None
+                                    }
+                                    Err(e) => Some(Message::Error { code: 404, message: e }),
+                                }
+                            },
+                            Message::ApprovePending { user_id: pending_user_id, channel_id } => {
+                                let admitted = server_state.lock().unwrap().admit_from_lobby(pending_user_id, channel_id);
+                                
+                                match admitted {
+                                    Some((session_ids, members)) => {
+                                        for pending_session in session_ids {
+                                            persist_session(&server_state, pending_session).await;
+                                        }
+                                        
+                                        // The same as an ordinary join, on behalf of the waiting user
+                                        let state = server_state.lock().unwrap();
+                                        state.notify_e2e_members(channel_id, pending_user_id);
+                                        let _ = tx.send((pending_user_id, Message::JoinChannel { channel_id, ghost: false }));
+                                        state.send_to_user(pending_user_id, Message::ChannelMediaState { channel_id, members });
+                                        if let Some(audio_quality) = state.channels.get(&channel_id).map(|c| c.audio_quality) {
+                                            state.send_to_user(pending_user_id, Message::JoinChannelAck { channel_id, audio_quality });
+                                        }
+                                        None
+                                    }
+                                    None => Some(Message::Error { code: 404, message: "User is not waiting to join the channel".to_string() }),
+                                }
+                            },
+                            Message::RejectPending { user_id: pending_user_id, channel_id, reason } => {
+                                let mut state = server_state.lock().unwrap();
+                                if state.lobby.resolve(pending_user_id, channel_id) {
+                                    state.send_to_user(pending_user_id, Message::JoinRejected { channel_id, reason });
+                                    None
+                                } else {
+                                    Some(Message::Error { code: 404, message: "User is not waiting to join the channel".to_string() })
+                                }
+                            },
+                            Message::SetChannelTopic { channel_id, topic } => {
+                                match user_id {
+                                    None => Some(Message::Error { code: 401, message: "Not logged in".to_string() }),
+                                    Some(id) if !server_state.lock().unwrap().is_admin(id) => {
+                                        Some(Message::Error { code: 403, message: "Only admins can change a channel's topic".to_string() })
+                                    }
+                                    Some(_) => {
+                                        let result = {
+                                            let mut state = server_state.lock().unwrap();
+                                            state.set_channel_topic(channel_id, topic)
+                                        };
+                                        
+                                        match result {
+                                            Ok(channel) => {
+                                                let _ = tx.send((Uuid::nil(), Message::ChannelUpdate { channel }));
+                                                None
+                                            }
+                                            Err(e) => Some(Message::Error { code: 400, message: e }),
+                                        }
+                                    }
+                                }
+                            },
+                            Message::TextMessage { user_id: uid, .. } if user_id != Some(uid) => {
+                                Some(Message::Error { code: 403, message: "Cannot send a message as another user".to_string() })
+                            },
+                            Message::TextMessage { user_id: uid, channel_id, content, .. } => {
+                                let content = content.trim().to_string();
+                                let is_member = server_state.lock().unwrap().channel_members(channel_id).contains(&uid);
+                                
+                                if !is_member {
+                                    Some(Message::Error { code: 403, message: "Not a member of this channel".to_string() })
+                                } else if content.is_empty() {
+                                    Some(Message::Error { code: 400, message: "Message is empty".to_string() })
+                                } else if content.chars().count() > MAX_TEXT_MESSAGE_LENGTH {
+                                    Some(Message::Error {
+                                        code: 400,
+                                        message: format!("Messages cannot be longer than {} characters", MAX_TEXT_MESSAGE_LENGTH),
+                                    })
+                                } else {
+                                    let message_id = Uuid::new_v4();
+                                    let timestamp = server_state
+                                        .lock()
+                                        .unwrap()
+                                        .archive_text_message(message_id, channel_id, uid, &content, announcements::now_secs());
+                                    
+                                    // The sender gets it back too, so everyone sees the same timestamp and order
+                                    let _ = tx.send((Uuid::nil(), Message::TextMessage { message_id, user_id: uid, channel_id, content, timestamp }));
+                                    None
+                                }
+                            },
+                            Message::DirectMessage { to_user_id, content, .. } => {
+                                let content = content.trim().to_string();
+                                
+                                match user_id {
+                                    None => Some(Message::Error { code: 401, message: "Not logged in".to_string() }),
+                                    Some(_) if content.is_empty() => Some(Message::Error { code: 400, message: "Message is empty".to_string() }),
+                                    Some(_) if content.chars().count() > MAX_TEXT_MESSAGE_LENGTH => Some(Message::Error {
+                                        code: 400,
+                                        message: format!("Messages cannot be longer than {} characters", MAX_TEXT_MESSAGE_LENGTH),
+                                    }),
+                                    Some(from) => {
+                                        let route = server_state.lock().unwrap().route_direct_message(from, to_user_id, content, announcements::now_secs());
+                                        match route {
+                                            Ok(Some((peer, relay))) => {
+                                                tokio::spawn(async move {
+                                                    if let Err(e) = federation::send_to_peer(&peer, &relay).await {
+                                                        warn!("Failed to relay a direct message to {}: {}", peer.url, e);
+                                                    }
+                                                });
+                                                None
+                                            }
+                                            Ok(None) => None,
+                                            Err(error) => Some(*error),
+                                        }
+                                    }
+                                }
+                            },
+                            Message::FileTransferOffer { .. }
+                            | Message::FileTransferAccept { .. }
+                            | Message::FileTransferReject { .. }
+                            | Message::FileTransferChunk { .. }
+                            | Message::FileTransferComplete { .. } => match user_id {
+                                None => Some(Message::Error { code: 401, message: "Not logged in".to_string() }),
+                                Some(uid) => server_state.lock().unwrap().relay_file_transfer(uid, message.clone()),
+                            },
+                            Message::FederationPresence { .. } => {
+                                let change = server_state.lock().unwrap().update_federated_presence(message.clone(), announcements::now_secs());
+                                match change {
+                                    Ok(change) => {
+                                        for user in change.joined {
+                                            let _ = tx.send((Uuid::nil(), Message::UserJoined { user }));
+                                        }
+                                        for user_id in change.left {
+                                            let _ = tx.send((Uuid::nil(), Message::UserLeft { user_id }));
+                                        }
+                                        None
+                                    }
+                                    Err(error) => {
+                                        warn!("Refused federation presence from {}", addr);
+                                        Some(*error)
+                                    }
+                                }
+                            },
+                            Message::FederationRelay { message: ref content, .. } => {
+                                if content.chars().count() > MAX_TEXT_MESSAGE_LENGTH {
+                                    Some(Message::Error { code: 400, message: "Message is too long".to_string() })
+                                } else {
+                                    let mut state = server_state.lock().unwrap();
+                                    state.deliver_federated_dm(message.clone(), announcements::now_secs()).err().map(|error| *error)
+                                }
+                            },
+                            Message::SetBroadcastSelf { enabled } => {
+                                if user_id.is_none() {
+                                    Some(Message::Error { code: 401, message: "Not logged in".to_string() })
+                                } else {
+                                    if let Some(session) = server_state.lock().unwrap().sessions.get_mut(&session_id) {
+                                        session.broadcast_self = enabled;
+                                    }
+                                    None
+                                }
+                            },
+                            Message::GetSpeakingStats { channel_id } => {
+                                let state = server_state.lock().unwrap();
+                                
+                                if !config::get_config().speaking_stats_enabled {
+                                    Some(Message::Error { code: 403, message: "Speaking stats are disabled on this server".to_string() })
+                                } else if !user_id.is_some_and(|id| state.channel_members(channel_id).contains(&id)) {
+                                    Some(Message::Error { code: 403, message: "Not a member of this channel".to_string() })
+                                } else {
+                                    Some(Message::SpeakingStats { channel_id, stats: state.speaking_stats(channel_id) })
+                                }
+                            },
+                            Message::SearchMessages { query, channel_ids, limit, offset } => {
+                                match user_id {
+                                    None => Some(Message::Error { code: 401, message: "Not logged in".to_string() }),
+                                    Some(id) => {
+                                        let results = server_state.lock().unwrap().search_messages(id, &query, &channel_ids, limit, offset);
+                                        Some(Message::SearchResults { results })
+                                    }
+                                }
+                            },
+                            Message::RenewSession { token } => {
+                                if user_id.is_none() {
+                                    Some(Message::Error { code: 401, message: "Not logged in".to_string() })
+                                } else if server_state.lock().unwrap().renew_session(session_id, &token) {
+                                    None
+                                } else {
+                                    Some(Message::Error { code: 401, message: "Session could not be renewed".to_string() })
+                                }
+                            },
+                            Message::SearchUsers { query, include_offline } => {
+                                if user_id.is_none() {
+                                    Some(Message::Error { code: 401, message: "Not logged in".to_string() })
+                                } else {
+                                    let users = server_state.lock().unwrap().search_users(query.trim(), include_offline);
+                                    Some(Message::SearchUsersResult { users })
+                                }
+                            },
+                            Message::E2EPublicKey { user_id: uid, .. } if user_id != Some(uid) => {
+                                Some(Message::Error { code: 403, message: "Cannot announce a key for another user".to_string() })
+                            },
+                            Message::E2EPublicKey { user_id: uid, ref public_key } => {
+                                server_state.lock().unwrap().public_keys.insert(uid, public_key.clone());
+                                
+                                // Everyone may end up sharing an encrypted channel with this user
+                                let _ = tx.send((uid, message.clone()));
+                                
+                                None
+                            },
+                            Message::E2EChannelEnabled { .. } if !user_id.is_some_and(|id| server_state.lock().unwrap().is_admin(id)) => {
+                                Some(Message::Error { code: 403, message: "Only admins can encrypt a channel".to_string() })
+                            },
+                            Message::E2EChannelEnabled { channel_id } => {
+                                let result = server_state.lock().unwrap().enable_e2e(channel_id);
+                                
+                                match result {
+                                    Ok(channel) => {
+                                        let _ = tx.send((Uuid::nil(), Message::E2EChannelEnabled { channel_id }));
+                                        let _ = tx.send((Uuid::nil(), Message::ChannelUpdate { channel }));
+                                        
+                                        server_state.lock().unwrap().notify_e2e_members(channel_id, Uuid::nil());
+                                        None
+                                    }
+                                    Err(e) => Some(Message::Error { code: 400, message: e }),
+                                }
+                            },
+                            Message::E2EVoiceKey { sender_id, .. } if user_id != Some(sender_id) => {
+                                Some(Message::Error { code: 403, message: "Cannot send a key on behalf of another user".to_string() })
+                            },
+                            Message::E2EVoiceKey { recipient_id, .. } => {
+                                // The key is wrapped for the recipient, so it's passed on as is
+                                server_state.lock().unwrap().send_to_user(recipient_id, message.clone());
+                                None
+                            },
+                            Message::KeyRotationInit { initiator_id, .. } if user_id != Some(initiator_id) => {
+                                Some(Message::Error { code: 403, message: "Cannot rotate a key on behalf of another user".to_string() })
+                            },
+                            Message::KeyRotationInit { channel_id, initiator_id, .. } => {
+                                let state = server_state.lock().unwrap();
+                                let members = state.channel_members(channel_id);
+                                
+                                if !members.contains(&initiator_id) {
+                                    Some(Message::Error { code: 403, message: "Not a member of this channel".to_string() })
+                                } else {
+                                    for member in members.into_iter().filter(|m| *m != initiator_id) {
+                                        state.send_to_user(member, message.clone());
+                                    }
+                                    None
+                                }
+                            },
+                            Message::VideoQualityUpdate { user_id: uid, .. } if user_id != Some(uid) => {
+                                Some(Message::Error { code: 403, message: "Cannot update video on behalf of another user".to_string() })
+                            },
+                            Message::VideoQualityUpdate { user_id: uid, channel_id, quality } => {
+                                let mut state = server_state.lock().unwrap();
+                                let members = state.channel_members(channel_id);
+                                
+                                if !members.contains(&uid) {
+                                    Some(Message::Error { code: 403, message: "Not a member of this channel".to_string() })
+                                } else {
+                                    // Clients pause their video when privacy mode blurs it
+                                    if quality == VideoQuality::Paused {
+                                        state.audit_log.privacy_mode_triggered(session_id);
+                                    }
+                                    
+                                    for member in members.into_iter().filter(|m| *m != uid) {
+                                        state.send_to_user(member, message.clone());
+                                    }
+                                    None
+                                }
+                            },
+                            Message::SDPOffer { from_user_id: from, .. }
+                            | Message::SDPAnswer { from_user_id: from, .. }
+                            | Message::ICECandidate { from, .. }
+                                if user_id != Some(from) =>
+                            {
+                                Some(Message::Error { code: 403, message: "Cannot signal on behalf of another user".to_string() })
+                            },
+                            Message::SDPOffer { to_user_id: to, .. }
+                            | Message::SDPAnswer { to_user_id: to, .. }
+                            | Message::ICECandidate { to, .. } => {
+                                // Only ever relayed to the user it's addressed to
+                                let state = server_state.lock().unwrap();
+                                if state.is_connected(to) {
+                                    state.send_to_user(to, message.clone());
+                                    None
+                                } else {
+                                    Some(Message::Error { code: 404, message: "User is not online".to_string() })
+                                }
+                            },
+                            Message::StatusUpdate { user_id, status } => {
+                                // Update user status
+                                let user = {
+                                    let mut state = server_state.lock().unwrap();
+                                    state.users.get_mut(&user_id).map(|user| {
+                                        user.status = status;
+                                        user.clone()
+                                    })
+                                };
+                                
+                                // Broadcast the updated user to all clients
+                                if let Some(user) = user {
+                                    let _ = tx.send((user_id, Message::UserUpdated { user }));
+                                }
+                                
+                                None
+                            },
+                            Message::JoinChannel { .. } | Message::LeaveChannel { .. } if user_id.is_none() => {
+                                Some(Message::Error { code: 401, message: "Not logged in".to_string() })
+                            },
+                            Message::JoinChannel { ghost: true, .. }
+                                if !user_id.is_some_and(|id| server_state.lock().unwrap().can_ghost(id)) =>
+                            {
+                                Some(Message::Error { code: 403, message: "Not allowed to join as a ghost".to_string() })
+                            },
+                            Message::JoinChannel { channel_id, .. }
+                                if server_state.lock().unwrap().must_wait_in_lobby(user_id, channel_id) =>
+                            {
+                                let Some(waiting_user_id) = user_id else { continue };
+                                let mut state = server_state.lock().unwrap();
+                                
+                                // Admins already know about a user asking again
+                                if !state.lobby.is_waiting(waiting_user_id, channel_id) {
+                                    state.lobby.wait(waiting_user_id, channel_id);
+                                    
+                                    if let Some(user) = state.users.get(&waiting_user_id).cloned() {
+                                        state.send_to_admins(Message::JoinPending { user, channel_id });
+                                    }
+                                }
+                                
+                                Some(Message::WaitingForApproval { channel_id })
+                            },
+                            Message::JoinChannel { channel_id, ghost } => {
+                                // Logged in, or answered above
+                                let Some(joiner) = user_id else { continue };
+                                
+                                // Add user to channel
+                                let members = {
+                                    let mut state = server_state.lock().unwrap();
+                                    if !state.channels.contains_key(&channel_id) {
+                                        None
+                                    } else {
+                                        if let Some(session) = state.sessions.get_mut(&session_id) {
+                                            if !session.channels.contains(&channel_id) {
+                                                session.channels.push(channel_id);
+                                                session.channels_changed.notify_one();
+                                            }
+                                            session.ghost_mode = ghost;
+                                        }
+                                        state.channel_activity.touch(channel_id, Instant::now());
+                                        
+                                        // Ghosts see themselves, but nobody else sees them
+                                        let mut members = state.visible_channel_members(channel_id);
+                                        if ghost {
+                                            state.notify_ghost_members(channel_id);
+                                            members.push(joiner);
+                                        }
+                                        Some(members)
+                                    }
+                                };
+                                
+                                match members {
+                                    Some(members) => {
+                                        persist_session(&server_state, session_id).await;
+                                        
+                                        {
+                                            let mut state = server_state.lock().unwrap();
+                                            
+                                            // The joiner gets the members in the reply
+                                            state.notify_e2e_members(channel_id, joiner);
+                                            state.recording_member_joined(channel_id, joiner);
+                                            
+                                            // Goes out after the reply, through the session's own queue
+                                            if let Some(audio_quality) = state.channels.get(&channel_id).map(|c| c.audio_quality) {
+                                                state.send_to_session(session_id, Message::JoinChannelAck { channel_id, audio_quality });
+                                            }
+                                            state.send_recent_messages(session_id, channel_id);
+                                        }
+                                        
+                                        // Broadcast to all clients
+                                        if !ghost {
+                                            let _ = tx.send((joiner, message.clone()));
+                                        }
+                                        
+                                        // Confirm the join to the user
+                                        Some(Message::ChannelMediaState { channel_id, members })
+                                    }
+                                    None => Some(Message::Error {
+                                        code: 404,
+                                        message: "Channel not found".to_string(),
+                                    }),
+                                }
+                            },
+                            Message::LeaveChannel { channel_id } => {
+                                // Logged in, or answered above
+                                let Some(leaver) = user_id else { continue };
+                                
+                                // Remove user from channel
+                                let was_ghost = {
+                                    let mut state = server_state.lock().unwrap();
+                                    let was_ghost = match state.sessions.get_mut(&session_id) {
+                                        Some(session) => {
+                                            session.speaking_time.left(channel_id, Instant::now(), &session.channels);
+                                            session.channels.retain(|&id| id != channel_id);
+                                            session.channels_changed.notify_one();
+                                            std::mem::take(&mut session.ghost_mode)
+                                        }
+                                        None => false,
+                                    };
+                                    if was_ghost {
+                                        state.notify_ghost_members(channel_id);
+                                    }
+                                    
+                                    if !state.channel_members(channel_id).contains(&leaver) {
+                                        state.recording_member_left(channel_id, leaver);
+                                        
+                                        // Takes down the recording notice
+                                        if state.recorder.is_some() {
+                                            state.send_to_user(leaver, Message::RecordingConsent {
+                                                recording_user_id: Uuid::nil(),
+                                                recording_started: false,
+                                            });
+                                        }
+                                    }
+                                    was_ghost
+                                };
+                                
+                                persist_session(&server_state, session_id).await;
+                                
+                                // Broadcast to all clients, who never heard a ghost arrive
+                                if !was_ghost {
+                                    let _ = tx.send((leaver, message.clone()));
+                                }
+                                
+                                None
+                            },
+                            Message::VoiceData { user_id, channel_id, .. }
+                            | Message::VideoData { user_id, channel_id, .. }
+                            | Message::ScreenShareData { user_id, channel_id, .. }
+                                if !server_state.lock().unwrap().may_send_media(session_id, user_id, channel_id) =>
+                            {
+                                Some(Message::Error { code: 403, message: "Cannot send media as another user or to a channel you haven't joined".to_string() })
+                            },
+                            Message::VoiceData { channel_id, .. }
+                            | Message::VideoData { channel_id, .. }
+                            | Message::ScreenShareData { channel_id, .. }
+                                if server_state.lock().unwrap().is_text_channel(channel_id) =>
+                            {
+                                Some(Message::Error { code: 400, message: "Text channels don't carry voice or video".to_string() })
+                            },
+                            Message::VoiceData { user_id, channel_id, ref data, .. }
+                            | Message::VideoData { user_id, channel_id, ref data, .. }
+                            | Message::ScreenShareData { user_id, channel_id, ref data, .. }
+                                if !server_state.lock().unwrap().admit_media(channel_id, data.len()) =>
+                            {
+                                if server_state.lock().unwrap().notify_rate_limited(channel_id, user_id) {
+                                    Some(Message::Error { code: 429, message: "Media rate limit exceeded, reduce quality".to_string() })
+                                } else {
+                                    continue;
+                                }
+                            },
+                            Message::VoiceData { user_id, channel_id, seq, ref data, .. } => {
+                                // Frames re-sent after a reconnect have already been forwarded
+                                if server_state.lock().unwrap().is_duplicate_frame(channel_id, user_id, seq) {
+                                    continue;
+                                }
+                                
+                                // Collect speech for transcription, and transcribe it once a full window is in
+                                let window = {
+                                    let mut state = server_state.lock().unwrap();
+                                    
+                                    // Encrypted voice can't be transcribed
+                                    let encrypted = state.channels.get(&channel_id).is_some_and(|c| c.e2e_encrypted);
+                                    let quality = state.channels.get(&channel_id).map(|c| c.audio_quality).unwrap_or_default();
+                                    state.transcriber.as_mut().filter(|_| !encrypted).and_then(|transcriber| {
+                                        transcriber
+                                            .push_voice(user_id, data, quality)
+                                            .map(|pcm| (pcm, transcriber.backend(), transcriber.language().to_string()))
+                                    })
+                                };
+                                
+                                if let Some((pcm, backend, language)) = window {
+                                    let tx = Arc::clone(&tx);
+                                    tokio::spawn(async move {
+                                        match backend.transcribe(&pcm, VOICE_SAMPLE_RATE, &language).await {
+                                            Ok(text) if !text.is_empty() => {
+                                                let timestamp = SystemTime::now()
+                                                    .duration_since(UNIX_EPOCH)
+                                                    .map(|d| d.as_secs())
+                                                    .unwrap_or_default();
+                                                
+                                                let _ = tx.send((Uuid::nil(), Message::TranscriptionResult {
+                                                    user_id,
+                                                    channel_id,
+                                                    text,
+                                                    timestamp,
+                                                }));
+                                            }
+                                            Ok(_) => {}
+                                            Err(e) => error!("Failed to transcribe voice from {}: {}", user_id, e),
+                                        }
+                                    });
+                                }
+                                
+                                {
+                                    let mut state = server_state.lock().unwrap();
+                                    state.record_forwarded(channel_id, data.len());
+                                    state.record_voice(channel_id, user_id, data);
+                                }
+                                
+                                // Send voice data to the sessions in the channel
+                                send_to_channel(&server_state, channel_id, user_id, &message);
+                                
+                                // Relay to clients connected to other server instances
+                                let session_store = {
+                                    let state = server_state.lock().unwrap();
+                                    Arc::clone(&state.session_store)
+                                };
+                                if let Err(e) = session_store.publish(channel_id, user_id, &message).await {
+                                    error!("Failed to relay voice data: {}", e);
+                                }
+                                
+                                None
+                            },
+                            Message::VideoData { user_id, channel_id, seq, video_codec, ref data, .. } => {
+                                // Frames re-sent after a reconnect have already been forwarded
+                                if server_state.lock().unwrap().is_duplicate_frame(channel_id, user_id, seq) {
+                                    continue;
+                                }
+                                
+                                // The first frame after video starts says what it's encoded with
+                                let previous_codec = server_state.lock().unwrap().video_codecs.insert(user_id, video_codec);
+                                if previous_codec != Some(video_codec) {
+                                    let _ = tx.send((user_id, Message::VideoCodecInfo { user_id, codec: video_codec }));
+                                }
+                                
+                                server_state.lock().unwrap().record_forwarded(channel_id, data.len());
+                                
+                                // Send video data to the sessions in the channel
+                                send_to_channel(&server_state, channel_id, user_id, &message);
+                                
+                                // Relay to clients connected to other server instances
+                                let session_store = {
+                                    let state = server_state.lock().unwrap();
+                                    Arc::clone(&state.session_store)
+                                };
+                                if let Err(e) = session_store.publish(channel_id, user_id, &message).await {
+                                    error!("Failed to relay video data: {}", e);
+                                }
+                                
+                                None
+                            },
+                            Message::ScreenShareData { user_id, channel_id, seq, ref data } => {
+                                // Frames re-sent after a reconnect have already been forwarded
+                                if server_state.lock().unwrap().is_duplicate_frame(channel_id, user_id, seq) {
+                                    continue;
+                                }
+                                
+                                server_state.lock().unwrap().record_forwarded(channel_id, data.len());
+                                
+                                // Send screen share data to the sessions in the channel
+                                send_to_channel(&server_state, channel_id, user_id, &message);
+                                
+                                // Relay to clients connected to other server instances
+                                let session_store = {
+                                    let state = server_state.lock().unwrap();
+                                    Arc::clone(&state.session_store)
+                                };
+                                if let Err(e) = session_store.publish(channel_id, user_id, &message).await {
+                                    error!("Failed to relay screen share data: {}", e);
+                                }
+                                
+                                None
+                            },
+                            Message::VoiceStarted { user_id } => {
+                                {
+                                    let mut state = server_state.lock().unwrap();
+                                    state.voice_streams.insert(user_id);
+                                    state.touch_session_channels(session_id);
+                                    if config::get_config().speaking_stats_enabled {
+                                        state.voice_started(session_id);
+                                    }
+                                }
+                                
+                                // Broadcast voice started to all clients
+                                let _ = tx.send((user_id, message.clone()));
+                                
+                                None
+                            },
+                            Message::VoiceStopped { user_id } => {
+                                {
+                                    let mut state = server_state.lock().unwrap();
+                                    state.voice_streams.remove(&user_id);
+                                    if config::get_config().speaking_stats_enabled {
+                                        state.voice_stopped(session_id);
+                                    }
+                                }
+                                
+                                // Broadcast voice stopped to all clients
+                                let _ = tx.send((user_id, message.clone()));
+                                
+                                None
+                            },
+                            Message::VideoStarted { user_id } => {
+                                {
+                                    let mut state = server_state.lock().unwrap();
+                                    state.video_streams.insert(user_id);
+                                    state.video_codecs.remove(&user_id);
+                                }
+                                
+                                // Broadcast video started to all clients
+                                let _ = tx.send((user_id, message.clone()));
+                                
+                                None
+                            },
+                            Message::VideoStopped { user_id } => {
+                                {
+                                    let mut state = server_state.lock().unwrap();
+                                    state.video_streams.remove(&user_id);
+                                    state.video_codecs.remove(&user_id);
+                                }
+                                
+                                // Broadcast video stopped to all clients
+                                let _ = tx.send((user_id, message.clone()));
+                                
+                                None
+                            },
+                            Message::ScreenShareStarted { user_id } => {
+                                server_state.lock().unwrap().screen_streams.insert(user_id);
+                                
+                                // Broadcast screen share started to all clients
+                                let _ = tx.send((user_id, message.clone()));
+                                
+                                None
+                            },
+                            Message::ScreenShareStopped { user_id } => {
+                                server_state.lock().unwrap().screen_streams.remove(&user_id);
+                                
+                                // Broadcast screen share stopped to all clients
+                                let _ = tx.send((user_id, message.clone()));
+                                
+                                None
+                            },
+                            Message::ScreenShareRegionChanged { user_id: uid, .. } if user_id != Some(uid) => {
+                                Some(Message::Error { code: 403, message: "Cannot change another user's screen share".to_string() })
+                            },
+                            Message::ScreenShareRegionChanged { user_id: uid, .. } => {
+                                // Viewers resize for the new shape of the shared screen
+                                let _ = tx.send((uid, message.clone()));
+                                
+                                None
+                            },
+                            Message::SyncHint { user_id: uid, .. } if user_id != Some(uid) => {
+                                Some(Message::Error { code: 403, message: "Cannot send a sync hint for another user".to_string() })
+                            },
+                            Message::SyncHint { user_id: uid, .. } => {
+                                // Receivers use it to line up the user's voice and video
+                                let _ = tx.send((uid, message.clone()));
+                                
+                                None
+                            },
+                            _ => None,
+                        };
+                        
+                        // Send response if needed
+                        if let Some(response) = response {
+                            if out_tx.send(encode_frame(&response)?).await.is_err() {
+                                break;
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        error!("Error parsing message: {}", e);
+                        break;
+                    }
+                }
+            },
+            Some(Err(e)) => {
+                error!("Error reading message: {}", e);
+                disconnect_reason = audit::classify_disconnect(&e);
+                break;
+            }
+            None => break,
+        }
+    }
+    
+    // Connection closed, cleanup
+    let removed = {
+        let mut state = server_state.lock().unwrap();
+        state.audit_log.disconnected(session_id, disconnect_reason);
+        let removed = state.remove_session(session_id);
+        if let Some(session) = removed.as_ref().filter(|s| s.ghost_mode) {
+            for channel_id in &session.channels {
+                state.notify_ghost_members(*channel_id);
+            }
+        }
+        let removed = removed.and_then(|session| session.user_id);
+        if let Some(uid) = removed {
+            // Broadcast that user left, once their last session has gone
+            if !state.is_connected(uid) {
+                let _ = tx.send((uid, Message::UserLeft { user_id: uid }));
+            }
+        }
+        removed.map(|uid| (uid, Arc::clone(&state.session_store)))
+    };
+    
+    // A clean disconnect doesn't need to be recovered
+    if let Some((uid, session_store)) = removed {
+        if let Err(e) = session_store.delete_session(uid).await {
+            error!("Failed to delete session for {}: {}", uid, e);
+        }
+    }
+    
+    // Stop queueing, then give the client whatever is already queued
+    forward_task.abort();
+    drop(out_tx);
+    let _ = forward_task.await;
+    if tokio::time::timeout(FLUSH_TIMEOUT, write_task).await.is_err() {
+        warn!("Gave up sending the last messages to {}", addr);
+    }
+    
+    info!("Connection closed for {}", addr);
+    Ok(())
+}
+
+// Messages on the wire are JSON, each preceded by its length as a big-endian u32
+fn frame_codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder().max_frame_length(MAX_FRAME_LENGTH).new_codec()
+}
+
+fn encode_frame(message: &Message) -> open_reverb_common::error::Result<Bytes> {
+    Ok(Bytes::from(serde_json::to_vec(message)?))
+}
+
+// Check whether a freshly accepted socket is starting an HTTP request rather than a framed message.
+// A client that hasn't said that much in time is left to the framed handler, which times it out.
+async fn is_http_request(socket: &TcpStream) -> bool {
+    let mut buf = [0u8; 4];
+    
+    let peek = async {
+        loop {
+            match socket.peek(&mut buf).await {
+                Ok(0) | Err(_) => return false,
+                Ok(n) if n >= buf.len() => return &buf == b"GET ",
+                // Wait for the rest of the first bytes to arrive
+                Ok(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    };
+    
+    tokio::time::timeout(FIRST_BYTES_TIMEOUT, peek).await.unwrap_or(false)
+}
+
+// Handle a client connecting over WebSocket
+async fn handle_websocket_connection(
+    socket: TcpStream,
+    addr: String,
+    ws_path: String,
+    server_state: Arc<Mutex<ServerState>>,
+    tx: Arc<broadcast::Sender<(Uuid, Message)>>
+) -> open_reverb_common::error::Result<()> {
+    // Only accept the upgrade on the configured path. tungstenite decides the error type.
+    #[allow(clippy::result_large_err)]
+    let check_path = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        if request.uri().path() == ws_path {
+            Ok(response)
+        } else {
+            let mut not_found = ErrorResponse::new(None);
+            *not_found.status_mut() = StatusCode::NOT_FOUND;
+            Err(not_found)
+        }
+    };
+    
+    let ws_stream = tokio_tungstenite::accept_hdr_async(socket, check_path)
+        .await
+        .map_err(|e| OpenReverbError::network("WebSocket handshake failed").with_source(e))?;
+    info!("WebSocket connection established with {}", addr);
+    
+    // Bridge the WebSocket to an in-memory stream so the regular length-delimited
+    // connection handler can be reused unchanged
+    let (local, remote) = tokio::io::duplex(64 * 1024);
+    let bridge_task = tokio::spawn(bridge_websocket(ws_stream, remote));
+    
+    let result = handle_connection(local, addr, server_state, tx).await;
+    
+    bridge_task.abort();
+    
+    result
+}
+
+// Handle a client connecting over TLS
+async fn handle_tls_connection(
+    socket: TcpStream,
+    addr: String,
+    tls_config: Arc<rustls::ServerConfig>,
+    server_state: Arc<Mutex<ServerState>>,
+    tx: Arc<broadcast::Sender<(Uuid, Message)>>
+) -> open_reverb_common::error::Result<()> {
+    // Bridged to an in-memory stream like WebSocket, so the connection handler is unchanged
+    let (local, remote) = tokio::io::duplex(64 * 1024);
+    let bridge_addr = addr.clone();
+    let mut bridge_task = tokio::spawn(async move {
+        if let Err(e) = tls::accept(socket, tls_config, remote).await {
+            warn!("TLS session with {} failed: {}", bridge_addr, e);
+        }
+    });
+    
+    let result = handle_connection(local, addr.clone(), server_state, tx).await;
+    
+    // The handler's stream is closed now, so the bridge sends what's left and says goodbye;
+    // unless the client has stopped reading
+    if tokio::time::timeout(TLS_CLOSE_TIMEOUT, &mut bridge_task).await.is_err() {
+        warn!("Gave up closing the TLS session with {} cleanly", addr);
+        bridge_task.abort();
+    }
+    
+    result
+}
+
+// Translate between binary WebSocket frames and length-prefixed frames
+async fn bridge_websocket(ws_stream: WebSocketStream<TcpStream>, stream: DuplexStream) {
+    let (mut ws_writer, mut ws_reader) = ws_stream.split();
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = FramedRead::new(reader, frame_codec());
+    let mut writer = FramedWrite::new(writer, frame_codec());
+    
+    let incoming = async {
+        while let Some(Ok(frame)) = ws_reader.next().await {
+            let data = match frame {
+                tokio_tungstenite::tungstenite::Message::Binary(data) => data,
+                tokio_tungstenite::tungstenite::Message::Close(_) => break,
+                _ => continue,
+            };
+            if writer.send(Bytes::from(data)).await.is_err() {
+                break;
+            }
+        }
+    };
+    
+    let outgoing = async {
+        while let Some(Ok(frame)) = reader.next().await {
+            if ws_writer.send(tokio_tungstenite::tungstenite::Message::Binary(frame.to_vec())).await.is_err() {
+                break;
+            }
+        }
+        
+        let _ = ws_writer.close().await;
+    };
+    
+    // Whichever side finishes first ends the connection
+    tokio::select! {
+        _ = incoming => {},
+        _ = outgoing => {},
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Initialize logging
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+    
+    info!("Starting Open Reverb Server");
+    
+    let config = config::get_config();
+    
+    // Bind to address
+    let addr = format!("{}:{}", config.host, config.port);
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Server listening on {}", addr);
+    
+    if let Some(ws_path) = &config.ws_path {
+        info!("WebSocket endpoint enabled at {}", ws_path);
+    }
+    
+    // Every TCP connection is TLS once a certificate and key are configured
+    let tls_config = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            info!("TLS enabled with the certificate in {}", cert_path);
+            Some(tls::load_config(cert_path, key_path)?)
+        }
+        (None, None) => None,
+        _ => return Err("tls_cert_path and tls_key_path must be set together".into()),
+    };
+    
+    // WebSocket upgrades are answered before TLS, so they would go unencrypted
+    if tls_config.is_some() && config.ws_path.is_some() {
+        return Err("ws_path can't be used with TLS; put a TLS-terminating proxy in front of a separate WebSocket server".into());
+    }
+    
+    if let Some(params) = &config.simulate_network {
+        warn!("Simulating a degraded network on all connections: {:?}", params);
+    }
+    
+    // Sessions are kept in Redis when configured, so other instances can pick them up
+    let session_store: Arc<dyn SessionStore> = match &config.redis_url {
+        Some(redis_url) => {
+            info!("Storing sessions in Redis at {}", redis_url);
+            match RedisSessionStore::connect(redis_url).await {
+                Ok(store) => Arc::new(store),
+                Err(e) => return Err(format!("Failed to connect to Redis: {}", e).into()),
+            }
+        }
+        None => Arc::new(InMemorySessionStore::new()),
+    };
+    
+    let database = match &config.database_path {
+        Some(path) => Database::open(path)?,
+        None => Database::in_memory(),
+    };
+    
+    // Create a server state
+    let mut state = ServerState::new(Arc::clone(&session_store));
+    state.load_database(database);
+    let server_state = Arc::new(Mutex::new(state));
+    
+    // Create a broadcast channel for messages
+    let (tx, _) = broadcast::channel::<(Uuid, Message)>(100);
+    let tx = Arc::new(tx);
+    
+    let relay_state = Arc::clone(&server_state);
+    session_store.spawn_forwarding(Arc::new(move |channel_id, sender_id, message| {
+        send_to_channel(&relay_state, channel_id, sender_id, &message);
+    }));
+    
+    tokio::spawn(broadcast_stats(Arc::clone(&server_state)));
+    tokio::spawn(reset_media_rates(Arc::clone(&server_state)));
+    tokio::spawn(send_announcements(Arc::clone(&server_state), Arc::clone(&tx)));
+    tokio::spawn(enforce_retention(Arc::clone(&server_state)));
+    if let Some(secs) = config.max_session_duration_secs {
+        tokio::spawn(expire_sessions(Arc::clone(&server_state), Duration::from_secs(secs)));
+    }
+    if !config.federation.is_empty() {
+        tokio::spawn(sync_federation(Arc::clone(&server_state), Arc::clone(&tx)));
+    }
+    tokio::spawn(clean_up_idle_channels(Arc::clone(&server_state), Arc::clone(&tx)));
+    
+    // Accept connections
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        
+        if !server_state.lock().unwrap().ip_filter.permits(addr.ip()) {
+            info!("Refused connection from {}", addr);
+            metrics::get_metrics().blocked_connections_total.inc();
+            drop(socket);
+            continue;
+        }
+        info!("New connection from {}", addr);
+        
+        // Clone the server state and channel for this connection
+        let server_state = Arc::clone(&server_state);
+        let tx = Arc::clone(&tx);
+        let tls_config = tls_config.clone();
+        
+        // Spawn a new task for each connection
+        tokio::spawn(async move {
+            info!("Connection established with {}", addr);
+            
+            // Clients behind HTTP-only firewalls connect with a WebSocket upgrade instead
+            let result = match &config.ws_path {
+                Some(ws_path) if is_http_request(&socket).await => {
+                    handle_websocket_connection(socket, addr.to_string(), ws_path.clone(), server_state, tx).await
+                }
+                _ => match tls_config {
+                    Some(tls_config) => handle_tls_connection(socket, addr.to_string(), tls_config, server_state, tx).await,
+                    None => handle_connection(socket, addr.to_string(), server_state, tx).await,
+                },
+            };
+            
+            if let Err(e) = result {
+                error!("Error handling connection from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const USER: Uuid = Uuid::from_u128(1);
+    const OTHER_USER: Uuid = Uuid::from_u128(2);
+    
+    fn voice(user_id: Uuid) -> Message {
+        Message::VoiceData { user_id, channel_id: Uuid::from_u128(3), seq: 0, captured_at_ms: 0, epoch: 0, data: vec![0; 4] }
+    }
+    
+    #[test]
+    fn own_voice_is_suppressed_without_broadcast_self() {
+        assert!(!should_forward(Some(USER), USER, false, &voice(USER)));
+        assert!(should_forward(Some(USER), OTHER_USER, false, &voice(OTHER_USER)));
+    }
+    
+    #[test]
+    fn server_offers_only_switched_on_features() {
+        let mut config = config::ServerConfig::default();
+        assert_eq!(server_features(&config), Features::E2E_VOICE);
+        
+        config.transcription_enabled = true;
+        config.recording_enabled = true;
+        assert_eq!(server_features(&config), Features::E2E_VOICE | Features::TRANSCRIPTION | Features::SERVER_RECORDING);
+    }
+    
+    #[test]
+    fn feature_messages_need_the_feature() {
+        assert_eq!(required_feature(&Message::E2EChannelEnabled { channel_id: Uuid::nil() }), Some(Features::E2E_VOICE));
+        assert_eq!(required_feature(&voice(USER)), None);
+    }
+    
+    #[test]
+    fn credentials_are_left_out_of_the_log() {
+        let logged = [
+            loggable(&Message::LoginRequest { username: "alice".to_string(), password: "hunter2".to_string() }),
+            loggable(&Message::TOTPResponse { user_id: USER, code: "123456".to_string() }),
+            loggable(&Message::RenewSession { token: "renew-secret".to_string() }),
+            loggable(&Message::ResumeSession { token: "resume-secret".to_string() }),
+        ];
+        
+        for secret in ["hunter2", "123456", "secret"] {
+            assert!(logged.iter().all(|line| !line.contains(secret)), "{} was logged", secret);
+        }
+        assert!(logged[0].contains("alice"));
+    }
+    
+    #[test]
+    fn usernames_differing_only_in_case_are_taken() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        
+        let alice = state.find_or_add_user("Alice".to_string()).unwrap();
+        assert_eq!(state.find_or_add_user("Alice".to_string()), Ok(alice));
+        assert_eq!(state.find_or_add_user("aLICE".to_string()), Err("Username taken (case-insensitive match)".to_string()));
+        assert!(state.find_or_add_user("Al ice".to_string()).is_err());
+        assert_eq!(state.users.len(), 1);
+    }
+    
+    // A logged-in session, and what it's sent
+    fn login(state: &mut ServerState, addr: &str, username: &str) -> (Uuid, mpsc::UnboundedReceiver<Message>) {
+        let (direct_tx, direct_rx) = mpsc::unbounded_channel();
+        let session_id = state.add_session(addr.to_string(), direct_tx);
+        let user_id = state.find_or_add_user(username.to_string()).unwrap();
+        state.sessions.get_mut(&session_id).unwrap().user_id = Some(user_id);
+        (user_id, direct_rx)
+    }
+    
+    // The ID of the session connected from `addr`
+    fn session(state: &ServerState, addr: &str) -> Uuid {
+        *state.sessions.iter().find(|(_, s)| s.addr == addr).expect("no session from that address").0
+    }
+    
+    // A logged-in session in `channel_id`
+    fn join(state: &mut ServerState, addr: &str, username: &str, channel_id: Uuid, ghost: bool) -> Uuid {
+        let (user_id, _) = login(state, addr, username);
+        
+        let session = state.sessions.get_mut(&session(state, addr)).unwrap();
+        session.channels.push(channel_id);
+        session.ghost_mode = ghost;
+        user_id
+    }
+    
+    #[test]
+    fn ghosts_get_channel_traffic_but_are_hidden_from_members() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let channel_id = Uuid::new_v4();
+        let member = join(&mut state, "10.0.0.1:5000", "member", channel_id, false);
+        let ghost = join(&mut state, "10.0.0.2:5000", "moderator", channel_id, true);
+        
+        // Members are only told about each other
+        assert_eq!(state.visible_channel_members(channel_id), vec![member]);
+        // Media and relayed messages go to everyone in the channel, ghosts included
+        assert!(state.channel_members(channel_id).contains(&ghost));
+        assert!(should_forward(Some(ghost), member, false, &voice(member)));
+        
+        // What admins are sent
+        let ghosts: Vec<Uuid> = state.ghost_members(channel_id).iter().map(|u| u.id).collect();
+        assert_eq!(ghosts, vec![ghost]);
+        assert!(state.ghost_members(Uuid::new_v4()).is_empty());
+    }
+    
+    #[test]
+    fn statistics_count_each_channel_and_reach_only_admins() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        state.admin_users = vec!["admin".to_string()];
+        let lounge = state.create_channel(NewChannel { name: "Lounge".to_string(), ..Default::default() }, false).unwrap().id;
+        let alice = join(&mut state, "10.0.0.1:5000", "alice", lounge, false);
+        let bob = join(&mut state, "10.0.0.2:5000", "bob", lounge, false);
+        state.voice_streams.extend([alice, bob]);
+        state.video_streams.insert(alice);
+        state.screen_streams.insert(bob);
+        
+        let mut previous_bytes = HashMap::new();
+        state.record_forwarded(lounge, 2_000);
+        let stats = state.server_statistics(&mut previous_bytes, Duration::from_secs(2));
+        assert_eq!(stats.total_connections, 2);
+        let channel = stats.per_channel.iter().find(|c| c.channel_id == lounge).unwrap();
+        assert_eq!((channel.members, channel.voice_streams, channel.video_streams), (2, 2, 2));
+        assert_eq!(channel.bytes_forwarded_per_sec, 1_000);
+        
+        // Rates only count what was forwarded since the last time
+        state.record_forwarded(lounge, 500);
+        let stats = state.server_statistics(&mut previous_bytes, Duration::from_secs(1));
+        assert_eq!(stats.per_channel.iter().find(|c| c.channel_id == lounge).unwrap().bytes_forwarded_per_sec, 500);
+        
+        // Channels are listed by name
+        let names: Vec<&str> = stats.per_channel.iter().map(|c| c.name.as_str()).collect();
+        assert!(names.windows(2).all(|pair| pair[0] <= pair[1]));
+        
+        let (admin, mut admin_rx) = login(&mut state, "10.0.0.3:5000", "admin");
+        let (_, mut carol_rx) = login(&mut state, "10.0.0.4:5000", "carol");
+        let stats = state.server_statistics(&mut previous_bytes, Duration::ZERO);
+        assert_eq!(stats.admin_ids, vec![admin]);
+        state.send_to_admins(Message::ServerStats { stats });
+        assert!(matches!(admin_rx.try_recv(), Ok(Message::ServerStats { stats }) if stats.total_connections == 4));
+        assert!(carol_rx.try_recv().is_err());
+    }
+    
+    const PEER: &str = "peer.example.com:8080";
+    const SECRET: &str = "s3cret";
+    
+    fn peer() -> config::FederatedServer {
+        config::FederatedServer { url: PEER.to_string(), shared_secret: SECRET.to_string(), ca_cert_path: None, plaintext: false }
+    }
+    
+    fn federated_state(allow_dm: bool) -> ServerState {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        state.federation = Federation::new(vec![peer()], allow_dm);
+        state
+    }
+    
+    // What the peer would send us
+    fn presence_from_peer(usernames: &[&str]) -> Message {
+        federation::presence(&peer(), usernames.iter().map(|u| u.to_string()).collect(), 5)
+    }
+    
+    fn dm_from_peer(secret: &str, from: &str, to: &str, message: &str) -> Message {
+        let peer = config::FederatedServer { shared_secret: secret.to_string(), ..peer() };
+        federation::relay(&peer, from.to_string(), to.to_string(), message.to_string(), 6)
+    }
+    
+    #[test]
+    fn federated_users_are_listed_alongside_local_ones() {
+        let mut state = federated_state(false);
+        login(&mut state, "10.0.0.1:5000", "alice");
+        
+        let guess = config::FederatedServer { shared_secret: "guess".to_string(), ..peer() };
+        assert!(state.update_federated_presence(federation::presence(&guess, vec!["mallory".to_string()], 5), 5).is_err());
+        let change = state.update_federated_presence(presence_from_peer(&["bob"]), 5).unwrap();
+        assert_eq!(change.joined.len(), 1);
+        
+        let mut users: Vec<(String, Option<String>)> = state.get_server_info().users
+            .into_iter()
+            .map(|u| (u.username, u.federated_from))
+            .collect();
+        users.sort();
+        assert_eq!(users, vec![("alice".to_string(), None), ("bob".to_string(), Some(PEER.to_string()))]);
+        
+        // Only our own users are reported to peers
+        assert_eq!(state.online_usernames(), vec!["alice".to_string()]);
+    }
+    
+    #[test]
+    fn users_are_found_by_any_part_of_their_name_in_any_case() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        login(&mut state, "10.0.0.1:5000", "Alice");
+        login(&mut state, "10.0.0.2:5000", "malice");
+        login(&mut state, "10.0.0.3:5000", "bob");
+        state.find_or_add_user("ALICIA".to_string()).unwrap();
+        let found = |state: &ServerState, query: &str, include_offline| {
+            state.search_users(query, include_offline).into_iter().map(|u| u.username).collect::<Vec<_>>()
+        };
+        
+        assert_eq!(found(&state, "lic", false), ["Alice", "malice"]);
+        assert_eq!(found(&state, "ALI", false), ["Alice", "malice"]);
+        assert_eq!(found(&state, "ali", true), ["Alice", "ALICIA", "malice"]);
+        assert!(found(&state, "carol", true).is_empty());
+        
+        // Clients show their full list rather than search for nothing; the server would list everyone
+        assert_eq!(found(&state, "", false), ["Alice", "bob", "malice"]);
+    }
+    
+    #[test]
+    fn user_searches_return_the_first_names_alphabetically_up_to_the_limit() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        // Added in reverse, so they come back sorted rather than in the order they were added
+        for i in (0..MAX_USER_SEARCH_RESULTS + 20).rev() {
+            state.find_or_add_user(format!("user{:03}", i)).unwrap();
+        }
+        
+        let found: Vec<String> = state.search_users("user", true).into_iter().map(|u| u.username).collect();
+        let expected: Vec<String> = (0..MAX_USER_SEARCH_RESULTS).map(|i| format!("user{:03}", i)).collect();
+        assert_eq!(found, expected);
+    }
+    
+    #[test]
+    fn direct_messages_are_relayed_between_federated_servers() {
+        let mut state = federated_state(true);
+        let (alice, mut alice_rx) = login(&mut state, "10.0.0.1:5000", "alice");
+        let (carol, mut carol_rx) = login(&mut state, "10.0.0.2:5000", "carol");
+        state.update_federated_presence(presence_from_peer(&["bob"]), 5).unwrap();
+        let bob = state.federation.find(PEER, "bob").unwrap().id;
+        
+        // Local users are sent it straight away
+        assert!(matches!(state.route_direct_message(alice, carol, "hi".to_string(), 5), Ok(None)));
+        assert!(matches!(carol_rx.try_recv(), Ok(Message::DirectMessage { from_user_id, content, .. }) if from_user_id == alice && content == "hi"));
+        
+        // Federated users' go to their server
+        match state.route_direct_message(alice, bob, "hi bob".to_string(), 5) {
+            Ok(Some((peer, relay @ Message::FederationRelay { .. }))) => {
+                assert_eq!(peer.url, PEER);
+                let Message::FederationRelay { ref from_username, ref target_username, ref message, .. } = relay else { unreachable!() };
+                assert_eq!((from_username.as_str(), target_username.as_str(), message.as_str()), ("alice", "bob", "hi bob"));
+                // Signed with the secret rather than carrying it
+                assert!(!serde_json::to_string(&relay).unwrap().contains(SECRET));
+                assert!(Federation::new(vec![peer], true).authenticate(&relay, 5).is_some());
+            }
+            other => panic!("unexpected route: {:?}", other),
+        }
+        
+        // And replies from them arrive as from the federated user
+        state.deliver_federated_dm(dm_from_peer(SECRET, "bob", "Alice", "hi alice"), 6).unwrap();
+        match alice_rx.try_recv() {
+            Ok(Message::DirectMessage { from_user_id, to_user_id, content, timestamp }) => {
+                assert_eq!((from_user_id, to_user_id, content.as_str(), timestamp), (bob, alice, "hi alice", 6));
+            }
+            other => panic!("unexpected delivery: {:?}", other),
+        }
+        
+        assert!(matches!(state.deliver_federated_dm(dm_from_peer("guess", "bob", "alice", "hi"), 6).map_err(|e| *e), Err(Message::Error { code: 401, .. })));
+        assert!(matches!(state.deliver_federated_dm(dm_from_peer(SECRET, "mallory", "alice", "hi"), 6).map_err(|e| *e), Err(Message::Error { code: 404, .. })));
+        assert!(matches!(state.deliver_federated_dm(dm_from_peer(SECRET, "bob", "dave", "hi"), 6).map_err(|e| *e), Err(Message::Error { code: 404, .. })));
+        // The same message can't be sent again
+        assert!(matches!(state.deliver_federated_dm(dm_from_peer(SECRET, "bob", "Alice", "hi alice"), 6).map_err(|e| *e), Err(Message::Error { code: 401, .. })));
+    }
+    
+    #[test]
+    fn direct_messages_wait_for_offline_users() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (alice, mut alice_rx) = login(&mut state, "10.0.0.1:5000", "alice");
+        let (bob, _) = login(&mut state, "10.0.0.2:5000", "bob");
+        state.remove_session(session(&state, "10.0.0.2:5000"));
+        
+        assert!(matches!(state.route_direct_message(alice, bob, "are you there?".to_string(), 5), Ok(None)));
+        assert!(matches!(state.route_direct_message(alice, Uuid::new_v4(), "hi".to_string(), 5).map_err(|e| *e), Err(Message::Error { code: 404, .. })));
+        // No echo for the sender
+        assert!(alice_rx.try_recv().is_err());
+        
+        let queued = state.pending_dms.take(bob);
+        assert!(matches!(queued.as_slice(), [Message::DirectMessage { from_user_id, content, .. }] if *from_user_id == alice && content == "are you there?"));
+    }
+    
+    #[test]
+    fn files_go_only_to_the_user_they_were_offered_to() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (alice, mut alice_rx) = login(&mut state, "10.0.0.1:5000", "alice");
+        let (bob, mut bob_rx) = login(&mut state, "10.0.0.2:5000", "bob");
+        let (mallory, mut mallory_rx) = login(&mut state, "10.0.0.3:5000", "mallory");
+        let id = Uuid::new_v4();
+        let offer = Message::FileTransferOffer {
+            id,
+            from: alice,
+            to: bob,
+            filename: "notes.txt".to_string(),
+            size_bytes: 5,
+            mime_type: "text/plain".to_string(),
+        };
+        
+        assert!(matches!(state.relay_file_transfer(mallory, offer.clone()), Some(Message::Error { code: 403, .. })));
+        assert!(state.relay_file_transfer(alice, offer).is_none());
+        assert!(matches!(bob_rx.try_recv(), Ok(Message::FileTransferOffer { filename, .. }) if filename == "notes.txt"));
+        
+        assert!(matches!(state.relay_file_transfer(mallory, Message::FileTransferAccept { id }), Some(Message::Error { code: 404, .. })));
+        assert!(state.relay_file_transfer(bob, Message::FileTransferAccept { id }).is_none());
+        assert!(matches!(alice_rx.try_recv(), Ok(Message::FileTransferAccept { .. })));
+        
+        let chunk = Message::FileTransferChunk { id, offset: 0, data: b"notes".to_vec() };
+        assert!(state.relay_file_transfer(alice, chunk).is_none());
+        assert!(matches!(bob_rx.try_recv(), Ok(Message::FileTransferChunk { data, .. }) if data == b"notes"));
+        assert!(mallory_rx.try_recv().is_err());
+        
+        // The receiver is told when the sender leaves part way
+        state.remove_session(session(&state, "10.0.0.1:5000"));
+        assert!(matches!(bob_rx.try_recv(), Ok(Message::FileTransferReject { .. })));
+        assert!(matches!(state.relay_file_transfer(alice, Message::FileTransferComplete { id }), Some(Message::Error { code: 404, .. })));
+    }
+    
+    #[test]
+    fn federated_direct_messages_can_be_switched_off() {
+        let mut state = federated_state(false);
+        let (alice, _alice_rx) = login(&mut state, "10.0.0.1:5000", "alice");
+        state.update_federated_presence(presence_from_peer(&["bob"]), 5).unwrap();
+        let bob = state.federation.find(PEER, "bob").unwrap().id;
+        
+        assert!(matches!(state.route_direct_message(alice, bob, "hi".to_string(), 5).map_err(|e| *e), Err(Message::Error { code: 403, .. })));
+        assert!(matches!(state.deliver_federated_dm(dm_from_peer(SECRET, "bob", "alice", "hi"), 6).map_err(|e| *e), Err(Message::Error { code: 403, .. })));
+    }
+    
+    #[tokio::test]
+    async fn direct_messages_reach_a_federated_server_over_tls() {
+        let listener = tokio::net::TcpListener::bind("localhost:0").await.unwrap();
+        let mut state = federated_state(true);
+        let peer = config::FederatedServer {
+            url: format!("localhost:{}", listener.local_addr().unwrap().port()),
+            ca_cert_path: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/ca.pem").to_string()),
+            ..peer()
+        };
+        state.federation = Federation::new(vec![peer.clone()], true);
+        let (alice, _alice_rx) = login(&mut state, "10.0.0.1:5000", "alice");
+        let now = announcements::now_secs();
+        state.update_federated_presence(federation::presence(&peer, vec!["bob".to_string()], now), now).unwrap();
+        let bob = state.federation.find(&peer.url, "bob").unwrap().id;
+        
+        // The peer: reads one frame over TLS
+        let received = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let config = tls::server_config(include_str!("../testdata/cert.pem"), include_str!("../testdata/key.pem")).unwrap();
+            let (local, remote) = tokio::io::duplex(1024);
+            tokio::spawn(tls::accept(socket, config, remote));
+            let mut frames = tokio_util::codec::Framed::new(local, frame_codec());
+            frames.next().await.unwrap().unwrap()
+        });
+        
+        let Ok(Some((to, relay))) = state.route_direct_message(alice, bob, "hi bob".to_string(), now) else {
+            panic!("not relayed");
+        };
+        federation::send_to_peer(&to, &relay).await.unwrap();
+        
+        let bytes = received.await.unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains(SECRET));
+        let relay: Message = serde_json::from_slice(&bytes).unwrap();
+        
+        // Which the peer, sharing the secret, takes as ours
+        let mut theirs = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        theirs.federation = Federation::new(vec![config::FederatedServer { url: "us.example.com:8080".to_string(), ..peer.clone() }], true);
+        let (_bob, mut bob_rx) = login(&mut theirs, "10.0.0.2:5000", "bob");
+        theirs.update_federated_presence(federation::presence(&peer, vec!["alice".to_string()], now), now).unwrap();
+        theirs.deliver_federated_dm(relay, now).unwrap();
+        assert!(matches!(bob_rx.try_recv(), Ok(Message::DirectMessage { content, .. }) if content == "hi bob"));
+    }
+    
+    #[tokio::test]
+    async fn connections_speak_length_delimited_json() {
+        let state = Arc::new(Mutex::new(ServerState::new(Arc::new(InMemorySessionStore::new()))));
+        let (tx, _) = broadcast::channel(16);
+        let (client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(handle_connection(server, "10.0.0.1:5000".to_string(), Arc::clone(&state), Arc::new(tx)));
+        
+        let mut client = tokio_util::codec::Framed::new(client, frame_codec());
+        client.send(encode_frame(&Message::Ping { nonce: 7 }).unwrap()).await.unwrap();
+        
+        let reply = client.next().await.unwrap().unwrap();
+        assert_eq!(&reply[..], serde_json::to_vec(&Message::Pong { nonce: 7 }).unwrap());
+        
+        // Hanging up ends the connection cleanly
+        drop(client);
+        assert!(handler.await.unwrap().is_ok());
+        let entries = state.lock().unwrap().audit_log.query(None, 0, 10);
+        assert_eq!(entries[0].disconnect_reason, Some(DisconnectReason::Clean));
+    }
+    
+    #[tokio::test]
+    async fn websocket_clients_get_pongs_for_their_pings() {
+        let state = Arc::new(Mutex::new(ServerState::new(Arc::new(InMemorySessionStore::new()))));
+        let (tx, _) = broadcast::channel(16);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            assert!(is_http_request(&socket).await);
+            handle_websocket_connection(socket, addr.to_string(), "/voice".to_string(), state, Arc::new(tx)).await
+        });
+        
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let (mut client, _) = tokio_tungstenite::client_async(format!("ws://{}/voice", addr), socket).await.unwrap();
+        client.send(tokio_tungstenite::tungstenite::Message::Binary(encode_frame(&Message::Ping { nonce: 7 }).unwrap().to_vec())).await.unwrap();
+        
+        let reply = client.next().await.unwrap().unwrap();
+        assert_eq!(reply.into_data(), serde_json::to_vec(&Message::Pong { nonce: 7 }).unwrap());
+        
+        client.close(None).await.unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn connections_that_stall_before_their_first_bytes_are_not_websockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"GE").await.unwrap();
+        let started = tokio::time::Instant::now();
+        assert!(!is_http_request(&socket).await);
+        assert!(started.elapsed() >= FIRST_BYTES_TIMEOUT);
+    }
+    
+    // Hears the same thing whatever it's given
+    struct FixedText;
+    
+    #[async_trait::async_trait]
+    impl transcription::TranscriptionBackend for FixedText {
+        async fn transcribe(&self, _pcm: &[i16], _sample_rate: u32, _language: &str) -> transcription::TranscriptionResult<String> {
+            Ok("hello world".to_string())
+        }
+    }
+    
+    #[tokio::test]
+    async fn voice_is_transcribed_once_a_window_is_in() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        state.transcriber = Some(Transcriber::new(Arc::new(FixedText), "en".to_string()));
+        let quality = AudioQuality { codec: open_reverb_common::models::AudioCodec::PCM, channels: 1, ..AudioQuality::default() };
+        let channel_id = state.create_channel(NewChannel { name: "Lounge".to_string(), audio_quality: Some(quality), ..Default::default() }, false).unwrap().id;
+        let state = Arc::new(Mutex::new(state));
+        
+        let (tx, mut rx) = broadcast::channel(16);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_connection(server, "10.0.0.1:5000".to_string(), state, Arc::new(tx)));
+        let mut client = tokio_util::codec::Framed::new(client, frame_codec());
+        
+        let login = Message::LoginRequest { username: "alice".to_string(), password: "hunter2".to_string() };
+        client.send(encode_frame(&login).unwrap()).await.unwrap();
+        let reply: Message = serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap();
+        let Message::LoginResponse { user_id: Some(user_id), .. } = reply else { panic!("unexpected message: {:?}", reply) };
+        client.send(encode_frame(&Message::JoinChannel { channel_id, ghost: false }).unwrap()).await.unwrap();
+        
+        // A second of voice at a time, five seconds making a window
+        let second: Vec<u8> = (0..quality.sample_rate).flat_map(|i| (((i % 100) as i16 - 50) * 200).to_le_bytes()).collect();
+        for seq in 0..5 {
+            let voice = Message::VoiceData { user_id, channel_id, seq, captured_at_ms: 0, epoch: 0, data: second.clone() };
+            client.send(encode_frame(&voice).unwrap()).await.unwrap();
+        }
+        
+        let transcript = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let (_, Message::TranscriptionResult { user_id, channel_id, text, .. }) = rx.recv().await.unwrap() {
+                    return (user_id, channel_id, text);
+                }
+            }
+        });
+        assert_eq!(transcript.await.unwrap(), (user_id, channel_id, "hello world".to_string()));
+    }
+    
+    #[tokio::test]
+    async fn topics_are_broadcast_unless_too_long() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        state.admin_users = vec!["alice".to_string()];
+        let channel_id = state.create_channel(NewChannel { name: "Lounge".to_string(), channel_type: ChannelType::Text, ..Default::default() }, false).unwrap().id;
+        let state = Arc::new(Mutex::new(state));
+        
+        let (tx, mut rx) = broadcast::channel(16);
+        let tx = Arc::new(tx);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_connection(server, "10.0.0.1:5000".to_string(), Arc::clone(&state), Arc::clone(&tx)));
+        let mut client = tokio_util::codec::Framed::new(client, frame_codec());
+        
+        // Not before logging in, nor by anyone but an admin
+        client.send(encode_frame(&Message::SetChannelTopic { channel_id, topic: "Games".to_string() }).unwrap()).await.unwrap();
+        let reply: Message = serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(reply, Message::Error { code: 401, .. }));
+        let (_, mut bob) = connect(&state, &tx, "10.0.0.2:5000", "bob").await;
+        bob.send(encode_frame(&Message::SetChannelTopic { channel_id, topic: "Games".to_string() }).unwrap()).await.unwrap();
+        assert_eq!(next_error(&mut bob).await, 403);
+        assert_eq!(state.lock().unwrap().channels[&channel_id].topic, None);
+        
+        let login = Message::LoginRequest { username: "alice".to_string(), password: "hunter2".to_string() };
+        client.send(encode_frame(&login).unwrap()).await.unwrap();
+        client.next().await.unwrap().unwrap();
+        client.next().await.unwrap().unwrap();
+        
+        // Everyone hears about the new topic
+        client.send(encode_frame(&Message::SetChannelTopic { channel_id, topic: "  Board games  ".to_string() }).unwrap()).await.unwrap();
+        let update = loop {
+            if let (_, Message::ChannelUpdate { channel }) = rx.recv().await.unwrap() {
+                break channel;
+            }
+        };
+        assert_eq!((update.id, update.topic.as_deref()), (channel_id, Some("Board games")));
+        
+        // The limit is in characters, not bytes
+        let topic = "é".repeat(MAX_TOPIC_LENGTH + 1);
+        client.send(encode_frame(&Message::SetChannelTopic { channel_id, topic }).unwrap()).await.unwrap();
+        let reply = loop {
+            match serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap() {
+                reply @ Message::Error { .. } => break reply,
+                _ => continue,
+            }
+        };
+        assert!(matches!(reply, Message::Error { code: 400, message } if message == "Channel topic cannot be longer than 256 characters"));
+        assert_eq!(state.lock().unwrap().channels[&channel_id].topic.as_deref(), Some("Board games"));
+        assert!(state.lock().unwrap().set_channel_topic(channel_id, "é".repeat(MAX_TOPIC_LENGTH)).is_ok());
+        
+        // A blank topic clears it
+        let cleared = state.lock().unwrap().set_channel_topic(channel_id, " ".to_string()).unwrap();
+        assert_eq!(cleared.topic, None);
+    }
+    
+    #[tokio::test]
+    async fn text_channels_refuse_voice_and_video() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let lounge = state.create_channel(NewChannel { name: "Lounge".to_string(), channel_type: ChannelType::Text, ..Default::default() }, false).unwrap().id;
+        let voice = state.create_channel(NewChannel { name: "Voice".to_string(), ..Default::default() }, false).unwrap().id;
+        assert!(state.is_text_channel(lounge));
+        assert!(!state.is_text_channel(voice));
+        assert!(!state.is_text_channel(Uuid::new_v4()));
+        let state = Arc::new(Mutex::new(state));
+        
+        let (tx, _rx) = broadcast::channel(16);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_connection(server, "10.0.0.1:5000".to_string(), Arc::clone(&state), Arc::new(tx)));
+        let mut client = tokio_util::codec::Framed::new(client, frame_codec());
+        
+        let login = Message::LoginRequest { username: "alice".to_string(), password: "hunter2".to_string() };
+        client.send(encode_frame(&login).unwrap()).await.unwrap();
+        let user_id = match serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap() {
+            Message::LoginResponse { user_id: Some(user_id), .. } => user_id,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        client.send(encode_frame(&Message::JoinChannel { channel_id: lounge, ghost: false }).unwrap()).await.unwrap();
+        
+        let voice_data = Message::VoiceData { user_id, channel_id: lounge, seq: 0, captured_at_ms: 0, epoch: 0, data: vec![0; 16] };
+        let video_data = Message::VideoData { user_id, channel_id: lounge, seq: 0, captured_at_ms: 0, video_codec: VideoCodec::H264, data: vec![0; 16] };
+        for media in [voice_data, video_data] {
+            client.send(encode_frame(&media).unwrap()).await.unwrap();
+            let reply = loop {
+                if let error @ Message::Error { .. } = serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap() {
+                    break error;
+                }
+            };
+            assert!(matches!(reply, Message::Error { code: 400, message } if message == "Text channels don't carry voice or video"));
+        }
+    }
+    
+    #[tokio::test]
+    async fn channels_are_told_when_a_users_video_codec_changes() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let channel_id = state.create_channel(NewChannel { name: "Video".to_string(), ..Default::default() }, false).unwrap().id;
+        let state = Arc::new(Mutex::new(state));
+        let (tx, mut rx) = broadcast::channel(64);
+        let tx = Arc::new(tx);
+        
+        let (user_id, mut client) = connect(&state, &tx, "10.0.0.1:5000", "alice").await;
+        client.send(encode_frame(&Message::JoinChannel { channel_id, ghost: false }).unwrap()).await.unwrap();
+        while !matches!(serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap(), Message::JoinChannelAck { .. }) {}
+        
+        for (seq, video_codec) in [VideoCodec::H264, VideoCodec::H264, VideoCodec::VP9].into_iter().enumerate() {
+            let frame = Message::VideoData { user_id, channel_id, seq: seq as u32, captured_at_ms: 0, video_codec, data: vec![0; 16] };
+            client.send(encode_frame(&frame).unwrap()).await.unwrap();
+        }
+        
+        // Once when the video starts, and again when it switches, but not for every frame
+        let mut announced = Vec::new();
+        while announced.last() != Some(&VideoCodec::VP9) {
+            if let (_, Message::VideoCodecInfo { user_id: id, codec }) = rx.recv().await.unwrap() {
+                assert_eq!(id, user_id);
+                announced.push(codec);
+            }
+        }
+        assert_eq!(announced, [VideoCodec::H264, VideoCodec::VP9]);
+    }
+    
+    #[test]
+    fn sessions_are_warned_before_they_expire_and_can_be_renewed() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (_, mut rx) = login(&mut state, "10.0.0.1:5000", "alice");
+        let session_id = session(&state, "10.0.0.1:5000");
+        let (direct_tx, mut anonymous_rx) = mpsc::unbounded_channel();
+        state.add_session("10.0.0.2:5000".to_string(), direct_tx);
+        
+        // Nothing until five minutes are left
+        state.check_session_expiry(Duration::from_secs(10 * 60));
+        assert!(rx.try_recv().is_err());
+        
+        state.check_session_expiry(SESSION_EXPIRY_WARNING);
+        let Ok(Message::SessionExpiring { remaining_secs, renew_token }) = rx.try_recv() else { panic!("no warning") };
+        assert!((299..=300).contains(&remaining_secs));
+        
+        // Warned once, however often it's checked
+        state.check_session_expiry(SESSION_EXPIRY_WARNING);
+        assert!(rx.try_recv().is_err());
+        
+        // Only the token it was sent renews it, and only once
+        assert!(!state.renew_session(session_id, "guess"));
+        assert!(state.renew_session(session_id, &renew_token));
+        assert!(!state.renew_session(session_id, &renew_token));
+        
+        // The countdown starts again, with a new token
+        state.check_session_expiry(Duration::from_secs(10 * 60));
+        assert!(rx.try_recv().is_err());
+        state.check_session_expiry(SESSION_EXPIRY_WARNING);
+        assert!(matches!(rx.try_recv(), Ok(Message::SessionExpiring { renew_token: token, .. }) if token != renew_token));
+        
+        state.check_session_expiry(Duration::ZERO);
+        assert!(matches!(rx.try_recv(), Ok(Message::SessionExpired {})));
+        
+        // Connections that haven't logged in have no session to expire
+        assert!(anonymous_rx.try_recv().is_err());
+    }
+    
+    #[test]
+    fn media_over_the_channel_limit_is_dropped() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        // 80 kbps is 10240 bytes a window
+        let limited = state.create_channel(NewChannel { name: "Limited".to_string(), media_bandwidth_limit_kbps: 80, ..Default::default() }, false).unwrap().id;
+        let unlimited = state.create_channel(NewChannel { name: "Unlimited".to_string(), ..Default::default() }, false).unwrap().id;
+        let user_id = Uuid::new_v4();
+        
+        assert!(state.admit_media(limited, 6_000));
+        assert!(state.admit_media(limited, 4_240));
+        assert!(!state.admit_media(limited, 1));
+        // A dropped frame doesn't count against the allowance
+        assert_eq!(state.bytes_sent_this_second[&limited], 10_240);
+        
+        // The sender is told once a window
+        assert!(state.notify_rate_limited(limited, user_id));
+        assert!(!state.notify_rate_limited(limited, user_id));
+        
+        for _ in 0..100 {
+            assert!(state.admit_media(unlimited, 64 * 1024));
+        }
+        
+        // What reset_media_rates does each window
+        state.bytes_sent_this_second.clear();
+        state.rate_limited.clear();
+        assert!(state.admit_media(limited, 10_240));
+        assert!(state.notify_rate_limited(limited, user_id));
+    }
+    
+    // A client connected over an in-memory stream and logged in, with its user id
+    async fn connect(
+        state: &Arc<Mutex<ServerState>>,
+        tx: &Arc<broadcast::Sender<(Uuid, Message)>>,
+        addr: &str,
+        username: &str,
+    ) -> (Uuid, tokio_util::codec::Framed<tokio::io::DuplexStream, LengthDelimitedCodec>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_connection(server, addr.to_string(), Arc::clone(state), Arc::clone(tx)));
+        let mut client = tokio_util::codec::Framed::new(client, frame_codec());
+        
+        let login = Message::LoginRequest { username: username.to_string(), password: "hunter2".to_string() };
+        client.send(encode_frame(&login).unwrap()).await.unwrap();
+        let user_id = match serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap() {
+            Message::LoginResponse { user_id: Some(user_id), .. } => user_id,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        (user_id, client)
+    }
+    
+    #[tokio::test]
+    async fn clients_that_fall_behind_keep_getting_broadcasts() {
+        let state = Arc::new(Mutex::new(ServerState::new(Arc::new(InMemorySessionStore::new()))));
+        let (tx, _) = broadcast::channel(16);
+        let tx = Arc::new(tx);
+        let (_, mut client) = connect(&state, &tx, "10.0.0.1:5000", "alice").await;
+        
+        // More than the broadcast holds, before the session gets a chance to read any
+        for i in 0..40 {
+            tx.send((Uuid::new_v4(), Message::ServerAnnouncement { text: i.to_string() })).unwrap();
+        }
+        
+        let last = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Message::ServerAnnouncement { text } = serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap() {
+                    if text == "39" {
+                        break text;
+                    }
+                }
+            }
+        })
+        .await;
+        assert_eq!(last.as_deref(), Ok("39"));
+    }
+    
+    #[tokio::test]
+    async fn joining_or_leaving_before_logging_in_doesnt_take_the_server_down() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let channel_id = state.create_channel(NewChannel { name: "Lounge".to_string(), ..Default::default() }, false).unwrap().id;
+        let state = Arc::new(Mutex::new(state));
+        let (tx, _) = broadcast::channel(16);
+        let tx = Arc::new(tx);
+        
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_connection(server, "10.0.0.1:5000".to_string(), Arc::clone(&state), Arc::clone(&tx)));
+        let mut client = tokio_util::codec::Framed::new(client, frame_codec());
+        client.send(encode_frame(&Message::JoinChannel { channel_id, ghost: false }).unwrap()).await.unwrap();
+        let reply: Message = serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(reply, Message::Error { code: 401, .. }));
+        client.send(encode_frame(&Message::LeaveChannel { channel_id }).unwrap()).await.unwrap();
+        let reply: Message = serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(reply, Message::Error { code: 401, .. }));
+        
+        // The state lock isn't poisoned, so others can still log in
+        assert!(!state.is_poisoned());
+        connect(&state, &tx, "10.0.0.2:5000", "bob").await;
+    }
+    
+    // The code of the next error the client is sent, skipping everything before it
+    async fn next_error(client: &mut tokio_util::codec::Framed<tokio::io::DuplexStream, LengthDelimitedCodec>) -> u32 {
+        loop {
+            if let Message::Error { code, .. } = serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap() {
+                return code;
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn only_admins_can_encrypt_a_channel() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        state.admin_users = vec!["admin".to_string()];
+        let channel_id = state.create_channel(NewChannel { name: "Lounge".to_string(), ..Default::default() }, false).unwrap().id;
+        let state = Arc::new(Mutex::new(state));
+        let (tx, mut rx) = broadcast::channel(64);
+        let tx = Arc::new(tx);
+        let hello = encode_frame(&Message::Hello { supported_features: Features::E2E_VOICE }).unwrap();
+        let enable = encode_frame(&Message::E2EChannelEnabled { channel_id }).unwrap();
+        
+        let (_, mut alice) = connect(&state, &tx, "10.0.0.1:5000", "alice").await;
+        alice.send(hello.clone()).await.unwrap();
+        alice.send(enable.clone()).await.unwrap();
+        assert_eq!(next_error(&mut alice).await, 403);
+        assert!(!state.lock().unwrap().channels[&channel_id].e2e_encrypted);
+        
+        let (_, mut admin) = connect(&state, &tx, "10.0.0.2:5000", "admin").await;
+        admin.send(hello).await.unwrap();
+        admin.send(enable).await.unwrap();
+        while !matches!(rx.recv().await.unwrap(), (_, Message::E2EChannelEnabled { .. })) {}
+        assert!(state.lock().unwrap().channels[&channel_id].e2e_encrypted);
+    }
+    
+    #[tokio::test]
+    async fn only_admins_can_move_channels() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        state.admin_users = vec!["admin".to_string()];
+        let games = state.create_channel(NewChannel { name: "Games".to_string(), ..Default::default() }, false).unwrap().id;
+        let chess = state.create_channel(NewChannel { name: "Chess".to_string(), ..Default::default() }, false).unwrap().id;
+        let state = Arc::new(Mutex::new(state));
+        let (tx, mut rx) = broadcast::channel(64);
+        let tx = Arc::new(tx);
+        let move_chess = encode_frame(&Message::UpdateChannel { channel_id: chess, parent_id: Some(games) }).unwrap();
+        
+        let (_, mut alice) = connect(&state, &tx, "10.0.0.1:5000", "alice").await;
+        alice.send(move_chess.clone()).await.unwrap();
+        assert_eq!(next_error(&mut alice).await, 403);
+        assert_eq!(state.lock().unwrap().channels[&chess].parent_id, None);
+        
+        let (_, mut admin) = connect(&state, &tx, "10.0.0.2:5000", "admin").await;
+        admin.send(move_chess).await.unwrap();
+        while !matches!(rx.recv().await.unwrap(), (_, Message::ChannelUpdate { .. })) {}
+        assert_eq!(state.lock().unwrap().channels[&chess].parent_id, Some(games));
+    }
+    
+    #[tokio::test]
+    async fn user_list_updates_add_up_to_the_full_list() {
+        let state = Arc::new(Mutex::new(ServerState::new(Arc::new(InMemorySessionStore::new()))));
+        let (tx, mut rx) = broadcast::channel(64);
+        let tx = Arc::new(tx);
+        
+        // What a client keeps, starting from the list it's sent on login
+        let (_, mut alice) = connect(&state, &tx, "10.0.0.1:5000", "alice").await;
+        let mut users: HashMap<Uuid, User> = match serde_json::from_slice(&alice.next().await.unwrap().unwrap()).unwrap() {
+            Message::ServerInfo { server } => server.users.into_iter().map(|u| (u.id, u)).collect(),
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        let mut apply = |message: Message| match message {
+            Message::UserJoined { user } | Message::UserUpdated { user } => {
+                users.insert(user.id, user);
+            }
+            Message::UserLeft { user_id } => {
+                users.remove(&user_id);
+            }
+            _ => {}
+        };
+        
+        let (bob_id, mut bob) = connect(&state, &tx, "10.0.0.2:5000", "bob").await;
+        let (_, carol) = connect(&state, &tx, "10.0.0.3:5000", "carol").await;
+        // Bob on a second device isn't a new user, and closing it doesn't take bob offline
+        let (_, bob_again) = connect(&state, &tx, "10.0.0.4:5000", "bob").await;
+        drop(bob_again);
+        drop(carol);
+        loop {
+            let (_, message) = rx.recv().await.unwrap();
+            let left = matches!(message, Message::UserLeft { .. });
+            apply(message);
+            if left {
+                break;
+            }
+        }
+        while state.lock().unwrap().sessions.len() > 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        
+        bob.send(encode_frame(&Message::StatusUpdate { user_id: bob_id, status: UserStatus::Away }).unwrap()).await.unwrap();
+        loop {
+            let (_, message) = rx.recv().await.unwrap();
+            let updated = matches!(message, Message::UserUpdated { .. });
+            apply(message);
+            if updated {
+                break;
+            }
+        }
+        
+        let mut full: Vec<User> = state.lock().unwrap().get_server_info().users;
+        let mut kept: Vec<User> = users.into_values().collect();
+        full.sort_by_key(|u| u.id);
+        kept.sort_by_key(|u| u.id);
+        assert_eq!(serde_json::to_value(&kept).unwrap(), serde_json::to_value(&full).unwrap());
+        assert_eq!(kept.iter().map(|u| u.username.as_str()).collect::<HashSet<_>>(), HashSet::from(["alice", "bob"]));
+        assert_eq!(kept.iter().find(|u| u.id == bob_id).unwrap().status, UserStatus::Away);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn clients_that_stop_answering_pings_are_dropped() {
+        let state = Arc::new(Mutex::new(ServerState::new(Arc::new(InMemorySessionStore::new()))));
+        let (tx, _) = broadcast::channel(16);
+        let (client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(handle_connection(server, "10.0.0.1:5000".to_string(), Arc::clone(&state), Arc::new(tx)));
+        let mut client = tokio_util::codec::Framed::new(client, frame_codec());
+        
+        let started = tokio::time::Instant::now();
+        let ping: Message = serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap();
+        let Message::Ping { nonce } = ping else { panic!("unexpected message: {:?}", ping) };
+        assert_eq!(started.elapsed(), Duration::from_secs(config::get_config().ping_interval_secs));
+        
+        // Answering keeps the connection up until the next ping
+        client.send(encode_frame(&Message::Pong { nonce }).unwrap()).await.unwrap();
+        let ping: Message = serde_json::from_slice(&client.next().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(ping, Message::Ping { .. }));
+        
+        // Then silence
+        assert!(handler.await.unwrap().is_ok());
+        let config = config::get_config();
+        assert_eq!(started.elapsed(), Duration::from_secs(2 * config.ping_interval_secs + config.ping_timeout_secs));
+        assert!(state.lock().unwrap().sessions.is_empty());
+        let entries = state.lock().unwrap().audit_log.query(None, 0, 10);
+        assert_eq!(entries[0].disconnect_reason, Some(DisconnectReason::Timeout));
+    }
+    
+    #[test]
+    fn joiners_are_sent_recent_chat_in_order() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let channel_id = Uuid::new_v4();
+        let alice = join(&mut state, "10.0.0.1:5000", "alice", channel_id, false);
+        
+        let first = state.archive_text_message(Uuid::new_v4(), channel_id, alice, "first", 100);
+        // The clock went backwards, but the order is kept
+        let second = state.archive_text_message(Uuid::new_v4(), channel_id, alice, "second", 90);
+        assert_eq!((first, second), (100, 100));
+        state.archive_text_message(Uuid::new_v4(), Uuid::new_v4(), alice, "elsewhere", 110);
+        
+        let (_, mut bob_rx) = login(&mut state, "10.0.0.2:5000", "bob");
+        state.send_recent_messages(session(&state, "10.0.0.2:5000"), channel_id);
+        
+        let mut contents = Vec::new();
+        while let Ok(Message::TextMessage { content, .. }) = bob_rx.try_recv() {
+            contents.push(content);
+        }
+        assert_eq!(contents, vec!["first", "second"]);
+    }
+    
+    #[test]
+    fn idle_channels_are_deleted_unless_permanent_or_occupied() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let idle = state.create_channel(NewChannel { name: "idle".to_string(), idle_timeout_secs: Some(60), ..Default::default() }, false).unwrap().id;
+        let kept = state.create_channel(NewChannel { name: "kept".to_string(), permanent: true, idle_timeout_secs: Some(60), ..Default::default() }, true).unwrap().id;
+        let occupied = state.create_channel(NewChannel { name: "occupied".to_string(), idle_timeout_secs: Some(60), ..Default::default() }, false).unwrap().id;
+        join(&mut state, "10.0.0.1:5000", "alice", occupied, false);
+        
+        // Only admins can keep a channel for good
+        let mine = NewChannel { name: "mine".to_string(), permanent: true, ..Default::default() };
+        assert_eq!(state.create_channel(mine, false).err().unwrap(), "Only admins can create permanent channels");
+        
+        let later = Instant::now() + Duration::from_secs(120);
+        assert_eq!(state.remove_idle_channels(later), vec![idle]);
+        assert!(!state.channels.contains_key(&idle));
+        assert!(state.channels.contains_key(&kept));
+        assert!(state.channels.contains_key(&occupied));
+        // The built-in channels are permanent
+        assert_eq!(state.channels.len(), 5);
+    }
+    
+    fn add_channels(state: &mut ServerState, count: usize) {
+        for i in 0..count {
+            state.create_channel(NewChannel { name: format!("room-{:04}", i), ..Default::default() }, false).unwrap();
+        }
+    }
+    
+    #[test]
+    fn channels_are_paged_in_name_order() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        add_channels(&mut state, 7);
+        // Along with the three built-in channels
+        let total = state.channels.len() as u32;
+        
+        let mut names = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (page, page_total) = state.channel_page(offset, 4);
+            assert_eq!(page_total, total);
+            assert!(page.len() <= 4);
+            if page.is_empty() {
+                break;
+            }
+            offset += page.len() as u32;
+            names.extend(page.into_iter().map(|c| c.name));
+        }
+        
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+        assert_eq!(names.len() as u32, total);
+        assert_eq!(state.channel_page(0, u32::MAX).0.len() as u32, total.min(MAX_CHANNEL_PAGE_SIZE));
+    }
+    
+    #[test]
+    fn big_servers_send_a_summary_on_login() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        login(&mut state, "10.0.0.1:5000", "alice");
+        assert!(matches!(state.login_server_info(), Message::ServerInfo { .. }));
+        
+        add_channels(&mut state, config::get_config().eager_load_threshold as usize);
+        match state.login_server_info() {
+            Message::ServerInfoSummary { server_id, channel_count, user_count, .. } => {
+                assert_eq!(server_id, state.get_server_info().id);
+                assert_eq!(channel_count as usize, state.channels.len());
+                assert_eq!(user_count, 1);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn diagnostic_pings_are_echoed_whole() {
+        let padding = "x".repeat(1400);
+        assert!(matches!(diagnostic_pong(1400, padding.clone()), Message::DiagnosticPong { payload_size: 1400, padding: p } if p == padding));
+        assert!(matches!(diagnostic_pong(2000, padding), Message::Error { code: 400, .. }));
+        assert!(matches!(diagnostic_pong(MAX_DIAGNOSTIC_PAYLOAD + 1, String::new()), Message::Error { code: 413, .. }));
+    }
+    
+    #[test]
+    fn broadcast_self_delivers_own_voice_only() {
+        assert!(should_forward(Some(USER), USER, true, &voice(USER)));
+        assert!(!should_forward(Some(USER), USER, true, &Message::VoiceStarted { user_id: USER }));
+    }
+    
+    #[test]
+    fn dropped_connections_resume_with_their_token() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (direct_tx, _direct_rx) = mpsc::unbounded_channel();
+        let first = state.add_session("10.0.0.1:5000".to_string(), direct_tx);
+        
+        let (user_id, token) = match password_login(&mut state, first, "alice", "hunter2") {
+            Message::LoginResponse { success: true, user_id: Some(user_id), token: Some(token), .. } => (user_id, token),
+            other => panic!("unexpected message: {:?}", other),
+        };
+        
+        // Back on a new connection from the same address and port, before the old one is
+        // noticed to have gone, without the password
+        let (direct_tx, _direct_rx) = mpsc::unbounded_channel();
+        let second = state.add_session("10.0.0.1:5000".to_string(), direct_tx);
+        assert_ne!(first, second);
+        let resumed = state.handle_login_step(second, Message::ResumeSession { token: token.clone() });
+        assert!(matches!(resumed, Message::LoginResponse { success: true, user_id: Some(id), token: Some(t), .. } if id == user_id && t == token));
+        
+        // Closing the old connection leaves the new one logged in
+        state.remove_session(first);
+        assert_eq!(state.sessions[&second].user_id, Some(user_id));
+        assert!(state.is_connected(user_id));
+        
+        // Stale, forged and unknown tokens get a 401
+        let stale = state.session_tokens.issue(user_id, 0);
+        assert!(matches!(state.handle_login_step(second, Message::ResumeSession { token: stale }), Message::Error { code: 401, .. }));
+        let forged = SessionTokens::new(60).issue(user_id, announcements::now_secs());
+        assert!(matches!(state.handle_login_step(second, Message::ResumeSession { token: forged }), Message::Error { code: 401, .. }));
+        let restarted = ServerState::new(Arc::new(InMemorySessionStore::new())).session_tokens.verify(&token, announcements::now_secs());
+        assert!(restarted.is_err());
+    }
+    
+    #[tokio::test]
+    async fn users_and_channels_outlive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("open-reverb-state-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.db");
+        
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        state.load_database(Database::open(&path).unwrap());
+        let alice = state.find_or_add_user("Alice".to_string()).unwrap();
+        let lounge = state.create_channel(NewChannel { name: "Lounge".to_string(), channel_type: ChannelType::Text, ..Default::default() }, false).unwrap().id;
+        let channel_count = state.channels.len();
+        state.database.flush().await;
+        
+        let mut restarted = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        restarted.load_database(Database::open(&path).unwrap());
+        assert_eq!(restarted.channels.len(), channel_count);
+        assert_eq!(restarted.channels[&lounge].channel_type, ChannelType::Text);
+        assert_eq!(restarted.users[&alice].status, UserStatus::Offline);
+        assert_eq!(restarted.find_or_add_user("Alice".to_string()), Ok(alice));
+        assert!(restarted.find_or_add_user("alice".to_string()).is_err());
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    // Alice starts recording with Bob and Carol in the channel
+    fn start_recording(state: &mut ServerState) -> (Uuid, Uuid, Uuid, [mpsc::UnboundedReceiver<Message>; 3]) {
+        let channel_id = Uuid::new_v4();
+        let (alice, alice_rx) = login(state, "10.0.0.1:5000", "alice");
+        let (bob, bob_rx) = login(state, "10.0.0.2:5000", "bob");
+        let (carol, carol_rx) = login(state, "10.0.0.3:5000", "carol");
+        for session in state.sessions.values_mut() {
+            session.channels.push(channel_id);
+        }
+        
+        assert!(state.handle_recording_request(alice, true, true).is_some());
+        (alice, bob, carol, [alice_rx, bob_rx, carol_rx])
+    }
+    
+    fn recording_notice(rx: &mut mpsc::UnboundedReceiver<Message>) -> Option<bool> {
+        match rx.try_recv() {
+            Ok(Message::RecordingConsent { recording_started, .. }) => Some(recording_started),
+            Ok(other) => panic!("unexpected message: {:?}", other),
+            Err(_) => None,
+        }
+    }
+    
+    #[test]
+    fn recordings_start_once_everyone_consents() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (alice, bob, carol, [mut alice_rx, mut bob_rx, mut carol_rx]) = start_recording(&mut state);
+        
+        // Everyone else is asked, and Alice waits on them
+        assert_eq!((recording_notice(&mut bob_rx), recording_notice(&mut carol_rx)), (Some(true), Some(true)));
+        assert_eq!(recording_notice(&mut alice_rx), None);
+        
+        state.handle_consent_response(bob, true);
+        // Answering twice doesn't count for Carol
+        state.handle_consent_response(bob, true);
+        assert_eq!(recording_notice(&mut alice_rx), None);
+        
+        state.handle_consent_response(carol, true);
+        assert_eq!(recording_notice(&mut alice_rx), Some(true));
+        assert!(state.pending_recordings.is_empty());
+        
+        // Stopping tells everyone, Alice included
+        assert!(state.handle_recording_request(alice, false, true).is_none());
+        for rx in [&mut alice_rx, &mut bob_rx, &mut carol_rx] {
+            assert_eq!(recording_notice(rx), Some(false));
+        }
+    }
+    
+    #[test]
+    fn one_rejection_refuses_the_recording() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (alice, bob, carol, [mut alice_rx, mut bob_rx, mut carol_rx]) = start_recording(&mut state);
+        recording_notice(&mut bob_rx);
+        recording_notice(&mut carol_rx);
+        
+        state.handle_consent_response(bob, true);
+        state.handle_consent_response(carol, false);
+        assert!(matches!(alice_rx.try_recv(), Ok(Message::Error { code: 451, .. })));
+        for rx in [&mut bob_rx, &mut carol_rx] {
+            assert_eq!(recording_notice(rx), Some(false));
+        }
+        assert!(!state.pending_recordings.contains_key(&alice));
+        
+        // Without a request waiting, a late answer changes nothing
+        state.handle_consent_response(bob, true);
+        assert!(alice_rx.try_recv().is_err());
+    }
+    
+    #[test]
+    fn timed_out_requests_are_refused_unless_consent_is_off() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (alice, _, _, [mut alice_rx, ..]) = start_recording(&mut state);
+        
+        // What the consent timeout does, once it fires
+        state.refuse_recording(alice);
+        assert!(matches!(alice_rx.try_recv(), Ok(Message::Error { code: 451, .. })));
+        
+        // Not requiring consent, members are only told and Alice starts straight away
+        assert!(state.handle_recording_request(alice, true, false).is_none());
+        assert_eq!(recording_notice(&mut alice_rx), Some(true));
+        assert!(state.pending_recordings.is_empty());
+    }
+    
+    fn login_error(message: Message) -> Option<String> {
+        match message {
+            Message::LoginResponse { success: false, error, .. } => error,
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    
+    // Log in with a password as login_with_password does, hashing with the state at hand
+    fn password_login(state: &mut ServerState, session_id: Uuid, username: &str, password: &str) -> Message {
+        let (user_id, hash) = state.stored_password(username.to_string()).unwrap();
+        let check = PasswordCheck::run(password, hash.as_deref());
+        state.finish_password_login(session_id, user_id, check)
+    }
+    
+    #[test]
+    fn wrong_passwords_are_refused() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (direct_tx, _direct_rx) = mpsc::unbounded_channel();
+        let session_id = state.add_session("10.0.0.1:5000".to_string(), direct_tx);
+        
+        // The first login sets the password
+        assert!(matches!(password_login(&mut state, session_id, "alice", "hunter2"), Message::LoginResponse { success: true, .. }));
+        assert_eq!(login_error(password_login(&mut state, session_id, "alice", "hunter3")).as_deref(), Some("Invalid username or password"));
+        assert!(matches!(password_login(&mut state, session_id, "alice", "hunter2"), Message::LoginResponse { success: true, .. }));
+        
+        // Two first logins racing, the second one's password doesn't replace the first's
+        let (user_id, hash) = state.stored_password("bob".to_string()).unwrap();
+        let first = PasswordCheck::run("hunter2", hash.as_deref());
+        let second = PasswordCheck::run("letmein", hash.as_deref());
+        assert!(matches!(state.finish_password_login(session_id, user_id, first), Message::LoginResponse { success: true, .. }));
+        assert!(login_error(state.finish_password_login(session_id, user_id, second)).is_some());
+        assert!(matches!(password_login(&mut state, session_id, "bob", "hunter2"), Message::LoginResponse { success: true, .. }));
+    }
+    
+    // A session whose password was accepted, waiting for a TOTP code as it would with
+    // totp_required set
+    fn start_totp(state: &mut ServerState, addr: &str, username: &str) -> (Uuid, mpsc::UnboundedReceiver<Message>) {
+        let (direct_tx, direct_rx) = mpsc::unbounded_channel();
+        let session_id = state.add_session(addr.to_string(), direct_tx);
+        let user_id = state.find_or_add_user(username.to_string()).unwrap();
+        assert!(matches!(state.request_totp(session_id, user_id), Message::TOTPRequest { user_id: id } if id == user_id));
+        (user_id, direct_rx)
+    }
+    
+    fn setup_secret(direct_rx: &mut mpsc::UnboundedReceiver<Message>) -> String {
+        match direct_rx.try_recv() {
+            Ok(Message::SetupTOTP { secret, .. }) => secret,
+            other => panic!("expected SetupTOTP, got {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn totp_codes_are_accepted_once() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (alice, mut direct_rx) = start_totp(&mut state, "10.0.0.1:5000", "alice");
+        let secret = setup_secret(&mut direct_rx);
+        let code = totp::code(&secret, announcements::now_secs());
+        
+        assert_eq!(login_error(state.handle_totp_response(session(&state, "10.0.0.1:5000"), alice, "12345x".to_string())).as_deref(), Some("Invalid code"));
+        let accepted = state.handle_totp_response(session(&state, "10.0.0.1:5000"), alice, code.clone());
+        assert!(matches!(accepted, Message::LoginResponse { success: true, user_id: Some(id), .. } if id == alice));
+        assert_eq!(state.database.user_by_id(alice).unwrap().totp_secret.as_ref(), Some(&secret));
+        
+        // The same code again, on another connection: no new secret, and the code is spent
+        let (_, mut direct_rx) = start_totp(&mut state, "10.0.0.1:5001", "alice");
+        assert!(direct_rx.try_recv().is_err());
+        assert_eq!(login_error(state.handle_totp_response(session(&state, "10.0.0.1:5001"), alice, code)).as_deref(), Some("Invalid code"));
+        assert_eq!(state.sessions[&session(&state, "10.0.0.1:5001")].user_id, None);
+    }
+    
+    #[test]
+    fn totp_guesses_are_limited() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (alice, mut direct_rx) = start_totp(&mut state, "10.0.0.1:5000", "alice");
+        let secret = setup_secret(&mut direct_rx);
+        
+        for _ in 1..MAX_TOTP_ATTEMPTS {
+            assert_eq!(login_error(state.handle_totp_response(session(&state, "10.0.0.1:5000"), alice, "12345x".to_string())).as_deref(), Some("Invalid code"));
+        }
+        assert_eq!(
+            login_error(state.handle_totp_response(session(&state, "10.0.0.1:5000"), alice, "12345x".to_string())).as_deref(),
+            Some("Too many invalid codes, log in again")
+        );
+        
+        // Even the right code needs the password sent again now
+        let code = totp::code(&secret, announcements::now_secs());
+        assert_eq!(login_error(state.handle_totp_response(session(&state, "10.0.0.1:5000"), alice, code)).as_deref(), Some("No login is waiting for a code"));
+        assert_eq!(state.database.user_by_id(alice).unwrap().totp_secret, None);
+    }
+    
+    #[tokio::test]
+    async fn totp_secrets_outlive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("open-reverb-totp-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.db");
+        
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        state.load_database(Database::open(&path).unwrap());
+        let (alice, mut direct_rx) = start_totp(&mut state, "10.0.0.1:5000", "alice");
+        let secret = setup_secret(&mut direct_rx);
+        let code = totp::code(&secret, announcements::now_secs());
+        assert!(matches!(state.handle_totp_response(session(&state, "10.0.0.1:5000"), alice, code), Message::LoginResponse { success: true, .. }));
+        state.database.flush().await;
+        
+        // After a restart, logging in as alice doesn't hand out a new secret, and a code
+        // from any other secret is refused
+        let mut restarted = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        restarted.load_database(Database::open(&path).unwrap());
+        let (_, mut direct_rx) = start_totp(&mut restarted, "10.0.0.2:5000", "alice");
+        assert!(direct_rx.try_recv().is_err());
+        let other_secret = totp::generate_secret();
+        let guess = totp::code(&other_secret, announcements::now_secs());
+        assert!(login_error(restarted.handle_totp_response(session(&restarted, "10.0.0.2:5000"), alice, guess)).is_some());
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn media_only_accepted_from_members_as_themselves() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let channel_ids: Vec<Uuid> = state.channels.keys().copied().collect();
+        let (here, elsewhere) = (channel_ids[0], channel_ids[1]);
+        let alice = join(&mut state, "10.0.0.1:5000", "alice", here, false);
+        let bob = join(&mut state, "10.0.0.2:5000", "bob", here, false);
+        
+        assert!(state.may_send_media(session(&state, "10.0.0.1:5000"), alice, here));
+        // Speaking as someone else, or into a channel the session isn't in
+        assert!(!state.may_send_media(session(&state, "10.0.0.1:5000"), bob, here));
+        assert!(!state.may_send_media(session(&state, "10.0.0.1:5000"), alice, elsewhere));
+        assert!(!state.may_send_media(Uuid::new_v4(), alice, here));
+    }
+    
+    #[test]
+    fn media_only_reaches_sessions_in_the_channel() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let channel_ids: Vec<Uuid> = state.channels.keys().copied().collect();
+        let (here, elsewhere) = (channel_ids[0], channel_ids[1]);
+        let alice = join(&mut state, "10.0.0.1:5000", "alice", here, false);
+        join(&mut state, "10.0.0.2:5000", "bob", elsewhere, false);
+        
+        let mut alice_subscriptions = HashMap::new();
+        let mut bob_subscriptions = HashMap::new();
+        state.sync_channel_subscriptions(session(&state, "10.0.0.1:5000"), &mut alice_subscriptions);
+        state.sync_channel_subscriptions(session(&state, "10.0.0.2:5000"), &mut bob_subscriptions);
+        
+        let state = Mutex::new(state);
+        send_to_channel(&state, here, alice, &voice(alice));
+        assert!(matches!(alice_subscriptions.get_mut(&here).unwrap().try_recv(), Ok((sender, Message::VoiceData { .. })) if sender == alice));
+        assert!(bob_subscriptions.get_mut(&elsewhere).unwrap().try_recv().is_err());
+        
+        // Leaving unsubscribes
+        let mut state = state.into_inner().unwrap();
+        state.sessions.get_mut(&session(&state, "10.0.0.1:5000")).unwrap().channels.clear();
+        state.sync_channel_subscriptions(session(&state, "10.0.0.1:5000"), &mut alice_subscriptions);
+        assert!(alice_subscriptions.is_empty());
+    }
+    
+    #[test]
+    fn resent_media_frames_are_forwarded_once() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let (channel_id, alice, bob) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let deduplicated = metrics::get_metrics().deduplicated_frames_total.get();
+        
+        assert!(!state.is_duplicate_frame(channel_id, alice, 1));
+        assert!(state.is_duplicate_frame(channel_id, alice, 1));
+        assert!(metrics::get_metrics().deduplicated_frames_total.get() > deduplicated);
+        
+        // The same number from someone else, or in another channel, is a different frame
+        assert!(!state.is_duplicate_frame(channel_id, bob, 1));
+        assert!(!state.is_duplicate_frame(Uuid::new_v4(), alice, 1));
+        
+        // Only so many recent frames are remembered
+        for seq in 2..=SEEN_SEQ_CAPACITY as u32 + 1 {
+            assert!(!state.is_duplicate_frame(channel_id, alice, seq));
+        }
+        assert!(!state.is_duplicate_frame(channel_id, alice, 1));
+    }
+    
+    #[test]
+    fn channel_names_are_checked_on_create() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let mut create = |name: &str| state.create_channel(NewChannel { name: name.to_string(), ..Default::default() }, false);
+        
+        assert!(create("Lounge").is_ok());
+        assert_eq!(create("  ").err().unwrap(), "Channel name cannot be empty");
+        assert_eq!(create(&"x".repeat(65)).err().unwrap(), "Channel name cannot be longer than 64 characters");
+        assert_eq!(create("lounge").err().unwrap(), "A channel named lounge already exists");
+        assert!(create(&"x".repeat(64)).is_ok());
+    }
+    
+    #[test]
+    fn searches_leave_out_restricted_channels_the_user_isnt_in() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let mut create = |name: &str| state.create_channel(NewChannel { name: name.to_string(), ..Default::default() }, false).unwrap().id;
+        let open = create("Lounge");
+        let lobby = create("Staff");
+        let encrypted = create("Secrets");
+        state.set_lobby_mode(lobby, true).unwrap();
+        state.enable_e2e(encrypted).unwrap();
+        let member = join(&mut state, "10.0.0.1:5000", "alice", lobby, false);
+        let (outsider, _) = login(&mut state, "10.0.0.2:5000", "bob");
+        for channel_id in [open, lobby, encrypted] {
+            state.archive_text_message(Uuid::new_v4(), channel_id, member, "meeting at noon", 1);
+        }
+        
+        let channels = |results: Vec<SearchResult>| results.into_iter().map(|r| r.channel_id).collect::<HashSet<_>>();
+        assert_eq!(channels(state.search_messages(outsider, "meeting", &[], 10, 0)), HashSet::from([open]));
+        assert!(state.search_messages(outsider, "meeting", &[lobby, encrypted], 10, 0).is_empty());
+        assert_eq!(channels(state.search_messages(member, "meeting", &[], 10, 0)), HashSet::from([open, lobby]));
+    }
+    
+    #[test]
+    fn deleting_a_channel_takes_its_members_out() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let parent = state.create_channel(NewChannel { name: "Games".to_string(), ..Default::default() }, false).unwrap().id;
+        let child = state.create_channel(NewChannel { name: "Chess".to_string(), parent_id: Some(parent), ..Default::default() }, false).unwrap().id;
+        let built_in = *state.channels.values().find(|channel| channel.permanent).map(|channel| &channel.id).unwrap();
+        join(&mut state, "10.0.0.1:5000", "alice", child, false);
+        join(&mut state, "10.0.0.2:5000", "bob", built_in, false);
+        
+        assert_eq!(state.delete_channel(parent, false).err().unwrap(), "Delete the channel's sub-channels first");
+        assert_eq!(state.delete_channel(built_in, false).err().unwrap(), "Only admins can delete permanent channels");
+        
+        assert_eq!(state.delete_channel(child, false), Ok(vec![session(&state, "10.0.0.1:5000")]));
+        assert!(!state.channels.contains_key(&child));
+        assert!(state.sessions[&session(&state, "10.0.0.1:5000")].channels.is_empty());
+        assert_eq!(state.sessions[&session(&state, "10.0.0.2:5000")].channels, vec![built_in]);
+        
+        assert!(state.delete_channel(parent, false).is_ok());
+        assert!(state.delete_channel(built_in, true).is_ok());
+        assert_eq!(state.delete_channel(built_in, true).err().unwrap(), "Channel not found");
+    }
+    
+    #[test]
+    fn deleting_a_channel_forgets_its_media_state() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let dir = std::env::temp_dir().join(format!("open-reverb-recording-{}", Uuid::new_v4()));
+        state.recorder = Some(Recorder::new(&dir));
+        let channel_id = state.create_channel(NewChannel { name: "Lounge".to_string(), media_bandwidth_limit_kbps: 8, ..Default::default() }, false).unwrap().id;
+        let user_id = Uuid::new_v4();
+        
+        assert!(!state.is_duplicate_frame(channel_id, user_id, 1));
+        assert!(!state.admit_media(channel_id, 2048));
+        assert!(state.notify_rate_limited(channel_id, user_id));
+        state.record_forwarded(channel_id, 100);
+        state.recording_member_joined(channel_id, user_id);
+        state.recorder.as_mut().unwrap().acknowledge(user_id);
+        assert!(state.recorder.as_ref().unwrap().is_active(channel_id));
+        
+        state.delete_channel(channel_id, false).unwrap();
+        assert!(!state.seen_seq.contains_key(&channel_id));
+        assert!(!state.bytes_forwarded.contains_key(&channel_id));
+        assert!(!state.bytes_sent_this_second.contains_key(&channel_id));
+        assert!(state.rate_limited.is_empty());
+        assert!(!state.recorder.as_ref().unwrap().is_active(channel_id));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+    
+    #[test]
+    fn channels_cannot_be_moved_under_themselves() {
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let mut create = |name: &str, parent_id| state.create_channel(NewChannel { name: name.to_string(), parent_id, ..Default::default() }, false).unwrap().id;
+        let games = create("Games", None);
+        let chess = create("Chess", Some(games));
+        let blitz = create("Blitz", Some(chess));
+        let music = create("Music", None);
+        
+        // Itself, its child, and its grandchild
+        for parent_id in [games, chess, blitz] {
+            assert_eq!(state.update_channel(games, Some(parent_id)).err().unwrap(), "Circular channel hierarchy detected");
+        }
+        assert!(!validate_no_cycle(music, music, &state.channels));
+        assert!(validate_no_cycle(music, blitz, &state.channels));
+        
+        // Nothing was moved
+        assert_eq!(state.channels[&games].parent_id, None);
+        
+        // A cycle already in the tree is stopped at rather than walked forever
+        state.channels.get_mut(&games).unwrap().parent_id = Some(blitz);
+        assert!(!validate_no_cycle(music, chess, &state.channels));
+    }
+    
+    #[test]
+    fn channel_hierarchies_only_go_so_deep() {
+        let max_depth = config::get_config().max_hierarchy_depth;
+        let mut state = ServerState::new(Arc::new(InMemorySessionStore::new()));
+        let mut create = |name: String, parent_id| state.create_channel(NewChannel { name, parent_id, ..Default::default() }, false);
+        
+        let mut deepest = None;
+        for level in 0..max_depth {
+            deepest = Some(create(format!("level-{}", level), deepest).unwrap().id);
+        }
+        assert_eq!(
+            create("too-deep".to_string(), deepest).err().unwrap(),
+            format!("Channel hierarchy cannot be deeper than {} levels", max_depth)
+        );
+        
+        // A channel moves with its sub-channels, so both count
+        let parent = create("parent".to_string(), None).unwrap().id;
+        let child = create("child".to_string(), Some(parent)).unwrap().id;
+        let top = state.channels.values().find(|channel| channel.name == "level-0").unwrap().id;
+        assert!(state.update_channel(parent, Some(top)).is_ok());
+        assert_eq!(channel_depth(child, &state.channels), 3);
+        assert_eq!(
+            state.update_channel(parent, deepest).err().unwrap(),
+            format!("Channel hierarchy cannot be deeper than {} levels", max_depth)
+        );
+        assert!(state.update_channel(parent, None).is_ok());
+    }
+}
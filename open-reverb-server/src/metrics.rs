@@ -0,0 +1,43 @@
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// A monotonically increasing count of events
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+    
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Server-wide counters
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // Media frames dropped because they had already been forwarded
+    pub deduplicated_frames_total: Counter,
+    // Chat messages deleted for being past their channel's retention period
+    pub messages_deleted_total: Counter,
+    // Channels created by users
+    pub channel_creation_total: Counter,
+    // Channels deleted for having been idle too long
+    pub channel_deleted_total: Counter,
+    // Connections refused by the IP allowlist or blocklist
+    pub blocked_connections_total: Counter,
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+pub fn get_metrics() -> &'static Metrics {
+    &METRICS
+}
\ No newline at end of file
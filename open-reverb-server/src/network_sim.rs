@@ -0,0 +1,99 @@
+use rand::Rng;
+use std::time::Duration;
+use tracing::debug;
+
+use crate::config::NetworkSimParams;
+
+// Makes every connection behave like a poor network, for testing clients.
+// Applied to whole messages in both directions.
+#[derive(Debug, Clone)]
+pub struct ServerNetworkSimulator {
+    params: NetworkSimParams,
+}
+
+impl ServerNetworkSimulator {
+    pub fn new(params: NetworkSimParams) -> Self {
+        Self { params }
+    }
+    
+    // Delay the message, then drop or corrupt it. Returns None if the message was lost.
+    pub async fn degrade(&self, mut message: Vec<u8>) -> Option<Vec<u8>> {
+        let (delay, dropped, corrupt_at) = {
+            let mut rng = rand::thread_rng();
+            
+            let jitter = if self.params.jitter_ms > 0 {
+                rng.gen_range(0..=self.params.jitter_ms)
+            } else {
+                0
+            };
+            
+            let dropped = rng.gen::<f32>() < self.params.drop_rate;
+            
+            let corrupt_at = if !message.is_empty() && rng.gen::<f32>() < self.params.corrupt_rate {
+                Some((rng.gen_range(0..message.len()), rng.gen_range(0..8)))
+            } else {
+                None
+            };
+            
+            (Duration::from_millis((self.params.latency_ms + jitter) as u64), dropped, corrupt_at)
+        };
+        
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        
+        if dropped {
+            debug!("Simulated network dropped a {} byte message", message.len());
+            return None;
+        }
+        
+        if let Some((index, bit)) = corrupt_at {
+            message[index] ^= 1 << bit;
+        }
+        
+        Some(message)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test(start_paused = true)]
+    async fn messages_are_held_back_by_latency_and_jitter() {
+        let simulator = ServerNetworkSimulator::new(NetworkSimParams { latency_ms: 100, jitter_ms: 50, ..Default::default() });
+        
+        for _ in 0..20 {
+            let started = tokio::time::Instant::now();
+            assert_eq!(simulator.degrade(b"hello".to_vec()).await, Some(b"hello".to_vec()));
+            assert!((100..=150).contains(&started.elapsed().as_millis()));
+        }
+    }
+    
+    #[tokio::test]
+    async fn drop_rate_is_the_fraction_lost() {
+        assert!(ServerNetworkSimulator::new(NetworkSimParams { drop_rate: 1.0, ..Default::default() }).degrade(vec![1]).await.is_none());
+        assert!(ServerNetworkSimulator::new(NetworkSimParams::default()).degrade(vec![1]).await.is_some());
+        
+        let simulator = ServerNetworkSimulator::new(NetworkSimParams { drop_rate: 0.5, ..Default::default() });
+        let mut delivered = 0;
+        for _ in 0..1000 {
+            if simulator.degrade(vec![1]).await.is_some() {
+                delivered += 1;
+            }
+        }
+        assert!((350..650).contains(&delivered), "{} of 1000 delivered", delivered);
+    }
+    
+    #[tokio::test]
+    async fn corruption_flips_a_single_bit() {
+        let simulator = ServerNetworkSimulator::new(NetworkSimParams { corrupt_rate: 1.0, ..Default::default() });
+        let message = vec![0u8; 16];
+        
+        let corrupted = simulator.degrade(message.clone()).await.unwrap();
+        let flipped: u32 = corrupted.iter().zip(&message).map(|(a, b)| (a ^ b).count_ones()).sum();
+        assert_eq!(flipped, 1);
+        
+        // Nothing to flip in an empty message
+        assert_eq!(simulator.degrade(Vec::new()).await, Some(Vec::new()));
+    }
+}
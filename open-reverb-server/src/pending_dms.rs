@@ -0,0 +1,74 @@
+use std::collections::{HashMap, VecDeque};
+
+use open_reverb_common::protocol::Message;
+use uuid::Uuid;
+
+// Direct messages kept for each offline user; past this the oldest are dropped
+pub const MAX_PENDING_DMS: usize = 50;
+
+// Direct messages sent to users while they were offline, delivered when they next log in. Only
+// kept until the server restarts.
+pub struct PendingDirectMessages {
+    queued: HashMap<Uuid, VecDeque<Message>>,
+}
+
+impl PendingDirectMessages {
+    pub fn new() -> Self {
+        Self { queued: HashMap::new() }
+    }
+    
+    pub fn queue(&mut self, to_user_id: Uuid, message: Message) {
+        let queue = self.queued.entry(to_user_id).or_default();
+        if queue.len() == MAX_PENDING_DMS {
+            queue.pop_front();
+        }
+        queue.push_back(message);
+    }
+    
+    // Everything waiting for a user who just logged in, oldest first
+    pub fn take(&mut self, user_id: Uuid) -> Vec<Message> {
+        self.queued.remove(&user_id).map(Vec::from).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn dm(to_user_id: Uuid, n: u64) -> Message {
+        Message::DirectMessage { from_user_id: Uuid::nil(), to_user_id, content: n.to_string(), timestamp: n }
+    }
+    
+    #[test]
+    fn delivered_once_in_order() {
+        let mut pending = PendingDirectMessages::new();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        pending.queue(alice, dm(alice, 1));
+        pending.queue(alice, dm(alice, 2));
+        
+        let timestamps: Vec<u64> = pending
+            .take(alice)
+            .into_iter()
+            .map(|m| match m {
+                Message::DirectMessage { timestamp, .. } => timestamp,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(timestamps, vec![1, 2]);
+        assert!(pending.take(alice).is_empty());
+        assert!(pending.take(bob).is_empty());
+    }
+    
+    #[test]
+    fn oldest_dropped_when_full() {
+        let mut pending = PendingDirectMessages::new();
+        let alice = Uuid::from_u128(1);
+        for n in 0..MAX_PENDING_DMS as u64 + 5 {
+            pending.queue(alice, dm(alice, n));
+        }
+        
+        let queued = pending.take(alice);
+        assert_eq!(queued.len(), MAX_PENDING_DMS);
+        assert!(matches!(&queued[0], Message::DirectMessage { timestamp: 5, .. }));
+    }
+}
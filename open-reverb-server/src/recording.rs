@@ -0,0 +1,453 @@
+// Server-side recording of channel voice, for compliance. Each member's voice goes to an Ogg
+// Opus file of its own, <recording_dir>/<channel ID>/<user ID>-<date>.ogg.
+//
+// Nothing is recorded in a channel until every member has consented with ConsentAcknowledged.
+// Once it is recording, anyone who joins is left out of the recording until they consent too.
+//
+// Voice packets are written to the file as they arrive. Clients send PCM or ADPCM rather than
+// Opus, so the files won't play until there's an Opus encoder.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use open_reverb_common::models::{AudioCodec, AudioQuality};
+use open_reverb_common::voice_codec;
+use uuid::Uuid;
+
+// Ogg Opus granule positions always count samples at 48kHz
+const OPUS_GRANULE_RATE: u64 = 48_000;
+
+// Most lacing values one Ogg page can hold
+const MAX_PAGE_SEGMENTS: usize = 255;
+
+const HEADER_CONTINUED: u8 = 0x01;
+const HEADER_BEGIN: u8 = 0x02;
+const HEADER_END: u8 = 0x04;
+
+pub struct Recorder {
+    dir: PathBuf,
+    channels: HashMap<Uuid, ChannelRecording>,
+}
+
+#[derive(Default)]
+struct ChannelRecording {
+    // Members who haven't consented yet. Recording starts once this is empty.
+    awaiting_consent: HashSet<Uuid>,
+    consented: HashSet<Uuid>,
+    active: bool,
+    files: HashMap<Uuid, UserRecording>,
+}
+
+struct UserRecording {
+    writer: OggWriter<BufWriter<File>>,
+    granule_position: u64,
+}
+
+impl Recorder {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            channels: HashMap::new(),
+        }
+    }
+    
+    pub fn member_joined(&mut self, channel_id: Uuid, user_id: Uuid) {
+        let channel = self.channels.entry(channel_id).or_default();
+        if !channel.consented.contains(&user_id) {
+            channel.awaiting_consent.insert(user_id);
+        }
+    }
+    
+    // Closes the member's file. The recording stops once the channel is empty.
+    pub fn member_left(&mut self, channel_id: Uuid, user_id: Uuid) -> io::Result<()> {
+        let channel = match self.channels.get_mut(&channel_id) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        
+        channel.awaiting_consent.remove(&user_id);
+        channel.consented.remove(&user_id);
+        let file = channel.files.remove(&user_id);
+        
+        if channel.consented.is_empty() && channel.awaiting_consent.is_empty() {
+            self.channels.remove(&channel_id);
+        } else if channel.awaiting_consent.is_empty() {
+            // They were the last one holding it up
+            channel.active = true;
+        }
+        
+        match file {
+            Some(file) => file.finish(),
+            None => Ok(()),
+        }
+    }
+    
+    // Counts for every channel the user is waiting to consent in
+    pub fn acknowledge(&mut self, user_id: Uuid) {
+        for channel in self.channels.values_mut() {
+            if channel.awaiting_consent.remove(&user_id) {
+                channel.consented.insert(user_id);
+                if channel.awaiting_consent.is_empty() {
+                    channel.active = true;
+                }
+            }
+        }
+    }
+    
+    // Closes the files of everyone recorded in a channel that no longer exists
+    pub fn channel_deleted(&mut self, channel_id: Uuid) -> io::Result<()> {
+        match self.channels.remove(&channel_id) {
+            Some(channel) => channel.files.into_values().try_for_each(UserRecording::finish),
+            None => Ok(()),
+        }
+    }
+    
+    pub fn is_active(&self, channel_id: Uuid) -> bool {
+        self.channels.get(&channel_id).is_some_and(|c| c.active)
+    }
+    
+    // Write a voice packet sent with `quality`, if the channel is recording and the sender has consented
+    pub fn record_voice(&mut self, channel_id: Uuid, user_id: Uuid, data: &[u8], quality: AudioQuality) -> io::Result<()> {
+        let channel = match self.channels.get_mut(&channel_id) {
+            Some(channel) if channel.active && channel.consented.contains(&user_id) => channel,
+            _ => return Ok(()),
+        };
+        
+        let file = match channel.files.entry(user_id) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let dir = self.dir.join(channel_id.to_string());
+                fs::create_dir_all(&dir)?;
+                
+                let date = chrono::Utc::now().format("%Y-%m-%d");
+                let path = dir.join(format!("{}-{}.ogg", user_id, date));
+                entry.insert(UserRecording::create(path, quality)?)
+            }
+        };
+        
+        file.write_voice(data, quality)
+    }
+}
+
+impl UserRecording {
+    // Rejoining on the same day appends another Ogg stream to the file, which players treat as
+    // one recording after the other
+    fn create(path: PathBuf, quality: AudioQuality) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut writer = OggWriter::new(BufWriter::new(file), rand::random());
+        
+        writer.write_packet(&opus_head(quality), 0, false)?;
+        writer.write_packet(&opus_tags(), 0, false)?;
+        
+        Ok(Self { writer, granule_position: 0 })
+    }
+    
+    fn write_voice(&mut self, data: &[u8], quality: AudioQuality) -> io::Result<()> {
+        let (frames, sample_rate) = match quality.codec {
+            AudioCodec::Adpcm => voice_codec::frame_len(data).unwrap_or_default(),
+            // One 16-bit sample per channel per frame
+            AudioCodec::PCM => (data.len() / (2 * quality.channels.max(1) as usize), quality.sample_rate),
+        };
+        self.granule_position += frames as u64 * OPUS_GRANULE_RATE / sample_rate.max(1) as u64;
+        
+        self.writer.write_packet(data, self.granule_position, false)
+    }
+    
+    fn finish(mut self) -> io::Result<()> {
+        self.writer.write_packet(&[], self.granule_position, true)?;
+        self.writer.into_inner().flush()
+    }
+}
+
+// Identification header from RFC 7845
+fn opus_head(quality: AudioQuality) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(quality.channels.clamp(1, 255) as u8);
+    // Pre-skip
+    head.extend_from_slice(&0u16.to_le_bytes());
+    head.extend_from_slice(&quality.sample_rate.to_le_bytes());
+    // Output gain
+    head.extend_from_slice(&0i16.to_le_bytes());
+    // Mono or stereo, no channel mapping table
+    head.push(0);
+    head
+}
+
+// Comment header from RFC 7845, with no comments
+fn opus_tags() -> Vec<u8> {
+    let vendor = b"open-reverb";
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    tags
+}
+
+// Writes packets as Ogg pages (RFC 3533), each packet starting a new page
+pub struct OggWriter<W: Write> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+}
+
+impl<W: Write> OggWriter<W> {
+    pub fn new(writer: W, serial: u32) -> Self {
+        Self { writer, serial, sequence: 0 }
+    }
+    
+    // `end_of_stream` marks the last page of the stream; no packets may follow it
+    pub fn write_packet(&mut self, packet: &[u8], granule_position: u64, end_of_stream: bool) -> io::Result<()> {
+        let mut remaining = packet;
+        let mut continued = false;
+        
+        loop {
+            let body_len = remaining.len().min(MAX_PAGE_SEGMENTS * 255);
+            let (body, rest) = remaining.split_at(body_len);
+            
+            // A packet ends with a lacing value under 255, so one that fills the page carries on
+            // to the next, even if that page is empty
+            let mut lacing = vec![255u8; body_len / 255];
+            let complete = lacing.len() < MAX_PAGE_SEGMENTS;
+            if complete {
+                lacing.push((body_len % 255) as u8);
+            }
+            // An empty end of stream page holds no packet at all
+            if packet.is_empty() && end_of_stream {
+                lacing.clear();
+            }
+            
+            let mut header_type = 0;
+            if continued {
+                header_type |= HEADER_CONTINUED;
+            }
+            if self.sequence == 0 {
+                header_type |= HEADER_BEGIN;
+            }
+            if complete && end_of_stream {
+                header_type |= HEADER_END;
+            }
+            
+            // Pages where no packet finishes have no granule position
+            let granule_position = if complete { granule_position } else { u64::MAX };
+            self.write_page(header_type, granule_position, &lacing, body)?;
+            
+            if complete {
+                return Ok(());
+            }
+            remaining = rest;
+            continued = true;
+        }
+    }
+    
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+    
+    fn write_page(&mut self, header_type: u8, granule_position: u64, lacing: &[u8], body: &[u8]) -> io::Result<()> {
+        let mut page = Vec::with_capacity(27 + lacing.len() + body.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0);
+        page.push(header_type);
+        page.extend_from_slice(&granule_position.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        // Checksum, filled in below
+        page.extend_from_slice(&[0; 4]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(lacing);
+        page.extend_from_slice(body);
+        
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        
+        self.sequence += 1;
+        self.writer.write_all(&page)
+    }
+}
+
+// CRC-32 with polynomial 0x04c11db7, unreflected and with no final XOR, as Ogg uses
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |crc, byte| {
+        let mut crc = crc ^ ((*byte as u32) << 24);
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    
+    struct Page {
+        header_type: u8,
+        granule_position: u64,
+        serial: u32,
+        sequence: u32,
+        lacing: Vec<u8>,
+        body: Vec<u8>,
+    }
+    
+    // Split a file into pages, checking each one's capture pattern and checksum
+    fn parse_pages(mut data: &[u8]) -> Vec<Page> {
+        let mut pages = Vec::new();
+        while !data.is_empty() {
+            assert_eq!(&data[0..4], b"OggS");
+            assert_eq!(data[4], 0, "version");
+            
+            let segments = data[26] as usize;
+            let lacing = data[27..27 + segments].to_vec();
+            let body_len: usize = lacing.iter().map(|l| *l as usize).sum();
+            let page_len = 27 + segments + body_len;
+            
+            let mut unchecked = data[..page_len].to_vec();
+            unchecked[22..26].fill(0);
+            assert_eq!(ogg_crc(&unchecked), u32::from_le_bytes(data[22..26].try_into().unwrap()), "checksum");
+            
+            pages.push(Page {
+                header_type: data[5],
+                granule_position: u64::from_le_bytes(data[6..14].try_into().unwrap()),
+                serial: u32::from_le_bytes(data[14..18].try_into().unwrap()),
+                sequence: u32::from_le_bytes(data[18..22].try_into().unwrap()),
+                lacing,
+                body: data[27 + segments..page_len].to_vec(),
+            });
+            data = &data[page_len..];
+        }
+        pages
+    }
+    
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("open-reverb-recording-{}", Uuid::new_v4()))
+    }
+    
+    // The only file recorded for a user in a channel
+    fn recording_of(dir: &Path, channel_id: Uuid, user_id: Uuid) -> Option<Vec<u8>> {
+        let entries = fs::read_dir(dir.join(channel_id.to_string())).ok()?;
+        let path = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|p| p.file_name().unwrap().to_string_lossy().starts_with(&user_id.to_string()))?;
+        fs::read(path).ok()
+    }
+    
+    // 20ms of mono voice at 48kHz
+    fn voice_frame() -> Vec<u8> {
+        voice_codec::encode(&[1; 960], 48_000)
+    }
+    
+    fn mono() -> AudioQuality {
+        AudioQuality { channels: 1, sample_rate: 48_000, ..AudioQuality::default() }
+    }
+    
+    #[test]
+    fn recording_starts_once_everyone_consents() {
+        let dir = temp_dir();
+        let mut recorder = Recorder::new(&dir);
+        let (channel_id, alice, bob) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        
+        recorder.member_joined(channel_id, alice);
+        recorder.member_joined(channel_id, bob);
+        recorder.acknowledge(alice);
+        recorder.record_voice(channel_id, alice, &voice_frame(), mono()).unwrap();
+        
+        assert!(!recorder.is_active(channel_id));
+        assert!(recording_of(&dir, channel_id, alice).is_none());
+        
+        recorder.acknowledge(bob);
+        recorder.record_voice(channel_id, alice, &voice_frame(), mono()).unwrap();
+        recorder.member_left(channel_id, alice).unwrap();
+        
+        assert!(recorder.is_active(channel_id));
+        let name = fs::read_dir(dir.join(channel_id.to_string())).unwrap().next().unwrap().unwrap().file_name();
+        let name = name.to_string_lossy();
+        assert!(name.starts_with(&format!("{}-", alice)) && name.ends_with(".ogg"), "{}", name);
+        
+        let _ = fs::remove_dir_all(dir);
+    }
+    
+    #[test]
+    fn recordings_are_ogg_opus_streams() {
+        let dir = temp_dir();
+        let mut recorder = Recorder::new(&dir);
+        let (channel_id, alice) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        recorder.member_joined(channel_id, alice);
+        recorder.acknowledge(alice);
+        for _ in 0..3 {
+            recorder.record_voice(channel_id, alice, &voice_frame(), mono()).unwrap();
+        }
+        recorder.member_left(channel_id, alice).unwrap();
+        
+        let pages = parse_pages(&recording_of(&dir, channel_id, alice).unwrap());
+        
+        // Two headers, three voice packets and the end of stream
+        assert_eq!(pages.len(), 6);
+        assert!(pages.iter().all(|p| p.serial == pages[0].serial));
+        assert!(pages.iter().enumerate().all(|(i, p)| p.sequence == i as u32));
+        
+        assert_eq!(pages[0].header_type, HEADER_BEGIN);
+        assert_eq!(&pages[0].body[..8], b"OpusHead");
+        assert_eq!(pages[0].body[9], 1, "channel count");
+        assert_eq!(&pages[1].body[..8], b"OpusTags");
+        
+        for (i, page) in pages[2..5].iter().enumerate() {
+            assert_eq!(page.header_type, 0);
+            assert_eq!(page.body, voice_frame());
+            assert_eq!(page.granule_position, 960 * (i as u64 + 1));
+        }
+        
+        assert_eq!(pages[5].header_type, HEADER_END);
+        assert!(pages[5].lacing.is_empty());
+        assert_eq!(pages[5].granule_position, 960 * 3);
+        
+        let _ = fs::remove_dir_all(dir);
+    }
+    
+    #[test]
+    fn late_joiners_are_left_out_until_they_consent() {
+        let dir = temp_dir();
+        let mut recorder = Recorder::new(&dir);
+        let (channel_id, alice, bob) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        
+        recorder.member_joined(channel_id, alice);
+        recorder.acknowledge(alice);
+        recorder.member_joined(channel_id, bob);
+        
+        // Bob joining doesn't stop Alice being recorded
+        assert!(recorder.is_active(channel_id));
+        recorder.record_voice(channel_id, alice, &voice_frame(), mono()).unwrap();
+        recorder.record_voice(channel_id, bob, &voice_frame(), mono()).unwrap();
+        
+        assert!(recording_of(&dir, channel_id, alice).is_some());
+        assert!(recording_of(&dir, channel_id, bob).is_none());
+        
+        recorder.acknowledge(bob);
+        recorder.record_voice(channel_id, bob, &voice_frame(), mono()).unwrap();
+        recorder.member_left(channel_id, bob).unwrap();
+        
+        assert!(recording_of(&dir, channel_id, bob).is_some());
+        
+        let _ = fs::remove_dir_all(dir);
+    }
+    
+    #[test]
+    fn large_packets_continue_onto_the_next_page() {
+        let packet = vec![7u8; MAX_PAGE_SEGMENTS * 255 + 100];
+        let mut writer = OggWriter::new(Vec::new(), 1);
+        writer.write_packet(&packet, 480, true).unwrap();
+        
+        let pages = parse_pages(&writer.into_inner());
+        
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].granule_position, u64::MAX);
+        assert_eq!(pages[1].header_type, HEADER_CONTINUED | HEADER_END);
+        assert_eq!(pages[1].granule_position, 480);
+        assert_eq!([pages[0].body.clone(), pages[1].body.clone()].concat(), packet);
+    }
+}
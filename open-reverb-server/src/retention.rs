@@ -0,0 +1,138 @@
+use uuid::Uuid;
+
+use open_reverb_common::models::Channel;
+use crate::search::MessageArchive;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+// What a cleanup deleted from one channel. Messages sent before oldest_remaining (seconds since
+// the Unix epoch) are gone; it's the cutoff when nothing is left.
+#[derive(Debug, PartialEq)]
+pub struct Deletion {
+    pub channel_id: Uuid,
+    pub deleted_count: u32,
+    pub oldest_remaining: u64,
+}
+
+// A channel's own retention period takes precedence over the server's. None keeps messages
+// forever.
+pub fn retention_days(channel: &Channel, server_default: Option<u32>) -> Option<u32> {
+    channel.override_retention_days.or(server_default)
+}
+
+// Delete messages older than each channel's retention period. Only channels that lost
+// messages are returned.
+pub fn apply<'a>(
+    archive: &mut MessageArchive,
+    channels: impl IntoIterator<Item = &'a Channel>,
+    server_default: Option<u32>,
+    now_secs: u64,
+) -> Vec<Deletion> {
+    let mut deletions = Vec::new();
+    
+    for channel in channels {
+        let Some(days) = retention_days(channel, server_default) else {
+            continue;
+        };
+        
+        let cutoff = now_secs.saturating_sub(days as u64 * SECS_PER_DAY);
+        let deleted_count = archive.delete_before(channel.id, cutoff);
+        if deleted_count > 0 {
+            deletions.push(Deletion {
+                channel_id: channel.id,
+                deleted_count,
+                oldest_remaining: archive.oldest_timestamp(channel.id).unwrap_or(cutoff),
+            });
+        }
+    }
+    
+    deletions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use open_reverb_common::models::{AudioQuality, ChannelType};
+    
+    const NOW: u64 = 1_700_000_000;
+    
+    fn channel(override_retention_days: Option<u32>) -> Channel {
+        Channel {
+            id: Uuid::new_v4(),
+            name: "general".to_string(),
+            description: None,
+            parent_id: None,
+            members: Vec::new(),
+            topic: None,
+            e2e_encrypted: false,
+            channel_type: ChannelType::Text,
+            media_bandwidth_limit_kbps: 0,
+            lobby_mode: false,
+            audio_quality: AudioQuality::default(),
+            override_retention_days,
+            permanent: false,
+            idle_timeout_secs: None,
+        }
+    }
+    
+    fn days_ago(days: u64) -> u64 {
+        NOW - days * SECS_PER_DAY
+    }
+    
+    // One message sent each of the given numbers of days ago
+    fn archive_with(channel: &Channel, ages: &[u64]) -> MessageArchive {
+        let mut archive = MessageArchive::new();
+        for age in ages {
+            archive.add(Uuid::new_v4(), channel.id, Uuid::new_v4(), "hello", days_ago(*age));
+        }
+        archive
+    }
+    
+    #[test]
+    fn messages_past_the_retention_period_are_deleted() {
+        let channel = channel(None);
+        let mut archive = archive_with(&channel, &[40, 31, 29, 1]);
+        
+        let deletions = apply(&mut archive, [&channel], Some(30), NOW);
+        
+        assert_eq!(deletions, vec![Deletion { channel_id: channel.id, deleted_count: 2, oldest_remaining: days_ago(29) }]);
+        assert_eq!(archive.oldest_timestamp(channel.id), Some(days_ago(29)));
+    }
+    
+    #[test]
+    fn channel_override_takes_precedence_over_the_server() {
+        let short = channel(Some(7));
+        let default = channel(None);
+        assert_eq!(retention_days(&short, Some(30)), Some(7));
+        assert_eq!(retention_days(&default, Some(30)), Some(30));
+        
+        let mut archive = archive_with(&short, &[10, 1]);
+        archive.add(Uuid::new_v4(), default.id, Uuid::new_v4(), "hello", days_ago(10));
+        
+        let deletions = apply(&mut archive, [&short, &default], Some(30), NOW);
+        
+        assert_eq!(deletions.len(), 1);
+        assert_eq!(deletions[0].channel_id, short.id);
+        assert_eq!(archive.oldest_timestamp(default.id), Some(days_ago(10)));
+    }
+    
+    #[test]
+    fn channel_override_applies_without_a_server_default() {
+        let channel = channel(Some(7));
+        let mut archive = archive_with(&channel, &[10]);
+        
+        let deletions = apply(&mut archive, [&channel], None, NOW);
+        
+        // Nothing is left, so everything before the cutoff is gone
+        assert_eq!(deletions, vec![Deletion { channel_id: channel.id, deleted_count: 1, oldest_remaining: days_ago(7) }]);
+    }
+    
+    #[test]
+    fn messages_are_kept_forever_without_a_retention_period() {
+        let channel = channel(None);
+        let mut archive = archive_with(&channel, &[10_000]);
+        
+        assert!(apply(&mut archive, [&channel], None, NOW).is_empty());
+        assert_eq!(archive.oldest_timestamp(channel.id), Some(days_ago(10_000)));
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+use open_reverb_common::models::SearchResult;
+
+// Oldest messages stop being searchable past this many
+const MAX_ARCHIVED_MESSAGES: usize = 50_000;
+
+// Most results returned for one search
+pub const MAX_SEARCH_RESULTS: u32 = 100;
+
+// Characters of context either side of the match in a snippet
+const SNIPPET_CONTEXT: usize = 40;
+
+struct ArchivedMessage {
+    message_id: Uuid,
+    channel_id: Uuid,
+    user_id: Uuid,
+    content: String,
+    timestamp: u64,
+    // Lowercased words of the content, for matching
+    words: Vec<String>,
+}
+
+// Chat messages sent since startup, kept for searching
+pub struct MessageArchive {
+    messages: VecDeque<ArchivedMessage>,
+}
+
+impl MessageArchive {
+    pub fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+        }
+    }
+    
+    pub fn add(&mut self, message_id: Uuid, channel_id: Uuid, user_id: Uuid, content: &str, timestamp: u64) {
+        if self.messages.len() >= MAX_ARCHIVED_MESSAGES {
+            self.messages.pop_front();
+        }
+        
+        self.messages.push_back(ArchivedMessage {
+            message_id,
+            channel_id,
+            user_id,
+            content: content.to_string(),
+            timestamp,
+            words: words(content),
+        });
+    }
+    
+    // Messages containing every word of the query, newest first. A query word matches any word
+    // it starts, so "run" finds "running". An empty `channel_ids` searches every channel.
+    pub fn search(&self, query: &str, channel_ids: &[Uuid], limit: u32, offset: u32) -> Vec<SearchResult> {
+        let terms = words(query);
+        if terms.is_empty() {
+            return Vec::new();
+        }
+        
+        self.messages
+            .iter()
+            .rev()
+            .filter(|m| channel_ids.is_empty() || channel_ids.contains(&m.channel_id))
+            .filter(|m| terms.iter().all(|term| m.words.iter().any(|word| word.starts_with(term.as_str()))))
+            .skip(offset as usize)
+            .take(limit.min(MAX_SEARCH_RESULTS) as usize)
+            .map(|m| SearchResult {
+                message_id: m.message_id,
+                channel_id: m.channel_id,
+                user_id: m.user_id,
+                content: m.content.clone(),
+                timestamp: m.timestamp,
+                snippet: snippet(&m.content, &terms[0]),
+            })
+            .collect()
+    }
+    
+    // Delete a channel's messages sent before `cutoff`, returning how many there were
+    pub fn delete_before(&mut self, channel_id: Uuid, cutoff: u64) -> u32 {
+        let before = self.messages.len();
+        self.messages.retain(|m| m.channel_id != channel_id || m.timestamp >= cutoff);
+        (before - self.messages.len()) as u32
+    }
+    
+    pub fn oldest_timestamp(&self, channel_id: Uuid) -> Option<u64> {
+        self.messages.iter().filter(|m| m.channel_id == channel_id).map(|m| m.timestamp).min()
+    }
+    
+    pub fn newest_timestamp(&self, channel_id: Uuid) -> Option<u64> {
+        self.messages.iter().rev().find(|m| m.channel_id == channel_id).map(|m| m.timestamp)
+    }
+    
+    // A channel's last `limit` messages, oldest first, as (message_id, user_id, content, timestamp)
+    pub fn recent(&self, channel_id: Uuid, limit: usize) -> Vec<(Uuid, Uuid, &str, u64)> {
+        let mut recent: Vec<_> = self
+            .messages
+            .iter()
+            .rev()
+            .filter(|m| m.channel_id == channel_id)
+            .take(limit)
+            .map(|m| (m.message_id, m.user_id, m.content.as_str(), m.timestamp))
+            .collect();
+        recent.reverse();
+        recent
+    }
+    
+    // A channel's messages sent between `start` and `end` (both inclusive), oldest first, as
+    // (user_id, content, timestamp)
+    pub fn between(&self, channel_id: Uuid, start: u64, end: u64) -> Vec<(Uuid, &str, u64)> {
+        self.messages
+            .iter()
+            .filter(|m| m.channel_id == channel_id && m.timestamp >= start && m.timestamp <= end)
+            .map(|m| (m.user_id, m.content.as_str(), m.timestamp))
+            .collect()
+    }
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// The content around the first word starting with `term`, trimmed to whole characters
+fn snippet(content: &str, term: &str) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    let lower: Vec<char> = chars.iter().flat_map(|c| c.to_lowercase()).collect();
+    
+    // Lowercasing can change the number of characters; fall back to the start if it did
+    let term: Vec<char> = term.chars().collect();
+    let start = if lower.len() == chars.len() {
+        (0..lower.len())
+            .find(|&i| lower[i..].starts_with(&term) && (i == 0 || !lower[i - 1].is_alphanumeric()))
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    
+    let from = start.saturating_sub(SNIPPET_CONTEXT);
+    let to = (start + term.len() + SNIPPET_CONTEXT).min(chars.len());
+    
+    let mut snippet: String = chars[from..to].iter().collect();
+    if from > 0 {
+        snippet.insert(0, '…');
+    }
+    if to < chars.len() {
+        snippet.push('…');
+    }
+    
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const GENERAL: Uuid = Uuid::from_u128(1);
+    const GAMES: Uuid = Uuid::from_u128(2);
+    
+    fn archive(messages: &[(Uuid, &str)]) -> MessageArchive {
+        let mut archive = MessageArchive::new();
+        for (i, (channel_id, content)) in messages.iter().enumerate() {
+            archive.add(Uuid::new_v4(), *channel_id, Uuid::new_v4(), content, i as u64);
+        }
+        archive
+    }
+    
+    fn found(archive: &MessageArchive, query: &str, channel_ids: &[Uuid]) -> Vec<String> {
+        archive.search(query, channel_ids, 10, 0).into_iter().map(|r| r.content).collect()
+    }
+    
+    #[test]
+    fn query_words_match_the_start_of_words_in_any_case() {
+        let archive = archive(&[
+            (GENERAL, "Running late, sorry"),
+            (GENERAL, "who's up for a run?"),
+            (GAMES, "RUN it back"),
+            (GENERAL, "I'm brunching"),
+            (GAMES, "late-night run tonight"),
+        ]);
+        
+        // Newest first, and "un" inside "brunching" or "run" doesn't count
+        assert_eq!(found(&archive, "run", &[]), ["late-night run tonight", "RUN it back", "who's up for a run?", "Running late, sorry"]);
+        assert!(found(&archive, "un", &[]).is_empty());
+        assert_eq!(found(&archive, "BRUNCH", &[]), ["I'm brunching"]);
+        
+        // Every word has to match, in any order, and punctuation splits words
+        assert_eq!(found(&archive, "late run", &[]), ["late-night run tonight", "Running late, sorry"]);
+        assert_eq!(found(&archive, "night late", &[]), ["late-night run tonight"]);
+        assert_eq!(found(&archive, "who s", &[]), ["who's up for a run?"]);
+        
+        assert_eq!(found(&archive, "run", &[GAMES]), ["late-night run tonight", "RUN it back"]);
+        assert_eq!(found(&archive, "run", &[GAMES, GENERAL]).len(), 4);
+    }
+    
+    #[test]
+    fn empty_queries_find_nothing() {
+        let archive = archive(&[(GENERAL, "hello"), (GENERAL, "!!!")]);
+        
+        assert!(found(&archive, "", &[]).is_empty());
+        assert!(found(&archive, "  ", &[]).is_empty());
+        assert!(found(&archive, "?!", &[]).is_empty());
+    }
+    
+    #[test]
+    fn results_are_paged_and_capped() {
+        let contents: Vec<String> = (0..150).map(|i| format!("message {}", i)).collect();
+        let messages: Vec<(Uuid, &str)> = contents.iter().map(|c| (GENERAL, c.as_str())).collect();
+        let archive = archive(&messages);
+        
+        let page: Vec<u64> = archive.search("message", &[], 5, 10).iter().map(|r| r.timestamp).collect();
+        assert_eq!(page, [139, 138, 137, 136, 135]);
+        assert_eq!(archive.search("message", &[], 1_000, 0).len(), MAX_SEARCH_RESULTS as usize);
+        assert_eq!(archive.search("message", &[], 10, 145).len(), 5);
+    }
+    
+    #[test]
+    fn snippets_trim_around_the_first_term() {
+        let long = format!("{} needle {}", "a".repeat(100), "b".repeat(100));
+        let archive = archive(&[(GENERAL, &long), (GENERAL, "Needle at the start")]);
+        let results = archive.search("needle", &[], 10, 0);
+        
+        assert_eq!(results[0].snippet, "Needle at the start");
+        let snippet = &results[1].snippet;
+        assert_eq!(*snippet, format!("…{} needle {}…", "a".repeat(39), "b".repeat(39)));
+    }
+}
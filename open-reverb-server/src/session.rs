@@ -1,4 +1,3 @@
-use std::error::Error;
 use std::sync::Arc;
 
 use futures::{SinkExt, StreamExt};
@@ -14,7 +13,7 @@ use crate::server::Server;
 pub async fn handle_connection(
     socket: TcpStream,
     server: Arc<RwLock<Server>>,
-) -> Result<(), Box<dyn Error>> {
+) -> open_reverb_common::error::Result<()> {
     // Split the socket into a reader and writer
     let (read_half, write_half) = socket.into_split();
     
@@ -107,7 +106,7 @@ pub async fn handle_connection(
                 }
             }
             
-            Message::VoiceData { user_id: uid, channel_id: cid, data } => {
+            Message::VoiceData { user_id: uid, channel_id: cid, data, .. } => {
                 if let Some(channel_sender) = {
                     let server_read = server.read().await;
                     server_read.get_channel_sender(&cid)
@@ -117,7 +116,7 @@ pub async fn handle_connection(
                 }
             }
             
-            Message::VideoData { user_id: uid, channel_id: cid, data } => {
+            Message::VideoData { user_id: uid, channel_id: cid, data, .. } => {
                 if let Some(channel_sender) = {
                     let server_read = server.read().await;
                     server_read.get_channel_sender(&cid)
@@ -127,7 +126,7 @@ pub async fn handle_connection(
                 }
             }
             
-            Message::ScreenShareData { user_id: uid, channel_id: cid, data } => {
+            Message::ScreenShareData { user_id: uid, channel_id: cid, data, .. } => {
                 if let Some(channel_sender) = {
                     let server_read = server.read().await;
                     server_read.get_channel_sender(&cid)
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use open_reverb_common::protocol::Message;
+
+pub type StoreResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Delivers a message another instance published, given the channel and sender it came from
+pub type RelayHandler = Arc<dyn Fn(Uuid, Uuid, Message) + Send + Sync>;
+
+// The parts of a session that outlive a single server instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub user_id: Uuid,
+    pub channels: Vec<Uuid>,
+    pub addr: String,
+}
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn save_session(&self, session: &StoredSession) -> StoreResult<()>;
+    
+    async fn load_session(&self, user_id: Uuid) -> StoreResult<Option<StoredSession>>;
+    
+    async fn delete_session(&self, user_id: Uuid) -> StoreResult<()>;
+    
+    // Share a channel message with other server instances
+    async fn publish(&self, _channel_id: Uuid, _sender_id: Uuid, _message: &Message) -> StoreResult<()> {
+        Ok(())
+    }
+    
+    // Start handing messages published by other server instances to `deliver`
+    fn spawn_forwarding(&self, _deliver: RelayHandler) {}
+}
+
+// Used when no Redis server is configured; sessions only live as long as the process
+pub struct InMemorySessionStore {
+    sessions: Mutex<HashMap<Uuid, StoredSession>>,
+}
+
+impl InMemorySessionStore {
+    pub fn new() -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for InMemorySessionStore {
+    async fn save_session(&self, session: &StoredSession) -> StoreResult<()> {
+        self.sessions.lock().unwrap().insert(session.user_id, session.clone());
+        Ok(())
+    }
+    
+    async fn load_session(&self, user_id: Uuid) -> StoreResult<Option<StoredSession>> {
+        Ok(self.sessions.lock().unwrap().get(&user_id).cloned())
+    }
+    
+    async fn delete_session(&self, user_id: Uuid) -> StoreResult<()> {
+        self.sessions.lock().unwrap().remove(&user_id);
+        Ok(())
+    }
+}
+
+// A channel message relayed between server instances
+#[derive(Serialize, Deserialize)]
+struct RelayedMessage {
+    instance_id: Uuid,
+    sender_id: Uuid,
+    message: Message,
+}
+
+// Stores sessions in Redis so they survive restarts and are visible to every instance
+pub struct RedisSessionStore {
+    client: redis::Client,
+    connection: redis::aio::ConnectionManager,
+    // Lets an instance ignore the messages it published itself
+    instance_id: Uuid,
+}
+
+impl RedisSessionStore {
+    pub async fn connect(redis_url: &str) -> StoreResult<Self> {
+        let client = redis::Client::open(redis_url)?;
+        let connection = client.get_connection_manager().await?;
+        
+        Ok(Self {
+            client,
+            connection,
+            instance_id: Uuid::new_v4(),
+        })
+    }
+    
+    fn session_key(user_id: Uuid) -> String {
+        format!("session:{}", user_id)
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn save_session(&self, session: &StoredSession) -> StoreResult<()> {
+        let value = serde_json::to_string(session)?;
+        let mut connection = self.connection.clone();
+        connection.set::<_, _, ()>(Self::session_key(session.user_id), value).await?;
+        Ok(())
+    }
+    
+    async fn load_session(&self, user_id: Uuid) -> StoreResult<Option<StoredSession>> {
+        let mut connection = self.connection.clone();
+        let value: Option<String> = connection.get(Self::session_key(user_id)).await?;
+        
+        match value {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+    
+    async fn delete_session(&self, user_id: Uuid) -> StoreResult<()> {
+        let mut connection = self.connection.clone();
+        connection.del::<_, ()>(Self::session_key(user_id)).await?;
+        Ok(())
+    }
+    
+    async fn publish(&self, channel_id: Uuid, sender_id: Uuid, message: &Message) -> StoreResult<()> {
+        let relayed = RelayedMessage {
+            instance_id: self.instance_id,
+            sender_id,
+            message: message.clone(),
+        };
+        
+        let payload = serde_json::to_string(&relayed)?;
+        let mut connection = self.connection.clone();
+        connection.publish::<_, _, ()>(format!("channel:{}", channel_id), payload).await?;
+        Ok(())
+    }
+    
+    fn spawn_forwarding(&self, deliver: RelayHandler) {
+        let client = self.client.clone();
+        let instance_id = self.instance_id;
+        
+        tokio::spawn(async move {
+            let mut pubsub = match client.get_async_pubsub().await {
+                Ok(pubsub) => pubsub,
+                Err(e) => {
+                    error!("Failed to open Redis subscription: {}", e);
+                    return;
+                }
+            };
+            
+            if let Err(e) = pubsub.psubscribe("channel:*").await {
+                error!("Failed to subscribe to channel messages: {}", e);
+                return;
+            }
+            
+            info!("Forwarding channel messages from other server instances");
+            
+            let mut messages = pubsub.on_message();
+            while let Some(msg) = messages.next().await {
+                let relayed = match serde_json::from_slice::<RelayedMessage>(msg.get_payload_bytes()) {
+                    Ok(relayed) => relayed,
+                    Err(e) => {
+                        error!("Invalid message on {}: {}", msg.get_channel_name(), e);
+                        continue;
+                    }
+                };
+                
+                if relayed.instance_id == instance_id {
+                    continue;
+                }
+                
+                // Published to "channel:<id>"
+                match msg.get_channel_name().strip_prefix("channel:").and_then(|id| Uuid::parse_str(id).ok()) {
+                    Some(channel_id) => deliver(channel_id, relayed.sender_id, relayed.message),
+                    None => error!("Message on unexpected channel {}", msg.get_channel_name()),
+                }
+            }
+        });
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    
+    // Answers GET, SET and DEL from a map like Redis would, and OK to anything else
+    async fn mock_redis() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+        
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let data = Arc::clone(&data);
+                tokio::spawn(async move {
+                    let (reader, mut writer) = socket.into_split();
+                    let mut reader = BufReader::new(reader);
+                    while let Some(command) = read_command(&mut reader).await {
+                        let reply = {
+                            let mut data = data.lock().unwrap();
+                            match command.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                                ["SET", key, value] => {
+                                    data.insert(key.to_string(), value.to_string());
+                                    "+OK\r\n".to_string()
+                                }
+                                ["GET", key] => match data.get(key) {
+                                    Some(value) => format!("${}\r\n{}\r\n", value.len(), value),
+                                    None => "$-1\r\n".to_string(),
+                                },
+                                ["DEL", key] => format!(":{}\r\n", data.remove(key).is_some() as u8),
+                                _ => "+OK\r\n".to_string(),
+                            }
+                        };
+                        if writer.write_all(reply.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        
+        format!("redis://{}", addr)
+    }
+    
+    // Commands arrive as an array of bulk strings
+    async fn read_command<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Option<Vec<String>> {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.ok()?;
+        let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+        
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).await.ok()?;
+            line.clear();
+            reader.read_line(&mut line).await.ok()?;
+            args.push(line.trim_end().to_string());
+        }
+        Some(args)
+    }
+    
+    async fn check_crud(store: &dyn SessionStore) {
+        let session = StoredSession {
+            user_id: Uuid::new_v4(),
+            channels: vec![Uuid::new_v4()],
+            addr: "10.0.0.1:5000".to_string(),
+        };
+        assert!(store.load_session(session.user_id).await.unwrap().is_none());
+        
+        store.save_session(&session).await.unwrap();
+        let loaded = store.load_session(session.user_id).await.unwrap().unwrap();
+        assert_eq!((loaded.user_id, loaded.channels, loaded.addr), (session.user_id, session.channels.clone(), session.addr.clone()));
+        
+        // Saving again replaces it
+        let moved = StoredSession { channels: Vec::new(), ..session.clone() };
+        store.save_session(&moved).await.unwrap();
+        assert!(store.load_session(session.user_id).await.unwrap().unwrap().channels.is_empty());
+        
+        store.delete_session(session.user_id).await.unwrap();
+        assert!(store.load_session(session.user_id).await.unwrap().is_none());
+    }
+    
+    #[tokio::test]
+    async fn sessions_are_kept_in_redis() {
+        let store = RedisSessionStore::connect(&mock_redis().await).await.unwrap();
+        check_crud(&store).await;
+    }
+    
+    #[tokio::test]
+    async fn sessions_are_kept_in_memory() {
+        check_crud(&InMemorySessionStore::new()).await;
+    }
+}
@@ -0,0 +1,142 @@
+// Signed session tokens (JWTs, HS256) handed out on login, so a client that loses its
+// connection can log back in without its password. Each names the session it was issued for,
+// and is only accepted while this server still has that session, until it expires.
+
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Length of a generated secret in bytes, the size of the HMAC-SHA256 output
+const SECRET_LEN: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub user_id: Uuid,
+    // Session ID, one per login
+    pub sid: Uuid,
+    // Seconds since the Unix epoch
+    pub iat: u64,
+    pub exp: u64,
+}
+
+pub struct SessionTokens {
+    secret: Vec<u8>,
+    ttl_secs: u64,
+    // When each session issued a token expires, by session ID
+    sessions: HashMap<Uuid, u64>,
+}
+
+impl SessionTokens {
+    pub fn new(ttl_secs: u64) -> Self {
+        let mut secret = vec![0u8; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        
+        Self { secret, ttl_secs, sessions: HashMap::new() }
+    }
+    
+    // A token for a new session. Expired sessions are forgotten here.
+    pub fn issue(&mut self, user_id: Uuid, now: u64) -> String {
+        self.sessions.retain(|_, exp| *exp > now);
+        
+        let claims = Claims { user_id, sid: Uuid::new_v4(), iat: now, exp: now.saturating_add(self.ttl_secs) };
+        self.sessions.insert(claims.sid, claims.exp);
+        
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(&self.secret))
+            .expect("claims serialize")
+    }
+    
+    // The token's claims, if it was signed with this secret for a session that hasn't expired at `now`
+    pub fn verify(&self, token: &str, now: u64) -> Result<Claims, String> {
+        // Only ever HS256, whatever else the header might ask for. Expiry is checked against
+        // `now` below rather than the clock.
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.validate_exp = false;
+        
+        let claims = jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_secret(&self.secret), &validation)
+            .map_err(|_| "Invalid session token".to_string())?
+            .claims;
+        if claims.exp <= now {
+            return Err("Session token expired".to_string());
+        }
+        if self.sessions.get(&claims.sid).is_none_or(|exp| *exp <= now) {
+            return Err("Unknown session".to_string());
+        }
+        
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::BASE64URL_NOPAD;
+    
+    #[test]
+    fn tokens_verify_until_they_expire() {
+        let mut tokens = SessionTokens::new(60);
+        let user_id = Uuid::new_v4();
+        let token = tokens.issue(user_id, 1_000);
+        
+        let claims = tokens.verify(&token, 1_059).unwrap();
+        assert_eq!(claims.user_id, user_id);
+        assert_eq!((claims.iat, claims.exp), (1_000, 1_060));
+        
+        assert_eq!(tokens.verify(&token, 1_060).err().unwrap(), "Session token expired");
+    }
+    
+    #[test]
+    fn only_sessions_still_held_verify() {
+        let mut tokens = SessionTokens::new(60);
+        let token = tokens.issue(Uuid::new_v4(), 1_000);
+        let claims = tokens.verify(&token, 1_000).unwrap();
+        
+        // Issuing after it expired forgets it
+        tokens.issue(Uuid::new_v4(), 1_060);
+        assert!(!tokens.sessions.contains_key(&claims.sid));
+        
+        // A correctly signed token for a session this server never had
+        let mut other = SessionTokens::new(60);
+        other.secret = tokens.secret.clone();
+        let unknown = other.issue(Uuid::new_v4(), 1_000);
+        assert_eq!(tokens.verify(&unknown, 1_000).err().unwrap(), "Unknown session");
+    }
+    
+    #[test]
+    fn tampered_or_foreign_tokens_are_rejected() {
+        let mut tokens = SessionTokens::new(60);
+        let token = tokens.issue(Uuid::new_v4(), 1_000);
+        
+        // Same claims, someone else's user_id
+        let (header, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let sid = tokens.verify(&token, 1_000).unwrap().sid;
+        let forged_claims = Claims { user_id: Uuid::new_v4(), sid, iat: 1_000, exp: 1_060 };
+        let forged_payload = BASE64URL_NOPAD.encode(&serde_json::to_vec(&forged_claims).unwrap());
+        let forged = format!("{}.{}.{}", header, forged_payload, signature);
+        assert_eq!(tokens.verify(&forged, 1_000).err().unwrap(), "Invalid session token");
+        
+        // Same claims, a different signature
+        let signed_len = token.rfind('.').unwrap() + 1;
+        let mut resigned = token.clone();
+        let first = if token[signed_len..].starts_with('A') { "B" } else { "A" };
+        resigned.replace_range(signed_len..signed_len + 1, first);
+        assert_eq!(tokens.verify(&resigned, 1_000).err().unwrap(), "Invalid session token");
+        
+        assert!(SessionTokens::new(60).verify(&token, 1_000).is_err());
+        assert!(tokens.verify("not a token", 1_000).is_err());
+    }
+    
+    #[test]
+    fn unsigned_tokens_are_rejected() {
+        let mut tokens = SessionTokens::new(60);
+        let token = tokens.issue(Uuid::new_v4(), 1_000);
+        let sid = tokens.verify(&token, 1_000).unwrap().sid;
+        let header = BASE64URL_NOPAD.encode(br#"{"alg":"none","typ":"JWT"}"#);
+        let claims = Claims { user_id: Uuid::new_v4(), sid, iat: 1_000, exp: 1_060 };
+        let payload = BASE64URL_NOPAD.encode(&serde_json::to_vec(&claims).unwrap());
+        
+        assert!(tokens.verify(&format!("{}.{}.", header, payload), 1_000).is_err());
+    }
+}
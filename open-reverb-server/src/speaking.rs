@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::time::Instant;
+use uuid::Uuid;
+
+// How long one session has spoken in each of its channels
+#[derive(Debug, Default)]
+pub struct SpeakingTime {
+    // Set between VoiceStarted and VoiceStopped
+    voice_start_at: Option<Instant>,
+    speaking_time_ms: HashMap<Uuid, u64>,
+}
+
+impl SpeakingTime {
+    pub fn started(&mut self, now: Instant) {
+        // A repeated start doesn't restart the clock
+        self.voice_start_at.get_or_insert(now);
+    }
+    
+    // Credit the time since the last start to every channel the session is in
+    pub fn stopped(&mut self, now: Instant, channels: &[Uuid]) {
+        let Some(started_at) = self.voice_start_at.take() else {
+            return;
+        };
+        
+        let elapsed_ms = now.saturating_duration_since(started_at).as_millis() as u64;
+        for &channel_id in channels {
+            *self.speaking_time_ms.entry(channel_id).or_default() += elapsed_ms;
+        }
+    }
+    
+    // Time spoken in a channel, including speech still going on
+    pub fn channel_ms(&self, channel_id: Uuid, now: Instant) -> u64 {
+        let ongoing = self.voice_start_at.map_or(0, |started_at| now.saturating_duration_since(started_at).as_millis() as u64);
+        self.speaking_time_ms.get(&channel_id).copied().unwrap_or(0) + ongoing
+    }
+    
+    // Forget a channel the session left. Speech still going on is credited to the channels it
+    // was heard in so far, and keeps counting for the rest.
+    pub fn left(&mut self, channel_id: Uuid, now: Instant, channels: &[Uuid]) {
+        let speaking = self.voice_start_at.is_some();
+        self.stopped(now, channels);
+        self.speaking_time_ms.remove(&channel_id);
+        
+        if speaking {
+            self.started(now);
+        }
+    }
+}
+
+// Total speaking time per user, most first. Users with several sessions have them added up.
+pub fn totals(per_session: impl IntoIterator<Item = (Uuid, u64)>) -> Vec<(Uuid, u64)> {
+    let mut totals: HashMap<Uuid, u64> = HashMap::new();
+    for (user_id, ms) in per_session {
+        *totals.entry(user_id).or_default() += ms;
+    }
+    
+    let mut totals: Vec<(Uuid, u64)> = totals.into_iter().collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    const CHANNEL: Uuid = Uuid::from_u128(1);
+    
+    #[test]
+    fn accumulates_across_start_stop_cycles() {
+        let t0 = Instant::now();
+        let mut time = SpeakingTime::default();
+        
+        time.started(t0);
+        time.stopped(t0 + Duration::from_millis(1500), &[CHANNEL]);
+        time.started(t0 + Duration::from_secs(10));
+        time.stopped(t0 + Duration::from_millis(10_250), &[CHANNEL]);
+        
+        assert_eq!(time.channel_ms(CHANNEL, t0 + Duration::from_secs(20)), 1750);
+    }
+    
+    #[test]
+    fn stop_without_start_and_repeated_start_are_ignored() {
+        let t0 = Instant::now();
+        let mut time = SpeakingTime::default();
+        
+        time.stopped(t0 + Duration::from_secs(1), &[CHANNEL]);
+        time.started(t0 + Duration::from_secs(2));
+        time.started(t0 + Duration::from_secs(3));
+        time.stopped(t0 + Duration::from_secs(4), &[CHANNEL]);
+        
+        assert_eq!(time.channel_ms(CHANNEL, t0 + Duration::from_secs(5)), 2000);
+    }
+    
+    #[test]
+    fn leaving_resets_only_that_channel() {
+        let t0 = Instant::now();
+        let other = Uuid::from_u128(2);
+        let mut time = SpeakingTime::default();
+        
+        time.started(t0);
+        time.stopped(t0 + Duration::from_secs(1), &[CHANNEL, other]);
+        time.started(t0 + Duration::from_secs(2));
+        assert_eq!(time.channel_ms(CHANNEL, t0 + Duration::from_secs(3)), 2000);
+        
+        // Still talking in the other channel after leaving
+        time.left(CHANNEL, t0 + Duration::from_secs(3), &[CHANNEL, other]);
+        time.stopped(t0 + Duration::from_secs(4), &[other]);
+        
+        assert_eq!(time.channel_ms(CHANNEL, t0 + Duration::from_secs(5)), 0);
+        assert_eq!(time.channel_ms(other, t0 + Duration::from_secs(5)), 3000);
+    }
+    
+    #[test]
+    fn totals_add_up_sessions_and_sort_descending() {
+        let (a, b) = (Uuid::from_u128(10), Uuid::from_u128(11));
+        
+        assert_eq!(totals([(a, 100), (b, 250), (a, 200)]), vec![(a, 300), (b, 250)]);
+    }
+}
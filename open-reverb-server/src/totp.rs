@@ -0,0 +1,103 @@
+// Time-based one-time passwords (RFC 6238), as generated by authenticator apps
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+// Codes change every 30 seconds and are 6 digits, the defaults authenticator apps assume
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+
+// Steps either side of the current one that are still accepted, for clock drift
+const ALLOWED_SKEW: u64 = 1;
+
+// Length of a new secret in bytes; 160 bits as RFC 4226 recommends
+const SECRET_LEN: usize = 20;
+
+// A new random secret, base32 encoded for entering into an authenticator app
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+    
+    BASE32_NOPAD.encode(&secret)
+}
+
+// Check `code` against `secret` at `now` (seconds since the Unix epoch). Returns the time step
+// the code belongs to, so the caller can refuse it if it has been used before.
+pub fn verify(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code = code.trim();
+    
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    
+    let current = now / STEP_SECS;
+    (current.saturating_sub(ALLOWED_SKEW)..=current + ALLOWED_SKEW).find(|step| code_at(&key, *step) == code)
+}
+
+fn code_at(key: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    
+    // Dynamic truncation, RFC 4226 section 5.3
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    
+    format!("{:0width$}", value % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+// The code an authenticator app would show at `now`
+#[cfg(test)]
+pub fn code(secret: &str, now: u64) -> String {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    code_at(&key, now / STEP_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // "12345678901234567890", the SHA-1 key from RFC 6238's test vectors
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    
+    #[test]
+    fn matches_the_rfc_test_vectors() {
+        // The RFC's 8-digit codes, cut to the last 6 digits
+        assert_eq!(code(RFC_SECRET, 59), "287082");
+        assert_eq!(code(RFC_SECRET, 1_111_111_109), "081804");
+        assert_eq!(code(RFC_SECRET, 2_000_000_000), "279037");
+    }
+    
+    #[test]
+    fn accepts_codes_within_the_allowed_skew() {
+        let now = 1_111_111_109;
+        let step = now / STEP_SECS;
+        
+        assert_eq!(verify(RFC_SECRET, "081804", now), Some(step));
+        assert_eq!(verify(RFC_SECRET, " 081804 ", now), Some(step));
+        assert_eq!(verify(RFC_SECRET, &code(RFC_SECRET, now - STEP_SECS), now), Some(step - 1));
+        assert_eq!(verify(RFC_SECRET, &code(RFC_SECRET, now + STEP_SECS), now), Some(step + 1));
+        assert_eq!(verify(RFC_SECRET, &code(RFC_SECRET, now - 2 * STEP_SECS), now), None);
+    }
+    
+    #[test]
+    fn rejects_wrong_or_malformed_codes() {
+        let now = 1_111_111_109;
+        
+        assert_eq!(verify(RFC_SECRET, "081805", now), None);
+        assert_eq!(verify(RFC_SECRET, "81804", now), None);
+        assert_eq!(verify(RFC_SECRET, "08180a", now), None);
+        assert_eq!(verify("not base32!", "081804", now), None);
+    }
+    
+    #[test]
+    fn generated_secrets_verify() {
+        let secret = generate_secret();
+        
+        assert_eq!(BASE32_NOPAD.decode(secret.as_bytes()).unwrap().len(), SECRET_LEN);
+        assert!(verify(&secret, &code(&secret, 1_000_000), 1_000_000).is_some());
+    }
+}
@@ -0,0 +1,143 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use open_reverb_common::models::{AudioCodec, AudioQuality};
+use open_reverb_common::voice_codec;
+
+pub type TranscriptionResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Rate voice is transcribed at, whatever rate it was sent at
+pub const VOICE_SAMPLE_RATE: u32 = 48000;
+
+// Amount of speech collected from a user before it is transcribed
+const TRANSCRIPTION_WINDOW_SECS: u32 = 5;
+
+// RMS level below which a window is treated as silence
+const SILENCE_THRESHOLD: f64 = 200.0;
+
+// Turns a window of speech into text
+#[async_trait]
+pub trait TranscriptionBackend: Send + Sync {
+    async fn transcribe(&self, pcm: &[i16], sample_rate: u32, language: &str) -> TranscriptionResult<String>;
+}
+
+// Placeholder for a whisper.cpp backend. Until a model is wired in, it only
+// reports how much speech it heard, so the rest of the pipeline can be exercised.
+pub struct WhisperBackend;
+
+#[async_trait]
+impl TranscriptionBackend for WhisperBackend {
+    async fn transcribe(&self, pcm: &[i16], sample_rate: u32, _language: &str) -> TranscriptionResult<String> {
+        if pcm.is_empty() || rms(pcm) < SILENCE_THRESHOLD {
+            return Ok(String::new());
+        }
+        
+        let seconds = pcm.len() as f32 / sample_rate as f32;
+        Ok(format!("[{:.1}s of speech]", seconds))
+    }
+}
+
+// Collects voice per user until there is a full window to transcribe
+pub struct Transcriber {
+    backend: Arc<dyn TranscriptionBackend>,
+    language: String,
+    buffers: HashMap<Uuid, Vec<i16>>,
+}
+
+impl Transcriber {
+    pub fn new(backend: Arc<dyn TranscriptionBackend>, language: String) -> Self {
+        Self {
+            backend,
+            language,
+            buffers: HashMap::new(),
+        }
+    }
+    
+    pub fn backend(&self) -> Arc<dyn TranscriptionBackend> {
+        Arc::clone(&self.backend)
+    }
+    
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+    
+    // Add a frame of voice sent with `quality`, returning a full window once one is ready
+    pub fn push_voice(&mut self, user_id: Uuid, data: &[u8], quality: AudioQuality) -> Option<Vec<i16>> {
+        let (samples, sample_rate) = match quality.codec {
+            AudioCodec::Adpcm => voice_codec::decode(data)?,
+            AudioCodec::PCM => {
+                let channels = quality.channels.max(1) as usize;
+                let mono = data
+                    .chunks_exact(2 * channels)
+                    .map(|frame| i16::from_le_bytes([frame[0], frame[1]]))
+                    .collect();
+                (mono, quality.sample_rate)
+            }
+        };
+        
+        let buffer = self.buffers.entry(user_id).or_default();
+        buffer.extend(voice_codec::resample(&samples, sample_rate, VOICE_SAMPLE_RATE));
+        
+        let window = (VOICE_SAMPLE_RATE * TRANSCRIPTION_WINDOW_SECS) as usize;
+        if buffer.len() >= window {
+            Some(buffer.drain(..window).collect())
+        } else {
+            None
+        }
+    }
+    
+    pub fn remove_user(&mut self, user_id: Uuid) {
+        self.buffers.remove(&user_id);
+    }
+}
+
+fn rms(pcm: &[i16]) -> f64 {
+    let sum: f64 = pcm.iter().map(|&s| (s as f64) * (s as f64)).sum();
+    (sum / pcm.len() as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // A second of PCM at the transcription rate, each sample `left` then `right`
+    fn second_of_stereo(left: i16, right: i16) -> Vec<u8> {
+        (0..VOICE_SAMPLE_RATE).flat_map(|_| [left.to_le_bytes(), right.to_le_bytes()].concat()).collect()
+    }
+    
+    #[test]
+    fn voice_is_collected_into_windows_for_each_user() {
+        let mut transcriber = Transcriber::new(Arc::new(WhisperBackend), "en".to_string());
+        let quality = AudioQuality { sample_rate: VOICE_SAMPLE_RATE, channels: 2, bitrate_kbps: 64, codec: AudioCodec::PCM };
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        for _ in 1..TRANSCRIPTION_WINDOW_SECS {
+            assert!(transcriber.push_voice(alice, &second_of_stereo(1_000, -1_000), quality).is_none());
+        }
+        assert!(transcriber.push_voice(bob, &second_of_stereo(1_000, -1_000), quality).is_none());
+        
+        // Only the first channel is kept
+        let window = transcriber.push_voice(alice, &second_of_stereo(1_000, -1_000), quality).unwrap();
+        assert_eq!(window.len(), (VOICE_SAMPLE_RATE * TRANSCRIPTION_WINDOW_SECS) as usize);
+        assert!(window.iter().all(|s| *s == 1_000));
+        
+        // Leaving throws away what was collected
+        transcriber.remove_user(bob);
+        for _ in 1..TRANSCRIPTION_WINDOW_SECS {
+            assert!(transcriber.push_voice(bob, &second_of_stereo(1_000, -1_000), quality).is_none());
+        }
+        assert!(transcriber.push_voice(bob, &second_of_stereo(1_000, -1_000), quality).is_some());
+    }
+    
+    #[tokio::test]
+    async fn silence_isnt_transcribed() {
+        let backend = WhisperBackend;
+        
+        assert_eq!(backend.transcribe(&[], VOICE_SAMPLE_RATE, "en").await.unwrap(), "");
+        assert_eq!(backend.transcribe(&[10; 48_000], VOICE_SAMPLE_RATE, "en").await.unwrap(), "");
+        assert_eq!(backend.transcribe(&[1_000; 72_000], VOICE_SAMPLE_RATE, "en").await.unwrap(), "[1.5s of speech]");
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+use std::path::Path;
+use tracing::warn;
+use uuid::Uuid;
+
+// Greets each user the first time they log in
+pub struct Welcome {
+    content: Option<String>,
+    // Users already greeted since startup
+    welcomed: HashSet<Uuid>,
+}
+
+impl Welcome {
+    // `setting` is the message itself, or the path of a file holding it. Markdown files are sent
+    // as they are, to be shown as plain text.
+    pub fn new(setting: Option<&str>) -> Self {
+        let content = setting.and_then(|setting| {
+            let path = Path::new(setting);
+            if !path.is_file() {
+                return Some(setting.to_string());
+            }
+            
+            match std::fs::read_to_string(path) {
+                Ok(content) => Some(content),
+                Err(e) => {
+                    warn!("Failed to read welcome message from {}: {}", path.display(), e);
+                    None
+                }
+            }
+        });
+        
+        Self {
+            content: content.map(|c| c.trim().to_string()).filter(|c| !c.is_empty()),
+            welcomed: HashSet::new(),
+        }
+    }
+    
+    // The message to send a user who just logged in; None after their first login
+    pub fn for_login(&mut self, user_id: Uuid) -> Option<String> {
+        let content = self.content.as_ref()?;
+        self.welcomed.insert(user_id).then(|| content.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn sent_only_on_first_login() {
+        let mut welcome = Welcome::new(Some("Hello there"));
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        
+        assert_eq!(welcome.for_login(alice).as_deref(), Some("Hello there"));
+        assert_eq!(welcome.for_login(alice), None);
+        assert_eq!(welcome.for_login(bob).as_deref(), Some("Hello there"));
+    }
+    
+    #[test]
+    fn nothing_sent_when_unset() {
+        let mut welcome = Welcome::new(None);
+        
+        assert_eq!(welcome.for_login(Uuid::from_u128(1)), None);
+    }
+    
+    #[test]
+    fn reads_a_file_path() {
+        let path = std::env::temp_dir().join(format!("welcome-{}.md", Uuid::new_v4()));
+        std::fs::write(&path, "# Welcome\n\nRead the **rules** first.\n").unwrap();
+        
+        let mut welcome = Welcome::new(path.to_str());
+        let _ = std::fs::remove_file(&path);
+        
+        assert_eq!(welcome.for_login(Uuid::from_u128(1)).as_deref(), Some("# Welcome\n\nRead the **rules** first."));
+    }
+}